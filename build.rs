@@ -0,0 +1,84 @@
+//! Generates `build_info.rs` in `OUT_DIR` for `info::ServerInfo`: the
+//! enabled Cargo features, the git commit, the build date and the compiler
+//! version.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_ascii_lowercase())
+        })
+        .collect();
+    features.sort();
+
+    let git_commit = command_output("git", &["rev-parse", "--short=12", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rust_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        });
+
+    let generated = format!(
+        "/// Cargo features this binary was built with.\n\
+         pub const ENABLED_FEATURES: &[&str] = &{:?};\n\
+         /// Abbreviated commit the binary was built from, or `unknown`.\n\
+         pub const GIT_COMMIT: &str = {:?};\n\
+         /// UTC date of the build, `YYYY-MM-DD`.\n\
+         pub const BUILD_DATE: &str = {:?};\n\
+         /// Version of the compiler that built the binary.\n\
+         pub const RUST_VERSION: &str = {:?};\n",
+        features,
+        git_commit,
+        civil_date(build_secs),
+        rust_version,
+    );
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by Cargo");
+    fs::write(Path::new(&out_dir).join("build_info.rs"), generated)
+        .expect("Failed to write build_info.rs");
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+    (output.status.success() && !stdout.is_empty()).then(|| stdout.to_string())
+}
+
+/// Formats seconds since the Unix epoch as a `YYYY-MM-DD` UTC date.
+fn civil_date(secs: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,8 @@
+hello = Hello world!
+not-found = Not Found
+greeting = Hello, { $name }!
+items-count =
+    { $count ->
+        [one] You have one item.
+       *[other] You have { $count } items.
+    }
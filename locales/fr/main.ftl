@@ -0,0 +1,7 @@
+hello = Bonjour le monde !
+not-found = Introuvable
+items-count =
+    { $count ->
+        [one] Vous avez { $count } élément.
+       *[other] Vous avez { $count } éléments.
+    }
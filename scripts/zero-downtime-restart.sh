@@ -0,0 +1,50 @@
+#!/bin/sh
+# Restarts a running server without dropping connections.
+#
+# Usage: scripts/zero-downtime-restart.sh <pid> [timeout-seconds]
+#
+# Sends SIGUSR2 to <pid>, which starts a new server process on the same
+# listening sockets and drains once the new one serves. Waits for the old
+# process to exit, then prints the PID of the new one. Exits with 1 if the
+# old process is still running after the timeout (default: 60 seconds),
+# e.g. because the new process failed to start; the old one then keeps
+# serving.
+set -eu
+
+if [ $# -lt 1 ]; then
+    echo "usage: $0 <pid> [timeout-seconds]" >&2
+    exit 2
+fi
+old_pid=$1
+timeout=${2:-60}
+
+kill -0 "$old_pid" 2>/dev/null || {
+    echo "no process $old_pid" >&2
+    exit 1
+}
+children_before=$(pgrep -P "$old_pid" || true)
+kill -USR2 "$old_pid"
+
+new_pid=""
+elapsed=0
+while kill -0 "$old_pid" 2>/dev/null; do
+    # The new process starts as a child of the old one
+    for pid in $(pgrep -P "$old_pid" || true); do
+        case " $children_before " in
+            *" $pid "*) ;;
+            *) new_pid=$pid ;;
+        esac
+    done
+    if [ "$elapsed" -ge "$timeout" ]; then
+        echo "process $old_pid still running after ${timeout}s; restart failed or still draining" >&2
+        exit 1
+    fi
+    sleep 1
+    elapsed=$((elapsed + 1))
+done
+
+if [ -z "$new_pid" ] || ! kill -0 "$new_pid" 2>/dev/null; then
+    echo "process $old_pid exited, but no new process is running" >&2
+    exit 1
+fi
+echo "$new_pid"
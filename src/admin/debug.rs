@@ -0,0 +1,12 @@
+//! Debugging endpoints, only compiled with the `debug_endpoints` feature.
+
+use actix_web::HttpResponse;
+
+/// Handler for `POST /admin/debug/panic`.
+///
+/// Panics unconditionally so the
+/// [`PanicHandler`](crate::middleware::panic::PanicHandler) middleware can
+/// be verified against a running server.
+pub async fn panic_test_handler() -> HttpResponse {
+    panic!("test panic");
+}
@@ -0,0 +1,209 @@
+//! Administrative endpoints.
+//!
+//! Everything registered here is mounted under `/admin` behind the
+//! [`ApiKeyAuth`](crate::middleware::api_key::ApiKeyAuth) middleware.
+
+#[cfg(feature = "debug_endpoints")]
+pub mod debug;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde_json::json;
+
+use crate::audit::AuditTrail;
+use crate::config_reload::{ConfigChange, ReloadableConfig};
+use crate::dynamic_scope;
+use crate::error::{ApiError, FieldError};
+use crate::flags::{self, Flags};
+use crate::info;
+use crate::log_buffer;
+use crate::middleware::api_key::AdminKey;
+use crate::middleware::in_flight;
+use crate::middleware::request_id::CorrelationChain;
+use crate::route_meta::{self, DescribedRoute, RouteMetadata};
+
+/// Registers the admin routes on the `/admin` scope.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.described_route(
+        Method::POST,
+        "/config/reload",
+        reload_config,
+        RouteMetadata::new("Reload the admin API key from ADMIN_API_KEY_FILE"),
+    )
+    .described_route(
+        Method::GET,
+        "/config/preview",
+        preview_config,
+        RouteMetadata::new("Configuration changes a reload would make, without applying them"),
+    )
+    .described_route(
+        Method::POST,
+        "/config/apply",
+        apply_config,
+        RouteMetadata::new("Reload the configuration and apply its live changes"),
+    )
+    .described_route(
+        Method::GET,
+        "/status",
+        status,
+        RouteMetadata::new("Server status"),
+    )
+    .described_route(
+        Method::GET,
+        "/info",
+        info::info,
+        RouteMetadata::new("Build, runtime and configuration details"),
+    )
+    .described_route(
+        Method::GET,
+        "/info/features",
+        info::features,
+        RouteMetadata::new("Cargo features of the build"),
+    )
+    .described_route(
+        Method::GET,
+        "/in-flight",
+        in_flight::list_in_flight,
+        RouteMetadata::new("Requests being served, longest running first"),
+    )
+    .described_route(
+        Method::GET,
+        "/logs",
+        log_buffer::logs,
+        RouteMetadata::new("Last log records as JSON; ?level=, ?since= and ?limit= filter them"),
+    )
+    .described_route(
+        Method::GET,
+        "/logs/tail",
+        log_buffer::tail,
+        RouteMetadata::new(
+            "Last log lines; ?follow=true keeps streaming new ones, ?level= filters them",
+        ),
+    );
+    flags::configure_admin(cfg);
+    dynamic_scope::configure_admin(cfg);
+    route_meta::configure_admin(cfg);
+
+    #[cfg(feature = "debug_endpoints")]
+    cfg.described_route(
+        Method::POST,
+        "/debug/panic",
+        debug::panic_test_handler,
+        RouteMetadata::new("Panic in a handler, to test panic handling"),
+    );
+
+    #[cfg(feature = "profiling")]
+    cfg.described_route(
+        Method::GET,
+        "/debug/pprof/profile",
+        profiling::cpu_profile,
+        RouteMetadata::new("CPU profile in pprof format"),
+    );
+
+    #[cfg(feature = "heap_profiling")]
+    cfg.described_route(
+        Method::GET,
+        "/debug/pprof/heap",
+        profiling::heap_profile,
+        RouteMetadata::new("Heap profile in pprof format"),
+    );
+}
+
+/// Handler for `POST /admin/config/reload`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - `{"rotated": bool}`, 409 `not_reloadable` without `ADMIN_API_KEY_FILE`, or 400 `invalid_api_key` if the file cannot be read or its key was rejected.
+pub async fn reload_config(
+    key: web::Data<AdminKey>,
+    chain: CorrelationChain,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    let result = if !key.is_reloadable() {
+        Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "not_reloadable",
+            "ADMIN_API_KEY_FILE is not set",
+        ))
+    } else {
+        key.reload_audited("api", Some(&chain)).map_err(|e| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_api_key",
+                format!("The admin API key was not rotated: {}", e),
+            )
+        })
+    };
+    let detail = match &result {
+        Ok(rotated) => json!({ "rotated": rotated }),
+        Err(_) => json!({}),
+    };
+    trail.record_result("config_reloaded", detail, &result);
+    Ok(HttpResponse::Ok().json(json!({ "rotated": result? })))
+}
+
+fn invalid_config(errors: Vec<FieldError>) -> ApiError {
+    errors.into_iter().fold(
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_config",
+            "The configuration is invalid",
+        ),
+        |error, field| error.with_field(field.field, field.message),
+    )
+}
+
+fn describe_changes<'a>(
+    changes: impl IntoIterator<Item = &'a ConfigChange>,
+) -> Vec<serde_json::Value> {
+    changes.into_iter().map(ConfigChange::describe).collect()
+}
+
+/// Handler for `GET /admin/config/preview`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - `{"changes": [...]}` with secrets redacted, or 400 `invalid_config` listing every invalid field.
+pub async fn preview_config(config: web::Data<ReloadableConfig>) -> Result<HttpResponse, ApiError> {
+    let changes = config.preview().map_err(invalid_config)?;
+    Ok(HttpResponse::Ok().json(json!({ "changes": describe_changes(&changes) })))
+}
+
+/// Handler for `POST /admin/config/apply`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - `{"applied": [...], "pending_restart": [...]}`, or 400 `invalid_config` listing every invalid field, with nothing applied.
+pub async fn apply_config(
+    config: web::Data<ReloadableConfig>,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    let result = config.apply().map_err(invalid_config);
+    let detail = match &result {
+        Ok(changes) => json!({ "changes": describe_changes(changes) }),
+        Err(_) => json!({}),
+    };
+    trail.record_result("config_applied", detail, &result);
+    let changes = result?;
+    let (pending, applied): (Vec<_>, Vec<_>) =
+        changes.iter().partition(|change| change.restart_required);
+    Ok(HttpResponse::Ok().json(json!({
+        "applied": describe_changes(applied),
+        "pending_restart": describe_changes(pending),
+    })))
+}
+
+/// Handler for `GET /admin/status`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the server version and the feature flags.
+pub async fn status(flags: web::Data<Flags>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "flags": flags.describe(Utc::now()),
+    }))
+}
@@ -0,0 +1,162 @@
+//! Profiling endpoints, only compiled with the `profiling` feature.
+//!
+//! `GET /admin/debug/pprof/profile` samples CPU stacks for a while and
+//! returns a pprof protobuf (or a flamegraph SVG with `?format=flamegraph`).
+//! With the `heap_profiling` feature the server runs on jemalloc with
+//! sampling enabled and `GET /admin/debug/pprof/heap` dumps a pprof heap
+//! profile. Only one profiling session runs at a time; concurrent requests
+//! get `409 Conflict`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::rt::time::sleep;
+use actix_web::{web, HttpResponse};
+use log::info;
+use pprof::protos::Message;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+
+/// Longest CPU profile that may be requested.
+pub const MAX_PROFILE_SECS: u64 = 300;
+
+/// Sampling frequency of CPU profiles, in Hz.
+const PROFILE_FREQUENCY: i32 = 99;
+
+static SESSION_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Held while a profile is being taken; releases the session on drop.
+struct ProfilingSession;
+
+impl ProfilingSession {
+    fn acquire() -> Result<Self, ApiError> {
+        SESSION_ACTIVE
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ProfilingSession)
+            .map_err(|_| {
+                ApiError::new(
+                    StatusCode::CONFLICT,
+                    "profiling_in_progress",
+                    "Another profiling session is running",
+                )
+            })
+    }
+}
+
+impl Drop for ProfilingSession {
+    fn drop(&mut self) {
+        SESSION_ACTIVE.store(false, Ordering::Release);
+    }
+}
+
+fn profiling_failed(e: impl std::fmt::Display) -> ApiError {
+    ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "profiling_failed",
+        format!("Profiling failed: {}", e),
+    )
+}
+
+fn attachment(filename: &str) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename.to_string())],
+    }
+}
+
+/// Query parameters of the CPU profile endpoint.
+#[derive(Deserialize)]
+pub struct ProfileParams {
+    /// Sampling duration (default 30).
+    pub seconds: Option<u64>,
+    /// `pprof` (default) or `flamegraph`.
+    pub format: Option<String>,
+}
+
+/// Handler for `GET /admin/debug/pprof/profile`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - The profile, 400 for invalid parameters, or 409 while another session runs.
+pub async fn cpu_profile(params: web::Query<ProfileParams>) -> Result<HttpResponse, ApiError> {
+    let seconds = params.seconds.unwrap_or(30);
+    if seconds == 0 || seconds > MAX_PROFILE_SECS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            format!("seconds must be between 1 and {}", MAX_PROFILE_SECS),
+        )
+        .with_field("seconds", "out of range"));
+    }
+    let flamegraph = match params.format.as_deref() {
+        None | Some("pprof") => false,
+        Some("flamegraph") => true,
+        Some(_) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "format must be pprof or flamegraph",
+            )
+            .with_field("format", "unsupported format"))
+        }
+    };
+
+    let _session = ProfilingSession::acquire()?;
+    info!("Taking a {}s CPU profile", seconds);
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(PROFILE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(profiling_failed)?;
+    sleep(Duration::from_secs(seconds)).await;
+    let report = guard.report().build().map_err(profiling_failed)?;
+
+    let mut body = Vec::new();
+    if flamegraph {
+        report.flamegraph(&mut body).map_err(profiling_failed)?;
+        Ok(HttpResponse::Ok().content_type("image/svg+xml").body(body))
+    } else {
+        report
+            .pprof()
+            .map_err(profiling_failed)?
+            .encode(&mut body)
+            .map_err(profiling_failed)?;
+        Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header(attachment("profile.pb"))
+            .body(body))
+    }
+}
+
+/// Handler for `GET /admin/debug/pprof/heap`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - The heap profile, 409 while another session runs, or 503 if jemalloc profiling is not active.
+#[cfg(feature = "heap_profiling")]
+pub async fn heap_profile() -> Result<HttpResponse, ApiError> {
+    let unavailable = || {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "heap_profiling_unavailable",
+            "jemalloc heap profiling is not active",
+        )
+    };
+
+    let _session = ProfilingSession::acquire()?;
+    let prof_ctl = jemalloc_pprof::PROF_CTL.as_ref().ok_or_else(unavailable)?;
+    let mut prof_ctl = prof_ctl.lock().await;
+    if !prof_ctl.activated() {
+        return Err(unavailable());
+    }
+    let body = prof_ctl.dump_pprof().map_err(profiling_failed)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(attachment("heap.pb"))
+        .body(body))
+}
@@ -0,0 +1,50 @@
+//! Security audit log.
+//!
+//! Security-relevant events are written as single-line JSON objects to the
+//! `audit` log target, so they can be routed separately from the access log,
+//! e.g. `RUST_LOG=info,audit=info`. With the `db` feature they are also
+//! stored in the database; see [`store`]. Administrative actions are also
+//! appended to the audit trail file; see [`trail`].
+
+#[cfg(feature = "db")]
+pub mod store;
+pub mod trail;
+
+pub use trail::{AuditLog, AuditTrail, Outcome};
+
+use chrono::Utc;
+use log::info;
+use serde_json::{json, Value};
+
+use crate::middleware::request_id::CorrelationChain;
+
+/// Log target used for audit events.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// Records an audit event.
+///
+/// `details` should be a JSON object; its fields are merged into the event
+/// next to `event` and `timestamp`.
+pub fn record(event: &str, details: Value) {
+    let mut entry = json!({
+        "event": event,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+    if let (Some(entry), Value::Object(details)) = (entry.as_object_mut(), details) {
+        entry.extend(details);
+    }
+    info!(target: AUDIT_TARGET, "{}", entry);
+    #[cfg(feature = "db")]
+    store::persist(&entry);
+}
+
+/// Records an audit event caused by a request, including its correlation
+/// chain: the request's own ID and the ID of the calling request, if any.
+pub fn record_for(chain: &CorrelationChain, event: &str, details: Value) {
+    let mut details = details;
+    if let Value::Object(fields) = &mut details {
+        fields.insert("request_id".to_string(), json!(chain.own()));
+        fields.insert("parent_request_id".to_string(), json!(chain.parent()));
+    }
+    record(event, details);
+}
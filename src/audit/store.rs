@@ -0,0 +1,440 @@
+//! Audit events persisted in the database, only compiled with the `db`
+//! feature.
+//!
+//! [`record`](super::record) hands every event to an [`AuditWriter`], which
+//! queues it and inserts queued events in batches from a background task.
+//! The queue is bounded: when the database cannot keep up, new events are
+//! dropped (they still reach the `audit` log target) and counted in
+//! `audit_events_dropped_total`. `GET /admin/audit` queries stored events
+//! and a purge job deletes those older than `AUDIT_RETENTION_DAYS`.
+
+use std::env;
+use std::io::Error as IoError;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{info, warn};
+use rusqlite::params_from_iter;
+use rusqlite::types::Value as SqlValue;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::db::Database;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::util::export::Export;
+use crate::util::negotiate::vary_accept;
+
+/// Counter of events dropped because the queue was full.
+pub const DROPPED_METRIC: &str = "audit_events_dropped_total";
+
+/// Default for `AUDIT_QUEUE_CAPACITY`.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 10_000;
+/// Most events inserted in one transaction.
+pub const BATCH_SIZE: usize = 500;
+/// Default for `AUDIT_RETENTION_DAYS`.
+pub const DEFAULT_RETENTION_DAYS: u32 = 90;
+/// Default page size of `GET /admin/audit`.
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+/// Largest page size of `GET /admin/audit`.
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// An audit event before it is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NewAuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub action: String,
+    pub principal: Option<String>,
+    pub request_id: Option<String>,
+    pub details: Value,
+}
+
+impl NewAuditEvent {
+    /// Builds a stored event from a logged audit entry, taking the principal
+    /// from its `principal` or `username` field.
+    pub fn from_entry(entry: &Value) -> Option<NewAuditEvent> {
+        let text = |field: &str| entry.get(field).and_then(Value::as_str).map(str::to_string);
+        Some(NewAuditEvent {
+            timestamp: text("timestamp")
+                .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or_else(Utc::now),
+            action: text("event")?,
+            principal: text("principal").or_else(|| text("username")),
+            request_id: text("request_id"),
+            details: entry.clone(),
+        })
+    }
+}
+
+/// A stored audit event.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub timestamp: String,
+    pub action: String,
+    pub principal: Option<String>,
+    pub request_id: Option<String>,
+    pub details: Value,
+}
+
+/// Filters for [`AuditStore::query`]; `page` is the `next_page` cursor of
+/// the previous page.
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    pub principal: Option<String>,
+    pub action: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub page: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+/// Query string of `GET /admin/audit`; `from` and `to` are RFC 3339.
+#[derive(Deserialize)]
+pub struct AuditQueryParams {
+    pub principal: Option<String>,
+    pub action: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub page: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQueryParams {
+    /// Parses the time bounds.
+    ///
+    /// # Errors
+    ///
+    /// Returns a 400 ApiError naming the field that is not RFC 3339.
+    pub fn into_query(self) -> Result<AuditQuery, ApiError> {
+        let parse = |field: &str, value: Option<String>| {
+            value
+                .map(|v| {
+                    DateTime::parse_from_rfc3339(&v)
+                        .map(|t| t.with_timezone(&Utc))
+                        .map_err(|_| {
+                            ApiError::new(
+                                StatusCode::BAD_REQUEST,
+                                "invalid_timestamp",
+                                "Invalid timestamp",
+                            )
+                            .with_field(field, "must be an RFC 3339 timestamp")
+                        })
+                })
+                .transpose()
+        };
+        Ok(AuditQuery {
+            from: parse("from", self.from)?,
+            to: parse("to", self.to)?,
+            principal: self.principal,
+            action: self.action,
+            page: self.page,
+            limit: self.limit,
+        })
+    }
+}
+
+/// One page of events, newest first.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    /// Cursor for the next page, `None` on the last page.
+    pub next_page: Option<i64>,
+}
+
+/// Reads and writes the `audit_events` table.
+#[derive(Clone)]
+pub struct AuditStore {
+    db: Database,
+}
+
+impl AuditStore {
+    pub fn new(db: Database) -> Self {
+        AuditStore { db }
+    }
+
+    /// Inserts `events` in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the insert fails; no event is stored then.
+    pub async fn insert_batch(&self, events: Vec<NewAuditEvent>) -> Result<(), IoError> {
+        self.db
+            .run(move |conn| {
+                let tx = conn.transaction()?;
+                {
+                    let mut insert = tx.prepare_cached(
+                        "INSERT INTO audit_events (timestamp_ms, action, principal, request_id, details)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                    )?;
+                    for event in &events {
+                        insert.execute((
+                            event.timestamp.timestamp_millis(),
+                            &event.action,
+                            &event.principal,
+                            &event.request_id,
+                            event.details.to_string(),
+                        ))?;
+                    }
+                }
+                tx.commit()
+            })
+            .await
+    }
+
+    /// Returns the page of events matching `query`, newest first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the query fails.
+    pub async fn query(&self, query: AuditQuery) -> Result<AuditPage, IoError> {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(principal) = query.principal {
+            conditions.push("principal = ?");
+            values.push(SqlValue::Text(principal));
+        }
+        if let Some(action) = query.action {
+            conditions.push("action = ?");
+            values.push(SqlValue::Text(action));
+        }
+        if let Some(from) = query.from {
+            conditions.push("timestamp_ms >= ?");
+            values.push(SqlValue::Integer(from.timestamp_millis()));
+        }
+        if let Some(to) = query.to {
+            conditions.push("timestamp_ms < ?");
+            values.push(SqlValue::Integer(to.timestamp_millis()));
+        }
+        if let Some(page) = query.page {
+            conditions.push("id < ?");
+            values.push(SqlValue::Integer(page));
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        // One extra row tells whether another page follows
+        let sql = format!(
+            "SELECT id, timestamp_ms, action, principal, request_id, details
+             FROM audit_events {} ORDER BY id DESC LIMIT {}",
+            filter,
+            limit + 1
+        );
+
+        let mut events = self
+            .db
+            .run(move |conn| {
+                let mut statement = conn.prepare(&sql)?;
+                let rows = statement.query_map(params_from_iter(values), |row| {
+                    let timestamp_ms: i64 = row.get(1)?;
+                    let details: String = row.get(5)?;
+                    Ok(AuditEvent {
+                        id: row.get(0)?,
+                        timestamp: Utc
+                            .timestamp_millis_opt(timestamp_ms)
+                            .single()
+                            .map(|t| t.to_rfc3339())
+                            .unwrap_or_default(),
+                        action: row.get(2)?,
+                        principal: row.get(3)?,
+                        request_id: row.get(4)?,
+                        details: serde_json::from_str(&details).unwrap_or(Value::Null),
+                    })
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        let next_page = if events.len() > limit {
+            events.truncate(limit);
+            events.last().map(|e| e.id)
+        } else {
+            None
+        };
+        Ok(AuditPage { events, next_page })
+    }
+
+    /// Deletes events recorded before `cutoff`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<usize, IoError>` - The number of deleted events.
+    pub async fn purge_before(&self, cutoff: DateTime<Utc>) -> Result<usize, IoError> {
+        let cutoff = cutoff.timestamp_millis();
+        self.db
+            .run(move |conn| {
+                conn.execute("DELETE FROM audit_events WHERE timestamp_ms < ?1", [cutoff])
+            })
+            .await
+    }
+
+    /// Spawns a job deleting events older than `retention` every `every`.
+    pub fn spawn_purge(&self, retention: Duration, every: Duration) {
+        let store = self.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                let cutoff = Utc::now()
+                    - chrono::Duration::from_std(retention)
+                        .unwrap_or_else(|_| chrono::Duration::days(36_500));
+                match store.purge_before(cutoff).await {
+                    Ok(0) => {}
+                    Ok(n) => info!("Purged {} audit event(s) older than {}", n, cutoff),
+                    Err(e) => warn!("Failed to purge audit events: {}", e),
+                }
+                actix_web::rt::time::sleep(every).await;
+            }
+        });
+    }
+}
+
+/// Queues audit events for batched insertion.
+#[derive(Clone)]
+pub struct AuditWriter {
+    sender: mpsc::Sender<NewAuditEvent>,
+}
+
+impl AuditWriter {
+    /// Starts the background task inserting into `store`, queueing at most
+    /// `capacity` events.
+    pub fn spawn(store: AuditStore, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel(capacity.max(1));
+        actix_web::rt::spawn(async move {
+            while let Some(first) = receiver.recv().await {
+                let mut batch = vec![first];
+                while batch.len() < BATCH_SIZE {
+                    match receiver.try_recv() {
+                        Ok(event) => batch.push(event),
+                        Err(_) => break,
+                    }
+                }
+                let count = batch.len();
+                if let Err(e) = store.insert_batch(batch).await {
+                    warn!("Failed to store {} audit event(s): {}", count, e);
+                }
+            }
+        });
+        AuditWriter { sender }
+    }
+
+    /// Queues `event`, dropping and counting it if the queue is full.
+    pub fn enqueue(&self, event: NewAuditEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                Metrics::global().inc(DROPPED_METRIC, &[("reason", "queue_full")]);
+            }
+            Err(TrySendError::Closed(_)) => {
+                Metrics::global().inc(DROPPED_METRIC, &[("reason", "writer_stopped")]);
+            }
+        }
+    }
+}
+
+static WRITER: OnceLock<AuditWriter> = OnceLock::new();
+
+/// Makes `writer` receive every recorded audit event. Only the first call
+/// has an effect.
+pub fn install(writer: AuditWriter) {
+    let _ = WRITER.set(writer);
+}
+
+/// Hands a logged audit entry to the installed writer, if any.
+pub(crate) fn persist(entry: &Value) {
+    if let (Some(writer), Some(event)) = (WRITER.get(), NewAuditEvent::from_entry(entry)) {
+        writer.enqueue(event);
+    }
+}
+
+/// Sets up persistence from `AUDIT_QUEUE_CAPACITY`,
+/// `AUDIT_RETENTION_DAYS` and `AUDIT_PURGE_INTERVAL_SECS`: installs the
+/// writer and starts the purge job.
+///
+/// # Returns
+///
+/// * `AuditStore` - The store, for the query endpoint.
+pub fn start(db: Database) -> AuditStore {
+    let read = |var: &str| env::var(var).ok().and_then(|v| v.parse::<u64>().ok());
+    let store = AuditStore::new(db);
+    let capacity = read("AUDIT_QUEUE_CAPACITY")
+        .map(|c| c as usize)
+        .unwrap_or(DEFAULT_QUEUE_CAPACITY);
+    install(AuditWriter::spawn(store.clone(), capacity));
+
+    let retention_days = read("AUDIT_RETENTION_DAYS").unwrap_or(DEFAULT_RETENTION_DAYS.into());
+    let every = Duration::from_secs(read("AUDIT_PURGE_INTERVAL_SECS").unwrap_or(3600));
+    store.spawn_purge(Duration::from_secs(retention_days * 86_400), every);
+    info!("Persisting audit events, kept for {} days", retention_days);
+    store
+}
+
+/// Handler for `GET /admin/audit`.
+///
+/// With `?format=csv` or `?format=ndjson`, or an `Accept` header asking for
+/// them, every matching event is exported instead, newest first, fetched a
+/// page at a time; see [`Export`].
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with an [`AuditPage`] as JSON or the export, 400 for malformed timestamps or export parameters, or 500 if the query fails.
+pub async fn list_events(
+    req: HttpRequest,
+    store: web::Data<AuditStore>,
+    query: web::Query<AuditQueryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner().into_query()?;
+    if let Some(export) = Export::from_request(&req)? {
+        let rows = export_events(store.get_ref().clone(), query);
+        return Ok(export.respond("audit-events", rows));
+    }
+    let page = store.query(query).await.map_err(|e| {
+        warn!("Audit query failed: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "audit_query_failed",
+            "Audit events could not be queried",
+        )
+    })?;
+    Ok(vary_accept(HttpResponse::Ok().json(page)))
+}
+
+/// Streams every event matching `query` from its `page` on, fetching
+/// [`MAX_PAGE_SIZE`] events at a time.
+fn export_events(
+    store: AuditStore,
+    query: AuditQuery,
+) -> impl Stream<Item = Result<AuditEvent, IoError>> {
+    let first = AuditQuery {
+        limit: Some(MAX_PAGE_SIZE),
+        ..query
+    };
+    stream::unfold(Some(first), move |next| {
+        let store = store.clone();
+        async move {
+            let query = next?;
+            match store.query(query.clone()).await {
+                Ok(page) => {
+                    let next = page.next_page.map(|page| AuditQuery {
+                        page: Some(page),
+                        ..query
+                    });
+                    let events = page.events.into_iter().map(Ok::<_, IoError>);
+                    Some((stream::iter(events).left_stream(), next))
+                }
+                Err(e) => Some((stream::iter(vec![Err(e)]).right_stream(), None)),
+            }
+        }
+    })
+    .flatten()
+}
@@ -0,0 +1,285 @@
+//! Append-only record of administrative actions.
+//!
+//! Every admin handler that changes something takes an [`AuditTrail`] and
+//! records what it did and whether it worked. Each [`AuditEvent`] is
+//! appended as one JSON line to `AUDIT_LOG_FILE`, which is never truncated
+//! or rewritten, and the last [`TRAIL_CAPACITY`] events are kept in memory
+//! for `GET /admin/audit?since=...&action=...`. Events also go to the
+//! `audit` log target like every other audit event, and so to the database
+//! with the `db` feature.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, Write};
+use std::sync::Mutex;
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::middleware::request_id::CorrelationChain;
+use crate::util::real_ip::real_ip;
+
+/// Events kept in memory for `GET /admin/audit`.
+pub const TRAIL_CAPACITY: usize = 1000;
+
+/// Whether an action did what was asked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+}
+
+/// An administrative action.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    /// The client IP, as far as trusted proxies tell.
+    pub admin_ip: Option<String>,
+    pub request_id: String,
+    /// Name of the action, e.g. `feature_flag_overridden`; also the
+    /// event name in the `audit` log.
+    pub action: String,
+    pub outcome: Outcome,
+    /// What the action was applied to, or why it failed.
+    pub detail: Value,
+}
+
+impl AuditEvent {
+    /// The event as JSON, as written to the file.
+    pub fn describe(&self) -> Value {
+        json!({
+            "timestamp": self.timestamp.to_rfc3339(),
+            "admin_ip": self.admin_ip,
+            "request_id": self.request_id,
+            "action": self.action,
+            "outcome": self.outcome.as_str(),
+            "detail": self.detail,
+        })
+    }
+
+    /// Whether the event's action starts with `action`: `feature_flag`
+    /// matches `feature_flag_overridden` and
+    /// `feature_flag_override_cleared`.
+    pub fn matches_action(&self, action: &str) -> bool {
+        self.action.starts_with(action)
+    }
+}
+
+/// The append-only file and the most recent events, shared by every
+/// worker.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+    recent: Mutex<VecDeque<AuditEvent>>,
+}
+
+impl AuditLog {
+    /// Appends events to `path`, creating it readable by the owner only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be opened.
+    pub fn open(path: &str) -> Result<Self, IoError> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        Ok(AuditLog {
+            file: Some(Mutex::new(options.open(path)?)),
+            recent: Mutex::default(),
+        })
+    }
+
+    /// Appends to `AUDIT_LOG_FILE`; events are only kept in memory when it
+    /// is unset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be opened.
+    pub fn from_env() -> Result<Self, IoError> {
+        match env::var("AUDIT_LOG_FILE") {
+            Ok(path) if !path.is_empty() => {
+                let log = AuditLog::open(&path).map_err(|e| {
+                    IoError::new(
+                        e.kind(),
+                        format!("cannot open AUDIT_LOG_FILE '{}': {}", path, e),
+                    )
+                })?;
+                info!("Appending administrative actions to {}", path);
+                Ok(log)
+            }
+            _ => Ok(AuditLog::default()),
+        }
+    }
+
+    /// Writes `event` to the file and keeps it in memory. A failed write is
+    /// logged and counted in `audit_trail_write_errors_total`; the action
+    /// itself has already happened.
+    pub fn append(&self, event: AuditEvent) {
+        if let Some(file) = &self.file {
+            let line = format!("{}\n", event.describe());
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+                warn!("Failed to write the audit trail: {}", e);
+                Metrics::global().inc("audit_trail_write_errors_total", &[]);
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == TRAIL_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(event);
+    }
+
+    /// Returns the events in memory, newest first, recorded at or after
+    /// `since` and whose action starts with `action`.
+    pub fn query(&self, since: Option<DateTime<Utc>>, action: Option<&str>) -> Vec<AuditEvent> {
+        self.recent
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| since.is_none_or(|since| e.timestamp >= since))
+            .filter(|e| action.is_none_or(|action| e.matches_action(action)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Records the actions of one request; extracted in admin handlers.
+///
+/// Without an [`AuditLog`] in app data, events only go to the `audit` log
+/// target.
+pub struct AuditTrail {
+    log: Option<web::Data<AuditLog>>,
+    admin_ip: Option<String>,
+    chain: CorrelationChain,
+}
+
+impl FromRequest for AuditTrail {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(AuditTrail {
+            log: req.app_data::<web::Data<AuditLog>>().cloned(),
+            admin_ip: real_ip(req).ip().map(|ip| ip.to_string()),
+            chain: req
+                .extensions()
+                .get::<CorrelationChain>()
+                .cloned()
+                .unwrap_or_else(CorrelationChain::root),
+        }))
+    }
+}
+
+impl AuditTrail {
+    /// Records `action` with its `outcome`.
+    pub fn record(&self, action: &str, outcome: Outcome) {
+        self.record_with(action, outcome, Value::Null);
+    }
+
+    /// Records `action` with its `outcome` and `detail`.
+    pub fn record_with(&self, action: &str, outcome: Outcome, detail: Value) {
+        let event = AuditEvent {
+            timestamp: Utc::now(),
+            admin_ip: self.admin_ip.clone(),
+            request_id: self.chain.own().to_string(),
+            action: action.to_string(),
+            outcome,
+            detail,
+        };
+        super::record_for(
+            &self.chain,
+            action,
+            json!({
+                "admin_ip": event.admin_ip,
+                "outcome": outcome.as_str(),
+                "detail": event.detail,
+            }),
+        );
+        if let Some(log) = &self.log {
+            log.append(event);
+        }
+    }
+
+    /// Records `action` as a success with `detail`, or as a failure with
+    /// the error code of `result`.
+    pub fn record_result<T>(&self, action: &str, detail: Value, result: &Result<T, ApiError>) {
+        match result {
+            Ok(_) => self.record_with(action, Outcome::Success, detail),
+            Err(e) => {
+                let mut detail = detail;
+                match &mut detail {
+                    Value::Object(fields) => {
+                        fields.insert("error".to_string(), json!(e.code()));
+                    }
+                    _ => detail = json!({ "error": e.code() }),
+                }
+                self.record_with(action, Outcome::Failure, detail)
+            }
+        }
+    }
+}
+
+/// Query string of `GET /admin/audit`; `since` is RFC 3339.
+#[derive(Deserialize)]
+pub struct TrailQuery {
+    pub since: Option<String>,
+    pub action: Option<String>,
+}
+
+/// Handler for `GET /admin/audit` when events are not persisted in a
+/// database.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the matching events of the last [`TRAIL_CAPACITY`], newest first, or 400 if `since` is not RFC 3339.
+pub async fn list_events(
+    log: web::Data<AuditLog>,
+    query: web::Query<TrailQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let since = query
+        .since
+        .as_deref()
+        .map(|since| {
+            DateTime::parse_from_rfc3339(since)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|_| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_query",
+                        "since must be an RFC 3339 timestamp",
+                    )
+                    .with_field("since", "not an RFC 3339 timestamp")
+                })
+        })
+        .transpose()?;
+    let events: Vec<Value> = log
+        .query(since, query.action.as_deref())
+        .iter()
+        .map(AuditEvent::describe)
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({ "events": events })))
+}
@@ -0,0 +1,266 @@
+//! Users file authentication backend.
+//!
+//! Each non-empty, non-comment line of the file has the form
+//! `username:argon2-hash:role1,role2`, where the hash is a PHC string such as
+//! those produced by the `argon2` CLI.
+//!
+//! Password changes (see [`reset`](super::reset)) update the in-memory users
+//! and rewrite the user's line in the file, keeping every other line.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use async_trait::async_trait;
+use log::error;
+use ring::digest;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use super::{AuthBackend, AuthError, Principal};
+
+struct UserRecord {
+    hash: String,
+    roles: Vec<String>,
+}
+
+/// Authenticates against users loaded from a file.
+pub struct FileBackend {
+    users: RwLock<HashMap<String, UserRecord>>,
+    /// File rewritten on password changes; `None` for parsed contents.
+    path: Option<PathBuf>,
+    /// Hash verified for unknown users so they take as long as known ones.
+    dummy_hash: String,
+}
+
+impl FileBackend {
+    /// Loads users from `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or a line is malformed.
+    pub fn load(path: &str) -> Result<FileBackend, IoError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            error!("Failed to read users file '{}': {}", path, e);
+            e
+        })?;
+        let mut backend = FileBackend::parse(&contents)?;
+        backend.path = Some(PathBuf::from(path));
+        Ok(backend)
+    }
+
+    /// Parses users from the file contents.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a line is malformed or contains an invalid hash.
+    pub fn parse(contents: &str) -> Result<FileBackend, IoError> {
+        let mut users = HashMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(3, ':');
+            let (username, hash) = match (parts.next(), parts.next()) {
+                (Some(username), Some(hash)) if !username.is_empty() => (username, hash),
+                _ => {
+                    error!("Malformed users file line {}", number + 1);
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("Malformed users file line {}", number + 1),
+                    ));
+                }
+            };
+            if PasswordHash::new(hash).is_err() {
+                error!("Invalid password hash on users file line {}", number + 1);
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid password hash on users file line {}", number + 1),
+                ));
+            }
+            let roles = parts
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string)
+                .collect();
+
+            users.insert(
+                username.to_string(),
+                UserRecord {
+                    hash: hash.to_string(),
+                    roles,
+                },
+            );
+        }
+
+        Ok(FileBackend {
+            users: RwLock::new(users),
+            path: None,
+            dummy_hash: hash_password("dummy password for unknown users"),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthBackend for FileBackend {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Principal, AuthError> {
+        let record = self
+            .users
+            .read()
+            .unwrap()
+            .get(username)
+            .map(|r| (r.hash.clone(), r.roles.clone()));
+        let hash = record
+            .as_ref()
+            .map(|(hash, _)| hash.clone())
+            .unwrap_or_else(|| self.dummy_hash.clone());
+        let password = password.to_string();
+
+        // Argon2 is deliberately slow; keep it off the async worker thread
+        let verified =
+            actix_web::rt::task::spawn_blocking(move || verify_password(&password, &hash))
+                .await
+                .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+
+        match record {
+            Some((_, roles)) if verified => Ok(Principal {
+                username: username.to_string(),
+                roles,
+            }),
+            _ => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    async fn password_stamp(&self, username: &str) -> Option<String> {
+        let users = self.users.read().unwrap();
+        let hash = digest::digest(&digest::SHA256, users.get(username)?.hash.as_bytes());
+        Some(
+            hash.as_ref()[..16]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        )
+    }
+
+    async fn set_password_hash(&self, username: &str, hash: String) -> Result<(), AuthError> {
+        if let Some(path) = self.path.clone() {
+            let (username, hash) = (username.to_string(), hash.clone());
+            actix_web::rt::task::spawn_blocking(move || {
+                rewrite_users_file(&path, &username, &hash)
+            })
+            .await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?
+            .map_err(|e| {
+                error!("Failed to update users file: {}", e);
+                AuthError::Unavailable(e.to_string())
+            })?;
+        }
+        match self.users.write().unwrap().get_mut(username) {
+            Some(record) => {
+                record.hash = hash;
+                Ok(())
+            }
+            None => Err(AuthError::InvalidCredentials),
+        }
+    }
+}
+
+/// Serializes rewrites, so concurrent password changes never lose one another.
+static REWRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Replaces the hash on the line of `username`, writing through a temporary
+/// file so a crash never leaves a truncated users file.
+///
+/// The temporary file is only readable by the owner until it takes over the
+/// permissions of the users file.
+fn rewrite_users_file(path: &Path, username: &str, hash: &str) -> Result<(), IoError> {
+    let _rewriting = REWRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let contents = fs::read_to_string(path)?;
+    let permissions = fs::metadata(path)?.permissions();
+    let mut found = false;
+    let mut updated: Vec<String> = contents
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim();
+            let mut parts = trimmed.splitn(3, ':');
+            if trimmed.starts_with('#') || parts.next() != Some(username) {
+                return line.to_string();
+            }
+            found = true;
+            let roles = parts.nth(1).unwrap_or_default();
+            format!("{}:{}:{}", username, hash, roles)
+        })
+        .collect();
+    if !found {
+        return Err(IoError::new(
+            ErrorKind::NotFound,
+            format!("user '{}' is not in the users file", username),
+        ));
+    }
+    updated.push(String::new());
+
+    let tmp = temp_path(path);
+    let written = write_private(&tmp, updated.join("\n").as_bytes())
+        .and_then(|()| fs::set_permissions(&tmp, permissions))
+        .and_then(|()| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Returns a temporary path next to `path`, unique within this host.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Creates `path`, which must not exist yet, with mode 0600 and writes `data`.
+fn write_private(path: &Path, data: &[u8]) -> Result<(), IoError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Hashes `password` with argon2id and a random salt, returning a PHC string.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 hashing with default parameters cannot fail")
+        .to_string()
+}
+
+/// Verifies `password` against a PHC-formatted argon2 hash.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
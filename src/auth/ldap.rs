@@ -0,0 +1,371 @@
+//! LDAP / Active Directory authentication backend (`ldap` feature).
+//!
+//! Two modes are supported:
+//!
+//! * **search+bind**: when `LDAP_BIND_DN` is set, a service account searches
+//!   `LDAP_BASE_DN` with `LDAP_USER_FILTER` to find the user's DN, then binds
+//!   as that DN with the supplied password.
+//! * **bind-as-user**: otherwise the DN is built from `LDAP_USER_DN_TEMPLATE`
+//!   and bound directly; the user's own entry is then searched for groups.
+//!
+//! Group DNs from `LDAP_GROUP_ATTRIBUTE` are mapped to roles through
+//! `LDAP_GROUP_ROLES`. Connections are pooled, and failed logins are cached
+//! briefly so repeated bad attempts don't each cost a directory round trip.
+
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use log::{debug, error, warn};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::hash::BuildHasher;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{AuthBackend, AuthError, Principal};
+
+/// LDAP result code for a failed bind.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Settings for [`LdapBackend`].
+#[derive(Clone, Debug)]
+pub struct LdapConfig {
+    pub url: String,
+    pub starttls: bool,
+    pub base_dn: String,
+    /// Search filter; `{username}` is replaced with the escaped username.
+    pub user_filter: String,
+    /// DN for bind-as-user; `{username}` is replaced with the escaped username.
+    pub user_dn_template: Option<String>,
+    /// Service account for search+bind.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub group_attribute: String,
+    /// `(group DN, role)` pairs.
+    pub group_roles: Vec<(String, String)>,
+    pub pool_size: usize,
+    pub negative_cache_ttl: Duration,
+    pub timeout: Duration,
+}
+
+impl LdapConfig {
+    /// Creates a config with default filter, attribute, pool and timeouts.
+    pub fn new(url: &str, base_dn: &str) -> Self {
+        LdapConfig {
+            url: url.to_string(),
+            starttls: false,
+            base_dn: base_dn.to_string(),
+            user_filter: "(uid={username})".to_string(),
+            user_dn_template: None,
+            bind_dn: None,
+            bind_password: None,
+            group_attribute: "memberOf".to_string(),
+            group_roles: Vec::new(),
+            pool_size: 4,
+            negative_cache_ttl: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Reads the configuration from `LDAP_*` environment variables.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if required variables are missing, neither
+    /// `LDAP_BIND_DN` nor `LDAP_USER_DN_TEMPLATE` is set, or the connection
+    /// would not use TLS and `LDAP_ALLOW_INSECURE` is not `true`.
+    pub fn from_env() -> Result<Self, IoError> {
+        let required = |name: &str| {
+            env::var(name).map_err(|_| {
+                error!("{} must be set for the LDAP backend", name);
+                IoError::new(ErrorKind::InvalidInput, format!("{} is not set", name))
+            })
+        };
+
+        let mut config = LdapConfig::new(&required("LDAP_URL")?, &required("LDAP_BASE_DN")?);
+        config.starttls = env::var("LDAP_STARTTLS")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        if let Ok(filter) = env::var("LDAP_USER_FILTER") {
+            config.user_filter = filter;
+        }
+        config.user_dn_template = env::var("LDAP_USER_DN_TEMPLATE").ok();
+        config.bind_dn = env::var("LDAP_BIND_DN").ok();
+        config.bind_password = env::var("LDAP_BIND_PASSWORD").ok();
+        if let Ok(attribute) = env::var("LDAP_GROUP_ATTRIBUTE") {
+            config.group_attribute = attribute;
+        }
+        config.group_roles = parse_group_roles(&env::var("LDAP_GROUP_ROLES").unwrap_or_default())?;
+        config.pool_size = env::var("LDAP_POOL_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(config.pool_size);
+        config.negative_cache_ttl = env::var("LDAP_NEGATIVE_CACHE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(config.negative_cache_ttl);
+        config.timeout = env::var("LDAP_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(config.timeout);
+
+        if config.bind_dn.is_none() && config.user_dn_template.is_none() {
+            error!("Either LDAP_BIND_DN or LDAP_USER_DN_TEMPLATE must be set");
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "LDAP backend needs LDAP_BIND_DN or LDAP_USER_DN_TEMPLATE",
+            ));
+        }
+
+        let insecure_allowed = env::var("LDAP_ALLOW_INSECURE")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+        if !config.url.starts_with("ldaps://") && !config.starttls && !insecure_allowed {
+            error!("LDAP_URL must use ldaps:// or LDAP_STARTTLS=true");
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "LDAP connection would not use TLS",
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parses `LDAP_GROUP_ROLES`: `;`-separated `role:group DN` pairs.
+///
+/// # Errors
+///
+/// Returns an error if an entry has no `:` or an empty role or DN.
+pub fn parse_group_roles(value: &str) -> Result<Vec<(String, String)>, IoError> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once(':') {
+            Some((role, dn)) if !role.trim().is_empty() && !dn.trim().is_empty() => {
+                Ok((dn.trim().to_string(), role.trim().to_string()))
+            }
+            _ => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid LDAP_GROUP_ROLES entry '{}'", entry),
+            )),
+        })
+        .collect()
+}
+
+/// Maps group DNs to roles. DNs are compared case-insensitively.
+pub fn map_groups(groups: &[String], mapping: &[(String, String)]) -> Vec<String> {
+    let mut roles: Vec<String> = Vec::new();
+    for (dn, role) in mapping {
+        if groups.iter().any(|g| g.eq_ignore_ascii_case(dn)) && !roles.contains(role) {
+            roles.push(role.clone());
+        }
+    }
+    roles
+}
+
+/// Authenticates against an LDAP directory.
+pub struct LdapBackend {
+    config: LdapConfig,
+    pool: Mutex<Vec<Ldap>>,
+    failures: Mutex<HashMap<u64, Instant>>,
+    hasher: RandomState,
+}
+
+impl LdapBackend {
+    pub fn new(config: LdapConfig) -> Self {
+        LdapBackend {
+            config,
+            pool: Mutex::new(Vec::new()),
+            failures: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Keyed hash of the credentials, so plaintext passwords aren't kept.
+    fn cache_key(&self, username: &str, password: &str) -> u64 {
+        self.hasher.hash_one((username, password))
+    }
+
+    fn recently_failed(&self, key: u64) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures
+            .get(&key)
+            .map(|at| at.elapsed() < self.config.negative_cache_ttl)
+            .unwrap_or(false)
+    }
+
+    fn remember_failure(&self, key: u64) {
+        let ttl = self.config.negative_cache_ttl;
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, at| at.elapsed() < ttl);
+        failures.insert(key, Instant::now());
+    }
+
+    async fn connect(&self) -> Result<Ldap, AuthError> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.config.timeout)
+            .set_starttls(self.config.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    fn checkin(&self, ldap: Ldap) {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < self.config.pool_size {
+            pool.push(ldap);
+        }
+    }
+
+    /// Binds as `dn`, distinguishing rejected credentials from failures.
+    async fn bind(&self, ldap: &mut Ldap, dn: &str, password: &str) -> Result<(), AuthError> {
+        let result = ldap
+            .with_timeout(self.config.timeout)
+            .simple_bind(dn, password)
+            .await
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+        match result.rc {
+            0 => Ok(()),
+            INVALID_CREDENTIALS => Err(AuthError::InvalidCredentials),
+            rc => Err(AuthError::Unavailable(format!(
+                "bind failed with result code {}",
+                rc
+            ))),
+        }
+    }
+
+    async fn find_user(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+    ) -> Result<Option<SearchEntry>, AuthError> {
+        let filter = self
+            .config
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .with_timeout(self.config.timeout)
+            .search(
+                &self.config.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![self.config.group_attribute.as_str()],
+            )
+            .await
+            .and_then(|result| result.success())
+            .map_err(|e| AuthError::Unavailable(e.to_string()))?;
+        Ok(entries.into_iter().next().map(SearchEntry::construct))
+    }
+
+    fn groups(&self, entry: &SearchEntry) -> Vec<String> {
+        entry
+            .attrs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&self.config.group_attribute))
+            .map(|(_, values)| values.clone())
+            .unwrap_or_default()
+    }
+
+    async fn authenticate_with(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> Result<Principal, AuthError> {
+        let groups = match (&self.config.bind_dn, &self.config.user_dn_template) {
+            (Some(bind_dn), _) => {
+                let bind_password = self.config.bind_password.as_deref().unwrap_or_default();
+                self.bind(ldap, bind_dn, bind_password)
+                    .await
+                    .map_err(|e| match e {
+                        AuthError::InvalidCredentials => {
+                            AuthError::Unavailable("service account bind rejected".to_string())
+                        }
+                        e => e,
+                    })?;
+                let entry = self
+                    .find_user(ldap, username)
+                    .await?
+                    .ok_or(AuthError::InvalidCredentials)?;
+                self.bind(ldap, &entry.dn, password).await?;
+                self.groups(&entry)
+            }
+            (None, Some(template)) => {
+                let dn = template.replace("{username}", &dn_escape(username));
+                self.bind(ldap, &dn, password).await?;
+                self.find_user(ldap, username)
+                    .await?
+                    .map(|entry| self.groups(&entry))
+                    .unwrap_or_default()
+            }
+            (None, None) => {
+                return Err(AuthError::Unavailable(
+                    "neither bind DN nor user DN template configured".to_string(),
+                ))
+            }
+        };
+
+        Ok(Principal {
+            username: username.to_string(),
+            roles: map_groups(&groups, &self.config.group_roles),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Principal, AuthError> {
+        // An empty password would be an unauthenticated bind, which succeeds
+        if username.is_empty() || password.is_empty() {
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        let key = self.cache_key(username, password);
+        if self.recently_failed(key) {
+            debug!("Rejecting '{}' from the negative cache", username);
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        let pooled = self.pool.lock().unwrap().pop();
+        let result = match pooled {
+            Some(mut ldap) => match self.authenticate_with(&mut ldap, username, password).await {
+                // The pooled connection may have been closed by the server
+                Err(AuthError::Unavailable(reason)) => {
+                    debug!("Pooled LDAP connection failed ({}), reconnecting", reason);
+                    let mut ldap = self.connect().await?;
+                    let result = self.authenticate_with(&mut ldap, username, password).await;
+                    (ldap, result)
+                }
+                result => (ldap, result),
+            },
+            None => {
+                let mut ldap = self.connect().await?;
+                let result = self.authenticate_with(&mut ldap, username, password).await;
+                (ldap, result)
+            }
+        };
+
+        match result {
+            (ldap, Ok(principal)) => {
+                self.checkin(ldap);
+                Ok(principal)
+            }
+            (ldap, Err(AuthError::InvalidCredentials)) => {
+                self.checkin(ldap);
+                self.remember_failure(key);
+                Err(AuthError::InvalidCredentials)
+            }
+            (_, Err(e)) => {
+                warn!("LDAP authentication for '{}' failed: {}", username, e);
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Macaroon bearer tokens, only compiled with the `macaroon` feature.
+//!
+//! A macaroon is a bearer token whose holder can restrict it further
+//! without contacting the server: every caveat appended to it chains the
+//! HMAC signature, so caveats cannot be removed. Tokens are minted with
+//! `MACAROON_ROOT_KEY` by `POST /auth/macaroon/create` (admin only) and
+//! narrowed by anyone through `POST /auth/macaroon/attenuate`.
+//!
+//! Supported first-party caveats:
+//!
+//! * `time < 2025-01-01T00:00:00Z` - valid until the instant (a bare date
+//!   means midnight UTC).
+//! * `time > 2024-06-01T00:00:00Z` - valid from the instant.
+//! * `route = /hello` - only for that path and paths below it.
+//! * `method = GET` - only for that HTTP method.
+//!
+//! A macaroon is accepted when its signature verifies and every caveat is
+//! satisfied by the request. Time caveats allow for `CLOCK_SKEW_SECS` of
+//! clock drift (see [`crate::clock`]). Unknown caveats are never satisfied, and
+//! third-party caveats are rejected since no discharge macaroons are
+//! accepted.
+
+use std::env;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Once;
+
+use ::macaroon::{ByteString, Caveat as RawCaveat, Format, Macaroon, MacaroonKey, Verifier};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::audit;
+use crate::clock::Clock;
+use crate::error::ApiError;
+use crate::middleware::api_key::{AdminKey, ApiKeyAuth};
+use crate::middleware::request_id::CorrelationChain;
+use crate::route_meta::{self, DescribedRoute, RouteMetadata};
+
+/// Shortest accepted `MACAROON_ROOT_KEY`.
+pub const MIN_ROOT_KEY_BYTES: usize = 32;
+
+/// Location recorded in minted macaroons.
+const LOCATION: &str = "secure-actix-web-server";
+
+/// A first-party caveat understood by this server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Caveat {
+    /// `time < ...`
+    Before(DateTime<Utc>),
+    /// `time > ...`
+    After(DateTime<Utc>),
+    /// `route = ...`
+    Route(String),
+    /// `method = ...`
+    Method(String),
+}
+
+impl Caveat {
+    /// Parses a caveat predicate such as `route = /hello`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem for unknown or malformed
+    /// predicates.
+    pub fn parse(predicate: &str) -> Result<Caveat, String> {
+        let mut parts = predicate.split_whitespace();
+        let (Some(field), Some(op), Some(value), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("malformed caveat '{}'", predicate));
+        };
+        match (field, op) {
+            ("time", "<") => Ok(Caveat::Before(parse_time(value)?)),
+            ("time", ">") => Ok(Caveat::After(parse_time(value)?)),
+            ("route", "=") if value.starts_with('/') => Ok(Caveat::Route(value.to_string())),
+            ("method", "=") => Ok(Caveat::Method(value.to_ascii_uppercase())),
+            _ => Err(format!("unsupported caveat '{}'", predicate)),
+        }
+    }
+
+    /// Returns whether a request for `method` and `path` satisfies the
+    /// caveat at the time of `clock`, allowing for its skew.
+    pub fn is_satisfied(&self, method: &str, path: &str, clock: &Clock) -> bool {
+        match self {
+            Caveat::Before(t) => !clock.has_expired(*t),
+            Caveat::After(t) => !clock.is_before(*t),
+            Caveat::Route(route) => {
+                let route = route.trim_end_matches('/');
+                path.strip_prefix(route)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+            Caveat::Method(m) => method.eq_ignore_ascii_case(m),
+        }
+    }
+}
+
+impl fmt::Display for Caveat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Caveat::Before(t) => write!(f, "time < {}", t.to_rfc3339()),
+            Caveat::After(t) => write!(f, "time > {}", t.to_rfc3339()),
+            Caveat::Route(route) => write!(f, "route = {}", route),
+            Caveat::Method(method) => write!(f, "method = {}", method),
+        }
+    }
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|t| t.and_utc())
+        .ok_or_else(|| format!("invalid time '{}'", value))
+}
+
+/// Why a macaroon was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MacaroonError {
+    /// Not a macaroon, or its signature does not verify.
+    Invalid,
+    /// A `time` caveat no longer (or not yet) holds.
+    Expired,
+    /// A caveat restricts the token to other requests.
+    NotPermitted(String),
+}
+
+impl From<MacaroonError> for ApiError {
+    fn from(e: MacaroonError) -> Self {
+        match e {
+            MacaroonError::Invalid => ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_macaroon",
+                "Invalid macaroon",
+            ),
+            MacaroonError::Expired => ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "macaroon_expired",
+                "The macaroon is expired or not yet valid",
+            ),
+            MacaroonError::NotPermitted(caveat) => ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "macaroon_not_permitted",
+                format!("The macaroon does not allow this request ({})", caveat),
+            ),
+        }
+    }
+}
+
+fn bytes_to_string(bytes: &ByteString) -> String {
+    String::from_utf8_lossy(&bytes.0).into_owned()
+}
+
+fn serialize(macaroon: &Macaroon) -> Result<String, ApiError> {
+    macaroon.serialize(Format::V2).map_err(|e| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Failed to serialize macaroon: {:?}", e),
+        )
+    })
+}
+
+fn invalid_caveat(message: String) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_caveat", message)
+}
+
+/// Mints and verifies macaroons with the root key.
+pub struct MacaroonAuthority {
+    key: MacaroonKey,
+}
+
+impl MacaroonAuthority {
+    /// Creates an authority for `root_key`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the key is shorter than [`MIN_ROOT_KEY_BYTES`].
+    pub fn new(root_key: &[u8]) -> Result<Self, IoError> {
+        if root_key.len() < MIN_ROOT_KEY_BYTES {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "MACAROON_ROOT_KEY must be at least {} bytes",
+                    MIN_ROOT_KEY_BYTES
+                ),
+            ));
+        }
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            ::macaroon::initialize().expect("Failed to initialize the macaroon crypto library")
+        });
+        Ok(MacaroonAuthority {
+            key: MacaroonKey::generate(root_key),
+        })
+    }
+
+    /// Reads the base64-encoded `MACAROON_ROOT_KEY`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<MacaroonAuthority>, IoError>` - The authority, `None` if MACAROON_ROOT_KEY is not set, or an IoError if it is malformed or too short.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let Ok(encoded) = env::var("MACAROON_ROOT_KEY") else {
+            return Ok(None);
+        };
+        let key = STANDARD.decode(encoded.trim()).map_err(|e| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("MACAROON_ROOT_KEY is not valid base64: {}", e),
+            )
+        })?;
+        MacaroonAuthority::new(&key).map(Some)
+    }
+
+    /// Mints a macaroon for `identifier` restricted by `caveats`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a 400 ApiError if a caveat is not supported.
+    pub fn mint(&self, identifier: &str, caveats: &[String]) -> Result<String, ApiError> {
+        let mut macaroon = Macaroon::create(Some(LOCATION.into()), &self.key, identifier.into())
+            .map_err(|e| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_identifier",
+                    format!("Cannot create macaroon: {:?}", e),
+                )
+            })?;
+        for caveat in caveats {
+            let caveat = Caveat::parse(caveat).map_err(invalid_caveat)?;
+            macaroon.add_first_party_caveat(caveat.to_string().into());
+        }
+        serialize(&macaroon)
+    }
+
+    /// Appends `caveat` to the serialized `macaroon`. Needs no key, so any
+    /// holder can attenuate a token.
+    ///
+    /// # Errors
+    ///
+    /// Returns a 400 ApiError if the macaroon cannot be decoded or the
+    /// caveat is not supported.
+    pub fn attenuate(macaroon: &str, caveat: &str) -> Result<String, ApiError> {
+        let mut macaroon = Macaroon::deserialize(macaroon.trim()).map_err(|_| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_macaroon",
+                "Invalid macaroon",
+            )
+        })?;
+        let caveat = Caveat::parse(caveat).map_err(invalid_caveat)?;
+        macaroon.add_first_party_caveat(caveat.to_string().into());
+        serialize(&macaroon)
+    }
+
+    /// Verifies a serialized macaroon for a request for `method` and `path`
+    /// at the time of `clock`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String, MacaroonError>` - The macaroon identifier, or why it was rejected.
+    pub fn verify(
+        &self,
+        macaroon: &str,
+        method: &str,
+        path: &str,
+        clock: &Clock,
+    ) -> Result<String, MacaroonError> {
+        let macaroon =
+            Macaroon::deserialize(macaroon.trim()).map_err(|_| MacaroonError::Invalid)?;
+        if !macaroon.third_party_caveats().is_empty() {
+            return Err(MacaroonError::Invalid);
+        }
+
+        // Each caveat is evaluated here and then satisfied exactly, so the
+        // verifier only has to check the signature chain
+        let mut verifier = Verifier::default();
+        let mut failure = None;
+        for raw in macaroon.first_party_caveats() {
+            let RawCaveat::FirstParty(first_party) = raw else {
+                return Err(MacaroonError::Invalid);
+            };
+            let predicate = first_party.predicate();
+            let text = bytes_to_string(&predicate);
+            match Caveat::parse(&text) {
+                Ok(caveat) if caveat.is_satisfied(method, path, clock) => {
+                    verifier.satisfy_exact(predicate)
+                }
+                Ok(Caveat::Before(_) | Caveat::After(_)) => {
+                    failure.get_or_insert(MacaroonError::Expired);
+                }
+                _ => {
+                    failure.get_or_insert(MacaroonError::NotPermitted(text));
+                }
+            }
+        }
+
+        // A forged token must not learn which of its caveats failed
+        verifier
+            .verify(&macaroon, &self.key, Vec::new())
+            .map_err(|_| MacaroonError::Invalid)?;
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(bytes_to_string(&macaroon.identifier())),
+        }
+    }
+}
+
+/// Body of `POST /auth/macaroon/create`.
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    pub identifier: String,
+    #[serde(default)]
+    pub caveats: Vec<String>,
+}
+
+/// Body of `POST /auth/macaroon/attenuate`.
+#[derive(Deserialize)]
+pub struct AttenuateRequest {
+    pub macaroon: String,
+    pub caveat: String,
+}
+
+/// Handler for `POST /auth/macaroon/create`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - `{"macaroon": "..."}`, or 400 for an empty identifier or unsupported caveat.
+pub async fn create(
+    authority: web::Data<MacaroonAuthority>,
+    chain: CorrelationChain,
+    body: web::Json<CreateRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    if body.identifier.trim().is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_identifier",
+            "identifier must not be empty",
+        )
+        .with_field("identifier", "must not be empty"));
+    }
+    let macaroon = authority.mint(&body.identifier, &body.caveats)?;
+    audit::record_for(
+        &chain,
+        "macaroon_created",
+        json!({ "identifier": body.identifier, "caveats": body.caveats }),
+    );
+    Ok(HttpResponse::Ok().json(json!({ "macaroon": macaroon })))
+}
+
+/// Handler for `POST /auth/macaroon/attenuate`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - `{"macaroon": "..."}` with the caveat appended, or 400 for an undecodable macaroon or unsupported caveat.
+pub async fn attenuate(body: web::Json<AttenuateRequest>) -> Result<HttpResponse, ApiError> {
+    let macaroon = MacaroonAuthority::attenuate(&body.macaroon, &body.caveat)?;
+    Ok(HttpResponse::Ok().json(json!({ "macaroon": macaroon })))
+}
+
+/// Registers the authority and the `/auth/macaroon` routes. Creating
+/// macaroons requires the admin API key.
+pub fn configure(
+    authority: web::Data<MacaroonAuthority>,
+    admin_key: AdminKey,
+) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        route_meta::record(
+            "POST",
+            "/auth/macaroon/create",
+            RouteMetadata::new("Mint a macaroon")
+                .auth_required()
+                .middleware("ApiKeyAuth"),
+        );
+        cfg.app_data(authority)
+            .service(
+                web::resource("/auth/macaroon/create")
+                    .wrap(ApiKeyAuth::shared(admin_key))
+                    .route(web::post().to(create)),
+            )
+            .described_route(
+                Method::POST,
+                "/auth/macaroon/attenuate",
+                attenuate,
+                RouteMetadata::new("Append a caveat to a macaroon"),
+            );
+    }
+}
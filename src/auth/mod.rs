@@ -0,0 +1,223 @@
+//! Credential authentication for the login endpoint.
+//!
+//! Credentials are checked by an [`AuthBackend`]. Two implementations exist:
+//!
+//! * [`file::FileBackend`] reads users and argon2 password hashes from a file.
+//! * [`ldap::LdapBackend`] (with the `ldap` feature) validates credentials
+//!   against LDAP / Active Directory and maps groups to roles.
+//!
+//! `AUTH_BACKEND` selects the backend (`file` or `ldap`); `POST /auth/login`
+//! and the `GET /protected` example route are only registered when a backend
+//! is configured. Routes wrapped in
+//! [`BasicAuth`](crate::middleware::basic_auth::BasicAuth) receive the caller
+//! through the [`Principal`] extractor.
+//!
+//! Users of backends that can change passwords can reset a forgotten one
+//! through the [`reset`] endpoints. With the `webauthn` feature, users can
+//! also enroll passkeys and log in with them; see `webauthn`. With the
+//! `macaroon` feature, callers can instead present attenuatable bearer
+//! tokens; see `macaroon`.
+//!
+//! [`secret_policy`] checks the strength of the admin API key and of the
+//! password hashes at startup.
+
+pub mod file;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+#[cfg(feature = "macaroon")]
+pub mod macaroon;
+pub mod reset;
+pub mod secret_policy;
+#[cfg(feature = "webauthn")]
+pub mod webauthn;
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::io::Error as IoError;
+use std::sync::Arc;
+
+use crate::audit;
+use crate::error::ApiError;
+use crate::middleware::request_id::CorrelationChain;
+
+/// An authenticated user.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub username: String,
+    pub roles: Vec<String>,
+}
+
+impl FromRequest for Principal {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Principal>().cloned().ok_or_else(|| {
+            ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Authentication required",
+            )
+        }))
+    }
+}
+
+/// Why authentication failed.
+#[derive(Debug)]
+pub enum AuthError {
+    /// Unknown user or wrong password. Deliberately not distinguished.
+    InvalidCredentials,
+    /// The backend could not be reached or failed unexpectedly.
+    Unavailable(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidCredentials => write!(f, "invalid credentials"),
+            AuthError::Unavailable(reason) => {
+                write!(f, "authentication backend unavailable: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidCredentials => ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_credentials",
+                "Invalid username or password",
+            ),
+            AuthError::Unavailable(_) => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "auth_unavailable",
+                "Authentication is temporarily unavailable",
+            ),
+        }
+    }
+}
+
+/// A source of truth for user credentials.
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// Verifies `password` for `username`.
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Principal, AuthError>;
+
+    /// Returns a value that changes whenever the password of `username`
+    /// changes, or `None` if the user is unknown or the backend cannot
+    /// change passwords.
+    async fn password_stamp(&self, _username: &str) -> Option<String> {
+        None
+    }
+
+    /// Replaces the password hash of `username`.
+    async fn set_password_hash(&self, _username: &str, _hash: String) -> Result<(), AuthError> {
+        Err(AuthError::Unavailable(
+            "this backend cannot change passwords".to_string(),
+        ))
+    }
+}
+
+/// Builds the backend selected by `AUTH_BACKEND`.
+///
+/// # Returns
+///
+/// * `Result<Option<Arc<dyn AuthBackend>>, IoError>` - The backend, `None` if authentication is not configured, or an IoError if the configuration is invalid.
+pub fn backend_from_env() -> Result<Option<Arc<dyn AuthBackend>>, IoError> {
+    match env::var("AUTH_BACKEND").as_deref() {
+        Ok("ldap") => {
+            #[cfg(feature = "ldap")]
+            {
+                info!("Using LDAP authentication backend");
+                Ok(Some(Arc::new(ldap::LdapBackend::new(
+                    ldap::LdapConfig::from_env()?,
+                ))))
+            }
+            #[cfg(not(feature = "ldap"))]
+            {
+                Err(IoError::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "AUTH_BACKEND=ldap requires the `ldap` feature",
+                ))
+            }
+        }
+        Ok("file") | Err(_) => match env::var("AUTH_USERS_FILE") {
+            Ok(path) => {
+                info!("Using file authentication backend: {}", path);
+                Ok(Some(Arc::new(file::FileBackend::load(&path)?)))
+            }
+            Err(_) => {
+                warn!("AUTH_USERS_FILE is not set; login is disabled");
+                Ok(None)
+            }
+        },
+        Ok(other) => Err(IoError::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown AUTH_BACKEND '{}'", other),
+        )),
+    }
+}
+
+/// Body of a login request.
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// Handler for `POST /auth/login`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the principal as JSON, 401 for bad credentials, or 503 if the backend is unavailable.
+pub async fn login(
+    backend: web::Data<dyn AuthBackend>,
+    chain: CorrelationChain,
+    body: web::Json<LoginRequest>,
+) -> Result<HttpResponse, ApiError> {
+    match backend.authenticate(&body.username, &body.password).await {
+        Ok(principal) => {
+            info!("Login succeeded for '{}'", principal.username);
+            audit::record_for(
+                &chain,
+                "login_succeeded",
+                json!({ "username": principal.username, "roles": principal.roles }),
+            );
+            Ok(HttpResponse::Ok().json(principal))
+        }
+        Err(e) => {
+            warn!("Login failed for '{}': {}", body.username, e);
+            audit::record_for(
+                &chain,
+                "login_failed",
+                json!({ "username": body.username, "reason": e.to_string() }),
+            );
+            Err(e.into())
+        }
+    }
+}
+
+/// Handler for `GET /protected`, an example of a route behind authentication.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the authenticated caller's identity as JSON.
+pub async fn protected(principal: Principal) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "username": principal.username,
+        "roles": principal.roles,
+        "auth_method": "basic",
+    }))
+}
@@ -0,0 +1,519 @@
+//! Password reset with signed, single-use tokens.
+//!
+//! * `POST /auth/password-reset/request` with `{"username": "..."}` always
+//!   answers 202, whether or not the user exists. For a known user, a token
+//!   valid for `RESET_TOKEN_TTL_MINS` is handed to the [`ResetNotifier`]
+//!   (the `PASSWORD_RESET_WEBHOOK_URL` webhook, or else an email through
+//!   the [mail queue](crate::mail)), which delivers it to the user out of
+//!   band.
+//! * `POST /auth/password-reset/confirm` with `{"token": "...",
+//!   "new_password": "..."}` sets the new password.
+//!
+//! Tokens are HMAC-SHA256 signed with a key derived from the
+//! [`MasterKey`] and are never stored. Each carries the user's
+//! [password stamp](super::AuthBackend::password_stamp), so every
+//! outstanding token dies once the password changes, and a nonce recorded
+//! when the token is used, so it cannot be submitted twice. Basic
+//! authentication checks the password on every request, so the old password
+//! stops working immediately; the server keeps no other sessions.
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{info, warn};
+use reqwest::Url;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::file::hash_password;
+use super::AuthBackend;
+use crate::audit;
+use crate::clock::Clock;
+use crate::error::ApiError;
+use crate::mail::{self, templates, MailQueue};
+use crate::master_key::MasterKey;
+use crate::middleware::request_id::CorrelationChain;
+use crate::outbound::{OutboundClient, UrlPolicy};
+use crate::throttle::{self, Throttle};
+use crate::util::rate_limit::RateLimiter;
+use crate::util::real_ip::RealIp;
+
+/// HKDF purpose label of the token signing key.
+const KEY_PURPOSE: &str = "password-reset-token";
+
+/// Default for `RESET_TOKEN_TTL_MINS`.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Delivers reset tokens to users.
+#[async_trait]
+pub trait ResetNotifier: Send + Sync {
+    async fn send(&self, username: &str, token: &str, expires_at: i64) -> Result<(), String>;
+}
+
+/// Posts `{"username", "token", "expires_at"}` to a webhook, which is
+/// expected to email the user.
+pub struct WebhookNotifier {
+    client: OutboundClient,
+    url: Url,
+}
+
+impl WebhookNotifier {
+    /// Creates a notifier posting to `url`. The webhook is operator
+    /// configured, so its host and port are allowed by the SSRF policy.
+    pub fn new(url: Url, policy: UrlPolicy) -> Self {
+        WebhookNotifier {
+            client: OutboundClient::new(policy.allow_url(&url))
+                .with_timeout(Duration::from_secs(10)),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl ResetNotifier for WebhookNotifier {
+    async fn send(&self, username: &str, token: &str, expires_at: i64) -> Result<(), String> {
+        let body = json!({ "username": username, "token": token, "expires_at": expires_at });
+        let response = self
+            .client
+            .post(
+                self.url.as_str(),
+                "application/json",
+                body.to_string().into_bytes(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("webhook answered {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// Emails the token to the user. Usernames that are not addresses are
+/// combined with `domain` (`MAIL_USER_DOMAIN`), when set.
+pub struct EmailNotifier {
+    queue: MailQueue,
+    domain: Option<String>,
+}
+
+impl EmailNotifier {
+    /// Creates a notifier queueing messages on `queue`.
+    pub fn new(queue: MailQueue, domain: Option<String>) -> Self {
+        EmailNotifier { queue, domain }
+    }
+
+    /// Returns the address `username` is reached at, if any.
+    pub fn address(&self, username: &str) -> Option<String> {
+        if templates::is_address(username) {
+            return Some(username.to_string());
+        }
+        let address = format!("{}@{}", username, self.domain.as_deref()?);
+        templates::is_address(&address).then_some(address)
+    }
+}
+
+#[async_trait]
+impl ResetNotifier for EmailNotifier {
+    async fn send(&self, username: &str, token: &str, expires_at: i64) -> Result<(), String> {
+        let to = self
+            .address(username)
+            .ok_or_else(|| format!("no email address for '{}'", username))?;
+        let email = templates::password_reset(&to, username, token, expires_at);
+        if !self.queue.enqueue(email) {
+            return Err("mail queue is full".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Requirements for new passwords.
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 12,
+            max_length: 256,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Checks `password` for `username`.
+    ///
+    /// # Errors
+    ///
+    /// Returns why the password is rejected.
+    pub fn check(&self, username: &str, password: &str) -> Result<(), String> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(format!("must be at least {} characters", self.min_length));
+        }
+        if length > self.max_length {
+            return Err(format!("must be at most {} characters", self.max_length));
+        }
+        if password.to_lowercase().contains(&username.to_lowercase()) {
+            return Err("must not contain the username".to_string());
+        }
+        let first = password.chars().next();
+        if password.chars().all(|c| Some(c) == first) {
+            return Err("must not repeat a single character".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The verified contents of a token.
+struct Claims {
+    username: String,
+    expires_at: i64,
+    nonce: String,
+    stamp: String,
+}
+
+/// Issues and redeems password reset tokens.
+pub struct PasswordReset {
+    backend: Arc<dyn AuthBackend>,
+    key: hmac::Key,
+    ttl: Duration,
+    policy: PasswordPolicy,
+    notifier: Option<Arc<dyn ResetNotifier>>,
+    clock: Clock,
+    /// Nonces of redeemed tokens, until the tokens expire.
+    used: Mutex<HashMap<String, i64>>,
+    client_limiter: RateLimiter,
+    user_limiter: RateLimiter,
+    rng: SystemRandom,
+}
+
+impl PasswordReset {
+    /// Creates the reset flow for `backend`, signing tokens with a key
+    /// derived from `master_key`.
+    pub fn new(backend: Arc<dyn AuthBackend>, master_key: &MasterKey, ttl: Duration) -> Self {
+        PasswordReset {
+            backend,
+            key: master_key.hmac_key(KEY_PURPOSE),
+            ttl,
+            policy: PasswordPolicy::default(),
+            notifier: None,
+            clock: Clock::default(),
+            used: Mutex::new(HashMap::new()),
+            // 10 requests per client, then one a minute
+            client_limiter: RateLimiter::per_interval(10, Duration::from_secs(60)),
+            // 3 tokens per user, then one every 15 minutes
+            user_limiter: RateLimiter::per_interval(3, Duration::from_secs(15 * 60)),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Delivers tokens through `notifier`.
+    pub fn with_notifier(mut self, notifier: Arc<dyn ResetNotifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Issues and checks tokens against `clock`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replaces the password policy.
+    pub fn with_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reads `RESET_TOKEN_TTL_MINS`, `PASSWORD_MIN_LENGTH` and
+    /// `PASSWORD_RESET_WEBHOOK_URL`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PasswordReset, IoError>` - The reset flow, or an IoError if the webhook URL or outbound policy is invalid.
+    pub fn from_env(
+        backend: Arc<dyn AuthBackend>,
+        master_key: &MasterKey,
+    ) -> Result<Self, IoError> {
+        let ttl = env::var("RESET_TOKEN_TTL_MINS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|mins| Duration::from_secs(mins * 60))
+            .unwrap_or(DEFAULT_TOKEN_TTL);
+        let mut policy = PasswordPolicy::default();
+        if let Some(min) = env::var("PASSWORD_MIN_LENGTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            policy.min_length = min;
+        }
+        let reset = PasswordReset::new(backend, master_key, ttl).with_policy(policy);
+
+        match env::var("PASSWORD_RESET_WEBHOOK_URL") {
+            Ok(url) => {
+                let url = Url::parse(&url).map_err(|e| {
+                    IoError::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid PASSWORD_RESET_WEBHOOK_URL: {}", e),
+                    )
+                })?;
+                info!("Password reset tokens are delivered to {}", url);
+                Ok(
+                    reset
+                        .with_notifier(Arc::new(WebhookNotifier::new(url, UrlPolicy::from_env()?))),
+                )
+            }
+            Err(_) => match mail::queue() {
+                Some(queue) => {
+                    info!("Password reset tokens are delivered by email");
+                    let domain = env::var("MAIL_USER_DOMAIN").ok().filter(|d| !d.is_empty());
+                    Ok(reset.with_notifier(Arc::new(EmailNotifier::new(queue.clone(), domain))))
+                }
+                None => {
+                    warn!(
+                        "Neither PASSWORD_RESET_WEBHOOK_URL nor email is configured; reset tokens cannot be delivered"
+                    );
+                    Ok(reset)
+                }
+            },
+        }
+    }
+
+    /// Issues a token for `username` valid from now.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The token, or `None` if the user is unknown or cannot change passwords.
+    pub async fn issue(&self, username: &str) -> Option<String> {
+        self.issue_at(username, self.clock.timestamp()).await
+    }
+
+    /// Like [`issue`](Self::issue), as if issued at `issued_at` (Unix
+    /// seconds).
+    pub async fn issue_at(&self, username: &str, issued_at: i64) -> Option<String> {
+        self.mint(username, issued_at).await.map(|(token, _)| token)
+    }
+
+    /// Signs a token for `username`, returning it with its expiry.
+    async fn mint(&self, username: &str, issued_at: i64) -> Option<(String, i64)> {
+        let stamp = self.backend.password_stamp(username).await?;
+        let mut nonce = [0u8; 16];
+        self.rng.fill(&mut nonce).ok()?;
+        let nonce: String = nonce.iter().map(|b| format!("{:02x}", b)).collect();
+        let expires_at = issued_at + self.ttl.as_secs() as i64;
+
+        let payload = format!("{}\n{}\n{}\n{}", username, expires_at, nonce, stamp);
+        let signature = hmac::sign(&self.key, payload.as_bytes());
+        let token = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(payload),
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        );
+        Some((token, expires_at))
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, ApiError> {
+        let invalid = || invalid_token("The reset token is invalid");
+        let (payload, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        hmac::verify(&self.key, &payload, &signature).map_err(|_| invalid())?;
+
+        let payload = String::from_utf8(payload).map_err(|_| invalid())?;
+        let mut fields = payload.splitn(4, '\n');
+        let (Some(username), Some(expires_at), Some(nonce), Some(stamp)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let expires_at: i64 = expires_at.parse().map_err(|_| invalid())?;
+        if self.clock.has_expired_at(expires_at) {
+            return Err(invalid_token("The reset token has expired"));
+        }
+        Ok(Claims {
+            username: username.to_string(),
+            expires_at,
+            nonce: nonce.to_string(),
+            stamp: stamp.to_string(),
+        })
+    }
+
+    /// Redeems `token`, setting `new_password` for its user.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<String, ApiError>` - The user whose password changed, 400 `invalid_token` for a forged, expired or used token, 400 `weak_password` if the policy rejects the password, or 503 if the backend fails.
+    pub async fn confirm(&self, token: &str, new_password: &str) -> Result<String, ApiError> {
+        let claims = self.verify(token)?;
+        if self.backend.password_stamp(&claims.username).await.as_ref() != Some(&claims.stamp) {
+            return Err(used_token());
+        }
+        // Checked before the token is consumed, so the user can try again
+        self.policy
+            .check(&claims.username, new_password)
+            .map_err(|reason| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "weak_password",
+                    "The new password does not meet the password policy",
+                )
+                .with_field("new_password", reason)
+            })?;
+
+        {
+            let mut used = self.used.lock().unwrap();
+            // Kept for as long as the token is accepted
+            used.retain(|_, expires_at| !self.clock.has_expired_at(*expires_at));
+            if used
+                .insert(claims.nonce.clone(), claims.expires_at)
+                .is_some()
+            {
+                return Err(used_token());
+            }
+        }
+
+        let password = new_password.to_string();
+        let hash = actix_web::rt::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .map_err(|_| unavailable())?;
+        if let Err(e) = self.backend.set_password_hash(&claims.username, hash).await {
+            warn!("Failed to reset password of '{}': {}", claims.username, e);
+            // Nothing changed, so the token may be used again
+            self.used.lock().unwrap().remove(&claims.nonce);
+            return Err(unavailable());
+        }
+        Ok(claims.username)
+    }
+}
+
+fn invalid_token(message: &str) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_token", message)
+}
+
+fn used_token() -> ApiError {
+    invalid_token("The reset token has already been used")
+}
+
+fn unavailable() -> ApiError {
+    ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "reset_unavailable",
+        "The password could not be changed, please try again later",
+    )
+}
+
+/// Body of `POST /auth/password-reset/request`.
+#[derive(Deserialize)]
+pub struct ResetRequest {
+    pub username: String,
+}
+
+/// Body of `POST /auth/password-reset/confirm`.
+#[derive(Deserialize)]
+pub struct ResetConfirm {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// Handler for `POST /auth/password-reset/request`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 202 Accepted whether or not the user exists, or 429 Too Many Requests when the client asks too often.
+pub async fn request_reset(
+    req: HttpRequest,
+    reset: web::Data<PasswordReset>,
+    client: RealIp,
+    chain: CorrelationChain,
+    body: web::Json<ResetRequest>,
+) -> HttpResponse {
+    let client_key = client
+        .ip()
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    if let Err(retry_after) = reset.client_limiter.check(&client_key) {
+        return throttle::respond(
+            &req,
+            Throttle::RateLimited,
+            ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "Too many password reset requests",
+            ),
+            retry_after,
+        );
+    }
+
+    let username = body.into_inner().username;
+    audit::record_for(
+        &chain,
+        "password_reset_requested",
+        json!({ "username": username, "client": client_key }),
+    );
+
+    // Issued and delivered in the background so the response time does not
+    // reveal whether the user exists
+    if reset.user_limiter.check(&username).is_ok() {
+        let reset = reset.into_inner();
+        actix_web::rt::spawn(async move {
+            let issued_at = reset.clock.timestamp();
+            let Some((token, expires_at)) = reset.mint(&username, issued_at).await else {
+                return;
+            };
+            let Some(notifier) = &reset.notifier else {
+                return;
+            };
+            if let Err(e) = notifier.send(&username, &token, expires_at).await {
+                warn!("Failed to deliver password reset token: {}", e);
+            }
+        });
+    }
+
+    HttpResponse::Accepted().json(json!({
+        "status": "accepted",
+        "message": "If the account exists, a password reset link has been sent",
+    }))
+}
+
+/// Handler for `POST /auth/password-reset/confirm`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK once the password has changed; see [`PasswordReset::confirm`] for errors.
+pub async fn confirm_reset(
+    reset: web::Data<PasswordReset>,
+    chain: CorrelationChain,
+    body: web::Json<ResetConfirm>,
+) -> Result<HttpResponse, ApiError> {
+    match reset.confirm(&body.token, &body.new_password).await {
+        Ok(username) => {
+            info!("Password reset for '{}'", username);
+            audit::record_for(
+                &chain,
+                "password_reset_completed",
+                json!({ "username": username }),
+            );
+            Ok(HttpResponse::Ok().json(json!({ "status": "password_changed" })))
+        }
+        Err(e) => {
+            audit::record_for(
+                &chain,
+                "password_reset_rejected",
+                json!({ "reason": e.message() }),
+            );
+            Err(e)
+        }
+    }
+}
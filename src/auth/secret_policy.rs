@@ -0,0 +1,311 @@
+//! Startup checks of the strength of administrative secrets.
+//!
+//! Three secrets are checked before the server starts:
+//!
+//! * The admin API key, whose entropy is estimated from the character
+//!   classes it uses and its length, discounting repeated and sequential
+//!   characters; keys containing a common word such as `password` or
+//!   `changeme` are weak whatever their length.
+//! * The argon2 hashes of the users file, against a minimum memory and
+//!   iteration cost; only argon2id is accepted.
+//! * `ADMIN_PASSWORD`, which the server never reads: a plaintext password
+//!   left in the environment is reported, and a hash there is checked like
+//!   the users file.
+//!
+//! `ADMIN_SECRET_POLICY` sets what happens to weak secrets: `warn` logs
+//! each of them, `strict` refuses to start and `off` skips the checks. It
+//! defaults to `strict` under `APP_ENV=prod` and `warn` otherwise.
+
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::str::FromStr;
+
+use argon2::password_hash::PasswordHash;
+use log::warn;
+
+use crate::middleware::api_key::AdminKey;
+use crate::tls_keylog::is_production;
+
+/// Default for `ADMIN_KEY_MIN_ENTROPY_BITS`.
+pub const DEFAULT_MIN_KEY_ENTROPY_BITS: u32 = 64;
+
+/// Default for `ADMIN_HASH_MIN_MEMORY_KIB`, the argon2id default.
+pub const DEFAULT_MIN_HASH_MEMORY_KIB: u32 = 19 * 1024;
+
+/// Default for `ADMIN_HASH_MIN_ITERATIONS`, the argon2id default.
+pub const DEFAULT_MIN_HASH_ITERATIONS: u32 = 2;
+
+/// Words that make a secret weak wherever they appear in it.
+const COMMON_WORDS: &[&str] = &[
+    "password", "passwd", "changeme", "letmein", "qwerty", "default", "example", "123456",
+];
+
+/// What to do about weak secrets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    /// Skip the checks.
+    Off,
+    /// Log each weak secret.
+    Warn,
+    /// Refuse to start.
+    Strict,
+}
+
+impl FromStr for Strictness {
+    type Err = IoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Strictness::Off),
+            "warn" => Ok(Strictness::Warn),
+            "strict" => Ok(Strictness::Strict),
+            _ => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "ADMIN_SECRET_POLICY must be off, warn or strict, got '{}'",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// Minimum strength of administrative secrets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecretPolicy {
+    pub strictness: Strictness,
+    pub min_key_entropy_bits: u32,
+    pub min_hash_memory_kib: u32,
+    pub min_hash_iterations: u32,
+}
+
+impl Default for SecretPolicy {
+    fn default() -> Self {
+        SecretPolicy {
+            strictness: Strictness::Warn,
+            min_key_entropy_bits: DEFAULT_MIN_KEY_ENTROPY_BITS,
+            min_hash_memory_kib: DEFAULT_MIN_HASH_MEMORY_KIB,
+            min_hash_iterations: DEFAULT_MIN_HASH_ITERATIONS,
+        }
+    }
+}
+
+impl SecretPolicy {
+    /// Reads `ADMIN_SECRET_POLICY`, `ADMIN_KEY_MIN_ENTROPY_BITS`,
+    /// `ADMIN_HASH_MIN_MEMORY_KIB` and `ADMIN_HASH_MIN_ITERATIONS`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if one of them is invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        let defaults = SecretPolicy::default();
+        let strictness = match env::var("ADMIN_SECRET_POLICY") {
+            Ok(value) => value.parse()?,
+            Err(_) if is_production() => Strictness::Strict,
+            Err(_) => defaults.strictness,
+        };
+        Ok(SecretPolicy {
+            strictness,
+            min_key_entropy_bits: number_from_env(
+                "ADMIN_KEY_MIN_ENTROPY_BITS",
+                defaults.min_key_entropy_bits,
+            )?,
+            min_hash_memory_kib: number_from_env(
+                "ADMIN_HASH_MIN_MEMORY_KIB",
+                defaults.min_hash_memory_kib,
+            )?,
+            min_hash_iterations: number_from_env(
+                "ADMIN_HASH_MIN_ITERATIONS",
+                defaults.min_hash_iterations,
+            )?,
+        })
+    }
+
+    /// Checks the admin API key.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Why the key is weak, or `None` if it passes.
+    pub fn check_key(&self, key: &str) -> Option<String> {
+        if let Some(word) = common_word(key) {
+            return Some(format!("contains the common word '{}'", word));
+        }
+        let bits = estimate_entropy_bits(key);
+        (bits < self.min_key_entropy_bits as f64).then(|| {
+            format!(
+                "has an estimated {:.0} bits of entropy, below the minimum of {}",
+                bits, self.min_key_entropy_bits
+            )
+        })
+    }
+
+    /// Checks a PHC password hash against the minimum argon2id costs.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - Why the hash is weak, or `None` if it passes.
+    pub fn check_hash(&self, hash: &str) -> Option<String> {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return Some("is not a PHC password hash".to_string());
+        };
+        if parsed.algorithm.as_str() != "argon2id" {
+            return Some(format!("uses {} rather than argon2id", parsed.algorithm));
+        }
+        let param = |name: &str| parsed.params.get_decimal(name).unwrap_or(0);
+        if param("m") < self.min_hash_memory_kib {
+            return Some(format!(
+                "uses {} KiB of memory, below the minimum of {}",
+                param("m"),
+                self.min_hash_memory_kib
+            ));
+        }
+        if param("t") < self.min_hash_iterations {
+            return Some(format!(
+                "uses {} iterations, below the minimum of {}",
+                param("t"),
+                self.min_hash_iterations
+            ));
+        }
+        None
+    }
+
+    /// Checks the hash of every user in the contents of a users file;
+    /// malformed lines are left to the file backend to reject.
+    pub fn check_users(&self, contents: &str) -> Vec<String> {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, ':');
+                let (username, hash) = (parts.next()?, parts.next()?);
+                let weakness = self.check_hash(hash)?;
+                Some(format!(
+                    "the password hash of user '{}' {}",
+                    username, weakness
+                ))
+            })
+            .collect()
+    }
+
+    /// Checks the admin API key, `ADMIN_PASSWORD` and the users file named
+    /// by `AUTH_USERS_FILE`.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - One description per weak secret, never including the secret.
+    pub fn check_env(&self, admin_key: &AdminKey) -> Vec<String> {
+        let mut weaknesses = Vec::new();
+        if let Some(weakness) = admin_key.weakness(self) {
+            weaknesses.push(format!("the admin API key {}", weakness));
+        }
+        if let Ok(password) = env::var("ADMIN_PASSWORD") {
+            if PasswordHash::new(&password).is_ok() {
+                if let Some(weakness) = self.check_hash(&password) {
+                    weaknesses.push(format!("ADMIN_PASSWORD {}", weakness));
+                }
+            } else if !password.is_empty() {
+                weaknesses.push(
+                    "ADMIN_PASSWORD holds a plaintext password, which the server does not use; remove it and store an argon2id hash in AUTH_USERS_FILE".to_string(),
+                );
+            }
+        }
+        let file_backend = matches!(env::var("AUTH_BACKEND").as_deref(), Ok("file") | Err(_));
+        if let (true, Ok(path)) = (file_backend, env::var("AUTH_USERS_FILE")) {
+            if let Ok(contents) = fs::read_to_string(&path) {
+                weaknesses.extend(self.check_users(&contents));
+            }
+        }
+        weaknesses
+    }
+
+    /// Applies the strictness to `weaknesses`: logs them, or fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError listing the weak secrets under
+    /// [`Strictness::Strict`].
+    pub fn enforce(&self, weaknesses: &[String]) -> Result<(), IoError> {
+        match self.strictness {
+            Strictness::Off => Ok(()),
+            _ if weaknesses.is_empty() => Ok(()),
+            Strictness::Warn => {
+                for weakness in weaknesses {
+                    warn!("Weak admin secret: {}", weakness);
+                }
+                Ok(())
+            }
+            Strictness::Strict => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "refusing weak admin secrets under ADMIN_SECRET_POLICY=strict: {}",
+                    weaknesses.join("; ")
+                ),
+            )),
+        }
+    }
+}
+
+/// Reads the policy from the environment, checks the secrets it covers and
+/// enforces it.
+///
+/// # Errors
+///
+/// Returns an IoError if the policy is invalid, or if it is strict and a
+/// secret is weak.
+pub fn check_from_env(admin_key: &AdminKey) -> Result<(), IoError> {
+    let policy = SecretPolicy::from_env()?;
+    if policy.strictness == Strictness::Off {
+        return Ok(());
+    }
+    policy.enforce(&policy.check_env(admin_key))
+}
+
+/// Estimates the entropy of `secret` in bits: the characters that neither
+/// repeat nor continue a sequence from the previous one, times the bits of
+/// the character classes used.
+pub fn estimate_entropy_bits(secret: &str) -> f64 {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.is_empty() {
+        return 0.0;
+    }
+    let mut pool = 0u32;
+    if chars.iter().any(char::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if chars.iter().any(char::is_ascii_digit) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    let effective = 1 + chars
+        .windows(2)
+        .filter(|pair| (pair[1] as i64 - pair[0] as i64).abs() > 1)
+        .count();
+    effective as f64 * (pool as f64).log2()
+}
+
+fn common_word(secret: &str) -> Option<&'static str> {
+    let secret = secret.to_ascii_lowercase();
+    COMMON_WORDS
+        .iter()
+        .copied()
+        .find(|word| secret.contains(word))
+}
+
+fn number_from_env(name: &str, default: u32) -> Result<u32, IoError> {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("{} must be a number, got '{}'", name, value),
+            )
+        }),
+        Err(_) => Ok(default),
+    }
+}
@@ -0,0 +1,915 @@
+//! WebAuthn (passkey) registration and login.
+//!
+//! Built with the `webauthn` feature. A user who is logged in with Basic
+//! credentials enrolls a passkey through `POST /auth/webauthn/register/start`
+//! and `/finish`, and can then log in without a password through
+//! `POST /auth/webauthn/login/start` and `/finish`. A successful login
+//! establishes a session, returned in the `session` cookie.
+//!
+//! The relying party ID (`WEBAUTHN_RP_ID`) and origin (`WEBAUTHN_ORIGIN`) come
+//! from configuration. The origin must be on the RP ID's domain, and every
+//! ceremony request must carry it in its `Origin` header. The authenticator
+//! signs the origin it was used on, so a response collected by a phishing
+//! site fails verification. Challenges are kept server-side for
+//! `WEBAUTHN_CHALLENGE_TTL_SECS` and can be answered once.
+//!
+//! Credentials are stored with their public key, signature counter and
+//! authenticator AAGUID in `WEBAUTHN_CREDENTIALS_FILE`. A counter that does
+//! not increase suggests a cloned authenticator: the credential is flagged
+//! and refuses further logins.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, WebauthnError,
+};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+use super::{AuthBackend, Principal};
+use crate::audit;
+use crate::clock::Clock;
+use crate::error::ApiError;
+use crate::middleware::basic_auth::BasicAuth;
+use crate::middleware::request_id::CorrelationChain;
+use crate::route_meta::{DescribedRoute, RouteMetadata, ScopeMetadata};
+
+/// Name of the cookie holding the session established by a passkey login.
+pub const SESSION_COOKIE: &str = "session";
+
+/// Default for `WEBAUTHN_CHALLENGE_TTL_SECS`.
+pub const DEFAULT_CHALLENGE_TTL: Duration = Duration::from_secs(120);
+
+/// Default for `WEBAUTHN_SESSION_TTL_SECS`.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(8 * 3600);
+
+/// Relying party settings.
+#[derive(Clone, Debug)]
+pub struct WebauthnConfig {
+    /// Domain the credentials are scoped to, e.g. `example.com`.
+    pub rp_id: String,
+    /// Name shown by authenticators.
+    pub rp_name: String,
+    /// Origin the ceremonies run on, e.g. `https://login.example.com`.
+    pub origin: Url,
+    pub challenge_ttl: Duration,
+    pub session_ttl: Duration,
+    /// File persisting the credentials; `None` keeps them in memory.
+    pub credentials_file: Option<PathBuf>,
+}
+
+impl WebauthnConfig {
+    /// Creates the settings for `rp_id` and `origin` with default TTLs.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the origin is not on the RP ID's domain or is
+    /// not https (other than for `localhost`).
+    pub fn new(rp_id: &str, origin: Url) -> Result<Self, IoError> {
+        let host = origin.host_str().unwrap_or_default();
+        if host != rp_id && !host.ends_with(&format!(".{}", rp_id)) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "WEBAUTHN_ORIGIN host '{}' is not within WEBAUTHN_RP_ID '{}'",
+                    host, rp_id
+                ),
+            ));
+        }
+        if origin.scheme() != "https" && host != "localhost" {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "WEBAUTHN_ORIGIN must be https",
+            ));
+        }
+        Ok(WebauthnConfig {
+            rp_id: rp_id.to_string(),
+            rp_name: rp_id.to_string(),
+            origin,
+            challenge_ttl: DEFAULT_CHALLENGE_TTL,
+            session_ttl: DEFAULT_SESSION_TTL,
+            credentials_file: None,
+        })
+    }
+
+    /// Reads `WEBAUTHN_RP_ID`, `WEBAUTHN_ORIGIN`, `WEBAUTHN_RP_NAME`,
+    /// `WEBAUTHN_CHALLENGE_TTL_SECS`, `WEBAUTHN_SESSION_TTL_SECS` and
+    /// `WEBAUTHN_CREDENTIALS_FILE`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<WebauthnConfig>, IoError>` - The settings, `None` if WEBAUTHN_RP_ID is not set, or an IoError if they are invalid.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let Ok(rp_id) = env::var("WEBAUTHN_RP_ID") else {
+            return Ok(None);
+        };
+        let origin = env::var("WEBAUTHN_ORIGIN")
+            .ok()
+            .and_then(|o| Url::parse(&o).ok())
+            .ok_or_else(|| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    "WEBAUTHN_ORIGIN must be set to a URL when WEBAUTHN_RP_ID is set",
+                )
+            })?;
+        let secs = |var: &str| {
+            env::var(var)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&s| s > 0)
+                .map(Duration::from_secs)
+        };
+
+        let mut config = WebauthnConfig::new(&rp_id, origin)?;
+        if let Ok(name) = env::var("WEBAUTHN_RP_NAME") {
+            config.rp_name = name;
+        }
+        config.challenge_ttl = secs("WEBAUTHN_CHALLENGE_TTL_SECS").unwrap_or(DEFAULT_CHALLENGE_TTL);
+        config.session_ttl = secs("WEBAUTHN_SESSION_TTL_SECS").unwrap_or(DEFAULT_SESSION_TTL);
+        config.credentials_file = env::var("WEBAUTHN_CREDENTIALS_FILE")
+            .ok()
+            .map(PathBuf::from);
+        Ok(Some(config))
+    }
+}
+
+/// An enrolled passkey.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredCredential {
+    /// Credential ID, base64url-encoded.
+    pub id: String,
+    /// The credential, including its public key.
+    pub passkey: Passkey,
+    /// Authenticator model, if it reported one.
+    pub aaguid: Option<Uuid>,
+    /// Highest signature counter seen.
+    pub sign_count: u32,
+    /// Set after a counter regression; flagged credentials cannot log in.
+    pub flagged: bool,
+    /// Enrollment time, in Unix seconds.
+    pub created_at: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct UserEntry {
+    /// Opaque user handle given to authenticators.
+    id: Uuid,
+    /// Roles granted to sessions, as of the last enrollment.
+    roles: Vec<String>,
+    credentials: Vec<StoredCredential>,
+}
+
+enum Ceremony {
+    Registration {
+        principal: Principal,
+        user_id: Uuid,
+        state: PasskeyRegistration,
+    },
+    Authentication {
+        username: String,
+        state: PasskeyAuthentication,
+    },
+}
+
+/// Details the authenticator reported about itself at registration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthenticatorInfo {
+    pub aaguid: Option<Uuid>,
+    pub sign_count: u32,
+}
+
+/// Parses the authenticator data of a CBOR attestation object.
+///
+/// # Returns
+///
+/// * `Option<AuthenticatorInfo>` - The AAGUID (`None` if absent or all zero) and signature counter, or `None` if the object is malformed.
+pub fn parse_attestation_object(object: &[u8]) -> Option<AuthenticatorInfo> {
+    let auth_data = cbor_map_bytes(object, "authData")?;
+    // rpIdHash (32), flags (1), signCount (4), then attested credential data
+    let flags = *auth_data.get(32)?;
+    let sign_count = u32::from_be_bytes(auth_data.get(33..37)?.try_into().ok()?);
+    let aaguid = if flags & 0x40 != 0 {
+        Uuid::from_slice(auth_data.get(37..53)?)
+            .ok()
+            .filter(|aaguid| !aaguid.is_nil())
+    } else {
+        None
+    };
+    Some(AuthenticatorInfo { aaguid, sign_count })
+}
+
+/// Returns whether a signature counter of `received` after `stored`
+/// suggests a cloned authenticator. Authenticators without a counter always
+/// report 0.
+pub fn is_counter_regression(stored: u32, received: u32) -> bool {
+    (stored != 0 || received != 0) && received <= stored
+}
+
+/// Passkey enrollment, login and the sessions they establish.
+pub struct PasskeyAuth {
+    webauthn: Webauthn,
+    origin: String,
+    challenge_ttl: Duration,
+    session_ttl: Duration,
+    users: Mutex<HashMap<String, UserEntry>>,
+    challenges: Mutex<HashMap<String, (DateTime<Utc>, Ceremony)>>,
+    sessions: Mutex<HashMap<String, (DateTime<Utc>, Principal)>>,
+    clock: Clock,
+    file: Option<PathBuf>,
+    /// Orders writes of the credentials file.
+    persisting: tokio::sync::Mutex<()>,
+    rng: SystemRandom,
+}
+
+impl PasskeyAuth {
+    /// Creates the relying party, loading stored credentials if the
+    /// credentials file exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the relying party cannot be built or the
+    /// credentials file cannot be read.
+    pub fn new(config: WebauthnConfig) -> Result<Self, IoError> {
+        let invalid = |e: WebauthnError| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid WebAuthn configuration: {}", e),
+            )
+        };
+        let webauthn = WebauthnBuilder::new(&config.rp_id, &config.origin)
+            .map_err(invalid)?
+            .rp_name(&config.rp_name)
+            .build()
+            .map_err(invalid)?;
+
+        let users = match &config.credentials_file {
+            Some(path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)
+                .map_err(|e| {
+                    IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid credentials file '{}': {}", path.display(), e),
+                    )
+                })?,
+            _ => HashMap::new(),
+        };
+
+        Ok(PasskeyAuth {
+            webauthn,
+            origin: config.origin.origin().ascii_serialization(),
+            challenge_ttl: config.challenge_ttl,
+            session_ttl: config.session_ttl,
+            users: Mutex::new(users),
+            challenges: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            clock: Clock::default(),
+            file: config.credentials_file,
+            persisting: tokio::sync::Mutex::new(()),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Expires challenges and sessions by `clock`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Builds the relying party from the environment.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<PasskeyAuth>, IoError>` - The relying party, `None` if WebAuthn is not configured, or an IoError if the configuration is invalid.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let Some(config) = WebauthnConfig::from_env()? else {
+            return Ok(None);
+        };
+        info!(
+            "Passkey login enabled for RP ID {} on {}",
+            config.rp_id, config.origin
+        );
+        PasskeyAuth::new(config).map(Some)
+    }
+
+    /// Registers the ceremony routes. Enrollment requires Basic credentials
+    /// checked against `backend`.
+    pub fn configure(
+        passkeys: web::Data<PasskeyAuth>,
+        backend: Arc<dyn AuthBackend>,
+    ) -> impl FnOnce(&mut web::ServiceConfig) {
+        move |cfg| {
+            let register = ScopeMetadata::new("/auth/webauthn/register")
+                .middleware("BasicAuth")
+                .auth_required()
+                .configure(|cfg| {
+                    cfg.described_route(
+                        Method::POST,
+                        "/start",
+                        register_start,
+                        RouteMetadata::new("Start enrolling a passkey"),
+                    )
+                    .described_route(
+                        Method::POST,
+                        "/finish",
+                        register_finish,
+                        RouteMetadata::new("Finish enrolling a passkey"),
+                    );
+                });
+            cfg.app_data(passkeys)
+                .service(
+                    web::scope("/auth/webauthn/register")
+                        .wrap(BasicAuth::new(backend))
+                        .configure(register),
+                )
+                .described_route(
+                    Method::POST,
+                    "/auth/webauthn/login/start",
+                    login_start,
+                    RouteMetadata::new("Start a passkey login"),
+                )
+                .described_route(
+                    Method::POST,
+                    "/auth/webauthn/login/finish",
+                    login_finish,
+                    RouteMetadata::new("Finish a passkey login"),
+                );
+        }
+    }
+
+    /// Rejects requests whose `Origin` header is not the configured origin.
+    ///
+    /// # Errors
+    ///
+    /// Returns 403 `origin_mismatch` for a missing or different origin.
+    pub fn check_origin(&self, req: &HttpRequest) -> Result<(), ApiError> {
+        let origin = req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok());
+        if origin == Some(self.origin.as_str()) {
+            return Ok(());
+        }
+        warn!(
+            "Rejected WebAuthn request from origin {:?}, expected {}",
+            origin, self.origin
+        );
+        Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "origin_mismatch",
+            "The request origin is not allowed to use passkeys",
+        ))
+    }
+
+    /// Returns the credentials enrolled by `username`.
+    pub fn credentials(&self, username: &str) -> Vec<StoredCredential> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|entry| entry.credentials.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the user of an unexpired session.
+    pub fn session(&self, token: &str) -> Option<Principal> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = self.clock.now();
+        sessions.retain(|_, (expires, _)| *expires > now);
+        sessions.get(token).map(|(_, principal)| principal.clone())
+    }
+
+    /// Starts enrolling a passkey for `principal`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(String, CreationChallengeResponse), ApiError>` - The challenge ID and the options for `navigator.credentials.create()`, or 500 if the challenge cannot be created.
+    pub fn start_registration(
+        &self,
+        principal: &Principal,
+    ) -> Result<(String, CreationChallengeResponse), ApiError> {
+        let (user_id, exclude) = {
+            let users = self.users.lock().unwrap();
+            match users.get(&principal.username) {
+                Some(entry) => (
+                    entry.id,
+                    entry
+                        .credentials
+                        .iter()
+                        .map(|c| c.passkey.cred_id().clone())
+                        .collect(),
+                ),
+                None => (Uuid::new_v4(), Vec::new()),
+            }
+        };
+        let (options, state) = self
+            .webauthn
+            .start_passkey_registration(
+                user_id,
+                &principal.username,
+                &principal.username,
+                Some(exclude),
+            )
+            .map_err(|e| {
+                warn!("Failed to start passkey registration: {}", e);
+                ceremony_failed()
+            })?;
+        let id = self.store_challenge(Ceremony::Registration {
+            principal: principal.clone(),
+            user_id,
+            state,
+        })?;
+        Ok((id, options))
+    }
+
+    /// Verifies the authenticator's response and stores the credential.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<StoredCredential, ApiError>` - The enrolled credential, 400 `invalid_challenge` for an unknown or expired challenge, or 400 `webauthn_failed` if verification fails.
+    pub async fn finish_registration(
+        &self,
+        challenge_id: &str,
+        credential: &RegisterPublicKeyCredential,
+    ) -> Result<StoredCredential, ApiError> {
+        let Ceremony::Registration {
+            principal,
+            user_id,
+            state,
+        } = self.take_challenge(challenge_id)?
+        else {
+            return Err(invalid_challenge());
+        };
+        let passkey = self
+            .webauthn
+            .finish_passkey_registration(credential, &state)
+            .map_err(|e| {
+                warn!(
+                    "Passkey registration failed for '{}': {}",
+                    principal.username, e
+                );
+                verification_failed()
+            })?;
+        let info = parse_attestation_object(credential.response.attestation_object.as_ref())
+            .unwrap_or(AuthenticatorInfo {
+                aaguid: None,
+                sign_count: 0,
+            });
+        let stored = StoredCredential {
+            id: URL_SAFE_NO_PAD.encode(passkey.cred_id()),
+            passkey,
+            aaguid: info.aaguid,
+            sign_count: info.sign_count,
+            flagged: false,
+            created_at: self.clock.timestamp(),
+        };
+
+        {
+            let mut users = self.users.lock().unwrap();
+            let entry = users
+                .entry(principal.username.clone())
+                .or_insert_with(|| UserEntry {
+                    id: user_id,
+                    roles: Vec::new(),
+                    credentials: Vec::new(),
+                });
+            entry.roles = principal.roles.clone();
+            entry.credentials.push(stored.clone());
+        }
+        self.persist().await;
+        info!("Passkey enrolled for '{}'", principal.username);
+        Ok(stored)
+    }
+
+    /// Starts a passkey login for `username`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(String, RequestChallengeResponse), ApiError>` - The challenge ID and the options for `navigator.credentials.get()`, or 400 `no_passkey` if the user has no usable passkey.
+    pub fn start_login(
+        &self,
+        username: &str,
+    ) -> Result<(String, RequestChallengeResponse), ApiError> {
+        let passkeys: Vec<Passkey> = self
+            .credentials(username)
+            .into_iter()
+            .filter(|c| !c.flagged)
+            .map(|c| c.passkey)
+            .collect();
+        if passkeys.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "no_passkey",
+                "No passkey is enrolled for this user",
+            ));
+        }
+        let (options, state) = self
+            .webauthn
+            .start_passkey_authentication(&passkeys)
+            .map_err(|e| {
+                warn!("Failed to start passkey login: {}", e);
+                ceremony_failed()
+            })?;
+        let id = self.store_challenge(Ceremony::Authentication {
+            username: username.to_string(),
+            state,
+        })?;
+        Ok((id, options))
+    }
+
+    /// Verifies the authenticator's assertion and establishes a session.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Principal, String), ApiError>` - The user and the session token, 400 `invalid_challenge` or `webauthn_failed` as for registration, or 401 `credential_flagged` after a counter regression.
+    pub async fn finish_login(
+        &self,
+        challenge_id: &str,
+        credential: &PublicKeyCredential,
+    ) -> Result<(Principal, String), ApiError> {
+        let Ceremony::Authentication { username, state } = self.take_challenge(challenge_id)?
+        else {
+            return Err(invalid_challenge());
+        };
+        let result = match self
+            .webauthn
+            .finish_passkey_authentication(credential, &state)
+        {
+            Ok(result) => result,
+            Err(WebauthnError::CredentialPossibleCompromise) => {
+                let id = URL_SAFE_NO_PAD.encode(&credential.raw_id);
+                self.flag(&username, &id).await;
+                return Err(flagged());
+            }
+            Err(e) => {
+                warn!("Passkey login failed for '{}': {}", username, e);
+                return Err(verification_failed());
+            }
+        };
+
+        let id = URL_SAFE_NO_PAD.encode(result.cred_id());
+        let principal = {
+            let mut users = self.users.lock().unwrap();
+            let entry = users.get_mut(&username).ok_or_else(verification_failed)?;
+            let stored = entry
+                .credentials
+                .iter_mut()
+                .find(|c| c.id == id)
+                .ok_or_else(verification_failed)?;
+            if stored.flagged || is_counter_regression(stored.sign_count, result.counter()) {
+                stored.flagged = true;
+                None
+            } else {
+                stored.sign_count = result.counter();
+                stored.passkey.update_credential(&result);
+                Some(Principal {
+                    username: username.clone(),
+                    roles: entry.roles.clone(),
+                })
+            }
+        };
+        self.persist().await;
+        let Some(principal) = principal else {
+            warn!(
+                "Passkey {} of '{}' flagged: signature counter did not increase",
+                id, username
+            );
+            audit::record(
+                "passkey_flagged",
+                json!({ "username": username, "credential_id": id }),
+            );
+            return Err(flagged());
+        };
+
+        let token = self.random_id()?;
+        self.sessions.lock().unwrap().insert(
+            token.clone(),
+            (self.expiry(self.session_ttl), principal.clone()),
+        );
+        Ok((principal, token))
+    }
+
+    async fn flag(&self, username: &str, id: &str) {
+        if let Some(entry) = self.users.lock().unwrap().get_mut(username) {
+            for stored in entry.credentials.iter_mut().filter(|c| c.id == id) {
+                stored.flagged = true;
+            }
+        }
+        self.persist().await;
+        warn!(
+            "Passkey {} of '{}' flagged as possibly cloned",
+            id, username
+        );
+        audit::record(
+            "passkey_flagged",
+            json!({ "username": username, "credential_id": id }),
+        );
+    }
+
+    fn store_challenge(&self, ceremony: Ceremony) -> Result<String, ApiError> {
+        let id = self.random_id()?;
+        let now = self.clock.now();
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, (expires, _)| *expires > now);
+        challenges.insert(id.clone(), (self.expiry(self.challenge_ttl), ceremony));
+        Ok(id)
+    }
+
+    /// Removes and returns an unexpired challenge, so each is answered once.
+    fn take_challenge(&self, id: &str) -> Result<Ceremony, ApiError> {
+        match self.challenges.lock().unwrap().remove(id) {
+            Some((expires, ceremony)) if expires > self.clock.now() => Ok(ceremony),
+            _ => Err(invalid_challenge()),
+        }
+    }
+
+    /// Challenges and sessions live only in this instance, so they expire
+    /// exactly after `ttl`, without the clock's skew tolerance.
+    fn expiry(&self, ttl: Duration) -> DateTime<Utc> {
+        chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| self.clock.now().checked_add_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    fn random_id(&self) -> Result<String, ApiError> {
+        let mut bytes = [0u8; 32];
+        self.rng.fill(&mut bytes).map_err(|_| ceremony_failed())?;
+        Ok(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// Writes the credentials file, if configured.
+    async fn persist(&self) {
+        let Some(path) = self.file.clone() else {
+            return;
+        };
+        let _guard = self.persisting.lock().await;
+        let contents = match serde_json::to_string_pretty(&*self.users.lock().unwrap()) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to serialize passkeys: {}", e);
+                return;
+            }
+        };
+        let written = actix_web::rt::task::spawn_blocking(move || write_atomic(&path, &contents))
+            .await
+            .map_err(|e| IoError::new(ErrorKind::Other, e.to_string()))
+            .and_then(|result| result);
+        if let Err(e) = written {
+            warn!("Failed to write credentials file: {}", e);
+        }
+    }
+}
+
+/// Writes through a temporary file so a crash never truncates the file.
+fn write_atomic(path: &Path, contents: &str) -> Result<(), IoError> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Returns the byte string stored under the text key `key` of a CBOR map.
+fn cbor_map_bytes<'a>(data: &'a [u8], key: &str) -> Option<&'a [u8]> {
+    let mut pos = 0;
+    let (major, entries) = cbor_head(data, &mut pos)?;
+    if major != 5 {
+        return None;
+    }
+    for _ in 0..entries {
+        let (major, len) = cbor_head(data, &mut pos)?;
+        if major != 3 {
+            return None;
+        }
+        let name = cbor_slice(data, &mut pos, len)?;
+        if name == key.as_bytes() {
+            let (major, len) = cbor_head(data, &mut pos)?;
+            return if major == 2 {
+                cbor_slice(data, &mut pos, len)
+            } else {
+                None
+            };
+        }
+        cbor_skip(data, &mut pos)?;
+    }
+    None
+}
+
+/// Reads a CBOR item header, returning its major type and argument.
+/// Indefinite lengths are not used in attestation objects and are rejected.
+fn cbor_head(data: &[u8], pos: &mut usize) -> Option<(u8, u64)> {
+    let initial = *data.get(*pos)?;
+    *pos += 1;
+    let size = match initial & 0x1f {
+        info @ 0..=23 => return Some((initial >> 5, u64::from(info))),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return None,
+    };
+    let bytes = cbor_slice(data, pos, size)?;
+    let value = bytes.iter().fold(0u64, |v, b| (v << 8) | u64::from(*b));
+    Some((initial >> 5, value))
+}
+
+/// Returns the next `len` bytes.
+fn cbor_slice<'a>(data: &'a [u8], pos: &mut usize, len: u64) -> Option<&'a [u8]> {
+    let end = pos.checked_add(usize::try_from(len).ok()?)?;
+    let slice = data.get(*pos..end)?;
+    *pos = end;
+    Some(slice)
+}
+
+/// Skips one CBOR item.
+fn cbor_skip(data: &[u8], pos: &mut usize) -> Option<()> {
+    let (major, value) = cbor_head(data, pos)?;
+    match major {
+        0 | 1 | 7 => {}
+        2 | 3 => {
+            cbor_slice(data, pos, value)?;
+        }
+        4 => {
+            for _ in 0..value {
+                cbor_skip(data, pos)?;
+            }
+        }
+        5 => {
+            for _ in 0..value {
+                cbor_skip(data, pos)?;
+                cbor_skip(data, pos)?;
+            }
+        }
+        6 => cbor_skip(data, pos)?,
+        _ => return None,
+    }
+    Some(())
+}
+
+fn invalid_challenge() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_challenge",
+        "The challenge is unknown, expired or already used",
+    )
+}
+
+fn verification_failed() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "webauthn_failed",
+        "The authenticator response could not be verified",
+    )
+}
+
+fn flagged() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "credential_flagged",
+        "This passkey has been disabled because it may have been cloned",
+    )
+}
+
+fn ceremony_failed() -> ApiError {
+    ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "webauthn_error",
+        "The passkey ceremony could not be started",
+    )
+}
+
+/// Body of `POST /auth/webauthn/register/finish`.
+#[derive(Deserialize)]
+pub struct RegisterFinish {
+    pub challenge_id: String,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+/// Body of `POST /auth/webauthn/login/start`.
+#[derive(Deserialize)]
+pub struct LoginStart {
+    pub username: String,
+}
+
+/// Body of `POST /auth/webauthn/login/finish`.
+#[derive(Deserialize)]
+pub struct LoginFinish {
+    pub challenge_id: String,
+    pub credential: PublicKeyCredential,
+}
+
+/// Handler for `POST /auth/webauthn/register/start`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with `challenge_id` and the creation `options`, or 403 for a foreign origin.
+pub async fn register_start(
+    passkeys: web::Data<PasskeyAuth>,
+    req: HttpRequest,
+    principal: Principal,
+) -> Result<HttpResponse, ApiError> {
+    passkeys.check_origin(&req)?;
+    let (challenge_id, options) = passkeys.start_registration(&principal)?;
+    Ok(HttpResponse::Ok().json(json!({ "challenge_id": challenge_id, "options": options })))
+}
+
+/// Handler for `POST /auth/webauthn/register/finish`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 201 Created with the credential ID and AAGUID; see [`PasskeyAuth::finish_registration`] for errors.
+pub async fn register_finish(
+    passkeys: web::Data<PasskeyAuth>,
+    req: HttpRequest,
+    chain: CorrelationChain,
+    body: web::Json<RegisterFinish>,
+) -> Result<HttpResponse, ApiError> {
+    passkeys.check_origin(&req)?;
+    let stored = passkeys
+        .finish_registration(&body.challenge_id, &body.credential)
+        .await?;
+    audit::record_for(
+        &chain,
+        "passkey_registered",
+        json!({ "credential_id": stored.id, "aaguid": stored.aaguid.map(|a| a.to_string()) }),
+    );
+    Ok(HttpResponse::Created().json(json!({
+        "credential_id": stored.id,
+        "aaguid": stored.aaguid.map(|a| a.to_string()),
+        "sign_count": stored.sign_count,
+    })))
+}
+
+/// Handler for `POST /auth/webauthn/login/start`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with `challenge_id` and the request `options`; see [`PasskeyAuth::start_login`] for errors.
+pub async fn login_start(
+    passkeys: web::Data<PasskeyAuth>,
+    req: HttpRequest,
+    body: web::Json<LoginStart>,
+) -> Result<HttpResponse, ApiError> {
+    passkeys.check_origin(&req)?;
+    let (challenge_id, options) = passkeys.start_login(&body.username)?;
+    Ok(HttpResponse::Ok().json(json!({ "challenge_id": challenge_id, "options": options })))
+}
+
+/// Handler for `POST /auth/webauthn/login/finish`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the principal and the `session` cookie; see [`PasskeyAuth::finish_login`] for errors.
+pub async fn login_finish(
+    passkeys: web::Data<PasskeyAuth>,
+    req: HttpRequest,
+    chain: CorrelationChain,
+    body: web::Json<LoginFinish>,
+) -> Result<HttpResponse, ApiError> {
+    passkeys.check_origin(&req)?;
+    match passkeys
+        .finish_login(&body.challenge_id, &body.credential)
+        .await
+    {
+        Ok((principal, token)) => {
+            info!("Passkey login succeeded for '{}'", principal.username);
+            audit::record_for(
+                &chain,
+                "login_succeeded",
+                json!({ "username": principal.username, "method": "webauthn" }),
+            );
+            let cookie = Cookie::build(SESSION_COOKIE, token)
+                .path("/")
+                .secure(true)
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .max_age(time::Duration::seconds(
+                    passkeys.session_ttl.as_secs() as i64
+                ))
+                .finish();
+            Ok(HttpResponse::Ok().cookie(cookie).json(principal))
+        }
+        Err(e) => {
+            audit::record_for(
+                &chain,
+                "login_failed",
+                json!({ "method": "webauthn", "reason": e.code() }),
+            );
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,565 @@
+//! Two-tier response cache.
+//!
+//! [`MultiLayerCache`] caches successful `GET` responses under the path
+//! prefixes listed in `RESPONSE_CACHE_PATHS`. A request is answered from L1,
+//! a bounded in-process LRU map of `L1_CACHE_SIZE` entries, else from L2, a
+//! Redis server at `L2_CACHE_REDIS_ADDR` shared by every instance, else by
+//! the handler. A handler response is stored in L2, then in L1; an L2 hit is
+//! copied into L1 for the rest of its lifetime.
+//!
+//! Redis is best effort: when it fails or answers slower than
+//! `L2_CACHE_TIMEOUT_MS` the request carries on as an L2 miss, so the cache
+//! degrades to L1 only instead of failing requests.
+//!
+//! Keys hold the method, path, query and `Accept-Language`, scoped with
+//! [`Tenant::scoped_key`] when the request has a tenant. Requests carrying
+//! credentials or cookies are never cached, nor responses that set cookies,
+//! are marked `no-store` or `private`, or vary on another request header
+//! than `Accept-Language`, which the key would not tell apart. Hits replay
+//! the stored response headers, e.g. `Content-Encoding`, `ETag` or `Vary`.
+//! Responses carry `X-Cache: L1`, `L2` or `MISS`; hits also carry `Age`,
+//! the seconds since the entry was stored.
+
+pub mod redis;
+
+use std::env;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::num::NonZeroUsize;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::{error, Error, HttpMessage, HttpResponse};
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+use log::{info, warn};
+use lru::LruCache;
+use serde_json::json;
+
+use crate::metrics::Metrics;
+use crate::middleware::api_key::API_KEY_HEADER;
+use crate::middleware::tenant::Tenant;
+
+pub use redis::RedisStore;
+
+/// Header telling which tier answered.
+pub const CACHE_HEADER: &str = "x-cache";
+
+/// Default number of L1 entries.
+pub const DEFAULT_L1_CACHE_SIZE: usize = 1000;
+
+/// Default lifetime of a cached response.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// Default time allowed for each Redis command.
+pub const DEFAULT_L2_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Largest response body cached; larger or streamed bodies are passed
+/// through.
+pub const MAX_CACHED_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Largest total size of the stored headers of a cached response.
+pub const MAX_CACHED_HEADER_BYTES: usize = 16 * 1024;
+
+/// Largest encoded entry, as read back from L2: the expiry, the header
+/// count, the headers and the body.
+pub const MAX_ENTRY_BYTES: usize = 10 + MAX_CACHED_HEADER_BYTES + MAX_CACHED_BODY_BYTES as usize;
+
+/// Response headers that describe one particular response rather than the
+/// content, and are not replayed from the cache.
+const UNCACHED_HEADERS: [&str; 8] = [
+    "age",
+    "connection",
+    "content-length",
+    "date",
+    "keep-alive",
+    "set-cookie",
+    "transfer-encoding",
+    CACHE_HEADER,
+];
+
+/// The shared L2 tier.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// Returns the value stored under `key`, if any.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, IoError>;
+
+    /// Stores `value` under `key`, expiring after `ttl`.
+    async fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), IoError>;
+}
+
+/// A cached response body and its headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedResponse {
+    /// Headers replayed on hits, in their original order.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: Bytes,
+    /// Milliseconds since the Unix epoch after which the entry is stale.
+    pub expires_at_ms: u64,
+}
+
+impl CachedResponse {
+    /// Encodes the entry for L2: the expiry as 8 big-endian bytes, the
+    /// number of headers as 2, each header as its name and value, both
+    /// preceded by their length as 2 bytes, then the body.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(10 + encoded_len(&self.headers) + self.body.len());
+        out.extend_from_slice(&self.expires_at_ms.to_be_bytes());
+        out.extend_from_slice(&(self.headers.len() as u16).to_be_bytes());
+        for (name, value) in &self.headers {
+            for part in [name.as_str().as_bytes(), value.as_bytes()] {
+                out.extend_from_slice(&(part.len() as u16).to_be_bytes());
+                out.extend_from_slice(part);
+            }
+        }
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    /// Decodes an entry written by [`encode`](Self::encode); `None` if
+    /// `bytes` is not one.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let expires_at_ms = u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?);
+        let count = u16::from_be_bytes(bytes.get(8..10)?.try_into().ok()?);
+        let mut rest = &bytes[10..];
+        let mut part = || {
+            let current: &[u8] = rest;
+            let len = u16::from_be_bytes(current.get(..2)?.try_into().ok()?) as usize;
+            let value = current.get(2..2 + len)?;
+            rest = &current[2 + len..];
+            Some(value)
+        };
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let name = HeaderName::from_bytes(part()?).ok()?;
+            let value = HeaderValue::from_bytes(part()?).ok()?;
+            headers.push((name, value));
+        }
+        Some(CachedResponse {
+            headers,
+            body: Bytes::copy_from_slice(rest),
+            expires_at_ms,
+        })
+    }
+
+    /// Takes the cacheable headers of `headers`; `None` if together they
+    /// are larger than [`MAX_CACHED_HEADER_BYTES`].
+    fn headers_of(headers: &header::HeaderMap) -> Option<Vec<(HeaderName, HeaderValue)>> {
+        let headers: Vec<(HeaderName, HeaderValue)> = headers
+            .iter()
+            .filter(|(name, _)| !UNCACHED_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        (encoded_len(&headers) <= MAX_CACHED_HEADER_BYTES).then_some(headers)
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        Some(Duration::from_millis(
+            self.expires_at_ms.checked_sub(now_ms())?,
+        ))
+        .filter(|d| !d.is_zero())
+    }
+
+    /// Time since the entry was stored, for an entry stored for `ttl`.
+    fn age(&self, ttl: Duration) -> Duration {
+        ttl.saturating_sub(self.remaining().unwrap_or_default())
+    }
+
+    fn respond(&self, tier: &'static str, ttl: Duration) -> HttpResponse {
+        let mut res = HttpResponse::Ok();
+        for (name, value) in &self.headers {
+            res.append_header((name.clone(), value.clone()));
+        }
+        res.insert_header((CACHE_HEADER, tier))
+            .insert_header((header::AGE, self.age(ttl).as_secs().to_string()))
+            .body(self.body.clone())
+    }
+}
+
+/// Bytes `headers` take in an encoded entry.
+fn encoded_len(headers: &[(HeaderName, HeaderValue)]) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| 4 + name.as_str().len() + value.len())
+        .sum()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Hit, miss and eviction counts of a [`MultiLayerCache`].
+#[derive(Default)]
+pub struct CacheStats {
+    pub l1_hits: AtomicU64,
+    pub l2_hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub l1_evictions: AtomicU64,
+    pub l2_errors: AtomicU64,
+}
+
+impl CacheStats {
+    fn record(&self, counter: &AtomicU64, metric: &str, labels: &[(&str, &str)]) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Metrics::global().inc(metric, labels);
+    }
+}
+
+struct L1Entry {
+    response: CachedResponse,
+    expires: Instant,
+}
+
+struct Inner {
+    paths: Vec<String>,
+    ttl: Duration,
+    l1: Mutex<LruCache<String, L1Entry>>,
+    l1_size: NonZeroUsize,
+    l2: Option<Arc<dyn CacheStore>>,
+    stats: CacheStats,
+}
+
+/// Middleware caching `GET` responses in L1, then L2. Clones share both
+/// tiers and the statistics, so one instance serves every worker.
+#[derive(Clone)]
+pub struct MultiLayerCache {
+    inner: Arc<Inner>,
+}
+
+impl MultiLayerCache {
+    /// Caches responses under `paths` for `ttl`, keeping at most `l1_size`
+    /// entries in process and, with `l2`, every entry in `l2` as well.
+    pub fn new(
+        paths: Vec<String>,
+        l1_size: NonZeroUsize,
+        ttl: Duration,
+        l2: Option<Arc<dyn CacheStore>>,
+    ) -> Self {
+        MultiLayerCache {
+            inner: Arc::new(Inner {
+                paths,
+                ttl,
+                l1: Mutex::new(LruCache::new(l1_size)),
+                l1_size,
+                l2,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// Reads `RESPONSE_CACHE_PATHS`, `L1_CACHE_SIZE`,
+    /// `RESPONSE_CACHE_TTL_SECS`, `L2_CACHE_REDIS_ADDR` and
+    /// `L2_CACHE_TIMEOUT_MS`; `None` when no path is cached.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if a number is invalid.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let paths: Vec<String> = env::var("RESPONSE_CACHE_PATHS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        if paths.is_empty() {
+            return Ok(None);
+        }
+        let l1_size = match env::var("L1_CACHE_SIZE") {
+            Ok(size) => size.parse::<NonZeroUsize>().map_err(|_| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("L1_CACHE_SIZE must be a positive number, got '{}'", size),
+                )
+            })?,
+            Err(_) => NonZeroUsize::new(DEFAULT_L1_CACHE_SIZE).unwrap(),
+        };
+        let ttl = duration_from_env("RESPONSE_CACHE_TTL_SECS", Duration::from_secs)?
+            .unwrap_or(DEFAULT_TTL);
+        let l2: Option<Arc<dyn CacheStore>> = match env::var("L2_CACHE_REDIS_ADDR") {
+            Ok(addr) if !addr.is_empty() => {
+                let timeout = duration_from_env("L2_CACHE_TIMEOUT_MS", Duration::from_millis)?
+                    .unwrap_or(DEFAULT_L2_TIMEOUT);
+                info!("Response cache L2: Redis at {}", addr);
+                Some(Arc::new(RedisStore::new(&addr, timeout)))
+            }
+            _ => None,
+        };
+        info!(
+            "Caching responses under {} for {}s, {} entries in process",
+            paths.join(", "),
+            ttl.as_secs(),
+            l1_size
+        );
+        Ok(Some(MultiLayerCache::new(paths, l1_size, ttl, l2)))
+    }
+
+    /// Hit, miss and eviction counts.
+    pub fn stats(&self) -> &CacheStats {
+        &self.inner.stats
+    }
+
+    /// Number of entries in L1.
+    pub fn l1_len(&self) -> usize {
+        self.inner.l1.lock().unwrap().len()
+    }
+
+    /// The cache key of `req`, or `None` if it must not be cached.
+    pub fn key(&self, req: &ServiceRequest) -> Option<String> {
+        if req.method() != Method::GET
+            || !self.inner.paths.iter().any(|p| req.path().starts_with(p))
+        {
+            return None;
+        }
+        let headers = req.headers();
+        if headers.contains_key(header::AUTHORIZATION)
+            || headers.contains_key(header::COOKIE)
+            || headers.contains_key(API_KEY_HEADER)
+        {
+            return None;
+        }
+        let language = headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let key = format!("cache:GET {} {}", req.uri(), language);
+        Some(match req.extensions().get::<Tenant>() {
+            Some(tenant) => tenant.scoped_key(&key),
+            None => key,
+        })
+    }
+
+    fn l1_get(&self, key: &str) -> Option<CachedResponse> {
+        let mut l1 = self.inner.l1.lock().unwrap();
+        match l1.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.response.clone()),
+            Some(_) => {
+                l1.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn l1_put(&self, key: String, response: CachedResponse, ttl: Duration) {
+        let entry = L1Entry {
+            response,
+            expires: Instant::now() + ttl,
+        };
+        let evicted = self.inner.l1.lock().unwrap().push(key.clone(), entry);
+        if evicted.is_some_and(|(evicted, _)| evicted != key) {
+            self.inner.stats.record(
+                &self.inner.stats.l1_evictions,
+                "cache_l1_evictions_total",
+                &[],
+            );
+        }
+    }
+
+    async fn l2_get(&self, key: &str) -> Option<CachedResponse> {
+        let l2 = self.inner.l2.as_ref()?;
+        match l2.get(key).await {
+            Ok(value) => value.as_deref().and_then(CachedResponse::decode),
+            Err(e) => {
+                self.l2_failed("read", &e);
+                None
+            }
+        }
+    }
+
+    async fn store(&self, key: String, response: CachedResponse) {
+        if let Some(l2) = &self.inner.l2 {
+            if let Err(e) = l2.set(&key, &response.encode(), self.inner.ttl).await {
+                self.l2_failed("write", &e);
+            }
+        }
+        self.l1_put(key, response, self.inner.ttl);
+    }
+
+    fn l2_failed(&self, operation: &str, e: &IoError) {
+        warn!(
+            "Response cache L2 {} failed, using L1 only: {}",
+            operation, e
+        );
+        self.inner
+            .stats
+            .record(&self.inner.stats.l2_errors, "cache_l2_errors_total", &[]);
+    }
+}
+
+fn duration_from_env(name: &str, unit: fn(u64) -> Duration) -> Result<Option<Duration>, IoError> {
+    env::var(name)
+        .ok()
+        .map(|value| {
+            value.parse().map(unit).map_err(|_| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} must be a number, got '{}'", name, value),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// Whether a handler response may be cached.
+fn cacheable<B: MessageBody>(res: &ServiceResponse<B>) -> bool {
+    let headers = res.headers();
+    let private = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .map(str::trim)
+                .any(|d| d.eq_ignore_ascii_case("no-store") || d.eq_ignore_ascii_case("private"))
+        });
+    // The key tells requests apart by Accept-Language only
+    let varies = headers.get_all(header::VARY).any(|v| {
+        v.to_str().map_or(true, |v| {
+            v.split(',')
+                .map(str::trim)
+                .any(|field| !field.is_empty() && !field.eq_ignore_ascii_case("accept-language"))
+        })
+    });
+    res.status() == StatusCode::OK
+        && !private
+        && !varies
+        && !headers.contains_key(header::SET_COOKIE)
+        && matches!(res.response().body().size(), BodySize::Sized(n) if n <= MAX_CACHED_BODY_BYTES)
+}
+
+impl Default for MultiLayerCache {
+    /// A cache of no path, caching nothing.
+    fn default() -> Self {
+        MultiLayerCache::new(
+            Vec::new(),
+            NonZeroUsize::new(DEFAULT_L1_CACHE_SIZE).unwrap(),
+            DEFAULT_TTL,
+            None,
+        )
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MultiLayerCache
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = MultiLayerCacheMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MultiLayerCacheMiddleware {
+            service: Rc::new(service),
+            cache: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`MultiLayerCache`].
+pub struct MultiLayerCacheMiddleware<S> {
+    service: Rc<S>,
+    cache: MultiLayerCache,
+}
+
+impl<S, B> Service<ServiceRequest> for MultiLayerCacheMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let Some(key) = self.cache.key(&req) else {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_boxed_body) });
+        };
+        let service = self.service.clone();
+        let cache = self.cache.clone();
+
+        Box::pin(async move {
+            let stats = &cache.inner.stats;
+            if let Some(hit) = cache.l1_get(&key) {
+                stats.record(&stats.l1_hits, "cache_hits_total", &[("tier", "l1")]);
+                return Ok(req.into_response(hit.respond("L1", cache.inner.ttl)));
+            }
+            if let Some(hit) = cache.l2_get(&key).await {
+                if let Some(remaining) = hit.remaining() {
+                    stats.record(&stats.l2_hits, "cache_hits_total", &[("tier", "l2")]);
+                    cache.l1_put(key, hit.clone(), remaining);
+                    return Ok(req.into_response(hit.respond("L2", cache.inner.ttl)));
+                }
+            }
+            stats.record(&stats.misses, "cache_misses_total", &[]);
+
+            let mut res = service.call(req).await?;
+            res.headers_mut().insert(
+                HeaderName::from_static(CACHE_HEADER),
+                HeaderValue::from_static("MISS"),
+            );
+            if !cacheable(&res) {
+                return Ok(res.map_into_boxed_body());
+            }
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let bytes = body::to_bytes(body).await.map_err(|e| {
+                let e: Box<dyn std::error::Error> = e.into();
+                error::ErrorInternalServerError(e.to_string())
+            })?;
+            if let Some(headers) = CachedResponse::headers_of(res.headers()) {
+                let response = CachedResponse {
+                    headers,
+                    body: bytes.clone(),
+                    expires_at_ms: now_ms() + cache.inner.ttl.as_millis() as u64,
+                };
+                cache.store(key, response).await;
+            }
+            Ok(ServiceResponse::new(
+                req,
+                res.set_body(bytes).map_into_boxed_body(),
+            ))
+        })
+    }
+}
+
+/// Handler for `GET /admin/cache/stats`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the hits of each tier, the misses, L1 evictions and L2 errors.
+pub async fn stats(cache: web::Data<MultiLayerCache>) -> HttpResponse {
+    let stats = cache.stats();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    HttpResponse::Ok().json(json!({
+        "l1": {
+            "hits": load(&stats.l1_hits),
+            "evictions": load(&stats.l1_evictions),
+            "entries": cache.l1_len(),
+            "capacity": cache.inner.l1_size.get(),
+        },
+        "l2": {
+            "enabled": cache.inner.l2.is_some(),
+            "hits": load(&stats.l2_hits),
+            "errors": load(&stats.l2_errors),
+        },
+        "misses": load(&stats.misses),
+    }))
+}
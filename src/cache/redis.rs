@@ -0,0 +1,171 @@
+//! The Redis L2 tier.
+//!
+//! Like the Redis health check this speaks just enough RESP for `GET` and
+//! `SET ... PX`, without a client library. Connections are pooled and
+//! reused; one that fails or times out is dropped, and the next command
+//! opens a fresh one.
+
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use super::{CacheStore, MAX_ENTRY_BYTES};
+use crate::outbound::happy_eyeballs::{self, DEFAULT_ATTEMPT_DELAY};
+
+/// Idle connections kept for reuse.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// A reply to a command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Integer(i64),
+    /// A bulk string; `None` for the null reply of a missing key.
+    Bulk(Option<Vec<u8>>),
+}
+
+/// A Redis server used as [`CacheStore`].
+pub struct RedisStore {
+    addr: String,
+    timeout: Duration,
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+}
+
+impl RedisStore {
+    /// `addr` is the server's `host:port`; each command, connecting
+    /// included, must complete within `timeout`.
+    pub fn new(addr: &str, timeout: Duration) -> Self {
+        RedisStore {
+            addr: addr.to_string(),
+            timeout,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sends `args` as one command and reads the reply.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the server cannot be reached, answers with an
+    /// error or does not answer within the timeout.
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Reply, IoError> {
+        let pooled = self.idle.lock().await.pop();
+        let result = tokio::time::timeout(self.timeout, async {
+            let mut conn = match pooled {
+                Some(conn) => conn,
+                None => BufStream::new(
+                    happy_eyeballs::connect_host(&self.addr, DEFAULT_ATTEMPT_DELAY).await?,
+                ),
+            };
+            conn.write_all(&encode(args)).await?;
+            conn.flush().await?;
+            let reply = read_reply(&mut conn).await?;
+            Ok::<_, IoError>((conn, reply))
+        })
+        .await
+        .map_err(|_| IoError::new(ErrorKind::TimedOut, "Redis did not answer in time"))?;
+
+        // A connection is only reused after a complete reply
+        let (conn, reply) = result?;
+        let mut idle = self.idle.lock().await;
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(conn);
+        }
+        reply
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, IoError> {
+        match self.command(&[b"GET", key.as_bytes()]).await? {
+            Reply::Bulk(value) => Ok(value),
+            other => Err(unexpected("GET", &other)),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), IoError> {
+        let ttl = ttl.as_millis().max(1).to_string();
+        match self
+            .command(&[b"SET", key.as_bytes(), value, b"PX", ttl.as_bytes()])
+            .await?
+        {
+            Reply::Simple(ok) if ok == "OK" => Ok(()),
+            other => Err(unexpected("SET", &other)),
+        }
+    }
+}
+
+fn unexpected(command: &str, reply: &Reply) -> IoError {
+    IoError::new(
+        ErrorKind::InvalidData,
+        format!("unexpected reply to {}: {:?}", command, reply),
+    )
+}
+
+/// Encodes a command as a RESP array of bulk strings.
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Reads one reply. An error reply is returned as the inner error, since
+/// the connection stays usable after it.
+///
+/// # Errors
+///
+/// Returns an IoError on malformed replies and I/O failures.
+pub async fn read_reply<R>(reader: &mut R) -> Result<Result<Reply, IoError>, IoError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(IoError::new(
+            ErrorKind::UnexpectedEof,
+            "Redis closed the connection",
+        ));
+    }
+    let line = line.trim_end_matches(['\r', '\n']);
+    let malformed = || {
+        IoError::new(
+            ErrorKind::InvalidData,
+            format!("malformed reply '{}'", line),
+        )
+    };
+    let mut chars = line.chars();
+    let kind = chars.next();
+    let rest = chars.as_str();
+    match kind {
+        Some('+') => Ok(Ok(Reply::Simple(rest.to_string()))),
+        Some('-') => Ok(Err(IoError::other(rest.to_string()))),
+        Some(':') => Ok(Ok(Reply::Integer(rest.parse().map_err(|_| malformed())?))),
+        Some('$') => {
+            let len: i64 = rest.parse().map_err(|_| malformed())?;
+            if len < 0 {
+                return Ok(Ok(Reply::Bulk(None)));
+            }
+            // No entry is larger; never let the server size the allocation
+            if len as u64 > MAX_ENTRY_BYTES as u64 {
+                return Err(malformed());
+            }
+            let mut value = vec![0; len as usize + 2];
+            reader.read_exact(&mut value).await?;
+            if !value.ends_with(b"\r\n") {
+                return Err(malformed());
+            }
+            value.truncate(len as usize);
+            Ok(Ok(Reply::Bulk(Some(value))))
+        }
+        _ => Err(malformed()),
+    }
+}
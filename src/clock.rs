@@ -0,0 +1,176 @@
+//! The server's notion of the current time, shared by every time-based check.
+//!
+//! Hosts' clocks drift a few seconds apart, so a token minted by one
+//! instance can look expired, or not yet valid, to another. Every check of
+//! a timestamp minted elsewhere goes through a [`Clock`], which tolerates
+//! `CLOCK_SKEW_SECS` of drift in the caller's favor:
+//!
+//! * macaroon `time <` and `time >` caveats, the expiry and not-before of
+//!   bearer tokens;
+//! * the `X-Amz-Date` freshness of SigV4 signed requests;
+//! * the expiry of signed password reset tokens;
+//! * passkey challenges and sessions, which are only checked by the
+//!   instance that issued them and so are not given the tolerance.
+//!
+//! The clock is injectable: tests build one from a [`MockClock`] and move
+//! time forward instead of sleeping.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// Default for `CLOCK_SKEW_SECS`.
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// Where a [`Clock`] reads the time.
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+pub struct SystemTime;
+
+impl TimeSource for SystemTime {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    /// Starts the clock at `now`.
+    pub fn new(now: DateTime<Utc>) -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += chrono::Duration::from_std(by).expect("duration out of range");
+    }
+
+    /// Sets the clock to `now`.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    /// A [`Clock`] reading this one, with the default skew tolerance.
+    pub fn clock(&self) -> Clock {
+        Clock::with_source(Arc::new(self.clone()), DEFAULT_CLOCK_SKEW)
+    }
+}
+
+impl TimeSource for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// The current time and the skew tolerated when checking timestamps.
+#[derive(Clone)]
+pub struct Clock {
+    source: Arc<dyn TimeSource>,
+    skew: Duration,
+}
+
+impl Default for Clock {
+    /// The system clock with the default skew tolerance.
+    fn default() -> Self {
+        Clock::with_source(Arc::new(SystemTime), DEFAULT_CLOCK_SKEW)
+    }
+}
+
+impl Clock {
+    /// Reads the time from `source`, tolerating `skew`.
+    pub fn with_source(source: Arc<dyn TimeSource>, skew: Duration) -> Self {
+        Clock { source, skew }
+    }
+
+    /// The same clock with another skew tolerance.
+    pub fn with_skew(mut self, skew: Duration) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// A clock stopped at `now`, for checks at a given instant.
+    pub fn fixed(now: DateTime<Utc>) -> Self {
+        MockClock::new(now).clock()
+    }
+
+    /// The system clock, tolerating `CLOCK_SKEW_SECS`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if `CLOCK_SKEW_SECS` is not a number of seconds.
+    pub fn from_env() -> Result<Self, IoError> {
+        let skew = match env::var("CLOCK_SKEW_SECS") {
+            Ok(secs) => Duration::from_secs(secs.trim().parse().map_err(|_| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "CLOCK_SKEW_SECS must be a number of seconds, got '{}'",
+                        secs
+                    ),
+                )
+            })?),
+            Err(_) => DEFAULT_CLOCK_SKEW,
+        };
+        Ok(Clock::default().with_skew(skew))
+    }
+
+    /// The current time.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.source.now()
+    }
+
+    /// The current time in seconds since the Unix epoch.
+    pub fn timestamp(&self) -> i64 {
+        self.now().timestamp()
+    }
+
+    /// The tolerated skew.
+    pub fn skew(&self) -> Duration {
+        self.skew
+    }
+
+    /// Whether something valid until `expires_at` has expired, more than the
+    /// skew ago.
+    pub fn has_expired(&self, expires_at: DateTime<Utc>) -> bool {
+        (self.now() - expires_at)
+            .to_std()
+            .is_ok_and(|elapsed| elapsed >= self.skew)
+    }
+
+    /// [`has_expired`](Self::has_expired) for a Unix timestamp.
+    pub fn has_expired_at(&self, expires_at: i64) -> bool {
+        let skew = i64::try_from(self.skew.as_secs()).unwrap_or(i64::MAX);
+        expires_at.saturating_add(skew) <= self.timestamp()
+    }
+
+    /// Whether something valid from `not_before` is not valid yet, even
+    /// allowing for the skew.
+    pub fn is_before(&self, not_before: DateTime<Utc>) -> bool {
+        (not_before - self.now())
+            .to_std()
+            .is_ok_and(|ahead| ahead > self.skew)
+    }
+
+    /// Whether `at` is within `window`, plus the skew, of now in either
+    /// direction.
+    pub fn is_within(&self, at: DateTime<Utc>, window: Duration) -> bool {
+        let now = self.now();
+        let distance = if now >= at { now - at } else { at - now };
+        let distance = distance.to_std().unwrap_or(Duration::MAX);
+        distance <= window.saturating_add(self.skew)
+    }
+}
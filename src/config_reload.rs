@@ -0,0 +1,573 @@
+//! Previewing configuration changes before applying them.
+//!
+//! A reload used to be blind: SIGHUP rotated the admin API key and nobody
+//! saw what changed until something broke. [`ReloadableConfig`] knows the
+//! configuration the server runs with. [`preview`](ReloadableConfig::preview)
+//! loads the configuration again from the environment (and
+//! `ADMIN_API_KEY_FILE`), validates every field and lists the fields that
+//! differ, without changing anything; `GET /admin/config/preview` returns
+//! that list. [`apply`](ReloadableConfig::apply), behind
+//! `POST /admin/config/apply`, loads and validates once more and applies the
+//! result in one step, and the handler records the changes in the audit
+//! log.
+//!
+//! Only fields marked live take effect at once: the admin API key and the
+//! feature flags, whose file is also read again when only its contents
+//! changed. The others are read once at startup: a change to them is reported
+//! with `restart_required` and keeps being reported until the server
+//! restarts. Secret values are never returned or logged, only whether they
+//! changed.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde_json::{json, Value};
+
+use crate::error::FieldError;
+use crate::flags::{self, Flags};
+use crate::middleware::api_key::{validate_key, AdminKey};
+use crate::middleware::extra_headers::parse_extra_headers;
+use crate::middleware::header_sanitizer::HeaderSanitizer;
+use crate::util::real_ip::TrustedProxies;
+
+/// Placeholder returned instead of secret values.
+pub const REDACTED: &str = "[redacted]";
+
+/// A configuration field, named after its environment variable.
+pub struct ConfigField {
+    pub name: &'static str,
+    /// Whether the value must never be shown.
+    pub secret: bool,
+    /// Whether the server only reads it at startup.
+    pub restart_required: bool,
+    pub kind: FieldKind,
+    /// Value used when the variable is unset, if there is a fixed one.
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+    validate: fn(&str) -> Result<(), String>,
+}
+
+/// What a field holds, for [`schema`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    String,
+    Secret,
+    Path,
+    /// Comma-separated entries.
+    List,
+    Integer,
+    PositiveInteger,
+    SocketAddress,
+}
+
+fn any(_: &str) -> Result<(), String> {
+    Ok(())
+}
+
+fn number(value: &str) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a number", value))
+}
+
+fn positive(value: &str) -> Result<(), String> {
+    value
+        .parse::<NonZeroUsize>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a positive number", value))
+}
+
+fn socket_address(value: &str) -> Result<(), String> {
+    value
+        .to_socket_addrs()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// The fields [`ReloadableConfig`] tracks.
+pub const FIELDS: &[ConfigField] = &[
+    ConfigField {
+        name: "ADMIN_API_KEY",
+        secret: true,
+        restart_required: false,
+        kind: FieldKind::Secret,
+        default: None,
+        description: "Key required in the `X-Api-Key` header for every `/admin` route; when unset, all admin requests are rejected",
+        validate: validate_key,
+    },
+    ConfigField {
+        name: "FEATURE_FLAGS_FILE",
+        secret: false,
+        restart_required: false,
+        kind: FieldKind::Path,
+        default: None,
+        description: "JSON file declaring the feature flags",
+        validate: |path| {
+            flags::read_file(path)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        },
+    },
+    ConfigField {
+        name: "FEATURE_FLAGS",
+        secret: false,
+        restart_required: false,
+        kind: FieldKind::List,
+        default: None,
+        description: "Comma-separated `name=on` or `name=off` entries declaring flags, or forcing those of the file; a bare name is on",
+        validate: |list| flags::parse_env_flags(list).map(|_| ()),
+    },
+    ConfigField {
+        name: "ADMIN_API_KEY_FILE",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Path,
+        default: None,
+        description: "File holding the admin key instead of `ADMIN_API_KEY`, read again on reload",
+        validate: any,
+    },
+    ConfigField {
+        name: "SERVER_ADDRESS",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::SocketAddress,
+        default: Some("127.0.0.1:3000"),
+        description: "Address and port the server listens on",
+        validate: socket_address,
+    },
+    ConfigField {
+        name: "NUM_WORKERS",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::PositiveInteger,
+        default: None,
+        description: "Number of worker threads; by default the number of CPU cores, limited to the cgroup CPU quota",
+        validate: positive,
+    },
+    ConfigField {
+        name: "CERT_FILE",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Path,
+        default: Some("cert.pem"),
+        description: "PEM file of the TLS certificate chain",
+        validate: any,
+    },
+    ConfigField {
+        name: "KEY_FILE",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Path,
+        default: Some("key.pem"),
+        description: "PEM file of the TLS private key",
+        validate: any,
+    },
+    ConfigField {
+        name: "CLIENT_CA_FILE",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Path,
+        default: None,
+        description: "PEM file of CA certificates; when set, clients must present a certificate signed by one of them",
+        validate: any,
+    },
+    ConfigField {
+        name: "TRUSTED_PROXIES",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::List,
+        default: None,
+        description: "Comma-separated addresses or CIDR ranges of reverse proxies whose forwarding headers are believed",
+        validate: |list| {
+            TrustedProxies::parse(list)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        },
+    },
+    ConfigField {
+        name: "STRIP_UNTRUSTED_HEADERS",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::List,
+        default: None,
+        description: "Comma-separated header names removed from requests not coming from a trusted proxy",
+        validate: |list| {
+            HeaderSanitizer::default()
+                .extended(list)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        },
+    },
+    ConfigField {
+        name: "EXTRA_RESPONSE_HEADERS",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::List,
+        default: None,
+        description: "Comma-separated `Name:Value` pairs added to every response unless the handler sets the header",
+        validate: |list| {
+            parse_extra_headers(list)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        },
+    },
+    ConfigField {
+        name: "HSTS_MAX_AGE",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Integer,
+        default: Some("31536000"),
+        description: "`max-age` of the `Strict-Transport-Security` header, in seconds",
+        validate: number,
+    },
+    ConfigField {
+        name: "RESOURCE_CPU_BUDGET_MS",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Integer,
+        default: Some("0"),
+        description: "CPU time a request may use, in milliseconds; 0 is unlimited",
+        validate: number,
+    },
+    ConfigField {
+        name: "RESOURCE_MEMORY_BUDGET_BYTES",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::Integer,
+        default: Some("0"),
+        description: "Memory a request may hold, in bytes; 0 is unlimited",
+        validate: number,
+    },
+    ConfigField {
+        name: "RUST_LOG",
+        secret: false,
+        restart_required: true,
+        kind: FieldKind::String,
+        default: Some("error"),
+        description: "Log filter, e.g. `info`, `debug` or `warn`",
+        validate: any,
+    },
+];
+
+impl FieldKind {
+    fn name(self) -> &'static str {
+        match self {
+            FieldKind::String => "string",
+            FieldKind::Secret => "secret",
+            FieldKind::Path => "path",
+            FieldKind::List => "list",
+            FieldKind::Integer => "integer",
+            FieldKind::PositiveInteger => "positive_integer",
+            FieldKind::SocketAddress => "socket_address",
+        }
+    }
+}
+
+/// Describes [`FIELDS`] as a JSON Schema (draft 2020-12) of an object keyed
+/// by environment variable, printed by `--print-schema`.
+///
+/// Integer fields are typed `integer` with their minimum, all others
+/// `string`. Each property carries its description and default, if any;
+/// secrets are `writeOnly`, and `x-kind` and `x-restart-required` keep the
+/// rest of the descriptor.
+///
+/// # Returns
+///
+/// * `Value` - The schema.
+pub fn schema() -> Value {
+    let properties: serde_json::Map<String, Value> = FIELDS
+        .iter()
+        .map(|field| {
+            let mut property = json!({
+                "description": field.description,
+                "x-kind": field.kind.name(),
+                "x-restart-required": field.restart_required,
+            });
+            let minimum = match field.kind {
+                FieldKind::Integer => Some(0),
+                FieldKind::PositiveInteger => Some(1),
+                _ => None,
+            };
+            match minimum {
+                Some(minimum) => {
+                    property["type"] = json!("integer");
+                    property["minimum"] = json!(minimum);
+                }
+                None => property["type"] = json!("string"),
+            }
+            if let Some(default) = field.default {
+                property["default"] = match minimum {
+                    Some(_) => default
+                        .parse::<u64>()
+                        .map(Value::from)
+                        .unwrap_or(json!(default)),
+                    None => json!(default),
+                };
+            }
+            if field.secret {
+                property["writeOnly"] = json!(true);
+            }
+            (field.name.to_string(), property)
+        })
+        .collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "secure-actix-web-server configuration",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": true,
+    })
+}
+
+/// Field values by name; `None` for unset fields.
+pub type ConfigValues = BTreeMap<&'static str, Option<String>>;
+
+/// Reads every field from the environment, the admin API key from
+/// `ADMIN_API_KEY_FILE` when that is set.
+///
+/// # Errors
+///
+/// Returns a field error for every value that cannot be read.
+pub fn load() -> Result<ConfigValues, Vec<FieldError>> {
+    let mut values = ConfigValues::new();
+    let mut errors = Vec::new();
+    for field in FIELDS {
+        let mut value = env::var(field.name).ok().filter(|v| !v.is_empty());
+        if field.name == "ADMIN_API_KEY" {
+            if let Some(path) = env::var("ADMIN_API_KEY_FILE")
+                .ok()
+                .filter(|p| !p.is_empty())
+            {
+                match fs::read_to_string(&path) {
+                    Ok(contents) => {
+                        value = Some(contents.trim_end_matches(['\r', '\n']).to_string())
+                    }
+                    Err(e) => errors.push(FieldError {
+                        field: field.name.to_string(),
+                        message: format!("cannot read ADMIN_API_KEY_FILE '{}': {}", path, e),
+                    }),
+                }
+            }
+        }
+        values.insert(field.name, value);
+    }
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(errors)
+    }
+}
+
+/// A field whose value would change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigChange {
+    pub field: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    pub secret: bool,
+    pub restart_required: bool,
+}
+
+impl ConfigChange {
+    /// The change as JSON, secret values replaced by [`REDACTED`].
+    pub fn describe(&self) -> Value {
+        let shown = |value: &Option<String>| match value {
+            Some(_) if self.secret => json!(REDACTED),
+            value => json!(value),
+        };
+        json!({
+            "field": self.field,
+            "old": shown(&self.old),
+            "new": shown(&self.new),
+            "secret": self.secret,
+            "restart_required": self.restart_required,
+        })
+    }
+}
+
+/// The configuration the server runs with, shared by every worker.
+#[derive(Clone)]
+pub struct ReloadableConfig {
+    /// Values in effect: read at startup, then updated as live fields are
+    /// applied.
+    current: Arc<RwLock<ConfigValues>>,
+    admin_key: AdminKey,
+    /// Feature flags reloaded on apply; without them the flag fields are
+    /// only compared.
+    flags: Option<Flags>,
+    /// Serializes [`apply`](Self::apply) calls.
+    applying: Arc<Mutex<()>>,
+}
+
+/// Whether a field declares feature flags.
+fn is_flag_field(name: &str) -> bool {
+    name == "FEATURE_FLAGS_FILE" || name == "FEATURE_FLAGS"
+}
+
+impl ReloadableConfig {
+    /// Tracks `startup`, the values the server started with, and the live
+    /// `admin_key`.
+    pub fn new(startup: ConfigValues, admin_key: AdminKey) -> Self {
+        ReloadableConfig {
+            current: Arc::new(RwLock::new(startup)),
+            admin_key,
+            flags: None,
+            applying: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Reloads `flags` when the flag fields, or the contents of
+    /// `FEATURE_FLAGS_FILE`, change.
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Tracks the current environment, whatever its validity: startup has
+    /// already checked the values the server uses.
+    pub fn from_env(admin_key: AdminKey) -> Self {
+        let values = load().unwrap_or_else(|_| {
+            FIELDS
+                .iter()
+                .map(|field| (field.name, env::var(field.name).ok()))
+                .collect()
+        });
+        ReloadableConfig::new(values, admin_key)
+    }
+
+    /// Loads the configuration again and lists how it differs from the one
+    /// in effect, changing nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns every invalid field, not only the first.
+    pub fn preview(&self) -> Result<Vec<ConfigChange>, Vec<FieldError>> {
+        let loaded = load()?;
+        let current = self.current.read().unwrap().clone();
+        let mut changes = Vec::new();
+        let mut errors = Vec::new();
+        for field in FIELDS {
+            let new = loaded.get(field.name).cloned().flatten();
+            if let Some(value) = &new {
+                if let Err(message) = (field.validate)(value) {
+                    errors.push(FieldError {
+                        field: field.name.to_string(),
+                        message,
+                    });
+                    continue;
+                }
+            }
+            let old = current.get(field.name).cloned().flatten();
+            let changed = if field.name == "ADMIN_API_KEY" {
+                match &new {
+                    Some(key) => !self.admin_key.matches(key.as_bytes()),
+                    None => self.admin_key.is_set(),
+                }
+            } else {
+                old != new
+            };
+            if !changed {
+                continue;
+            }
+            if field.name == "ADMIN_API_KEY" && new.is_none() {
+                errors.push(FieldError {
+                    field: field.name.to_string(),
+                    message: "the admin API key cannot be removed at runtime".to_string(),
+                });
+                continue;
+            }
+            changes.push(ConfigChange {
+                field: field.name,
+                old,
+                new,
+                secret: field.secret,
+                restart_required: field.restart_required,
+            });
+        }
+
+        // The flags file may have been edited under the same name
+        if let Some(declared) = &self.flags {
+            let unchanged = !changes.iter().any(|c| is_flag_field(c.field));
+            let path = current.get("FEATURE_FLAGS_FILE").cloned().flatten();
+            if unchanged && errors.is_empty() && path.is_some() {
+                match flags::load_definitions() {
+                    Ok(definitions) if !declared.declares(&definitions) => {
+                        changes.push(ConfigChange {
+                            field: "FEATURE_FLAGS_FILE",
+                            old: path.clone(),
+                            new: path,
+                            secret: false,
+                            restart_required: false,
+                        })
+                    }
+                    Ok(_) => {}
+                    Err(e) => errors.push(FieldError {
+                        field: "FEATURE_FLAGS_FILE".to_string(),
+                        message: e.to_string(),
+                    }),
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(changes)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Loads and validates the configuration again, then applies the live
+    /// changes at once. Changes needing a restart are returned but not
+    /// applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns every invalid field; nothing is applied then.
+    pub fn apply(&self) -> Result<Vec<ConfigChange>, Vec<FieldError>> {
+        let _applying = self.applying.lock().unwrap_or_else(|e| e.into_inner());
+        let changes = self.preview()?;
+        let live: Vec<&ConfigChange> = changes.iter().filter(|c| !c.restart_required).collect();
+
+        // Loaded before anything is applied, so a failure applies nothing
+        let definitions = match (&self.flags, live.iter().find(|c| is_flag_field(c.field))) {
+            (Some(_), Some(change)) => Some(flags::load_definitions().map_err(|e| {
+                vec![FieldError {
+                    field: change.field.to_string(),
+                    message: e.to_string(),
+                }]
+            })?),
+            _ => None,
+        };
+
+        for change in &live {
+            if change.field == "ADMIN_API_KEY" {
+                if let Some(key) = &change.new {
+                    self.admin_key.rotate(key).map_err(|e| {
+                        vec![FieldError {
+                            field: change.field.to_string(),
+                            message: e.to_string(),
+                        }]
+                    })?;
+                }
+            }
+        }
+        if let (Some(declared), Some(definitions)) = (&self.flags, definitions) {
+            declared.reload(definitions).map_err(|e| {
+                vec![FieldError {
+                    field: "FEATURE_FLAGS_FILE".to_string(),
+                    message: e.to_string(),
+                }]
+            })?;
+        }
+
+        let mut current = self.current.write().unwrap();
+        for change in live {
+            current.insert(change.field, change.new.clone());
+        }
+        Ok(changes)
+    }
+}
@@ -0,0 +1,80 @@
+//! Live open connections, by protocol.
+//!
+//! `open_connections{protocol=...}` on `/metrics` is the number of client
+//! connections open right now: `http/1.1` and `h2` by the protocol
+//! negotiated through ALPN, and `websocket` for WebSockets served with
+//! [`ws::serve`](crate::ws::serve). A WebSocket also counts as the HTTP/1.1
+//! connection it was upgraded from.
+//!
+//! Each connection holds an [`OpenConnection`] guard in its connection data,
+//! which the server drops when the connection ends for any reason, a client
+//! vanishing mid-request included, so the gauge always comes back down.
+
+use std::any::Any;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use actix_tls::accept::rustls_0_20::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+
+use crate::metrics::Metrics;
+
+/// Name of the gauge.
+pub const OPEN_CONNECTIONS: &str = "open_connections";
+
+/// Counts one open connection of a protocol until dropped.
+#[derive(Debug)]
+pub struct OpenConnection {
+    gauge: Arc<AtomicI64>,
+}
+
+impl OpenConnection {
+    /// Counts a connection speaking `protocol` as open.
+    pub fn open(protocol: &str) -> Self {
+        let gauge = Metrics::global().gauge(OPEN_CONNECTIONS, &[("protocol", protocol)]);
+        gauge.fetch_add(1, Ordering::Relaxed);
+        OpenConnection { gauge }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The `protocol` label for a connection that negotiated `alpn`; without
+/// ALPN a client speaks HTTP/1.1.
+pub fn protocol(alpn: Option<&[u8]>) -> &'static str {
+    match alpn {
+        Some(b"h2") => "h2",
+        _ => "http/1.1",
+    }
+}
+
+/// Connection callback for `HttpServer::on_connect`, counting the
+/// connection until the server drops its connection data.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let protocol = if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        protocol(tls.get_ref().1.alpn_protocol())
+    } else if connection.is::<TcpStream>() {
+        protocol(None)
+    } else {
+        return;
+    };
+    data.insert(OpenConnection::open(protocol));
+}
+
+/// Current value of `open_connections` for `protocol`.
+pub fn open(protocol: &str) -> i64 {
+    Metrics::global()
+        .gauge(OPEN_CONNECTIONS, &[("protocol", protocol)])
+        .load(Ordering::Relaxed)
+}
+
+/// Open HTTP/1.1 connections, those a drain waits for: HTTP/2 clients keep
+/// theirs open for as long as they answer pings.
+pub fn open_http1() -> usize {
+    open(protocol(None)).max(0) as usize
+}
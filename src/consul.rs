@@ -0,0 +1,263 @@
+//! Consul service registration.
+//!
+//! Enabled with the `consul` feature. At startup the service registers with
+//! the local Consul agent, advertising its address, port and tags together
+//! with two checks:
+//!
+//! * an HTTPS check against `/health`, run by the agent every
+//!   `CONSUL_CHECK_INTERVAL_SECS`;
+//! * a TTL check kept alive by a background heartbeat job.
+//!
+//! If a heartbeat fails (for example because the agent restarted and forgot
+//! the service), the job re-registers with exponential backoff. None of these
+//! failures stop the server from serving; they are only logged. The service
+//! is deregistered during graceful shutdown.
+
+use actix_web::rt::task::JoinHandle;
+use actix_web::rt::time::sleep;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::outbound::HttpClient;
+
+/// Longest wait between re-registration attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Shortest accepted `CONSUL_TTL_SECS`, so heartbeats are at least a second apart.
+pub const MIN_TTL: Duration = Duration::from_secs(3);
+
+/// Longest wait for an answer from the agent.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings for registering with the Consul agent.
+#[derive(Clone, Debug)]
+pub struct ConsulConfig {
+    /// Base URL of the Consul agent HTTP API.
+    pub agent_url: String,
+    pub service_id: String,
+    pub service_name: String,
+    /// Address advertised to other services.
+    pub address: String,
+    pub port: u16,
+    pub tags: Vec<String>,
+    /// How often the agent runs the HTTPS `/health` check.
+    pub check_interval: Duration,
+    /// TTL of the heartbeat check; heartbeats are sent at a third of it.
+    pub ttl: Duration,
+    /// Skip certificate verification in the agent's HTTPS check.
+    pub tls_skip_verify: bool,
+}
+
+impl ConsulConfig {
+    /// Builds the configuration from environment variables.
+    ///
+    /// `server_address` is the `host:port` the server binds to; it is
+    /// advertised unless `CONSUL_SERVICE_ADDRESS` overrides the host.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<ConsulConfig>, IoError>` - The configuration, or `None` if the port cannot be determined.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `CONSUL_TTL_SECS` is below [`MIN_TTL`].
+    pub fn from_env(server_address: &str) -> Result<Option<ConsulConfig>, IoError> {
+        let ttl = Duration::from_secs(secs_from_env("CONSUL_TTL_SECS", 30));
+        if ttl < MIN_TTL {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "CONSUL_TTL_SECS must be at least {}, got {}",
+                    MIN_TTL.as_secs(),
+                    ttl.as_secs()
+                ),
+            ));
+        }
+        let (host, port) = match server_address.rsplit_once(':') {
+            Some((host, port)) => match port.parse() {
+                Ok(port) => (host, port),
+                Err(_) => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let address = env::var("CONSUL_SERVICE_ADDRESS").unwrap_or_else(|_| host.to_string());
+        let service_name =
+            env::var("CONSUL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+        let service_id = env::var("CONSUL_SERVICE_ID")
+            .unwrap_or_else(|_| format!("{}-{}-{}", service_name, address, port));
+
+        Ok(Some(ConsulConfig {
+            agent_url: env::var("CONSUL_HTTP_ADDR")
+                .unwrap_or_else(|_| "http://127.0.0.1:8500".to_string()),
+            service_id,
+            service_name,
+            address,
+            port,
+            tags: env::var("CONSUL_TAGS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(str::to_string)
+                .collect(),
+            check_interval: Duration::from_secs(secs_from_env("CONSUL_CHECK_INTERVAL_SECS", 10)),
+            ttl,
+            tls_skip_verify: env::var("CONSUL_TLS_SKIP_VERIFY")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+        }))
+    }
+
+    fn ttl_check_id(&self) -> String {
+        format!("{}:ttl", self.service_id)
+    }
+}
+
+fn secs_from_env(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Registers this instance with Consul and keeps the registration alive.
+pub struct ConsulRegistration {
+    client: reqwest::Client,
+    config: ConsulConfig,
+}
+
+impl ConsulRegistration {
+    /// Creates a registration for `config`.
+    pub fn new(config: ConsulConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        ConsulRegistration { client, config }
+    }
+
+    /// Sends the requests to the agent with the shared `client`.
+    pub fn with_client(mut self, client: &HttpClient) -> Self {
+        self.client = client.client().clone();
+        self
+    }
+
+    /// Returns the registration configuration.
+    pub fn config(&self) -> &ConsulConfig {
+        &self.config
+    }
+
+    /// Returns the JSON body sent to `/v1/agent/service/register`.
+    pub fn registration_payload(&self) -> Value {
+        let config = &self.config;
+        json!({
+            "ID": config.service_id,
+            "Name": config.service_name,
+            "Address": config.address,
+            "Port": config.port,
+            "Tags": config.tags,
+            "Checks": [
+                {
+                    "CheckID": format!("{}:https", config.service_id),
+                    "Name": "HTTPS health",
+                    "HTTP": format!("https://{}:{}/health", config.address, config.port),
+                    "Interval": format!("{}s", config.check_interval.as_secs()),
+                    "TLSSkipVerify": config.tls_skip_verify,
+                },
+                {
+                    "CheckID": config.ttl_check_id(),
+                    "Name": "Heartbeat",
+                    "TTL": format!("{}ms", config.ttl.as_millis()),
+                },
+            ],
+        })
+    }
+
+    /// Registers the service and its checks with the agent.
+    pub async fn register(&self) -> Result<(), reqwest::Error> {
+        self.client
+            .put(self.url("/v1/agent/service/register"))
+            .timeout(REQUEST_TIMEOUT)
+            .json(&self.registration_payload())
+            .send()
+            .await?
+            .error_for_status()?;
+        info!(
+            "Registered service '{}' with Consul at {}",
+            self.config.service_id, self.config.agent_url
+        );
+        Ok(())
+    }
+
+    /// Marks the TTL check as passing.
+    pub async fn heartbeat(&self) -> Result<(), reqwest::Error> {
+        let path = format!("/v1/agent/check/pass/{}", self.config.ttl_check_id());
+        self.client
+            .put(self.url(&path))
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Removes the service from the agent.
+    pub async fn deregister(&self) -> Result<(), reqwest::Error> {
+        let path = format!("/v1/agent/service/deregister/{}", self.config.service_id);
+        self.client
+            .put(self.url(&path))
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        info!(
+            "Deregistered service '{}' from Consul",
+            self.config.service_id
+        );
+        Ok(())
+    }
+
+    /// Starts the background job that registers and then heartbeats.
+    ///
+    /// Heartbeats are sent every third of the TTL. A failed heartbeat
+    /// triggers re-registration with exponential backoff.
+    pub fn spawn(self: Arc<Self>) -> JoinHandle<()> {
+        actix_web::rt::spawn(async move {
+            let interval = self.config.ttl / 3;
+            self.register_with_backoff().await;
+            loop {
+                sleep(interval).await;
+                if let Err(e) = self.heartbeat().await {
+                    warn!("Consul heartbeat failed, re-registering: {}", e);
+                    self.register_with_backoff().await;
+                }
+            }
+        })
+    }
+
+    async fn register_with_backoff(&self) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match self.register().await {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!(
+                        "Consul registration failed, retrying in {}s: {}",
+                        backoff.as_secs(),
+                        e
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.agent_url.trim_end_matches('/'), path)
+    }
+}
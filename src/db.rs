@@ -0,0 +1,91 @@
+//! SQLite persistence, only compiled with the `db` feature.
+//!
+//! A single connection is shared behind a mutex; queries run on the blocking
+//! thread pool through [`Database::run`] so they never stall a worker. The
+//! schema is created on open.
+
+use std::env;
+use std::io::Error as IoError;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+
+/// Schema, applied on every open; each statement must be idempotent.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS audit_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp_ms INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    principal TEXT,
+    request_id TEXT,
+    details TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_events_timestamp ON audit_events (timestamp_ms);
+CREATE INDEX IF NOT EXISTS audit_events_principal ON audit_events (principal, id);
+CREATE INDEX IF NOT EXISTS audit_events_action ON audit_events (action, id);
+";
+
+/// Handle to the database, cheap to clone.
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Database {
+    /// Opens (or creates) the database file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be opened or the schema cannot
+    /// be applied.
+    pub fn open(path: &str) -> Result<Database, IoError> {
+        Database::init(Connection::open(path).map_err(to_io)?)
+    }
+
+    /// Opens a private in-memory database.
+    pub fn open_in_memory() -> Result<Database, IoError> {
+        Database::init(Connection::open_in_memory().map_err(to_io)?)
+    }
+
+    /// Opens the database at `DATABASE_PATH`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Database>, IoError>` - The database, `None` if `DATABASE_PATH` is not set, or an IoError if it cannot be opened.
+    pub fn from_env() -> Result<Option<Database>, IoError> {
+        match env::var("DATABASE_PATH") {
+            Ok(path) => Database::open(&path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn init(conn: Connection) -> Result<Database, IoError> {
+        conn.execute_batch(SCHEMA).map_err(to_io)?;
+        Ok(Database {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if `f` fails or the blocking task panics.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, IoError>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        actix_web::rt::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn).map_err(to_io)
+        })
+        .await
+        .map_err(IoError::other)?
+    }
+}
+
+fn to_io(e: rusqlite::Error) -> IoError {
+    IoError::other(e)
+}
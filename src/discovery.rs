@@ -0,0 +1,296 @@
+//! Discovery files for crawlers and security researchers.
+//!
+//! * `GET /robots.txt` disallows all crawling by default. `ROBOTS_POLICY`
+//!   selects `disallow`, `allow` or `file`, which serves `ROBOTS_FILE`;
+//!   setting `ROBOTS_FILE` alone selects `file`.
+//! * `GET /.well-known/security.txt` is generated per RFC 9116 from
+//!   `SECURITY_TXT_CONTACT`, `SECURITY_TXT_EXPIRES`, `SECURITY_TXT_POLICY`
+//!   and `SECURITY_TXT_PREFERRED_LANGUAGES`. It is only served when a
+//!   contact is configured; an `Expires` date in the past fails startup,
+//!   since RFC 9116 tells readers to ignore an expired file.
+//!
+//! Both are read at startup and served as `text/plain` with a one-day
+//! cache lifetime. They stay reachable for everyone: the paths are exempt
+//! from the path-based authentication middlewares, from per-tenant rate
+//! limits and from load shedding.
+
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+
+use actix_web::http::{header, Method};
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use log::{info, warn};
+
+use crate::route_meta::{DescribedRoute, RouteMetadata};
+use crate::routes::ONE_DAY;
+
+/// Paths served to anonymous clients whatever the server's other settings.
+pub const PUBLIC_PATHS: &[&str] = &["/robots.txt", "/.well-known/security.txt"];
+
+/// Cache lifetime of the discovery files.
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// Returns whether `path` is one of the [`PUBLIC_PATHS`].
+pub fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path)
+}
+
+/// What `/robots.txt` tells crawlers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RobotsPolicy {
+    /// Disallow crawling of every path.
+    DisallowAll,
+    /// Allow crawling of every path.
+    AllowAll,
+    /// Serve these contents, read from `ROBOTS_FILE`.
+    File(String),
+}
+
+impl RobotsPolicy {
+    /// Reads `ROBOTS_POLICY` and `ROBOTS_FILE`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the policy is unknown, or `file` is selected and
+    /// `ROBOTS_FILE` is unset or cannot be read.
+    pub fn from_env() -> Result<Self, IoError> {
+        let file = env::var("ROBOTS_FILE").ok().filter(|f| !f.is_empty());
+        let policy = env::var("ROBOTS_POLICY").ok();
+        let policy = policy
+            .as_deref()
+            .unwrap_or(if file.is_some() { "file" } else { "disallow" });
+        match policy {
+            "disallow" => Ok(RobotsPolicy::DisallowAll),
+            "allow" => Ok(RobotsPolicy::AllowAll),
+            "file" => {
+                let path = file.ok_or_else(|| {
+                    IoError::new(
+                        ErrorKind::InvalidInput,
+                        "ROBOTS_POLICY=file needs ROBOTS_FILE",
+                    )
+                })?;
+                let contents = fs::read_to_string(&path).map_err(|e| {
+                    IoError::new(
+                        e.kind(),
+                        format!("cannot read ROBOTS_FILE '{}': {}", path, e),
+                    )
+                })?;
+                info!("Serving /robots.txt from {}", path);
+                Ok(RobotsPolicy::File(contents))
+            }
+            other => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unknown ROBOTS_POLICY '{}', expected disallow, allow or file",
+                    other
+                ),
+            )),
+        }
+    }
+
+    /// Renders the robots.txt body.
+    pub fn render(&self) -> String {
+        match self {
+            RobotsPolicy::DisallowAll => "User-agent: *\nDisallow: /\n".to_string(),
+            RobotsPolicy::AllowAll => "User-agent: *\nDisallow:\n".to_string(),
+            RobotsPolicy::File(contents) => contents.clone(),
+        }
+    }
+}
+
+/// The fields of `/.well-known/security.txt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SecurityTxt {
+    /// `mailto:`, `https:` or `tel:` URIs, most preferred first.
+    pub contacts: Vec<String>,
+    pub expires: DateTime<Utc>,
+    pub policy: Option<String>,
+    /// Language tags, e.g. `en, fr`.
+    pub preferred_languages: Option<String>,
+}
+
+impl SecurityTxt {
+    /// Checks the fields against RFC 9116 as of `now`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no contact, a contact or the policy is
+    /// not a valid URI, or `expires` is not after `now`.
+    pub fn new(
+        contacts: Vec<String>,
+        expires: DateTime<Utc>,
+        policy: Option<String>,
+        preferred_languages: Option<String>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, IoError> {
+        let invalid = |msg: String| IoError::new(ErrorKind::InvalidInput, msg);
+        if contacts.is_empty() {
+            return Err(invalid("security.txt needs at least one contact".into()));
+        }
+        if let Some(contact) = contacts.iter().find(|c| {
+            !["mailto:", "https://", "tel:"]
+                .iter()
+                .any(|scheme| c.starts_with(scheme))
+        }) {
+            return Err(invalid(format!(
+                "security.txt contact '{}' must be a mailto:, https:// or tel: URI",
+                contact
+            )));
+        }
+        if let Some(policy) = policy.as_ref().filter(|p| !p.starts_with("https://")) {
+            return Err(invalid(format!(
+                "security.txt policy '{}' must be an https:// URL",
+                policy
+            )));
+        }
+        if expires <= now {
+            return Err(invalid(format!(
+                "security.txt expired on {}; set SECURITY_TXT_EXPIRES to a future date",
+                expires.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )));
+        }
+        if expires > now + Duration::days(365) {
+            warn!("security.txt expires more than a year ahead, which RFC 9116 advises against");
+        }
+        Ok(SecurityTxt {
+            contacts,
+            expires,
+            policy,
+            preferred_languages,
+        })
+    }
+
+    /// Reads `SECURITY_TXT_CONTACT` (comma separated), `SECURITY_TXT_EXPIRES`
+    /// (RFC 3339), `SECURITY_TXT_POLICY` and
+    /// `SECURITY_TXT_PREFERRED_LANGUAGES`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<SecurityTxt>, IoError>` - The fields, `None` if no contact is configured, or an IoError if they are invalid or expired.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let contacts: Vec<String> = env::var("SECURITY_TXT_CONTACT")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+            .collect();
+        if contacts.is_empty() {
+            return Ok(None);
+        }
+        let expires = env::var("SECURITY_TXT_EXPIRES").map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                "SECURITY_TXT_CONTACT needs SECURITY_TXT_EXPIRES",
+            )
+        })?;
+        let expires = DateTime::parse_from_rfc3339(&expires)
+            .map_err(|e| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid SECURITY_TXT_EXPIRES '{}': {}", expires, e),
+                )
+            })?
+            .with_timezone(&Utc);
+        let optional = |var: &str| env::var(var).ok().filter(|v| !v.trim().is_empty());
+        SecurityTxt::new(
+            contacts,
+            expires,
+            optional("SECURITY_TXT_POLICY"),
+            optional("SECURITY_TXT_PREFERRED_LANGUAGES"),
+            Utc::now(),
+        )
+        .map(Some)
+    }
+
+    /// Renders the security.txt body, one `Field: value` line per field.
+    pub fn render(&self) -> String {
+        let mut body = String::new();
+        for contact in &self.contacts {
+            body.push_str(&format!("Contact: {}\n", contact));
+        }
+        body.push_str(&format!(
+            "Expires: {}\n",
+            self.expires.to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+        if let Some(policy) = &self.policy {
+            body.push_str(&format!("Policy: {}\n", policy));
+        }
+        if let Some(languages) = &self.preferred_languages {
+            body.push_str(&format!("Preferred-Languages: {}\n", languages));
+        }
+        body
+    }
+}
+
+/// The rendered discovery files.
+#[derive(Clone, Debug)]
+pub struct Discovery {
+    pub robots: String,
+    /// `None` when no security contact is configured.
+    pub security_txt: Option<String>,
+}
+
+impl Discovery {
+    pub fn new(robots: &RobotsPolicy, security_txt: Option<&SecurityTxt>) -> Self {
+        Discovery {
+            robots: robots.render(),
+            security_txt: security_txt.map(SecurityTxt::render),
+        }
+    }
+
+    /// Reads [`RobotsPolicy::from_env`] and [`SecurityTxt::from_env`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either is invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        let robots = RobotsPolicy::from_env()?;
+        let security_txt = SecurityTxt::from_env()?;
+        Ok(Discovery::new(&robots, security_txt.as_ref()))
+    }
+}
+
+/// Handler for `GET /robots.txt`.
+pub async fn robots_txt(discovery: web::Data<Discovery>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+        .content_type("text/plain; charset=utf-8")
+        .body(discovery.robots.clone())
+}
+
+/// Handler for `GET /.well-known/security.txt`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the file, or 404 Not Found when no contact is configured.
+pub async fn security_txt(discovery: web::Data<Discovery>) -> HttpResponse {
+    match &discovery.security_txt {
+        Some(body) => HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .content_type("text/plain; charset=utf-8")
+            .body(body.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Registers the discovery routes.
+pub fn configure(discovery: web::Data<Discovery>) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(discovery)
+            .described_route(
+                Method::GET,
+                "/robots.txt",
+                robots_txt,
+                RouteMetadata::new("Crawler policy").cache_ttl(ONE_DAY),
+            )
+            .described_route(
+                Method::GET,
+                "/.well-known/security.txt",
+                security_txt,
+                RouteMetadata::new("RFC 9116 security contact").cache_ttl(ONE_DAY),
+            );
+    }
+}
@@ -0,0 +1,300 @@
+//! Route groups that can be switched off at runtime.
+//!
+//! A [`DynamicScope`] is a [`Scope`] registered under a feature name in
+//! [`Features`]. While the feature is disabled, every route of the scope
+//! answers 404 `not_found`, as if it did not exist; no restart is needed
+//! to bring it back. Admins toggle features with
+//! `POST /admin/features/{name}/enable` and `.../disable`, and list them
+//! with `GET /admin/features`. Every change is recorded in the audit trail.
+//!
+//! `FEATURES_ENABLED` lists the features enabled at startup, comma
+//! separated; the others start disabled. When it is unset, every feature
+//! starts enabled.
+//!
+//! Unlike [feature flags](crate::flags), which handlers evaluate per
+//! caller, a feature here is on or off for everyone, and stays so until
+//! toggled again.
+
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{
+    forward_ready, AppService, HttpServiceFactory, Service, ServiceRequest, ServiceResponse,
+    Transform,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, Error, HttpResponse, ResponseError, Scope};
+use futures_util::future::LocalBoxFuture;
+use log::info;
+use serde_json::{json, Value};
+
+use crate::audit::AuditTrail;
+use crate::error::ApiError;
+use crate::route_meta::{DescribedRoute, RouteMetadata};
+
+/// Cheap, cloneable handle to the registered features and their states.
+#[derive(Clone, Default)]
+pub struct Features {
+    states: Arc<RwLock<BTreeMap<String, Arc<AtomicBool>>>>,
+    /// Features enabled at registration; `None` enables all of them.
+    initially_enabled: Option<Arc<HashSet<String>>>,
+}
+
+impl Features {
+    /// Creates features enabled at registration if listed in `enabled`,
+    /// or all of them when it is `None`.
+    pub fn new(enabled: Option<Vec<String>>) -> Self {
+        Features {
+            states: Arc::default(),
+            initially_enabled: enabled.map(|names| Arc::new(names.into_iter().collect())),
+        }
+    }
+
+    /// Reads `FEATURES_ENABLED`.
+    pub fn from_env() -> Self {
+        let enabled = env::var("FEATURES_ENABLED").ok().map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        });
+        if let Some(names) = &enabled {
+            info!("Features enabled at startup: {}", names.join(", "));
+        }
+        Features::new(enabled)
+    }
+
+    /// Returns the state of `name`, registering the feature on first use.
+    /// Every worker's scope shares the same state.
+    pub fn register(&self, name: &str) -> Arc<AtomicBool> {
+        if let Some(state) = self.states.read().unwrap().get(name) {
+            return state.clone();
+        }
+        let enabled = self
+            .initially_enabled
+            .as_ref()
+            .is_none_or(|names| names.contains(name));
+        self.states
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(enabled)))
+            .clone()
+    }
+
+    /// Returns whether `name` is enabled, or `None` for an unknown feature.
+    pub fn is_enabled(&self, name: &str) -> Option<bool> {
+        self.states
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|state| state.load(Ordering::Relaxed))
+    }
+
+    /// Enables or disables `name`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<bool>` - The previous state, or `None` for an unknown feature.
+    pub fn set(&self, name: &str, enabled: bool) -> Option<bool> {
+        self.states
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|state| state.swap(enabled, Ordering::Relaxed))
+    }
+
+    /// Describes every feature with its state, sorted by name.
+    pub fn describe(&self) -> Value {
+        Value::Array(
+            self.states
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(name, state)| {
+                    json!({
+                        "name": name,
+                        "enabled": state.load(Ordering::Relaxed),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A scope answering 404 while its feature is disabled.
+pub struct DynamicScope {
+    pub enabled: Arc<AtomicBool>,
+    pub inner: Scope,
+}
+
+impl DynamicScope {
+    /// Registers `inner` as feature `name` of `features`.
+    pub fn new(features: &Features, name: &str, inner: Scope) -> Self {
+        DynamicScope {
+            enabled: features.register(name),
+            inner,
+        }
+    }
+}
+
+impl HttpServiceFactory for DynamicScope {
+    fn register(self, config: &mut AppService) {
+        self.inner
+            .wrap(FeatureGate {
+                enabled: self.enabled,
+            })
+            .register(config)
+    }
+}
+
+/// Middleware rejecting requests while a feature is disabled.
+struct FeatureGate {
+    enabled: Arc<AtomicBool>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for FeatureGate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = FeatureGateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FeatureGateMiddleware {
+            service,
+            enabled: self.enabled.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`FeatureGate`].
+struct FeatureGateMiddleware<S> {
+    service: S,
+    enabled: Arc<AtomicBool>,
+}
+
+impl<S, B> Service<ServiceRequest> for FeatureGateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !self.enabled.load(Ordering::Relaxed) {
+            let res = ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "The requested resource was not found",
+            )
+            .error_response();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+/// Registers the feature admin routes on the `/admin` scope.
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    cfg.described_route(
+        Method::GET,
+        "/features",
+        list_features,
+        RouteMetadata::new("Route groups and whether they are enabled"),
+    )
+    .described_route(
+        Method::POST,
+        "/features/{name}/enable",
+        enable_feature,
+        RouteMetadata::new("Enable a route group"),
+    )
+    .described_route(
+        Method::POST,
+        "/features/{name}/disable",
+        disable_feature,
+        RouteMetadata::new("Disable a route group"),
+    );
+}
+
+/// Handler for `GET /admin/features`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with every feature and whether it is enabled.
+pub async fn list_features(features: web::Data<Features>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "features": features.describe() }))
+}
+
+/// Handler for `POST /admin/features/{name}/enable`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the feature's new state, or 404 `unknown_feature`.
+pub async fn enable_feature(
+    features: web::Data<Features>,
+    name: web::Path<String>,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    toggle(&features, &name, &trail, true)
+}
+
+/// Handler for `POST /admin/features/{name}/disable`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the feature's new state, or 404 `unknown_feature`.
+pub async fn disable_feature(
+    features: web::Data<Features>,
+    name: web::Path<String>,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    toggle(&features, &name, &trail, false)
+}
+
+fn toggle(
+    features: &Features,
+    name: &str,
+    trail: &AuditTrail,
+    enabled: bool,
+) -> Result<HttpResponse, ApiError> {
+    let result = features.set(name, enabled).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown_feature",
+            format!("No feature is named '{}'", name),
+        )
+    });
+    trail.record_result(
+        "feature_toggled",
+        json!({ "feature": name, "enabled": enabled }),
+        &result,
+    );
+    if result? != enabled {
+        info!(
+            "Feature '{}' {}",
+            name,
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "name": name,
+        "enabled": enabled,
+    })))
+}
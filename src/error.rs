@@ -0,0 +1,98 @@
+//! Structured JSON error responses.
+//!
+//! Handlers and extractors return [`ApiError`] to produce bodies of the form
+//! `{"error": "<code>", "message": "...", "fields": [...]}`, where `fields`
+//! is only present for field-level validation failures.
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+use crate::util::echo::safe_json;
+
+/// A validation failure tied to a specific input field.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+/// An error rendered as a structured JSON response.
+#[derive(Clone, Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    fields: Vec<FieldError>,
+}
+
+impl ApiError {
+    /// Creates an error with a machine-readable `code` and human-readable `message`.
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Adds a field-level error.
+    pub fn with_field(mut self, field: impl Into<String>, message: impl Into<String>) -> Self {
+        self.fields.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    /// Returns the machine-readable error code.
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    /// Returns the human-readable message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the field-level errors.
+    pub fn fields(&self) -> &[FieldError] {
+        &self.fields
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Messages may quote the offending input
+        safe_json(
+            self.status,
+            &ErrorBody {
+                error: self.code,
+                message: &self.message,
+                fields: self.fields.clone(),
+            },
+        )
+    }
+}
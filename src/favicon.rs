@@ -0,0 +1,174 @@
+//! Favicons served from the binary.
+//!
+//! Browsers request `/favicon.ico` (and iOS `/apple-touch-icon.png`) on
+//! every page load, whether or not the site has one. Both are compiled into
+//! the binary, so they are served without a static directory, and can be
+//! replaced with `FAVICON_FILE` and `APPLE_TOUCH_ICON_FILE`, read once at
+//! startup. With `FAVICON_DISABLED=true`, both answer 204 instead, still
+//! cacheable so browsers stop asking.
+//!
+//! The icons are cached for a week. Their paths are left out of the access
+//! log by default (see `ACCESS_LOG_EXCLUDED_PATHS`).
+
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+
+use actix_web::http::{header, Method};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use log::info;
+
+use crate::route_meta::{DescribedRoute, RouteMetadata};
+use crate::routes::ONE_WEEK;
+
+/// The icon paths, left out of the access log by default.
+pub const FAVICON_PATHS: &[&str] = &["/favicon.ico", "/apple-touch-icon.png"];
+
+/// Cache lifetime of the icons and of the 204 answered when disabled.
+const CACHE_CONTROL: &str = "public, max-age=604800";
+
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
+const DEFAULT_APPLE_TOUCH_ICON: &[u8] = include_bytes!("../assets/apple-touch-icon.png");
+
+/// An icon and its content type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Asset {
+    pub bytes: Bytes,
+    pub content_type: &'static str,
+}
+
+impl Asset {
+    /// Reads `path`, taking the content type from its extension.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the extension is not ico, png or svg, or the
+    /// file cannot be read.
+    pub fn from_file(path: &str) -> Result<Asset, IoError> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let content_type = match extension.as_deref() {
+            Some("ico") => "image/x-icon",
+            Some("png") => "image/png",
+            Some("svg") => "image/svg+xml",
+            _ => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("icon '{}' must be an .ico, .png or .svg file", path),
+                ))
+            }
+        };
+        let bytes = fs::read(path)
+            .map_err(|e| IoError::new(e.kind(), format!("cannot read '{}': {}", path, e)))?;
+        Ok(Asset {
+            bytes: Bytes::from(bytes),
+            content_type,
+        })
+    }
+}
+
+/// The icons served; a missing icon answers 204.
+#[derive(Clone, Debug)]
+pub struct Favicons {
+    pub favicon: Option<Asset>,
+    pub apple_touch_icon: Option<Asset>,
+}
+
+impl Favicons {
+    /// The icons compiled into the binary.
+    pub fn embedded() -> Self {
+        Favicons {
+            favicon: Some(Asset {
+                bytes: Bytes::from_static(DEFAULT_FAVICON),
+                content_type: "image/x-icon",
+            }),
+            apple_touch_icon: Some(Asset {
+                bytes: Bytes::from_static(DEFAULT_APPLE_TOUCH_ICON),
+                content_type: "image/png",
+            }),
+        }
+    }
+
+    /// Answers 204 for both icons.
+    pub fn disabled() -> Self {
+        Favicons {
+            favicon: None,
+            apple_touch_icon: None,
+        }
+    }
+
+    /// Reads `FAVICON_DISABLED`, `FAVICON_FILE` and `APPLE_TOUCH_ICON_FILE`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Favicons, IoError>` - The icons, or an IoError if an override file cannot be used.
+    pub fn from_env() -> Result<Self, IoError> {
+        if env::var("FAVICON_DISABLED").is_ok_and(|v| v == "true") {
+            info!("Favicons disabled: answering 204");
+            return Ok(Favicons::disabled());
+        }
+        let mut favicons = Favicons::embedded();
+        if let Ok(path) = env::var("FAVICON_FILE") {
+            favicons.favicon = Some(Asset::from_file(&path)?);
+            info!("Serving /favicon.ico from {}", path);
+        }
+        if let Ok(path) = env::var("APPLE_TOUCH_ICON_FILE") {
+            favicons.apple_touch_icon = Some(Asset::from_file(&path)?);
+            info!("Serving /apple-touch-icon.png from {}", path);
+        }
+        Ok(favicons)
+    }
+}
+
+fn serve(asset: Option<&Asset>) -> HttpResponse {
+    match asset {
+        Some(asset) => HttpResponse::Ok()
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .content_type(asset.content_type)
+            .body(asset.bytes.clone()),
+        None => HttpResponse::NoContent()
+            .insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+            .finish(),
+    }
+}
+
+/// Handler for `GET /favicon.ico`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the icon, or 204 No Content when favicons are disabled.
+pub async fn favicon(favicons: web::Data<Favicons>) -> HttpResponse {
+    serve(favicons.favicon.as_ref())
+}
+
+/// Handler for `GET /apple-touch-icon.png`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the icon, or 204 No Content when favicons are disabled.
+pub async fn apple_touch_icon(favicons: web::Data<Favicons>) -> HttpResponse {
+    serve(favicons.apple_touch_icon.as_ref())
+}
+
+/// Registers the icon routes.
+pub fn configure(favicons: web::Data<Favicons>) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(favicons)
+            .described_route(
+                Method::GET,
+                "/favicon.ico",
+                favicon,
+                RouteMetadata::new("Site icon").cache_ttl(ONE_WEEK),
+            )
+            .described_route(
+                Method::GET,
+                "/apple-touch-icon.png",
+                apple_touch_icon,
+                RouteMetadata::new("Home screen icon for iOS").cache_ttl(ONE_WEEK),
+            );
+    }
+}
@@ -0,0 +1,97 @@
+//! FIPS mode for the server's TLS configuration.
+//!
+//! With `TLS_FIPS_MODE=true`, the server only negotiates FIPS-approved
+//! algorithms: TLS 1.3 and 1.2 with AES-GCM cipher suites (ChaCha20-Poly1305
+//! is excluded) and ECDHE over the NIST curves P-256 and P-384 (X25519 is
+//! excluded). The mode is only available in builds with the `fips` feature;
+//! requesting it otherwise fails at startup with
+//! [`TlsConfigError::FipsNotSupported`] rather than silently serving the
+//! default algorithms.
+//!
+//! The algorithms are still implemented by rustls' own cryptography; a
+//! deployment that needs a FIPS 140-2 validated module must also provide
+//! one, which the rustls version in use cannot plug in.
+
+use std::env;
+
+use log::info;
+use rustls::cipher_suite::{
+    TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+};
+use rustls::kx_group::{SECP256R1, SECP384R1};
+use rustls::version::{TLS12, TLS13};
+use rustls::{ConfigBuilder, ServerConfig, SupportedCipherSuite, SupportedKxGroup, WantsVerifier};
+
+use crate::tls_error::TlsConfigError;
+
+/// The cipher suites negotiated in FIPS mode, most preferred first.
+pub fn cipher_suites() -> [SupportedCipherSuite; 6] {
+    [
+        TLS13_AES_256_GCM_SHA384,
+        TLS13_AES_128_GCM_SHA256,
+        TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ]
+}
+
+/// The key exchange groups offered in FIPS mode.
+pub fn kx_groups() -> [&'static SupportedKxGroup; 2] {
+    [&SECP256R1, &SECP384R1]
+}
+
+/// Whether the TLS configuration is restricted to FIPS-approved algorithms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RustlsFipsMode {
+    pub enabled: bool,
+}
+
+impl RustlsFipsMode {
+    /// Reads `TLS_FIPS_MODE`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TlsConfigError::FipsNotSupported`] if FIPS mode is
+    /// requested in a build without the `fips` feature.
+    pub fn from_env() -> Result<Self, TlsConfigError> {
+        let enabled = env::var("TLS_FIPS_MODE").is_ok_and(|v| v == "true");
+        if enabled && !cfg!(feature = "fips") {
+            return Err(TlsConfigError::FipsNotSupported);
+        }
+        Ok(RustlsFipsMode { enabled })
+    }
+
+    /// Starts a server configuration with the algorithms of this mode: the
+    /// rustls defaults, or the FIPS-approved subset.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TlsConfigError::BuildFailed`] if rustls rejects the
+    /// selection.
+    pub fn server_builder(
+        &self,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, TlsConfigError> {
+        if !self.enabled {
+            return Ok(ServerConfig::builder().with_safe_defaults());
+        }
+        let builder = ServerConfig::builder()
+            .with_cipher_suites(&cipher_suites())
+            .with_kx_groups(&kx_groups())
+            .with_protocol_versions(&[&TLS13, &TLS12])
+            .map_err(|e| TlsConfigError::BuildFailed {
+                reason: e.to_string(),
+            })?;
+        info!(
+            "TLS FIPS mode active: cipher suites {}; key exchange over P-256 and P-384",
+            cipher_suites()
+                .iter()
+                .map(|s| format!("{:?}", s.suite()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(builder)
+    }
+}
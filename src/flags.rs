@@ -0,0 +1,575 @@
+//! Feature flags with runtime overrides.
+//!
+//! Flags are declared in `FEATURE_FLAGS_FILE`, a JSON array of
+//! `{"name", "description", "default", "rollout_percent"}` objects, so
+//! dormant features can ship and be switched on per environment. A flag
+//! with a `rollout_percent` is on for that share of callers: each caller
+//! (the authenticated principal, or else the client IP) falls in a stable
+//! bucket derived from a hash of the flag name and the caller, so the same
+//! caller always gets the same answer.
+//!
+//! `FEATURE_FLAGS` declares flags from the environment as comma-separated
+//! `name=on` or `name=off` entries, which also force flags of the file on or
+//! off for every caller. Both are read again when the configuration is
+//! applied through `POST /admin/config/apply` (see [`crate::config_reload`]).
+//!
+//! Handlers read flags through the [`Flags`] handle in app data, and
+//! [`gated`] registers a route that answers 404 while its flag is off, so
+//! routes can ship dark. Admins can force a flag on or off with
+//! `PUT /admin/flags/{name}`; overrides always expire, at most
+//! [`MAX_OVERRIDE_TTL`] later, so a forgotten override cannot outlive an
+//! incident. Every change is recorded in the audit trail.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{
+    forward_ready, HttpServiceFactory, Service, ServiceRequest, ServiceResponse, Transform,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, Resource, ResponseError};
+use chrono::{DateTime, Utc};
+use futures_util::future::LocalBoxFuture;
+use log::info;
+use ring::digest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::audit::AuditTrail;
+use crate::auth::Principal;
+use crate::error::ApiError;
+use crate::route_meta::{self, DescribedRoute, RouteMetadata};
+use crate::util::real_ip::real_ip;
+
+/// Longest accepted override.
+pub const MAX_OVERRIDE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A flag as declared in `FEATURE_FLAGS_FILE`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct FlagDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Value for every caller when there is no rollout, and for callers
+    /// that cannot be identified.
+    #[serde(default)]
+    pub default: bool,
+    /// Share of callers, 0 to 100, the flag is on for.
+    #[serde(default)]
+    pub rollout_percent: Option<u8>,
+}
+
+/// A value forced by an admin until `expires_at`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlagOverride {
+    pub enabled: bool,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Why a flag operation failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FlagError {
+    /// No flag has this name.
+    Unknown(String),
+    /// The override TTL is zero or longer than [`MAX_OVERRIDE_TTL`].
+    InvalidTtl,
+}
+
+impl From<FlagError> for ApiError {
+    fn from(e: FlagError) -> Self {
+        match e {
+            FlagError::Unknown(name) => ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_flag",
+                format!("No feature flag is named '{}'", name),
+            ),
+            FlagError::InvalidTtl => ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_ttl",
+                format!(
+                    "ttl_secs must be between 1 and {}",
+                    MAX_OVERRIDE_TTL.as_secs()
+                ),
+            )
+            .with_field("ttl_secs", "out of range"),
+        }
+    }
+}
+
+/// Returns the rollout bucket, 0 to 99, of `subject` for flag `name`.
+pub fn bucket(name: &str, subject: &str) -> u8 {
+    let hash = digest::digest(&digest::SHA256, format!("{}:{}", name, subject).as_bytes());
+    let bytes = hash.as_ref();
+    (u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % 100) as u8
+}
+
+/// Returns who a request is evaluated for: the authenticated principal, or
+/// else the client IP.
+pub fn subject(req: &HttpRequest) -> Option<String> {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return Some(format!("user:{}", principal.username));
+    }
+    real_ip(req).ip().map(|ip| format!("ip:{}", ip))
+}
+
+/// Checks definitions and indexes them by name.
+fn validate(definitions: Vec<FlagDefinition>) -> Result<BTreeMap<String, FlagDefinition>, IoError> {
+    let invalid = |message: String| IoError::new(ErrorKind::InvalidData, message);
+    let mut map = BTreeMap::new();
+    for definition in definitions {
+        if definition.name.trim().is_empty() {
+            return Err(invalid("A feature flag has an empty name".to_string()));
+        }
+        if definition.rollout_percent.is_some_and(|p| p > 100) {
+            return Err(invalid(format!(
+                "Feature flag '{}' has a rollout above 100 percent",
+                definition.name
+            )));
+        }
+        if map.contains_key(&definition.name) {
+            return Err(invalid(format!(
+                "Feature flag '{}' is declared twice",
+                definition.name
+            )));
+        }
+        map.insert(definition.name.clone(), definition);
+    }
+    Ok(map)
+}
+
+/// Reads the flags declared in the JSON file at `path`.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or parsed, or declares
+/// invalid flags.
+pub fn read_file(path: &str) -> Result<Vec<FlagDefinition>, IoError> {
+    let definitions: Vec<FlagDefinition> =
+        serde_json::from_slice(&fs::read(path)?).map_err(|e| {
+            IoError::new(
+                ErrorKind::InvalidData,
+                format!("Invalid FEATURE_FLAGS_FILE '{}': {}", path, e),
+            )
+        })?;
+    validate(definitions.clone())?;
+    Ok(definitions)
+}
+
+/// Parses `FEATURE_FLAGS`, comma-separated `name=on` or `name=off` entries;
+/// a bare name is on.
+///
+/// # Errors
+///
+/// Returns a description of the first malformed entry.
+pub fn parse_env_flags(value: &str) -> Result<Vec<(String, bool)>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, state) = entry.split_once('=').unwrap_or((entry, "on"));
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(format!("'{}' has no flag name", entry));
+            }
+            let enabled = match state.trim().to_ascii_lowercase().as_str() {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err(format!("'{}' is neither on nor off", entry)),
+            };
+            Ok((name.to_string(), enabled))
+        })
+        .collect()
+}
+
+/// Reads the flags of `FEATURE_FLAGS_FILE`, then applies `FEATURE_FLAGS`:
+/// a listed flag is declared if needed, and on or off for every caller.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or parsed, or either
+/// declares invalid flags.
+pub fn load_definitions() -> Result<Vec<FlagDefinition>, IoError> {
+    let mut definitions = match env::var("FEATURE_FLAGS_FILE") {
+        Ok(path) if !path.is_empty() => read_file(&path)?,
+        _ => Vec::new(),
+    };
+    let listed = env::var("FEATURE_FLAGS").unwrap_or_default();
+    let listed = parse_env_flags(&listed).map_err(|e| {
+        IoError::new(
+            ErrorKind::InvalidData,
+            format!("Invalid FEATURE_FLAGS: {}", e),
+        )
+    })?;
+    for (name, enabled) in listed {
+        match definitions.iter_mut().find(|d| d.name == name) {
+            Some(definition) => {
+                definition.default = enabled;
+                definition.rollout_percent = None;
+            }
+            None => definitions.push(FlagDefinition {
+                name,
+                description: String::new(),
+                default: enabled,
+                rollout_percent: None,
+            }),
+        }
+    }
+    Ok(definitions)
+}
+
+/// Cheap, cloneable handle to the declared flags and their overrides.
+#[derive(Clone, Default)]
+pub struct Flags {
+    definitions: Arc<RwLock<Arc<BTreeMap<String, FlagDefinition>>>>,
+    overrides: Arc<RwLock<HashMap<String, FlagOverride>>>,
+}
+
+impl Flags {
+    /// Creates flags from their definitions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a name is empty or declared twice, or a rollout
+    /// exceeds 100 percent.
+    pub fn new(definitions: Vec<FlagDefinition>) -> Result<Self, IoError> {
+        Ok(Flags {
+            definitions: Arc::new(RwLock::new(Arc::new(validate(definitions)?))),
+            overrides: Arc::default(),
+        })
+    }
+
+    /// Loads the flags declared in `FEATURE_FLAGS_FILE` and
+    /// `FEATURE_FLAGS`; no flags are declared when both are unset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed, or the flags
+    /// are invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        let flags = Flags::new(load_definitions()?)?;
+        let count = flags.definitions().len();
+        if count > 0 {
+            info!("Loaded {} feature flags", count);
+        }
+        Ok(flags)
+    }
+
+    fn definitions(&self) -> Arc<BTreeMap<String, FlagDefinition>> {
+        self.definitions.read().unwrap().clone()
+    }
+
+    /// Returns the declared flag names, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.definitions().keys().cloned().collect()
+    }
+
+    /// Returns whether exactly `definitions` are declared.
+    pub fn declares(&self, definitions: &[FlagDefinition]) -> bool {
+        let declared = self.definitions();
+        declared.len() == definitions.len()
+            && definitions.iter().all(|d| declared.get(&d.name) == Some(d))
+    }
+
+    /// Replaces the declared flags. Overrides of flags still declared are
+    /// kept; those of removed flags are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and changes nothing, if the flags are invalid.
+    pub fn reload(&self, definitions: Vec<FlagDefinition>) -> Result<(), IoError> {
+        let definitions = validate(definitions)?;
+        let mut declared = self.definitions.write().unwrap();
+        self.overrides
+            .write()
+            .unwrap()
+            .retain(|name, _| definitions.contains_key(name));
+        *declared = Arc::new(definitions);
+        Ok(())
+    }
+
+    /// Returns the override of `name` in effect at `now`, if any.
+    pub fn override_at(&self, name: &str, now: DateTime<Utc>) -> Option<FlagOverride> {
+        self.overrides
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .filter(|o| o.expires_at > now)
+    }
+
+    /// Evaluates `name` for `subject` at `now`: an unexpired override wins,
+    /// then the rollout for an identified subject, then the default.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FlagError::Unknown`] if no flag is named `name`.
+    pub fn evaluate_at(
+        &self,
+        name: &str,
+        subject: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, FlagError> {
+        let definitions = self.definitions();
+        let definition = definitions
+            .get(name)
+            .ok_or_else(|| FlagError::Unknown(name.to_string()))?;
+        if let Some(o) = self.override_at(name, now) {
+            return Ok(o.enabled);
+        }
+        Ok(match (definition.rollout_percent, subject) {
+            (Some(percent), Some(subject)) => bucket(name, subject) < percent,
+            _ => definition.default,
+        })
+    }
+
+    /// Returns whether `name` is on for `req`. Unknown flags are off.
+    pub fn is_enabled(&self, name: &str, req: &HttpRequest) -> bool {
+        self.evaluate_at(name, subject(req).as_deref(), Utc::now())
+            .unwrap_or(false)
+    }
+
+    /// Forces `name` to `enabled` for `ttl`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FlagOverride, FlagError>` - The override, or an error for an unknown flag or a TTL out of range.
+    pub fn set_override(
+        &self,
+        name: &str,
+        enabled: bool,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<FlagOverride, FlagError> {
+        if !self.definitions().contains_key(name) {
+            return Err(FlagError::Unknown(name.to_string()));
+        }
+        if ttl.is_zero() || ttl > MAX_OVERRIDE_TTL {
+            return Err(FlagError::InvalidTtl);
+        }
+        let ttl = chrono::Duration::from_std(ttl).map_err(|_| FlagError::InvalidTtl)?;
+        let o = FlagOverride {
+            enabled,
+            expires_at: now + ttl,
+        };
+        let mut overrides = self.overrides.write().unwrap();
+        overrides.retain(|_, o| o.expires_at > now);
+        overrides.insert(name.to_string(), o);
+        Ok(o)
+    }
+
+    /// Removes the override of `name`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool, FlagError>` - Whether an override was removed, or an error for an unknown flag.
+    pub fn clear_override(&self, name: &str) -> Result<bool, FlagError> {
+        if !self.definitions().contains_key(name) {
+            return Err(FlagError::Unknown(name.to_string()));
+        }
+        Ok(self.overrides.write().unwrap().remove(name).is_some())
+    }
+
+    /// Describes every flag with its override in effect at `now`.
+    pub fn describe(&self, now: DateTime<Utc>) -> Value {
+        Value::Array(
+            self.definitions()
+                .values()
+                .map(|d| {
+                    json!({
+                        "name": d.name,
+                        "description": d.description,
+                        "default": d.default,
+                        "rollout_percent": d.rollout_percent,
+                        "override": self.override_at(&d.name, now).map(|o| json!({
+                            "enabled": o.enabled,
+                            "expires_at": o.expires_at.to_rfc3339(),
+                        })),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Serves `resource` only while `flag` is on for the caller, evaluated
+/// with the [`Flags`] in app data; otherwise it answers 404 `not_found`, as
+/// if the route did not exist. Unknown flags are off, so a route can ship
+/// before its flag is declared.
+pub fn gated(flag: &str, resource: Resource) -> impl HttpServiceFactory {
+    resource.wrap(FlagGate {
+        flag: flag.to_string(),
+    })
+}
+
+/// Middleware hiding a route while its flag is off.
+struct FlagGate {
+    flag: String,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for FlagGate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = FlagGateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FlagGateMiddleware {
+            service,
+            flag: self.flag.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`FlagGate`].
+struct FlagGateMiddleware<S> {
+    service: S,
+    flag: String,
+}
+
+impl<S, B> Service<ServiceRequest> for FlagGateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let enabled = req
+            .app_data::<web::Data<Flags>>()
+            .is_some_and(|flags| flags.is_enabled(&self.flag, req.request()));
+        if !enabled {
+            let res = ApiError::new(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "The requested resource was not found",
+            )
+            .error_response();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { Ok(fut.await?.map_into_left_body()) })
+    }
+}
+
+/// Body of `PUT /admin/flags/{name}`.
+#[derive(Deserialize)]
+pub struct OverrideRequest {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+}
+
+/// Registers the flag admin routes on the `/admin` scope.
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    cfg.described_route(
+        Method::GET,
+        "/flags",
+        list_flags,
+        RouteMetadata::new("Feature flags and their overrides"),
+    );
+    route_meta::record(
+        "PUT",
+        "/flags/{name}",
+        RouteMetadata::new("Override a feature flag"),
+    );
+    route_meta::record(
+        "DELETE",
+        "/flags/{name}",
+        RouteMetadata::new("Remove a feature flag override"),
+    );
+    cfg.service(
+        web::resource("/flags/{name}")
+            .route(web::put().to(put_override))
+            .route(web::delete().to(delete_override)),
+    );
+}
+
+/// Handler for `GET /admin/flags`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with every flag and its override.
+pub async fn list_flags(flags: web::Data<Flags>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "flags": flags.describe(Utc::now()) }))
+}
+
+/// Handler for `PUT /admin/flags/{name}`, overriding a flag for a while.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the override, 404 for an unknown flag, or 400 for a TTL out of range.
+pub async fn put_override(
+    flags: web::Data<Flags>,
+    name: web::Path<String>,
+    trail: AuditTrail,
+    body: web::Json<OverrideRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let result = flags
+        .set_override(
+            &name,
+            body.enabled,
+            Duration::from_secs(body.ttl_secs),
+            Utc::now(),
+        )
+        .map_err(ApiError::from);
+    let detail = match &result {
+        Ok(o) => json!({
+            "flag": name.as_str(),
+            "enabled": o.enabled,
+            "expires_at": o.expires_at.to_rfc3339(),
+        }),
+        Err(_) => json!({ "flag": name.as_str() }),
+    };
+    trail.record_result("feature_flag_overridden", detail, &result);
+    let o = result?;
+    info!(
+        "Feature flag '{}' overridden to {} until {}",
+        name,
+        o.enabled,
+        o.expires_at.to_rfc3339()
+    );
+    Ok(HttpResponse::Ok().json(json!({
+        "name": name.as_str(),
+        "enabled": o.enabled,
+        "expires_at": o.expires_at.to_rfc3339(),
+    })))
+}
+
+/// Handler for `DELETE /admin/flags/{name}`, removing an override.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 204 No Content, or 404 for an unknown flag.
+pub async fn delete_override(
+    flags: web::Data<Flags>,
+    name: web::Path<String>,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    let result = flags.clear_override(&name).map_err(ApiError::from);
+    let detail = match &result {
+        Ok(cleared) => json!({ "flag": name.as_str(), "cleared": cleared }),
+        Err(_) => json!({ "flag": name.as_str() }),
+    };
+    trail.record_result("feature_flag_override_cleared", detail, &result);
+    if result? {
+        info!("Feature flag '{}' override removed", name);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,240 @@
+//! Built-in dependency checks.
+//!
+//! The database checks speak just enough of each wire protocol to prove the
+//! server is up and answering, without credentials or client libraries.
+//! [`TcpDependency`] only proves the endpoint accepts connections, and
+//! optionally completes a TLS handshake.
+
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rustls_client::{
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::dependency::{DependencyHealthCheck, HealthStatus};
+use crate::outbound::happy_eyeballs::{self, DEFAULT_ATTEMPT_DELAY};
+use crate::outbound::HttpClient;
+
+/// Checks a PostgreSQL server by sending an `SSLRequest`, which every server
+/// answers with a single `S` or `N` byte before authentication.
+pub struct PostgresDependency {
+    addr: String,
+}
+
+impl PostgresDependency {
+    /// `addr` is the server's `host:port`.
+    pub fn new(addr: &str) -> Self {
+        PostgresDependency {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl DependencyHealthCheck for PostgresDependency {
+    async fn check(&self) -> HealthStatus {
+        // Length 8, then the SSLRequest code 80877103
+        const SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+
+        let result = async {
+            let mut stream =
+                happy_eyeballs::connect_host(&self.addr, DEFAULT_ATTEMPT_DELAY).await?;
+            stream.write_all(&SSL_REQUEST).await?;
+            let mut reply = [0u8; 1];
+            stream.read_exact(&mut reply).await?;
+            Ok::<u8, std::io::Error>(reply[0])
+        }
+        .await;
+
+        match result {
+            Ok(b'S') | Ok(b'N') => HealthStatus::Healthy,
+            Ok(other) => {
+                HealthStatus::Unhealthy(format!("unexpected reply {:#04x} to SSLRequest", other))
+            }
+            Err(e) => HealthStatus::Unhealthy(e.to_string()),
+        }
+    }
+}
+
+/// Checks a Redis server with `PING`. A server requiring authentication
+/// answers `-NOAUTH`, which still proves it is up.
+pub struct RedisDependency {
+    addr: String,
+}
+
+impl RedisDependency {
+    /// `addr` is the server's `host:port`.
+    pub fn new(addr: &str) -> Self {
+        RedisDependency {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl DependencyHealthCheck for RedisDependency {
+    async fn check(&self) -> HealthStatus {
+        let result = async {
+            let mut stream =
+                happy_eyeballs::connect_host(&self.addr, DEFAULT_ATTEMPT_DELAY).await?;
+            stream.write_all(b"PING\r\n").await?;
+            let mut reply = [0u8; 64];
+            let n = stream.read(&mut reply).await?;
+            Ok::<Vec<u8>, std::io::Error>(reply[..n].to_vec())
+        }
+        .await;
+
+        match result {
+            Ok(reply) if reply.starts_with(b"+PONG") || reply.starts_with(b"-NOAUTH") => {
+                HealthStatus::Healthy
+            }
+            Ok(reply) => HealthStatus::Unhealthy(format!(
+                "unexpected reply to PING: {}",
+                String::from_utf8_lossy(&reply).trim_end()
+            )),
+            Err(e) => HealthStatus::Unhealthy(e.to_string()),
+        }
+    }
+}
+
+/// Checks an HTTP(S) endpoint, healthy when it answers with a 2xx status.
+pub struct ExternalHttpDependency {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl ExternalHttpDependency {
+    /// Checks `url` with the shared `client`, reusing its pooled
+    /// connections between checks.
+    pub fn new(url: &str, client: &HttpClient) -> Self {
+        ExternalHttpDependency {
+            url: url.to_string(),
+            client: client.client().clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl DependencyHealthCheck for ExternalHttpDependency {
+    async fn check(&self) -> HealthStatus {
+        match self.client.get(&self.url).send().await {
+            Ok(resp) if resp.status().is_success() => HealthStatus::Healthy,
+            Ok(resp) => HealthStatus::Unhealthy(format!("returned {}", resp.status())),
+            Err(e) => HealthStatus::Unhealthy(e.to_string()),
+        }
+    }
+}
+
+/// Checks that an endpoint accepts TCP connections and, with
+/// [`with_tls`](Self::with_tls), completes a TLS handshake whose certificate
+/// is valid for the expected host name.
+pub struct TcpDependency {
+    addr: String,
+    tls: Option<(ServerName, Arc<ClientConfig>)>,
+}
+
+impl TcpDependency {
+    /// `addr` is the endpoint's `host:port`.
+    pub fn new(addr: &str) -> Self {
+        TcpDependency {
+            addr: addr.to_string(),
+            tls: None,
+        }
+    }
+
+    /// Also performs a TLS handshake, verifying the certificate for
+    /// `server_name` against the web PKI roots and `extra_roots` (DER).
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if `server_name` is not a valid DNS name or IP
+    /// address, or a root certificate is invalid.
+    pub fn with_tls(mut self, server_name: &str, extra_roots: &[Vec<u8>]) -> Result<Self, IoError> {
+        let name = ServerName::try_from(server_name).map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("invalid TLS server name '{}'", server_name),
+            )
+        })?;
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        for der in extra_roots {
+            roots
+                .add(&Certificate(der.clone()))
+                .map_err(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))?;
+        }
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        self.tls = Some((name, Arc::new(config)));
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl DependencyHealthCheck for TcpDependency {
+    async fn check(&self) -> HealthStatus {
+        let result = async {
+            let mut stream =
+                happy_eyeballs::connect_host(&self.addr, DEFAULT_ATTEMPT_DELAY).await?;
+            if let Some((name, config)) = &self.tls {
+                handshake(&mut stream, name.clone(), config.clone()).await?;
+            }
+            Ok::<(), IoError>(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => HealthStatus::Healthy,
+            Err(e) => HealthStatus::Unhealthy(e.to_string()),
+        }
+    }
+}
+
+/// Drives a TLS client handshake over `stream` until it completes.
+async fn handshake(
+    stream: &mut TcpStream,
+    name: ServerName,
+    config: Arc<ClientConfig>,
+) -> Result<(), IoError> {
+    let tls_error = |e: rustls_client::Error| {
+        IoError::new(
+            ErrorKind::InvalidData,
+            format!("TLS handshake failed: {}", e),
+        )
+    };
+    let mut conn = ClientConnection::new(config, name).map_err(tls_error)?;
+    let mut buf = [0u8; 16 * 1024];
+    while conn.is_handshaking() {
+        if conn.wants_write() {
+            let mut out = Vec::new();
+            conn.write_tls(&mut out)?;
+            stream.write_all(&out).await?;
+            continue;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed during the TLS handshake",
+            ));
+        }
+        let mut received = &buf[..n];
+        while !received.is_empty() {
+            conn.read_tls(&mut received)?;
+            conn.process_new_packets().map_err(tls_error)?;
+        }
+    }
+    Ok(())
+}
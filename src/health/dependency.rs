@@ -0,0 +1,230 @@
+//! Health checks for downstream dependencies.
+//!
+//! Each dependency implements [`DependencyHealthCheck`] and is registered in
+//! the [`HealthRegistry`] as critical or non-critical. Checks run
+//! concurrently and each is bounded by the registry's timeout
+//! (`HEALTH_CHECK_TIMEOUT_MS`).
+//!
+//! [`HealthRegistry::check_all`] is single-flight: probes arriving while a
+//! round of checks is running wait for it and share its reports instead of
+//! starting their own, so frequent probes cannot pile up on a slow
+//! dependency. The reports can also be reused for a short time
+//! (`HEALTH_CACHE_MS`). Dependencies registered with an interval are dialed
+//! at most once per interval; in between, their last report is reused.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::timeout;
+use async_trait::async_trait;
+use futures_util::future::{join_all, BoxFuture, FutureExt, Shared};
+use serde::Serialize;
+
+/// Default for `HEALTH_CHECK_TIMEOUT_MS`.
+pub const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// Result of checking one dependency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy(String),
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, HealthStatus::Healthy)
+    }
+}
+
+/// A downstream dependency whose health can be checked.
+#[async_trait]
+pub trait DependencyHealthCheck: Send + Sync {
+    async fn check(&self) -> HealthStatus;
+}
+
+/// Outcome of one dependency check, as reported by the health endpoints.
+#[derive(Clone, Debug, Serialize)]
+pub struct DependencyReport {
+    pub name: String,
+    /// `up` or `down`.
+    pub status: &'static str,
+    pub critical: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DependencyReport {
+    pub fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+struct Registration {
+    name: String,
+    checker: Arc<dyn DependencyHealthCheck>,
+    critical: bool,
+    interval: Duration,
+    last: Mutex<Option<(Instant, DependencyReport)>>,
+}
+
+impl Registration {
+    /// Checks the dependency, or returns its last report if it is younger
+    /// than the interval.
+    async fn check(&self, limit: Duration) -> DependencyReport {
+        let cached = self
+            .last
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(at, _)| at.elapsed() < self.interval)
+            .map(|(_, report)| report.clone());
+        if let Some(report) = cached {
+            return report;
+        }
+        let report = run(&self.name, self.checker.as_ref(), self.critical, limit).await;
+        if !self.interval.is_zero() {
+            *self.last.lock().unwrap() = Some((Instant::now(), report.clone()));
+        }
+        report
+    }
+}
+
+type Round = Shared<BoxFuture<'static, Arc<Vec<DependencyReport>>>>;
+
+/// The set of dependencies checked by `/ready`.
+pub struct HealthRegistry {
+    checks: Vec<Arc<Registration>>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    in_flight: Mutex<Option<Round>>,
+    cached: Mutex<Option<(Instant, Arc<Vec<DependencyReport>>)>>,
+}
+
+impl Default for HealthRegistry {
+    fn default() -> Self {
+        HealthRegistry::new(DEFAULT_CHECK_TIMEOUT)
+    }
+}
+
+impl HealthRegistry {
+    /// Creates an empty registry timing out each check after `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        HealthRegistry {
+            checks: Vec::new(),
+            timeout,
+            cache_ttl: Duration::ZERO,
+            in_flight: Mutex::new(None),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Reuses the reports of [`check_all`](Self::check_all) for `ttl` after
+    /// they were produced.
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Registers a dependency. Failures of critical dependencies make the
+    /// instance unready; others only mark it degraded.
+    pub fn register(
+        &mut self,
+        name: &str,
+        checker: Arc<dyn DependencyHealthCheck>,
+        is_critical: bool,
+    ) -> &mut Self {
+        self.register_with_interval(name, checker, is_critical, Duration::ZERO)
+    }
+
+    /// Registers a dependency that is checked at most once per `interval`,
+    /// e.g. one that is expensive to dial.
+    pub fn register_with_interval(
+        &mut self,
+        name: &str,
+        checker: Arc<dyn DependencyHealthCheck>,
+        is_critical: bool,
+        interval: Duration,
+    ) -> &mut Self {
+        self.checks.push(Arc::new(Registration {
+            name: name.to_string(),
+            checker,
+            critical: is_critical,
+            interval,
+            last: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Returns whether any dependency is registered.
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    /// Checks every dependency concurrently. Callers arriving while a
+    /// round is running share its reports.
+    pub async fn check_all(&self) -> Vec<DependencyReport> {
+        if let Some((at, reports)) = &*self.cached.lock().unwrap() {
+            if at.elapsed() < self.cache_ttl {
+                return reports.to_vec();
+            }
+        }
+
+        let round = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match &*in_flight {
+                Some(round) => round.clone(),
+                None => {
+                    let checks = self.checks.clone();
+                    let limit = self.timeout;
+                    let round = async move {
+                        Arc::new(join_all(checks.iter().map(|r| r.check(limit))).await)
+                    }
+                    .boxed()
+                    .shared();
+                    *in_flight = Some(round.clone());
+                    round
+                }
+            }
+        };
+        let reports = round.await;
+
+        // The first caller to see the finished round retires it
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.as_ref().is_some_and(|r| r.peek().is_some()) {
+            *in_flight = None;
+            *self.cached.lock().unwrap() = Some((Instant::now(), reports.clone()));
+        }
+        reports.to_vec()
+    }
+
+    /// Checks the dependency registered as `name`, if any.
+    pub async fn check_one(&self, name: &str) -> Option<DependencyReport> {
+        let r = self.checks.iter().find(|r| r.name == name)?;
+        Some(r.check(self.timeout).await)
+    }
+}
+
+async fn run(
+    name: &str,
+    checker: &dyn DependencyHealthCheck,
+    critical: bool,
+    limit: Duration,
+) -> DependencyReport {
+    let started = Instant::now();
+    let status = match timeout(limit, checker.check()).await {
+        Ok(status) => status,
+        Err(_) => HealthStatus::Unhealthy(format!("timed out after {}ms", limit.as_millis())),
+    };
+    let (status, error) = match status {
+        HealthStatus::Healthy => ("up", None),
+        HealthStatus::Unhealthy(reason) => ("down", Some(reason)),
+    };
+    DependencyReport {
+        name: name.to_string(),
+        status,
+        critical,
+        latency_ms: started.elapsed().as_millis() as u64,
+        error,
+    }
+}
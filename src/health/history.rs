@@ -0,0 +1,187 @@
+//! Recent readiness results, for spotting flapping dependencies.
+//!
+//! Every `/ready` evaluation is recorded in a [`HealthHistory`] ring buffer
+//! of the last `HEALTH_HISTORY_SIZE` results. `GET /health/history` lists
+//! them, oldest first, and `GET /health/trend` summarizes them with
+//! [`HealthTrend::compute`]. A result counts as a failure when `/ready`
+//! answered 503, i.e. it was `unavailable` or `shutting_down`; `degraded`
+//! still served traffic and counts as healthy.
+
+use std::collections::VecDeque;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Default for `HEALTH_HISTORY_SIZE`.
+pub const DEFAULT_HISTORY_SIZE: usize = 60;
+
+/// Window of [`Trend::error_rate_last_5min`].
+const ERROR_RATE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// One `/ready` result.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthSample {
+    pub timestamp: DateTime<Utc>,
+    /// `ready`, `degraded`, `unavailable` or `shutting_down`.
+    pub status: &'static str,
+    pub latency_ms: u64,
+}
+
+impl HealthSample {
+    /// Returns whether `/ready` answered 200 for this result.
+    pub fn is_healthy(&self) -> bool {
+        matches!(self.status, "ready" | "degraded")
+    }
+
+    fn describe(&self) -> Value {
+        json!({
+            "timestamp": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "status": self.status,
+            "latency_ms": self.latency_ms,
+        })
+    }
+}
+
+/// The last readiness results, oldest first.
+#[derive(Debug)]
+pub struct HealthHistory {
+    capacity: usize,
+    samples: Mutex<VecDeque<HealthSample>>,
+}
+
+impl HealthHistory {
+    /// Keeps the last `capacity` results; at least one is kept.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        HealthHistory {
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Reads `HEALTH_HISTORY_SIZE`, using the default for unset or invalid
+    /// values.
+    pub fn from_env() -> Self {
+        let capacity = env::var("HEALTH_HISTORY_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(DEFAULT_HISTORY_SIZE);
+        HealthHistory::new(capacity)
+    }
+
+    /// Records a result taken now.
+    pub fn record(&self, status: &'static str, latency: Duration) {
+        self.push(HealthSample {
+            timestamp: Utc::now(),
+            status,
+            latency_ms: latency.as_millis() as u64,
+        });
+    }
+
+    /// Appends `sample`, dropping the oldest result when full.
+    pub fn push(&self, sample: HealthSample) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Returns the recorded results, oldest first.
+    pub fn samples(&self) -> Vec<HealthSample> {
+        self.samples.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl Default for HealthHistory {
+    fn default() -> Self {
+        HealthHistory::new(DEFAULT_HISTORY_SIZE)
+    }
+}
+
+/// Summary of a [`HealthHistory`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Trend {
+    /// Fraction, 0 to 1, of the results of the last five minutes that
+    /// failed; 0 without results.
+    pub error_rate_last_5min: f64,
+    /// Mean check latency over the whole history; 0 without results.
+    pub avg_latency_ms: f64,
+    /// Changes between healthy and failing over the whole history.
+    pub flap_count: u32,
+}
+
+/// Computes [`Trend`]s.
+pub struct HealthTrend;
+
+impl HealthTrend {
+    /// Summarizes `history` as of now.
+    pub fn compute(history: &HealthHistory) -> Trend {
+        HealthTrend::compute_at(history, Utc::now())
+    }
+
+    /// Summarizes `history` as of `now`.
+    pub fn compute_at(history: &HealthHistory, now: DateTime<Utc>) -> Trend {
+        let samples = history.samples();
+        let window_start =
+            now - chrono::Duration::from_std(ERROR_RATE_WINDOW).unwrap_or(chrono::Duration::zero());
+        let recent: Vec<&HealthSample> = samples
+            .iter()
+            .filter(|s| s.timestamp >= window_start)
+            .collect();
+        let error_rate_last_5min = if recent.is_empty() {
+            0.0
+        } else {
+            recent.iter().filter(|s| !s.is_healthy()).count() as f64 / recent.len() as f64
+        };
+        let avg_latency_ms = if samples.is_empty() {
+            0.0
+        } else {
+            samples.iter().map(|s| s.latency_ms as f64).sum::<f64>() / samples.len() as f64
+        };
+        let flap_count = samples
+            .windows(2)
+            .filter(|pair| pair[0].is_healthy() != pair[1].is_healthy())
+            .count() as u32;
+        Trend {
+            error_rate_last_5min,
+            avg_latency_ms,
+            flap_count,
+        }
+    }
+}
+
+/// Handler for `GET /health/history`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the recorded results, oldest first, or an empty array when no history is kept.
+pub async fn history(history: Option<web::Data<HealthHistory>>) -> HttpResponse {
+    let samples = history.map(|h| h.samples()).unwrap_or_default();
+    HttpResponse::Ok().json(Value::Array(
+        samples.iter().map(HealthSample::describe).collect(),
+    ))
+}
+
+/// Handler for `GET /health/trend`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the [`Trend`] of the recorded results.
+pub async fn trend(history: Option<web::Data<HealthHistory>>) -> HttpResponse {
+    let trend = match history {
+        Some(history) => HealthTrend::compute(&history),
+        None => HealthTrend::compute(&HealthHistory::new(1)),
+    };
+    HttpResponse::Ok().json(trend)
+}
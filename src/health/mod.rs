@@ -0,0 +1,257 @@
+//! Liveness and readiness endpoints.
+//!
+//! * `GET /health` reports that the process is alive and serving requests.
+//! * `GET /ready` reports whether the instance should receive new traffic.
+//!   It returns 503 once shutdown has started or while a critical
+//!   dependency is down; non-critical failures only mark it `degraded`.
+//! * `GET /health/dependency/{name}` checks a single dependency.
+//! * `GET /health/history` and `GET /health/trend` report recent `/ready`
+//!   results; see [`history`].
+
+pub mod checks;
+pub mod dependency;
+pub mod history;
+
+pub use dependency::{DependencyHealthCheck, HealthRegistry, HealthStatus};
+pub use history::{HealthHistory, HealthSample, HealthTrend, Trend};
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::json;
+
+use crate::error::ApiError;
+use crate::lifecycle::Lifecycle;
+use crate::outbound::HttpClient;
+use crate::proxy::Proxy;
+
+/// Default for `HEALTH_TCP_INTERVAL_MS`.
+pub const DEFAULT_TCP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Handler for the `/health` liveness route.
+///
+/// # Returns
+///
+/// * `impl Responder` - An HTTP response with a 200 OK status and "OK" body.
+pub async fn health() -> impl Responder {
+    HttpResponse::Ok().body("OK")
+}
+
+/// Handler for the `/ready` readiness route.
+///
+/// # Returns
+///
+/// * `impl Responder` - 200 OK with status `ready` or `degraded` and the
+///   dependency reports, or 503 Service Unavailable once shutdown has begun
+///   or a critical dependency is down. The result is recorded in the
+///   [`HealthHistory`] when one is configured.
+pub async fn ready(
+    lifecycle: web::Data<Lifecycle>,
+    registry: web::Data<HealthRegistry>,
+    history: Option<web::Data<HealthHistory>>,
+) -> impl Responder {
+    if !lifecycle.is_ready() {
+        if let Some(history) = &history {
+            history.record("shutting_down", Duration::ZERO);
+        }
+        return HttpResponse::ServiceUnavailable().json(json!({ "status": "shutting_down" }));
+    }
+
+    let started = Instant::now();
+    let reports = registry.check_all().await;
+    let latency = started.elapsed();
+    let critical_down = reports.iter().any(|r| r.critical && !r.is_up());
+    let any_down = reports.iter().any(|r| !r.is_up());
+    let (mut response, status) = match (critical_down, any_down) {
+        (true, _) => (HttpResponse::ServiceUnavailable(), "unavailable"),
+        (false, true) => (HttpResponse::Ok(), "degraded"),
+        (false, false) => (HttpResponse::Ok(), "ready"),
+    };
+    if let Some(history) = &history {
+        history.record(status, latency);
+    }
+    response.json(json!({ "status": status, "dependencies": reports }))
+}
+
+/// Handler for `GET /health/dependency/{name}`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the report when the dependency is up, 503 when it is down, or 404 for an unknown name.
+pub async fn dependency(
+    name: web::Path<String>,
+    registry: web::Data<HealthRegistry>,
+) -> Result<HttpResponse, ApiError> {
+    let report = registry.check_one(&name).await.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown_dependency",
+            format!("No dependency named '{}'", name),
+        )
+    })?;
+    let mut response = if report.is_up() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response.json(report))
+}
+
+/// Builds the registry from environment variables.
+///
+/// `HEALTH_POSTGRES_ADDR` and `HEALTH_REDIS_ADDR` register `postgres` and
+/// `redis`; `HEALTH_HTTP_DEPENDENCIES` registers comma-separated `name=url`
+/// pairs, and `HEALTH_TCP_TARGETS` comma-separated `name=host:port` or
+/// `name=tls://host:port` pairs, dialed at most once per
+/// `HEALTH_TCP_INTERVAL_MS`. Dependencies are critical unless listed in
+/// `HEALTH_NONCRITICAL`. `HEALTH_CACHE_MS` sets how long `/ready` reuses
+/// the last reports. HTTP dependencies are checked with the shared
+/// `client`.
+///
+/// # Returns
+///
+/// * `Result<HealthRegistry, IoError>` - The registry, or an IoError if `HEALTH_HTTP_DEPENDENCIES` or `HEALTH_TCP_TARGETS` is malformed.
+pub fn registry_from_env(client: &HttpClient) -> Result<HealthRegistry, IoError> {
+    let timeout = env::var("HEALTH_CHECK_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(dependency::DEFAULT_CHECK_TIMEOUT);
+    let noncritical = noncritical_from_env();
+    let critical = |name: &str| !noncritical.iter().any(|n| n == name);
+
+    let cache_ttl = env::var("HEALTH_CACHE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::ZERO);
+
+    let mut registry = HealthRegistry::new(timeout).with_cache_ttl(cache_ttl);
+    if let Ok(addr) = env::var("HEALTH_POSTGRES_ADDR") {
+        registry.register(
+            "postgres",
+            Arc::new(checks::PostgresDependency::new(&addr)),
+            critical("postgres"),
+        );
+    }
+    if let Ok(addr) = env::var("HEALTH_REDIS_ADDR") {
+        registry.register(
+            "redis",
+            Arc::new(checks::RedisDependency::new(&addr)),
+            critical("redis"),
+        );
+    }
+    for entry in env::var("HEALTH_HTTP_DEPENDENCIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let (name, url) = entry
+            .split_once('=')
+            .filter(|(name, url)| !name.is_empty() && !url.is_empty())
+            .ok_or_else(|| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid HEALTH_HTTP_DEPENDENCIES entry '{}'", entry),
+                )
+            })?;
+        registry.register(
+            name,
+            Arc::new(checks::ExternalHttpDependency::new(url, client)),
+            critical(name),
+        );
+    }
+    let interval = tcp_interval_from_env();
+    for entry in env::var("HEALTH_TCP_TARGETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let invalid = || {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid HEALTH_TCP_TARGETS entry '{}'", entry),
+            )
+        };
+        let (name, target) = entry
+            .split_once('=')
+            .filter(|(name, target)| !name.is_empty() && !target.is_empty())
+            .ok_or_else(invalid)?;
+        let check = match target.strip_prefix("tls://") {
+            Some(addr) => {
+                let (host, _) = addr.rsplit_once(':').ok_or_else(invalid)?;
+                checks::TcpDependency::new(addr)
+                    .with_tls(host.trim_start_matches('[').trim_end_matches(']'), &[])?
+            }
+            None => checks::TcpDependency::new(target),
+        };
+        registry.register_with_interval(name, Arc::new(check), critical(name), interval);
+    }
+    Ok(registry)
+}
+
+/// With `HEALTH_PROXY_UPSTREAMS=true`, registers a TCP check of every
+/// reverse proxy backend (and the canary), named `upstream:<host:port>`,
+/// with a TLS handshake for https upstreams. Like `HEALTH_TCP_TARGETS`,
+/// they are dialed at most once per `HEALTH_TCP_INTERVAL_MS` and critical
+/// unless listed in `HEALTH_NONCRITICAL`.
+///
+/// # Errors
+///
+/// Returns an IoError if an upstream has no host.
+pub fn register_proxy_upstreams(
+    registry: &mut HealthRegistry,
+    proxy: &Proxy,
+) -> Result<(), IoError> {
+    if env::var("HEALTH_PROXY_UPSTREAMS").map_or(true, |v| v != "true") {
+        return Ok(());
+    }
+    let noncritical = noncritical_from_env();
+    let interval = tcp_interval_from_env();
+    let canary = proxy.canary().map(|c| c.pool());
+    for backend in proxy
+        .pool()
+        .backends()
+        .iter()
+        .chain(canary.into_iter().flat_map(|p| p.backends()))
+    {
+        let url = backend.url();
+        let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Upstream {} has no host", url),
+            ));
+        };
+        let addr = format!("{}:{}", host, port);
+        let name = format!("upstream:{}", addr);
+        let mut check = checks::TcpDependency::new(&addr);
+        if url.scheme() == "https" {
+            check = check.with_tls(host.trim_start_matches('[').trim_end_matches(']'), &[])?;
+        }
+        let critical = !noncritical.contains(&name);
+        registry.register_with_interval(&name, Arc::new(check), critical, interval);
+    }
+    Ok(())
+}
+
+fn noncritical_from_env() -> Vec<String> {
+    env::var("HEALTH_NONCRITICAL")
+        .unwrap_or_default()
+        .split(',')
+        .map(|n| n.trim().to_string())
+        .collect()
+}
+
+fn tcp_interval_from_env() -> Duration {
+    env::var("HEALTH_TCP_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_TCP_INTERVAL)
+}
@@ -0,0 +1,348 @@
+//! Localization of user-facing messages using Fluent (`.ftl`) bundles.
+//!
+//! Bundles are loaded at startup from a locales directory laid out as
+//! `<locales>/<language-tag>/*.ftl`. Each request is assigned a locale by the
+//! [`LocaleNegotiation`](crate::middleware::locale::LocaleNegotiation)
+//! middleware, and handlers format messages through the [`Localizer`]
+//! extractor. Messages missing from the negotiated locale fall back to the
+//! default locale and then to English.
+
+use actix_web::dev::Payload;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use unic_langid::LanguageIdentifier;
+
+/// English messages compiled into the binary, used when no [`I18n`] has been
+/// registered with the application.
+const BUILTIN_EN_US: &str = include_str!("../../locales/en-US/main.ftl");
+
+/// The locale negotiated for a request, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct Locale(pub LanguageIdentifier);
+
+struct LocaleBundle {
+    id: LanguageIdentifier,
+    bundle: FluentBundle<FluentResource>,
+}
+
+/// A set of Fluent bundles keyed by language tag.
+pub struct I18n {
+    default: LanguageIdentifier,
+    bundles: BTreeMap<String, LocaleBundle>,
+}
+
+impl I18n {
+    /// Loads every locale found in `dir`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// * The locales directory cannot be read
+    /// * A `.ftl` file cannot be read or contains syntax errors
+    /// * No bundle exists for the default locale
+    pub fn load<P: AsRef<Path>>(dir: P, default: &str) -> Result<I18n, IoError> {
+        let dir = dir.as_ref();
+        info!("Loading translations from: {}", dir.display());
+
+        let mut sources = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let tag = match path.file_name().and_then(|n| n.to_str()) {
+                Some(tag) => tag.to_string(),
+                None => continue,
+            };
+
+            let mut files: Vec<_> = fs::read_dir(&path)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().map(|ext| ext == "ftl").unwrap_or(false))
+                .collect();
+            files.sort();
+
+            for file in files {
+                let source = fs::read_to_string(&file).map_err(|e| {
+                    error!(
+                        "Failed to read translation file '{}': {}",
+                        file.display(),
+                        e
+                    );
+                    e
+                })?;
+                sources.push((tag.clone(), source));
+            }
+        }
+
+        let i18n = I18n::from_sources(default, sources)?;
+        info!(
+            "Loaded translations for: {}",
+            i18n.bundles.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+        Ok(i18n)
+    }
+
+    /// Builds bundles from in-memory `(language tag, ftl source)` pairs.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a tag or source is invalid, or if no source is
+    /// provided for the default locale.
+    pub fn from_sources<I>(default: &str, sources: I) -> Result<I18n, IoError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let default = parse_tag(default)?;
+        let mut bundles: BTreeMap<String, LocaleBundle> = BTreeMap::new();
+
+        for (tag, source) in sources {
+            let id = match tag.parse::<LanguageIdentifier>() {
+                Ok(id) => id,
+                Err(_) => {
+                    warn!("Skipping translations with invalid language tag '{}'", tag);
+                    continue;
+                }
+            };
+            let resource = FluentResource::try_new(source).map_err(|(_, errors)| {
+                error!("Failed to parse translations for '{}': {:?}", tag, errors);
+                IoError::new(ErrorKind::InvalidData, "Invalid translation file")
+            })?;
+
+            let entry = bundles.entry(id.to_string()).or_insert_with(|| {
+                let mut bundle = FluentBundle::new_concurrent(vec![id.clone()]);
+                bundle.set_use_isolating(false);
+                LocaleBundle {
+                    id: id.clone(),
+                    bundle,
+                }
+            });
+            entry.bundle.add_resource(resource).map_err(|errors| {
+                error!("Conflicting translations for '{}': {:?}", tag, errors);
+                IoError::new(ErrorKind::InvalidData, "Conflicting translation messages")
+            })?;
+        }
+
+        if !bundles.contains_key(&default.to_string()) {
+            error!("No translations found for default locale '{}'", default);
+            return Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("No translations found for default locale '{}'", default),
+            ));
+        }
+
+        Ok(I18n { default, bundles })
+    }
+
+    /// Returns the built-in English bundle shared by applications that did
+    /// not register their own translations.
+    pub fn builtin() -> Arc<I18n> {
+        static BUILTIN: OnceLock<Arc<I18n>> = OnceLock::new();
+        BUILTIN
+            .get_or_init(|| {
+                let sources = vec![("en-US".to_string(), BUILTIN_EN_US.to_string())];
+                Arc::new(
+                    I18n::from_sources("en-US", sources).expect("invalid built-in translations"),
+                )
+            })
+            .clone()
+    }
+
+    /// Returns the configured default locale.
+    pub fn default_locale(&self) -> &LanguageIdentifier {
+        &self.default
+    }
+
+    /// Returns the language tags of all loaded locales.
+    pub fn locales(&self) -> Vec<String> {
+        self.bundles.keys().cloned().collect()
+    }
+
+    /// Picks the best available locale for a request.
+    ///
+    /// Candidates are considered in order: the `?lang=` override, then the
+    /// `Accept-Language` entries by descending quality, then the default
+    /// locale. A candidate matches a loaded locale exactly, or otherwise any
+    /// loaded locale with the same language (so `fr-CA` matches `fr`).
+    pub fn negotiate(
+        &self,
+        lang_override: Option<&str>,
+        accept_language: Option<&str>,
+    ) -> LanguageIdentifier {
+        let candidates = lang_override.into_iter().map(str::to_string).chain(
+            accept_language
+                .map(parse_accept_language)
+                .unwrap_or_default(),
+        );
+
+        for candidate in candidates {
+            if let Some(id) = self.match_locale(&candidate) {
+                return id;
+            }
+        }
+        self.default.clone()
+    }
+
+    fn match_locale(&self, tag: &str) -> Option<LanguageIdentifier> {
+        let wanted = tag.parse::<LanguageIdentifier>().ok()?;
+        if let Some(bundle) = self.bundles.get(&wanted.to_string()) {
+            return Some(bundle.id.clone());
+        }
+        self.bundles
+            .values()
+            .find(|b| b.id.language == wanted.language)
+            .map(|b| b.id.clone())
+    }
+
+    /// Formats the message `key` for `locale`.
+    ///
+    /// Falls back to the default locale and then to any English bundle when
+    /// the key is missing. If no bundle defines the key, the key itself is
+    /// returned.
+    pub fn format(
+        &self,
+        locale: &LanguageIdentifier,
+        key: &str,
+        args: Option<&FluentArgs>,
+    ) -> String {
+        let english = self
+            .bundles
+            .values()
+            .find(|b| b.id.language.as_str() == "en");
+        let chain = [
+            self.bundles.get(&locale.to_string()),
+            self.bundles.get(&self.default.to_string()),
+            english,
+        ];
+
+        for bundle in chain.into_iter().flatten() {
+            let pattern = match bundle.bundle.get_message(key).and_then(|m| m.value()) {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            if bundle.id != *locale {
+                warn!(
+                    "Missing translation '{}' for '{}', using '{}'",
+                    key, locale, bundle.id
+                );
+            }
+
+            let mut errors = Vec::new();
+            let value = bundle.bundle.format_pattern(pattern, args, &mut errors);
+            if !errors.is_empty() {
+                warn!("Errors formatting translation '{}': {:?}", key, errors);
+            }
+            return value.into_owned();
+        }
+
+        warn!("No translation found for '{}'", key);
+        key.to_string()
+    }
+}
+
+/// Handle for formatting messages in the locale negotiated for a request.
+#[derive(Clone)]
+pub struct Localizer {
+    i18n: Arc<I18n>,
+    locale: LanguageIdentifier,
+}
+
+impl Localizer {
+    /// Creates a localizer for `locale`.
+    pub fn new(i18n: Arc<I18n>, locale: LanguageIdentifier) -> Self {
+        Localizer { i18n, locale }
+    }
+
+    /// Returns the locale messages are formatted in.
+    pub fn locale(&self) -> &LanguageIdentifier {
+        &self.locale
+    }
+
+    /// Formats a message without arguments.
+    pub fn text(&self, key: &str) -> String {
+        self.i18n.format(&self.locale, key, None)
+    }
+
+    /// Formats a message with placeable arguments.
+    pub fn text_with_args(&self, key: &str, args: &FluentArgs) -> String {
+        self.i18n.format(&self.locale, key, Some(args))
+    }
+}
+
+impl FromRequest for Localizer {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let i18n = req
+            .app_data::<web::Data<I18n>>()
+            .map(|data| data.clone().into_inner())
+            .unwrap_or_else(I18n::builtin);
+
+        let locale = match req.extensions().get::<Locale>() {
+            Some(locale) => locale.0.clone(),
+            None => i18n.negotiate(
+                lang_override(req.query_string()),
+                req.headers()
+                    .get(actix_web::http::header::ACCEPT_LANGUAGE)
+                    .and_then(|v| v.to_str().ok()),
+            ),
+        };
+
+        ready(Ok(Localizer::new(i18n, locale)))
+    }
+}
+
+/// Extracts the `lang` parameter from a query string.
+pub fn lang_override(query: &str) -> Option<&str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == "lang")
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// Parses an `Accept-Language` header into tags ordered by quality.
+///
+/// Wildcards, entries with `q=0` and malformed quality values are dropped.
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut entries: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            if tag.is_empty() || tag == "*" {
+                return None;
+            }
+            let mut quality = 1.0;
+            for param in parts {
+                if let Some(q) = param.trim().strip_prefix("q=") {
+                    quality = q.trim().parse::<f32>().ok()?;
+                }
+            }
+            if quality <= 0.0 {
+                return None;
+            }
+            Some((tag.to_string(), quality))
+        })
+        .collect();
+
+    // Stable sort keeps header order for equal qualities
+    entries.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    entries.into_iter().map(|(tag, _)| tag).collect()
+}
+
+fn parse_tag(tag: &str) -> Result<LanguageIdentifier, IoError> {
+    tag.parse::<LanguageIdentifier>().map_err(|_| {
+        error!("Invalid locale '{}'", tag);
+        IoError::new(ErrorKind::InvalidInput, format!("Invalid locale '{}'", tag))
+    })
+}
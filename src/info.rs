@@ -0,0 +1,78 @@
+//! Build, runtime and configuration details of the running server.
+//!
+//! `GET /admin/info` returns a [`ServerInfo`] and `GET /admin/info/features`
+//! only the Cargo features. The features, commit, build date and compiler
+//! version are generated by `build.rs`; the commit is `unknown` when the
+//! binary was not built from a git checkout.
+
+use std::process;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+use serde_json::json;
+
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// TLS implementation serving HTTPS.
+pub const TLS_PROVIDER: &str = "rustls 0.20";
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Records when the server started, for the uptime; later calls keep the
+/// first time.
+pub fn mark_started() -> Instant {
+    *STARTED.get_or_init(Instant::now)
+}
+
+/// What the server is and how long it has been running.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ServerInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub build_date: String,
+    pub rust_version: String,
+    pub os: String,
+    pub arch: String,
+    pub tls_provider: String,
+    pub features_enabled: Vec<String>,
+    pub uptime_secs: u64,
+    pub pid: u32,
+}
+
+impl ServerInfo {
+    /// Collects the details of this process.
+    pub fn current() -> Self {
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: GIT_COMMIT.to_string(),
+            build_date: BUILD_DATE.to_string(),
+            rust_version: RUST_VERSION.to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            tls_provider: TLS_PROVIDER.to_string(),
+            features_enabled: ENABLED_FEATURES.iter().map(|f| f.to_string()).collect(),
+            uptime_secs: mark_started().elapsed().as_secs(),
+            pid: process::id(),
+        }
+    }
+}
+
+/// Handler for `GET /admin/info`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the [`ServerInfo`] of this process.
+pub async fn info() -> HttpResponse {
+    HttpResponse::Ok().json(ServerInfo::current())
+}
+
+/// Handler for `GET /admin/info/features`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with `{"features": [...]}`, the Cargo features of the build.
+pub async fn features() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "features": ENABLED_FEATURES }))
+}
@@ -0,0 +1,436 @@
+//! Process lifecycle state and the graceful shutdown sequence.
+//!
+//! On Kubernetes, endpoint removal lags behind SIGTERM, so traffic keeps
+//! arriving for a few seconds after the signal. The shutdown sequence
+//! therefore runs in phases:
+//!
+//! 1. On SIGTERM, `/ready` starts returning 503 so the pod is removed from
+//!    the service endpoints, while all other routes keep serving normally.
+//! 2. After `PRE_SHUTDOWN_DELAY`, the connection drain starts: the
+//!    listeners stop accepting and responses carry `Connection: close` so
+//!    keep-alive clients move to other pods.
+//! 3. The process exits once in-flight requests have completed and the
+//!    buffered access log has been flushed. Requests still running after
+//!    `SHUTDOWN_TIMEOUT_SECS` are aborted.
+//!
+//! While draining, a [`DrainReport`] logs the requests holding the drain
+//! open every `DRAIN_REPORT_INTERVAL`, and the ones aborted at the
+//! timeout, so a shutdown that takes the full timeout says why.
+//!
+//! The drain also waits for the HTTP/1.1 connections still open to close,
+//! after their last response or when their keep-alive expires, so no client
+//! has a request cut off by sending it on an idle connection as the server
+//! stops. Connections the server accepted just before it stopped accepting
+//! are given [`TLS_HANDSHAKE_TIMEOUT`] to show up.
+
+use actix_web::dev::ServerHandle;
+use log::{info, warn};
+use std::env;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::middleware::access_log;
+use crate::middleware::in_flight::{InFlightRegistry, InFlightRequest};
+
+/// Default for `SHUTDOWN_TIMEOUT_SECS`, the Actix default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default for `DRAIN_REPORT_INTERVAL`.
+pub const DEFAULT_DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time a client has to complete the TLS handshake.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often the drain checks whether the requests in flight completed.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Callback run when the drain starts.
+type DrainHook = Box<dyn Fn() + Send + Sync>;
+
+/// Callback counting open connections the drain waits for.
+type ConnectionCount = Box<dyn Fn() -> usize + Send + Sync>;
+
+/// Shared readiness and drain flags.
+pub struct Lifecycle {
+    ready: AtomicBool,
+    draining: AtomicBool,
+    on_drain: Mutex<Vec<DrainHook>>,
+}
+
+impl Lifecycle {
+    /// Creates a lifecycle in the ready, not draining state.
+    pub fn new() -> Self {
+        Lifecycle {
+            ready: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+            on_drain: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns `true` while the instance should receive new traffic.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Marks the instance as not ready; `/ready` will return 503.
+    pub fn mark_not_ready(&self) {
+        self.ready.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns `true` once the connection drain has started.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Marks the start of the connection drain and runs the hooks added
+    /// with [`on_drain`](Self::on_drain), once.
+    pub fn begin_drain(&self) {
+        self.ready.store(false, Ordering::SeqCst);
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            for hook in self.on_drain.lock().unwrap().drain(..) {
+                hook();
+            }
+        }
+    }
+
+    /// Runs `hook` when the drain starts, before the server stops, e.g. to
+    /// stop accept loops running outside the server. Runs it right away if
+    /// the drain has already started.
+    pub fn on_drain(&self, hook: impl Fn() + Send + Sync + 'static) {
+        let mut hooks = self.on_drain.lock().unwrap();
+        if self.is_draining() {
+            drop(hooks);
+            hook();
+        } else {
+            hooks.push(Box::new(hook));
+        }
+    }
+}
+
+impl fmt::Debug for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lifecycle")
+            .field("ready", &self.is_ready())
+            .field("draining", &self.is_draining())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses a delay such as `15`, `15s`, `500ms` or `2m`; bare numbers are
+/// seconds.
+pub fn parse_delay(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// Reads the delay between SIGTERM and the connection drain from
+/// `PRE_SHUTDOWN_DELAY`, or else from the older `SHUTDOWN_GRACE_DELAY_SECS`.
+///
+/// # Errors
+///
+/// Returns an error if `PRE_SHUTDOWN_DELAY` is not a valid delay.
+pub fn grace_delay_from_env() -> Result<Duration, IoError> {
+    if let Ok(value) = env::var("PRE_SHUTDOWN_DELAY") {
+        return parse_delay(&value).ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "invalid PRE_SHUTDOWN_DELAY '{}', expected e.g. 15s or 500ms",
+                    value
+                ),
+            )
+        });
+    }
+    Ok(env::var("SHUTDOWN_GRACE_DELAY_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::ZERO))
+}
+
+/// Reports the requests holding the connection drain open.
+pub struct DrainReport {
+    registry: Arc<InFlightRegistry>,
+    timeout: Duration,
+    interval: Duration,
+    connections: Vec<ConnectionCount>,
+    settle: Duration,
+    pause: bool,
+}
+
+impl DrainReport {
+    /// Reports on `registry` while the server drains for at most `timeout`.
+    pub fn new(registry: Arc<InFlightRegistry>, timeout: Duration) -> Self {
+        DrainReport {
+            registry,
+            timeout,
+            interval: DEFAULT_DRAIN_REPORT_INTERVAL,
+            connections: Vec::new(),
+            settle: Duration::ZERO,
+            pause: true,
+        }
+    }
+
+    /// Logs the requests in flight every `interval` instead.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Also waits until `count` open connections are left, e.g.
+    /// [`connections::open_http1`](crate::connections::open_http1).
+    pub fn with_connections(mut self, count: impl Fn() -> usize + Send + Sync + 'static) -> Self {
+        self.connections.push(Box::new(count));
+        self
+    }
+
+    /// Waits at least `settle` after the server stops accepting, for the
+    /// connections it accepted just before to be counted.
+    pub fn with_settle_time(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// Leaves the server's listeners accepting, for listeners relayed by
+    /// accept loops outside the server that a drain hook stops instead.
+    pub fn without_pause(mut self) -> Self {
+        self.pause = false;
+        self
+    }
+
+    /// Reads `SHUTDOWN_TIMEOUT_SECS` and `DRAIN_REPORT_INTERVAL`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either is invalid.
+    pub fn from_env(registry: Arc<InFlightRegistry>) -> Result<Self, IoError> {
+        let timeout = match env::var("SHUTDOWN_TIMEOUT_SECS") {
+            Ok(secs) => Duration::from_secs(secs.trim().parse().map_err(|_| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "SHUTDOWN_TIMEOUT_SECS must be a number of seconds, got '{}'",
+                        secs
+                    ),
+                )
+            })?),
+            Err(_) => DEFAULT_SHUTDOWN_TIMEOUT,
+        };
+        let report = DrainReport::new(registry, timeout);
+        match env::var("DRAIN_REPORT_INTERVAL") {
+            Ok(value) => match parse_delay(&value).filter(|d| !d.is_zero()) {
+                Some(interval) => Ok(report.with_interval(interval)),
+                None => Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "invalid DRAIN_REPORT_INTERVAL '{}', expected e.g. 5s or 500ms",
+                        value
+                    ),
+                )),
+            },
+            Err(_) => Ok(report),
+        }
+    }
+
+    /// The drain timeout, for `HttpServer::shutdown_timeout`.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Drains the server behind `handle`: stops accepting, waits for the
+    /// requests in flight and the counted connections, logging the requests
+    /// every interval, then stops the server. The requests left at the
+    /// timeout are logged and aborted, and idle connections closed.
+    ///
+    /// The requests are awaited here rather than by the server's graceful
+    /// stop: once the accept thread stops, an Actix worker may exit before
+    /// it sees the stop command, dropping its connections.
+    ///
+    /// Returns whether every request completed.
+    pub async fn drain(&self, handle: &ServerHandle) -> bool {
+        use actix_web::rt::time::{interval, interval_at, sleep, Instant};
+
+        if self.pause {
+            handle.pause().await;
+        }
+        let settled = Instant::now() + self.settle;
+        let deadline = sleep(self.timeout);
+        tokio::pin!(deadline);
+        let mut reports = interval_at(Instant::now() + self.interval, self.interval);
+        let mut polls = interval(DRAIN_POLL_INTERVAL);
+        let drained = loop {
+            let settling = Instant::now() < settled;
+            if self.registry.is_empty() && !settling && self.open_connections() == 0 {
+                break true;
+            }
+            tokio::select! {
+                _ = polls.tick() => {}
+                _ = reports.tick() => self.log_in_flight(),
+                _ = &mut deadline => break self.registry.is_empty(),
+            }
+        };
+        if !drained {
+            self.log_aborted();
+        }
+        let idle = self.open_connections();
+        if idle > 0 {
+            info!("Drain timeout reached: closing {} idle connections", idle);
+        }
+        handle.stop(drained).await;
+        drained
+    }
+
+    fn open_connections(&self) -> usize {
+        self.connections.iter().map(|count| count()).sum()
+    }
+
+    fn log_in_flight(&self) {
+        let requests = self.registry.snapshot();
+        if requests.is_empty() {
+            return;
+        }
+        info!("Draining: {} requests in flight", requests.len());
+        for request in &requests {
+            info!("In flight: {}", describe(request));
+        }
+    }
+
+    fn log_aborted(&self) {
+        let requests = self.registry.snapshot();
+        if requests.is_empty() {
+            return;
+        }
+        warn!(
+            "Shutdown timeout of {}s reached: aborting {} requests",
+            self.timeout.as_secs_f64(),
+            requests.len()
+        );
+        for request in &requests {
+            warn!("Aborted at the shutdown timeout: {}", describe(request));
+        }
+    }
+}
+
+fn describe(request: &InFlightRequest) -> String {
+    format!(
+        "method={} route={:?} elapsed_ms={} client_ip={} request_id={}",
+        request.method,
+        request.route,
+        request.elapsed().as_millis(),
+        request.client_ip.as_deref().unwrap_or("-"),
+        request.request_id
+    )
+}
+
+/// Waits for a shutdown signal and runs the shutdown sequence.
+///
+/// SIGTERM honours `grace_delay` before draining; SIGINT (Ctrl-C) drains
+/// immediately, which is what you want during local development. `report`
+/// logs the requests holding the drain open.
+pub async fn handle_shutdown_signals(
+    handle: ServerHandle,
+    lifecycle: Arc<Lifecycle>,
+    grace_delay: Duration,
+    report: DrainReport,
+) {
+    let delay = match wait_for_signal().await {
+        Signal::Terminate => grace_delay,
+        Signal::Interrupt => Duration::ZERO,
+    };
+    shutdown_with_report(handle, &lifecycle, delay, &report).await;
+}
+
+/// Runs the shutdown phases: readiness flip, grace delay, then drain.
+pub async fn shutdown(handle: ServerHandle, lifecycle: &Lifecycle, delay: Duration) {
+    run_shutdown(handle, lifecycle, delay, None).await;
+}
+
+/// Runs the shutdown phases, reporting the requests holding the drain open.
+pub async fn shutdown_with_report(
+    handle: ServerHandle,
+    lifecycle: &Lifecycle,
+    delay: Duration,
+    report: &DrainReport,
+) {
+    run_shutdown(handle, lifecycle, delay, Some(report)).await;
+}
+
+async fn run_shutdown(
+    handle: ServerHandle,
+    lifecycle: &Lifecycle,
+    delay: Duration,
+    report: Option<&DrainReport>,
+) {
+    lifecycle.mark_not_ready();
+    info!("Shutdown phase 1: readiness set to not ready");
+
+    if !delay.is_zero() {
+        info!(
+            "Shutdown phase 2: serving normally for {}s grace delay",
+            delay.as_secs_f64()
+        );
+        actix_web::rt::time::sleep(delay).await;
+        info!("Shutdown phase 2 complete: grace delay elapsed");
+    }
+
+    lifecycle.begin_drain();
+    info!("Shutdown phase 3: draining connections");
+    match report {
+        Some(report) => {
+            report.drain(&handle).await;
+        }
+        None => handle.stop(true).await,
+    }
+    info!("Shutdown complete: all connections drained");
+    access_log::flush();
+}
+
+enum Signal {
+    Terminate,
+    Interrupt,
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> Signal {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("failed to install SIGINT handler");
+
+    tokio::select! {
+        _ = sigterm.recv() => {
+            info!("Received SIGTERM");
+            Signal::Terminate
+        }
+        _ = sigint.recv() => {
+            info!("Received SIGINT");
+            Signal::Interrupt
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> Signal {
+    let _ = actix_web::rt::signal::ctrl_c().await;
+    info!("Received Ctrl-C");
+    Signal::Interrupt
+}
@@ -0,0 +1,543 @@
+//! The most recent log records, kept in memory.
+//!
+//! [`init`] installs `env_logger` as usual and also copies every record it
+//! prints into a [`LogBuffer`] of the last `LOG_BUFFER_LINES` records, and no
+//! more than `LOG_BUFFER_BYTES` of text. Messages go through a [`Redactor`]
+//! first, so secrets reach neither the buffer nor the console.
+//! `GET /admin/logs` returns records as JSON, filtered by level and time;
+//! `GET /admin/logs/tail` returns the end of the buffer as text and, with
+//! `?follow=true`, keeps streaming lines as they are logged.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::config_reload::REDACTED;
+use crate::error::ApiError;
+use crate::util::stream::StreamBody;
+
+/// Default for `LOG_BUFFER_LINES`.
+pub const DEFAULT_LINES: usize = 1000;
+
+/// Default for `LOG_BUFFER_BYTES`.
+pub const DEFAULT_BYTES: usize = 1024 * 1024;
+
+/// Keys whose values are always redacted; `LOG_REDACT_KEYS` adds more.
+pub const DEFAULT_REDACTED_KEYS: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "api_key",
+    "api-key",
+    "apikey",
+    "authorization",
+    "cookie",
+    "private_key",
+];
+
+/// Records returned by `/admin/logs` and lines by `/admin/logs/tail`
+/// without a limit.
+const DEFAULT_TAIL_LINES: usize = 100;
+
+/// A log record as it was printed.
+#[derive(Debug)]
+pub struct LogRecord {
+    /// Position in the log, counting from 0 at startup.
+    pub seq: u64,
+    pub time: DateTime<Utc>,
+    pub level: Level,
+    /// The formatted line, already redacted.
+    pub line: Arc<str>,
+}
+
+impl LogRecord {
+    /// JSON form returned by `/admin/logs`.
+    pub fn describe(&self) -> Value {
+        json!({
+            "seq": self.seq,
+            "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "level": self.level.to_string(),
+            "line": &*self.line,
+        })
+    }
+}
+
+struct Records {
+    /// Sequence number of the first retained record.
+    first: u64,
+    records: VecDeque<Arc<LogRecord>>,
+    /// Length of the retained lines.
+    bytes: usize,
+}
+
+/// Ring buffer of the last log records.
+pub struct LogBuffer {
+    capacity: usize,
+    max_bytes: usize,
+    records: Mutex<Records>,
+    /// Sequence number the next record will get; watched by followers.
+    next: watch::Sender<u64>,
+}
+
+impl LogBuffer {
+    /// Keeps the last `capacity` records (at least one), with no limit on
+    /// their size.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        LogBuffer {
+            capacity,
+            max_bytes: usize::MAX,
+            records: Mutex::new(Records {
+                first: 0,
+                records: VecDeque::with_capacity(capacity),
+                bytes: 0,
+            }),
+            next: watch::channel(0).0,
+        }
+    }
+
+    /// Also drops the oldest records while the lines take more than
+    /// `max_bytes`; the newest record is kept whatever its size.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Reads `LOG_BUFFER_LINES` and `LOG_BUFFER_BYTES`, using the defaults
+    /// for unset or invalid values.
+    pub fn from_env() -> Self {
+        LogBuffer::new(positive_from_env("LOG_BUFFER_LINES").unwrap_or(DEFAULT_LINES))
+            .with_max_bytes(positive_from_env("LOG_BUFFER_BYTES").unwrap_or(DEFAULT_BYTES))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Appends a line logged at `Info` now.
+    pub fn push(&self, line: impl Into<Arc<str>>) {
+        self.push_at(Level::Info, Utc::now(), line);
+    }
+
+    /// Appends a record, dropping the oldest ones when full.
+    pub fn push_at(&self, level: Level, time: DateTime<Utc>, line: impl Into<Arc<str>>) {
+        let line = line.into();
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = records.first + records.records.len() as u64;
+        records.bytes += line.len();
+        records.records.push_back(Arc::new(LogRecord {
+            seq,
+            time,
+            level,
+            line,
+        }));
+        while records.records.len() > self.capacity
+            || (records.bytes > self.max_bytes && records.records.len() > 1)
+        {
+            if let Some(dropped) = records.records.pop_front() {
+                records.bytes -= dropped.line.len();
+                records.first += 1;
+            }
+        }
+        drop(records);
+        self.next.send_replace(seq + 1);
+    }
+
+    /// Returns the last `limit` records at `level` or more severe, logged at
+    /// or after `since`, oldest first, and the sequence number following
+    /// the buffer.
+    pub fn query(
+        &self,
+        level: LevelFilter,
+        since: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> (Vec<Arc<LogRecord>>, u64) {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let next = records.first + records.records.len() as u64;
+        let mut matching: Vec<_> = records
+            .records
+            .iter()
+            .rev()
+            .filter(|r| r.level <= level && since.is_none_or(|since| r.time >= since))
+            .take(limit)
+            .cloned()
+            .collect();
+        matching.reverse();
+        (matching, next)
+    }
+
+    /// Returns the retained records from sequence number `seq` on, and the
+    /// sequence number following them. Records already dropped are skipped.
+    pub fn since(&self, seq: u64) -> (Vec<Arc<LogRecord>>, u64) {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let skip = seq.saturating_sub(records.first) as usize;
+        let next = records.first + records.records.len() as u64;
+        (records.records.iter().skip(skip).cloned().collect(), next)
+    }
+
+    /// Streams the lines at `level` or more severe logged from sequence
+    /// number `seq` on, forever.
+    pub fn follow(
+        self: Arc<Self>,
+        seq: u64,
+        level: LevelFilter,
+    ) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        let changes = self.next.subscribe();
+        stream::unfold(
+            (self, changes, seq),
+            move |(buffer, mut changes, mut seq)| async move {
+                loop {
+                    // Mark the current value seen before reading, so a line
+                    // logged in between still wakes us up
+                    changes.borrow_and_update();
+                    let (records, next) = buffer.since(seq);
+                    seq = next;
+                    let records: Vec<_> =
+                        records.into_iter().filter(|r| r.level <= level).collect();
+                    if !records.is_empty() {
+                        return Some((Ok(join(&records)), (buffer, changes, seq)));
+                    }
+                    // The sender lives in the buffer, which we hold
+                    let _ = changes.changed().await;
+                }
+            },
+        )
+    }
+}
+
+fn positive_from_env(name: &str) -> Option<usize> {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+}
+
+/// Joins the lines of records into one chunk, each ending with a newline.
+fn join(records: &[Arc<LogRecord>]) -> Bytes {
+    let mut chunk = String::with_capacity(records.iter().map(|r| r.line.len() + 1).sum());
+    for record in records {
+        chunk.push_str(&record.line);
+        chunk.push('\n');
+    }
+    Bytes::from(chunk)
+}
+
+/// Replaces the values of secret keys in log messages with `[redacted]`.
+///
+/// A key matches case-insensitively when it is not preceded by a letter or
+/// digit and is followed by `=` or `:`, as in `token=abc`,
+/// `"password": "abc"` or `Authorization: Bearer abc`. The value ends at its
+/// closing quote or, unquoted, at whitespace or punctuation; an
+/// authorization scheme such as `Bearer` takes the following word along.
+#[derive(Clone, Debug)]
+pub struct Redactor {
+    /// Lowercase keys.
+    keys: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new<I, S>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Redactor {
+            keys: keys
+                .into_iter()
+                .map(|key| key.as_ref().trim().to_ascii_lowercase())
+                .filter(|key| !key.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Redacts [`DEFAULT_REDACTED_KEYS`] and the comma-separated keys of
+    /// `LOG_REDACT_KEYS`.
+    pub fn from_env() -> Self {
+        let extra = env::var("LOG_REDACT_KEYS").unwrap_or_default();
+        Redactor::new(
+            DEFAULT_REDACTED_KEYS
+                .iter()
+                .copied()
+                .chain(extra.split(',')),
+        )
+    }
+
+    /// Returns `text` with the values of secret keys redacted.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let lower = text.to_ascii_lowercase().into_bytes();
+        let mut redacted = String::new();
+        let mut copied = 0;
+        let mut i = 0;
+        while i < lower.len() {
+            match self.value_at(&lower, i) {
+                Some((start, end)) => {
+                    redacted.push_str(&text[copied..start]);
+                    redacted.push_str(REDACTED);
+                    copied = end;
+                    i = end;
+                }
+                None => i += 1,
+            }
+        }
+        if copied == 0 {
+            return Cow::Borrowed(text);
+        }
+        redacted.push_str(&text[copied..]);
+        Cow::Owned(redacted)
+    }
+
+    /// Whether a field named `name`, such as `new_password`, holds a secret:
+    /// its name contains one of the keys.
+    pub fn is_secret(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.keys.iter().any(|key| name.contains(key.as_str()))
+    }
+
+    /// The byte range of the value of a key starting at `i`, if any.
+    fn value_at(&self, lower: &[u8], i: usize) -> Option<(usize, usize)> {
+        if i > 0 && lower[i - 1].is_ascii_alphanumeric() {
+            return None;
+        }
+        self.keys
+            .iter()
+            .filter(|key| lower[i..].starts_with(key.as_bytes()))
+            .find_map(|key| value_after(lower, i + key.len()))
+    }
+}
+
+/// The byte range of the value following a key that ends at `i`.
+fn value_after(lower: &[u8], mut i: usize) -> Option<(usize, usize)> {
+    // The closing quote of a quoted key, as in JSON
+    if matches!(lower.get(i), Some(b'"' | b'\'')) {
+        i += 1;
+    }
+    i = skip_spaces(lower, i);
+    if !matches!(lower.get(i), Some(b'=' | b':')) {
+        return None;
+    }
+    i = skip_spaces(lower, i + 1);
+    if let Some(&quote @ (b'"' | b'\'')) = lower.get(i) {
+        let start = i + 1;
+        let end = lower[start..]
+            .iter()
+            .position(|&b| b == quote)
+            .map_or(lower.len(), |p| start + p);
+        return Some((start, end));
+    }
+    let mut end = word_end(lower, i);
+    if matches!(&lower[i..end], b"bearer" | b"basic" | b"digest") && lower.get(end) == Some(&b' ') {
+        end = word_end(lower, end + 1);
+    }
+    (end > i).then_some((i, end))
+}
+
+fn skip_spaces(bytes: &[u8], i: usize) -> usize {
+    i + bytes[i.min(bytes.len())..]
+        .iter()
+        .take_while(|b| **b == b' ' || **b == b'\t')
+        .count()
+}
+
+fn word_end(bytes: &[u8], i: usize) -> usize {
+    i + bytes[i..]
+        .iter()
+        .take_while(|&&b| !b" \t\r\n,;&)}]\"'".contains(&b))
+        .count()
+}
+
+/// `env_logger` also writing into a [`LogBuffer`], with messages redacted
+/// for both.
+pub struct BufferingLogger {
+    inner: env_logger::Logger,
+    buffer: Arc<LogBuffer>,
+    redactor: Redactor,
+}
+
+impl BufferingLogger {
+    pub fn new(inner: env_logger::Logger, buffer: Arc<LogBuffer>, redactor: Redactor) -> Self {
+        BufferingLogger {
+            inner,
+            buffer,
+            redactor,
+        }
+    }
+}
+
+impl Log for BufferingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let message = record.args().to_string();
+        let message = self.redactor.redact(&message);
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+        let now = Utc::now();
+        self.buffer.push_at(
+            record.level(),
+            now,
+            format!(
+                "{} {:<5} {}: {}",
+                now.to_rfc3339_opts(SecondsFormat::Millis, true),
+                record.level(),
+                record.target(),
+                message
+            ),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs `env_logger`, configured from `RUST_LOG` as usual, redacting
+/// messages with [`Redactor::from_env`] and copying them into a buffer of
+/// `LOG_BUFFER_LINES` records.
+///
+/// # Returns
+///
+/// * `Arc<LogBuffer>` - The buffer, also when another logger was already installed (it then stays empty).
+pub fn init() -> Arc<LogBuffer> {
+    let buffer = Arc::new(LogBuffer::from_env());
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter();
+    let logger = BufferingLogger::new(inner, buffer.clone(), Redactor::from_env());
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    buffer
+}
+
+/// Parses a `level` parameter, `trace` when absent.
+fn parse_level(level: Option<&str>) -> Result<LevelFilter, ApiError> {
+    level.map_or(Ok(LevelFilter::Trace), |level| {
+        LevelFilter::from_str(level).map_err(|_| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "level must be one of off, error, warn, info, debug or trace",
+            )
+            .with_field("level", "unknown level")
+        })
+    })
+}
+
+/// Checks the record count in `field` against the buffer, defaulting to 100.
+fn parse_limit(buffer: &LogBuffer, field: &str, limit: Option<usize>) -> Result<usize, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_TAIL_LINES.min(buffer.capacity()));
+    if limit > buffer.capacity() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            format!("{} must be at most {}", field, buffer.capacity()),
+        )
+        .with_field(field, "out of range"));
+    }
+    Ok(limit)
+}
+
+/// Query parameters of `/admin/logs`; `since` is RFC 3339.
+#[derive(Deserialize)]
+pub struct LogsParams {
+    /// Least severe level to return (default trace, i.e. everything).
+    pub level: Option<String>,
+    /// Number of records to return, the most recent ones (default 100).
+    pub limit: Option<usize>,
+    pub since: Option<String>,
+}
+
+/// Handler for `GET /admin/logs`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with `{"records": [...]}`, the last matching records oldest first, or 400 if `level` or `since` is invalid or `limit` exceeds the buffer.
+pub async fn logs(
+    buffer: web::Data<LogBuffer>,
+    params: web::Query<LogsParams>,
+) -> Result<HttpResponse, ApiError> {
+    let level = parse_level(params.level.as_deref())?;
+    let limit = parse_limit(&buffer, "limit", params.limit)?;
+    let since = params
+        .since
+        .as_deref()
+        .map(|since| {
+            DateTime::parse_from_rfc3339(since)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|_| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_query",
+                        "since must be an RFC 3339 timestamp",
+                    )
+                    .with_field("since", "not an RFC 3339 timestamp")
+                })
+        })
+        .transpose()?;
+    let (records, _) = buffer.query(level, since, limit);
+    let records: Vec<Value> = records.iter().map(|r| r.describe()).collect();
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(json!({ "records": records })))
+}
+
+/// Query parameters of `/admin/logs/tail`.
+#[derive(Deserialize)]
+pub struct TailParams {
+    /// Number of past lines to return (default 100).
+    pub lines: Option<usize>,
+    /// Least severe level to return (default trace, i.e. everything).
+    pub level: Option<String>,
+    /// Keep streaming new lines (default false).
+    pub follow: Option<bool>,
+}
+
+/// Handler for `GET /admin/logs/tail`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK streaming the last lines as text, followed by new ones with `follow=true`, or 400 if `level` is invalid or `lines` exceeds the buffer.
+pub async fn tail(
+    buffer: web::Data<LogBuffer>,
+    params: web::Query<TailParams>,
+) -> Result<HttpResponse, ApiError> {
+    let level = parse_level(params.level.as_deref())?;
+    let count = parse_limit(&buffer, "lines", params.lines)?;
+    let (records, next) = buffer.query(level, None, count);
+    let past = stream::iter((!records.is_empty()).then(|| Ok::<_, Infallible>(join(&records))));
+    let mut res = HttpResponse::Ok();
+    res.content_type("text/plain; charset=utf-8")
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+    let body = if params.follow.unwrap_or(false) {
+        past.chain(buffer.into_inner().follow(next, level))
+            .boxed_local()
+    } else {
+        past.boxed_local()
+    };
+    Ok(StreamBody::new(body).into_response(res))
+}
@@ -0,0 +1,116 @@
+//! Digests of server errors.
+//!
+//! The access log reports every 5xx response to [`record`]. When
+//! `MAIL_ALERT_TO` is set, errors are collected and mailed as one digest
+//! every `MAIL_ALERT_INTERVAL_SECS`, so an incident produces a handful of
+//! messages instead of one per failed request.
+
+use std::env;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+
+use super::{templates, MailQueue};
+
+/// Default for `MAIL_ALERT_INTERVAL_SECS`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+/// Most errors listed in one digest; later ones are only counted.
+pub const MAX_LISTED: usize = 100;
+
+/// A request answered with a server error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerError {
+    pub at: DateTime<Utc>,
+    pub status: u16,
+    pub method: String,
+    pub path: String,
+    pub request_id: String,
+}
+
+/// Errors collected since the last digest.
+#[derive(Default)]
+pub struct AlertDigest {
+    pending: Mutex<(Vec<ServerError>, usize)>,
+}
+
+impl AlertDigest {
+    /// Adds `error` to the next digest.
+    pub fn push(&self, error: ServerError) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.0.len() < MAX_LISTED {
+            pending.0.push(error);
+        } else {
+            pending.1 += 1;
+        }
+    }
+
+    /// Takes the collected errors and the number that were not listed.
+    pub fn take(&self) -> (Vec<ServerError>, usize) {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    /// Queues a digest to `to` every `every` while errors were collected.
+    pub fn spawn(self: &Arc<Self>, queue: MailQueue, to: String, every: Duration) {
+        let digest = self.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let (errors, dropped) = digest.take();
+                if errors.is_empty() && dropped == 0 {
+                    continue;
+                }
+                if !queue.enqueue(templates::error_digest(&to, &errors, dropped)) {
+                    warn!(
+                        "Dropped a digest of {} server errors",
+                        errors.len() + dropped
+                    );
+                }
+            }
+        });
+    }
+}
+
+static DIGEST: OnceLock<Arc<AlertDigest>> = OnceLock::new();
+
+/// Makes [`record`] collect into `digest`. Only the first call has an effect.
+pub fn install(digest: Arc<AlertDigest>) {
+    let _ = DIGEST.set(digest);
+}
+
+/// Records a server error for the next digest, if digests are enabled.
+pub fn record(status: u16, method: &str, path: &str, request_id: &str) {
+    if let Some(digest) = DIGEST.get() {
+        digest.push(ServerError {
+            at: Utc::now(),
+            status,
+            method: method.to_string(),
+            path: path.to_string(),
+            request_id: request_id.to_string(),
+        });
+    }
+}
+
+/// Starts sending digests to `MAIL_ALERT_TO` through `queue`, if set.
+pub fn from_env(queue: &MailQueue) {
+    let Some(to) = env::var("MAIL_ALERT_TO").ok().filter(|to| !to.is_empty()) else {
+        return;
+    };
+    let every = env::var("MAIL_ALERT_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let digest = Arc::new(AlertDigest::default());
+    digest.spawn(queue.clone(), to.clone(), every);
+    install(digest);
+    info!(
+        "Mailing server error digests to {} every {}s",
+        to,
+        every.as_secs()
+    );
+}
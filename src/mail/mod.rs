@@ -0,0 +1,313 @@
+//! Outbound email.
+//!
+//! Messages are rendered from [`templates`] and handed to a [`MailQueue`],
+//! whose background task delivers them through a [`Mailer`] and retries
+//! transient failures with exponential backoff, so request handlers never
+//! wait on SMTP. Two mailers exist: [`LogMailer`], which only logs messages
+//! (for development), and the SMTP mailer of the `email` feature.
+//!
+//! `MAIL_TRANSPORT` selects the mailer (`smtp` or `log`); it defaults to
+//! `smtp` when `SMTP_HOST` is set. [`alerts`] batches 5xx responses into
+//! periodic digests sent to `MAIL_ALERT_TO`.
+
+pub mod alerts;
+#[cfg(feature = "email")]
+pub mod smtp;
+pub mod templates;
+
+use std::env;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::audit::AuditTrail;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+
+/// Default for `MAIL_QUEUE_CAPACITY`.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+
+/// A message ready to be sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Why a message could not be delivered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MailError {
+    /// Worth retrying, e.g. a 4xx SMTP reply or a connection failure.
+    Transient(String),
+    /// Retrying cannot help, e.g. a 5xx SMTP reply or an invalid address.
+    Permanent(String),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Transient(reason) => write!(f, "transient failure: {}", reason),
+            MailError::Permanent(reason) => write!(f, "permanent failure: {}", reason),
+        }
+    }
+}
+
+/// Delivers messages.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+/// Logs messages instead of sending them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        info!(
+            "Email to {} with subject {:?}:\n{}",
+            email.to, email.subject, email.text
+        );
+        Ok(())
+    }
+}
+
+/// How often and how patiently transient failures are retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts per message, including the first.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before retry number `retry` (starting at 1),
+    /// doubling each time up to `max_backoff`.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Bounded queue of messages delivered by a background task.
+#[derive(Clone)]
+pub struct MailQueue {
+    sender: mpsc::Sender<Email>,
+}
+
+impl MailQueue {
+    /// Starts the task delivering through `mailer`, queueing at most
+    /// `capacity` messages.
+    pub fn spawn(mailer: Arc<dyn Mailer>, retry: RetryPolicy, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Email>(capacity.max(1));
+        actix_web::rt::spawn(async move {
+            while let Some(email) = receiver.recv().await {
+                let _ = deliver(mailer.as_ref(), &retry, &email).await;
+            }
+        });
+        MailQueue { sender }
+    }
+
+    /// Queues `email` for delivery.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the message was queued; it is dropped when the queue is full.
+    pub fn enqueue(&self, email: Email) -> bool {
+        match self.sender.try_send(email) {
+            Ok(()) => true,
+            Err(TrySendError::Full(email)) => {
+                warn!("Mail queue is full, dropping message to {}", email.to);
+                Metrics::global().inc("mail_dropped_total", &[("reason", "queue_full")]);
+                false
+            }
+            Err(TrySendError::Closed(email)) => {
+                warn!("Mail queue is stopped, dropping message to {}", email.to);
+                Metrics::global().inc("mail_dropped_total", &[("reason", "queue_stopped")]);
+                false
+            }
+        }
+    }
+
+    /// Builds the mailer selected by `MAIL_TRANSPORT` and starts its queue.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<MailQueue>, IoError>` - The queue, or `None` if no transport is configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the transport is unknown, its settings are
+    /// invalid, or `smtp` is selected in a build without the `email` feature.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let transport = env::var("MAIL_TRANSPORT").ok().or_else(|| {
+            env::var("SMTP_HOST")
+                .is_ok_and(|h| !h.is_empty())
+                .then(|| "smtp".to_string())
+        });
+        let mailer: Arc<dyn Mailer> = match transport.as_deref() {
+            None | Some("") => return Ok(None),
+            Some("log") => {
+                warn!("MAIL_TRANSPORT=log: emails are logged, not sent");
+                Arc::new(LogMailer)
+            }
+            #[cfg(feature = "email")]
+            Some("smtp") => Arc::new(smtp::SmtpMailer::from_env()?),
+            #[cfg(not(feature = "email"))]
+            Some("smtp") => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    "MAIL_TRANSPORT=smtp needs a build with the `email` feature",
+                ))
+            }
+            Some(other) => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown MAIL_TRANSPORT '{}'", other),
+                ))
+            }
+        };
+
+        let read = |var: &str| env::var(var).ok().and_then(|v| v.parse::<u64>().ok());
+        let defaults = RetryPolicy::default();
+        let retry = RetryPolicy {
+            max_attempts: read("MAIL_MAX_ATTEMPTS")
+                .map(|n| n.max(1) as u32)
+                .unwrap_or(defaults.max_attempts),
+            initial_backoff: read("MAIL_RETRY_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.initial_backoff),
+            max_backoff: defaults.max_backoff,
+        };
+        let capacity = read("MAIL_QUEUE_CAPACITY")
+            .map(|c| c as usize)
+            .unwrap_or(DEFAULT_QUEUE_CAPACITY);
+        Ok(Some(MailQueue::spawn(mailer, retry, capacity)))
+    }
+}
+
+/// Sends `email`, retrying transient failures as `retry` allows.
+///
+/// # Returns
+///
+/// * `Result<(), MailError>` - The outcome of the last attempt.
+pub async fn deliver(
+    mailer: &dyn Mailer,
+    retry: &RetryPolicy,
+    email: &Email,
+) -> Result<(), MailError> {
+    let mut attempt = 1;
+    loop {
+        match mailer.send(email).await {
+            Ok(()) => {
+                Metrics::global().inc("mail_sent_total", &[]);
+                return Ok(());
+            }
+            Err(MailError::Transient(reason)) if attempt < retry.max_attempts => {
+                let delay = retry.backoff(attempt);
+                warn!(
+                    "Email to {} failed ({}), retrying in {}ms",
+                    email.to,
+                    reason,
+                    delay.as_millis()
+                );
+                Metrics::global().inc("mail_retries_total", &[]);
+                actix_web::rt::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                warn!("Giving up on email to {}: {}", email.to, e);
+                Metrics::global().inc("mail_failed_total", &[]);
+                return Err(e);
+            }
+        }
+    }
+}
+
+static QUEUE: OnceLock<MailQueue> = OnceLock::new();
+
+/// Makes `queue` available to [`queue`] callers. Only the first call has an
+/// effect.
+pub fn install(queue: MailQueue) {
+    let _ = QUEUE.set(queue);
+}
+
+/// Returns the installed queue, if email is configured.
+pub fn queue() -> Option<&'static MailQueue> {
+    QUEUE.get()
+}
+
+/// Body of `POST /admin/test-email`.
+#[derive(Deserialize)]
+pub struct TestEmailRequest {
+    pub to: String,
+}
+
+/// Handler for `POST /admin/test-email`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 202 Accepted once the message is queued, 400 for an invalid address, or 503 when email is not configured or the queue is full.
+pub async fn test_email(
+    body: web::Json<TestEmailRequest>,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    let to = body.into_inner().to;
+    let result = enqueue_test_message(&to);
+    trail.record_result("test_email_queued", json!({ "to": to }), &result);
+    result?;
+    Ok(HttpResponse::Accepted().json(json!({ "status": "queued", "to": to })))
+}
+
+fn enqueue_test_message(to: &str) -> Result<(), ApiError> {
+    let queue = queue().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "mail_unavailable",
+            "Email is not configured",
+        )
+    })?;
+    if !templates::is_address(to) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_address",
+            "to must be an email address",
+        )
+        .with_field("to", "not an email address"));
+    }
+    if !queue.enqueue(templates::test_message(to)) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "mail_queue_full",
+            "The mail queue is full, please retry later",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! SMTP mailer, only compiled with the `email` feature.
+//!
+//! Connections are pooled and secured according to `SMTP_TLS`: `starttls`
+//! upgrades a plain connection (port 587 by default), `implicit` speaks TLS
+//! from the start (port 465) and `none` sends in the clear (port 25), which
+//! is only meant for local relays and tests.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{info, warn};
+
+use super::{Email, MailError, Mailer};
+
+/// Default for `SMTP_POOL_SIZE`.
+pub const DEFAULT_POOL_SIZE: u32 = 4;
+/// Default for `SMTP_TIMEOUT_SECS`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the connection to the SMTP server is secured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpTls {
+    StartTls,
+    Implicit,
+    None,
+}
+
+impl SmtpTls {
+    /// The port used when `SMTP_PORT` is not set.
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SmtpTls::StartTls => 587,
+            SmtpTls::Implicit => 465,
+            SmtpTls::None => 25,
+        }
+    }
+}
+
+/// Settings of an [`SmtpMailer`].
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    /// Username and password, when the server requires authentication.
+    pub credentials: Option<(String, String)>,
+    pub from: String,
+    pub pool_size: u32,
+    pub timeout: Duration,
+}
+
+impl SmtpConfig {
+    /// Creates settings for `host` with STARTTLS, no credentials and the
+    /// default port, pool size and timeout.
+    pub fn new(host: &str, from: &str) -> Self {
+        SmtpConfig {
+            host: host.to_string(),
+            port: SmtpTls::StartTls.default_port(),
+            tls: SmtpTls::StartTls,
+            credentials: None,
+            from: from.to_string(),
+            pool_size: DEFAULT_POOL_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Reads `SMTP_HOST`, `SMTP_PORT`, `SMTP_TLS`, `SMTP_USERNAME`,
+    /// `SMTP_PASSWORD`, `SMTP_POOL_SIZE`, `SMTP_TIMEOUT_SECS` and
+    /// `MAIL_FROM`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `SMTP_HOST` or `MAIL_FROM` is missing, or
+    /// `SMTP_TLS` is not `starttls`, `implicit` or `none`.
+    pub fn from_env() -> Result<Self, IoError> {
+        let invalid = |message: String| IoError::new(ErrorKind::InvalidInput, message);
+        let host = env::var("SMTP_HOST")
+            .ok()
+            .filter(|h| !h.is_empty())
+            .ok_or_else(|| invalid("SMTP_HOST is not set".to_string()))?;
+        let from = env::var("MAIL_FROM")
+            .ok()
+            .filter(|f| !f.is_empty())
+            .ok_or_else(|| invalid("MAIL_FROM is not set".to_string()))?;
+        let tls = match env::var("SMTP_TLS").as_deref() {
+            Err(_) | Ok("starttls") => SmtpTls::StartTls,
+            Ok("implicit") => SmtpTls::Implicit,
+            Ok("none") => SmtpTls::None,
+            Ok(other) => return Err(invalid(format!("Unknown SMTP_TLS '{}'", other))),
+        };
+        let credentials = env::var("SMTP_USERNAME")
+            .ok()
+            .filter(|u| !u.is_empty())
+            .map(|user| (user, env::var("SMTP_PASSWORD").unwrap_or_default()));
+
+        let mut config = SmtpConfig::new(&host, &from);
+        config.tls = tls;
+        config.port = env::var("SMTP_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(tls.default_port());
+        config.credentials = credentials;
+        if let Some(size) = env::var("SMTP_POOL_SIZE").ok().and_then(|v| v.parse().ok()) {
+            config.pool_size = size;
+        }
+        if let Some(secs) = env::var("SMTP_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            config.timeout = Duration::from_secs(secs);
+        }
+        Ok(config)
+    }
+}
+
+/// Sends messages through an SMTP server.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Creates a mailer for `config`. Connections are opened on first use.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the sender address is invalid or the TLS settings
+    /// cannot be built for the host.
+    pub fn new(config: &SmtpConfig) -> Result<Self, IoError> {
+        let from: Mailbox = config.from.parse().map_err(|e| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid MAIL_FROM '{}': {}", config.from, e),
+            )
+        })?;
+        let builder = match config.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpTls::None => {
+                warn!(
+                    "SMTP_TLS=none: emails are sent to {} unencrypted",
+                    config.host
+                );
+                Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                    &config.host,
+                ))
+            }
+        }
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+
+        let mut builder = builder
+            .port(config.port)
+            .timeout(Some(config.timeout))
+            .pool_config(PoolConfig::new().max_size(config.pool_size.max(1)));
+        if let Some((user, password)) = &config.credentials {
+            builder = builder.credentials(Credentials::new(user.clone(), password.clone()));
+        }
+        info!(
+            "Sending email through {}:{} ({:?}) as {}",
+            config.host, config.port, config.tls, from
+        );
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+
+    /// Creates a mailer from [`SmtpConfig::from_env`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings are missing or invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        SmtpMailer::new(&SmtpConfig::from_env()?)
+    }
+
+    fn message(&self, email: &Email) -> Result<Message, MailError> {
+        let to: Mailbox = email
+            .to
+            .parse()
+            .map_err(|e| MailError::Permanent(format!("invalid recipient: {}", e)))?;
+        Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(email.subject.clone())
+            .multipart(MultiPart::alternative_plain_html(
+                email.text.clone(),
+                email.html.clone(),
+            ))
+            .map_err(|e| MailError::Permanent(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = self.message(email)?;
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_permanent() => Err(MailError::Permanent(e.to_string())),
+            // 4xx replies, timeouts and connection failures may succeed later
+            Err(e) => Err(MailError::Transient(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Email templates.
+//!
+//! Each message has a subject, a plain text body and an HTML body. Templates
+//! use `{{name}}` placeholders filled by [`render`]; values are escaped in
+//! HTML bodies.
+
+use chrono::{TimeZone, Utc};
+
+use super::alerts::ServerError;
+use super::Email;
+
+const RESET_SUBJECT: &str = "Reset your password";
+const RESET_TEXT: &str = "Hello {{username}},
+
+Someone asked to reset the password of your account. If it was you, use this
+token to choose a new password before {{expires}}:
+
+{{token}}
+
+If you did not ask for a reset, you can ignore this message.
+";
+const RESET_HTML: &str = "<p>Hello {{username}},</p>
+<p>Someone asked to reset the password of your account. If it was you, use this token to choose a new password before {{expires}}:</p>
+<p><code>{{token}}</code></p>
+<p>If you did not ask for a reset, you can ignore this message.</p>
+";
+
+const DIGEST_SUBJECT: &str = "{{count}} server errors";
+const DIGEST_TEXT: &str =
+    "{{count}} requests failed with a server error since the last digest{{dropped}}:
+
+{{rows}}";
+const DIGEST_HTML: &str =
+    "<p>{{count}} requests failed with a server error since the last digest{{dropped}}:</p>
+<table>
+<tr><th>Time</th><th>Status</th><th>Request</th><th>Request ID</th></tr>
+{{rows}}</table>
+";
+
+const TEST_SUBJECT: &str = "Test email";
+const TEST_TEXT: &str = "This is a test email sent from the admin API.\n";
+const TEST_HTML: &str = "<p>This is a test email sent from the admin API.</p>\n";
+
+/// Replaces the `{{name}}` placeholders of `template` with their values,
+/// HTML-escaped when `html` is set. Unknown placeholders are left as is.
+pub fn render(template: &str, vars: &[(&str, &str)], html: bool) -> String {
+    let mut out = template.to_string();
+    for (name, value) in vars {
+        let value = if html {
+            escape_html(value)
+        } else {
+            value.to_string()
+        };
+        out = out.replace(&format!("{{{{{}}}}}", name), &value);
+    }
+    out
+}
+
+/// Escapes `&`, `<`, `>`, `"` and `'` for use in HTML.
+pub fn escape_html(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Returns whether `address` looks like a deliverable address: one `@`
+/// between a non-empty local part and a dotted domain, without spaces or
+/// angle brackets.
+pub fn is_address(address: &str) -> bool {
+    let Some((local, domain)) = address.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && !address
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+}
+
+fn message(to: &str, subject: String, text: String, html: String) -> Email {
+    Email {
+        to: to.to_string(),
+        subject,
+        text,
+        html,
+    }
+}
+
+/// The message carrying a password reset token.
+pub fn password_reset(to: &str, username: &str, token: &str, expires_at: i64) -> Email {
+    let expires = Utc
+        .timestamp_opt(expires_at, 0)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| expires_at.to_string());
+    let vars = [
+        ("username", username),
+        ("token", token),
+        ("expires", expires.as_str()),
+    ];
+    message(
+        to,
+        RESET_SUBJECT.to_string(),
+        render(RESET_TEXT, &vars, false),
+        render(RESET_HTML, &vars, true),
+    )
+}
+
+/// The digest of server errors collected since the previous one; `dropped`
+/// errors did not fit in the digest.
+pub fn error_digest(to: &str, errors: &[ServerError], dropped: usize) -> Email {
+    let count = (errors.len() + dropped).to_string();
+    let dropped = if dropped > 0 {
+        format!(" ({} not listed)", dropped)
+    } else {
+        String::new()
+    };
+
+    let mut text_rows = String::new();
+    let mut html_rows = String::new();
+    for e in errors {
+        let time = e.at.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+        text_rows.push_str(&format!(
+            "{} {} {} {} request_id={}\n",
+            time, e.status, e.method, e.path, e.request_id
+        ));
+        html_rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{} {}</td><td>{}</td></tr>\n",
+            time,
+            e.status,
+            escape_html(&e.method),
+            escape_html(&e.path),
+            escape_html(&e.request_id)
+        ));
+    }
+
+    let vars = [("count", count.as_str()), ("dropped", dropped.as_str())];
+    let text = render(DIGEST_TEXT, &vars, false).replace("{{rows}}", &text_rows);
+    let html = render(DIGEST_HTML, &vars, true).replace("{{rows}}", &html_rows);
+    message(to, render(DIGEST_SUBJECT, &vars, false), text, html)
+}
+
+/// The message sent by `POST /admin/test-email`.
+pub fn test_message(to: &str) -> Email {
+    message(
+        to,
+        TEST_SUBJECT.to_string(),
+        TEST_TEXT.to_string(),
+        TEST_HTML.to_string(),
+    )
+}
@@ -4,15 +4,90 @@
 //! custom 404 handling. It uses environment variables for configuration and
 //! supports multi-threading.
 
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+pub mod admin;
+pub mod audit;
+pub mod auth;
+pub mod cache;
+pub mod clock;
+pub mod config_reload;
+pub mod connections;
+#[cfg(feature = "consul")]
+pub mod consul;
+#[cfg(feature = "db")]
+pub mod db;
+pub mod discovery;
+pub mod dynamic_scope;
+pub mod error;
+pub mod favicon;
+pub mod fips;
+pub mod flags;
+pub mod health;
+pub mod i18n;
+pub mod info;
+pub mod lifecycle;
+pub mod log_buffer;
+pub mod mail;
+pub mod master_key;
+pub mod memory;
+pub mod metrics;
+pub mod middleware;
+pub mod outbound;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod pwa;
+pub mod resource_budget;
+pub mod restart;
+pub mod revocation;
+pub mod route_meta;
+pub mod route_overlap;
+pub mod routes;
+pub mod scan;
+pub mod startup;
+pub mod static_files;
+pub mod systemd;
+pub mod tcp_keepalive;
+pub mod throttle;
+pub mod tls_chain;
+pub mod tls_error;
+pub mod tls_info;
+pub mod tls_keylog;
+pub mod util;
+pub mod workers;
+pub mod ws;
+
+use actix_web::middleware::Condition;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError};
 use dotenv::dotenv;
-use log::{error, info};
-use num_cpus;
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use i18n::Localizer;
+use log::{error, info, warn};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use std::env;
 use std::fs::File;
 use std::io::{BufReader, Error as IoError};
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use tls_error::TlsConfigError;
+
+// Heap profiling needs jemalloc with sampling switched on at startup; either
+// way allocations are counted per thread once a request budget is set
+#[cfg(feature = "heap_profiling")]
+#[global_allocator]
+static ALLOC: resource_budget::CountingAllocator<tikv_jemallocator::Jemalloc> =
+    resource_budget::CountingAllocator::new(tikv_jemallocator::Jemalloc);
+
+#[cfg(not(feature = "heap_profiling"))]
+#[global_allocator]
+static ALLOC: resource_budget::CountingAllocator<std::alloc::System> =
+    resource_budget::CountingAllocator::new(std::alloc::System);
+
+#[cfg(feature = "heap_profiling")]
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
 
 /// Loads TLS configuration from certificate and key files.
 ///
@@ -22,47 +97,60 @@ use std::io::{BufReader, Error as IoError};
 ///
 /// # Returns
 ///
-/// * `Result<ServerConfig, IoError>` - The TLS configuration on success, or an IoError if loading fails.
+/// * `Result<ServerConfig, TlsConfigError>` - The TLS configuration on success, or why loading failed; it converts into an IoError.
 ///
 /// # Errors
 ///
-/// This function will return an error if:
-/// * The certificate or key files cannot be read
-/// * The certificate or key data is invalid
-/// * The ServerConfig cannot be constructed with the provided certificate and key
-pub fn load_tls_config() -> Result<ServerConfig, IoError> {
+/// This function will return:
+/// * [`TlsConfigError::FileNotFound`] if the certificate or key file cannot be opened
+/// * [`TlsConfigError::CertParse`] or [`TlsConfigError::KeyParse`] if their
+///   data is invalid, or the certificate file holds no certificate
+/// * [`TlsConfigError::NoKeys`] if the key file holds no private key
+/// * [`TlsConfigError::KeyCertMismatch`] if the private key does not match
+///   the certificate, or the first certificate is a CA certificate instead
+///   of the leaf for the private key
+/// * [`TlsConfigError::ClientAuth`] if the client CA file named by
+///   `CLIENT_CA_FILE` cannot be loaded, or `MTLS_REQUIRED_PATHS` is set
+///   without `CLIENT_CA_FILE`
+/// * [`TlsConfigError::FipsNotSupported`] if `TLS_FIPS_MODE` is set in a
+///   build without the `fips` feature
+/// * [`TlsConfigError::BuildFailed`] if the ServerConfig cannot be constructed with the provided certificate and key
+pub fn load_tls_config() -> Result<ServerConfig, TlsConfigError> {
     let cert_path = env::var("CERT_FILE").unwrap_or_else(|_| "cert.pem".to_string());
     let key_path = env::var("KEY_FILE").unwrap_or_else(|_| "key.pem".to_string());
 
     info!("Loading TLS certificate from: {}", cert_path);
     info!("Loading TLS private key from: {}", key_path);
 
-    let cert_file = match File::open(&cert_path) {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Failed to open certificate file '{}': {}", cert_path, e);
-            return Err(e);
+    let cert_file = File::open(&cert_path).map_err(|source| {
+        error!(
+            "Failed to open certificate file '{}': {}",
+            cert_path, source
+        );
+        TlsConfigError::FileNotFound {
+            path: cert_path.clone(),
+            source,
         }
-    };
-    let key_file = match File::open(&key_path) {
-        Ok(file) => file,
-        Err(e) => {
-            error!("Failed to open private key file '{}': {}", key_path, e);
-            return Err(e);
+    })?;
+    let key_file = File::open(&key_path).map_err(|source| {
+        error!("Failed to open private key file '{}': {}", key_path, source);
+        TlsConfigError::FileNotFound {
+            path: key_path.clone(),
+            source,
         }
-    };
+    })?;
 
     let mut cert_reader = BufReader::new(cert_file);
     let mut key_reader = BufReader::new(key_file);
 
-    let cert_chain = match certs(&mut cert_reader) {
+    let cert_chain: Vec<Certificate> = match certs(&mut cert_reader) {
         Ok(certs) => certs.into_iter().map(Certificate).collect(),
         Err(e) => {
             error!("Failed to parse certificate: {}", e);
-            return Err(IoError::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid certificate",
-            ));
+            return Err(TlsConfigError::CertParse {
+                path: cert_path,
+                reason: e.to_string(),
+            });
         }
     };
 
@@ -70,43 +158,239 @@ pub fn load_tls_config() -> Result<ServerConfig, IoError> {
         Ok(keys) => keys.into_iter().map(PrivateKey).collect(),
         Err(e) => {
             error!("Failed to parse private key: {}", e);
-            return Err(IoError::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid private key",
-            ));
+            return Err(TlsConfigError::KeyParse {
+                path: key_path,
+                reason: e.to_string(),
+            });
         }
     };
 
     if keys.is_empty() {
         error!("No private keys found in the key file");
-        return Err(IoError::new(
-            std::io::ErrorKind::InvalidData,
-            "No private keys found",
-        ));
+        return Err(TlsConfigError::NoKeys { path: key_path });
     }
 
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
+    let Some(leaf) = cert_chain.first() else {
+        error!("No certificates found in '{}'", cert_path);
+        return Err(TlsConfigError::CertParse {
+            path: cert_path,
+            reason: "no certificates found".to_string(),
+        });
+    };
+
+    // rustls reports a mismatched pair only as a generic error, so check it here
+    if key_matches_certificate(leaf, &keys[0]) == Some(false) {
+        // A bundle missing its leaf, or with the leaf out of place, starts
+        // with a CA certificate
+        if is_ca_certificate(leaf) == Some(true) {
+            let position = cert_chain
+                .iter()
+                .position(|cert| key_matches_certificate(cert, &keys[0]) == Some(true));
+            let reason = match position {
+                Some(position) => format!(
+                    "leaf certificate is at position {} of the chain; it must come first",
+                    position + 1
+                ),
+                None => "certificate chain has no leaf: it starts with a CA certificate and no certificate matches the private key".to_string(),
+            };
+            error!("Invalid certificate chain in '{}': {}", cert_path, reason);
+            return Err(TlsConfigError::KeyCertMismatch { reason });
+        }
+        error!(
+            "Private key '{}' does not match certificate '{}'",
+            key_path, cert_path
+        );
+        return Err(TlsConfigError::KeyCertMismatch {
+            reason: "private key does not match certificate".to_string(),
+        });
+    }
+    if is_ca_certificate(leaf) == Some(true) {
+        warn!(
+            "The certificate in '{}' is marked as a CA; clients that verify certificates will reject it",
+            cert_path
+        );
+    }
+
+    // Require client certificates signed by CLIENT_CA_FILE when configured (mTLS),
+    // only making them optional when MTLS_REQUIRED_PATHS scopes the requirement
+    let optional_client_auth = env::var("MTLS_REQUIRED_PATHS").is_ok_and(|p| !p.trim().is_empty());
+    let builder = fips::RustlsFipsMode::from_env()?.server_builder()?;
+    let builder = match env::var("CLIENT_CA_FILE") {
+        Ok(ca_path) => builder.with_client_cert_verifier(
+            load_client_cert_verifier(&ca_path, optional_client_auth)
+                .map_err(TlsConfigError::ClientAuth)?,
+        ),
+        Err(_) if optional_client_auth => {
+            error!("MTLS_REQUIRED_PATHS is set but CLIENT_CA_FILE is not");
+            return Err(TlsConfigError::ClientAuth(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                "MTLS_REQUIRED_PATHS requires CLIENT_CA_FILE",
+            )));
+        }
+        Err(_) => builder.with_no_client_auth(),
+    };
+
+    let config = builder
         .with_single_cert(cert_chain, keys.remove(0))
         .map_err(|e| {
             error!("Failed to create ServerConfig: {}", e);
-            IoError::new(std::io::ErrorKind::InvalidData, e)
+            TlsConfigError::BuildFailed {
+                reason: e.to_string(),
+            }
         })?;
 
     info!("TLS configuration loaded successfully");
     Ok(config)
 }
 
+/// Checks whether `cert` is a CA certificate rather than an end-entity one.
+///
+/// # Returns
+///
+/// * `Option<bool>` - Whether its basic constraints mark it as a CA, or `None` if the certificate cannot be parsed.
+pub fn is_ca_certificate(cert: &Certificate) -> Option<bool> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(parsed.is_ca())
+}
+
+/// Checks whether `key` is the private key for `cert`'s public key.
+///
+/// # Returns
+///
+/// * `Option<bool>` - Whether the public keys are equal, or `None` if the certificate cannot be parsed or the key type is not supported (RSA, ECDSA P-256/P-384 and Ed25519 are).
+pub fn key_matches_certificate(cert: &Certificate, key: &PrivateKey) -> Option<bool> {
+    use ring::rand::SystemRandom;
+    use ring::signature::{
+        EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING,
+        ECDSA_P384_SHA384_ASN1_SIGNING,
+    };
+
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let cert_key = &parsed.public_key().subject_public_key.data;
+
+    let rng = SystemRandom::new();
+    let key_public: Vec<u8> = if let Ok(pair) = RsaKeyPair::from_pkcs8(&key.0) {
+        pair.public_key().as_ref().to_vec()
+    } else if let Ok(pair) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &key.0, &rng)
+    {
+        pair.public_key().as_ref().to_vec()
+    } else if let Ok(pair) = EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_ASN1_SIGNING, &key.0, &rng)
+    {
+        pair.public_key().as_ref().to_vec()
+    } else if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&key.0) {
+        pair.public_key().as_ref().to_vec()
+    } else {
+        return None;
+    };
+
+    Some(key_public == cert_key.as_ref())
+}
+
+/// Loads the client certificate verifier used for mutual TLS.
+///
+/// Every certificate in the PEM file at `ca_path` is trusted as a root for
+/// client certificates, and clients must present a certificate chaining to
+/// one of them. With `optional`, clients may also connect without a
+/// certificate, but one that is presented must still chain to a root.
+///
+/// # Returns
+///
+/// * `Result<Arc<dyn ClientCertVerifier>, IoError>` - The verifier on success, or an IoError if loading fails.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// * The CA file cannot be read
+/// * The CA file contains no valid certificates
+pub fn load_client_cert_verifier(
+    ca_path: &str,
+    optional: bool,
+) -> Result<Arc<dyn ClientCertVerifier>, IoError> {
+    info!("Loading client CA certificates from: {}", ca_path);
+
+    let ca_file = match File::open(ca_path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to open client CA file '{}': {}", ca_path, e);
+            return Err(e);
+        }
+    };
+
+    let ca_certs = match certs(&mut BufReader::new(ca_file)) {
+        Ok(certs) => certs,
+        Err(e) => {
+            error!("Failed to parse client CA certificates: {}", e);
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid client CA certificate",
+            ));
+        }
+    };
+
+    let mut roots = RootCertStore::empty();
+    for ca_cert in ca_certs {
+        if let Err(e) = roots.add(&Certificate(ca_cert)) {
+            error!("Failed to add client CA certificate: {}", e);
+            return Err(IoError::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid client CA certificate",
+            ));
+        }
+    }
+
+    if roots.is_empty() {
+        error!("No certificates found in the client CA file");
+        return Err(IoError::new(
+            std::io::ErrorKind::InvalidData,
+            "No client CA certificates found",
+        ));
+    }
+
+    if optional {
+        info!("Optional client certificate authentication enabled");
+        return Ok(AllowAnyAnonymousOrAuthenticatedClient::new(roots));
+    }
+    info!("Client certificate authentication enabled");
+    Ok(AllowAnyAuthenticatedClient::new(roots))
+}
+
+/// Escapes text for an HTML element or attribute.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Media types `/hello` can produce, in order of preference.
+pub const HELLO_TYPES: &[&str] = &["text/plain", "application/json", "text/html"];
+
 /// Handler for the `/hello` route.
 ///
-/// Returns a simple "Hello world!" message.
+/// Returns a simple "Hello world!" message in the negotiated locale, as
+/// plain text, JSON (`{"message": "..."}`) or HTML depending on `Accept`.
 ///
 /// # Returns
 ///
-/// * `impl Responder` - An HTTP response with a 200 OK status and "Hello world!" body.
-pub async fn hello() -> impl Responder {
-    HttpResponse::Ok().body("Hello world!")
+/// * `HttpResponse` - 200 OK with the localized `hello` message, or 406 `not_acceptable` listing [`HELLO_TYPES`]; always with `Vary: Accept`.
+pub async fn hello(req: HttpRequest, localizer: Localizer) -> HttpResponse {
+    let message = localizer.text("hello");
+    let res = match util::negotiate::negotiate_request(&req, HELLO_TYPES) {
+        Ok("application/json") => HttpResponse::Ok().json(serde_json::json!({ "message": message })),
+        Ok("text/html") => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(format!(
+                "<!DOCTYPE html>\n<html lang=\"{}\"><head><meta charset=\"utf-8\"><title>{}</title></head><body><p>{}</p></body></html>\n",
+                localizer.locale(),
+                escape_html(&message),
+                escape_html(&message)
+            )),
+        Ok(_) => HttpResponse::Ok()
+            .content_type(util::echo::TEXT_PLAIN)
+            .body(message),
+        Err(e) => e.error_response(),
+    };
+    util::negotiate::vary_accept(res)
 }
 
 /// Handler for routes that don't match any defined routes.
@@ -115,18 +399,42 @@ pub async fn hello() -> impl Responder {
 ///
 /// # Returns
 ///
-/// * `impl Responder` - An HTTP response with a 404 Not Found status and "Not Found" body.
-pub async fn not_found() -> impl Responder {
-    HttpResponse::NotFound().body("Not Found")
+/// * `impl Responder` - An HTTP response with a 404 Not Found status and the localized `not-found` message.
+pub async fn not_found(localizer: Localizer) -> impl Responder {
+    HttpResponse::NotFound().body(localizer.text("not-found"))
+}
+
+/// Makes `report` wait for the connections still open before the server
+/// stops, including those relayed from PROXY protocol listeners.
+pub fn drain_connections(
+    report: lifecycle::DrainReport,
+    proxy_protocol: Option<&proxy_protocol::ProxyProtocolAcceptor>,
+) -> lifecycle::DrainReport {
+    let report = report
+        .with_connections(connections::open_http1)
+        .with_settle_time(lifecycle::TLS_HANDSHAKE_TIMEOUT);
+    match proxy_protocol {
+        // The relays stop accepting when the drain starts; the backend
+        // keeps accepting the connections they already took
+        Some(acceptor) => {
+            let acceptor = acceptor.clone();
+            report
+                .with_connections(move || acceptor.open_relays())
+                .without_pause()
+        }
+        None => report,
+    }
 }
 
 /// The main function that sets up and runs the web server.
 ///
-/// This function performs the following steps:
+/// With `--print-schema`, it only prints [`config_reload::schema`] and
+/// returns. Otherwise it performs the following steps:
 /// 1. Loads environment variables
 /// 2. Initializes the logger
-/// 3. Loads TLS configuration
-/// 4. Configures server address and number of workers
+/// 3. Loads and validates all configuration, startup files and TLS first,
+///    stopping at the first invalid step (see [`startup`])
+/// 4. Starts background tasks and takes the listeners inherited on restart or from systemd
 /// 5. Sets up and runs the HTTP server with TLS support
 ///
 /// # Returns
@@ -134,41 +442,620 @@ pub async fn not_found() -> impl Responder {
 /// * `std::io::Result<()>` - Ok(()) if the server runs successfully, or an error if it fails to start.
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // Print the configuration schema and exit, without reading any of it
+    if std::env::args().skip(1).any(|arg| arg == "--print-schema") {
+        let schema = serde_json::to_string_pretty(&config_reload::schema())?;
+        println!("{}", schema);
+        return Ok(());
+    }
+    // Uptime reported by /admin/info
+    info::mark_started();
     // Load environment variables from .env file if present
     dotenv().ok();
-    // Initialize the logger
-    env_logger::init();
+    // Initialize the logger, keeping the last lines for /admin/logs/tail
+    let log_buffer = web::Data::from(log_buffer::init());
 
     info!("Starting server initialization");
 
-    // Load TLS configuration
-    let tls_config = match load_tls_config() {
-        Ok(config) => config,
-        Err(e) => {
-            error!("Failed to load TLS configuration: {}", e);
-            return Err(e);
-        }
-    };
+    // Load and validate every piece of configuration before anything starts:
+    // a failing step stops here, before any task is spawned or socket bound
+    let mut checks = startup::StartupChecks::new();
+
+    // Give files written by a secrets sidecar time to appear
+    checks.check("startup files", startup::wait_for_files_from_env())?;
+
+    let mut tls_config = checks.check("tls", load_tls_config())?;
+    // The same chain, for inspection through /tls
+    #[cfg(feature = "full")]
+    let tls_chain = web::Data::new(checks.check("tls chain", tls_chain::TlsChain::from_env())?);
+    // Session secrets for Wireshark, never under APP_ENV=prod
+    if let Some(key_log) = checks.check("TLS key log", tls_keylog::from_env())? {
+        tls_config.key_log = key_log;
+    }
+
+    // Load translations; the default locale must be present
+    let locales_dir = env::var("LOCALES_DIR").unwrap_or_else(|_| "locales".to_string());
+    let default_locale = env::var("DEFAULT_LOCALE").unwrap_or_else(|_| "en-US".to_string());
+    let i18n =
+        web::Data::new(checks.check("locales", i18n::I18n::load(&locales_dir, &default_locale))?);
+
+    // Feature flags, overridable at runtime through /admin/flags
+    let flags = web::Data::new(checks.check("feature flags", flags::Flags::from_env())?);
+    // Route groups that admins can switch off through /admin/features
+    let features = dynamic_scope::Features::from_env();
+    let features_data = web::Data::new(features.clone());
+    let flags_header = env::var("FEATURE_FLAGS_HEADER")
+        .map(|v| v == "true")
+        .unwrap_or(false);
 
     // Get server address from environment variable or use default
     let address = env::var("SERVER_ADDRESS").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
-    // Get number of workers from environment variable or use number of CPU cores
-    let num_workers = env::var("NUM_WORKERS")
+    checks.check("server address", address.to_socket_addrs().map(|_| ()))?;
+    // Get number of workers from environment variable or use the CPUs the cgroup allows
+    let num_workers = workers::WorkerCount::from_env().get();
+
+    // Wrap JSON responses in the standard envelope when enabled
+    let envelope_enabled = env::var("ENABLE_RESPONSE_ENVELOPE")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+
+    // Extra response headers from EXTRA_RESPONSE_HEADERS
+    let extra_headers = checks.check(
+        "extra response headers",
+        middleware::extra_headers::parse_extra_headers(
+            &env::var("EXTRA_RESPONSE_HEADERS").unwrap_or_default(),
+        ),
+    )?;
+
+    // Bodies and headers of 429 and 503 load-shedding responses
+    let throttle_responses = web::Data::new(checks.check(
+        "throttle responses",
+        throttle::ThrottleResponses::from_env(),
+    )?);
+
+    // Part count and size limits of multipart/form-data bodies
+    let multipart_limits = util::multipart::MultipartLimits::from_env();
+    // Malware scanning of uploaded files before they are served back
+    let upload_scanning =
+        web::Data::new(checks.check("upload scanner", scan::UploadScanning::from_env())?);
+
+    // Per-connection message rate of WebSocket clients
+    let ws_limits = web::Data::new(ws::MessageRateLimiter::from_env());
+
+    // HSTS max-age, optionally bounded by the certificate's remaining lifetime
+    let security_headers = checks.check(
+        "strict transport security",
+        middleware::security_headers::from_env(),
+    )?;
+
+    // `{key}` placeholders in response bodies, translated from TRANSLATIONS_DIR
+    let translations = checks
+        .check(
+            "response translations",
+            middleware::i18n::Translations::from_env(),
+        )?
+        .map(web::Data::new);
+    let rewriter = translations
+        .as_ref()
+        .map(|t| middleware::i18n::I18nRewriter::new(t.clone().into_inner()))
+        .unwrap_or_default();
+
+    // Two-tier cache of GET responses under RESPONSE_CACHE_PATHS
+    let response_cache = checks
+        .check("response cache", cache::MultiLayerCache::from_env())?
+        .map(web::Data::new);
+    let response_cache_enabled = response_cache.is_some();
+    let response_cache_middleware = response_cache
+        .as_ref()
+        .map(|cache| cache.get_ref().clone())
+        .unwrap_or_default();
+
+    // Cache-Control of each path from CACHE_POLICY_RULES
+    let cache_policy = checks.check(
+        "cache policy",
+        middleware::cache_policy::CachePolicy::from_env(),
+    )?;
+
+    // Keep-alive per HTTP version, mapped onto Actix's single timer
+    let keep_alive = middleware::keep_alive::KeepAliveSettings::from_env();
+    let effective = keep_alive.effective();
+    info!("HTTP/1.x keep-alive: {}", effective.h1);
+    info!("HTTP/2 keep-alive: {}", effective.h2);
+    if let Some(warning) = &effective.warning {
+        warn!("{}", warning);
+    }
+
+    // Header count and size limits, checked before any other middleware
+    let header_limits = middleware::header_limits::HeaderLimits::from_env();
+    info!(
+        "Header limits: {} headers, {} bytes per header, {} bytes in total, {} bytes per request line",
+        header_limits.max_count,
+        header_limits.max_size,
+        header_limits.max_bytes,
+        header_limits.max_request_line
+    );
+
+    // Access log sampling for fast, successful requests
+    let sampling = middleware::access_log::SamplingConfig::from_env();
+    info!(
+        "Access log sample rate {} (errors and requests over {}ms always logged)",
+        sampling.sample_rate,
+        sampling.slow_threshold.as_millis()
+    );
+
+    let access_log_excluded = middleware::access_log::excluded_paths_from_env();
+
+    // Behind a TLS-terminating proxy, plain HTTP requests are sent to https://
+    let proto_enforcer = middleware::proto_enforcement::ProtoEnforcer::from_env();
+
+    // Requests over SLOW_REQUEST_THRESHOLD_MS are logged with their context
+    let slow_requests = middleware::slow_request::SlowRequestDetector::from_env();
+
+    // Buffered access log file, flushed again on shutdown
+    let access_log_file =
+        checks.check("access log file", middleware::access_log::file_from_env())?;
+
+    // Proxies allowed to report the client address in forwarding headers
+    let trusted_proxies = checks.check(
+        "trusted proxies",
+        util::real_ip::TrustedProxies::parse(&env::var("TRUSTED_PROXIES").unwrap_or_default()),
+    )?;
+    // Headers only believed from trusted proxies, stripped from everyone else
+    let header_sanitizer = checks.check(
+        "untrusted headers",
+        middleware::header_sanitizer::HeaderSanitizer::from_env(),
+    )?;
+
+    // Longest X-Request-Id correlation chain accepted from callers
+    let max_chain_depth = env::var("MAX_CHAIN_DEPTH")
         .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or_else(num_cpus::get);
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(middleware::request_id::DEFAULT_MAX_CHAIN_DEPTH);
+
+    // API key protecting the /admin scope, reloadable from ADMIN_API_KEY_FILE
+    let admin_key = checks.check("admin API key", middleware::api_key::AdminKey::from_env())?;
+    if !admin_key.is_set() {
+        warn!("ADMIN_API_KEY is not set; all /admin requests will be rejected");
+    }
+    #[cfg(feature = "full")]
+    let admin_key_data = web::Data::new(admin_key.clone());
+    // Configuration previewed and applied through /admin/config
+    let reloadable_config = web::Data::new(
+        config_reload::ReloadableConfig::from_env(admin_key.clone())
+            .with_flags(flags.get_ref().clone()),
+    );
+
+    // DNS resolution for outbound connections, installed before any client is built
+    outbound::resolver::install(
+        checks.check("outbound DNS", outbound::resolver::DnsResolver::from_env())?,
+    );
+
+    // Revocation checking for client certificates (mTLS)
+    let revocation_checker = checks.check("revocation", revocation::checker_from_env())?;
+    let revocation_fail_open = env::var("REVOCATION_FAIL_OPEN")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+
+    // Credential backend for POST /auth/login
+    #[cfg(feature = "full")]
+    let auth_backend = checks
+        .check("credential backend", auth::backend_from_env())?
+        .map(web::Data::from);
+    // Weak admin secrets are logged, or refused under ADMIN_SECRET_POLICY=strict
+    checks.check(
+        "admin secrets",
+        auth::secret_policy::check_from_env(&admin_key),
+    )?;
+
+    // Outbound email for password resets and server error digests
+    let mail_queue = checks.check("email", mail::MailQueue::from_env())?;
+
+    // Every expiry and freshness check tolerates CLOCK_SKEW_SECS of drift
+    let clock = web::Data::new(checks.check("clock skew", clock::Clock::from_env())?);
 
-    info!("Server running on {} with {} workers", address, num_workers);
+    // Password reset tokens are signed with a key derived from MASTER_KEY
+    #[cfg(feature = "full")]
+    let master_key = checks.check("master key", master_key::MasterKey::from_env())?;
+    #[cfg(feature = "full")]
+    let password_reset = match (&auth_backend, &master_key) {
+        (Some(backend), Some(key)) => Some(web::Data::new(
+            checks
+                .check(
+                    "password reset",
+                    auth::reset::PasswordReset::from_env(backend.clone().into_inner(), key),
+                )?
+                .with_clock(clock.get_ref().clone()),
+        )),
+        _ => None,
+    };
+
+    // Passkey enrollment and login, for users of the credential backend
+    #[cfg(all(feature = "full", feature = "webauthn"))]
+    let passkeys = match &auth_backend {
+        Some(_) => checks
+            .check("webauthn", auth::webauthn::PasskeyAuth::from_env())?
+            .map(|passkeys| web::Data::new(passkeys.with_clock(clock.get_ref().clone()))),
+        None => None,
+    };
+
+    // Attenuatable bearer tokens signed with MACAROON_ROOT_KEY
+    #[cfg(feature = "macaroon")]
+    let macaroons = checks
+        .check("macaroons", auth::macaroon::MacaroonAuthority::from_env())?
+        .map(web::Data::new);
+    #[cfg(feature = "macaroon")]
+    let macaroon_auth =
+        middleware::macaroon_auth::MacaroonAuth::from_env().with_clock(clock.get_ref().clone());
+
+    // Administrative actions, appended to AUDIT_LOG_FILE when set
+    let audit_log = web::Data::new(checks.check("audit log file", audit::AuditLog::from_env())?);
+
+    // Delay between SIGTERM and the start of the connection drain
+    let grace_delay = checks.check("shutdown delay", lifecycle::grace_delay_from_env())?;
+    // Requests being served, reported while the drain waits on them
+    let in_flight = web::Data::new(middleware::in_flight::InFlightRegistry::from_env(
+        num_workers,
+    ));
+    let drain_report = checks.check(
+        "shutdown timeout",
+        lifecycle::DrainReport::from_env(in_flight.clone().into_inner()),
+    )?;
+    let shutdown_timeout = drain_report.timeout().as_secs();
+    // Longest drain of the old process when restarting on SIGUSR2
+    let restart_drain_timeout = checks.check(
+        "restart drain timeout",
+        restart::drain_timeout_from_env(drain_report.timeout()),
+    )?;
+    // Persist audit events when a database is configured
+    #[cfg(feature = "db")]
+    let database = checks.check("database", db::Database::from_env())?;
+
+    // Reverse proxy (and its traffic mirror) under PROXY_PATH_PREFIX
+    let reverse_proxy = checks
+        .check("reverse proxy", proxy::Proxy::from_env())?
+        .map(web::Data::new);
+    // One pooled HTTP client for health checks and Consul, shared by every
+    // worker
+    let http_client = checks.check("HTTP client", outbound::HttpClient::from_env())?;
+    let mut health_registry =
+        checks.check("health checks", health::registry_from_env(&http_client))?;
+    if let Some(proxy) = &reverse_proxy {
+        checks.check(
+            "upstream health checks",
+            health::register_proxy_upstreams(&mut health_registry, proxy),
+        )?;
+    }
+    let health_registry = web::Data::new(health_registry);
+    let http_client_data = web::Data::new(http_client.clone());
+    let health_history = web::Data::new(health::HealthHistory::from_env());
+    // Web app manifest and service worker
+    #[cfg(feature = "full")]
+    let pwa_config = web::Data::new(checks.check("web app manifest", pwa::PwaConfig::from_env())?);
+    // Files under STATIC_DIR, served precompressed when possible
+    #[cfg(feature = "full")]
+    let static_files = checks
+        .check("static files", static_files::StaticFiles::from_env())?
+        .map(web::Data::new);
+    // Favicons compiled into the binary, or the configured overrides
+    let favicons = web::Data::new(checks.check("favicons", favicon::Favicons::from_env())?);
+    // robots.txt and security.txt; an expired security.txt fails startup
+    let discovery =
+        web::Data::new(checks.check("discovery files", discovery::Discovery::from_env())?);
+
+    // AWS SigV4 signatures on AWS_SIGV4_PATHS when credentials are configured
+    let sigv4 = middleware::aws_sigv4::AwsSigV4Verifier::from_env();
+    let sigv4_enabled = sigv4.is_some();
+    let sigv4 = middleware::aws_sigv4::AwsSigV4::new(sigv4.unwrap_or_default())
+        .with_clock(clock.get_ref().clone());
+
+    // Client certificates on MTLS_REQUIRED_PATHS; other paths accept anonymous clients
+    let client_cert_scope = middleware::mtls::RequireClientCertificate::from_env();
+    let client_cert_scope_enabled = client_cert_scope.is_some();
+    let client_cert_scope = client_cert_scope.unwrap_or_default();
+
+    // Resolve tenants from the Host subdomain when TENANT_BASE_DOMAIN is set
+    let tenants = middleware::tenant::TenantResolution::from_env();
+    let tenants_enabled = tenants.is_some();
+    let tenants = tenants.unwrap_or_default();
+
+    // Answer `X-Dry-Run: true` mutating requests without running the handler
+    let dry_run = middleware::dry_run::DryRun::from_env();
+    let dry_run_enabled = dry_run.is_some();
+    let dry_run = dry_run.unwrap_or_default();
+    // Shed load while RSS exceeds MEMORY_PRESSURE_RSS_BYTES
+    let memory_watcher = web::Data::new(memory::MemoryPressureWatcher::from_env());
+    let memory_pressure = middleware::memory_pressure::MemoryPressure::new(memory_watcher.flag());
+
+    // Cancel requests using more CPU time or memory than RESOURCE_*_BUDGET_*
+    let resource_budget = checks.check(
+        "resource budget",
+        resource_budget::ResourceBudget::from_env(),
+    )?;
+
+    // TCP keepalive probes reap connections to clients that vanished
+    let tcp_keepalive = tcp_keepalive::TcpKeepaliveSettings::from_env();
+
+    // Strip PROXY protocol headers in front of the server when enabled
+    let proxy_protocol = env::var("PROXY_PROTOCOL")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+        .then(proxy_protocol::ProxyProtocolAcceptor::new);
+    let connect_proxy_protocol = proxy_protocol.clone();
+    let drain_report = drain_connections(drain_report, proxy_protocol.as_ref());
+
+    // Per-connection SNI logging for debugging certificate selection
+    let handshake_logger =
+        checks.check("TLS debug logging", tls_info::HandshakeLogger::from_env())?;
+
+    #[cfg(feature = "consul")]
+    let consul_config = checks.check("consul", consul::ConsulConfig::from_env(&address))?;
+
+    info!("Configuration validated: {}", checks.summary());
+
+    // Only now start background work: mail delivery, audit persistence,
+    // access log flushing and memory sampling
+    if let Some(queue) = &mail_queue {
+        mail::install(queue.clone());
+        mail::alerts::from_env(queue);
+    }
+    #[cfg(feature = "db")]
+    let audit_store = database.map(|db| web::Data::new(audit::store::start(db)));
+    if let Some((file, every)) = access_log_file {
+        middleware::access_log::start(file, every);
+    }
+    memory_watcher.clone().into_inner().spawn();
 
-    HttpServer::new(move || {
-        App::new()
-            .route("/hello", web::get().to(hello))
+    let lifecycle = web::Data::new(lifecycle::Lifecycle::new());
+    let server_lifecycle = lifecycle.clone();
+
+    // Prefer sockets handed over by the process restarting into this one,
+    // then those passed by systemd socket activation, over binding
+    let (listeners, inherited) = restart::bind_or_inherit(&address, tcp_keepalive.as_ref())?;
+    // Duplicates of the listeners, handed over on SIGUSR2
+    let zero_downtime = restart::ZeroDowntimeMgr::new(
+        &listeners,
+        // Requests are aborted after the shutdown timeout in any case
+        drain_connections(
+            lifecycle::DrainReport::new(
+                in_flight.clone().into_inner(),
+                restart_drain_timeout.min(drain_report.timeout()),
+            ),
+            proxy_protocol.as_ref(),
+        ),
+    )?;
+    match &tcp_keepalive {
+        Some(settings) => {
+            info!("TCP keepalive: {}", settings.describe());
+            for listener in &listeners {
+                settings.apply(listener)?;
+            }
+        }
+        None => info!("TCP keepalive: disabled"),
+    }
+
+    // Routes, grouped in modules; `core` builds only serve health and hello
+    let routes = routes::ModuleRegistry::new()
+        .with_module(routes::HealthModule)
+        .with_module(routes::DiscoveryModule { discovery })
+        .with_module(routes::FaviconModule { favicons });
+    #[cfg(feature = "full")]
+    let routes = routes
+        .with_module(routes::StaticModule {
+            pwa: pwa_config,
+            files: static_files,
+        })
+        .with_module(routes::AdminModule {
+            admin_key: admin_key_data,
+            memory_watcher: memory_watcher.clone(),
+            audit_log: audit_log.clone(),
+            translations: translations.clone(),
+            cache: response_cache.clone(),
+            proxy: reverse_proxy.is_some(),
+            #[cfg(feature = "db")]
+            audit_store,
+        })
+        .with_module(routes::TlsModule {
+            admin_key: admin_key.clone(),
+            chain: tls_chain,
+        })
+        .with_module(routes::ApiModule {
+            auth_backend,
+            password_reset,
+            features,
+            #[cfg(feature = "webauthn")]
+            passkeys,
+            #[cfg(feature = "macaroon")]
+            macaroons: macaroons
+                .clone()
+                .map(|authority| (authority, admin_key.clone())),
+            reverse_proxy,
+        });
+    #[cfg(not(feature = "full"))]
+    let routes = routes.with_module(routes::HelloModule);
+    // Documentation and security requirements of every route, for /admin/routes
+    let route_metadata = web::Data::new(routes.metadata());
+    info!("Route metadata describes {} routes", route_metadata.len());
+    // A scope or tail pattern registered before routes below its prefix
+    // swallows them; warn, or refuse to start under STRICT_ROUTES
+    route_overlap::check(&routes.mounts(), route_overlap::strict_from_env())?;
+
+    let server = HttpServer::new(move || {
+        let app = App::new();
+        #[cfg(feature = "macaroon")]
+        let app = app.wrap(Condition::new(macaroons.is_some(), macaroon_auth.clone()));
+        app.app_data(i18n.clone())
+            .app_data(server_lifecycle.clone())
+            .app_data(health_registry.clone())
+            .app_data(health_history.clone())
+            .app_data(log_buffer.clone())
+            .app_data(throttle_responses.clone())
+            .app_data(ws_limits.clone())
+            .app_data(multipart_limits.clone())
+            .app_data(upload_scanning.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(flags.clone())
+            .app_data(reloadable_config.clone())
+            .app_data(clock.clone())
+            .app_data(audit_log.clone())
+            .app_data(in_flight.clone())
+            .app_data(http_client_data.clone())
+            .app_data(features_data.clone())
+            .app_data(route_metadata.clone())
+            .wrap(Condition::new(
+                response_cache_enabled,
+                response_cache_middleware.clone(),
+            ))
+            .wrap(cache_policy.clone())
+            .wrap(Condition::new(
+                flags_header,
+                middleware::feature_flags::FeatureFlagsHeader,
+            ))
+            .wrap(middleware::drain::ConnectionDrain::new(
+                server_lifecycle.clone().into_inner(),
+            ))
+            .wrap(middleware::extra_headers::extra_headers(&extra_headers))
+            .wrap(security_headers.clone())
+            .wrap(middleware::keep_alive::H1KeepAlive::new(&keep_alive))
+            .wrap(middleware::revocation::RevocationCheck::new(
+                revocation_checker.clone(),
+                revocation_fail_open,
+            ))
+            .wrap(Condition::new(
+                client_cert_scope_enabled,
+                client_cert_scope.clone(),
+            ))
+            .wrap(middleware::mtls::ClientCertificates)
+            .wrap(Condition::new(translations.is_some(), rewriter.clone()))
+            .wrap(middleware::locale::LocaleNegotiation)
+            .wrap(Condition::new(dry_run_enabled, dry_run.clone()))
+            .wrap(Condition::new(
+                envelope_enabled,
+                middleware::envelope::ResponseEnvelope::new(),
+            ))
+            .wrap(Condition::new(sigv4_enabled, sigv4.clone()))
+            .wrap(Condition::new(tenants_enabled, tenants.clone()))
+            .wrap(resource_budget)
+            .wrap(middleware::panic::PanicHandler)
+            .wrap(memory_pressure.clone())
+            .wrap(header_limits)
+            .wrap(proto_enforcer)
+            .wrap(slow_requests.clone())
+            .wrap(middleware::in_flight::TrackInFlight::new(
+                in_flight.clone().into_inner(),
+            ))
+            .wrap(
+                middleware::access_log::AccessLog::new(sampling.clone())
+                    .excluding(access_log_excluded.clone()),
+            )
+            .wrap(middleware::request_id::AssignRequestId::new(
+                max_chain_depth,
+            ))
+            .wrap(header_sanitizer.clone())
+            .configure(|cfg| routes.configure(cfg))
             .default_service(web::route().to(not_found))
     })
+    .on_connect(move |connection, data| {
+        tls_info::on_connect(connection, data);
+        connections::on_connect(connection, data);
+        if let Some(acceptor) = &connect_proxy_protocol {
+            acceptor.on_connect(connection, data);
+        }
+        if let Some(logger) = &handshake_logger {
+            logger.on_connect(connection, data);
+        }
+    })
+    .keep_alive(keep_alive.server_keep_alive())
+    .tls_handshake_timeout(lifecycle::TLS_HANDSHAKE_TIMEOUT)
     .workers(num_workers)
-    .bind_rustls(address, tls_config)?
-    .run()
-    .await
+    .shutdown_timeout(shutdown_timeout)
+    .disable_signals();
+
+    let origin = if inherited {
+        " (inherited on restart)"
+    } else {
+        ""
+    };
+    let server = if let Some(acceptor) = &proxy_protocol {
+        let backend = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let backend_addr = backend.local_addr()?;
+        for listener in listeners {
+            info!(
+                "Server running on {}{} (PROXY protocol) with {} workers",
+                listener.local_addr()?,
+                origin,
+                num_workers
+            );
+            acceptor.spawn(listener, backend_addr)?;
+        }
+        // Stop relaying before the server stops, on restart and shutdown
+        let acceptor = acceptor.clone();
+        lifecycle.on_drain(move || acceptor.stop());
+        server.listen_rustls(backend, tls_config)?
+    } else {
+        let mut server = server;
+        for listener in listeners {
+            info!(
+                "Server running on {}{} with {} workers",
+                listener.local_addr()?,
+                origin,
+                num_workers
+            );
+            server = server.listen_rustls(listener, tls_config.clone())?;
+        }
+        server
+    };
+
+    info!(
+        "Shutdown grace delay after SIGTERM: {}s",
+        grace_delay.as_secs_f64()
+    );
+    // Register with Consul in the background; failures never block serving
+    #[cfg(feature = "consul")]
+    let consul = consul_config.map(|config| {
+        let registration =
+            Arc::new(consul::ConsulRegistration::new(config).with_client(&http_client));
+        let job = registration.clone().spawn();
+        (registration, job)
+    });
+
+    let server = server.run();
+    // A restarted process now serves; the previous one may drain
+    restart::notify_ready();
+    #[cfg(unix)]
+    if admin_key.is_reloadable() {
+        actix_web::rt::spawn(middleware::api_key::reload_on_sighup(admin_key));
+    }
+    #[cfg(unix)]
+    let restarting = actix_web::rt::spawn(
+        zero_downtime.restart_on_sigusr2(server.handle(), lifecycle.clone().into_inner()),
+    );
+    let shutting_down = actix_web::rt::spawn(lifecycle::handle_shutdown_signals(
+        server.handle(),
+        lifecycle.clone().into_inner(),
+        grace_delay,
+        drain_report,
+    ));
+    let result = server.await;
+    // The runtime stops when main returns: let the task that drained the
+    // server finish logging and flushing first
+    if lifecycle.is_draining() {
+        #[cfg(unix)]
+        futures_util::future::select(shutting_down, restarting).await;
+        #[cfg(not(unix))]
+        let _ = shutting_down.await;
+    }
+    // The server may also stop without a drain, e.g. on a worker error
+    middleware::access_log::flush();
+
+    #[cfg(feature = "consul")]
+    if let Some((registration, job)) = consul {
+        job.abort();
+        if let Err(e) = registration.deregister().await {
+            error!("Failed to deregister from Consul: {}", e);
+        }
+    }
+
+    result
 }
 
 // Add these lines to make the functions public and accessible for testing
@@ -0,0 +1,71 @@
+//! The server master key.
+//!
+//! `MASTER_KEY` holds a base64-encoded secret of at least 32 bytes. Features
+//! never use it directly: each derives its own key with HKDF-SHA256 and a
+//! purpose label, so a key serves exactly one purpose and rotating the
+//! master key invalidates everything derived from it.
+
+use std::env;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::{hkdf, hmac};
+
+/// Shortest accepted master key.
+pub const MIN_KEY_BYTES: usize = 32;
+
+/// Root secret from which purpose-specific keys are derived.
+pub struct MasterKey(hkdf::Prk);
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// Creates the key from raw secret bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the secret is shorter than [`MIN_KEY_BYTES`].
+    pub fn new(secret: &[u8]) -> Result<Self, IoError> {
+        if secret.len() < MIN_KEY_BYTES {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("MASTER_KEY must be at least {} bytes", MIN_KEY_BYTES),
+            ));
+        }
+        let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, b"secure-actix-web-server");
+        Ok(MasterKey(salt.extract(secret)))
+    }
+
+    /// Reads the base64-encoded `MASTER_KEY`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<MasterKey>, IoError>` - The key, `None` if MASTER_KEY is not set, or an IoError if it is malformed or too short.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let Ok(encoded) = env::var("MASTER_KEY") else {
+            return Ok(None);
+        };
+        let secret = STANDARD.decode(encoded.trim()).map_err(|e| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("MASTER_KEY is not valid base64: {}", e),
+            )
+        })?;
+        MasterKey::new(&secret).map(Some)
+    }
+
+    /// Derives the HMAC-SHA256 key for `purpose`.
+    pub fn hmac_key(&self, purpose: &str) -> hmac::Key {
+        let info = [purpose.as_bytes()];
+        self.0
+            .expand(&info, hmac::HMAC_SHA256)
+            .map(hmac::Key::from)
+            .expect("HKDF output of one hash length cannot fail")
+    }
+}
@@ -0,0 +1,218 @@
+//! Load shedding under memory pressure.
+//!
+//! The [`MemoryPressureWatcher`] samples the resident set size (RSS) of the
+//! process every `MEMORY_CHECK_INTERVAL_MS`, from `/proc/self/status` on
+//! Linux and `task_info` on macOS. While it exceeds
+//! `MEMORY_PRESSURE_RSS_BYTES`, the shared pressure flag is set and the
+//! [`MemoryPressure`](crate::middleware::memory_pressure::MemoryPressure)
+//! middleware rejects requests with 503, giving the allocator a chance to
+//! recover instead of letting the kernel kill the process.
+//!
+//! On other platforms RSS is unknown and the server is never under pressure.
+
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use log::{info, warn};
+use serde_json::json;
+
+use crate::metrics::Metrics;
+
+/// Default for `MEMORY_CHECK_INTERVAL_MS`.
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Parses the RSS in bytes from the contents of `/proc/self/status`, whose
+/// `VmRSS` line reads e.g. `VmRSS:     12345 kB`.
+pub fn parse_proc_status(contents: &str) -> Option<u64> {
+    let line = contents.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let mut fields = line["VmRSS:".len()..].split_whitespace();
+    let value: u64 = fields.next()?.parse().ok()?;
+    let unit = match fields.next() {
+        Some(unit) if unit.eq_ignore_ascii_case("kb") => 1024,
+        Some(unit) if unit.eq_ignore_ascii_case("mb") => 1024 * 1024,
+        Some(unit) if unit.eq_ignore_ascii_case("b") => 1,
+        None => 1,
+        Some(_) => return None,
+    };
+    value.checked_mul(unit)
+}
+
+/// `MACH_TASK_BASIC_INFO`, the `task_info` flavor reporting RSS.
+pub const MACH_TASK_BASIC_INFO: u32 = 20;
+
+/// Size of `struct mach_task_basic_info` in `integer_t` words.
+pub const MACH_TASK_BASIC_INFO_COUNT: u32 = 12;
+
+/// Parses the RSS in bytes from a `struct mach_task_basic_info` filled in by
+/// `task_info`, given as its `integer_t` words. `resident_size` is the
+/// second 64-bit field, after `virtual_size`.
+pub fn parse_mach_task_basic_info(words: &[i32]) -> Option<u64> {
+    if words.len() < MACH_TASK_BASIC_INFO_COUNT as usize {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&words[2].to_ne_bytes());
+    bytes[4..].copy_from_slice(&words[3].to_ne_bytes());
+    Some(u64::from_ne_bytes(bytes))
+}
+
+/// Reads the current RSS of the process, or `None` if it is unavailable.
+#[cfg(target_os = "linux")]
+pub fn read_rss() -> Option<u64> {
+    parse_proc_status(&std::fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// Reads the current RSS of the process, or `None` if it is unavailable.
+#[cfg(target_os = "macos")]
+pub fn read_rss() -> Option<u64> {
+    extern "C" {
+        static mach_task_self_: u32;
+        fn task_info(task: u32, flavor: u32, info: *mut i32, count: *mut u32) -> i32;
+    }
+
+    let mut words = [0i32; MACH_TASK_BASIC_INFO_COUNT as usize];
+    let mut count = MACH_TASK_BASIC_INFO_COUNT;
+    // SAFETY: `words` holds `count` integer_t words, as task_info expects
+    let result = unsafe {
+        task_info(
+            mach_task_self_,
+            MACH_TASK_BASIC_INFO,
+            words.as_mut_ptr(),
+            &mut count,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    parse_mach_task_basic_info(&words[..count as usize])
+}
+
+/// Reads the current RSS of the process, or `None` if it is unavailable.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn read_rss() -> Option<u64> {
+    None
+}
+
+/// Samples RSS and raises the pressure flag above the limit.
+pub struct MemoryPressureWatcher {
+    limit: Option<u64>,
+    interval: Duration,
+    rss: AtomicU64,
+    pressure: Arc<AtomicBool>,
+}
+
+impl MemoryPressureWatcher {
+    /// Creates a watcher flagging pressure above `limit` bytes, or never if
+    /// `None`.
+    pub fn new(limit: Option<u64>, interval: Duration) -> Self {
+        MemoryPressureWatcher {
+            limit,
+            interval,
+            rss: AtomicU64::new(0),
+            pressure: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Reads `MEMORY_PRESSURE_RSS_BYTES` and `MEMORY_CHECK_INTERVAL_MS`.
+    pub fn from_env() -> Self {
+        let limit = env::var("MEMORY_PRESSURE_RSS_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&limit| limit > 0);
+        let interval = env::var("MEMORY_CHECK_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_CHECK_INTERVAL);
+        MemoryPressureWatcher::new(limit, interval)
+    }
+
+    /// Returns the flag set while RSS is above the limit.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.pressure.clone()
+    }
+
+    /// Returns the RSS limit in bytes, if any.
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Returns the RSS of the last sample.
+    pub fn rss(&self) -> u64 {
+        self.rss.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the last sample was above the limit.
+    pub fn is_under_pressure(&self) -> bool {
+        self.pressure.load(Ordering::Relaxed)
+    }
+
+    /// Records an RSS sample, updating the pressure flag.
+    pub fn update(&self, rss: u64) {
+        self.rss.store(rss, Ordering::Relaxed);
+        Metrics::global()
+            .gauge("memory_rss_bytes", &[])
+            .store(rss.min(i64::MAX as u64) as i64, Ordering::Relaxed);
+
+        let pressure = self.limit.is_some_and(|limit| rss > limit);
+        let was = self.pressure.swap(pressure, Ordering::Relaxed);
+        if pressure && !was {
+            warn!(
+                "Memory pressure: RSS {} bytes exceeds {} bytes, shedding load",
+                rss,
+                self.limit.unwrap_or_default()
+            );
+            Metrics::global().inc("memory_pressure_events_total", &[]);
+        } else if was && !pressure {
+            info!("Memory pressure relieved: RSS {} bytes", rss);
+        }
+    }
+
+    /// Reads RSS and records it. Returns the sample, or `None` if RSS is
+    /// unavailable.
+    pub fn sample(&self) -> Option<u64> {
+        let rss = read_rss()?;
+        self.update(rss);
+        Some(rss)
+    }
+
+    /// Spawns the task sampling RSS every check interval.
+    pub fn spawn(self: &Arc<Self>) {
+        if read_rss().is_none() {
+            warn!("RSS is unavailable on this platform; memory pressure is never detected");
+            return;
+        }
+        if let Some(limit) = self.limit {
+            info!(
+                "Shedding load above {} bytes RSS, checked every {}ms",
+                limit,
+                self.interval.as_millis()
+            );
+        }
+        let watcher = self.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                watcher.sample();
+                actix_web::rt::time::sleep(watcher.interval).await;
+            }
+        });
+    }
+}
+
+/// Handler for `GET /admin/memory`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the current RSS, the limit and whether load is being shed.
+pub async fn memory_status(watcher: web::Data<MemoryPressureWatcher>) -> HttpResponse {
+    let rss = watcher.sample();
+    HttpResponse::Ok().json(json!({
+        "rss_bytes": rss,
+        "limit_bytes": watcher.limit(),
+        "under_pressure": watcher.is_under_pressure(),
+    }))
+}
@@ -0,0 +1,124 @@
+//! Process-wide metrics exposed in the Prometheus text format.
+//!
+//! Counters and gauges are identified by a name plus an optional set of
+//! labels and are created on first use. `GET /metrics` renders the whole
+//! registry.
+
+use actix_web::HttpResponse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+type Key = (String, String);
+
+/// Registry of counters and gauges.
+#[derive(Default)]
+pub struct Metrics {
+    counters: RwLock<BTreeMap<Key, Arc<AtomicU64>>>,
+    gauges: RwLock<BTreeMap<Key, Arc<AtomicI64>>>,
+}
+
+impl Metrics {
+    /// Returns the process-wide registry.
+    pub fn global() -> &'static Metrics {
+        static METRICS: OnceLock<Metrics> = OnceLock::new();
+        METRICS.get_or_init(Metrics::default)
+    }
+
+    /// Returns the counter `name` with `labels`, creating it if needed.
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> Arc<AtomicU64> {
+        let key = (name.to_string(), format_labels(labels));
+        if let Some(counter) = self.counters.read().unwrap().get(&key) {
+            return counter.clone();
+        }
+        self.counters
+            .write()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone()
+    }
+
+    /// Increments the counter `name` with `labels` by one.
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)]) {
+        self.counter(name, labels).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of a counter, or 0 if it does not exist.
+    pub fn counter_value(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let key = (name.to_string(), format_labels(labels));
+        self.counters
+            .read()
+            .unwrap()
+            .get(&key)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Returns the gauge `name` with `labels`, creating it if needed.
+    pub fn gauge(&self, name: &str, labels: &[(&str, &str)]) -> Arc<AtomicI64> {
+        let key = (name.to_string(), format_labels(labels));
+        if let Some(gauge) = self.gauges.read().unwrap().get(&key) {
+            return gauge.clone();
+        }
+        self.gauges.write().unwrap().entry(key).or_default().clone()
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_family(&mut out, "counter", &self.counters.read().unwrap(), |c| {
+            c.load(Ordering::Relaxed).to_string()
+        });
+        render_family(&mut out, "gauge", &self.gauges.read().unwrap(), |g| {
+            g.load(Ordering::Relaxed).to_string()
+        });
+        out
+    }
+}
+
+fn render_family<T>(
+    out: &mut String,
+    kind: &str,
+    metrics: &BTreeMap<Key, Arc<T>>,
+    value: impl Fn(&T) -> String,
+) {
+    let mut last_name = "";
+    for ((name, labels), metric) in metrics {
+        if name != last_name {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            last_name = name.as_str();
+        }
+        let _ = writeln!(out, "{}{} {}", name, labels, value(metric));
+    }
+}
+
+/// Formats labels as `{a="1",b="2"}`, escaping values.
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Handler for the `/metrics` route.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the registry in Prometheus text format.
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(Metrics::global().render())
+}
@@ -0,0 +1,367 @@
+//! Access logging with adaptive sampling.
+//!
+//! Logging every request is noisy, but sampling blindly loses detail on
+//! failures. Errors (4xx/5xx) and slow requests are therefore always logged,
+//! while fast successful requests are sampled at `LOG_SAMPLE_RATE`.
+//!
+//! The sampling decision is derived from a hash of the request ID, so it is
+//! deterministic: the same request is either logged everywhere or nowhere,
+//! which keeps multi-service traces intact. The `access_log_sampled_total`
+//! counter reports how many requests were sampled in and out.
+//!
+//! Entries go to the `access_log` log target, or to `ACCESS_LOG_FILE` when
+//! set. The file is written through a buffer flushed every
+//! `ACCESS_LOG_FLUSH_INTERVAL_MS` and once more during graceful shutdown, so
+//! the requests served last before a deploy are not lost.
+//!
+//! Requests for `ACCESS_LOG_EXCLUDED_PATHS` (by default the favicons, which
+//! browsers fetch on every page) are not logged unless they fail with a
+//! server error.
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::future::{ready, Ready};
+use std::io::{BufWriter, Error as IoError, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use log::{info, warn};
+
+use crate::favicon;
+use crate::mail;
+use crate::metrics::Metrics;
+use crate::middleware::request_id::request_id;
+use crate::util::real_ip::real_ip;
+
+/// Counter tracking sampling decisions, labelled `decision="in"|"out"`.
+pub const SAMPLED_METRIC: &str = "access_log_sampled_total";
+
+/// Sampling settings for the access log.
+#[derive(Clone, Debug)]
+pub struct SamplingConfig {
+    /// Fraction of fast, successful requests to log, between 0.0 and 1.0.
+    pub sample_rate: f64,
+    /// Requests at least this slow are always logged.
+    pub slow_threshold: Duration,
+}
+
+impl SamplingConfig {
+    /// Reads `LOG_SAMPLE_RATE` (default 1.0) and `LOG_SLOW_THRESHOLD_MS`
+    /// (default 1000).
+    pub fn from_env() -> Self {
+        let sample_rate = env::var("LOG_SAMPLE_RATE")
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|r| r.is_finite())
+            .map(|r| r.clamp(0.0, 1.0))
+            .unwrap_or(1.0);
+        let slow_threshold = env::var("LOG_SLOW_THRESHOLD_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_millis(1000));
+
+        SamplingConfig {
+            sample_rate,
+            slow_threshold,
+        }
+    }
+
+    /// Decides whether a completed request is logged.
+    pub fn should_log(&self, request_id: &str, status: StatusCode, elapsed: Duration) -> bool {
+        if status.is_client_error() || status.is_server_error() || elapsed >= self.slow_threshold {
+            return true;
+        }
+        sample_bucket(request_id) < self.sample_rate
+    }
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            sample_rate: 1.0,
+            slow_threshold: Duration::from_millis(1000),
+        }
+    }
+}
+
+/// Maps a request ID to a stable bucket in `[0, 1)` using FNV-1a.
+///
+/// FNV-1a leaves the high bits poorly mixed for short, similar IDs, so the
+/// hash goes through the SplitMix64 finalizer before being scaled.
+pub fn sample_bucket(request_id: &str) -> f64 {
+    let mut hash = request_id
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Default for `ACCESS_LOG_FLUSH_INTERVAL_MS`.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Buffered access log file.
+pub struct AccessLogFile {
+    path: PathBuf,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AccessLogFile {
+    /// Opens `path` for appending, creating it if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be opened.
+    pub fn open(path: &Path) -> Result<Self, IoError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLogFile {
+            path: path.to_path_buf(),
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `line` to the buffer.
+    pub fn write_line(&self, line: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{}", line) {
+            warn!(
+                "Failed to write access log '{}': {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Writes the buffered entries to the file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entries cannot be written.
+    pub fn flush(&self) -> Result<(), IoError> {
+        self.writer.lock().unwrap().flush()
+    }
+
+    /// Spawns a task flushing the buffer every `every`.
+    pub fn spawn_flush(self: &Arc<Self>, every: Duration) {
+        let file = self.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                actix_web::rt::time::sleep(every).await;
+                if let Err(e) = file.flush() {
+                    warn!(
+                        "Failed to flush access log '{}': {}",
+                        file.path.display(),
+                        e
+                    );
+                }
+            }
+        });
+    }
+}
+
+static FILE: OnceLock<Arc<AccessLogFile>> = OnceLock::new();
+
+/// Sends access log entries to `file` instead of the `access_log` target.
+/// Only the first installed file is used.
+pub fn install(file: Arc<AccessLogFile>) {
+    if FILE.set(file).is_err() {
+        warn!("An access log file is already installed");
+    }
+}
+
+/// Opens `ACCESS_LOG_FILE`, if set, with its `ACCESS_LOG_FLUSH_INTERVAL_MS`.
+/// Nothing is written to it until it is passed to [`start`].
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened.
+pub fn file_from_env() -> Result<Option<(Arc<AccessLogFile>, Duration)>, IoError> {
+    let Ok(path) = env::var("ACCESS_LOG_FILE") else {
+        return Ok(None);
+    };
+    let every = env::var("ACCESS_LOG_FLUSH_INTERVAL_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&ms| ms > 0)
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_FLUSH_INTERVAL);
+    let file = Arc::new(AccessLogFile::open(Path::new(&path))?);
+    Ok(Some((file, every)))
+}
+
+/// Installs `file` and starts flushing it every `every`.
+pub fn start(file: Arc<AccessLogFile>, every: Duration) {
+    file.spawn_flush(every);
+    info!("Writing the access log to {}", file.path.display());
+    install(file);
+}
+
+/// Flushes the installed access log file, if any. Called during graceful
+/// shutdown.
+pub fn flush() {
+    if let Some(file) = FILE.get() {
+        if let Err(e) = file.flush() {
+            warn!(
+                "Failed to flush access log '{}': {}",
+                file.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Reads the comma-separated `ACCESS_LOG_EXCLUDED_PATHS`, or returns the
+/// favicon paths when it is unset. An empty value excludes nothing.
+pub fn excluded_paths_from_env() -> Vec<String> {
+    match env::var("ACCESS_LOG_EXCLUDED_PATHS") {
+        Ok(paths) => paths
+            .split(',')
+            .map(str::trim)
+            .filter(|p| p.starts_with('/'))
+            .map(str::to_string)
+            .collect(),
+        Err(_) => favicon::FAVICON_PATHS
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+    }
+}
+
+/// Access log middleware.
+#[derive(Clone, Default)]
+pub struct AccessLog {
+    config: Arc<SamplingConfig>,
+    excluded: Arc<Vec<String>>,
+}
+
+impl AccessLog {
+    /// Creates the middleware with the given sampling settings.
+    pub fn new(config: SamplingConfig) -> Self {
+        AccessLog {
+            config: Arc::new(config),
+            excluded: Arc::default(),
+        }
+    }
+
+    /// Leaves requests for exactly these paths out of the log, unless they
+    /// fail with a server error.
+    pub fn excluding(mut self, paths: Vec<String>) -> Self {
+        self.excluded = Arc::new(paths);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware {
+            service,
+            config: self.config.clone(),
+            excluded: self.excluded.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`AccessLog`].
+pub struct AccessLogMiddleware<S> {
+    service: S,
+    config: Arc<SamplingConfig>,
+    excluded: Arc<Vec<String>>,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let config = self.config.clone();
+        let request_id = request_id(&req);
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        let excluded = self.excluded.contains(&path);
+        let peer = real_ip(req.request()).node.to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let elapsed = started.elapsed();
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+
+            if status.is_server_error() {
+                mail::alerts::record(status.as_u16(), method.as_str(), &path, &request_id);
+            }
+
+            if excluded && !status.is_server_error() {
+                return res;
+            }
+            if config.should_log(&request_id, status, elapsed) {
+                Metrics::global().inc(SAMPLED_METRIC, &[("decision", "in")]);
+                let ms = elapsed.as_secs_f64() * 1000.0;
+                if let Some(file) = FILE.get() {
+                    file.write_line(&format!(
+                        "{} {} \"{} {}\" {} {:.1}ms request_id={}",
+                        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                        peer,
+                        method,
+                        path,
+                        status.as_u16(),
+                        ms,
+                        request_id
+                    ));
+                } else if status.is_server_error() {
+                    warn!(
+                        target: "access_log",
+                        "{} \"{} {}\" {} {:.1}ms request_id={}",
+                        peer, method, path, status.as_u16(), ms, request_id
+                    );
+                } else {
+                    info!(
+                        target: "access_log",
+                        "{} \"{} {}\" {} {:.1}ms request_id={}",
+                        peer, method, path, status.as_u16(), ms, request_id
+                    );
+                }
+            } else {
+                Metrics::global().inc(SAMPLED_METRIC, &[("decision", "out")]);
+            }
+
+            res
+        })
+    }
+}
@@ -0,0 +1,302 @@
+//! API key authentication for administrative routes.
+//!
+//! Requests must carry the key configured in `ADMIN_API_KEY` in the
+//! `X-Api-Key` header. When no key is configured every request is rejected,
+//! so admin routes are never accidentally left open.
+//!
+//! The key can instead be read from `ADMIN_API_KEY_FILE`, which is read
+//! again on SIGHUP or `POST /admin/config/reload` so the key can be rotated
+//! without a restart. A replacement key is validated first; if it is
+//! rejected, the current key stays in effect.
+
+use std::env;
+use std::fs;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, RwLock};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{Error, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::audit;
+use crate::auth::secret_policy::SecretPolicy;
+use crate::error::ApiError;
+use crate::middleware::request_id::CorrelationChain;
+
+/// Header carrying the API key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Shortest key accepted when rotating.
+pub const MIN_ROTATED_KEY_LEN: usize = 16;
+
+/// The admin API key, shared by every worker so a rotation applies to all
+/// of them at once.
+#[derive(Clone, Default)]
+pub struct AdminKey {
+    key: Arc<RwLock<Option<Arc<str>>>>,
+    file: Option<Arc<str>>,
+}
+
+impl AdminKey {
+    /// Creates a fixed key; `None` rejects every request.
+    pub fn new(key: Option<String>) -> Self {
+        AdminKey {
+            key: Arc::new(RwLock::new(key.filter(|k| !k.is_empty()).map(Arc::from))),
+            file: None,
+        }
+    }
+
+    /// Reads the key from `path`, which [`reload`](Self::reload) reads again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be read or holds an invalid key.
+    pub fn from_file(path: &str) -> Result<Self, IoError> {
+        let admin_key = AdminKey {
+            file: Some(Arc::from(path)),
+            ..AdminKey::default()
+        };
+        admin_key.reload()?;
+        Ok(admin_key)
+    }
+
+    /// Reads the key from `ADMIN_API_KEY_FILE`, or else `ADMIN_API_KEY`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be read or holds an invalid key.
+    pub fn from_env() -> Result<Self, IoError> {
+        match env::var("ADMIN_API_KEY_FILE") {
+            Ok(path) if !path.is_empty() => {
+                let admin_key = AdminKey::from_file(&path)?;
+                info!("Admin API key loaded from {}", path);
+                Ok(admin_key)
+            }
+            _ => Ok(AdminKey::new(env::var("ADMIN_API_KEY").ok())),
+        }
+    }
+
+    /// Returns whether a key is configured.
+    pub fn is_set(&self) -> bool {
+        self.current().is_some()
+    }
+
+    /// Returns whether the key can be reloaded from a file.
+    pub fn is_reloadable(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Checks the current key against `policy`; `None` when it passes or
+    /// no key is set.
+    pub fn weakness(&self, policy: &SecretPolicy) -> Option<String> {
+        policy.check_key(&self.current()?)
+    }
+
+    fn current(&self) -> Option<Arc<str>> {
+        self.key.read().unwrap().clone()
+    }
+
+    /// Returns whether `presented` is the current key.
+    pub fn matches(&self, presented: &[u8]) -> bool {
+        self.current()
+            .is_some_and(|key| constant_time_eq(key.as_bytes(), presented))
+    }
+
+    /// Replaces the key with `new_key` once it passes [`validate_key`].
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool, IoError>` - Whether the key changed, or an IoError if `new_key` was rejected and the current key kept.
+    pub fn rotate(&self, new_key: &str) -> Result<bool, IoError> {
+        validate_key(new_key).map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+        let mut key = self.key.write().unwrap();
+        if key.as_deref() == Some(new_key) {
+            return Ok(false);
+        }
+        *key = Some(Arc::from(new_key));
+        Ok(true)
+    }
+
+    /// Reads `ADMIN_API_KEY_FILE` again and rotates to the key it holds.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool, IoError>` - Whether the key changed, or an IoError if the key is not file-based, the file cannot be read or its key was rejected.
+    pub fn reload(&self) -> Result<bool, IoError> {
+        let Some(path) = &self.file else {
+            return Err(IoError::new(
+                ErrorKind::Unsupported,
+                "ADMIN_API_KEY_FILE is not set",
+            ));
+        };
+        let contents = fs::read_to_string(path.as_ref())?;
+        self.rotate(contents.trim_end_matches(['\r', '\n']))
+    }
+
+    /// Reloads the key and records the outcome in the audit log, never
+    /// including the key itself.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool, IoError>` - See [`reload`](Self::reload).
+    pub fn reload_audited(
+        &self,
+        trigger: &str,
+        chain: Option<&CorrelationChain>,
+    ) -> Result<bool, IoError> {
+        let result = self.reload();
+        let (event, details) = match &result {
+            Ok(true) => ("admin_key_rotated", json!({ "trigger": trigger })),
+            Ok(false) => return result,
+            Err(e) => (
+                "admin_key_rotation_failed",
+                json!({ "trigger": trigger, "reason": e.to_string() }),
+            ),
+        };
+        match chain {
+            Some(chain) => audit::record_for(chain, event, details),
+            None => audit::record(event, details),
+        }
+        match &result {
+            Ok(_) => info!("Admin API key rotated ({})", trigger),
+            Err(e) => warn!("Kept the current admin API key ({}): {}", trigger, e),
+        }
+        result
+    }
+}
+
+/// Checks that `key` can serve as the admin API key: at least
+/// [`MIN_ROTATED_KEY_LEN`] characters that can be sent in a header, without
+/// whitespace.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - Ok, or why the key was rejected.
+pub fn validate_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("the key is empty".to_string());
+    }
+    if !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err("the key must be printable ASCII without whitespace".to_string());
+    }
+    if key.len() < MIN_ROTATED_KEY_LEN {
+        return Err(format!(
+            "the key must be at least {} characters",
+            MIN_ROTATED_KEY_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Reloads `key` from its file on every SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(key: AdminKey) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            warn!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the admin API key");
+        let _ = key.reload_audited("sighup", None);
+    }
+}
+
+/// Middleware requiring a valid API key.
+#[derive(Clone)]
+pub struct ApiKeyAuth {
+    key: AdminKey,
+}
+
+impl ApiKeyAuth {
+    /// Creates the middleware; `None` rejects every request.
+    pub fn new(key: Option<String>) -> Self {
+        ApiKeyAuth::shared(AdminKey::new(key))
+    }
+
+    /// Creates the middleware checking `key`, following its rotations.
+    pub fn shared(key: AdminKey) -> Self {
+        ApiKeyAuth { key }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApiKeyAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ApiKeyAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApiKeyAuthMiddleware {
+            service,
+            key: self.key.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`ApiKeyAuth`].
+pub struct ApiKeyAuthMiddleware<S> {
+    service: S,
+    key: AdminKey,
+}
+
+impl<S, B> Service<ServiceRequest> for ApiKeyAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let authorized = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .is_some_and(|presented| self.key.matches(presented.as_bytes()));
+
+        if !authorized {
+            warn!(
+                "Rejected unauthenticated admin request: {} {}",
+                req.method(),
+                req.path()
+            );
+            let res = ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Missing or invalid API key",
+            )
+            .error_response();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+/// Compares two byte strings in time independent of where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,676 @@
+//! AWS Signature Version 4 request authentication.
+//!
+//! Lets tools such as `awscurl` call the server with the credentials in
+//! `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`. Requests under the
+//! `AWS_SIGV4_PATHS` prefixes must carry an
+//! `Authorization: AWS4-HMAC-SHA256 Credential=..., SignedHeaders=...,
+//! Signature=...` header and an `X-Amz-Date` within `AWS_CLOCK_SKEW_SECS`,
+//! plus the global `CLOCK_SKEW_SECS`, of the server clock. The canonical request is rebuilt from the received
+//! method, path, query, signed headers and body hash, exactly as the client
+//! computed it, and the signature is checked with HMAC-SHA256.
+//!
+//! Failures are answered with an AWS-style XML error document, so SDKs and
+//! tools report them the same way as errors from AWS itself.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, HttpMessage, HttpResponse};
+use chrono::NaiveDateTime;
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use log::{info, warn};
+use ring::{digest, hmac};
+
+use crate::clock::Clock;
+use crate::discovery;
+use crate::middleware::request_id::request_id;
+
+/// The only supported signing algorithm.
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Header carrying the signing time, e.g. `20150830T123600Z`.
+pub const AMZ_DATE_HEADER: &str = "x-amz-date";
+
+/// Header carrying the hex SHA-256 of the body, or `UNSIGNED-PAYLOAD`.
+pub const CONTENT_SHA256_HEADER: &str = "x-amz-content-sha256";
+
+/// Default for `AWS_CLOCK_SKEW_SECS`.
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// Largest body buffered to compute its hash.
+pub const MAX_SIGNED_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Why a request failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigV4Error {
+    /// No `Authorization` header.
+    MissingAuthentication,
+    /// The header or a signed value is malformed.
+    IncompleteSignature(String),
+    /// The access key is not the configured one.
+    UnknownAccessKey,
+    /// The signing time is too far from the server clock.
+    RequestExpired,
+    /// The signature does not match the request.
+    SignatureDoesNotMatch,
+    /// The body is too large to be hashed.
+    BodyTooLarge,
+}
+
+impl SigV4Error {
+    /// Returns the HTTP status of the error response.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SigV4Error::IncompleteSignature(_) => StatusCode::BAD_REQUEST,
+            SigV4Error::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// Returns the AWS error code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SigV4Error::MissingAuthentication => "MissingAuthenticationToken",
+            SigV4Error::IncompleteSignature(_) => "IncompleteSignature",
+            SigV4Error::UnknownAccessKey => "InvalidClientTokenId",
+            SigV4Error::RequestExpired => "RequestExpired",
+            SigV4Error::SignatureDoesNotMatch => "SignatureDoesNotMatch",
+            SigV4Error::BodyTooLarge => "RequestEntityTooLarge",
+        }
+    }
+
+    /// Returns the human-readable error message.
+    pub fn message(&self) -> String {
+        match self {
+            SigV4Error::MissingAuthentication => "Missing Authentication Token".to_string(),
+            SigV4Error::IncompleteSignature(reason) => reason.clone(),
+            SigV4Error::UnknownAccessKey => {
+                "The security token included in the request is invalid.".to_string()
+            }
+            SigV4Error::RequestExpired => {
+                "Request has expired or is signed too far in the future.".to_string()
+            }
+            SigV4Error::SignatureDoesNotMatch => "The request signature we calculated does not \
+                match the signature you provided. Check your AWS Secret Access Key and signing \
+                method."
+                .to_string(),
+            SigV4Error::BodyTooLarge => format!(
+                "Signed request bodies are limited to {} bytes.",
+                MAX_SIGNED_BODY_BYTES
+            ),
+        }
+    }
+
+    /// Renders the error as an AWS XML error document.
+    pub fn error_response(&self, request_id: &str) -> HttpResponse {
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <ErrorResponse><Error><Type>Sender</Type><Code>{}</Code>\
+             <Message>{}</Message></Error><RequestId>{}</RequestId></ErrorResponse>",
+            self.code(),
+            xml_escape(&self.message()),
+            xml_escape(request_id)
+        );
+        HttpResponse::build(self.status())
+            .insert_header((CONTENT_TYPE, "application/xml"))
+            .body(body)
+    }
+}
+
+/// The parts of an `Authorization: AWS4-HMAC-SHA256 ...` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorization {
+    pub access_key_id: String,
+    /// `YYYYMMDD` date of the credential scope.
+    pub date: String,
+    pub region: String,
+    pub service: String,
+    /// Lower-case names of the signed headers, in the signed order.
+    pub signed_headers: Vec<String>,
+    /// Hex-encoded signature.
+    pub signature: String,
+}
+
+impl Authorization {
+    /// Returns the credential scope, `date/region/service/aws4_request`.
+    pub fn scope(&self) -> String {
+        format!(
+            "{}/{}/{}/aws4_request",
+            self.date, self.region, self.service
+        )
+    }
+}
+
+/// Parses an `Authorization` header value.
+///
+/// # Errors
+///
+/// Returns [`SigV4Error::IncompleteSignature`] naming what is missing or
+/// malformed.
+pub fn parse_authorization(value: &str) -> Result<Authorization, SigV4Error> {
+    let incomplete = |reason: &str| SigV4Error::IncompleteSignature(reason.to_string());
+    let rest = value
+        .trim()
+        .strip_prefix(ALGORITHM)
+        .filter(|rest| rest.starts_with(' '))
+        .ok_or_else(|| incomplete("Unsupported authorization algorithm"))?;
+
+    let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+    for part in rest.split(',') {
+        match part.trim().split_once('=') {
+            Some(("Credential", v)) => credential = Some(v),
+            Some(("SignedHeaders", v)) => signed_headers = Some(v),
+            Some(("Signature", v)) => signature = Some(v),
+            _ => {}
+        }
+    }
+    let credential = credential.ok_or_else(|| incomplete("Missing Credential"))?;
+    let signed_headers = signed_headers.ok_or_else(|| incomplete("Missing SignedHeaders"))?;
+    let signature = signature.ok_or_else(|| incomplete("Missing Signature"))?;
+
+    let scope: Vec<&str> = credential.split('/').collect();
+    let [access_key_id, date, region, service, "aws4_request"] = scope[..] else {
+        return Err(incomplete("Malformed Credential scope"));
+    };
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(incomplete("Malformed Credential date"));
+    }
+    if signature.len() != 64 || !signature.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(incomplete("Malformed Signature"));
+    }
+    let signed_headers: Vec<String> = signed_headers
+        .split(';')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect();
+    if !signed_headers.iter().any(|h| h == "host") {
+        return Err(incomplete("The host header must be signed"));
+    }
+
+    Ok(Authorization {
+        access_key_id: access_key_id.to_string(),
+        date: date.to_string(),
+        region: region.to_string(),
+        service: service.to_string(),
+        signed_headers,
+        signature: signature.to_ascii_lowercase(),
+    })
+}
+
+/// Percent-encodes everything but the unreserved characters, and `/` too
+/// unless `keep_slash`.
+pub fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric()
+            || matches!(b, b'-' | b'_' | b'.' | b'~')
+            || (keep_slash && b == b'/')
+        {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Decodes percent escapes, leaving malformed ones as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Builds the canonical query string: parameters decoded, re-encoded and
+/// sorted by name, then value.
+pub fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = p.split_once('=').unwrap_or((p, ""));
+            (
+                uri_encode(&percent_decode(name), false),
+                uri_encode(&percent_decode(value), false),
+            )
+        })
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Builds the canonical request.
+///
+/// `path` is the path as received; it is encoded once more, as AWS services
+/// other than S3 expect. `headers` holds every received header with a
+/// lower-case name; repeated headers are joined with commas.
+pub fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(String, String)],
+    signed_headers: &[String],
+    payload_hash: &str,
+) -> String {
+    let path = if path.is_empty() { "/" } else { path };
+    let canonical_headers: String = signed_headers
+        .iter()
+        .map(|name| {
+            let values: Vec<String> = headers
+                .iter()
+                .filter(|(n, _)| n == name)
+                .map(|(_, v)| v.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect();
+            format!("{}:{}\n", name, values.join(","))
+        })
+        .collect();
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        uri_encode(path, true),
+        canonical_query(query),
+        canonical_headers,
+        signed_headers.join(";"),
+        payload_hash
+    )
+}
+
+/// Builds the string to sign for a canonical request.
+pub fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        sha256_hex(canonical_request.as_bytes())
+    )
+}
+
+/// Derives the signing key for a date, region and service.
+pub fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let step = |key: &[u8], data: &str| {
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+            .as_ref()
+            .to_vec()
+    };
+    let key = step(format!("AWS4{}", secret).as_bytes(), date);
+    let key = step(&key, region);
+    let key = step(&key, service);
+    step(&key, "aws4_request")
+}
+
+/// Signs `string_to_sign`, returning the hex signature.
+pub fn sign(signing_key: &[u8], string_to_sign: &str) -> String {
+    hex(hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, signing_key),
+        string_to_sign.as_bytes(),
+    )
+    .as_ref())
+}
+
+/// Returns the hex SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A received request, as needed for verification.
+#[derive(Debug, Clone)]
+pub struct SignedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    /// Every header, with lower-case names.
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl SignedRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Verifies SigV4 signatures made with one set of credentials.
+pub struct AwsSigV4Verifier {
+    access_key_id: String,
+    secret_access_key: String,
+    clock_skew: Duration,
+    paths: Vec<String>,
+}
+
+impl Default for AwsSigV4Verifier {
+    /// A verifier that applies to no path.
+    fn default() -> Self {
+        AwsSigV4Verifier::new("", "", DEFAULT_CLOCK_SKEW).with_paths(Vec::new())
+    }
+}
+
+impl AwsSigV4Verifier {
+    /// Creates a verifier accepting signatures made with the given
+    /// credentials, on requests under `/`.
+    pub fn new(access_key_id: &str, secret_access_key: &str, clock_skew: Duration) -> Self {
+        AwsSigV4Verifier {
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            clock_skew,
+            paths: vec!["/".to_string()],
+        }
+    }
+
+    /// Only requires signatures on requests under the given path prefixes.
+    pub fn with_paths(mut self, paths: Vec<String>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// Reads `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+    /// `AWS_CLOCK_SKEW_SECS` and `AWS_SIGV4_PATHS`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<AwsSigV4Verifier>` - The verifier, or `None` if the credentials are not set.
+    pub fn from_env() -> Option<Self> {
+        let access_key_id = env::var("AWS_ACCESS_KEY_ID")
+            .ok()
+            .filter(|k| !k.is_empty())?;
+        let secret = env::var("AWS_SECRET_ACCESS_KEY")
+            .ok()
+            .filter(|k| !k.is_empty())?;
+        let skew = env::var("AWS_CLOCK_SKEW_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CLOCK_SKEW);
+        let paths: Vec<String> = env::var("AWS_SIGV4_PATHS")
+            .unwrap_or_else(|_| "/proxy".to_string())
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| p.starts_with('/'))
+            .collect();
+        info!(
+            "Requiring AWS SigV4 signatures from {} under {}",
+            access_key_id,
+            paths.join(", ")
+        );
+        Some(AwsSigV4Verifier::new(&access_key_id, &secret, skew).with_paths(paths))
+    }
+
+    /// Returns whether requests for `path` must be signed.
+    pub fn applies_to(&self, path: &str) -> bool {
+        !discovery::is_public(path)
+            && self.paths.iter().any(|prefix| {
+                let prefix = prefix.trim_end_matches('/');
+                path.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+    }
+
+    /// Verifies the signature of `request` at the time of `clock`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`SigV4Error`] describing the first check that failed.
+    pub fn verify(&self, request: &SignedRequest, clock: &Clock) -> Result<(), SigV4Error> {
+        let authorization = request
+            .header("authorization")
+            .ok_or(SigV4Error::MissingAuthentication)?;
+        let auth = parse_authorization(authorization)?;
+        if !crate::middleware::api_key::constant_time_eq(
+            auth.access_key_id.as_bytes(),
+            self.access_key_id.as_bytes(),
+        ) {
+            return Err(SigV4Error::UnknownAccessKey);
+        }
+
+        let amz_date = request.header(AMZ_DATE_HEADER).ok_or_else(|| {
+            SigV4Error::IncompleteSignature("Missing X-Amz-Date header".to_string())
+        })?;
+        let signed_at = NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ")
+            .map_err(|_| {
+                SigV4Error::IncompleteSignature("Malformed X-Amz-Date header".to_string())
+            })?
+            .and_utc();
+        if !amz_date.starts_with(&auth.date) {
+            return Err(SigV4Error::IncompleteSignature(
+                "Credential date does not match X-Amz-Date".to_string(),
+            ));
+        }
+        if !clock.is_within(signed_at, self.clock_skew) {
+            return Err(SigV4Error::RequestExpired);
+        }
+        if let Some(missing) = auth
+            .signed_headers
+            .iter()
+            .find(|name| request.header(name).is_none())
+        {
+            return Err(SigV4Error::IncompleteSignature(format!(
+                "Signed header '{}' is missing",
+                missing
+            )));
+        }
+
+        let body_hash = sha256_hex(&request.body);
+        let payload_hash = match request.header(CONTENT_SHA256_HEADER) {
+            Some("UNSIGNED-PAYLOAD") => "UNSIGNED-PAYLOAD".to_string(),
+            Some(claimed) if claimed.eq_ignore_ascii_case(&body_hash) => body_hash,
+            Some(_) => return Err(SigV4Error::SignatureDoesNotMatch),
+            None => body_hash,
+        };
+        let canonical = canonical_request(
+            &request.method,
+            &request.path,
+            &request.query,
+            &request.headers,
+            &auth.signed_headers,
+            &payload_hash,
+        );
+        let string_to_sign = string_to_sign(amz_date, &auth.scope(), &canonical);
+        let key = signing_key(
+            &self.secret_access_key,
+            &auth.date,
+            &auth.region,
+            &auth.service,
+        );
+        let expected = (0..auth.signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&auth.signature[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| SigV4Error::IncompleteSignature("Malformed Signature".to_string()))?;
+        hmac::verify(
+            &hmac::Key::new(hmac::HMAC_SHA256, &key),
+            string_to_sign.as_bytes(),
+            &expected,
+        )
+        .map_err(|_| SigV4Error::SignatureDoesNotMatch)
+    }
+}
+
+/// Middleware requiring SigV4 signatures.
+#[derive(Clone)]
+pub struct AwsSigV4 {
+    verifier: Arc<AwsSigV4Verifier>,
+    clock: Clock,
+}
+
+impl AwsSigV4 {
+    /// Creates the middleware for `verifier`.
+    pub fn new(verifier: AwsSigV4Verifier) -> Self {
+        AwsSigV4 {
+            verifier: Arc::new(verifier),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Checks `X-Amz-Date` against `clock`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AwsSigV4
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AwsSigV4Middleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AwsSigV4Middleware {
+            service: Rc::new(service),
+            verifier: self.verifier.clone(),
+            clock: self.clock.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`AwsSigV4`].
+pub struct AwsSigV4Middleware<S> {
+    service: Rc<S>,
+    verifier: Arc<AwsSigV4Verifier>,
+    clock: Clock,
+}
+
+impl<S, B> Service<ServiceRequest> for AwsSigV4Middleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if !self.verifier.applies_to(req.path()) {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        }
+        let service = self.service.clone();
+        let verifier = self.verifier.clone();
+        let clock = self.clock.clone();
+
+        Box::pin(async move {
+            // The body is hashed into the signature, so it is read up front
+            // and handed on to the handler afterwards
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            let mut too_large = false;
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > MAX_SIGNED_BODY_BYTES {
+                    too_large = true;
+                    break;
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let body = body.freeze();
+
+            let request = SignedRequest {
+                method: req.method().as_str().to_string(),
+                path: req.uri().path().to_string(),
+                query: req.query_string().to_string(),
+                headers: collect_headers(&req),
+                body: body.clone(),
+            };
+            let result = if too_large {
+                Err(SigV4Error::BodyTooLarge)
+            } else {
+                verifier.verify(&request, &clock)
+            };
+
+            match result {
+                Ok(()) => {
+                    let (_, mut payload) = actix_http::h1::Payload::create(true);
+                    payload.unread_data(body);
+                    req.set_payload(Payload::from(payload));
+                    service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body)
+                }
+                Err(e) => {
+                    warn!(
+                        "Rejected SigV4 request {} {}: {}",
+                        req.method(),
+                        req.path(),
+                        e.code()
+                    );
+                    let res = e.error_response(&request_id(&req));
+                    Ok(req.into_response(res).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+/// Collects the request headers with lower-case names. HTTP/2 requests
+/// carry the host in the `:authority` pseudo-header, which is added as
+/// `host`.
+fn collect_headers(req: &ServiceRequest) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = req
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((name.as_str().to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect();
+    if !headers.iter().any(|(name, _)| name == "host") {
+        if let Some(authority) = req.uri().authority() {
+            headers.push(("host".to_string(), authority.to_string()));
+        }
+    }
+    headers
+}
@@ -0,0 +1,142 @@
+//! HTTP Basic authentication.
+//!
+//! Credentials from the `Authorization: Basic` header are verified by the
+//! configured [`AuthBackend`]. On success the resulting [`Principal`] is
+//! stored in the request extensions, where handlers read it through the
+//! `Principal` extractor; otherwise the request is rejected with 401 and a
+//! `WWW-Authenticate` challenge.
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage, ResponseError};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+
+use crate::auth::{AuthBackend, AuthError};
+use crate::error::ApiError;
+
+/// Challenge sent with every 401 response.
+const CHALLENGE: &str = "Basic realm=\"secure-actix-web-server\", charset=\"UTF-8\"";
+
+/// Middleware requiring valid Basic credentials.
+#[derive(Clone)]
+pub struct BasicAuth {
+    backend: Arc<dyn AuthBackend>,
+}
+
+impl BasicAuth {
+    /// Creates the middleware verifying credentials against `backend`.
+    pub fn new(backend: Arc<dyn AuthBackend>) -> Self {
+        BasicAuth { backend }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BasicAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = BasicAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BasicAuthMiddleware {
+            service: Rc::new(service),
+            backend: self.backend.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`BasicAuth`].
+pub struct BasicAuthMiddleware<S> {
+    service: Rc<S>,
+    backend: Arc<dyn AuthBackend>,
+}
+
+impl<S, B> Service<ServiceRequest> for BasicAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let backend = self.backend.clone();
+        let credentials = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_basic);
+
+        Box::pin(async move {
+            let result = match &credentials {
+                Some((username, password)) => backend.authenticate(username, password).await,
+                None => Err(AuthError::InvalidCredentials),
+            };
+
+            match result {
+                Ok(principal) => {
+                    req.extensions_mut().insert(principal);
+                    service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body)
+                }
+                Err(e) => {
+                    if credentials.is_some() {
+                        warn!("Basic authentication failed for {}: {}", req.path(), e);
+                    }
+                    let mut res = match e {
+                        AuthError::InvalidCredentials => ApiError::new(
+                            StatusCode::UNAUTHORIZED,
+                            "unauthorized",
+                            "Missing or invalid credentials",
+                        ),
+                        e => e.into(),
+                    }
+                    .error_response();
+                    if res.status() == StatusCode::UNAUTHORIZED {
+                        res.headers_mut().insert(
+                            header::WWW_AUTHENTICATE,
+                            HeaderValue::from_static(CHALLENGE),
+                        );
+                    }
+                    Ok(req.into_response(res).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+/// Splits a `Basic` authorization header value into username and password.
+///
+/// # Returns
+///
+/// * `Option<(String, String)>` - The credentials, or `None` if the header is not well-formed Basic credentials.
+pub fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
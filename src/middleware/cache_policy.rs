@@ -0,0 +1,247 @@
+//! Declarative `Cache-Control` policy.
+//!
+//! `CACHE_POLICY_RULES` holds comma-separated `prefix=directive` rules, e.g.
+//! `/admin=no-store,/static=public:86400,/api/profile=private`. A directive
+//! is `no-store`, `private`, or `public:SECS` for `public, max-age=SECS`.
+//! The rule with the longest prefix matching the path, on a segment
+//! boundary, applies. Without the variable, `/admin` and `/auth` are
+//! `no-store`.
+//!
+//! [`CachePolicy`] sets the header after the handler has run, and leaves
+//! alone responses whose handler set `Cache-Control` itself. `public` rules
+//! only apply to successful `GET` and `HEAD` responses. Whatever the rules
+//! and the handler say, a response is `no-store` when it sets a cookie,
+//! when its route requires credentials according to the
+//! [`MetadataMap`], when the request carried credentials, or when it is a
+//! 401 challenge.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, Error, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use log::{error, info};
+
+use crate::middleware::api_key::API_KEY_HEADER;
+use crate::route_meta::MetadataMap;
+
+/// Rules used when `CACHE_POLICY_RULES` is not set.
+pub const DEFAULT_RULES: &str = "/admin=no-store,/auth=no-store";
+
+/// How responses under a path may be cached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheDirective {
+    /// Never stored, by browsers or intermediaries.
+    NoStore,
+    /// Stored by the browser only, for personalized responses.
+    Private,
+    /// Stored by anyone for the given time.
+    Public(Duration),
+}
+
+impl CacheDirective {
+    /// The `Cache-Control` value of the directive.
+    pub fn header_value(&self) -> HeaderValue {
+        match self {
+            CacheDirective::NoStore => HeaderValue::from_static("no-store"),
+            CacheDirective::Private => HeaderValue::from_static("private"),
+            CacheDirective::Public(max_age) => {
+                HeaderValue::from_str(&format!("public, max-age={}", max_age.as_secs())).unwrap()
+            }
+        }
+    }
+}
+
+/// A directive applied under a path prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheRule {
+    pub prefix: String,
+    pub directive: CacheDirective,
+}
+
+impl CacheRule {
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+            None => false,
+        }
+    }
+}
+
+/// Parses a list of `prefix=directive` rules.
+///
+/// # Returns
+///
+/// * `Result<Vec<CacheRule>, IoError>` - The rules, or an IoError describing the first invalid one.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// * A rule is missing the `=` separator, or its prefix does not start with `/`
+/// * A directive is not `no-store`, `private` or `public:SECS`
+pub fn parse_rules(value: &str) -> Result<Vec<CacheRule>, IoError> {
+    let invalid = |rule: &str, reason: &str| {
+        error!("Invalid cache policy rule '{}': {}", rule, reason);
+        IoError::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid cache policy rule '{}': {}", rule, reason),
+        )
+    };
+
+    let mut rules = Vec::new();
+    for rule in value.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (prefix, directive) = rule
+            .split_once('=')
+            .ok_or_else(|| invalid(rule, "expected prefix=directive"))?;
+        let prefix = prefix.trim();
+        if !prefix.starts_with('/') {
+            return Err(invalid(rule, "the prefix must start with '/'"));
+        }
+        let directive = match directive.trim().to_ascii_lowercase().as_str() {
+            "no-store" => CacheDirective::NoStore,
+            "private" => CacheDirective::Private,
+            other => match other.strip_prefix("public:").map(str::parse::<u64>) {
+                Some(Ok(secs)) => CacheDirective::Public(Duration::from_secs(secs)),
+                _ => return Err(invalid(rule, "expected no-store, private or public:SECS")),
+            },
+        };
+        rules.push(CacheRule {
+            prefix: prefix.to_string(),
+            directive,
+        });
+    }
+    Ok(rules)
+}
+
+/// Middleware setting `Cache-Control` from [`CacheRule`]s.
+#[derive(Clone, Default)]
+pub struct CachePolicy {
+    rules: Arc<Vec<CacheRule>>,
+}
+
+impl CachePolicy {
+    /// Creates the middleware applying `rules`.
+    pub fn new(rules: Vec<CacheRule>) -> Self {
+        CachePolicy {
+            rules: Arc::new(rules),
+        }
+    }
+
+    /// Reads `CACHE_POLICY_RULES`, or uses [`DEFAULT_RULES`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if a rule is invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        let rules =
+            parse_rules(&env::var("CACHE_POLICY_RULES").unwrap_or_else(|_| DEFAULT_RULES.into()))?;
+        info!("Cache policy: {} rules", rules.len());
+        Ok(CachePolicy::new(rules))
+    }
+
+    /// The rule with the longest prefix matching `path`.
+    pub fn rule_for(&self, path: &str) -> Option<&CacheRule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .max_by_key(|rule| rule.prefix.trim_end_matches('/').len())
+    }
+
+    /// The directive for a response to `req`, or `None` to leave it alone.
+    fn directive<B>(&self, req: &HttpRequest, res: &ServiceResponse<B>) -> Option<CacheDirective> {
+        if must_not_store(req, res) {
+            return Some(CacheDirective::NoStore);
+        }
+        if res.headers().contains_key(header::CACHE_CONTROL) {
+            return None;
+        }
+        match self.rule_for(req.path())?.directive {
+            CacheDirective::Public(_)
+                if !(matches!(*req.method(), Method::GET | Method::HEAD)
+                    && (res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED)) =>
+            {
+                None
+            }
+            directive => Some(directive),
+        }
+    }
+}
+
+/// Whether the response sets a cookie or answers a request that needed or
+/// carried credentials.
+fn must_not_store<B>(req: &HttpRequest, res: &ServiceResponse<B>) -> bool {
+    let auth_required = req
+        .app_data::<web::Data<MetadataMap>>()
+        .zip(req.match_pattern())
+        .and_then(|(routes, pattern)| {
+            routes
+                .get(req.method().as_str(), &pattern)
+                .map(|route| route.auth_required)
+        })
+        .unwrap_or(false);
+    res.headers().contains_key(header::SET_COOKIE)
+        || res.status() == StatusCode::UNAUTHORIZED
+        || auth_required
+        || req.headers().contains_key(header::AUTHORIZATION)
+        || req.headers().contains_key(API_KEY_HEADER)
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CachePolicy
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CachePolicyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CachePolicyMiddleware {
+            service,
+            policy: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`CachePolicy`].
+pub struct CachePolicyMiddleware<S> {
+    service: S,
+    policy: CachePolicy,
+}
+
+impl<S, B> Service<ServiceRequest> for CachePolicyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let policy = self.policy.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(directive) = policy.directive(res.request(), &res) {
+                res.headers_mut()
+                    .insert(header::CACHE_CONTROL, directive.header_value());
+            }
+            Ok(res)
+        })
+    }
+}
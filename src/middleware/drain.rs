@@ -0,0 +1,82 @@
+//! Connection drain middleware.
+//!
+//! Once the shutdown drain has started, responses are sent with
+//! `Connection: close` so keep-alive clients reconnect to another instance
+//! instead of reusing a connection to a server that is going away.
+
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::ConnectionType;
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+
+use crate::lifecycle::Lifecycle;
+
+/// Middleware that disables keep-alive while the server is draining.
+#[derive(Clone)]
+pub struct ConnectionDrain {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl ConnectionDrain {
+    /// Creates the middleware for the given lifecycle.
+    pub fn new(lifecycle: Arc<Lifecycle>) -> Self {
+        ConnectionDrain { lifecycle }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ConnectionDrain
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ConnectionDrainMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ConnectionDrainMiddleware {
+            service,
+            lifecycle: self.lifecycle.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`ConnectionDrain`].
+pub struct ConnectionDrainMiddleware<S> {
+    service: S,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl<S, B> Service<ServiceRequest> for ConnectionDrainMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let lifecycle = self.lifecycle.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if lifecycle.is_draining() {
+                res.response_mut()
+                    .head_mut()
+                    .set_connection_type(ConnectionType::Close);
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,283 @@
+//! Dry runs of mutating requests.
+//!
+//! With `ENABLE_DRY_RUN=true`, a POST, PUT, PATCH or DELETE request sending
+//! `X-Dry-Run: true` never reaches its handler. It is answered with 200 and
+//! what would have happened:
+//!
+//! ```json
+//! {"dry_run": true, "would_have": {"method": "POST", "path": "/auth/login",
+//!  "body": {"username": "alice"}, "handler": "/auth/login"}}
+//! ```
+//!
+//! `body` is the parsed JSON body, the text of any other body, or `null`
+//! when empty; bodies over [`MAX_DRY_RUN_BODY_BYTES`] get 413. The values
+//! of secret fields, such as `password`, `new_password` or `token`, are
+//! replaced with `[redacted]`, using the keys of the log [`Redactor`].
+//! `handler` is the pattern of the route that would have matched, or `null`
+//! if none would. Other methods and requests without the header are served
+//! as usual.
+//!
+//! The authentication of a scope or resource runs inside the route, after
+//! this middleware, so routes the [`MetadataMap`] marks as requiring
+//! credentials cannot be dry run: the `/admin` and `/tls` APIs, macaroon
+//! creation, WebAuthn registration and `/protected` get 403 instead.
+//!
+//! Dry runs are cheap to send and run no handler, so each client gets its
+//! own, lower rate limit: `DRY_RUN_RATE_LIMIT` per minute, per tenant when
+//! tenants are configured.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::BytesMut;
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use log::{debug, info};
+use serde_json::{json, Value};
+
+use crate::config_reload::REDACTED;
+use crate::error::ApiError;
+use crate::log_buffer::Redactor;
+use crate::middleware::tenant::Tenant;
+use crate::route_meta::MetadataMap;
+use crate::throttle::{self, Throttle};
+use crate::util::rate_limit::RateLimiter;
+use crate::util::real_ip::real_ip;
+
+/// Header asking for a dry run.
+pub const DRY_RUN_HEADER: &str = "x-dry-run";
+
+/// Largest body echoed back by a dry run.
+pub const MAX_DRY_RUN_BODY_BYTES: usize = 64 * 1024;
+
+/// Default dry runs per minute per client.
+pub const DEFAULT_DRY_RUNS_PER_MINUTE: u32 = 10;
+
+/// Middleware answering dry runs in place of the handler.
+#[derive(Clone)]
+pub struct DryRun {
+    limiter: Arc<RateLimiter>,
+    redactor: Arc<Redactor>,
+}
+
+impl DryRun {
+    /// Allows each client `per_minute` dry runs a minute.
+    pub fn new(per_minute: u32) -> Self {
+        let per_minute = per_minute.max(1);
+        DryRun {
+            limiter: Arc::new(RateLimiter::per_interval(
+                per_minute,
+                Duration::from_secs(60) / per_minute,
+            )),
+            redactor: Arc::new(Redactor::from_env()),
+        }
+    }
+
+    /// Reads `ENABLE_DRY_RUN` and `DRY_RUN_RATE_LIMIT`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<DryRun>` - The middleware, or `None` unless `ENABLE_DRY_RUN` is `true`.
+    pub fn from_env() -> Option<Self> {
+        if env::var("ENABLE_DRY_RUN").map_or(true, |v| v != "true") {
+            return None;
+        }
+        let per_minute = env::var("DRY_RUN_RATE_LIMIT")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|&r| r > 0)
+            .unwrap_or(DEFAULT_DRY_RUNS_PER_MINUTE);
+        info!("Dry runs enabled, {} per minute per client", per_minute);
+        Some(DryRun::new(per_minute))
+    }
+}
+
+impl Default for DryRun {
+    fn default() -> Self {
+        DryRun::new(DEFAULT_DRY_RUNS_PER_MINUTE)
+    }
+}
+
+/// Whether `req` asks for a dry run of a mutating method.
+fn is_dry_run(req: &ServiceRequest) -> bool {
+    matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) && req
+        .headers()
+        .get(DRY_RUN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Whether the route `req` would reach requires credentials.
+fn requires_auth(req: &ServiceRequest) -> bool {
+    req.app_data::<web::Data<MetadataMap>>()
+        .zip(req.match_pattern())
+        .and_then(|(routes, pattern)| {
+            routes
+                .get(req.method().as_str(), &pattern)
+                .map(|route| route.auth_required)
+        })
+        .unwrap_or(false)
+}
+
+/// The body as reported, secrets redacted: JSON when it parses as JSON,
+/// else text.
+fn describe_body(body: &[u8], content_type: Option<&str>, redactor: &Redactor) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    let json_type = content_type.is_some_and(|t| t == "application/json" || t.ends_with("+json"));
+    if json_type {
+        if let Ok(mut value) = serde_json::from_slice(body) {
+            redact_value(&mut value, redactor);
+            return value;
+        }
+    }
+    let text = String::from_utf8_lossy(body);
+    Value::String(redactor.redact(&text).into_owned())
+}
+
+/// Replaces the values of secret fields, at any depth, with `[redacted]`.
+fn redact_value(value: &mut Value, redactor: &Redactor) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if redactor.is_secret(key) {
+                    *field = json!(REDACTED);
+                } else {
+                    redact_value(field, redactor);
+                }
+            }
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| redact_value(item, redactor)),
+        _ => {}
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for DryRun
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = DryRunMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DryRunMiddleware {
+            service,
+            limiter: self.limiter.clone(),
+            redactor: self.redactor.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`DryRun`].
+pub struct DryRunMiddleware<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+    redactor: Arc<Redactor>,
+}
+
+impl<S, B> Service<ServiceRequest> for DryRunMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if !is_dry_run(&req) {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        }
+
+        let client = format!("dry-run:{}", real_ip(req.request()).node);
+        let key = match req.extensions().get::<Tenant>() {
+            Some(tenant) => tenant.scoped_key(&client),
+            None => client,
+        };
+        if let Err(retry_after) = self.limiter.check(&key) {
+            let res = throttle::respond(
+                req.request(),
+                Throttle::RateLimited,
+                ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    "Too many dry runs",
+                ),
+                retry_after,
+            );
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+        if requires_auth(&req) {
+            let res = ApiError::new(
+                StatusCode::FORBIDDEN,
+                "dry_run_not_allowed",
+                "Routes requiring credentials cannot be dry run",
+            )
+            .error_response();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let redactor = self.redactor.clone();
+        Box::pin(async move {
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > MAX_DRY_RUN_BODY_BYTES {
+                    let res = ApiError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "payload_too_large",
+                        "The body is too large for a dry run",
+                    )
+                    .error_response();
+                    return Ok(req.into_response(res).map_into_right_body());
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            let handler = req.match_pattern();
+            debug!(
+                "Dry run of {} {} (route {:?})",
+                req.method(),
+                req.path(),
+                handler
+            );
+            let content_type = req.mime_type().ok().flatten();
+            let res = HttpResponse::Ok().json(json!({
+                "dry_run": true,
+                "would_have": {
+                    "method": req.method().as_str(),
+                    "path": req.path(),
+                    "body": describe_body(
+                        &body,
+                        content_type.as_ref().map(|m| m.essence_str()),
+                        &redactor,
+                    ),
+                    "handler": handler,
+                },
+            }));
+            Ok(req.into_response(res).map_into_right_body())
+        })
+    }
+}
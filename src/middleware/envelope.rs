@@ -0,0 +1,164 @@
+//! Response envelope middleware.
+//!
+//! Injects standard fields (`request_id`, `timestamp` and `version`) into
+//! every successful JSON response so clients can rely on a uniform shape.
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{error, Error};
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+use serde_json::{Map, Value};
+
+use crate::middleware::request_id::request_id;
+
+/// Middleware that wraps successful JSON responses in a standard envelope.
+///
+/// * JSON objects have the standard fields merged into them.
+/// * JSON arrays and scalars are wrapped as `{"data": <body>, ...}`.
+/// * Error responses (4xx/5xx), non-JSON responses and bodies that fail to
+///   parse as JSON are passed through untouched.
+#[derive(Clone)]
+pub struct ResponseEnvelope {
+    version: Rc<str>,
+}
+
+impl ResponseEnvelope {
+    /// Creates an envelope that reports the crate version.
+    pub fn new() -> Self {
+        Self::with_version(env!("CARGO_PKG_VERSION"))
+    }
+
+    /// Creates an envelope that reports the given version string.
+    pub fn with_version(version: &str) -> Self {
+        ResponseEnvelope {
+            version: Rc::from(version),
+        }
+    }
+}
+
+impl Default for ResponseEnvelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ResponseEnvelope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = ResponseEnvelopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ResponseEnvelopeMiddleware {
+            service: Rc::new(service),
+            version: self.version.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`ResponseEnvelope`].
+pub struct ResponseEnvelopeMiddleware<S> {
+    service: Rc<S>,
+    version: Rc<str>,
+}
+
+impl<S, B> Service<ServiceRequest> for ResponseEnvelopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = request_id(&req);
+        let version = self.version.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+
+            let status = res.status();
+            if status.is_client_error() || status.is_server_error() || !is_json(&res) {
+                return Ok(res.map_into_boxed_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let bytes = body::to_bytes(body).await.map_err(|e| {
+                let e: Box<dyn std::error::Error> = e.into();
+                error::ErrorInternalServerError(e.to_string())
+            })?;
+
+            let body = match serde_json::from_slice::<Value>(&bytes) {
+                Ok(value) => {
+                    let value = wrap(value, &request_id, &version);
+                    serde_json::to_vec(&value).map_err(error::ErrorInternalServerError)?
+                }
+                Err(e) => {
+                    warn!("Response declared as JSON but failed to parse: {}", e);
+                    bytes.to_vec()
+                }
+            };
+
+            let res = res.set_body(body).map_into_boxed_body();
+            Ok(ServiceResponse::new(req, res))
+        })
+    }
+}
+
+/// Merges the standard fields into `value`.
+///
+/// Objects receive the fields directly; any other JSON value is placed under
+/// a `data` key of a new object.
+///
+/// # Returns
+///
+/// * `Value` - The enveloped JSON object.
+pub fn wrap(value: Value, request_id: &str, version: &str) -> Value {
+    let mut object = match value {
+        Value::Object(object) => object,
+        other => {
+            let mut object = Map::new();
+            object.insert("data".to_string(), other);
+            object
+        }
+    };
+
+    object.insert(
+        "request_id".to_string(),
+        Value::String(request_id.to_string()),
+    );
+    object.insert(
+        "timestamp".to_string(),
+        Value::String(chrono::Utc::now().to_rfc3339()),
+    );
+    object.insert("version".to_string(), Value::String(version.to_string()));
+
+    Value::Object(object)
+}
+
+/// Returns `true` if the response declares an `application/json` body.
+fn is_json<B>(res: &ServiceResponse<B>) -> bool {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().eq_ignore_ascii_case("application/json"))
+        .unwrap_or(false)
+}
@@ -0,0 +1,68 @@
+//! Configurable headers added to every response.
+//!
+//! `EXTRA_RESPONSE_HEADERS` holds comma-separated `Name:Value` pairs, e.g.
+//! `X-Region:eu-west-1,X-Cache-Marker:edge`. The pairs are validated once at
+//! startup and applied with Actix's `DefaultHeaders` middleware, so headers
+//! explicitly set by a handler take precedence.
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::DefaultHeaders;
+use log::error;
+use std::io::{Error as IoError, ErrorKind};
+
+/// Parses a list of `Name:Value` pairs.
+///
+/// # Returns
+///
+/// * `Result<Vec<(HeaderName, HeaderValue)>, IoError>` - The validated headers, or an IoError describing the first invalid entry.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// * An entry is missing the `:` separator
+/// * A header name is not a valid HTTP token
+/// * A header value contains characters not allowed in header values
+pub fn parse_extra_headers(value: &str) -> Result<Vec<(HeaderName, HeaderValue)>, IoError> {
+    let mut headers = Vec::new();
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, value) = entry.split_once(':').ok_or_else(|| {
+            error!(
+                "Invalid extra response header '{}': expected Name:Value",
+                entry
+            );
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid extra response header '{}'", entry),
+            )
+        })?;
+
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| {
+            error!("Invalid extra response header name '{}': {}", name, e);
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid extra response header name '{}'", name),
+            )
+        })?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|e| {
+            error!("Invalid value for extra response header '{}': {}", name, e);
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid value for extra response header '{}'", name),
+            )
+        })?;
+
+        headers.push((name, value));
+    }
+
+    Ok(headers)
+}
+
+/// Builds the middleware that adds `headers` to every response.
+pub fn extra_headers(headers: &[(HeaderName, HeaderValue)]) -> DefaultHeaders {
+    headers
+        .iter()
+        .fold(DefaultHeaders::new(), |middleware, (name, value)| {
+            middleware.add((name.clone(), value.clone()))
+        })
+}
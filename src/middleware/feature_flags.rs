@@ -0,0 +1,88 @@
+//! Feature flag debugging header.
+//!
+//! With `FEATURE_FLAGS_HEADER=true`, meant for development, every response
+//! carries `X-Feature-Flags` listing each declared flag as evaluated for
+//! that request, e.g. `new-checkout=on, beta-search=off`.
+
+use std::future::{ready, Ready};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, Error};
+use futures_util::future::LocalBoxFuture;
+
+use crate::flags::Flags;
+
+/// Header listing the evaluated flags.
+pub const FEATURE_FLAGS_HEADER: &str = "x-feature-flags";
+
+/// Middleware adding the `X-Feature-Flags` header.
+#[derive(Clone, Copy, Default)]
+pub struct FeatureFlagsHeader;
+
+impl<S, B> Transform<S, ServiceRequest> for FeatureFlagsHeader
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = FeatureFlagsHeaderMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(FeatureFlagsHeaderMiddleware { service }))
+    }
+}
+
+/// Service produced by [`FeatureFlagsHeader`].
+pub struct FeatureFlagsHeaderMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for FeatureFlagsHeaderMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            // Evaluated after the handler so authentication has run
+            let Some(flags) = res.request().app_data::<web::Data<Flags>>().cloned() else {
+                return Ok(res);
+            };
+            let listed = flags
+                .names()
+                .iter()
+                .map(|name| {
+                    let state = if flags.is_enabled(name, res.request()) {
+                        "on"
+                    } else {
+                        "off"
+                    };
+                    format!("{}={}", name, state)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            if listed.is_empty() {
+                return Ok(res);
+            }
+            if let Ok(value) = HeaderValue::from_str(&listed) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(FEATURE_FLAGS_HEADER), value);
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,311 @@
+//! Request header hardening.
+//!
+//! Actix's HTTP/1.x codec has fixed limits: a request head over 32 KiB or
+//! with more than 96 headers never reaches the application. Nothing smaller
+//! is configurable on the server builder, so this middleware enforces
+//! `MAX_HEADER_COUNT`, `MAX_HEADER_SIZE` and `MAX_HEADER_BYTES` on every
+//! request, answering 431 when one is exceeded.
+//!
+//! Unless `REJECT_SMUGGLING_PATTERNS` is `false`, it also rejects, with 400,
+//! requests sending `Content-Length` together with `Transfer-Encoding`.
+//! Proxies in front of or behind this hop may frame such requests
+//! differently, which is the basis of request smuggling. Rejected requests
+//! close the connection so no unread body bytes are parsed as a following
+//! request, and smuggling attempts are logged with the peer address.
+//!
+//! More than one `Content-Length` is ambiguous too, but on an HTTP/1.x
+//! connection the codec answers it with 400 before any middleware runs, so
+//! those rejections are neither counted nor logged. The check here covers
+//! requests that reach the application by other means.
+//!
+//! Headers continued with obsolete line folding are rejected by the codec
+//! the same way, with 400 and a closed connection. They cannot be logged
+//! with the peer address: the codec reports the parse error to no one but
+//! its `trace` output, and the TLS listeners give no access to the bytes
+//! before it.
+//!
+//! The method token and the request line (method, target and version) are
+//! limited too, by `MAX_METHOD_LENGTH` and `MAX_REQUEST_LINE_BYTES`, so a
+//! giant made-up method or target is answered with 400 and logged with the
+//! peer address. The defaults are far above anything a legitimate client
+//! sends.
+//!
+//! Every rejection increments `header_rejections_total{reason=...}`.
+
+use std::env;
+use std::future::{ready, Ready};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::{ConnectionType, Method, StatusCode, Uri};
+use actix_web::{Error, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::util::real_ip;
+
+/// Default for `MAX_HEADER_COUNT`.
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 64;
+/// Default for `MAX_HEADER_SIZE`.
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 8 * 1024;
+/// Default for `MAX_HEADER_BYTES`.
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+/// Default for `MAX_METHOD_LENGTH`.
+pub const DEFAULT_MAX_METHOD_LENGTH: usize = 32;
+/// Default for `MAX_REQUEST_LINE_BYTES`.
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 16 * 1024;
+
+/// Why a request was rejected, used as the `reason` metric label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderViolation {
+    TooManyHeaders,
+    HeaderTooLarge,
+    HeadersTooLarge,
+    DuplicateContentLength,
+    ContentLengthWithTransferEncoding,
+    MethodTooLong,
+    RequestLineTooLong,
+}
+
+impl HeaderViolation {
+    /// Returns the metric label for the violation.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HeaderViolation::TooManyHeaders => "too_many_headers",
+            HeaderViolation::HeaderTooLarge => "header_too_large",
+            HeaderViolation::HeadersTooLarge => "headers_too_large",
+            HeaderViolation::DuplicateContentLength => "duplicate_content_length",
+            HeaderViolation::ContentLengthWithTransferEncoding => {
+                "content_length_with_transfer_encoding"
+            }
+            HeaderViolation::MethodTooLong => "method_too_long",
+            HeaderViolation::RequestLineTooLong => "request_line_too_long",
+        }
+    }
+
+    /// Returns whether the violation is a request smuggling pattern rather
+    /// than an exceeded limit.
+    pub fn is_smuggling(&self) -> bool {
+        matches!(
+            self,
+            HeaderViolation::DuplicateContentLength
+                | HeaderViolation::ContentLengthWithTransferEncoding
+        )
+    }
+
+    /// Returns whether the violation concerns the request line.
+    pub fn is_request_line(&self) -> bool {
+        matches!(
+            self,
+            HeaderViolation::MethodTooLong | HeaderViolation::RequestLineTooLong
+        )
+    }
+
+    fn error(&self) -> ApiError {
+        let (status, message) = match self {
+            HeaderViolation::TooManyHeaders => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "The request has too many headers",
+            ),
+            HeaderViolation::HeaderTooLarge => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "A request header is too large",
+            ),
+            HeaderViolation::HeadersTooLarge => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "The request headers are too large",
+            ),
+            HeaderViolation::DuplicateContentLength => (
+                StatusCode::BAD_REQUEST,
+                "The request has more than one Content-Length header",
+            ),
+            HeaderViolation::ContentLengthWithTransferEncoding => (
+                StatusCode::BAD_REQUEST,
+                "The request has both Content-Length and Transfer-Encoding headers",
+            ),
+            HeaderViolation::MethodTooLong => {
+                (StatusCode::BAD_REQUEST, "The request method is too long")
+            }
+            HeaderViolation::RequestLineTooLong => {
+                (StatusCode::BAD_REQUEST, "The request line is too long")
+            }
+        };
+        ApiError::new(status, self.reason(), message)
+    }
+}
+
+/// Limits applied to request headers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderLimits {
+    /// Most header lines a request may carry.
+    pub max_count: usize,
+    /// Largest single header, counting its name and value.
+    pub max_size: usize,
+    /// Largest total of all header names and values.
+    pub max_bytes: usize,
+    /// Whether requests with ambiguous framing are rejected.
+    pub reject_smuggling: bool,
+    /// Longest accepted method token.
+    pub max_method_length: usize,
+    /// Longest accepted request line, counting method, target and version.
+    pub max_request_line: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            max_count: DEFAULT_MAX_HEADER_COUNT,
+            max_size: DEFAULT_MAX_HEADER_SIZE,
+            max_bytes: DEFAULT_MAX_HEADER_BYTES,
+            reject_smuggling: true,
+            max_method_length: DEFAULT_MAX_METHOD_LENGTH,
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Reads `MAX_HEADER_COUNT`, `MAX_HEADER_SIZE`, `MAX_HEADER_BYTES`,
+    /// `REJECT_SMUGGLING_PATTERNS`, `MAX_METHOD_LENGTH` and
+    /// `MAX_REQUEST_LINE_BYTES`, using the defaults for unset or invalid
+    /// values.
+    pub fn from_env() -> Self {
+        let read = |var: &str, default: usize| {
+            env::var(var)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        };
+        HeaderLimits {
+            max_count: read("MAX_HEADER_COUNT", DEFAULT_MAX_HEADER_COUNT),
+            max_size: read("MAX_HEADER_SIZE", DEFAULT_MAX_HEADER_SIZE),
+            max_bytes: read("MAX_HEADER_BYTES", DEFAULT_MAX_HEADER_BYTES),
+            reject_smuggling: env::var("REJECT_SMUGGLING_PATTERNS")
+                .map(|v| v != "false")
+                .unwrap_or(true),
+            max_method_length: read("MAX_METHOD_LENGTH", DEFAULT_MAX_METHOD_LENGTH),
+            max_request_line: read("MAX_REQUEST_LINE_BYTES", DEFAULT_MAX_REQUEST_LINE),
+        }
+    }
+
+    /// Checks the request line of a request for `uri` with `method`.
+    pub fn check_request_line(&self, method: &Method, uri: &Uri) -> Result<(), HeaderViolation> {
+        let method = method.as_str().len();
+        if method > self.max_method_length {
+            return Err(HeaderViolation::MethodTooLong);
+        }
+        let target = uri.path_and_query().map_or(1, |p| p.as_str().len());
+        // "METHOD SP target SP HTTP/x.y"
+        if method + 1 + target + 1 + "HTTP/1.1".len() > self.max_request_line {
+            return Err(HeaderViolation::RequestLineTooLong);
+        }
+        Ok(())
+    }
+
+    /// Checks `headers`, returning the first violation found.
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), HeaderViolation> {
+        if self.reject_smuggling {
+            let content_lengths = headers.get_all(header::CONTENT_LENGTH).count();
+            if content_lengths > 1 {
+                return Err(HeaderViolation::DuplicateContentLength);
+            }
+            if content_lengths == 1 && headers.contains_key(header::TRANSFER_ENCODING) {
+                return Err(HeaderViolation::ContentLengthWithTransferEncoding);
+            }
+        }
+
+        if headers.len() > self.max_count {
+            return Err(HeaderViolation::TooManyHeaders);
+        }
+        let mut total = 0;
+        for (name, value) in headers.iter() {
+            let size = name.as_str().len() + value.len();
+            if size > self.max_size {
+                return Err(HeaderViolation::HeaderTooLarge);
+            }
+            total += size;
+        }
+        if total > self.max_bytes {
+            return Err(HeaderViolation::HeadersTooLarge);
+        }
+        Ok(())
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for HeaderLimits
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HeaderLimitsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HeaderLimitsMiddleware {
+            service,
+            limits: *self,
+        }))
+    }
+}
+
+/// Service produced by [`HeaderLimits`].
+pub struct HeaderLimitsMiddleware<S> {
+    service: S,
+    limits: HeaderLimits,
+}
+
+impl<S, B> Service<ServiceRequest> for HeaderLimitsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let checked = self
+            .limits
+            .check_request_line(req.method(), req.uri())
+            .and_then(|()| self.limits.check(req.headers()));
+        if let Err(violation) = checked {
+            Metrics::global().inc("header_rejections_total", &[("reason", violation.reason())]);
+            // The connection peer, as reported by PROXY protocol if in use;
+            // forwarding headers of a rejected request are not trusted
+            let peer = || {
+                real_ip::peer(req.request())
+                    .map_or_else(|| "unknown peer".to_string(), |p| p.ip().to_string())
+            };
+            if violation.is_smuggling() {
+                warn!(
+                    "Rejected possible request smuggling ({}) from {}",
+                    violation.reason(),
+                    peer()
+                );
+            } else if violation.is_request_line() {
+                warn!(
+                    "Rejected over-long request line ({}) from {}",
+                    violation.reason(),
+                    peer()
+                );
+            }
+            let mut res = violation.error().error_response();
+            res.head_mut().set_connection_type(ConnectionType::Close);
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
@@ -0,0 +1,351 @@
+//! Stripping and normalizing request headers before anything reads them.
+//!
+//! Some headers are only meaningful when a proxy we trust set them:
+//! forwarding headers, `X-Real-IP`, `X-Internal-Auth`. A client talking to
+//! the server directly can send them too, hoping a handler believes them.
+//! [`HeaderSanitizer`] removes them from requests whose peer is not in
+//! `TRUSTED_PROXIES`; the list is [`DEFAULT_STRIPPED_HEADERS`] plus any names
+//! in `STRIP_UNTRUSTED_HEADERS`. Requests from trusted proxies keep them.
+//!
+//! It also normalizes duplicates: a request with more than one `Host` is
+//! rejected with 400, as servers disagree on which one counts, and repeated
+//! `Accept` headers are joined into one list.
+//!
+//! Finally it sets `X-Internal-Received-At` to the time the request arrived
+//! (RFC 3339, milliseconds, UTC), replacing any value the client sent, even
+//! through a trusted proxy. Handlers can rely on it not being spoofed.
+//!
+//! On the way out it guards against response splitting: a response header
+//! value holding `\r` or `\n`, say user input echoed into a `Location`,
+//! could end the header block early and inject headers or a body. Such
+//! headers are removed, or with `HEADER_SANITIZE_MODE=encode` their line
+//! breaks are percent-encoded, and a warning names the header.
+//! `HeaderValue` already refuses line breaks, so only values built with
+//! its unchecked constructors can get this far; handlers echoing input
+//! into a header should build the value with [`sanitize_value`].
+//!
+//! The middleware must be the outermost one so that no other middleware
+//! sees the stripped headers, and so that it checks the response headers
+//! last.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{Error, ResponseError};
+use chrono::{SecondsFormat, Utc};
+use futures_util::future::LocalBoxFuture;
+use log::{debug, error, warn};
+
+use crate::error::ApiError;
+use crate::util::real_ip::{self, TrustedProxies};
+
+/// Server-owned header holding the time the request was received.
+pub const RECEIVED_AT_HEADER: &str = "x-internal-received-at";
+
+/// Headers removed from requests that did not come through a trusted proxy.
+pub const DEFAULT_STRIPPED_HEADERS: &[&str] = &[
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "x-client-ip",
+    "x-internal-auth",
+    "x-original-url",
+    "x-rewrite-url",
+];
+
+/// What to do with a response header value containing a line break.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SanitizeMode {
+    /// Remove the header.
+    #[default]
+    Remove,
+    /// Replace `\r` with `%0D` and `\n` with `%0A`.
+    Encode,
+}
+
+impl FromStr for SanitizeMode {
+    type Err = IoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "remove" => Ok(SanitizeMode::Remove),
+            "encode" => Ok(SanitizeMode::Encode),
+            _ => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "HEADER_SANITIZE_MODE must be remove or encode, got '{}'",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// Middleware stripping untrusted headers and setting server-owned ones.
+#[derive(Clone)]
+pub struct HeaderSanitizer {
+    stripped: Arc<Vec<HeaderName>>,
+    mode: SanitizeMode,
+}
+
+impl Default for HeaderSanitizer {
+    fn default() -> Self {
+        HeaderSanitizer::new(
+            DEFAULT_STRIPPED_HEADERS
+                .iter()
+                .map(|&name| HeaderName::from_static(name)),
+        )
+    }
+}
+
+impl HeaderSanitizer {
+    /// Strips `stripped` from requests of untrusted peers.
+    pub fn new(stripped: impl IntoIterator<Item = HeaderName>) -> Self {
+        let mut names: Vec<HeaderName> = stripped.into_iter().collect();
+        names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        names.dedup();
+        HeaderSanitizer {
+            stripped: Arc::new(names),
+            mode: SanitizeMode::default(),
+        }
+    }
+
+    /// Handles response headers containing line breaks with `mode`.
+    pub fn with_mode(mut self, mode: SanitizeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The handling of response headers containing line breaks.
+    pub fn mode(&self) -> SanitizeMode {
+        self.mode
+    }
+
+    /// Adds the comma-separated header names in `list` to the stripped ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an entry is not a valid header name.
+    pub fn extended(&self, list: &str) -> Result<Self, IoError> {
+        let mut names = self.stripped.to_vec();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let name = HeaderName::from_bytes(entry.as_bytes()).map_err(|e| {
+                error!(
+                    "Invalid header name '{}' in STRIP_UNTRUSTED_HEADERS: {}",
+                    entry, e
+                );
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid header name '{}' in STRIP_UNTRUSTED_HEADERS", entry),
+                )
+            })?;
+            names.push(name);
+        }
+        Ok(HeaderSanitizer::new(names).with_mode(self.mode))
+    }
+
+    /// The default list extended with `STRIP_UNTRUSTED_HEADERS`, handling
+    /// line breaks in response headers as `HEADER_SANITIZE_MODE` says.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `STRIP_UNTRUSTED_HEADERS` holds an invalid name
+    /// or `HEADER_SANITIZE_MODE` is neither `remove` nor `encode`.
+    pub fn from_env() -> Result<Self, IoError> {
+        let mode = match env::var("HEADER_SANITIZE_MODE") {
+            Ok(mode) => mode.parse()?,
+            Err(_) => SanitizeMode::default(),
+        };
+        Ok(HeaderSanitizer::default()
+            .extended(&env::var("STRIP_UNTRUSTED_HEADERS").unwrap_or_default())?
+            .with_mode(mode))
+    }
+
+    /// The header names stripped from untrusted peers, sorted.
+    pub fn stripped(&self) -> &[HeaderName] {
+        &self.stripped
+    }
+
+    /// Removes the stripped headers from `headers`, returning those removed.
+    pub fn strip(&self, headers: &mut HeaderMap) -> Vec<HeaderName> {
+        self.stripped
+            .iter()
+            .filter(|name| headers.remove(*name).next().is_some())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Removes or encodes the response headers whose value contains `\r` or
+/// `\n`, returning their names.
+pub fn sanitize_response_headers(headers: &mut HeaderMap, mode: SanitizeMode) -> Vec<HeaderName> {
+    // Values of one name are iterated together
+    let mut names: Vec<HeaderName> = headers
+        .iter()
+        .filter(|(_, value)| has_line_break(value))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.dedup();
+    for name in &names {
+        let values: Vec<HeaderValue> = headers.get_all(name).cloned().collect();
+        headers.remove(name);
+        for value in values {
+            if let Some(value) = sanitize_value(value.as_bytes(), mode) {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+    names
+}
+
+/// Builds a header value from `value`, which may come from user input:
+/// `None` if it holds a line break and `mode` is
+/// [`Remove`](SanitizeMode::Remove), or if it is not a valid value
+/// otherwise; with [`Encode`](SanitizeMode::Encode), line breaks are
+/// percent-encoded.
+pub fn sanitize_value(value: &[u8], mode: SanitizeMode) -> Option<HeaderValue> {
+    if !value.iter().any(|&b| b == b'\r' || b == b'\n') {
+        return HeaderValue::from_bytes(value).ok();
+    }
+    if mode == SanitizeMode::Remove {
+        return None;
+    }
+    let mut encoded = Vec::with_capacity(value.len() + 8);
+    for &byte in value {
+        match byte {
+            b'\r' => encoded.extend_from_slice(b"%0D"),
+            b'\n' => encoded.extend_from_slice(b"%0A"),
+            _ => encoded.push(byte),
+        }
+    }
+    HeaderValue::from_bytes(&encoded).ok()
+}
+
+fn has_line_break(value: &HeaderValue) -> bool {
+    value.as_bytes().iter().any(|&b| b == b'\r' || b == b'\n')
+}
+
+/// Joins repeated `Accept` headers into one.
+///
+/// # Errors
+///
+/// Returns an error if there is more than one `Host`.
+pub fn normalize_duplicates(headers: &mut HeaderMap) -> Result<(), ApiError> {
+    if headers.get_all(header::HOST).count() > 1 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "duplicate_host",
+            "The request has more than one Host header",
+        ));
+    }
+    if headers.get_all(header::ACCEPT).count() > 1 {
+        let joined: Vec<&[u8]> = headers
+            .get_all(header::ACCEPT)
+            .map(HeaderValue::as_bytes)
+            .collect();
+        // Every part is a valid header value, so the join is one too
+        if let Ok(value) = HeaderValue::from_bytes(&joined.join(&b", "[..])) {
+            headers.insert(header::ACCEPT, value);
+        }
+    }
+    Ok(())
+}
+
+impl<S, B> Transform<S, ServiceRequest> for HeaderSanitizer
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = HeaderSanitizerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HeaderSanitizerMiddleware {
+            service,
+            sanitizer: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`HeaderSanitizer`].
+pub struct HeaderSanitizerMiddleware<S> {
+    service: S,
+    sanitizer: HeaderSanitizer,
+}
+
+impl<S, B> Service<ServiceRequest> for HeaderSanitizerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let received_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let peer = real_ip::peer(req.request());
+        let trusted = match (peer, req.app_data::<TrustedProxies>()) {
+            (Some(peer), Some(proxies)) => proxies.contains(&peer.ip()),
+            _ => false,
+        };
+
+        if let Err(e) = normalize_duplicates(req.headers_mut()) {
+            warn!(
+                "Rejected request with duplicate Host headers from {}",
+                peer.map_or_else(|| "unknown peer".to_string(), |p| p.ip().to_string())
+            );
+            let res = e.error_response();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+        if !trusted {
+            let stripped = self.sanitizer.strip(req.headers_mut());
+            if !stripped.is_empty() {
+                debug!(
+                    "Stripped headers {:?} from untrusted peer {:?}",
+                    stripped, peer
+                );
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&received_at) {
+            req.headers_mut()
+                .insert(HeaderName::from_static(RECEIVED_AT_HEADER), value);
+        }
+
+        let mode = self.sanitizer.mode;
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let sanitized = sanitize_response_headers(res.headers_mut(), mode);
+            if !sanitized.is_empty() {
+                warn!(
+                    "Response headers {:?} of {} {} contained line breaks and were {}",
+                    sanitized,
+                    res.request().method(),
+                    res.request().path(),
+                    match mode {
+                        SanitizeMode::Remove => "removed",
+                        SanitizeMode::Encode => "encoded",
+                    }
+                );
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
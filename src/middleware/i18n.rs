@@ -0,0 +1,307 @@
+//! Response body translation middleware.
+//!
+//! Handlers may write `{key}` placeholders into `text/plain` and
+//! `application/json` responses. [`I18nRewriter`] picks a language from the
+//! request's `Accept-Language` header and replaces each placeholder with the
+//! string of that key from `TRANSLATIONS_DIR`, a directory of flat JSON
+//! objects named after their language code (`en.json`, `fr.json`, ...).
+//!
+//! Keys missing from the negotiated language are taken from English.
+//! Placeholders no translation defines are left as they are, so literal
+//! braces in bodies survive. Values substituted into JSON bodies are escaped
+//! so the document stays valid.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{ACCEPT_LANGUAGE, CONTENT_TYPE};
+use actix_web::{error, web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::{error, info};
+use serde_json::json;
+
+use crate::i18n::parse_accept_language;
+
+/// Language used when negotiation finds no match, and for missing keys.
+pub const FALLBACK_LANGUAGE: &str = "en";
+
+/// Translated strings keyed by language code, then by placeholder key.
+#[derive(Clone, Debug, Default)]
+pub struct Translations {
+    languages: BTreeMap<String, HashMap<String, String>>,
+}
+
+impl Translations {
+    /// Creates translations from in-memory `(language, strings)` pairs.
+    pub fn new<I>(languages: I) -> Self
+    where
+        I: IntoIterator<Item = (String, HashMap<String, String>)>,
+    {
+        Translations {
+            languages: languages
+                .into_iter()
+                .map(|(code, strings)| (code.to_ascii_lowercase(), strings))
+                .collect(),
+        }
+    }
+
+    /// Loads every `<language>.json` file in `dir`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// * The directory or one of its JSON files cannot be read
+    /// * A file is not a JSON object of strings
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, IoError> {
+        let dir = dir.as_ref();
+        let mut languages = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "json").unwrap_or(true) {
+                continue;
+            }
+            let code = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(code) => code.to_string(),
+                None => continue,
+            };
+            let strings: HashMap<String, String> = serde_json::from_slice(&fs::read(&path)?)
+                .map_err(|e| {
+                    error!("Invalid translations file '{}': {}", path.display(), e);
+                    IoError::new(ErrorKind::InvalidData, e)
+                })?;
+            languages.push((code, strings));
+        }
+        Ok(Translations::new(languages))
+    }
+
+    /// Loads translations from `TRANSLATIONS_DIR`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Translations>, IoError>` - The translations, or `None` if `TRANSLATIONS_DIR` is not set.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let dir = match env::var("TRANSLATIONS_DIR") {
+            Ok(dir) if !dir.is_empty() => dir,
+            _ => return Ok(None),
+        };
+        let translations = Translations::load(&dir).map_err(|e| {
+            error!("Failed to load translations from '{}': {}", dir, e);
+            e
+        })?;
+        info!(
+            "Translating response bodies into: {}",
+            translations.languages().join(", ")
+        );
+        Ok(Some(translations))
+    }
+
+    /// Returns the available language codes, sorted.
+    pub fn languages(&self) -> Vec<String> {
+        self.languages.keys().cloned().collect()
+    }
+
+    /// Picks the language for an `Accept-Language` header.
+    ///
+    /// Entries are tried by descending quality, each matching a language
+    /// code exactly or by its primary subtag (so `en-US` matches `en`).
+    /// Falls back to English.
+    pub fn negotiate(&self, accept_language: Option<&str>) -> String {
+        let candidates = accept_language
+            .map(parse_accept_language)
+            .unwrap_or_default();
+        for candidate in candidates {
+            let candidate = candidate.to_ascii_lowercase();
+            if self.languages.contains_key(&candidate) {
+                return candidate;
+            }
+            let primary = candidate.split('-').next().unwrap_or_default();
+            if self.languages.contains_key(primary) {
+                return primary.to_string();
+            }
+        }
+        FALLBACK_LANGUAGE.to_string()
+    }
+
+    /// Returns the string for `key` in `language`, falling back to English.
+    pub fn lookup(&self, language: &str, key: &str) -> Option<&str> {
+        [language, FALLBACK_LANGUAGE]
+            .iter()
+            .filter_map(|code| self.languages.get(*code))
+            .find_map(|strings| strings.get(key))
+            .map(String::as_str)
+    }
+
+    /// Replaces the `{key}` placeholders of `text` with their strings in
+    /// `language`. With `json_escape`, substituted strings are escaped for
+    /// use inside a JSON string.
+    pub fn rewrite(&self, language: &str, text: &str, json_escape: bool) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let translated = after
+                .find('}')
+                .map(|end| &after[..end])
+                .filter(|key| is_key(key))
+                .and_then(|key| Some((key, self.lookup(language, key)?)));
+            match translated {
+                Some((key, value)) => {
+                    if json_escape {
+                        let quoted = serde_json::Value::from(value).to_string();
+                        out.push_str(&quoted[1..quoted.len() - 1]);
+                    } else {
+                        out.push_str(value);
+                    }
+                    rest = &after[key.len() + 1..];
+                }
+                None => {
+                    out.push('{');
+                    rest = after;
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Placeholder keys are non-empty runs of ASCII letters, digits, `_`, `-`
+/// and `.`.
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+}
+
+/// Kinds of bodies that are rewritten.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BodyKind {
+    Text,
+    Json,
+}
+
+fn body_kind<B>(res: &ServiceResponse<B>) -> Option<BodyKind> {
+    let mime = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())?
+        .trim()
+        .to_ascii_lowercase();
+    match mime.as_str() {
+        "text/plain" => Some(BodyKind::Text),
+        "application/json" => Some(BodyKind::Json),
+        _ => None,
+    }
+}
+
+/// Middleware translating `{key}` placeholders in response bodies.
+#[derive(Clone)]
+pub struct I18nRewriter {
+    translations: Arc<Translations>,
+}
+
+impl I18nRewriter {
+    /// Creates the middleware for `translations`.
+    pub fn new(translations: Arc<Translations>) -> Self {
+        I18nRewriter { translations }
+    }
+}
+
+impl Default for I18nRewriter {
+    fn default() -> Self {
+        I18nRewriter::new(Arc::new(Translations::default()))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for I18nRewriter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = I18nRewriterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(I18nRewriterMiddleware {
+            service: Rc::new(service),
+            translations: self.translations.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`I18nRewriter`].
+pub struct I18nRewriterMiddleware<S> {
+    service: Rc<S>,
+    translations: Arc<Translations>,
+}
+
+impl<S, B> Service<ServiceRequest> for I18nRewriterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let language = self.translations.negotiate(
+            req.headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|v| v.to_str().ok()),
+        );
+        let translations = self.translations.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let kind = match body_kind(&res) {
+                Some(kind) => kind,
+                None => return Ok(res.map_into_boxed_body()),
+            };
+
+            let (req, res) = res.into_parts();
+            let (res, body) = res.into_parts();
+            let bytes = body::to_bytes(body).await.map_err(|e| {
+                let e: Box<dyn std::error::Error> = e.into();
+                error::ErrorInternalServerError(e.to_string())
+            })?;
+
+            let body = match std::str::from_utf8(&bytes) {
+                Ok(text) => translations
+                    .rewrite(&language, text, kind == BodyKind::Json)
+                    .into_bytes(),
+                Err(_) => bytes.to_vec(),
+            };
+
+            let res = res.set_body(body).map_into_boxed_body();
+            Ok(ServiceResponse::new(req, res))
+        })
+    }
+}
+
+/// Handler for `GET /admin/i18n/supported-languages`.
+pub async fn supported_languages(translations: web::Data<Translations>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "languages": translations.languages(),
+        "fallback": FALLBACK_LANGUAGE,
+    }))
+}
@@ -0,0 +1,247 @@
+//! Registry of the requests being served.
+//!
+//! [`TrackInFlight`] records every request in an [`InFlightRegistry`] until
+//! its response is ready, or until it is dropped. The shutdown drain logs
+//! snapshots of the registry while it waits, and the requests still in
+//! flight when the shutdown timeout aborts them; `GET /admin/in-flight`
+//! answers the same snapshot.
+//!
+//! The registry is sharded: each worker thread records its requests in its
+//! own shard, so workers do not contend on a lock. Each shard holds at most
+//! its share of `IN_FLIGHT_CAPACITY` requests; requests over it are served
+//! but not recorded, and counted in `in_flight_untracked_total`.
+
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use serde_json::{json, Value};
+
+use crate::metrics::Metrics;
+use crate::middleware::request_id::request_id;
+use crate::util::real_ip::real_ip;
+
+/// Default for `IN_FLIGHT_CAPACITY`.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Counter of requests not recorded because their shard was full.
+pub const UNTRACKED_METRIC: &str = "in_flight_untracked_total";
+
+/// Assigns each thread a shard the first time it records a request.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A request being served.
+#[derive(Clone, Debug)]
+pub struct InFlightRequest {
+    pub method: String,
+    /// The matched route pattern, e.g. `/admin/flags/{name}`, or the path
+    /// when no route matched.
+    pub route: String,
+    pub client_ip: Option<String>,
+    pub request_id: String,
+    pub started: Instant,
+}
+
+impl InFlightRequest {
+    /// Time since the request arrived.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The request as JSON, for the admin endpoint.
+    pub fn describe(&self) -> Value {
+        json!({
+            "method": self.method,
+            "route": self.route,
+            "elapsed_ms": self.elapsed().as_millis() as u64,
+            "client_ip": self.client_ip,
+            "request_id": self.request_id,
+        })
+    }
+}
+
+/// The requests being served, sharded by worker thread.
+pub struct InFlightRegistry {
+    shards: Vec<Mutex<HashMap<u64, InFlightRequest>>>,
+    shard_capacity: usize,
+    next_id: AtomicU64,
+}
+
+impl InFlightRegistry {
+    /// Creates a registry of `shards` shards holding `capacity` requests in
+    /// total; use one shard per worker.
+    pub fn new(shards: usize, capacity: usize) -> Self {
+        let shards = shards.max(1);
+        InFlightRegistry {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            shard_capacity: capacity.div_ceil(shards),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates a registry for `workers` workers holding
+    /// `IN_FLIGHT_CAPACITY` requests.
+    pub fn from_env(workers: usize) -> Self {
+        let capacity = env::var("IN_FLIGHT_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+        InFlightRegistry::new(workers, capacity)
+    }
+
+    /// Records `request` until the returned guard is dropped; `None` when
+    /// the shard of this thread is full.
+    pub fn track(self: &Arc<Self>, request: InFlightRequest) -> Option<InFlightGuard> {
+        let shard = SHARD.with(|shard| *shard) % self.shards.len();
+        let mut requests = self.shards[shard].lock().unwrap();
+        if requests.len() >= self.shard_capacity {
+            Metrics::global().inc(UNTRACKED_METRIC, &[]);
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        requests.insert(id, request);
+        Some(InFlightGuard {
+            registry: self.clone(),
+            shard,
+            id,
+        })
+    }
+
+    /// Returns the requests being served, longest running first.
+    pub fn snapshot(&self) -> Vec<InFlightRequest> {
+        let mut requests: Vec<InFlightRequest> = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().values().cloned().collect::<Vec<_>>())
+            .collect();
+        requests.sort_by_key(|request| request.started);
+        requests
+    }
+
+    /// Number of requests being served.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Returns `true` when no request is being served.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Removes a request from the registry when dropped.
+pub struct InFlightGuard {
+    registry: Arc<InFlightRegistry>,
+    shard: usize,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.registry.shards[self.shard]
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
+
+/// Middleware recording every request in an [`InFlightRegistry`].
+///
+/// Install it inside `AssignRequestId` so the recorded request ID is the
+/// assigned one.
+#[derive(Clone)]
+pub struct TrackInFlight {
+    registry: Arc<InFlightRegistry>,
+}
+
+impl TrackInFlight {
+    /// Creates the middleware recording in `registry`.
+    pub fn new(registry: Arc<InFlightRegistry>) -> Self {
+        TrackInFlight { registry }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TrackInFlight
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TrackInFlightMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TrackInFlightMiddleware {
+            service,
+            registry: self.registry.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`TrackInFlight`].
+pub struct TrackInFlightMiddleware<S> {
+    service: S,
+    registry: Arc<InFlightRegistry>,
+}
+
+impl<S, B> Service<ServiceRequest> for TrackInFlightMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let guard = self.registry.track(InFlightRequest {
+            method: req.method().to_string(),
+            route: req
+                .match_pattern()
+                .unwrap_or_else(|| req.path().to_string()),
+            client_ip: real_ip(req.request()).ip().map(|ip| ip.to_string()),
+            request_id: request_id(&req),
+            started: Instant::now(),
+        });
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            drop(guard);
+            res
+        })
+    }
+}
+
+/// Handler for `GET /admin/in-flight`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the requests being served, longest running first.
+pub async fn list_in_flight(registry: web::Data<InFlightRegistry>) -> HttpResponse {
+    let requests: Vec<Value> = registry
+        .snapshot()
+        .iter()
+        .map(InFlightRequest::describe)
+        .collect();
+    HttpResponse::Ok().json(json!({ "requests": requests }))
+}
@@ -0,0 +1,178 @@
+//! Keep-alive settings per HTTP version.
+//!
+//! Actix has a single keep-alive timer for all connections, and it means
+//! different things per protocol:
+//!
+//! * HTTP/1.x: the idle timeout between requests on a connection.
+//! * HTTP/2: the interval between PING frames. An idle connection stays open
+//!   as long as the client answers each PING before the next one is due.
+//!
+//! `H1_KEEP_ALIVE_SECS` and `H2_KEEP_ALIVE_SECS` are mapped onto that timer
+//! as closely as possible: the timer follows the HTTP/1.x setting when it is
+//! enabled (so short-lived probes are reaped quickly) and the HTTP/2 setting
+//! otherwise. Disabling HTTP/1.x keep-alive while HTTP/2 keeps it is done by
+//! this middleware, which answers HTTP/1.x requests with `Connection: close`.
+//! [`KeepAliveSettings::effective`] describes the resulting behaviour.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::time::Duration;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{ConnectionType, KeepAlive, Version};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+
+/// Actix's default keep-alive.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// Requested keep-alive per HTTP version; `None` disables it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAliveSettings {
+    pub h1: Option<Duration>,
+    pub h2: Option<Duration>,
+}
+
+impl Default for KeepAliveSettings {
+    fn default() -> Self {
+        KeepAliveSettings {
+            h1: Some(DEFAULT_KEEP_ALIVE),
+            h2: Some(DEFAULT_KEEP_ALIVE),
+        }
+    }
+}
+
+/// What each protocol actually gets, as logged at startup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EffectiveKeepAlive {
+    /// Value passed to `HttpServer::keep_alive`.
+    pub timer: Option<Duration>,
+    pub h1: String,
+    pub h2: String,
+    /// Set when a requested setting could not be honoured exactly.
+    pub warning: Option<String>,
+}
+
+impl KeepAliveSettings {
+    /// Reads `H1_KEEP_ALIVE_SECS` and `H2_KEEP_ALIVE_SECS`; `0` disables
+    /// keep-alive for that protocol. Unset values default to 5 seconds.
+    pub fn from_env() -> Self {
+        let read = |var: &str| match env::var(var).ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(DEFAULT_KEEP_ALIVE),
+        };
+        KeepAliveSettings {
+            h1: read("H1_KEEP_ALIVE_SECS"),
+            h2: read("H2_KEEP_ALIVE_SECS"),
+        }
+    }
+
+    /// Returns the setting for `HttpServer::keep_alive`.
+    pub fn server_keep_alive(&self) -> KeepAlive {
+        match self.h1.or(self.h2) {
+            Some(timer) => KeepAlive::Timeout(timer),
+            None => KeepAlive::Disabled,
+        }
+    }
+
+    /// Describes the behaviour each protocol ends up with.
+    pub fn effective(&self) -> EffectiveKeepAlive {
+        let timer = self.h1.or(self.h2);
+        let h1 = match self.h1 {
+            Some(d) => format!("idle timeout {}s", d.as_secs()),
+            None => "disabled (Connection: close)".to_string(),
+        };
+        let h2 = match timer {
+            Some(d) => format!("PING every {}s, closed if unanswered", d.as_secs()),
+            None => "no PINGs, open until the client closes".to_string(),
+        };
+        let warning = match (self.h2, timer) {
+            (Some(requested), Some(actual)) if requested != actual => Some(format!(
+                "H2_KEEP_ALIVE_SECS={} cannot be applied separately; Actix uses one timer, so HTTP/2 follows the HTTP/1.x value of {}s",
+                requested.as_secs(),
+                actual.as_secs()
+            )),
+            (None, Some(actual)) => Some(format!(
+                "HTTP/2 keep-alive cannot be disabled while HTTP/1.x keep-alive is on; HTTP/2 PINGs every {}s",
+                actual.as_secs()
+            )),
+            _ => None,
+        };
+        EffectiveKeepAlive {
+            timer,
+            h1,
+            h2,
+            warning,
+        }
+    }
+}
+
+/// Middleware closing HTTP/1.x connections after each response when
+/// HTTP/1.x keep-alive is disabled but the server timer is not.
+#[derive(Clone)]
+pub struct H1KeepAlive {
+    close: bool,
+}
+
+impl H1KeepAlive {
+    pub fn new(settings: &KeepAliveSettings) -> Self {
+        H1KeepAlive {
+            close: settings.h1.is_none(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for H1KeepAlive
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = H1KeepAliveMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(H1KeepAliveMiddleware {
+            service,
+            close: self.close,
+        }))
+    }
+}
+
+/// Service produced by [`H1KeepAlive`].
+pub struct H1KeepAliveMiddleware<S> {
+    service: S,
+    close: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for H1KeepAliveMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let close = self.close && matches!(req.version(), Version::HTTP_10 | Version::HTTP_11);
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if close {
+                res.response_mut()
+                    .head_mut()
+                    .set_connection_type(ConnectionType::Close);
+            }
+            Ok(res)
+        })
+    }
+}
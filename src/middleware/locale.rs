@@ -0,0 +1,80 @@
+//! Locale negotiation middleware.
+//!
+//! Determines the locale for each request from the `?lang=` query parameter,
+//! the `Accept-Language` header and the configured default, stores it in the
+//! request extensions for the [`Localizer`](crate::i18n::Localizer) extractor
+//! and reports it in the `Content-Language` response header.
+
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, ACCEPT_LANGUAGE, CONTENT_LANGUAGE};
+use actix_web::{web, Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+
+use crate::i18n::{lang_override, I18n, Locale};
+
+/// Middleware that negotiates the response locale.
+#[derive(Clone, Default)]
+pub struct LocaleNegotiation;
+
+impl<S, B> Transform<S, ServiceRequest> for LocaleNegotiation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = LocaleNegotiationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LocaleNegotiationMiddleware { service }))
+    }
+}
+
+/// Service produced by [`LocaleNegotiation`].
+pub struct LocaleNegotiationMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for LocaleNegotiationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let i18n: Arc<I18n> = req
+            .app_data::<web::Data<I18n>>()
+            .map(|data| data.clone().into_inner())
+            .unwrap_or_else(I18n::builtin);
+
+        let locale = i18n.negotiate(
+            lang_override(req.query_string()),
+            req.headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|v| v.to_str().ok()),
+        );
+        let content_language = HeaderValue::from_str(&locale.to_string()).ok();
+        req.extensions_mut().insert(Locale(locale));
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(value) = content_language {
+                res.headers_mut().insert(CONTENT_LANGUAGE, value);
+            }
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,173 @@
+//! Macaroon authentication, only compiled with the `macaroon` feature.
+//!
+//! Requests carrying an `Authorization: Macaroon <token>` header are checked
+//! against the [`MacaroonAuthority`] registered as app data: the signature
+//! and every caveat must hold for the request method, path and current
+//! time. Accepted requests get a [`Principal`] named after the macaroon
+//! identifier; rejected ones are answered with 401. Paths listed in
+//! `MACAROON_PATHS` additionally require a macaroon.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::{info, warn};
+
+use crate::auth::macaroon::{MacaroonAuthority, MacaroonError};
+use crate::auth::Principal;
+use crate::clock::Clock;
+use crate::discovery;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+
+/// Challenge sent with every 401 response.
+const CHALLENGE: &str = "Macaroon realm=\"secure-actix-web-server\"";
+
+/// Middleware verifying macaroons.
+#[derive(Clone, Default)]
+pub struct MacaroonAuth {
+    paths: Arc<Vec<String>>,
+    clock: Clock,
+}
+
+impl MacaroonAuth {
+    /// Creates the middleware; requests under `paths` need a macaroon.
+    pub fn new(paths: Vec<String>) -> Self {
+        MacaroonAuth {
+            paths: Arc::new(paths),
+            clock: Clock::default(),
+        }
+    }
+
+    /// Checks time caveats against `clock`.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reads the comma-separated `MACAROON_PATHS`.
+    pub fn from_env() -> Self {
+        let paths: Vec<String> = env::var("MACAROON_PATHS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| p.starts_with('/'))
+            .collect();
+        if !paths.is_empty() {
+            info!("Requiring macaroons under {}", paths.join(", "));
+        }
+        MacaroonAuth::new(paths)
+    }
+
+    /// Returns whether requests for `path` need a macaroon.
+    pub fn applies_to(&self, path: &str) -> bool {
+        !discovery::is_public(path)
+            && self.paths.iter().any(|prefix| {
+                let prefix = prefix.trim_end_matches('/');
+                path.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MacaroonAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = MacaroonAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MacaroonAuthMiddleware {
+            service: Rc::new(service),
+            config: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`MacaroonAuth`].
+pub struct MacaroonAuthMiddleware<S> {
+    service: Rc<S>,
+    config: MacaroonAuth,
+}
+
+impl<S, B> Service<ServiceRequest> for MacaroonAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_macaroon)
+            .map(str::to_string);
+        let authority = req.app_data::<web::Data<MacaroonAuthority>>().cloned();
+
+        let result = match (token, authority) {
+            (Some(token), Some(authority)) => Some(authority.verify(
+                &token,
+                req.method().as_str(),
+                req.path(),
+                &self.config.clock,
+            )),
+            _ if self.config.applies_to(req.path()) => Some(Err(MacaroonError::Invalid)),
+            _ => None,
+        };
+
+        match result {
+            Some(Ok(identifier)) => {
+                req.extensions_mut().insert(Principal {
+                    username: identifier,
+                    roles: Vec::new(),
+                });
+            }
+            Some(Err(e)) => {
+                let reason = match &e {
+                    MacaroonError::Invalid => "invalid",
+                    MacaroonError::Expired => "expired",
+                    MacaroonError::NotPermitted(_) => "not_permitted",
+                };
+                warn!("Rejected macaroon for {} ({})", req.path(), reason);
+                Metrics::global().inc("macaroon_rejections_total", &[("reason", reason)]);
+                let mut res = ApiError::from(e).error_response();
+                res.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(CHALLENGE),
+                );
+                return Box::pin(async move { Ok(req.into_response(res).map_into_right_body()) });
+            }
+            None => {}
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+/// Returns the token of a `Macaroon` authorization header value, or `None`
+/// for other schemes.
+pub fn parse_macaroon(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("macaroon") && !token.is_empty()).then_some(token)
+}
@@ -0,0 +1,110 @@
+//! Memory pressure load shedding middleware.
+//!
+//! While the [`MemoryPressureWatcher`](crate::memory::MemoryPressureWatcher)
+//! reports RSS above its limit, requests are answered with 503 without
+//! reaching a handler. `Retry-After` is estimated from the shedding so far
+//! by [`ThrottleResponses`](crate::throttle::ThrottleResponses), which can
+//! also customize the body, and is 5 seconds until there is an estimate. `/health` and `/admin` stay
+//! reachable so operators can inspect the instance; `/ready` is shed, which
+//! takes the instance out of load balancer rotation until it recovers.
+
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+
+use crate::discovery;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::throttle::{self, Throttle};
+
+/// Seconds clients are asked to wait before retrying, until the wait can
+/// be estimated.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+/// Path prefixes served even under memory pressure.
+const EXEMPT_PREFIXES: &[&str] = &["/health", "/admin"];
+
+/// Middleware rejecting requests while the pressure flag is set.
+#[derive(Clone)]
+pub struct MemoryPressure {
+    pressure: Arc<AtomicBool>,
+}
+
+impl MemoryPressure {
+    /// Creates the middleware for the watcher's pressure flag.
+    pub fn new(pressure: Arc<AtomicBool>) -> Self {
+        MemoryPressure { pressure }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MemoryPressure
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = MemoryPressureMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MemoryPressureMiddleware {
+            service,
+            pressure: self.pressure.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`MemoryPressure`].
+pub struct MemoryPressureMiddleware<S> {
+    service: S,
+    pressure: Arc<AtomicBool>,
+}
+
+impl<S, B> Service<ServiceRequest> for MemoryPressureMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let exempt = discovery::is_public(req.path())
+            || EXEMPT_PREFIXES.iter().any(|prefix| {
+                req.path()
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            });
+        if self.pressure.load(Ordering::Relaxed) && !exempt {
+            Metrics::global().inc("memory_pressure_rejections_total", &[]);
+            let res = throttle::respond(
+                req.request(),
+                Throttle::LoadShed,
+                ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "memory_pressure",
+                    "The server is under memory pressure, please retry later",
+                ),
+                Duration::from_secs(RETRY_AFTER_SECS),
+            );
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
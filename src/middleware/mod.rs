@@ -0,0 +1,32 @@
+//! Custom Actix-web middleware used by the server.
+//!
+//! Each submodule provides a `Transform` that can be attached to the `App`
+//! with `App::wrap`.
+
+pub mod access_log;
+pub mod api_key;
+pub mod aws_sigv4;
+pub mod basic_auth;
+pub mod cache_policy;
+pub mod drain;
+pub mod dry_run;
+pub mod envelope;
+pub mod extra_headers;
+pub mod feature_flags;
+pub mod header_limits;
+pub mod header_sanitizer;
+pub mod i18n;
+pub mod in_flight;
+pub mod keep_alive;
+pub mod locale;
+#[cfg(feature = "macaroon")]
+pub mod macaroon_auth;
+pub mod memory_pressure;
+pub mod mtls;
+pub mod panic;
+pub mod proto_enforcement;
+pub mod request_id;
+pub mod revocation;
+pub mod security_headers;
+pub mod slow_request;
+pub mod tenant;
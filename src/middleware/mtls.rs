@@ -0,0 +1,179 @@
+//! Mutual TLS middleware.
+//!
+//! [`ClientCertificates`] copies the client certificate chain captured for
+//! the connection into the request extensions so handlers can use the
+//! [`PeerCertificate`](crate::tls_info::PeerCertificate) extractor.
+//!
+//! Client authentication in rustls applies to the whole connection. To
+//! require certificates only on some paths, the TLS layer accepts clients
+//! with or without a certificate (any certificate presented is still
+//! verified against `CLIENT_CA_FILE`) and [`RequireClientCertificate`]
+//! rejects requests under `MTLS_REQUIRED_PATHS` made without one.
+
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::info;
+
+use crate::discovery;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::tls_info::{PeerCertificate, TlsInfo};
+
+/// Middleware exposing the client certificate chain to handlers.
+#[derive(Clone, Default)]
+pub struct ClientCertificates;
+
+impl<S, B> Transform<S, ServiceRequest> for ClientCertificates
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ClientCertificatesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ClientCertificatesMiddleware { service }))
+    }
+}
+
+/// Service produced by [`ClientCertificates`].
+pub struct ClientCertificatesMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ClientCertificatesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let chain = req
+            .request()
+            .conn_data::<TlsInfo>()
+            .and_then(|info| info.peer_certificates.clone());
+        if let Some(chain) = chain {
+            req.extensions_mut().insert(PeerCertificate(chain));
+        }
+        self.service.call(req)
+    }
+}
+
+/// Middleware requiring a client certificate on requests under the
+/// configured path prefixes.
+#[derive(Clone, Default)]
+pub struct RequireClientCertificate {
+    paths: Arc<Vec<String>>,
+}
+
+impl RequireClientCertificate {
+    /// Requires a client certificate on requests under any of `paths`.
+    pub fn new(paths: Vec<String>) -> Self {
+        RequireClientCertificate {
+            paths: Arc::new(paths),
+        }
+    }
+
+    /// Reads the comma-separated path prefixes of `MTLS_REQUIRED_PATHS`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<RequireClientCertificate>` - The middleware, or `None` if no path is configured.
+    pub fn from_env() -> Option<Self> {
+        let paths: Vec<String> = env::var("MTLS_REQUIRED_PATHS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| p.starts_with('/'))
+            .collect();
+        if paths.is_empty() {
+            return None;
+        }
+        info!("Requiring client certificates under {}", paths.join(", "));
+        Some(RequireClientCertificate::new(paths))
+    }
+
+    /// Returns whether requests for `path` need a client certificate.
+    pub fn applies_to(&self, path: &str) -> bool {
+        !discovery::is_public(path)
+            && self.paths.iter().any(|prefix| {
+                let prefix = prefix.trim_end_matches('/');
+                path.strip_prefix(prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireClientCertificate
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireClientCertificateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireClientCertificateMiddleware {
+            service,
+            scope: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`RequireClientCertificate`].
+pub struct RequireClientCertificateMiddleware<S> {
+    service: S,
+    scope: RequireClientCertificate,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireClientCertificateMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // The TLS layer has already verified any certificate it accepted
+        let has_certificate = req
+            .request()
+            .conn_data::<TlsInfo>()
+            .and_then(|info| info.peer_certificates.as_ref())
+            .is_some_and(|chain| !chain.is_empty());
+        if !has_certificate && self.scope.applies_to(req.path()) {
+            Metrics::global().inc("client_certificate_rejections_total", &[]);
+            let res = ApiError::new(
+                StatusCode::FORBIDDEN,
+                "client_certificate_required",
+                "A client certificate is required for this resource",
+            )
+            .error_response();
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
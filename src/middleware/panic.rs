@@ -0,0 +1,98 @@
+//! Panic recovery middleware.
+//!
+//! A panic inside a handler would otherwise unwind through the worker and
+//! take it down. [`PanicHandler`] catches the panic, logs it and returns a
+//! `500 Internal Server Error` so the worker keeps serving other requests.
+//!
+//! The 500 is returned as an error rather than a response: building a
+//! response needs the `HttpRequest`, and holding a clone of it while the
+//! request is routed would stop the router from updating its match info.
+
+use std::future::{ready, Ready};
+use std::panic::AssertUnwindSafe;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use log::error;
+
+use crate::error::ApiError;
+
+/// Middleware that turns handler panics into 500 responses.
+#[derive(Clone, Default)]
+pub struct PanicHandler;
+
+impl<S, B> Transform<S, ServiceRequest> for PanicHandler
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = PanicHandlerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(PanicHandlerMiddleware { service }))
+    }
+}
+
+/// Service produced by [`PanicHandler`].
+pub struct PanicHandlerMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for PanicHandlerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // Keep what is needed to log the panic
+        let method = req.method().clone();
+        let path = req.path().to_string();
+
+        let fut = match std::panic::catch_unwind(AssertUnwindSafe(|| self.service.call(req))) {
+            Ok(fut) => fut,
+            Err(panic) => return Box::pin(ready(Err(panic_error(&method, &path, panic)))),
+        };
+
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(res) => res,
+                Err(panic) => Err(panic_error(&method, &path, panic)),
+            }
+        })
+    }
+}
+
+/// Logs the panic and builds the 500 error.
+fn panic_error(
+    method: &actix_web::http::Method,
+    path: &str,
+    panic: Box<dyn std::any::Any + Send>,
+) -> Error {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic payload".to_string());
+    error!("Handler panicked on {} {}: {}", method, path, message);
+
+    ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal_error",
+        "Internal Server Error",
+    )
+    .into()
+}
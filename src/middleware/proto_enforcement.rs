@@ -0,0 +1,150 @@
+//! HTTPS redirects behind a TLS-terminating load balancer.
+//!
+//! When a load balancer terminates TLS, the server only sees plain HTTP and
+//! cannot tell whether the client used HTTPS; the HSTS header sent by
+//! `SecurityHeaders` only helps once the client has been on HTTPS. With
+//! `FORCE_HTTPS=true` and `TRUST_PROXY=true`, [`ProtoEnforcer`] reads the
+//! scheme the proxy reports in `X-Forwarded-Proto` and answers requests
+//! made over `http` with a `301` to the same host and path over `https`.
+//!
+//! With `TRUST_PROXY=false` the server terminates TLS itself, every
+//! request already arrived over HTTPS, and the header is not consulted.
+//! Requests without the header are passed through.
+
+use std::env;
+use std::future::{ready, Ready};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpResponse, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::info;
+
+use crate::error::ApiError;
+
+/// Middleware redirecting requests the proxy received over plain HTTP.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProtoEnforcer {
+    enabled: bool,
+}
+
+impl ProtoEnforcer {
+    /// Redirects `http` requests when `enabled`, otherwise passes every
+    /// request through.
+    pub fn new(enabled: bool) -> Self {
+        ProtoEnforcer { enabled }
+    }
+
+    /// Enabled when both `FORCE_HTTPS` and `TRUST_PROXY` are `true`.
+    pub fn from_env() -> Self {
+        let flag = |var: &str| env::var(var).is_ok_and(|v| v == "true");
+        match (flag("FORCE_HTTPS"), flag("TRUST_PROXY")) {
+            (true, true) => {
+                info!("FORCE_HTTPS: redirecting X-Forwarded-Proto: http requests to https");
+                ProtoEnforcer::new(true)
+            }
+            (true, false) => {
+                info!("FORCE_HTTPS ignored without TRUST_PROXY: connections already use TLS");
+                ProtoEnforcer::new(false)
+            }
+            _ => ProtoEnforcer::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+/// Returns the scheme reported by the nearest proxy, if any.
+fn forwarded_proto(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get_all("x-forwarded-proto")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .last()
+        .map(str::to_ascii_lowercase)
+}
+
+/// Strips the port from a `Host` value, keeping bracketed IPv6 literals.
+fn host_without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
+
+/// Returns the `https://` URL of the request, or `None` without a host.
+fn https_location(req: &ServiceRequest) -> Option<String> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .filter(|h| !h.is_empty())?;
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    Some(format!("https://{}{}", host_without_port(host), path))
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ProtoEnforcer
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = ProtoEnforcerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ProtoEnforcerMiddleware {
+            service,
+            enabled: self.enabled,
+        }))
+    }
+}
+
+/// Service produced by [`ProtoEnforcer`].
+pub struct ProtoEnforcerMiddleware<S> {
+    service: S,
+    enabled: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for ProtoEnforcerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !self.enabled || forwarded_proto(&req).as_deref() != Some("http") {
+            let fut = self.service.call(req);
+            return Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) });
+        }
+
+        let res = match https_location(&req) {
+            Some(location) => HttpResponse::MovedPermanently()
+                .insert_header((header::LOCATION, location))
+                .finish(),
+            None => ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "missing_host",
+                "The request has no Host to redirect to",
+            )
+            .error_response(),
+        };
+        Box::pin(async move { Ok(req.into_response(res).map_into_right_body()) })
+    }
+}
@@ -0,0 +1,433 @@
+//! Request ID middleware.
+//!
+//! Assigns every request an identifier. A request arriving without a
+//! well-formed `X-Request-Id` header starts a new correlation chain with a
+//! UUID. When another service passes its ID, this server's ID becomes a
+//! child of it, `{parent_id}:{new_uuid}`, so logs across services can be
+//! followed from any hop back to the originating request. Chains deeper
+//! than the configured maximum are rejected with `400 Bad Request`.
+//!
+//! The ID is stored in the request extensions as [`RequestId`] and
+//! [`CorrelationChain`], and echoed in the response header so clients can
+//! quote it when reporting problems. Outbound calls made for the request
+//! should send [`CorrelationChain::own`] as their `X-Request-Id`.
+//!
+//! Every request also gets a W3C trace context ([`TraceContext`]). A valid
+//! incoming `traceparent` is continued: the trace ID and sampling flag are
+//! kept, the caller's span becomes the parent, and `tracestate` is passed
+//! on unchanged. Otherwise a new trace is started. The server records no
+//! spans itself; its random span ID links the services it calls to the
+//! caller's span. The context is stored in the extensions and attached to
+//! the [`CorrelationChain`], so outbound calls made with the chain (the
+//! [`OutboundClient`](crate::outbound::OutboundClient)) and proxied
+//! requests send `traceparent` and `tracestate` along with `X-Request-Id`.
+
+use std::future::{ready, Ready};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::error::ApiError;
+
+/// Header used to carry the request identifier.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Separator between the IDs in a correlation chain.
+pub const CHAIN_SEPARATOR: char = ':';
+
+/// Default for the `MAX_CHAIN_DEPTH` setting.
+pub const DEFAULT_MAX_CHAIN_DEPTH: usize = 8;
+
+/// W3C trace context header carrying the trace and parent span IDs.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// W3C trace context header carrying vendor-specific trace state.
+pub const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Longest incoming request ID that is accepted as a parent.
+const MAX_REQUEST_ID_LEN: usize = 1024;
+
+/// Longest `tracestate` that is propagated, as allowed by the W3C spec.
+const MAX_TRACESTATE_LEN: usize = 512;
+
+/// The identifier assigned to a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Returns the identifier as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let id = req
+            .extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId(incoming_or_new(req.headers())));
+        ready(Ok(id))
+    }
+}
+
+/// The W3C trace context of a request: its trace, its own span and the
+/// caller's span.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    flags: u8,
+    tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new, sampled trace.
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: *Uuid::new_v4().as_bytes(),
+            span_id: new_span_id(),
+            parent_span_id: None,
+            flags: 0x01,
+            tracestate: None,
+        }
+    }
+
+    /// Continues the trace of a valid `traceparent`, with a new span whose
+    /// parent is the caller's. `tracestate` is kept when it is well formed.
+    pub fn child_of(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let (trace_id, parent_span_id, flags) = parse_traceparent(traceparent)?;
+        Some(TraceContext {
+            trace_id,
+            span_id: new_span_id(),
+            parent_span_id: Some(parent_span_id),
+            flags,
+            tracestate: tracestate
+                .map(str::trim)
+                .filter(|s| {
+                    !s.is_empty()
+                        && s.len() <= MAX_TRACESTATE_LEN
+                        && s.bytes().all(|b| b == b' ' || b.is_ascii_graphic())
+                })
+                .map(str::to_string),
+        })
+    }
+
+    /// Continues the trace in `headers`, or starts a new one.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        header(TRACEPARENT_HEADER)
+            .and_then(|parent| TraceContext::child_of(parent, header(TRACESTATE_HEADER)))
+            .unwrap_or_else(TraceContext::root)
+    }
+
+    /// Returns the trace ID, as 32 lowercase hex digits.
+    pub fn trace_id(&self) -> String {
+        hex(&self.trace_id)
+    }
+
+    /// Returns this server's span ID, as 16 lowercase hex digits.
+    pub fn span_id(&self) -> String {
+        hex(&self.span_id)
+    }
+
+    /// Returns the caller's span ID, if the trace was continued.
+    pub fn parent_span_id(&self) -> Option<String> {
+        self.parent_span_id.map(|id| hex(&id))
+    }
+
+    /// Returns the `traceparent` to send to the services this request calls.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id(),
+            self.span_id(),
+            self.flags
+        )
+    }
+
+    /// Returns the `tracestate` received with the trace, if any.
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+}
+
+impl FromRequest for TraceContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let trace = req
+            .extensions()
+            .get::<TraceContext>()
+            .cloned()
+            .unwrap_or_else(|| TraceContext::from_headers(req.headers()));
+        ready(Ok(trace))
+    }
+}
+
+/// Parses a version 00 `traceparent`, or the fields of a later version it
+/// starts with, into the trace ID, the parent span ID and the flags.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let value = value.trim();
+    let version = value.get(..2)?;
+    let valid_length = match version {
+        "00" => value.len() == 55,
+        "ff" => false,
+        _ => value.len() == 55 || value.as_bytes().get(55) == Some(&b'-'),
+    };
+    if !valid_length || !is_lower_hex(version) {
+        return None;
+    }
+    let mut fields = value[..55].split('-').skip(1);
+    let trace_id: [u8; 16] = decode_hex(fields.next()?)?.try_into().ok()?;
+    let span_id: [u8; 8] = decode_hex(fields.next()?)?.try_into().ok()?;
+    let flags = decode_hex(fields.next()?)?;
+    if trace_id == [0; 16] || span_id == [0; 8] || flags.len() != 1 {
+        return None;
+    }
+    Some((trace_id, span_id, flags[0]))
+}
+
+fn new_span_id() -> [u8; 8] {
+    let bytes = Uuid::new_v4();
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&bytes.as_bytes()[8..]);
+    id
+}
+
+fn is_lower_hex(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !is_lower_hex(value) {
+        return None;
+    }
+    (0..value.len() / 2)
+        .map(|i| u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A request's own ID together with the ID of the request that caused it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorrelationChain {
+    id: String,
+    /// Length of the parent prefix in `id`, if there is a parent.
+    parent_len: Option<usize>,
+    trace: Option<TraceContext>,
+}
+
+impl CorrelationChain {
+    /// Starts a new chain.
+    pub fn root() -> Self {
+        CorrelationChain {
+            id: Uuid::new_v4().to_string(),
+            parent_len: None,
+            trace: None,
+        }
+    }
+
+    /// Creates a child of the chain ending in `parent`.
+    pub fn child_of(parent: &str) -> Self {
+        CorrelationChain {
+            id: format!("{}{}{}", parent, CHAIN_SEPARATOR, Uuid::new_v4()),
+            parent_len: Some(parent.len()),
+            trace: None,
+        }
+    }
+
+    /// Attaches the request's trace context, propagated by outbound calls
+    /// made with the chain.
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Returns the request's trace context, if attached.
+    pub fn trace(&self) -> Option<&TraceContext> {
+        self.trace.as_ref()
+    }
+
+    /// Returns the ID of the calling request, if any.
+    pub fn parent(&self) -> Option<&str> {
+        self.parent_len.map(|len| &self.id[..len])
+    }
+
+    /// Returns this request's ID: the full chain, ending in its own UUID.
+    pub fn own(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the number of IDs in the chain.
+    pub fn depth(&self) -> usize {
+        chain_depth(&self.id)
+    }
+}
+
+impl FromRequest for CorrelationChain {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let chain = req
+            .extensions()
+            .get::<CorrelationChain>()
+            .cloned()
+            .unwrap_or_else(CorrelationChain::root);
+        ready(Ok(chain))
+    }
+}
+
+/// Returns the ID assigned to `req`, falling back to the incoming header or
+/// a new UUID when the [`AssignRequestId`] middleware is not installed.
+pub fn request_id(req: &ServiceRequest) -> String {
+    match req.extensions().get::<RequestId>() {
+        Some(id) => id.0.clone(),
+        None => incoming_or_new(req.headers()),
+    }
+}
+
+fn incoming(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid(v))
+}
+
+fn incoming_or_new(headers: &HeaderMap) -> String {
+    incoming(headers)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Accepts IDs of bounded length made of visible ASCII characters.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn chain_depth(id: &str) -> usize {
+    id.split(CHAIN_SEPARATOR).count()
+}
+
+/// Middleware assigning request IDs.
+#[derive(Clone)]
+pub struct AssignRequestId {
+    max_depth: usize,
+}
+
+impl AssignRequestId {
+    /// Creates the middleware, rejecting chains longer than `max_depth`.
+    pub fn new(max_depth: usize) -> Self {
+        AssignRequestId { max_depth }
+    }
+}
+
+impl Default for AssignRequestId {
+    fn default() -> Self {
+        AssignRequestId::new(DEFAULT_MAX_CHAIN_DEPTH)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AssignRequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AssignRequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AssignRequestIdMiddleware {
+            service,
+            max_depth: self.max_depth,
+        }))
+    }
+}
+
+/// Service produced by [`AssignRequestId`].
+pub struct AssignRequestIdMiddleware<S> {
+    service: S,
+    max_depth: usize,
+}
+
+impl<S, B> Service<ServiceRequest> for AssignRequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let chain = match incoming(req.headers()) {
+            Some(parent) if chain_depth(parent) >= self.max_depth => {
+                warn!(
+                    "Rejected request with correlation chain deeper than {}: {}",
+                    self.max_depth, parent
+                );
+                audit::record(
+                    "correlation_chain_too_deep",
+                    json!({ "chain": parent, "max_depth": self.max_depth }),
+                );
+                let res = ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "correlation_chain_too_deep",
+                    format!(
+                        "Request ID chains may contain at most {} IDs",
+                        self.max_depth
+                    ),
+                )
+                .error_response();
+                return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+            }
+            Some(parent) => CorrelationChain::child_of(parent),
+            None => CorrelationChain::root(),
+        };
+
+        let trace = TraceContext::from_headers(req.headers());
+        let chain = chain.with_trace(trace.clone());
+        let header = HeaderValue::from_str(chain.own()).ok();
+        req.extensions_mut()
+            .insert(RequestId(chain.own().to_string()));
+        req.extensions_mut().insert(trace);
+        req.extensions_mut().insert(chain);
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Some(header) = header {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
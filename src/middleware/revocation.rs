@@ -0,0 +1,121 @@
+//! Rejects requests made with revoked client certificates.
+//!
+//! Runs the configured [`RevocationChecker`] against the chain exposed by
+//! [`ClientCertificates`](crate::middleware::mtls::ClientCertificates), so it
+//! must be wrapped inside that middleware. Requests without a client
+//! certificate, or made while no checker is configured, pass through
+//! untouched.
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+
+use crate::error::ApiError;
+use crate::revocation::{RevocationChecker, RevocationStatus};
+use crate::tls_info::PeerCertificate;
+
+/// Middleware checking client certificates for revocation.
+#[derive(Clone)]
+pub struct RevocationCheck {
+    checker: Option<Arc<dyn RevocationChecker>>,
+    fail_open: bool,
+}
+
+impl RevocationCheck {
+    /// Creates the middleware; `None` disables checking. With `fail_open`,
+    /// certificates whose status is unknown are accepted; otherwise they are
+    /// rejected.
+    pub fn new(checker: Option<Arc<dyn RevocationChecker>>, fail_open: bool) -> Self {
+        RevocationCheck { checker, fail_open }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RevocationCheck
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RevocationCheckMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RevocationCheckMiddleware {
+            service: Rc::new(service),
+            checker: self.checker.clone(),
+            fail_open: self.fail_open,
+        }))
+    }
+}
+
+/// Service produced by [`RevocationCheck`].
+pub struct RevocationCheckMiddleware<S> {
+    service: Rc<S>,
+    checker: Option<Arc<dyn RevocationChecker>>,
+    fail_open: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for RevocationCheckMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let checker = self.checker.clone();
+        let fail_open = self.fail_open;
+
+        Box::pin(async move {
+            let chain = req.extensions().get::<PeerCertificate>().cloned();
+            if let (Some(checker), Some(chain)) = (checker, chain) {
+                let rejection = match checker.check(chain.chain()).await {
+                    RevocationStatus::Good => None,
+                    RevocationStatus::Unknown if fail_open => None,
+                    RevocationStatus::Revoked => Some(ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "certificate_revoked",
+                        "Client certificate has been revoked",
+                    )),
+                    RevocationStatus::Unknown => Some(ApiError::new(
+                        StatusCode::UNAUTHORIZED,
+                        "certificate_status_unknown",
+                        "Client certificate revocation status could not be determined",
+                    )),
+                };
+
+                if let Some(error) = rejection {
+                    warn!(
+                        "Rejected client certificate on {} {}: {}",
+                        req.method(),
+                        req.path(),
+                        error.code()
+                    );
+                    let res = error.error_response();
+                    return Ok(req.into_response(res).map_into_right_body());
+                }
+            }
+
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
@@ -0,0 +1,280 @@
+//! Security response headers.
+//!
+//! This sets `X-Content-Type-Options: nosniff`, so browsers never guess a
+//! content type that differs from the declared one, and
+//! `Strict-Transport-Security`. The HSTS max-age comes from a
+//! [`HstsMaxAgeStrategy`]: either a fixed value, or one derived from the
+//! server certificate's expiry so browsers never pin HSTS for longer than
+//! the current certificate is valid. A long max-age on a soon-expiring
+//! certificate turns any renewal mishap into a hard failure for every
+//! returning visitor.
+//!
+//! The certificate expiry is held by the middleware and can be replaced with
+//! [`SecurityHeaders::set_cert_not_after`] whenever the certificate is
+//! reloaded; the max-age is recomputed from it for every response.
+
+use std::env;
+use std::fs::File;
+use std::future::{ready, Ready};
+use std::io::{BufReader, Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use log::info;
+
+use crate::util::echo::NOSNIFF;
+
+/// Default for `HSTS_MAX_AGE_CAP`: two years.
+pub const DEFAULT_HSTS_MAX_AGE_CAP: u32 = 63_072_000;
+/// Default fixed max-age: one year.
+pub const DEFAULT_HSTS_MAX_AGE: u32 = 31_536_000;
+
+/// Marks an unknown certificate expiry.
+const UNKNOWN_EXPIRY: i64 = i64::MIN;
+
+/// How the HSTS max-age is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HstsMaxAgeStrategy {
+    /// Always the given number of seconds.
+    Fixed(u32),
+    /// The time left until the certificate expires, minus `margin_secs`,
+    /// never below zero and never above the cap.
+    CertExpiry { margin_secs: u32 },
+}
+
+impl HstsMaxAgeStrategy {
+    /// Computes the max-age at `now` (Unix seconds).
+    ///
+    /// With [`CertExpiry`](Self::CertExpiry) and an unknown certificate
+    /// expiry the max-age is 0, so no HSTS policy is pinned.
+    pub fn max_age(&self, cert_not_after: Option<i64>, now: i64, cap: u32) -> u32 {
+        match *self {
+            HstsMaxAgeStrategy::Fixed(secs) => secs,
+            HstsMaxAgeStrategy::CertExpiry { margin_secs } => match cert_not_after {
+                Some(not_after) => {
+                    (not_after - now - i64::from(margin_secs)).clamp(0, i64::from(cap)) as u32
+                }
+                None => 0,
+            },
+        }
+    }
+}
+
+/// Builder for [`SecurityHeaders`].
+#[derive(Clone, Debug)]
+pub struct SecurityHeadersBuilder {
+    hsts_strategy: HstsMaxAgeStrategy,
+    hsts_max_age_cap: u32,
+    include_subdomains: bool,
+    cert_not_after: Option<i64>,
+}
+
+impl Default for SecurityHeadersBuilder {
+    fn default() -> Self {
+        SecurityHeadersBuilder {
+            hsts_strategy: HstsMaxAgeStrategy::Fixed(DEFAULT_HSTS_MAX_AGE),
+            hsts_max_age_cap: DEFAULT_HSTS_MAX_AGE_CAP,
+            include_subdomains: false,
+            cert_not_after: None,
+        }
+    }
+}
+
+impl SecurityHeadersBuilder {
+    pub fn new() -> Self {
+        SecurityHeadersBuilder::default()
+    }
+
+    /// Sets how the HSTS max-age is chosen.
+    pub fn hsts_strategy(mut self, strategy: HstsMaxAgeStrategy) -> Self {
+        self.hsts_strategy = strategy;
+        self
+    }
+
+    /// Sets the largest max-age the `CertExpiry` strategy may produce.
+    pub fn hsts_max_age_cap(mut self, cap: u32) -> Self {
+        self.hsts_max_age_cap = cap;
+        self
+    }
+
+    /// Adds `includeSubDomains` to the HSTS header.
+    pub fn include_subdomains(mut self, include: bool) -> Self {
+        self.include_subdomains = include;
+        self
+    }
+
+    /// Sets the server certificate's expiry (Unix seconds).
+    pub fn cert_not_after(mut self, not_after: i64) -> Self {
+        self.cert_not_after = Some(not_after);
+        self
+    }
+
+    pub fn build(self) -> SecurityHeaders {
+        SecurityHeaders {
+            config: Arc::new(HstsConfig {
+                strategy: self.hsts_strategy,
+                cap: self.hsts_max_age_cap,
+                include_subdomains: self.include_subdomains,
+                cert_not_after: AtomicI64::new(self.cert_not_after.unwrap_or(UNKNOWN_EXPIRY)),
+            }),
+        }
+    }
+}
+
+struct HstsConfig {
+    strategy: HstsMaxAgeStrategy,
+    cap: u32,
+    include_subdomains: bool,
+    cert_not_after: AtomicI64,
+}
+
+/// Middleware adding security headers to every response, unless the handler
+/// set them itself.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    config: Arc<HstsConfig>,
+}
+
+impl SecurityHeaders {
+    /// Replaces the certificate expiry used by the `CertExpiry` strategy.
+    /// Call this whenever the server certificate is reloaded.
+    pub fn set_cert_not_after(&self, not_after: i64) {
+        self.config
+            .cert_not_after
+            .store(not_after, Ordering::Relaxed);
+    }
+
+    /// Returns the current `Strict-Transport-Security` value.
+    pub fn hsts_value(&self) -> String {
+        let not_after = match self.config.cert_not_after.load(Ordering::Relaxed) {
+            UNKNOWN_EXPIRY => None,
+            not_after => Some(not_after),
+        };
+        let max_age = self.config.strategy.max_age(
+            not_after,
+            chrono::Utc::now().timestamp(),
+            self.config.cap,
+        );
+        if self.config.include_subdomains {
+            format!("max-age={}; includeSubDomains", max_age)
+        } else {
+            format!("max-age={}", max_age)
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SecurityHeadersMiddleware {
+            service,
+            headers: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`SecurityHeaders`].
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    headers: SecurityHeaders,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let headers = self.headers.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if !res.headers().contains_key(X_CONTENT_TYPE_OPTIONS) {
+                res.headers_mut()
+                    .insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static(NOSNIFF));
+            }
+            if !res.headers().contains_key(STRICT_TRANSPORT_SECURITY) {
+                if let Ok(value) = HeaderValue::from_str(&headers.hsts_value()) {
+                    res.headers_mut().insert(STRICT_TRANSPORT_SECURITY, value);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Builds the middleware from environment variables.
+///
+/// `HSTS_CERT_EXPIRY_MARGIN_SECS` selects the `CertExpiry` strategy, reading
+/// the expiry from `CERT_FILE`; otherwise `HSTS_MAX_AGE` is used as a fixed
+/// value. `HSTS_MAX_AGE_CAP` and `HSTS_INCLUDE_SUBDOMAINS` apply to both.
+///
+/// # Returns
+///
+/// * `Result<SecurityHeaders, IoError>` - The middleware, or an IoError if the certificate expiry cannot be read.
+pub fn from_env() -> Result<SecurityHeaders, IoError> {
+    let parse = |var: &str| env::var(var).ok().and_then(|v| v.parse::<u32>().ok());
+    let mut builder = SecurityHeadersBuilder::new()
+        .hsts_max_age_cap(parse("HSTS_MAX_AGE_CAP").unwrap_or(DEFAULT_HSTS_MAX_AGE_CAP))
+        .include_subdomains(
+            env::var("HSTS_INCLUDE_SUBDOMAINS")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+        );
+
+    match parse("HSTS_CERT_EXPIRY_MARGIN_SECS") {
+        Some(margin_secs) => {
+            let cert_path = env::var("CERT_FILE").unwrap_or_else(|_| "cert.pem".to_string());
+            let not_after = cert_not_after_from_file(&cert_path)?;
+            builder = builder
+                .hsts_strategy(HstsMaxAgeStrategy::CertExpiry { margin_secs })
+                .cert_not_after(not_after);
+        }
+        None => {
+            let max_age = parse("HSTS_MAX_AGE").unwrap_or(DEFAULT_HSTS_MAX_AGE);
+            builder = builder.hsts_strategy(HstsMaxAgeStrategy::Fixed(max_age));
+        }
+    }
+
+    let headers = builder.build();
+    info!("Strict-Transport-Security: {}", headers.hsts_value());
+    Ok(headers)
+}
+
+/// Reads the expiry (Unix seconds) of the first certificate in a PEM file.
+///
+/// # Errors
+///
+/// Returns an IoError if the file cannot be read or holds no parsable
+/// certificate.
+pub fn cert_not_after_from_file(path: &str) -> Result<i64, IoError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    let leaf = certs
+        .first()
+        .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "no certificate found"))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf)
+        .map_err(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(cert.validity().not_after.timestamp())
+}
@@ -0,0 +1,214 @@
+//! Logging of slow requests with their full context.
+//!
+//! The access log may sample a slow request in, but records too little to
+//! tell why it was slow. [`SlowRequestDetector`] logs a warning for every
+//! request whose response took `SLOW_REQUEST_THRESHOLD_MS` or more, with
+//! its method, path, query, status, request ID and `User-Agent`, and counts
+//! it in `slow_requests_total`.
+//!
+//! With `LOG_REQUEST_BODIES=true`, the warning also carries the first
+//! `SLOW_REQUEST_BODY_LOG_BYTES` bytes of the request body. They are
+//! recorded as the handler reads the body, which is still streamed, so
+//! only the part the handler consumed appears. Bodies may hold credentials
+//! or personal data; leave this off outside of debugging.
+
+use std::cell::RefCell;
+use std::env;
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use futures_util::Stream;
+use log::warn;
+
+use crate::metrics::Metrics;
+use crate::middleware::request_id::request_id;
+
+/// Counter of requests at or over the threshold.
+pub const SLOW_REQUESTS_METRIC: &str = "slow_requests_total";
+
+/// Default for `SLOW_REQUEST_THRESHOLD_MS`.
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_millis(1000);
+
+/// Default for `SLOW_REQUEST_BODY_LOG_BYTES`.
+pub const DEFAULT_BODY_LOG_BYTES: usize = 1024;
+
+/// Middleware logging requests slower than a threshold.
+#[derive(Clone, Debug)]
+pub struct SlowRequestDetector {
+    threshold: Duration,
+    /// Bytes of the request body to log, or `None` to log none.
+    body_bytes: Option<usize>,
+}
+
+impl SlowRequestDetector {
+    /// Logs requests taking `threshold` or more, without their bodies.
+    pub fn new(threshold: Duration) -> Self {
+        SlowRequestDetector {
+            threshold,
+            body_bytes: None,
+        }
+    }
+
+    /// Also logs the first `bytes` bytes of the request body.
+    pub fn with_body_logging(mut self, bytes: usize) -> Self {
+        self.body_bytes = Some(bytes);
+        self
+    }
+
+    /// Reads `SLOW_REQUEST_THRESHOLD_MS`, `LOG_REQUEST_BODIES` and
+    /// `SLOW_REQUEST_BODY_LOG_BYTES`.
+    pub fn from_env() -> Self {
+        let threshold = env::var("SLOW_REQUEST_THRESHOLD_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_THRESHOLD);
+        let detector = SlowRequestDetector::new(threshold);
+        if !env::var("LOG_REQUEST_BODIES").is_ok_and(|v| v == "true") {
+            return detector;
+        }
+        let bytes = env::var("SLOW_REQUEST_BODY_LOG_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_BODY_LOG_BYTES);
+        warn!("LOG_REQUEST_BODIES=true: slow request bodies are logged");
+        detector.with_body_logging(bytes)
+    }
+}
+
+impl Default for SlowRequestDetector {
+    fn default() -> Self {
+        SlowRequestDetector::new(DEFAULT_THRESHOLD)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SlowRequestDetector
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SlowRequestDetectorMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SlowRequestDetectorMiddleware {
+            service,
+            config: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`SlowRequestDetector`].
+pub struct SlowRequestDetectorMiddleware<S> {
+    service: S,
+    config: SlowRequestDetector,
+}
+
+impl<S, B> Service<ServiceRequest> for SlowRequestDetectorMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let threshold = self.config.threshold;
+        let captured = self.config.body_bytes.map(|limit| {
+            let captured = Rc::new(RefCell::new(BytesMut::new()));
+            let payload = req.take_payload();
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(BodyPrefix {
+                    inner: payload,
+                    captured: captured.clone(),
+                    limit,
+                }),
+            });
+            captured
+        });
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let query = req.query_string().to_string();
+        let request_id = request_id(&req);
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let elapsed = started.elapsed();
+            if elapsed < threshold {
+                return res;
+            }
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            Metrics::global().inc(SLOW_REQUESTS_METRIC, &[]);
+            let body = captured
+                .map(|captured| {
+                    format!(
+                        " body={:?}",
+                        String::from_utf8_lossy(&captured.borrow()).as_ref()
+                    )
+                })
+                .unwrap_or_default();
+            warn!(
+                target: "slow_request",
+                "Slow request: duration_ms={} method={} path={:?} query={:?} status={} request_id={} user_agent={:?}{}",
+                elapsed.as_millis(),
+                method,
+                path,
+                query,
+                status.as_u16(),
+                request_id,
+                user_agent,
+                body
+            );
+            res
+        })
+    }
+}
+
+/// Request body stream recording its first `limit` bytes.
+struct BodyPrefix {
+    inner: Payload,
+    captured: Rc<RefCell<BytesMut>>,
+    limit: usize,
+}
+
+impl Stream for BodyPrefix {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            let mut captured = self.captured.borrow_mut();
+            let room = self.limit.saturating_sub(captured.len());
+            captured.extend_from_slice(&chunk[..room.min(chunk.len())]);
+        }
+        poll
+    }
+}
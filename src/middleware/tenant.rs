@@ -0,0 +1,327 @@
+//! Tenant resolution by subdomain.
+//!
+//! With `TENANT_BASE_DOMAIN=example.com`, a request for
+//! `tenant-a.example.com` belongs to tenant `tenant-a` if it is listed in
+//! `TENANTS`. The resolved [`Tenant`] is stored in the request extensions
+//! and handlers receive it through the `Tenant` extractor. Requests are
+//! answered with 404 when:
+//!
+//! * the subdomain is not a registered tenant, or
+//! * the host is neither under the base domain nor listed in `ALLOWED_HOSTS`.
+//!
+//! The apex domain and `ALLOWED_HOSTS` are served without a tenant;
+//! tenant-scoped handlers reject them through the extractor. A TLS SNI name
+//! that differs from the `Host` header is rejected with 421, so a client
+//! cannot reuse a connection set up for one tenant to reach another.
+//!
+//! Per-tenant state such as cache entries and rate limit buckets must be
+//! keyed with [`Tenant::scoped_key`]. `TENANT_RATE_LIMIT` enables a request
+//! rate limit per tenant, enforced here.
+
+use std::collections::HashSet;
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, ResponseError};
+use futures_util::future::LocalBoxFuture;
+use log::{info, warn};
+
+use crate::discovery;
+use crate::error::ApiError;
+use crate::throttle::{self, Throttle};
+use crate::tls_info::TlsInfo;
+use crate::util::rate_limit::RateLimiter;
+
+/// The tenant a request belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tenant {
+    pub id: String,
+}
+
+impl Tenant {
+    /// Prefixes `key` with the tenant, for caches, rate limits and any other
+    /// state shared across tenants.
+    pub fn scoped_key(&self, key: &str) -> String {
+        format!("tenant:{}:{}", self.id, key)
+    }
+}
+
+impl FromRequest for Tenant {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Tenant>()
+                .cloned()
+                .ok_or_else(unknown_tenant),
+        )
+    }
+}
+
+fn unknown_tenant() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "unknown_tenant", "Unknown tenant")
+}
+
+/// Known tenants and the domain they live under.
+#[derive(Clone, Debug, Default)]
+pub struct TenantRegistry {
+    base_domain: String,
+    tenants: HashSet<String>,
+    allowed_hosts: HashSet<String>,
+}
+
+/// How a host maps to a tenant.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostMatch {
+    Tenant(Tenant),
+    /// The apex domain or an allowed host, served without a tenant.
+    NoTenant,
+    Unknown,
+}
+
+impl TenantRegistry {
+    pub fn new<I, S>(base_domain: &str, tenants: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        TenantRegistry {
+            base_domain: normalize_host(base_domain),
+            tenants: tenants
+                .into_iter()
+                .map(|t| t.into().to_ascii_lowercase())
+                .collect(),
+            allowed_hosts: HashSet::new(),
+        }
+    }
+
+    /// Adds hosts outside the base domain that are served without a tenant.
+    pub fn with_allowed_hosts<I, S>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_hosts
+            .extend(hosts.into_iter().map(|h| normalize_host(&h.into())));
+        self
+    }
+
+    /// Reads `TENANT_BASE_DOMAIN`, `TENANTS` and `ALLOWED_HOSTS`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<TenantRegistry>` - The registry, or `None` if `TENANT_BASE_DOMAIN` is not set.
+    pub fn from_env() -> Option<Self> {
+        let base_domain = env::var("TENANT_BASE_DOMAIN").ok()?;
+        let list = |var: &str| -> Vec<String> {
+            env::var(var)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let registry = TenantRegistry::new(&base_domain, list("TENANTS"))
+            .with_allowed_hosts(list("ALLOWED_HOSTS"));
+        info!(
+            "Resolving {} tenant(s) under {}",
+            registry.tenants.len(),
+            registry.base_domain
+        );
+        Some(registry)
+    }
+
+    /// Maps a `Host` value (port allowed) to a tenant.
+    pub fn resolve(&self, host: &str) -> HostMatch {
+        let host = normalize_host(host);
+        if host == self.base_domain || self.allowed_hosts.contains(&host) {
+            return HostMatch::NoTenant;
+        }
+        let subdomain = host
+            .strip_suffix(&self.base_domain)
+            .and_then(|rest| rest.strip_suffix('.'));
+        match subdomain {
+            Some(id) if !id.contains('.') && self.tenants.contains(id) => {
+                HostMatch::Tenant(Tenant { id: id.to_string() })
+            }
+            _ => HostMatch::Unknown,
+        }
+    }
+}
+
+/// Lowercases a host and removes the port and any trailing dot.
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = if host.starts_with('[') {
+        // IPv6 literal, possibly with a port
+        host.split_inclusive(']').next().unwrap_or(host)
+    } else {
+        host.rsplit_once(':').map_or(host, |(name, _)| name)
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The host a request was sent to: the `Host` header, or the URI authority
+/// for HTTP/2.
+fn request_host(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| req.uri().authority().map(|a| a.as_str().to_string()))
+}
+
+/// Middleware resolving the tenant of each request.
+#[derive(Clone, Default)]
+pub struct TenantResolution {
+    registry: Arc<TenantRegistry>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl TenantResolution {
+    pub fn new(registry: TenantRegistry) -> Self {
+        TenantResolution {
+            registry: Arc::new(registry),
+            limiter: None,
+        }
+    }
+
+    /// Reads the registry (see [`TenantRegistry::from_env`]) and
+    /// `TENANT_RATE_LIMIT`, the requests per second allowed per tenant, with
+    /// bursts of `TENANT_RATE_BURST` (default: the rate).
+    ///
+    /// # Returns
+    ///
+    /// * `Option<TenantResolution>` - The middleware, or `None` if tenants are not configured.
+    pub fn from_env() -> Option<Self> {
+        let resolution = TenantResolution::new(TenantRegistry::from_env()?);
+        let rate = env::var("TENANT_RATE_LIMIT")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|&r| r > 0);
+        Some(match rate {
+            Some(rate) => {
+                let burst = env::var("TENANT_RATE_BURST")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(rate);
+                resolution.with_rate_limit(rate, burst)
+            }
+            None => resolution,
+        })
+    }
+
+    /// Limits each tenant to `per_second` requests per second, with bursts
+    /// of `burst`.
+    pub fn with_rate_limit(mut self, per_second: u32, burst: u32) -> Self {
+        self.limiter = Some(Arc::new(RateLimiter::new(per_second, burst)));
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for TenantResolution
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = TenantResolutionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TenantResolutionMiddleware {
+            service,
+            config: self.clone(),
+        }))
+    }
+}
+
+/// Service produced by [`TenantResolution`].
+pub struct TenantResolutionMiddleware<S> {
+    service: S,
+    config: TenantResolution,
+}
+
+impl<S, B> Service<ServiceRequest> for TenantResolutionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let host = request_host(&req).unwrap_or_default();
+        let sni = req
+            .request()
+            .conn_data::<TlsInfo>()
+            .and_then(|info| info.sni_hostname.clone());
+
+        let rejection = if sni.is_some_and(|sni| normalize_host(&sni) != normalize_host(&host)) {
+            warn!(
+                "Rejected request for host '{}' on a connection for another SNI name",
+                host
+            );
+            Some((
+                ApiError::new(
+                    StatusCode::MISDIRECTED_REQUEST,
+                    "sni_host_mismatch",
+                    "The request host does not match the TLS server name",
+                ),
+                None,
+            ))
+        } else {
+            match self.config.registry.resolve(&host) {
+                HostMatch::Tenant(tenant) => {
+                    let limited = self
+                        .config
+                        .limiter
+                        .as_ref()
+                        .filter(|_| !discovery::is_public(req.path()))
+                        .and_then(|limiter| limiter.check(&tenant.scoped_key("requests")).err());
+                    req.extensions_mut().insert(tenant);
+                    limited.map(|retry_after| {
+                        (
+                            ApiError::new(
+                                StatusCode::TOO_MANY_REQUESTS,
+                                "rate_limited",
+                                "Too many requests for this tenant",
+                            ),
+                            Some(retry_after),
+                        )
+                    })
+                }
+                HostMatch::NoTenant => None,
+                HostMatch::Unknown => Some((unknown_tenant(), None)),
+            }
+        };
+
+        if let Some((error, retry_after)) = rejection {
+            let res = match retry_after {
+                Some(retry_after) => {
+                    throttle::respond(req.request(), Throttle::RateLimited, error, retry_after)
+                }
+                None => error.error_response(),
+            };
+            return Box::pin(ready(Ok(req.into_response(res).map_into_right_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
@@ -0,0 +1,154 @@
+//! The shared HTTP client for requests to configured endpoints.
+//!
+//! Health checks of HTTP dependencies and the Consul registration send
+//! requests every few seconds. Building a `reqwest::Client` for each, or
+//! even for each check, opens a new connection and TLS session every time
+//! and churns file descriptors. [`HttpClient`] is built once at startup,
+//! before the workers start, and cloned into everything that needs it, so
+//! they share its connection pool.
+//!
+//! It is only for URLs set in the configuration: URLs influenced by
+//! clients go through [`OutboundClient`](super::OutboundClient), which pins
+//! each request to addresses its policy checked. The reverse proxy and the
+//! traffic mirror keep clients of their own, as they need upstream
+//! certificate pinning and no redirects.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::resolver::{self, ReqwestResolver};
+
+/// Default for `HTTP_CLIENT_CONNECT_TIMEOUT_MS`.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default for `HTTP_CLIENT_TIMEOUT_MS`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default for `HTTP_CLIENT_POOL_IDLE_TIMEOUT_SECS`.
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Default for `HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST`.
+pub const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 8;
+
+/// Timeouts and connection pool limits of an [`HttpClient`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpClientSettings {
+    /// Longest wait for a TCP connection and TLS handshake.
+    pub connect_timeout: Duration,
+    /// Longest wait for a whole request, response body included.
+    pub timeout: Duration,
+    /// How long an idle pooled connection is kept.
+    pub pool_idle_timeout: Duration,
+    /// Idle connections kept per host.
+    pub pool_max_idle_per_host: usize,
+}
+
+impl Default for HttpClientSettings {
+    fn default() -> Self {
+        HttpClientSettings {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+        }
+    }
+}
+
+impl HttpClientSettings {
+    /// Reads `HTTP_CLIENT_CONNECT_TIMEOUT_MS`, `HTTP_CLIENT_TIMEOUT_MS`,
+    /// `HTTP_CLIENT_POOL_IDLE_TIMEOUT_SECS` and
+    /// `HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if one of them is not a number, or a timeout is
+    /// zero.
+    pub fn from_env() -> Result<Self, IoError> {
+        let defaults = HttpClientSettings::default();
+        Ok(HttpClientSettings {
+            connect_timeout: timeout_from_env(
+                "HTTP_CLIENT_CONNECT_TIMEOUT_MS",
+                defaults.connect_timeout,
+            )?,
+            timeout: timeout_from_env("HTTP_CLIENT_TIMEOUT_MS", defaults.timeout)?,
+            pool_idle_timeout: number_from_env("HTTP_CLIENT_POOL_IDLE_TIMEOUT_SECS")?
+                .map_or(defaults.pool_idle_timeout, Duration::from_secs),
+            pool_max_idle_per_host: number_from_env("HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST")?
+                .map_or(defaults.pool_max_idle_per_host, |n| n as usize),
+        })
+    }
+}
+
+fn number_from_env(name: &str) -> Result<Option<u64>, IoError> {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map(Some).map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("{} must be a number, got '{}'", name, value),
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+fn timeout_from_env(name: &str, default: Duration) -> Result<Duration, IoError> {
+    match number_from_env(name)? {
+        Some(0) => Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("{} must be greater than zero", name),
+        )),
+        Some(ms) => Ok(Duration::from_millis(ms)),
+        None => Ok(default),
+    }
+}
+
+/// An HTTP client with a connection pool, shared by cloning.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    settings: HttpClientSettings,
+}
+
+impl HttpClient {
+    /// Builds the client: rustls with the web PKI roots, TLS 1.2 or later,
+    /// host names resolved by the shared [`resolver`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the TLS backend cannot be initialized.
+    pub fn new(settings: HttpClientSettings) -> Result<Self, IoError> {
+        let client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .min_tls_version(reqwest::tls::Version::TLS_1_2)
+            .dns_resolver(Arc::new(ReqwestResolver(resolver::shared())))
+            .connect_timeout(settings.connect_timeout)
+            .timeout(settings.timeout)
+            .pool_idle_timeout(settings.pool_idle_timeout)
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+            .build()
+            .map_err(|e| IoError::other(format!("cannot build the HTTP client: {}", e)))?;
+        Ok(HttpClient { client, settings })
+    }
+
+    /// Builds the client with [`HttpClientSettings::from_env`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the settings are invalid or the client cannot
+    /// be built.
+    pub fn from_env() -> Result<Self, IoError> {
+        HttpClient::new(HttpClientSettings::from_env()?)
+    }
+
+    /// The underlying client; clones share its connection pool.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// The settings the client was built with.
+    pub fn settings(&self) -> &HttpClientSettings {
+        &self.settings
+    }
+}
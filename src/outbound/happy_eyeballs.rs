@@ -0,0 +1,127 @@
+//! Happy eyeballs (RFC 8305) address ordering and connection racing.
+//!
+//! On networks where IPv6 is broken, connecting to an IPv6 address can
+//! stall until the connect timeout. Addresses are therefore interleaved by
+//! family, and [`connect`] starts the next attempt when the previous one has
+//! not succeeded within the attempt delay, keeping earlier attempts running.
+//! The first connection to succeed wins.
+//!
+//! The reqwest clients get addresses in [`interleave`] order; their
+//! connector races the two families itself.
+
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+
+use super::resolver;
+use super::Resolve;
+
+/// Delay before the next connection attempt starts (RFC 8305 section 5).
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Something with an IP address family.
+pub trait HasIp: Copy {
+    fn ip(&self) -> IpAddr;
+}
+
+impl HasIp for IpAddr {
+    fn ip(&self) -> IpAddr {
+        *self
+    }
+}
+
+impl HasIp for SocketAddr {
+    fn ip(&self) -> IpAddr {
+        SocketAddr::ip(self)
+    }
+}
+
+/// Orders addresses alternating between families, starting with the family
+/// of the first address (RFC 8305 section 4). The order within each family
+/// is kept.
+pub fn interleave<A: HasIp>(addrs: &[A]) -> Vec<A> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let first_is_v6 = first.ip().is_ipv6();
+    let (mut preferred, mut other): (Vec<A>, Vec<A>) = addrs
+        .iter()
+        .copied()
+        .partition(|a| a.ip().is_ipv6() == first_is_v6);
+    let mut preferred = preferred.drain(..);
+    let mut other = other.drain(..);
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to the first address that answers, racing attempts started
+/// `attempt_delay` apart in [`interleave`] order. A failed attempt starts
+/// the next one immediately.
+///
+/// # Errors
+///
+/// Returns the last connection error when every attempt failed.
+pub async fn connect(addrs: &[SocketAddr], attempt_delay: Duration) -> Result<TcpStream, IoError> {
+    let mut remaining = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = IoError::new(ErrorKind::NotFound, "no addresses to connect to");
+
+    loop {
+        if attempts.is_empty() {
+            match remaining.next() {
+                Some(addr) => attempts.push(TcpStream::connect(addr)),
+                None => return Err(last_error),
+            }
+        }
+        let next_attempt = tokio::time::sleep(attempt_delay);
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    last_error = e;
+                    if let Some(addr) = remaining.next() {
+                        attempts.push(TcpStream::connect(addr));
+                    }
+                }
+            },
+            _ = next_attempt, if remaining.len() > 0 => {
+                if let Some(addr) = remaining.next() {
+                    attempts.push(TcpStream::connect(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Connects to `host:port`, resolving the host with the shared
+/// [`DnsResolver`](super::resolver::DnsResolver) and racing its addresses.
+///
+/// # Errors
+///
+/// Returns an IoError if the address is malformed, the host does not
+/// resolve, or no address accepts the connection.
+pub async fn connect_host(addr: &str, attempt_delay: Duration) -> Result<TcpStream, IoError> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return TcpStream::connect(addr).await;
+    }
+    let (host, port) = addr
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("invalid address '{}', expected host:port", addr),
+            )
+        })?;
+    let addrs = resolver::shared().resolve(host, port).await?;
+    connect(&addrs, attempt_delay).await
+}
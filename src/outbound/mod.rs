@@ -0,0 +1,336 @@
+//! Outbound HTTP client with SSRF protection.
+//!
+//! Features that fetch URLs influenced by clients (such as OCSP responders
+//! named in client certificates) use [`OutboundClient`]. Every request, and
+//! every redirect it follows, is checked against a [`UrlPolicy`]:
+//!
+//! 1. the scheme and port must be allowed;
+//! 2. the host is resolved, and every resolved address is checked;
+//! 3. connections are opened by a resolver that checks the addresses again,
+//!    so a DNS answer changing between check and connect (rebinding) cannot
+//!    redirect them.
+//!
+//! A client keeps one connection pool, shared by its clones, so connections
+//! to a host are reused across requests. Proxies are never used: a proxy
+//! would resolve the host again, past the checks.
+//!
+//! Redirects are followed manually, up to `max_redirects`. Violations fail
+//! with [`OutboundError::Blocked`] and are written to the audit log.
+//!
+//! A client created for a request with
+//! [`with_correlation`](OutboundClient::with_correlation) sends the
+//! request's ID as `X-Request-Id`, so the called service continues the
+//! correlation chain, and its trace context as `traceparent` and
+//! `tracestate`.
+//!
+//! Host names are resolved by the shared [`resolver::DnsResolver`] and
+//! connected to in [`happy_eyeballs`] order.
+//!
+//! Requests to URLs set in the configuration use the shared
+//! [`HttpClient`] instead; see [`client`].
+
+pub mod client;
+pub mod happy_eyeballs;
+pub mod policy;
+pub mod resolver;
+
+pub use client::{HttpClient, HttpClientSettings};
+pub use policy::UrlPolicy;
+
+use async_trait::async_trait;
+use log::warn;
+use reqwest::header::{HeaderValue, CONTENT_TYPE, LOCATION};
+use reqwest::{Method, Response, StatusCode, Url};
+use serde_json::json;
+use std::fmt;
+use std::io::Error as IoError;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audit;
+use crate::middleware::request_id::{
+    CorrelationChain, REQUEST_ID_HEADER, TRACEPARENT_HEADER, TRACESTATE_HEADER,
+};
+
+/// Why an outbound request failed.
+#[derive(Debug)]
+pub enum OutboundError {
+    InvalidUrl(String),
+    /// The destination is not allowed by the [`UrlPolicy`].
+    Blocked(String),
+    TooManyRedirects,
+    Resolve(IoError),
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboundError::InvalidUrl(e) => write!(f, "invalid URL: {}", e),
+            OutboundError::Blocked(reason) => write!(f, "outbound request blocked: {}", reason),
+            OutboundError::TooManyRedirects => write!(f, "too many redirects"),
+            OutboundError::Resolve(e) => write!(f, "failed to resolve host: {}", e),
+            OutboundError::Request(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OutboundError {}
+
+/// Resolves host names for the [`OutboundClient`].
+#[async_trait]
+pub trait Resolve: Send + Sync {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, IoError>;
+}
+
+/// Resolver using the system's DNS configuration.
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolve for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, IoError> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// Resolver of the reqwest client behind an [`OutboundClient`], only
+/// returning addresses the policy allows.
+struct CheckedResolver {
+    policy: Arc<UrlPolicy>,
+    resolver: Arc<dyn Resolve>,
+}
+
+impl reqwest::dns::Resolve for CheckedResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let policy = self.policy.clone();
+        let resolver = self.resolver.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs = resolver.resolve(host, 0).await?;
+            for addr in &addrs {
+                policy.check_ip(host, addr.ip())?;
+            }
+            let addrs: reqwest::dns::Addrs =
+                Box::new(happy_eyeballs::interleave(&addrs).into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Builds the reqwest client connecting through `resolver`.
+fn build_client(policy: &Arc<UrlPolicy>, resolver: &Arc<dyn Resolve>) -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy()
+        .dns_resolver(Arc::new(CheckedResolver {
+            policy: policy.clone(),
+            resolver: resolver.clone(),
+        }))
+        .build()
+        .expect("Failed to build outbound HTTP client")
+}
+
+/// HTTP client enforcing a [`UrlPolicy`].
+#[derive(Clone)]
+pub struct OutboundClient {
+    client: reqwest::Client,
+    policy: Arc<UrlPolicy>,
+    resolver: Arc<dyn Resolve>,
+    timeout: Duration,
+    correlation: Option<CorrelationChain>,
+}
+
+impl OutboundClient {
+    /// Creates a client using the shared [`resolver::DnsResolver`] and a 10
+    /// second timeout.
+    pub fn new(policy: UrlPolicy) -> Self {
+        let policy = Arc::new(policy);
+        let resolver: Arc<dyn Resolve> = resolver::shared();
+        OutboundClient {
+            client: build_client(&policy, &resolver),
+            policy,
+            resolver,
+            timeout: Duration::from_secs(10),
+            correlation: None,
+        }
+    }
+
+    /// Returns a client propagating `chain` to the services it calls.
+    pub fn with_correlation(&self, chain: &CorrelationChain) -> Self {
+        OutboundClient {
+            correlation: Some(chain.clone()),
+            ..self.clone()
+        }
+    }
+
+    /// Replaces the resolver, starting a new connection pool.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolve>) -> Self {
+        self.client = build_client(&self.policy, &resolver);
+        self.resolver = resolver;
+        self
+    }
+
+    /// Sets the timeout of each request, including each redirect.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn policy(&self) -> &UrlPolicy {
+        &self.policy
+    }
+
+    /// Sends a GET request.
+    ///
+    /// # Errors
+    ///
+    /// See [`send`](Self::send).
+    pub async fn get(&self, url: &str) -> Result<Response, OutboundError> {
+        self.send(Method::GET, url, None).await
+    }
+
+    /// Sends a POST request with the given body.
+    ///
+    /// # Errors
+    ///
+    /// See [`send`](Self::send).
+    pub async fn post(
+        &self,
+        url: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Response, OutboundError> {
+        self.send(Method::POST, url, Some((content_type.to_string(), body)))
+            .await
+    }
+
+    /// Sends a request, following redirects that pass the policy.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OutboundError::Blocked`] if the URL or any redirect target
+    /// violates the policy, [`OutboundError::TooManyRedirects`] past the
+    /// redirect limit, and other variants for invalid URLs and I/O failures.
+    pub async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<(String, Vec<u8>)>,
+    ) -> Result<Response, OutboundError> {
+        let mut url = Url::parse(url).map_err(|e| OutboundError::InvalidUrl(e.to_string()))?;
+        let mut method = method;
+        let mut body = body;
+
+        for _ in 0..=self.policy.max_redirects {
+            self.checked_addrs(&url).await?;
+
+            let mut request = self
+                .client
+                .request(method.clone(), url.clone())
+                .timeout(self.timeout);
+            if let Some(chain) = &self.correlation {
+                request = request.header(REQUEST_ID_HEADER, chain.own());
+                if let Some(trace) = chain.trace() {
+                    request = request.header(TRACEPARENT_HEADER, trace.traceparent());
+                    if let Some(state) = trace.tracestate() {
+                        request = request.header(TRACESTATE_HEADER, state);
+                    }
+                }
+            }
+            if let Some((content_type, bytes)) = &body {
+                request = request
+                    .header(
+                        CONTENT_TYPE,
+                        HeaderValue::from_str(content_type)
+                            .map_err(|e| OutboundError::InvalidUrl(e.to_string()))?,
+                    )
+                    .body(bytes.clone());
+            }
+            let response = request.send().await.map_err(OutboundError::Request)?;
+
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+            let Some(location) = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|l| l.to_str().ok())
+            else {
+                return Ok(response);
+            };
+            url = url
+                .join(location)
+                .map_err(|e| OutboundError::InvalidUrl(e.to_string()))?;
+
+            // 303, and 301/302 after POST, turn into a body-less GET
+            let status = response.status();
+            if status == StatusCode::SEE_OTHER
+                || (method == Method::POST
+                    && (status == StatusCode::MOVED_PERMANENTLY || status == StatusCode::FOUND))
+            {
+                method = Method::GET;
+                body = None;
+            }
+        }
+
+        warn!(
+            "Outbound request exceeded {} redirects",
+            self.policy.max_redirects
+        );
+        Err(OutboundError::TooManyRedirects)
+    }
+
+    /// Checks `url` against the policy and resolves it to permitted
+    /// addresses.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OutboundError::Blocked`] if the URL or any address it
+    /// resolves to is denied; blocked attempts are audited.
+    pub async fn checked_addrs(&self, url: &Url) -> Result<Vec<SocketAddr>, OutboundError> {
+        let result = self.check_and_resolve(url).await;
+        if let Err(OutboundError::Blocked(reason)) = &result {
+            warn!("Blocked outbound request to {}: {}", url, reason);
+            let details = json!({ "url": url.as_str(), "reason": reason });
+            match &self.correlation {
+                Some(chain) => audit::record_for(chain, "outbound_request_blocked", details),
+                None => audit::record("outbound_request_blocked", details),
+            }
+        }
+        result
+    }
+
+    async fn check_and_resolve(&self, url: &Url) -> Result<Vec<SocketAddr>, OutboundError> {
+        self.policy.check_url(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| OutboundError::InvalidUrl("URL has no host".to_string()))?;
+        let port = url.port_or_known_default().unwrap_or(0);
+
+        let literal = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>();
+        let addrs = match literal {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => self
+                .resolver
+                .resolve(host, port)
+                .await
+                .map_err(OutboundError::Resolve)?,
+        };
+        if addrs.is_empty() {
+            return Err(OutboundError::Resolve(IoError::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} did not resolve", host),
+            )));
+        }
+
+        // Every address must pass: the connection may use any of them
+        for addr in &addrs {
+            self.policy.check_ip(host, addr.ip())?;
+        }
+        Ok(happy_eyeballs::interleave(&addrs))
+    }
+}
@@ -0,0 +1,253 @@
+//! Rules for which outbound destinations may be contacted.
+
+use std::env;
+use std::io::Error as IoError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use reqwest::Url;
+
+use crate::util::real_ip::Cidr;
+
+use super::OutboundError;
+
+/// Policy applied to every URL fetched by the
+/// [`OutboundClient`](super::OutboundClient), including each redirect hop.
+///
+/// Addresses in private, loopback, link-local (including the cloud metadata
+/// service at 169.254.169.254), unique-local, multicast and other reserved
+/// ranges are denied unless their network is listed in `allowed_networks`.
+/// Hosts in `allowed_hosts` are exempt from that check, but not from the
+/// scheme and port allowlists.
+#[derive(Clone, Debug)]
+pub struct UrlPolicy {
+    pub allowed_schemes: Vec<String>,
+    /// Permitted ports; empty allows any port.
+    pub allowed_ports: Vec<u16>,
+    pub allowed_hosts: Vec<String>,
+    pub allowed_networks: Vec<Cidr>,
+    pub max_redirects: usize,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        UrlPolicy {
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_ports: vec![80, 443],
+            allowed_hosts: Vec::new(),
+            allowed_networks: Vec::new(),
+            max_redirects: 3,
+        }
+    }
+}
+
+impl UrlPolicy {
+    /// Reads the policy from `OUTBOUND_*` environment variables.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a port or network cannot be parsed.
+    pub fn from_env() -> Result<Self, IoError> {
+        let list = |name: &str| -> Option<Vec<String>> {
+            env::var(name).ok().map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+        };
+
+        let mut policy = UrlPolicy::default();
+        if let Some(schemes) = list("OUTBOUND_ALLOWED_SCHEMES") {
+            policy.allowed_schemes = schemes.iter().map(|s| s.to_ascii_lowercase()).collect();
+        }
+        if let Some(ports) = list("OUTBOUND_ALLOWED_PORTS") {
+            policy.allowed_ports = ports
+                .iter()
+                .map(|p| {
+                    p.parse().map_err(|_| {
+                        IoError::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Invalid port '{}' in OUTBOUND_ALLOWED_PORTS", p),
+                        )
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(hosts) = list("OUTBOUND_ALLOWED_HOSTS") {
+            policy.allowed_hosts = hosts.iter().map(|h| h.to_ascii_lowercase()).collect();
+        }
+        if let Ok(networks) = env::var("OUTBOUND_ALLOWED_NETWORKS") {
+            policy.allowed_networks = networks
+                .split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?;
+        }
+        policy.max_redirects = env::var("OUTBOUND_MAX_REDIRECTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(policy.max_redirects);
+        Ok(policy)
+    }
+
+    /// Allows a host regardless of the address it resolves to.
+    pub fn allow_host(mut self, host: &str) -> Self {
+        self.allowed_hosts.push(host.to_ascii_lowercase());
+        self
+    }
+
+    /// Allows the host of an operator-configured `url` like
+    /// [`allow_host`](Self::allow_host), and its scheme and port.
+    pub fn allow_url(mut self, url: &Url) -> Self {
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(url.scheme()))
+        {
+            self.allowed_schemes.push(url.scheme().to_ascii_lowercase());
+        }
+        if let Some(port) = url.port_or_known_default() {
+            if !self.allowed_ports.is_empty() && !self.allowed_ports.contains(&port) {
+                self.allowed_ports.push(port);
+            }
+        }
+        match url.host_str() {
+            Some(host) => self.allow_host(host),
+            None => self,
+        }
+    }
+
+    /// Returns whether `host` is exempt from the address checks.
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.allowed_hosts
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(host))
+    }
+
+    /// Checks the scheme and port of `url`. The host is checked once
+    /// resolved, with [`check_ip`](Self::check_ip).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OutboundError::Blocked`] for a disallowed scheme, port or
+    /// missing host.
+    pub fn check_url(&self, url: &Url) -> Result<(), OutboundError> {
+        if url.host_str().is_none() {
+            return Err(OutboundError::Blocked("URL has no host".to_string()));
+        }
+        if !url.username().is_empty() || url.password().is_some() {
+            return Err(OutboundError::Blocked(
+                "URLs with credentials are not allowed".to_string(),
+            ));
+        }
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(OutboundError::Blocked(format!(
+                "scheme '{}' is not allowed",
+                url.scheme()
+            )));
+        }
+        let port = url.port_or_known_default().unwrap_or(0);
+        if !self.allowed_ports.is_empty() && !self.allowed_ports.contains(&port) {
+            return Err(OutboundError::Blocked(format!(
+                "port {} is not allowed",
+                port
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks an address `host` resolved to.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`OutboundError::Blocked`] if the address is in a denied range
+    /// that is not allowlisted.
+    pub fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), OutboundError> {
+        if self.is_allowed_host(host) {
+            return Ok(());
+        }
+        let ip = ip.to_canonical();
+        if self.allowed_networks.iter().any(|n| n.contains(&ip)) {
+            return Ok(());
+        }
+        if is_internal(ip) {
+            return Err(OutboundError::Blocked(format!(
+                "{} resolves to internal address {}",
+                host, ip
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether `ip` is in a range that must not be reached from outside.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => is_internal_v6(ip),
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved, 240.0.0.0/4
+        || a >= 240
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // NAT64 (64:ff9b::/96) embeds an IPv4 address in its last 32 bits
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return is_internal_v4(embedded_v4(segments[6], segments[7]));
+    }
+    // 6to4 (2002::/16) embeds an IPv4 address after the prefix
+    if segments[0] == 0x2002 {
+        return is_internal_v4(embedded_v4(segments[1], segments[2]));
+    }
+    // Local-use NAT64 (64:ff9b:1::/48) only translates into the operator's
+    // own network, and the embedded address's position depends on the
+    // prefix length chosen there
+    if segments[..3] == [0x64, 0xff9b, 1] {
+        return true;
+    }
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link local, fe80::/10
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Deprecated site local, fec0::/10
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation, 2001:db8::/32
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+}
+
+/// Builds the IPv4 address carried in two IPv6 segments.
+fn embedded_v4(high: u16, low: u16) -> Ipv4Addr {
+    let [a, b] = high.to_be_bytes();
+    let [c, d] = low.to_be_bytes();
+    Ipv4Addr::new(a, b, c, d)
+}
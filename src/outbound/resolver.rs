@@ -0,0 +1,235 @@
+//! DNS resolution for outbound connections.
+//!
+//! [`DnsResolver`] queries A and AAAA records with hickory-resolver, either
+//! through the system configuration or the nameservers in
+//! `OUTBOUND_NAMESERVERS`. Every nameserver is used over UDP and TCP, so
+//! truncated answers are retried over TCP instead of failing.
+//!
+//! Answers are cached per host until their TTL expires (at most
+//! `OUTBOUND_DNS_MAX_TTL_SECS`). A host answering only one address family is
+//! fine: a failed AAAA lookup does not fail the resolution when A records
+//! exist, and vice versa.
+//!
+//! The resolver backs both the [`OutboundClient`](super::OutboundClient),
+//! whose [`UrlPolicy`](super::UrlPolicy) checks the resolved addresses, and
+//! the reverse proxy's HTTP client. Addresses are returned in
+//! [happy eyeballs](super::happy_eyeballs) order.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use log::{info, warn};
+
+use super::happy_eyeballs;
+use super::Resolve;
+use crate::metrics::Metrics;
+
+/// Default upper bound on how long an answer is cached.
+pub const DEFAULT_MAX_TTL: Duration = Duration::from_secs(300);
+
+/// A host name could not be resolved.
+#[derive(Debug)]
+pub struct ResolveFailure {
+    pub host: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ResolveFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to resolve {}: {}", self.host, self.reason)
+    }
+}
+
+impl std::error::Error for ResolveFailure {}
+
+/// Caching resolver for outbound connections.
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+    max_ttl: Duration,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl DnsResolver {
+    /// Creates a resolver querying `nameservers`, or the system's
+    /// nameservers when the list is empty. Without a readable system
+    /// configuration, hickory's default public nameservers are used.
+    pub fn new(nameservers: &[SocketAddr], max_ttl: Duration) -> Self {
+        let (config, mut opts) = if nameservers.is_empty() {
+            hickory_resolver::system_conf::read_system_conf().unwrap_or_else(|e| {
+                warn!("Failed to read the system DNS configuration: {}", e);
+                (ResolverConfig::default(), ResolverOpts::default())
+            })
+        } else {
+            let mut group = NameServerConfigGroup::new();
+            for ns in nameservers {
+                // UDP and TCP for each, so truncated answers fall back to TCP
+                group.merge(NameServerConfigGroup::from_ips_clear(
+                    &[ns.ip()],
+                    ns.port(),
+                    true,
+                ));
+            }
+            (
+                ResolverConfig::from_parts(None, vec![], group),
+                ResolverOpts::default(),
+            )
+        };
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        // Answers are cached here, where the TTL bound applies
+        opts.cache_size = 0;
+        DnsResolver {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            max_ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the resolver from `OUTBOUND_NAMESERVERS` (comma-separated
+    /// `ip:port` or `ip`, port 53) and `OUTBOUND_DNS_MAX_TTL_SECS`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DnsResolver, IoError>` - The resolver, or an IoError if a nameserver is invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        let nameservers = env::var("OUTBOUND_NAMESERVERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|ns| !ns.is_empty())
+            .map(|ns| {
+                ns.parse::<SocketAddr>()
+                    .or_else(|_| ns.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .map_err(|_| {
+                        IoError::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid OUTBOUND_NAMESERVERS entry '{}'", ns),
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let max_ttl = env::var("OUTBOUND_DNS_MAX_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_MAX_TTL);
+        if !nameservers.is_empty() {
+            info!("Outbound DNS nameservers: {:?}", nameservers);
+        }
+        Ok(DnsResolver::new(&nameservers, max_ttl))
+    }
+
+    /// Resolves `host` to its IPv4 and IPv6 addresses, in happy eyeballs
+    /// order.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ResolveFailure`] if neither family has an address.
+    pub async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, ResolveFailure> {
+        let key = host.to_ascii_lowercase();
+        if let Some((ips, _)) = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(_, expires)| *expires > Instant::now())
+        {
+            Metrics::global().inc("outbound_dns_cache_total", &[("result", "hit")]);
+            return Ok(ips.clone());
+        }
+        Metrics::global().inc("outbound_dns_cache_total", &[("result", "miss")]);
+
+        let failure = |reason: String| {
+            warn!("Failed to resolve {}: {}", host, reason);
+            ResolveFailure {
+                host: host.to_string(),
+                reason,
+            }
+        };
+        let lookup = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| failure(e.to_string()))?;
+        let ips: Vec<IpAddr> = lookup.iter().collect();
+        if ips.is_empty() {
+            return Err(failure("no addresses".to_string()));
+        }
+        let ips = happy_eyeballs::interleave(&ips);
+
+        let ttl = lookup
+            .valid_until()
+            .saturating_duration_since(Instant::now())
+            .min(self.max_ttl);
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        cache.retain(|_, (_, expires)| *expires > now);
+        cache.insert(key, (ips.clone(), now + ttl));
+        Ok(ips)
+    }
+}
+
+#[async_trait]
+impl Resolve for DnsResolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, IoError> {
+        let ips = self
+            .lookup(host)
+            .await
+            .map_err(|e| IoError::new(ErrorKind::NotFound, e))?;
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+}
+
+/// Adapter making a [`DnsResolver`] the resolver of a reqwest client.
+pub struct ReqwestResolver(pub Arc<DnsResolver>);
+
+impl reqwest::dns::Resolve for ReqwestResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.0.clone();
+        Box::pin(async move {
+            let ips = resolver.lookup(name.as_str()).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+static SHARED: OnceLock<Arc<DnsResolver>> = OnceLock::new();
+
+/// Installs the resolver returned by [`shared`]. Only the first call has an
+/// effect.
+pub fn install(resolver: DnsResolver) {
+    let _ = SHARED.set(Arc::new(resolver));
+}
+
+/// Returns the installed resolver, or one using the system configuration.
+pub fn shared() -> Arc<DnsResolver> {
+    SHARED
+        .get_or_init(|| Arc::new(DnsResolver::new(&[], DEFAULT_MAX_TTL)))
+        .clone()
+}
+
+/// Returns whether `error` was caused by a failed name resolution.
+pub fn is_resolve_failure(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<ResolveFailure>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
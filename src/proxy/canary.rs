@@ -0,0 +1,334 @@
+//! Canary routing to an alternate upstream.
+//!
+//! With `PROXY_CANARY_URL` set, part of the proxied traffic below
+//! `PROXY_CANARY_ROUTES` goes to the canary upstream instead of the primary
+//! backends:
+//!
+//! * requests whose `PROXY_CANARY_HEADER` header or `PROXY_CANARY_COOKIE`
+//!   cookie matches, either exactly `PROXY_CANARY_VALUE` or, with
+//!   `PROXY_CANARY_HMAC_KEY`, as an `<id>.<signature>` value signed with
+//!   that key (see [`sign`]);
+//! * `PROXY_CANARY_PERCENT` of the remaining callers. The assignment is
+//!   sticky: callers get a random ID in the `PROXY_CANARY_STICKY_COOKIE`
+//!   cookie, and the hash of that ID picks the upstream.
+//!
+//! The feature flag named by `PROXY_CANARY_FLAG` is a kill switch: while it
+//! evaluates off, every request goes to the primary. Requests also fall back
+//! to the primary while the canary's circuit breaker is open.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{web, HttpRequest};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use log::info;
+use reqwest::Url;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::{parse_upstream, BackendPool};
+use crate::flags::{self, Flags};
+use crate::middleware::api_key::constant_time_eq;
+
+/// Counter of proxied requests by upstream (`primary` or `canary`) and
+/// outcome (`ok` or `error`).
+pub const UPSTREAM_REQUESTS_METRIC: &str = "proxy_upstream_requests_total";
+/// Counter of canary requests sent to the primary because the canary's
+/// circuit was open.
+pub const CANARY_FALLBACK_METRIC: &str = "proxy_canary_fallbacks_total";
+/// Default for `PROXY_CANARY_STICKY_COOKIE`.
+pub const DEFAULT_STICKY_COOKIE: &str = "canary_bucket";
+/// How long the sticky assignment cookie lasts.
+const STICKY_MAX_AGE_DAYS: i64 = 30;
+
+/// How an opt-in header or cookie value is checked.
+#[derive(Clone)]
+pub enum CanaryMatch {
+    /// The value must equal this string.
+    Exact(String),
+    /// The value must be `<id>.<signature>`, signed with this key.
+    Signed(hmac::Key),
+}
+
+impl CanaryMatch {
+    /// Returns whether `value` opts in to the canary.
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            CanaryMatch::Exact(expected) => constant_time_eq(value.as_bytes(), expected.as_bytes()),
+            CanaryMatch::Signed(key) => value
+                .rsplit_once('.')
+                .filter(|(id, _)| !id.is_empty())
+                .and_then(|(id, signature)| {
+                    URL_SAFE_NO_PAD
+                        .decode(signature)
+                        .ok()
+                        .map(|signature| hmac::verify(key, id.as_bytes(), &signature).is_ok())
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Returns the `<id>.<signature>` opt-in value for `id`.
+pub fn sign(key: &hmac::Key, id: &str) -> String {
+    format!(
+        "{}.{}",
+        id,
+        URL_SAFE_NO_PAD.encode(hmac::sign(key, id.as_bytes()).as_ref())
+    )
+}
+
+/// Which requests go to the canary.
+#[derive(Clone)]
+pub struct CanaryConfig {
+    pub url: Url,
+    /// Path prefixes below the proxy prefix; empty applies to all.
+    pub routes: Vec<String>,
+    /// Header whose value opts a request in.
+    pub header: Option<String>,
+    /// Cookie whose value opts a request in.
+    pub cookie: Option<String>,
+    /// Check of the opt-in header or cookie; `None` disables opting in.
+    pub matcher: Option<CanaryMatch>,
+    /// Share of callers, 0 to 100, sent to the canary.
+    pub percent: u8,
+    /// Cookie holding the caller's sticky assignment ID.
+    pub sticky_cookie: String,
+    /// Feature flag that must be on for the canary to receive traffic.
+    pub flag: Option<String>,
+}
+
+impl CanaryConfig {
+    /// Sends no traffic to `url` until opt-in or a percentage is set.
+    pub fn new(url: Url) -> Self {
+        CanaryConfig {
+            url,
+            routes: Vec::new(),
+            header: None,
+            cookie: None,
+            matcher: None,
+            percent: 0,
+            sticky_cookie: DEFAULT_STICKY_COOKIE.to_string(),
+            flag: None,
+        }
+    }
+
+    /// Returns whether requests to `path` may go to the canary.
+    pub fn matches(&self, path: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|r| path.starts_with(r.as_str()))
+    }
+}
+
+/// Upstream chosen for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Upstream {
+    Primary,
+    Canary,
+}
+
+impl Upstream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Upstream::Primary => "primary",
+            Upstream::Canary => "canary",
+        }
+    }
+}
+
+/// Outcome of [`Canary::select`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub upstream: Upstream,
+    /// New sticky assignment ID to set on the response.
+    pub assign: Option<String>,
+}
+
+/// Routes part of the proxied traffic to the canary upstream.
+pub struct Canary {
+    config: CanaryConfig,
+    pool: BackendPool,
+    rng: SystemRandom,
+}
+
+impl Canary {
+    /// Creates a canary whose circuit breaker uses the pool defaults.
+    pub fn new(config: CanaryConfig) -> Self {
+        let pool = BackendPool::new(vec![config.url.clone()], super::Strategy::RoundRobin);
+        Self::with_pool(config, pool)
+    }
+
+    /// Creates a canary sending its requests through `pool`, whose circuit
+    /// breaker decides when to fall back to the primary.
+    pub fn with_pool(config: CanaryConfig, pool: BackendPool) -> Self {
+        Canary {
+            config,
+            pool,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Builds the canary from the `PROXY_CANARY_*` variables, with the
+    /// circuit breaker settings of the primary backends.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Canary>, IoError>` - The canary, `None` if `PROXY_CANARY_URL` is unset, or an IoError if the configuration is invalid.
+    pub fn from_env() -> Result<Option<Canary>, IoError> {
+        let Ok(url) = env::var("PROXY_CANARY_URL") else {
+            return Ok(None);
+        };
+        let invalid = |message: &str| IoError::new(ErrorKind::InvalidInput, message.to_string());
+        let mut config = CanaryConfig::new(parse_upstream("PROXY_CANARY_URL", &url)?);
+        config.routes = env::var("PROXY_CANARY_ROUTES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(str::to_string)
+            .collect();
+        config.header = env::var("PROXY_CANARY_HEADER").ok();
+        config.cookie = env::var("PROXY_CANARY_COOKIE").ok();
+        config.matcher = match (
+            env::var("PROXY_CANARY_HMAC_KEY"),
+            env::var("PROXY_CANARY_VALUE"),
+        ) {
+            (Ok(key), _) => {
+                let key = STANDARD
+                    .decode(key.trim())
+                    .map_err(|_| invalid("PROXY_CANARY_HMAC_KEY must be base64"))?;
+                Some(CanaryMatch::Signed(hmac::Key::new(hmac::HMAC_SHA256, &key)))
+            }
+            (Err(_), Ok(value)) if !value.is_empty() => Some(CanaryMatch::Exact(value)),
+            _ => None,
+        };
+        if config.matcher.is_some() && config.header.is_none() && config.cookie.is_none() {
+            return Err(invalid(
+                "PROXY_CANARY_VALUE and PROXY_CANARY_HMAC_KEY need PROXY_CANARY_HEADER or PROXY_CANARY_COOKIE",
+            ));
+        }
+        config.percent = match env::var("PROXY_CANARY_PERCENT") {
+            Ok(percent) => percent
+                .parse()
+                .ok()
+                .filter(|p| *p <= 100)
+                .ok_or_else(|| invalid("PROXY_CANARY_PERCENT must be between 0 and 100"))?,
+            Err(_) => 0,
+        };
+        if let Ok(cookie) = env::var("PROXY_CANARY_STICKY_COOKIE") {
+            config.sticky_cookie = cookie;
+        }
+        config.flag = env::var("PROXY_CANARY_FLAG").ok();
+        info!(
+            "Routing {}% of proxied traffic{} to canary {}",
+            config.percent,
+            if config.matcher.is_some() {
+                " and opted-in requests"
+            } else {
+                ""
+            },
+            config.url
+        );
+        let pool = BackendPool::from_env(vec![config.url.clone()])?;
+        Ok(Some(Canary::with_pool(config, pool)))
+    }
+
+    pub fn config(&self) -> &CanaryConfig {
+        &self.config
+    }
+
+    /// Returns the canary backend and its circuit breaker.
+    pub fn pool(&self) -> &BackendPool {
+        &self.pool
+    }
+
+    /// Decides which upstream serves `req`, whose path below the proxy
+    /// prefix is `path`. The canary's circuit is not consulted here.
+    pub fn select(&self, req: &HttpRequest, path: &str) -> Selection {
+        let primary = Selection {
+            upstream: Upstream::Primary,
+            assign: None,
+        };
+        if !self.config.matches(path) {
+            return primary;
+        }
+        if let Some(flag) = &self.config.flag {
+            let enabled = req
+                .app_data::<web::Data<Flags>>()
+                .is_some_and(|flags| flags.is_enabled(flag, req));
+            if !enabled {
+                return primary;
+            }
+        }
+        if self.opted_in(req) {
+            return Selection {
+                upstream: Upstream::Canary,
+                assign: None,
+            };
+        }
+        if self.config.percent == 0 {
+            return primary;
+        }
+        let (id, assign) = match req.cookie(&self.config.sticky_cookie) {
+            Some(cookie) if is_assignment_id(cookie.value()) => (cookie.value().to_string(), None),
+            _ => {
+                let id = self.new_assignment_id();
+                (id.clone(), Some(id))
+            }
+        };
+        Selection {
+            upstream: if flags::bucket("proxy-canary", &id) < self.config.percent {
+                Upstream::Canary
+            } else {
+                Upstream::Primary
+            },
+            assign,
+        }
+    }
+
+    /// Returns the cookie recording a new sticky assignment, scoped to the
+    /// proxy `prefix`.
+    pub fn assignment_cookie(&self, prefix: &str, id: String) -> Cookie<'static> {
+        Cookie::build(self.config.sticky_cookie.clone(), id)
+            .path(if prefix.is_empty() { "/" } else { prefix }.to_string())
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::days(STICKY_MAX_AGE_DAYS))
+            .finish()
+    }
+
+    fn opted_in(&self, req: &HttpRequest) -> bool {
+        let Some(matcher) = &self.config.matcher else {
+            return false;
+        };
+        let header = self.config.header.as_ref().and_then(|name| {
+            req.headers()
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        });
+        let cookie = self
+            .config
+            .cookie
+            .as_ref()
+            .and_then(|name| req.cookie(name))
+            .map(|c| c.value().to_string());
+        header
+            .into_iter()
+            .chain(cookie)
+            .any(|v| matcher.matches(&v))
+    }
+
+    fn new_assignment_id(&self) -> String {
+        let mut bytes = [0u8; 16];
+        // A failed fill still yields a valid, if shared, assignment
+        let _ = self.rng.fill(&mut bytes);
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Returns whether `value` looks like an ID from a sticky cookie.
+fn is_assignment_id(value: &str) -> bool {
+    value.len() == 32 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
@@ -0,0 +1,120 @@
+//! Circuit breaker for proxy backends.
+//!
+//! After `failure_threshold` consecutive failures the circuit opens and the
+//! backend is skipped for `open_for`. The circuit then becomes half-open:
+//! one trial request is let through, closing the circuit on success and
+//! reopening it on failure.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// State of a [`CircuitBreaker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+/// Tracks failures of one backend.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// Opens after `failure_threshold` consecutive failures, for `open_for`.
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            inner: Mutex::new(Inner {
+                consecutive_failures: 0,
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state_at(Instant::now())
+    }
+
+    /// Like [`state`](Self::state), at the given time.
+    pub fn state_at(&self, now: Instant) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        Self::state_of(&inner, self.open_for, now)
+    }
+
+    fn state_of(inner: &Inner, open_for: Duration, now: Instant) -> CircuitState {
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(opened) if now.saturating_duration_since(opened) < open_for => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Returns whether a request may be sent. In the half-open state only
+    /// the first caller gets the trial request.
+    pub fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    /// Like [`allow`](Self::allow), at the given time.
+    pub fn allow_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match Self::state_of(&inner, self.open_for, now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if inner.trial_in_flight => false,
+            CircuitState::HalfOpen => {
+                inner.trial_in_flight = true;
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.trial_in_flight = false;
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    /// Like [`record_failure`](Self::record_failure), at the given time.
+    pub fn record_failure_at(&self, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        if inner.trial_in_flight || inner.consecutive_failures >= self.failure_threshold {
+            inner.opened_at = Some(now);
+            inner.trial_in_flight = false;
+        }
+    }
+
+    /// Releases a trial request that ended without a result, e.g. because
+    /// the client went away.
+    pub fn abandon(&self) {
+        self.inner.lock().unwrap().trial_in_flight = false;
+    }
+}
@@ -0,0 +1,408 @@
+//! Shadow traffic to a secondary upstream.
+//!
+//! A sample of proxied requests is replayed against `MIRROR_URL` after the
+//! primary upstream has answered. The mirror's response never reaches the
+//! client: only its status, latency and a SHA-256 of its body are recorded,
+//! alongside the primary's hash so differing responses can be found in the
+//! `proxy_mirror` log. Mirror requests run detached, with their own timeout
+//! and a cap on how many may be in flight, so a slow or failing mirror
+//! cannot delay primary responses.
+//!
+//! The configuration can be replaced at runtime through
+//! `/admin/proxy/mirror`.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpResponse};
+use log::{info, warn};
+use reqwest::Url;
+use ring::digest;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{parse_upstream, upstream_url, Proxy};
+use crate::audit::{AuditTrail, Outcome};
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::middleware::access_log::sample_bucket;
+use crate::outbound::resolver::{self, ReqwestResolver};
+use crate::route_meta::{self, RouteMetadata};
+
+/// Counter of mirror requests by response status, or `error`.
+pub const MIRROR_REQUESTS_METRIC: &str = "proxy_mirror_requests_total";
+/// Counter of sampled requests that were not mirrored, by reason.
+pub const MIRROR_SKIPPED_METRIC: &str = "proxy_mirror_skipped_total";
+/// Sum of mirror request latencies in milliseconds.
+pub const MIRROR_LATENCY_METRIC: &str = "proxy_mirror_latency_ms_total";
+/// Counter of mirror responses whose status and body matched the primary's.
+pub const MIRROR_COMPARISON_METRIC: &str = "proxy_mirror_comparisons_total";
+
+/// Default for `MIRROR_MAX_BODY_BYTES`.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Most mirror requests in flight at once; further samples are skipped.
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// Where and how much traffic to mirror.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MirrorConfig {
+    pub url: Url,
+    /// Fraction (0.0-1.0) of matching requests to mirror.
+    pub sample_rate: f64,
+    /// Path prefixes below the proxy prefix to mirror; empty mirrors all.
+    pub routes: Vec<String>,
+    /// Requests with larger bodies are not mirrored.
+    pub max_body_bytes: usize,
+}
+
+impl MirrorConfig {
+    /// Mirrors every request to `url`.
+    pub fn new(url: Url) -> Self {
+        MirrorConfig {
+            url,
+            sample_rate: 1.0,
+            routes: Vec::new(),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
+
+    /// Returns whether requests to `path` are mirrored.
+    pub fn matches(&self, path: &str) -> bool {
+        self.routes.is_empty() || self.routes.iter().any(|r| path.starts_with(r.as_str()))
+    }
+}
+
+/// Settings accepted by `PUT /admin/proxy/mirror`.
+#[derive(Deserialize)]
+pub struct MirrorSettings {
+    pub url: String,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub routes: Vec<String>,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_max_body_bytes() -> usize {
+    DEFAULT_MAX_BODY_BYTES
+}
+
+impl MirrorSettings {
+    /// Validates the settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the URL is not http(s) or the sample rate is
+    /// outside 0.0-1.0.
+    pub fn into_config(self) -> Result<MirrorConfig, IoError> {
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "sample_rate must be between 0.0 and 1.0",
+            ));
+        }
+        Ok(MirrorConfig {
+            url: parse_upstream("url", &self.url)?,
+            sample_rate: self.sample_rate,
+            routes: self.routes,
+            max_body_bytes: self.max_body_bytes,
+        })
+    }
+}
+
+/// A proxied request as sent to the primary upstream.
+pub struct MirrorRequest {
+    pub method: Method,
+    /// Path below the proxy prefix, with the query string.
+    pub path_and_query: String,
+    /// Forwarded headers, hop-by-hop headers already removed.
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: web::Bytes,
+}
+
+/// Replays sampled requests against the mirror upstream.
+pub struct Mirror {
+    config: RwLock<Option<Arc<MirrorConfig>>>,
+    client: reqwest::Client,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Mirror {
+    /// Creates a mirror whose requests time out after `timeout`. `None`
+    /// leaves mirroring disabled until configured.
+    pub fn new(config: Option<MirrorConfig>, timeout: Duration) -> Self {
+        Mirror {
+            config: RwLock::new(config.map(Arc::new)),
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .dns_resolver(Arc::new(ReqwestResolver(resolver::shared())))
+                .timeout(timeout)
+                .build()
+                .expect("Failed to build mirror HTTP client"),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Builds the mirror from `MIRROR_URL`, `MIRROR_SAMPLE_RATE`,
+    /// `MIRROR_ROUTES`, `MIRROR_MAX_BODY_BYTES` and `MIRROR_TIMEOUT_MS`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if `MIRROR_URL` or `MIRROR_SAMPLE_RATE` is invalid.
+    pub fn from_env() -> Result<Mirror, IoError> {
+        let timeout = env::var("MIRROR_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::from_secs(5));
+        let Ok(url) = env::var("MIRROR_URL") else {
+            return Ok(Mirror::new(None, timeout));
+        };
+        let config = MirrorSettings {
+            url,
+            sample_rate: match env::var("MIRROR_SAMPLE_RATE") {
+                Ok(rate) => rate.parse().map_err(|_| {
+                    IoError::new(ErrorKind::InvalidInput, "Invalid MIRROR_SAMPLE_RATE")
+                })?,
+                Err(_) => default_sample_rate(),
+            },
+            routes: env::var("MIRROR_ROUTES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string)
+                .collect(),
+            max_body_bytes: env::var("MIRROR_MAX_BODY_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_BODY_BYTES),
+        }
+        .into_config()?;
+        info!(
+            "Mirroring {:.0}% of proxied traffic to {}",
+            config.sample_rate * 100.0,
+            config.url
+        );
+        Ok(Mirror::new(Some(config), timeout))
+    }
+
+    /// Returns the current configuration, `None` when disabled.
+    pub fn config(&self) -> Option<Arc<MirrorConfig>> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the configuration; `None` disables mirroring.
+    pub fn set_config(&self, config: Option<MirrorConfig>) {
+        *self.config.write().unwrap() = config.map(Arc::new);
+    }
+
+    /// Decides whether the request `request_id` to `path` is mirrored.
+    ///
+    /// Sampling uses the request ID, so the decision is stable for a request
+    /// and proportional across many.
+    pub fn select(
+        &self,
+        request_id: &str,
+        path: &str,
+        body_len: usize,
+    ) -> Option<Arc<MirrorConfig>> {
+        let config = self.config()?;
+        if !config.matches(path) || sample_bucket(request_id) >= config.sample_rate {
+            return None;
+        }
+        if body_len > config.max_body_bytes {
+            Metrics::global().inc(MIRROR_SKIPPED_METRIC, &[("reason", "body_too_large")]);
+            return None;
+        }
+        Some(config)
+    }
+
+    /// Sends `request` to the mirror in the background and records how its
+    /// response compares with the primary's.
+    pub fn dispatch(
+        &self,
+        config: Arc<MirrorConfig>,
+        request: MirrorRequest,
+        primary_status: u16,
+        primary_body: &[u8],
+    ) {
+        if self.in_flight.fetch_add(1, Ordering::AcqRel) >= MAX_IN_FLIGHT {
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+            Metrics::global().inc(MIRROR_SKIPPED_METRIC, &[("reason", "overloaded")]);
+            return;
+        }
+        let primary_hash = body_hash(primary_body);
+        let client = self.client.clone();
+        let in_flight = self.in_flight.clone();
+
+        actix_web::rt::spawn(async move {
+            let mut builder = client.request(
+                request.method.clone(),
+                upstream_url(&config.url, &request.path_and_query),
+            );
+            for (name, value) in &request.headers {
+                builder = builder.header(name.as_str(), value.as_slice());
+            }
+
+            let started = Instant::now();
+            let result = match builder.body(request.body).send().await {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    response.bytes().await.map(|body| (status, body))
+                }
+                Err(e) => Err(e),
+            };
+            let metrics = Metrics::global();
+            metrics
+                .counter(MIRROR_LATENCY_METRIC, &[])
+                .fetch_add(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+            match result {
+                Ok((status, body)) => {
+                    let mirror_hash = body_hash(&body);
+                    let matched = status == primary_status && mirror_hash == primary_hash;
+                    let status_label = status.to_string();
+                    metrics.inc(MIRROR_REQUESTS_METRIC, &[("status", status_label.as_str())]);
+                    metrics.inc(
+                        MIRROR_COMPARISON_METRIC,
+                        &[("result", if matched { "match" } else { "mismatch" })],
+                    );
+                    info!(
+                        target: "proxy_mirror",
+                        "{} {} primary={} sha256={} mirror={} sha256={}",
+                        request.method,
+                        request.path_and_query,
+                        primary_status,
+                        primary_hash,
+                        status,
+                        mirror_hash
+                    );
+                }
+                Err(e) => {
+                    metrics.inc(MIRROR_REQUESTS_METRIC, &[("status", "error")]);
+                    warn!(
+                        target: "proxy_mirror",
+                        "{} {} mirror request failed: {}",
+                        request.method,
+                        request.path_and_query,
+                        e
+                    );
+                }
+            }
+            in_flight.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+}
+
+/// Returns the hex SHA-256 of a response body.
+pub fn body_hash(body: &[u8]) -> String {
+    digest::digest(&digest::SHA256, body)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Registers the mirror admin routes on the `/admin` scope.
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    route_meta::record(
+        "GET",
+        "/proxy/mirror",
+        RouteMetadata::new("Traffic mirror settings"),
+    );
+    route_meta::record(
+        "PUT",
+        "/proxy/mirror",
+        RouteMetadata::new("Replace the traffic mirror settings"),
+    );
+    route_meta::record(
+        "DELETE",
+        "/proxy/mirror",
+        RouteMetadata::new("Turn traffic mirroring off"),
+    );
+    cfg.service(
+        web::resource("/proxy/mirror")
+            .route(web::get().to(get_mirror))
+            .route(web::put().to(put_mirror))
+            .route(web::delete().to(delete_mirror)),
+    );
+}
+
+fn describe(config: Option<Arc<MirrorConfig>>) -> HttpResponse {
+    HttpResponse::Ok().json(match config {
+        Some(config) => json!({
+            "enabled": true,
+            "url": config.url.as_str(),
+            "sample_rate": config.sample_rate,
+            "routes": config.routes,
+            "max_body_bytes": config.max_body_bytes,
+        }),
+        None => json!({ "enabled": false }),
+    })
+}
+
+/// Handler for `GET /admin/proxy/mirror`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the current mirror configuration.
+pub async fn get_mirror(proxy: web::Data<Proxy>) -> HttpResponse {
+    describe(proxy.mirror().config())
+}
+
+/// Handler for `PUT /admin/proxy/mirror`, replacing the configuration.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the new configuration, or 400 if it is invalid.
+pub async fn put_mirror(
+    proxy: web::Data<Proxy>,
+    settings: web::Json<MirrorSettings>,
+    trail: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    let result = settings.into_inner().into_config().map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_mirror_config",
+            e.to_string(),
+        )
+    });
+    let detail = match &result {
+        Ok(config) => json!({
+            "url": config.url.as_str(),
+            "sample_rate": config.sample_rate,
+        }),
+        Err(_) => json!({}),
+    };
+    trail.record_result("proxy_mirror_updated", detail, &result);
+    let config = result?;
+    info!(
+        "Mirror reconfigured: {:.0}% to {}",
+        config.sample_rate * 100.0,
+        config.url
+    );
+    proxy.mirror().set_config(Some(config));
+    Ok(describe(proxy.mirror().config()))
+}
+
+/// Handler for `DELETE /admin/proxy/mirror`, disabling mirroring.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the (disabled) configuration.
+pub async fn delete_mirror(proxy: web::Data<Proxy>, trail: AuditTrail) -> HttpResponse {
+    info!("Mirror disabled");
+    proxy.mirror().set_config(None);
+    trail.record("proxy_mirror_disabled", Outcome::Success);
+    describe(None)
+}
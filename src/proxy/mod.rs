@@ -0,0 +1,423 @@
+//! Reverse proxy to one or more upstreams.
+//!
+//! Requests under `PROXY_PATH_PREFIX` are forwarded to `PROXY_UPSTREAM_URL`,
+//! or to one of the `PROXY_BACKENDS` chosen by the [`BackendPool`], with the
+//! prefix removed. Hop-by-hop headers are dropped in both directions. The
+//! upstreams are operator configured, so requests to them do not go through
+//! the outbound [`UrlPolicy`](crate::outbound::UrlPolicy).
+//!
+//! A [`Mirror`] can additionally replay a sample of the proxied traffic
+//! against a second upstream; see [`mirror`]. A [`Canary`] can instead
+//! serve part of the traffic from an alternate upstream; see [`canary`].
+//! Upstream certificates can be pinned with a [`TlsPinner`]; see
+//! [`tls_pin`].
+
+pub mod canary;
+pub mod circuit_breaker;
+pub mod mirror;
+pub mod pool;
+pub mod tls_pin;
+
+pub use canary::{Canary, CanaryConfig, CanaryMatch, Upstream};
+pub use circuit_breaker::{CircuitBreaker, CircuitState};
+pub use mirror::{Mirror, MirrorConfig, MirrorRequest};
+pub use pool::{BackendPool, Strategy};
+pub use tls_pin::TlsPinner;
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{info, warn};
+use reqwest::Url;
+
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::middleware::request_id::{
+    RequestId, TraceContext, REQUEST_ID_HEADER, TRACEPARENT_HEADER, TRACESTATE_HEADER,
+};
+use crate::outbound::resolver::{self, ReqwestResolver};
+use crate::route_meta::{self, DescribedRoute, RouteMetadata, ScopeMetadata};
+use crate::util::query_builder::QueryStringBuilder;
+use canary::{CANARY_FALLBACK_METRIC, UPSTREAM_REQUESTS_METRIC};
+
+/// Headers that apply to a single connection and are never forwarded.
+pub const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+/// Returns whether `name` must not be forwarded.
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Forwards requests to the upstreams.
+pub struct Proxy {
+    prefix: String,
+    pool: BackendPool,
+    client: reqwest::Client,
+    mirror: Arc<Mirror>,
+    canary: Option<Canary>,
+    pinner: Option<Arc<TlsPinner>>,
+}
+
+impl Proxy {
+    /// Creates a proxy forwarding requests under `prefix` to `upstream`.
+    pub fn new(prefix: &str, upstream: Url, mirror: Mirror) -> Self {
+        Self::with_pool(
+            prefix,
+            BackendPool::new(vec![upstream], Strategy::RoundRobin),
+            mirror,
+        )
+    }
+
+    /// Creates a proxy balancing requests under `prefix` across `pool`.
+    pub fn with_pool(prefix: &str, pool: BackendPool, mirror: Mirror) -> Self {
+        Proxy {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            pool,
+            client: build_client(None),
+            mirror: Arc::new(mirror),
+            canary: None,
+            pinner: None,
+        }
+    }
+
+    /// Checks upstream certificates against the pins of `pinner`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if a root certificate added to `pinner` is invalid.
+    pub fn with_pinner(mut self, pinner: TlsPinner) -> Result<Self, IoError> {
+        let pinner = Arc::new(pinner);
+        self.client = build_client(Some(TlsPinner::client_config(&pinner)?));
+        self.pinner = Some(pinner);
+        Ok(self)
+    }
+
+    /// Serves part of the traffic from `canary`.
+    pub fn with_canary(mut self, canary: Canary) -> Self {
+        self.canary = Some(canary);
+        self
+    }
+
+    /// Builds the proxy from `PROXY_BACKENDS` (or `PROXY_UPSTREAM_URL`),
+    /// `PROXY_PATH_PREFIX`, the balancing settings, the mirror settings and
+    /// the canary settings and `PINNED_CERTS_FILE`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Proxy>, IoError>` - The proxy, `None` if no upstream is configured, or an IoError if the configuration is invalid.
+    pub fn from_env() -> Result<Option<Proxy>, IoError> {
+        let upstreams = match env::var("PROXY_BACKENDS") {
+            Ok(backends) => backends
+                .split(',')
+                .map(str::trim)
+                .filter(|b| !b.is_empty())
+                .map(|b| parse_upstream("PROXY_BACKENDS", b))
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(_) => match env::var("PROXY_UPSTREAM_URL") {
+                Ok(upstream) => vec![parse_upstream("PROXY_UPSTREAM_URL", &upstream)?],
+                Err(_) => Vec::new(),
+            },
+        };
+        if upstreams.is_empty() {
+            return Ok(None);
+        }
+        let prefix = env::var("PROXY_PATH_PREFIX").unwrap_or_else(|_| "/proxy".to_string());
+        if !prefix.starts_with('/') {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "PROXY_PATH_PREFIX must start with '/'",
+            ));
+        }
+        let pool = BackendPool::from_env(upstreams)?;
+        let names: Vec<&str> = pool.backends().iter().map(|b| b.url().as_str()).collect();
+        info!(
+            "Proxying {} to {} ({:?})",
+            prefix,
+            names.join(", "),
+            pool.strategy()
+        );
+        let mut proxy = Proxy::with_pool(&prefix, pool, Mirror::from_env()?);
+        if let Some(pinner) = TlsPinner::from_env()? {
+            proxy = proxy.with_pinner(pinner)?;
+        }
+        Ok(Some(match Canary::from_env()? {
+            Some(canary) => proxy.with_canary(canary),
+            None => proxy,
+        }))
+    }
+
+    /// Returns the path prefix the proxy is mounted on.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns the backends requests are balanced across.
+    pub fn pool(&self) -> &BackendPool {
+        &self.pool
+    }
+
+    /// Returns the mirror, which can be reconfigured while serving.
+    pub fn mirror(&self) -> &Mirror {
+        &self.mirror
+    }
+
+    /// Returns the canary upstream, if configured.
+    pub fn canary(&self) -> Option<&Canary> {
+        self.canary.as_ref()
+    }
+
+    /// Returns the upstream certificate pins, if configured.
+    pub fn pinner(&self) -> Option<&TlsPinner> {
+        self.pinner.as_deref()
+    }
+
+    /// Registers the proxy under its prefix, along with the proxy itself as
+    /// app data for the mirror admin routes.
+    pub fn configure(proxy: web::Data<Proxy>) -> impl FnOnce(&mut web::ServiceConfig) {
+        move |cfg| {
+            let prefix = proxy.prefix.clone();
+            // The scope only has a default service, so describe its tail here
+            let describe = ScopeMetadata::new(&prefix).configure(|_| {
+                route_meta::record(
+                    "ANY",
+                    "/{tail}",
+                    RouteMetadata::new("Forwarded to the upstream"),
+                );
+            });
+            cfg.app_data(proxy).service(
+                web::scope(&prefix)
+                    .configure(describe)
+                    .default_service(web::to(forward)),
+            );
+        }
+    }
+}
+
+/// Registers the proxy admin routes (mirror settings, backend stats and
+/// certificate pins) on the `/admin` scope.
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    mirror::configure_admin(cfg);
+    cfg.described_route(
+        Method::GET,
+        "/backends/stats",
+        pool::backend_stats,
+        RouteMetadata::new("Upstream backend statistics"),
+    )
+    .described_route(
+        Method::GET,
+        "/tls/pins",
+        tls_pin::list_pins,
+        RouteMetadata::new("Upstream certificate pins and their use"),
+    );
+}
+
+/// Builds the upstream HTTP client, with `tls` replacing the default TLS
+/// configuration.
+fn build_client(tls: Option<rustls_client::ClientConfig>) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(ReqwestResolver(resolver::shared())))
+        .timeout(Duration::from_secs(30));
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(tls);
+    }
+    builder.build().expect("Failed to build proxy HTTP client")
+}
+
+/// Parses an upstream URL, accepting only http and https.
+pub(crate) fn parse_upstream(var: &str, value: &str) -> Result<Url, IoError> {
+    Url::parse(value)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("{} must be an http or https URL", var),
+            )
+        })
+}
+
+/// Joins the upstream base URL with the request path below the prefix.
+fn upstream_url(base: &Url, path_and_query: &str) -> String {
+    format!("{}{}", base.as_str().trim_end_matches('/'), path_and_query)
+}
+
+/// Handler forwarding a request to the upstream.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - The upstream's response, 502 Bad Gateway if it could not be reached, or 503 Service Unavailable if every backend's circuit is open.
+///
+/// Requests selected for the canary go to the primary backends while the
+/// canary's circuit is open.
+pub async fn forward(
+    req: HttpRequest,
+    body: web::Bytes,
+    proxy: web::Data<Proxy>,
+    request_id: RequestId,
+    trace: TraceContext,
+) -> Result<HttpResponse, ApiError> {
+    let tail = req.path().strip_prefix(&proxy.prefix).unwrap_or("");
+    let path_and_query = QueryStringBuilder::from_uri(req.uri()).append_to(tail);
+    // The caller's trace headers are replaced by this server's span
+    let mut headers: Vec<(String, Vec<u8>)> = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            !is_hop_by_hop(name.as_str())
+                && *name != REQUEST_ID_HEADER
+                && *name != TRACEPARENT_HEADER
+                && *name != TRACESTATE_HEADER
+        })
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .collect();
+    headers.push((
+        TRACEPARENT_HEADER.to_string(),
+        trace.traceparent().into_bytes(),
+    ));
+    if let Some(state) = trace.tracestate() {
+        headers.push((TRACESTATE_HEADER.to_string(), state.as_bytes().to_vec()));
+    }
+
+    // Decided before forwarding so the mirror sees the request as received
+    let mirror = proxy.mirror.select(&request_id.0, tail, body.len());
+
+    let selection = proxy
+        .canary
+        .as_ref()
+        .map(|canary| canary.select(&req, tail));
+    let canary_backend = match (&proxy.canary, &selection) {
+        (Some(canary), Some(s)) if s.upstream == Upstream::Canary => {
+            let backend = canary.pool().select();
+            if backend.is_none() {
+                warn!("Canary circuit is open, falling back to the primary");
+                Metrics::global().inc(CANARY_FALLBACK_METRIC, &[]);
+            }
+            backend
+        }
+        _ => None,
+    };
+    let (backend, served_by) = match canary_backend {
+        Some(backend) => (backend, Upstream::Canary),
+        None => {
+            let backend = proxy.pool.select().ok_or_else(|| {
+                warn!("No proxy backend available, every circuit is open");
+                ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "no_backend_available",
+                    "No upstream server is available",
+                )
+            })?;
+            (backend, Upstream::Primary)
+        }
+    };
+    let mut upstream = proxy.client.request(
+        req.method().clone(),
+        upstream_url(backend.url(), &path_and_query),
+    );
+    for (name, value) in &headers {
+        upstream = upstream.header(name.as_str(), value.as_slice());
+    }
+    upstream = upstream
+        .header(REQUEST_ID_HEADER, request_id.0.as_str())
+        .body(body.clone());
+
+    let response = match upstream.send().await {
+        Ok(response) => {
+            let success = !response.status().is_server_error();
+            backend.finish(success);
+            count_upstream_request(served_by, success);
+            response
+        }
+        Err(e) => {
+            backend.finish(false);
+            count_upstream_request(served_by, false);
+            return Err(upstream_error(e));
+        }
+    };
+    let status =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in response.headers() {
+        if !is_hop_by_hop(name.as_str()) {
+            builder.append_header((name.as_str(), value.as_bytes()));
+        }
+    }
+    let response_body = response.bytes().await.map_err(|e| {
+        warn!("Reading upstream response failed: {}", e);
+        bad_gateway()
+    })?;
+
+    if let Some(config) = mirror {
+        proxy.mirror.dispatch(
+            config,
+            MirrorRequest {
+                method: req.method().clone(),
+                path_and_query,
+                headers,
+                body,
+            },
+            status.as_u16(),
+            &response_body,
+        );
+    }
+
+    if let (Some(canary), Some(id)) = (&proxy.canary, selection.and_then(|s| s.assign)) {
+        builder.cookie(canary.assignment_cookie(&proxy.prefix, id));
+    }
+    Ok(builder.body(response_body))
+}
+
+fn count_upstream_request(upstream: Upstream, success: bool) {
+    Metrics::global().inc(
+        UPSTREAM_REQUESTS_METRIC,
+        &[
+            ("upstream", upstream.as_str()),
+            ("outcome", if success { "ok" } else { "error" }),
+        ],
+    );
+}
+
+fn upstream_error(e: reqwest::Error) -> ApiError {
+    warn!("Proxy request to upstream failed: {}", e);
+    if tls_pin::is_pin_failure(&e) {
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "upstream_pin_mismatch",
+            "The upstream certificate does not match the pinned keys",
+        )
+    } else if resolver::is_resolve_failure(&e) {
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "upstream_dns_failure",
+            "The upstream host name could not be resolved",
+        )
+    } else {
+        bad_gateway()
+    }
+}
+
+fn bad_gateway() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_GATEWAY,
+        "bad_gateway",
+        "The upstream server could not be reached",
+    )
+}
@@ -0,0 +1,277 @@
+//! Load balancing across several proxy backends.
+//!
+//! [`BackendPool`] picks a backend per request with one of the
+//! [`Strategy`] variants, skipping backends whose [`CircuitBreaker`] is
+//! open. Each backend counts its requests, errors (connection failures and
+//! 5xx responses) and in-flight requests; `GET /admin/backends/stats`
+//! reports them.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use reqwest::Url;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+
+use super::circuit_breaker::CircuitBreaker;
+use super::Proxy;
+
+/// How a backend is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    Random,
+}
+
+impl Strategy {
+    /// Parses `round_robin`, `least_connections` or `random`.
+    pub fn parse(value: &str) -> Option<Strategy> {
+        match value.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "round_robin" => Some(Strategy::RoundRobin),
+            "least_connections" => Some(Strategy::LeastConnections),
+            "random" => Some(Strategy::Random),
+            _ => None,
+        }
+    }
+}
+
+/// One upstream of the pool.
+pub struct Backend {
+    url: Url,
+    in_flight: Arc<AtomicUsize>,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    breaker: CircuitBreaker,
+}
+
+impl Backend {
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+}
+
+/// A request in flight to a backend. Dropping it without calling
+/// [`finish`](Self::finish) counts neither success nor failure.
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+    finished: bool,
+}
+
+impl BackendGuard {
+    fn new(backend: Arc<Backend>) -> Self {
+        backend.in_flight.fetch_add(1, Ordering::AcqRel);
+        backend.requests.fetch_add(1, Ordering::Relaxed);
+        BackendGuard {
+            backend,
+            finished: false,
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.backend.url
+    }
+
+    /// Records the outcome of the request.
+    pub fn finish(mut self, success: bool) {
+        self.finished = true;
+        if success {
+            self.backend.breaker.record_success();
+        } else {
+            self.backend.errors.fetch_add(1, Ordering::Relaxed);
+            self.backend.breaker.record_failure();
+        }
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.in_flight.fetch_sub(1, Ordering::AcqRel);
+        if !self.finished {
+            self.backend.breaker.abandon();
+        }
+    }
+}
+
+/// Counters of one backend, as reported by the stats endpoint.
+#[derive(Debug, Serialize)]
+pub struct BackendStats {
+    pub url: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub in_flight: usize,
+    pub circuit: &'static str,
+}
+
+/// Backends of the reverse proxy.
+pub struct BackendPool {
+    backends: Vec<Arc<Backend>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    rng: SystemRandom,
+}
+
+impl BackendPool {
+    /// Creates a pool whose circuit breakers open after 5 consecutive
+    /// failures, for 30 seconds.
+    pub fn new(urls: Vec<Url>, strategy: Strategy) -> Self {
+        Self::with_circuit_breaker(urls, strategy, 5, Duration::from_secs(30))
+    }
+
+    /// Creates a pool with the given circuit breaker settings.
+    pub fn with_circuit_breaker(
+        urls: Vec<Url>,
+        strategy: Strategy,
+        failure_threshold: u32,
+        open_for: Duration,
+    ) -> Self {
+        BackendPool {
+            backends: urls
+                .into_iter()
+                .map(|url| {
+                    Arc::new(Backend {
+                        url,
+                        in_flight: Arc::new(AtomicUsize::new(0)),
+                        requests: AtomicU64::new(0),
+                        errors: AtomicU64::new(0),
+                        breaker: CircuitBreaker::new(failure_threshold, open_for),
+                    })
+                })
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Creates a pool of `urls` using `PROXY_BALANCING` (default:
+    /// round_robin), `PROXY_CIRCUIT_FAILURES` and `PROXY_CIRCUIT_OPEN_SECS`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if `PROXY_BALANCING` names an unknown strategy.
+    pub fn from_env(urls: Vec<Url>) -> Result<Self, IoError> {
+        let strategy = match env::var("PROXY_BALANCING") {
+            Ok(value) => Strategy::parse(&value).ok_or_else(|| {
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "unknown PROXY_BALANCING '{}', expected round_robin, least_connections or random",
+                        value
+                    ),
+                )
+            })?,
+            Err(_) => Strategy::RoundRobin,
+        };
+        let failures = env::var("PROXY_CIRCUIT_FAILURES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        let open_for = env::var("PROXY_CIRCUIT_OPEN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30));
+        Ok(Self::with_circuit_breaker(
+            urls, strategy, failures, open_for,
+        ))
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Picks a backend for a request.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<BackendGuard>` - The chosen backend, or `None` if every circuit is open.
+    pub fn select(&self) -> Option<BackendGuard> {
+        let count = self.backends.len();
+        if count == 0 {
+            return None;
+        }
+        let order: Vec<usize> = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+                (0..count).map(|i| (start + i) % count).collect()
+            }
+            Strategy::Random => {
+                let mut bytes = [0u8; 8];
+                let start = match self.rng.fill(&mut bytes) {
+                    Ok(()) => u64::from_le_bytes(bytes) as usize % count,
+                    Err(_) => 0,
+                };
+                (0..count).map(|i| (start + i) % count).collect()
+            }
+            Strategy::LeastConnections => {
+                let mut order: Vec<usize> = (0..count).collect();
+                order.sort_by_key(|&i| self.backends[i].in_flight());
+                order
+            }
+        };
+        order
+            .into_iter()
+            .map(|i| &self.backends[i])
+            .find(|backend| backend.breaker.allow())
+            .map(|backend| BackendGuard::new(backend.clone()))
+    }
+
+    /// Returns the counters of every backend.
+    pub fn stats(&self) -> Vec<BackendStats> {
+        self.backends
+            .iter()
+            .map(|backend| {
+                let requests = backend.requests.load(Ordering::Relaxed);
+                let errors = backend.errors.load(Ordering::Relaxed);
+                BackendStats {
+                    url: backend.url.to_string(),
+                    requests,
+                    errors,
+                    error_rate: if requests == 0 {
+                        0.0
+                    } else {
+                        errors as f64 / requests as f64
+                    },
+                    in_flight: backend.in_flight(),
+                    circuit: backend.breaker.state().as_str(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Handler for `GET /admin/backends/stats`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with the strategy, per-backend counters and the canary's counters, `null` without a canary.
+pub async fn backend_stats(proxy: web::Data<Proxy>) -> HttpResponse {
+    let pool = proxy.pool();
+    HttpResponse::Ok().json(serde_json::json!({
+        "strategy": match pool.strategy() {
+            Strategy::RoundRobin => "round_robin",
+            Strategy::LeastConnections => "least_connections",
+            Strategy::Random => "random",
+        },
+        "backends": pool.stats(),
+        "canary": proxy.canary().map(|canary| canary.pool().stats().pop()),
+    }))
+}
@@ -0,0 +1,336 @@
+//! Certificate pinning for the proxy's upstream connections.
+//!
+//! `PINNED_CERTS_FILE` lists one `host:sha256` pin per line, the hash being
+//! the SHA-256 of a certificate's SubjectPublicKeyInfo, in base64 (as in
+//! `openssl x509 -pubkey | openssl pkey -pubin -outform der | openssl dgst
+//! -sha256 -binary | base64`) or hex. Blank lines and lines starting with
+//! `#` are ignored. A host may have several pins, e.g. the current key and
+//! its successor.
+//!
+//! Connections to a pinned host must pass the usual chain validation and
+//! present a leaf certificate whose key matches one of the host's pins;
+//! otherwise the handshake fails and the client gets 502
+//! `upstream_pin_mismatch`. Other certificates the upstream sends are not
+//! checked, since any server can append them. Hosts without pins are only
+//! validated against the web PKI roots.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{info, warn};
+use ring::digest;
+use rustls_client::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls_client::{
+    Certificate, CertificateError, ClientConfig, Error as TlsError, OwnedTrustAnchor,
+    RootCertStore, ServerName,
+};
+use serde_json::{json, Value};
+use x509_parser::prelude::parse_x509_certificate;
+
+use super::Proxy;
+use crate::metrics::Metrics;
+
+/// Counter of upstream handshakes rejected because no pin matched, by host.
+pub const PIN_MISMATCH_METRIC: &str = "proxy_tls_pin_mismatches_total";
+
+/// A pinned key hash and how many handshakes it accepted.
+struct Pin {
+    sha256: [u8; 32],
+    matches: AtomicU64,
+}
+
+/// The host presented no certificate matching its pins.
+#[derive(Debug)]
+pub struct PinMismatch {
+    pub host: String,
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "certificate of {} does not match any pinned key",
+            self.host
+        )
+    }
+}
+
+impl StdError for PinMismatch {}
+
+/// Pinned key hashes per upstream host.
+#[derive(Default)]
+pub struct TlsPinner {
+    pins: BTreeMap<String, Vec<Pin>>,
+    mismatches: BTreeMap<String, AtomicU64>,
+    roots: Vec<Vec<u8>>,
+}
+
+impl TlsPinner {
+    /// Parses `host:sha256` lines.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the first line without a host or with a hash
+    /// that is not 32 bytes of base64 or hex.
+    pub fn parse(text: &str) -> Result<Self, IoError> {
+        let mut pinner = TlsPinner::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                IoError::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "line {}: expected host:sha256 with a base64 or hex SPKI hash",
+                        number + 1
+                    ),
+                )
+            };
+            let (host, hash) = line.rsplit_once(':').ok_or_else(invalid)?;
+            let host = host.trim().trim_start_matches('[').trim_end_matches(']');
+            if host.is_empty() {
+                return Err(invalid());
+            }
+            let sha256 = decode_hash(hash.trim()).ok_or_else(invalid)?;
+            pinner.add(host, sha256);
+        }
+        Ok(pinner)
+    }
+
+    /// Loads the pins in `PINNED_CERTS_FILE`; `None` when it is unset.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or has an invalid line.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        let Ok(path) = env::var("PINNED_CERTS_FILE") else {
+            return Ok(None);
+        };
+        let pinner = TlsPinner::parse(&fs::read_to_string(&path)?).map_err(|e| {
+            IoError::new(
+                ErrorKind::InvalidData,
+                format!("Invalid PINNED_CERTS_FILE '{}': {}", path, e),
+            )
+        })?;
+        info!(
+            "Pinning upstream certificates of {} from {}",
+            pinner.hosts().collect::<Vec<_>>().join(", "),
+            path
+        );
+        Ok(Some(pinner))
+    }
+
+    /// Pins `sha256` for `host`.
+    pub fn add(&mut self, host: &str, sha256: [u8; 32]) {
+        let host = host.to_ascii_lowercase();
+        self.mismatches.entry(host.clone()).or_default();
+        let pins = self.pins.entry(host).or_default();
+        if !pins.iter().any(|p| p.sha256 == sha256) {
+            pins.push(Pin {
+                sha256,
+                matches: AtomicU64::new(0),
+            });
+        }
+    }
+
+    /// Also trusts the CA certificate `der`, e.g. a private CA of the
+    /// upstreams.
+    pub fn with_root_certificate(mut self, der: Vec<u8>) -> Self {
+        self.roots.push(der);
+        self
+    }
+
+    /// Returns the pinned hosts, sorted.
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        self.pins.keys().map(String::as_str)
+    }
+
+    /// Checks the leaf certificate presented by `host`. Hosts without pins
+    /// always pass.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PinMismatch`] if the leaf's key matches no pin.
+    pub fn check(&self, host: &str, end_entity: &[u8]) -> Result<(), PinMismatch> {
+        let host = host.to_ascii_lowercase();
+        let Some(pins) = self.pins.get(&host) else {
+            return Ok(());
+        };
+        let hash = spki_sha256(end_entity);
+        if let Some(pin) = pins.iter().find(|p| hash == Some(p.sha256)) {
+            pin.matches.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        if let Some(count) = self.mismatches.get(&host) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        warn!("Upstream {} presented a certificate matching no pin", host);
+        Metrics::global().inc(PIN_MISMATCH_METRIC, &[("host", host.as_str())]);
+        Err(PinMismatch { host })
+    }
+
+    /// Describes every pin with its match count, and the rejected
+    /// handshakes per host.
+    pub fn describe(&self) -> Value {
+        Value::Array(
+            self.pins
+                .iter()
+                .map(|(host, pins)| {
+                    json!({
+                        "host": host,
+                        "mismatches": self
+                            .mismatches
+                            .get(host)
+                            .map_or(0, |c| c.load(Ordering::Relaxed)),
+                        "pins": pins
+                            .iter()
+                            .map(|p| json!({
+                                "sha256": STANDARD.encode(p.sha256),
+                                "matches": p.matches.load(Ordering::Relaxed),
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Builds the TLS configuration of the proxy's HTTP client, validating
+    /// chains against the web PKI roots (and the added roots) and checking
+    /// the pins of `pinner`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an added root certificate is invalid.
+    pub fn client_config(pinner: &Arc<TlsPinner>) -> Result<ClientConfig, IoError> {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        for der in &pinner.roots {
+            roots.add(&Certificate(der.clone())).map_err(|e| {
+                IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid root certificate: {}", e),
+                )
+            })?;
+        }
+        let verifier = PinningVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pinner: pinner.clone(),
+        };
+        Ok(ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth())
+    }
+}
+
+/// Chain validation followed by the pin check of the leaf.
+struct PinningVerifier {
+    inner: WebPkiVerifier,
+    pinner: Arc<TlsPinner>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+            _ => return Ok(verified),
+        };
+        self.pinner
+            .check(&host, &end_entity.0)
+            .map(|()| verified)
+            .map_err(|e| TlsError::InvalidCertificate(CertificateError::Other(Arc::new(e))))
+    }
+}
+
+/// Returns the SHA-256 of the SubjectPublicKeyInfo of the DER certificate
+/// `der`.
+pub fn spki_sha256(der: &[u8]) -> Option<[u8; 32]> {
+    let (_, certificate) = parse_x509_certificate(der).ok()?;
+    let hash = digest::digest(&digest::SHA256, certificate.public_key().raw);
+    hash.as_ref().try_into().ok()
+}
+
+fn decode_hash(value: &str) -> Option<[u8; 32]> {
+    let bytes = if value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..32)
+            .map(|i| u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?
+    } else {
+        STANDARD.decode(value).ok()?
+    };
+    bytes.try_into().ok()
+}
+
+/// Returns whether `error` was caused by an upstream failing its pins.
+pub fn is_pin_failure(error: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if error.is::<PinMismatch>() {
+            return true;
+        }
+        // Connectors may wrap the TLS error in several I/O errors, whose
+        // `source` skips the error they wrap
+        let mut inner = Some(error);
+        while let Some(io) = inner.and_then(|e| e.downcast_ref::<IoError>()) {
+            inner = io.get_ref().map(|e| e as &(dyn StdError + 'static));
+        }
+        if let Some(TlsError::InvalidCertificate(CertificateError::Other(other))) =
+            inner.and_then(|e| e.downcast_ref::<TlsError>())
+        {
+            if other.is::<PinMismatch>() {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Handler for `GET /admin/tls/pins`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - 200 OK with each pinned host, its pins and their match counts; empty without `PINNED_CERTS_FILE`.
+pub async fn list_pins(proxy: web::Data<Proxy>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "pins": proxy
+            .pinner()
+            .map_or_else(|| Value::Array(Vec::new()), |pinner| pinner.describe()),
+    }))
+}
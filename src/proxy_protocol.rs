@@ -0,0 +1,375 @@
+//! PROXY protocol (v1 and v2) support for TCP load balancers.
+//!
+//! Load balancers such as AWS NLB and HAProxy can prepend a PROXY header to
+//! each TCP connection carrying the client's original address. With
+//! `PROXY_PROTOCOL=true` the public listeners are served by a
+//! [`ProxyProtocolAcceptor`]: it reads and strips the header from every
+//! connection, then hands the remaining byte stream to the Actix server over
+//! a loopback connection. The client address from the header is recorded
+//! for that connection and stored in the connection data by
+//! [`on_connect`](ProxyProtocolAcceptor::on_connect), where
+//! [`real_ip`](crate::util::real_ip::real_ip) picks it up.
+//!
+//! Every connection must start with a PROXY header; connections without one
+//! are closed, as the protocol requires. Only enable this behind a load
+//! balancer that always sends it. The loopback listener behind the relay
+//! only accepts connections from the local host.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_tls::accept::rustls_0_20::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::{TcpSocket, TcpStream};
+use actix_web::rt::task::JoinHandle;
+use log::{debug, warn};
+use tokio::io::AsyncReadExt;
+
+/// Signature starting every v2 header.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including the trailing CRLF.
+pub const V1_MAX_LEN: usize = 107;
+/// Time a client has to send the complete header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Addresses carried by a PROXY header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyInfo {
+    /// The original client.
+    pub src_addr: SocketAddr,
+    /// The address the client connected to.
+    pub dst_addr: SocketAddr,
+}
+
+/// Why a PROXY header could not be used.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// More bytes are needed.
+    Incomplete,
+    /// The header is malformed.
+    Invalid(String),
+    /// A valid header without addresses (v1 `UNKNOWN`, v2 `LOCAL` or a
+    /// non-IP family); the connection's own addresses apply.
+    NoAddress,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "incomplete PROXY header"),
+            ParseError::Invalid(reason) => write!(f, "invalid PROXY header: {}", reason),
+            ParseError::NoAddress => write!(f, "PROXY header carries no address"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn invalid(reason: &str) -> ParseError {
+    ParseError::Invalid(reason.to_string())
+}
+
+/// Parses a v1 (text) header, e.g.
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+///
+/// `bytes` must hold the header up to and including its CRLF; anything after
+/// it is ignored.
+///
+/// # Errors
+///
+/// Returns [`ParseError::Incomplete`] without a CRLF, [`ParseError::NoAddress`]
+/// for `PROXY UNKNOWN`, and [`ParseError::Invalid`] otherwise.
+pub fn parse_proxy_protocol_v1(bytes: &[u8]) -> Result<ProxyInfo, ParseError> {
+    let window = &bytes[..bytes.len().min(V1_MAX_LEN)];
+    let Some(end) = window.windows(2).position(|w| w == b"\r\n") else {
+        return Err(if bytes.len() >= V1_MAX_LEN {
+            invalid("header too long")
+        } else {
+            ParseError::Incomplete
+        });
+    };
+    let line = std::str::from_utf8(&bytes[..end]).map_err(|_| invalid("not ASCII"))?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid("missing PROXY prefix"));
+    }
+    let family = fields.next().ok_or_else(|| invalid("missing protocol"))?;
+    match family {
+        "UNKNOWN" => return Err(ParseError::NoAddress),
+        "TCP4" | "TCP6" => {}
+        other => return Err(ParseError::Invalid(format!("unknown protocol {}", other))),
+    }
+
+    let mut next = |what: &str| {
+        fields
+            .next()
+            .ok_or_else(|| ParseError::Invalid(format!("missing {}", what)))
+    };
+    let src_ip: IpAddr = next("source address")?
+        .parse()
+        .map_err(|_| invalid("bad source address"))?;
+    let dst_ip: IpAddr = next("destination address")?
+        .parse()
+        .map_err(|_| invalid("bad destination address"))?;
+    let src_port = parse_port(next("source port")?)?;
+    let dst_port = parse_port(next("destination port")?)?;
+    if fields.next().is_some() {
+        return Err(invalid("trailing fields"));
+    }
+    let family_matches = match family {
+        "TCP4" => src_ip.is_ipv4() && dst_ip.is_ipv4(),
+        _ => src_ip.is_ipv6() && dst_ip.is_ipv6(),
+    };
+    if !family_matches {
+        return Err(invalid("address does not match protocol"));
+    }
+
+    Ok(ProxyInfo {
+        src_addr: SocketAddr::new(src_ip, src_port),
+        dst_addr: SocketAddr::new(dst_ip, dst_port),
+    })
+}
+
+fn parse_port(port: &str) -> Result<u16, ParseError> {
+    // Leading zeros are not allowed
+    if port.len() > 1 && port.starts_with('0') {
+        return Err(invalid("bad port"));
+    }
+    port.parse().map_err(|_| invalid("bad port"))
+}
+
+/// Parses a v2 (binary) header.
+///
+/// # Errors
+///
+/// Returns [`ParseError::Incomplete`] until the whole header is present,
+/// [`ParseError::NoAddress`] for `LOCAL` commands and non-IP families, and
+/// [`ParseError::Invalid`] otherwise.
+pub fn parse_proxy_protocol_v2(bytes: &[u8]) -> Result<ProxyInfo, ParseError> {
+    if bytes.len() < 16 {
+        return Err(if V2_SIGNATURE.starts_with(&bytes[..bytes.len().min(12)]) {
+            ParseError::Incomplete
+        } else {
+            invalid("bad signature")
+        });
+    }
+    if bytes[..12] != V2_SIGNATURE {
+        return Err(invalid("bad signature"));
+    }
+    let version_command = bytes[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    let len = usize::from(u16::from_be_bytes([bytes[14], bytes[15]]));
+    let Some(addresses) = bytes.get(16..16 + len) else {
+        return Err(ParseError::Incomplete);
+    };
+    match version_command & 0x0f {
+        0x0 => return Err(ParseError::NoAddress),
+        0x1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    // High nibble: address family; low nibble: transport (stream or dgram)
+    match bytes[13] >> 4 {
+        0x1 => {
+            if addresses.len() < 12 {
+                return Err(invalid("short IPv4 address block"));
+            }
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    addresses[at],
+                    addresses[at + 1],
+                    addresses[at + 2],
+                    addresses[at + 3],
+                ))
+            };
+            Ok(ProxyInfo {
+                src_addr: SocketAddr::new(ip(0), port(8)),
+                dst_addr: SocketAddr::new(ip(4), port(10)),
+            })
+        }
+        0x2 => {
+            if addresses.len() < 36 {
+                return Err(invalid("short IPv6 address block"));
+            }
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(ProxyInfo {
+                src_addr: SocketAddr::new(ip(0), port(32)),
+                dst_addr: SocketAddr::new(ip(16), port(34)),
+            })
+        }
+        // AF_UNSPEC and AF_UNIX
+        _ => Err(ParseError::NoAddress),
+    }
+}
+
+/// Reads exactly one PROXY header (v1 or v2) from `stream`, leaving the
+/// bytes after it unread.
+///
+/// # Returns
+///
+/// * `Result<Option<ProxyInfo>, IoError>` - The client addresses, `None` for headers without addresses, or an IoError if the header is missing or malformed.
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<ProxyInfo>, IoError> {
+    let to_io = |e: ParseError| IoError::new(ErrorKind::InvalidData, e);
+    let mut header = vec![0u8; 12];
+    stream.read_exact(&mut header).await?;
+
+    let result = if header == V2_SIGNATURE {
+        header.resize(16, 0);
+        stream.read_exact(&mut header[12..]).await?;
+        let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+        header.resize(16 + len, 0);
+        stream.read_exact(&mut header[16..]).await?;
+        parse_proxy_protocol_v2(&header)
+    } else if header.starts_with(b"PROXY ") {
+        // Byte by byte, so nothing after the CRLF is consumed
+        loop {
+            match parse_proxy_protocol_v1(&header) {
+                Err(ParseError::Incomplete) => header.push(stream.read_u8().await?),
+                result => break result,
+            }
+        }
+    } else {
+        return Err(to_io(invalid(
+            "connection did not start with a PROXY header",
+        )));
+    };
+
+    match result {
+        Ok(info) => Ok(Some(info)),
+        Err(ParseError::NoAddress) => Ok(None),
+        Err(e) => Err(to_io(e)),
+    }
+}
+
+/// Accepts PROXY protocol connections and relays them to the Actix server.
+#[derive(Clone, Default)]
+pub struct ProxyProtocolAcceptor {
+    /// Client addresses by the local address of the relayed connection,
+    /// which is the peer address the Actix server sees.
+    peers: Arc<Mutex<HashMap<SocketAddr, ProxyInfo>>>,
+    /// The accept loops started by [`spawn`](Self::spawn).
+    accept_loops: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Connections being relayed.
+    relays: Arc<AtomicUsize>,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new() -> Self {
+        ProxyProtocolAcceptor::default()
+    }
+
+    /// Serves `listener`, relaying each connection to `backend` once its
+    /// PROXY header has been read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the listener cannot be registered with the
+    /// runtime.
+    pub fn spawn(
+        &self,
+        listener: std::net::TcpListener,
+        backend: SocketAddr,
+    ) -> Result<(), IoError> {
+        listener.set_nonblocking(true)?;
+        let listener = actix_web::rt::net::TcpListener::from_std(listener)?;
+        let acceptor = self.clone();
+        let accept_loop = actix_web::rt::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let acceptor = acceptor.clone();
+                        acceptor.relays.fetch_add(1, Ordering::SeqCst);
+                        actix_web::rt::spawn(async move {
+                            if let Err(e) = acceptor.relay(stream, backend).await {
+                                debug!("PROXY protocol connection from {} closed: {}", peer, e);
+                            }
+                            acceptor.relays.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(e) => warn!("Failed to accept connection: {}", e),
+                }
+            }
+        });
+        self.accept_loops.lock().unwrap().push(accept_loop);
+        Ok(())
+    }
+
+    /// Stops accepting on the listeners passed to [`spawn`](Self::spawn)
+    /// and closes them; connections already relayed carry on. Call it
+    /// before stopping the server, or new connections are relayed to a
+    /// server no longer accepting them.
+    pub fn stop(&self) {
+        for accept_loop in self.accept_loops.lock().unwrap().drain(..) {
+            accept_loop.abort();
+        }
+    }
+
+    async fn relay(&self, mut client: TcpStream, backend: SocketAddr) -> Result<(), IoError> {
+        let info = actix_web::rt::time::timeout(HEADER_TIMEOUT, read_header(&mut client))
+            .await
+            .map_err(|_| IoError::new(ErrorKind::TimedOut, "no PROXY header received"))??;
+
+        // Bound before connecting, so the client address is recorded before
+        // the server can accept the connection and look it up
+        let socket = match backend {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(backend.ip(), 0))?;
+        let key = socket.local_addr()?;
+        if let Some(info) = info {
+            self.peers.lock().unwrap().insert(key, info);
+        }
+        let mut upstream = match socket.connect(backend).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                self.peers.lock().unwrap().remove(&key);
+                return Err(e);
+            }
+        };
+        let result = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+        self.peers.lock().unwrap().remove(&key);
+        result.map(|_| ())
+    }
+
+    /// Returns the number of connections being relayed, which a drain
+    /// waits for.
+    pub fn open_relays(&self) -> usize {
+        self.relays.load(Ordering::SeqCst)
+    }
+
+    /// Returns the client addresses of the relayed connection whose peer
+    /// address (as seen by the server) is `peer`.
+    pub fn lookup(&self, peer: SocketAddr) -> Option<ProxyInfo> {
+        self.peers.lock().unwrap().get(&peer).copied()
+    }
+
+    /// Connection callback storing the [`ProxyInfo`] of relayed connections
+    /// in the connection data.
+    pub fn on_connect(&self, connection: &dyn Any, data: &mut Extensions) {
+        let peer = connection
+            .downcast_ref::<TlsStream<TcpStream>>()
+            .and_then(|tls| tls.get_ref().0.peer_addr().ok())
+            .or_else(|| {
+                connection
+                    .downcast_ref::<TcpStream>()
+                    .and_then(|tcp| tcp.peer_addr().ok())
+            });
+        if let Some(info) = peer.and_then(|peer| self.lookup(peer)) {
+            data.insert(info);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Progressive Web App support.
+//!
+//! * `GET /manifest.json` serves the web app manifest, built from
+//!   environment variables at startup.
+//! * `GET /service-worker.js` serves a cache-first service worker that
+//!   precaches `PRECACHE_ASSETS` on install.
+//!
+//! The service worker is served with `Cache-Control: no-cache` so browsers
+//! pick up a new asset list on their next update check. Its cache name is
+//! derived from the asset list, so changing the list replaces the old cache.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+
+use actix_web::http::{header, Method};
+use actix_web::{web, HttpResponse, Responder};
+use ring::digest;
+use serde::Serialize;
+
+use crate::route_meta::{DescribedRoute, RouteMetadata};
+use crate::routes::ONE_DAY;
+
+/// An entry of the manifest's `icons` list.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Icon {
+    pub src: String,
+    pub sizes: String,
+    #[serde(rename = "type")]
+    pub mime_type: String,
+}
+
+impl Icon {
+    /// Parses `path:sizes`, e.g. `/icons/192.png:192x192`. The MIME type is
+    /// taken from the file extension.
+    pub fn parse(value: &str) -> Option<Icon> {
+        let (src, sizes) = value.rsplit_once(':')?;
+        if !src.starts_with('/') || sizes.is_empty() {
+            return None;
+        }
+        let extension = src
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        let mime_type = match extension.as_deref() {
+            Some("png") => "image/png",
+            Some("svg") => "image/svg+xml",
+            Some("webp") => "image/webp",
+            Some("ico") => "image/x-icon",
+            Some("jpg") | Some("jpeg") => "image/jpeg",
+            _ => return None,
+        };
+        Some(Icon {
+            src: src.to_string(),
+            sizes: sizes.to_string(),
+            mime_type: mime_type.to_string(),
+        })
+    }
+}
+
+/// The web app manifest.
+#[derive(Clone, Debug, Serialize)]
+pub struct Manifest {
+    pub name: String,
+    pub short_name: String,
+    pub start_url: String,
+    pub display: String,
+    pub background_color: String,
+    pub theme_color: String,
+    pub icons: Vec<Icon>,
+}
+
+/// Manifest and service worker settings.
+#[derive(Clone, Debug)]
+pub struct PwaConfig {
+    pub manifest: Manifest,
+    /// Paths cached by the service worker when it is installed.
+    pub precache_assets: Vec<String>,
+}
+
+impl PwaConfig {
+    /// Reads `SERVER_NAME`, `PWA_SHORT_NAME`, `PWA_BACKGROUND_COLOR`,
+    /// `PWA_THEME_COLOR`, `PWA_ICONS` and `PRECACHE_ASSETS`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PwaConfig, IoError>` - The settings, or an IoError if an icon or asset path is invalid.
+    pub fn from_env() -> Result<PwaConfig, IoError> {
+        let name =
+            env::var("SERVER_NAME").unwrap_or_else(|_| "Secure Actix Web Server".to_string());
+        let short_name = env::var("PWA_SHORT_NAME").unwrap_or_else(|_| name.clone());
+        let list = |var: &str| -> Vec<String> {
+            env::var(var)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        let icons = list("PWA_ICONS")
+            .iter()
+            .map(|icon| {
+                Icon::parse(icon).ok_or_else(|| {
+                    IoError::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "invalid PWA_ICONS entry '{}', expected /path.png:192x192",
+                            icon
+                        ),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let precache_assets = list("PRECACHE_ASSETS");
+        if let Some(asset) = precache_assets.iter().find(|a| !a.starts_with('/')) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("PRECACHE_ASSETS entry '{}' must be an absolute path", asset),
+            ));
+        }
+
+        Ok(PwaConfig {
+            manifest: Manifest {
+                name,
+                short_name,
+                start_url: "/".to_string(),
+                display: "standalone".to_string(),
+                background_color: env::var("PWA_BACKGROUND_COLOR")
+                    .unwrap_or_else(|_| "#fff".to_string()),
+                theme_color: env::var("PWA_THEME_COLOR").unwrap_or_else(|_| "#000".to_string()),
+                icons,
+            },
+            precache_assets,
+        })
+    }
+
+    /// Renders the service worker script.
+    pub fn service_worker(&self) -> String {
+        let assets = serde_json::to_string(&self.precache_assets).unwrap_or_else(|_| "[]".into());
+        let version: String = digest::digest(&digest::SHA256, assets.as_bytes())
+            .as_ref()
+            .iter()
+            .take(4)
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        SERVICE_WORKER
+            .replace("__CACHE_NAME__", &format!("precache-{}", version))
+            .replace("__ASSETS__", &assets)
+    }
+}
+
+const SERVICE_WORKER: &str = r#"const CACHE_NAME = "__CACHE_NAME__";
+const PRECACHE_ASSETS = __ASSETS__;
+
+self.addEventListener("install", (event) => {
+  event.waitUntil(
+    caches.open(CACHE_NAME).then((cache) => cache.addAll(PRECACHE_ASSETS))
+  );
+  self.skipWaiting();
+});
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(
+    caches.keys().then((names) =>
+      Promise.all(
+        names
+          .filter((name) => name.startsWith("precache-") && name !== CACHE_NAME)
+          .map((name) => caches.delete(name))
+      )
+    )
+  );
+  self.clients.claim();
+});
+
+self.addEventListener("fetch", (event) => {
+  if (event.request.method !== "GET") {
+    return;
+  }
+  event.respondWith(
+    caches.match(event.request).then((cached) => cached || fetch(event.request))
+  );
+});
+"#;
+
+/// Handler for `GET /manifest.json`.
+pub async fn manifest(config: web::Data<PwaConfig>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+        .content_type("application/manifest+json")
+        .body(serde_json::to_string(&config.manifest).unwrap_or_default())
+}
+
+/// Handler for `GET /service-worker.js`.
+pub async fn service_worker(config: web::Data<PwaConfig>) -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .content_type("application/javascript; charset=utf-8")
+        .body(config.service_worker())
+}
+
+/// Registers the manifest and service worker routes.
+pub fn configure(config: web::Data<PwaConfig>) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.app_data(config)
+            .described_route(
+                Method::GET,
+                "/manifest.json",
+                manifest,
+                RouteMetadata::new("Web app manifest").cache_ttl(ONE_DAY),
+            )
+            .described_route(
+                Method::GET,
+                "/service-worker.js",
+                service_worker,
+                RouteMetadata::new("Service worker precaching PRECACHE_ASSETS"),
+            );
+    }
+}
@@ -0,0 +1,374 @@
+//! Per-request CPU and memory budgets.
+//!
+//! A handler stuck in an expensive computation, or building an enormous
+//! response in memory, holds its worker back from every other request.
+//! [`ResourceBudget`] caps what one request may use: `RESOURCE_CPU_BUDGET_MS`
+//! of CPU time and `RESOURCE_MEMORY_BUDGET_BYTES` of memory it allocates and
+//! still holds. A request over either budget is cancelled, its handler
+//! future dropped, and answered with 503 `resource_budget_exceeded`.
+//!
+//! Requests share their worker thread, so usage is measured around every
+//! poll of the request: CPU time with the thread's CPU clock
+//! (`CLOCK_THREAD_CPUTIME_ID`), memory with [`CountingAllocator`], the
+//! server's global allocator, which counts the bytes each thread allocates
+//! and frees. Counting starts once a budget limiting anything is installed,
+//! so servers without budgets pay nothing per allocation. Between polls nothing of the request runs, so the budget is
+//! checked each time the handler yields. A handler that never yields cannot
+//! be interrupted, only cancelled once it does; the budget is a backstop,
+//! not a substitute for `web::block` around blocking work. The response
+//! body is streamed after the handler returns and is not counted.
+//!
+//! Routes needing a different budget wrap themselves in a
+//! [`ResourceBudgetOverride`], e.g.
+//! `web::resource("/reports").wrap(ResourceBudgetOverride::new(budget))`.
+//! Without the thread CPU clock (other platforms than Linux and macOS) only
+//! the memory budget applies.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::env;
+use std::future::{ready, Future, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage};
+use futures_util::future::{poll_fn, LocalBoxFuture};
+use log::warn;
+
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+
+/// CPU time and memory one request may use; zero means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceBudget {
+    pub cpu_budget_ms: u64,
+    pub memory_budget_bytes: usize,
+}
+
+impl ResourceBudget {
+    /// Reads `RESOURCE_CPU_BUDGET_MS` and `RESOURCE_MEMORY_BUDGET_BYTES`;
+    /// unset values are unlimited.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a value is not a non-negative integer.
+    pub fn from_env() -> Result<Self, IoError> {
+        Ok(ResourceBudget {
+            cpu_budget_ms: budget_from_env("RESOURCE_CPU_BUDGET_MS")?,
+            memory_budget_bytes: budget_from_env("RESOURCE_MEMORY_BUDGET_BYTES")?,
+        })
+    }
+
+    /// Returns whether either budget is set.
+    pub fn is_limited(&self) -> bool {
+        self.cpu_budget_ms > 0 || self.memory_budget_bytes > 0
+    }
+
+    /// Which budget `usage` exceeds, if any.
+    pub fn exceeded_by(&self, usage: &Usage) -> Option<&'static str> {
+        if self.cpu_budget_ms > 0 && usage.cpu > Duration::from_millis(self.cpu_budget_ms) {
+            Some("cpu")
+        } else if self.memory_budget_bytes > 0 && usage.memory > self.memory_budget_bytes as isize {
+            Some("memory")
+        } else {
+            None
+        }
+    }
+}
+
+fn budget_from_env<T: std::str::FromStr + Default>(name: &str) -> Result<T, IoError> {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("{} must be a non-negative integer, got '{}'", name, value),
+            )
+        }),
+        Err(_) => Ok(T::default()),
+    }
+}
+
+/// Resources a request has used so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// CPU time spent polling the request.
+    pub cpu: Duration,
+    /// Bytes allocated while polling the request and not yet freed.
+    pub memory: isize,
+}
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+/// Whether [`CountingAllocator`] counts; off until a budget needs it.
+static COUNTING: AtomicBool = AtomicBool::new(false);
+
+/// Makes [`CountingAllocator`] count allocations from now on. Called when a
+/// limiting budget is installed; counting is never turned off again.
+pub fn enable_counting() {
+    COUNTING.store(true, Ordering::Relaxed);
+}
+
+/// Global allocator wrapper counting the bytes each thread holds.
+///
+/// Allocations and frees on a thread adjust its count, whichever thread the
+/// memory came from, so the count is only meaningful as a difference
+/// between two points on the same thread. Until [`enable_counting`] is
+/// called, it forwards to the inner allocator without counting.
+pub struct CountingAllocator<A>(pub A);
+
+impl<A> CountingAllocator<A> {
+    /// Wraps `inner`.
+    pub const fn new(inner: A) -> Self {
+        CountingAllocator(inner)
+    }
+}
+
+fn count(delta: isize) {
+    if !COUNTING.load(Ordering::Relaxed) {
+        return;
+    }
+    // Ignored once the thread-local is destroyed at thread exit
+    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get().wrapping_add(delta)));
+}
+
+// SAFETY: every call is forwarded unchanged to the inner allocator
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+        count(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.0.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            count(new_size as isize - layout.size() as isize);
+        }
+        new
+    }
+}
+
+/// Bytes the current thread has allocated and not freed, as counted by
+/// [`CountingAllocator`]; always 0 under another global allocator or before
+/// [`enable_counting`].
+pub fn thread_allocated() -> isize {
+    ALLOCATED.try_with(Cell::get).unwrap_or(0)
+}
+
+/// CPU time the current thread has used, or `None` without a thread CPU
+/// clock.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid timespec for clock_gettime to fill in
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+/// CPU time the current thread has used, or `None` without a thread CPU
+/// clock.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// The budget applying to a request, which a [`ResourceBudgetOverride`]
+/// on its route may replace.
+#[derive(Clone)]
+struct BudgetSlot(Rc<Cell<ResourceBudget>>);
+
+/// Polls `inner`, adding the CPU time and memory of each poll to `usage`.
+struct Metered<F> {
+    inner: F,
+    usage: Usage,
+}
+
+impl<F: Future + Unpin> Future for Metered<F> {
+    type Output = (F::Output, Usage);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let cpu = thread_cpu_time();
+        let memory = thread_allocated();
+        let poll = Pin::new(&mut self.inner).poll(cx);
+        if let (Some(before), Some(after)) = (cpu, thread_cpu_time()) {
+            self.usage.cpu += after.saturating_sub(before);
+        }
+        self.usage.memory += thread_allocated().wrapping_sub(memory);
+        match poll {
+            Poll::Ready(output) => Poll::Ready((output, self.usage)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Middleware cancelling requests over their [`ResourceBudget`].
+impl<S, B> Transform<S, ServiceRequest> for ResourceBudget
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ResourceBudgetMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        if self.is_limited() {
+            enable_counting();
+        }
+        ready(Ok(ResourceBudgetMiddleware {
+            service,
+            budget: *self,
+        }))
+    }
+}
+
+/// Service produced by [`ResourceBudget`].
+pub struct ResourceBudgetMiddleware<S> {
+    service: S,
+    budget: ResourceBudget,
+}
+
+impl<S, B> Service<ServiceRequest> for ResourceBudgetMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let slot = BudgetSlot(Rc::new(Cell::new(self.budget)));
+        req.extensions_mut().insert(slot.clone());
+        let method = req.method().clone();
+        let path = req.path().to_string();
+
+        let mut fut = Metered {
+            inner: Box::pin(self.service.call(req)),
+            usage: Usage::default(),
+        };
+        Box::pin(async move {
+            poll_fn(|cx| {
+                if let Poll::Ready((res, _)) = Pin::new(&mut fut).poll(cx) {
+                    return Poll::Ready(res);
+                }
+                let budget = slot.0.get();
+                let Some(resource) = budget.exceeded_by(&fut.usage) else {
+                    return Poll::Pending;
+                };
+                warn!(
+                    "Cancelled {} {} over its {} budget: {}ms CPU, {} bytes allocated",
+                    method,
+                    path,
+                    resource,
+                    fut.usage.cpu.as_millis(),
+                    fut.usage.memory
+                );
+                Metrics::global().inc("resource_budget_exceeded_total", &[("resource", resource)]);
+                Poll::Ready(Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "resource_budget_exceeded",
+                    "The request used more resources than it is allowed",
+                )
+                .into()))
+            })
+            .await
+            // Dropping `fut` here cancels the handler
+        })
+    }
+}
+
+/// Replaces the [`ResourceBudget`] of the requests it wraps, for routes
+/// that legitimately need more, or should get less.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceBudgetOverride(pub ResourceBudget);
+
+impl ResourceBudgetOverride {
+    /// Applies `budget` instead of the server-wide one.
+    pub fn new(budget: ResourceBudget) -> Self {
+        ResourceBudgetOverride(budget)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ResourceBudgetOverride
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ResourceBudgetOverrideMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        if self.0.is_limited() {
+            enable_counting();
+        }
+        ready(Ok(ResourceBudgetOverrideMiddleware {
+            service,
+            budget: self.0,
+        }))
+    }
+}
+
+/// Service produced by [`ResourceBudgetOverride`].
+pub struct ResourceBudgetOverrideMiddleware<S> {
+    service: S,
+    budget: ResourceBudget,
+}
+
+impl<S, B> Service<ServiceRequest> for ResourceBudgetOverrideMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(slot) = req.extensions().get::<BudgetSlot>() {
+            slot.0.set(self.budget);
+        }
+        self.service.call(req)
+    }
+}
@@ -0,0 +1,364 @@
+//! Restarting without dropping connections.
+//!
+//! On SIGUSR2, [`ZeroDowntimeMgr`] starts the binary again with the same
+//! arguments and hands it the listening sockets as inherited file
+//! descriptors, listed in `RESTART_LISTEN_FDS`. The new process serves on
+//! them through [`bind_or_inherit`] instead of binding, so connections
+//! keep queueing on the same sockets throughout. Once it reports that it
+//! is serving, over a socket pair whose descriptor is in
+//! `RESTART_READY_FD`, the old process stops accepting and drains its
+//! connections for at most `DRAIN_TIMEOUT_SECS` before exiting: it waits
+//! for its requests and for its clients to close their connections, which
+//! its responses ask them to (see [`DrainReport`](crate::lifecycle::DrainReport)).
+//!
+//! A new process that fails its startup checks, exits or does not report
+//! within [`READY_TIMEOUT`] is abandoned, and the old one keeps serving.
+
+use log::{info, warn};
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::TcpListener;
+use std::time::Duration;
+
+#[cfg(unix)]
+use actix_web::dev::ServerHandle;
+#[cfg(unix)]
+use log::error;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::process::{Child, Command};
+#[cfg(unix)]
+use std::sync::Arc;
+
+use crate::lifecycle::DrainReport;
+#[cfg(unix)]
+use crate::lifecycle::Lifecycle;
+#[cfg(unix)]
+use crate::middleware::access_log;
+use crate::systemd;
+use crate::tcp_keepalive::{self, TcpKeepaliveSettings};
+
+/// Variable listing the inherited listening descriptors, comma-separated.
+pub const LISTEN_FDS_VAR: &str = "RESTART_LISTEN_FDS";
+
+/// Variable holding the descriptor the new process reports readiness on.
+pub const READY_FD_VAR: &str = "RESTART_READY_FD";
+
+/// How long the new process may take to start serving.
+pub const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Parses a comma-separated list of descriptors such as `RESTART_LISTEN_FDS`.
+///
+/// # Returns
+///
+/// * `Option<Vec<i32>>` - The descriptors, or `None` if an entry is not a non-negative number.
+pub fn parse_fds(value: &str) -> Option<Vec<i32>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|fd| !fd.is_empty())
+        .map(|fd| fd.parse().ok().filter(|&fd: &i32| fd >= 0))
+        .collect()
+}
+
+/// Takes ownership of a listening socket inherited from the process that
+/// restarted into this one, closing it again on the next exec.
+///
+/// # Safety
+///
+/// `fd` must be an open TCP listening socket that nothing else in this
+/// process owns.
+#[cfg(unix)]
+pub unsafe fn listener_from_inherited_fd(fd: RawFd) -> TcpListener {
+    let listener = TcpListener::from_raw_fd(fd);
+    if let Err(e) = socket2::SockRef::from(&listener).set_cloexec(true) {
+        warn!("Failed to set close-on-exec on inherited fd {}: {}", fd, e);
+    }
+    listener
+}
+
+/// Takes the listening sockets handed over by the process that restarted
+/// into this one, if any.
+///
+/// `RESTART_LISTEN_FDS` is removed from the environment afterwards so that
+/// a later restart or child process does not reuse it.
+///
+/// # Returns
+///
+/// * `Vec<TcpListener>` - The inherited listeners; empty when not restarted.
+#[cfg(unix)]
+pub fn inherited_listeners() -> Vec<TcpListener> {
+    let value = env::var(LISTEN_FDS_VAR).ok();
+    env::remove_var(LISTEN_FDS_VAR);
+    let fds = match value.as_deref().map(parse_fds) {
+        Some(Some(fds)) => fds,
+        Some(None) => {
+            warn!(
+                "Ignoring invalid {} '{}'",
+                LISTEN_FDS_VAR,
+                value.as_deref().unwrap_or_default()
+            );
+            return Vec::new();
+        }
+        None => return Vec::new(),
+    };
+
+    let mut listeners = Vec::with_capacity(fds.len());
+    for fd in fds {
+        // SAFETY: the previous process cleared close-on-exec on exactly
+        // these listening sockets and listed them for us alone.
+        let listener = unsafe { listener_from_inherited_fd(fd) };
+        match listener.set_nonblocking(true) {
+            Ok(()) => {
+                info!("Using listener inherited on restart on fd {}", fd);
+                listeners.push(listener);
+            }
+            Err(e) => warn!("Ignoring inherited file descriptor {}: {}", fd, e),
+        }
+    }
+    listeners
+}
+
+/// Restarts are only supported on Unix platforms.
+#[cfg(not(unix))]
+pub fn inherited_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+/// Returns the listeners to serve on: those inherited on restart, else
+/// those passed by systemd socket activation, else new ones bound to
+/// `addr`.
+///
+/// The caller builds the server on them: they are duplicated for
+/// [`ZeroDowntimeMgr`] first, and with PROXY protocol they are served by
+/// the relay rather than by the server itself.
+///
+/// # Returns
+///
+/// * `Result<(Vec<TcpListener>, bool), IoError>` - The listeners, and whether they were inherited on restart.
+///
+/// # Errors
+///
+/// Returns an error if `addr` cannot be bound.
+pub fn bind_or_inherit(
+    addr: &str,
+    keepalive: Option<&TcpKeepaliveSettings>,
+) -> Result<(Vec<TcpListener>, bool), IoError> {
+    let inherited = inherited_listeners();
+    if !inherited.is_empty() {
+        return Ok((inherited, true));
+    }
+    let activated = systemd::take_listeners();
+    if !activated.is_empty() {
+        return Ok((activated, false));
+    }
+    let bound = match keepalive {
+        Some(settings) => tcp_keepalive::bind(addr, settings)?,
+        None => vec![TcpListener::bind(addr)?],
+    };
+    Ok((bound, false))
+}
+
+/// Reads `DRAIN_TIMEOUT_SECS`, the longest the old process drains after a
+/// restart, defaulting to `default_timeout`.
+///
+/// # Errors
+///
+/// Returns an error if `DRAIN_TIMEOUT_SECS` is not a number of seconds.
+pub fn drain_timeout_from_env(default_timeout: Duration) -> Result<Duration, IoError> {
+    match env::var("DRAIN_TIMEOUT_SECS") {
+        Ok(secs) => secs.trim().parse().map(Duration::from_secs).map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "DRAIN_TIMEOUT_SECS must be a number of seconds, got '{}'",
+                    secs
+                ),
+            )
+        }),
+        Err(_) => Ok(default_timeout),
+    }
+}
+
+/// Tells the process that restarted into this one that it is serving, so
+/// that it starts draining. Does nothing when not restarted.
+#[cfg(unix)]
+pub fn notify_ready() {
+    use std::io::Write;
+
+    let fd = env::var(READY_FD_VAR)
+        .ok()
+        .and_then(|fd| fd.trim().parse::<RawFd>().ok());
+    env::remove_var(READY_FD_VAR);
+    if let Some(fd) = fd {
+        // SAFETY: the previous process passed us this end of its socket pair
+        let mut ready = unsafe { UnixStream::from_raw_fd(fd) };
+        match ready.write_all(b"1") {
+            Ok(()) => info!("Told the previous process to drain"),
+            Err(e) => warn!("Failed to tell the previous process to drain: {}", e),
+        }
+    }
+}
+
+/// Restarts are only supported on Unix platforms.
+#[cfg(not(unix))]
+pub fn notify_ready() {}
+
+/// Hands the listening sockets over to a new process on SIGUSR2.
+pub struct ZeroDowntimeMgr {
+    /// Duplicates of the server's listeners, handed to the new process.
+    listeners: Vec<TcpListener>,
+    drain: DrainReport,
+}
+
+impl ZeroDowntimeMgr {
+    /// Hands over duplicates of `listeners`, then drains for at most the
+    /// timeout of `drain`, logging the requests holding it open.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a listener cannot be duplicated.
+    pub fn new(listeners: &[TcpListener], drain: DrainReport) -> Result<Self, IoError> {
+        Ok(ZeroDowntimeMgr {
+            listeners: listeners
+                .iter()
+                .map(TcpListener::try_clone)
+                .collect::<Result<_, _>>()?,
+            drain,
+        })
+    }
+
+    /// Starts the binary again with the same arguments, passing it the
+    /// listeners and the writing end of a socket pair.
+    ///
+    /// Close-on-exec is only cleared on the passed descriptors while the
+    /// new process is spawned.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(Child, UnixStream), IoError>` - The new process and the end it reports readiness on.
+    #[cfg(unix)]
+    pub fn spawn_successor(&self) -> Result<(Child, UnixStream), IoError> {
+        let (ready, successor_end) = UnixStream::pair()?;
+        let inheritable = |inherit: bool| -> Result<(), IoError> {
+            for listener in &self.listeners {
+                socket2::SockRef::from(listener).set_cloexec(!inherit)?;
+            }
+            socket2::SockRef::from(&successor_end).set_cloexec(!inherit)
+        };
+        let fds: Vec<String> = self
+            .listeners
+            .iter()
+            .map(|listener| listener.as_raw_fd().to_string())
+            .collect();
+
+        inheritable(true)?;
+        let child = Command::new(env::current_exe()?)
+            .args(env::args_os().skip(1))
+            .env(LISTEN_FDS_VAR, fds.join(","))
+            .env(READY_FD_VAR, successor_end.as_raw_fd().to_string())
+            .spawn();
+        inheritable(false)?;
+        // Only the new process holds the writing end now, so its exit
+        // reads as end of file
+        drop(successor_end);
+        Ok((child?, ready))
+    }
+
+    /// Starts the new process, waits until it serves and drains this one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and keeps this process serving, if the new process
+    /// cannot be started or does not report within [`READY_TIMEOUT`].
+    #[cfg(unix)]
+    pub async fn restart(
+        &self,
+        handle: ServerHandle,
+        lifecycle: &Lifecycle,
+    ) -> Result<(), IoError> {
+        let (mut child, ready) = self.spawn_successor()?;
+        info!(
+            "Restart: started process {}, waiting for it to serve",
+            child.id()
+        );
+        let waited = actix_web::rt::task::spawn_blocking(move || wait_ready(ready, READY_TIMEOUT))
+            .await
+            .map_err(|e| IoError::other(e.to_string()))?;
+        if let Err(e) = waited {
+            // Do not leave a half-started process behind
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+
+        info!(
+            "Restart: process {} is serving, draining for at most {}s",
+            child.id(),
+            self.drain.timeout().as_secs_f64()
+        );
+        lifecycle.begin_drain();
+        let drained = self.drain.drain(&handle).await;
+        access_log::flush();
+        if drained {
+            info!("Restart complete: all connections drained");
+        } else {
+            warn!("Restart complete: requests in flight at the drain timeout were aborted");
+        }
+        Ok(())
+    }
+
+    /// Restarts on every SIGUSR2 until a restart succeeds.
+    #[cfg(unix)]
+    pub async fn restart_on_sigusr2(self, handle: ServerHandle, lifecycle: Arc<Lifecycle>) {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut sigusr2 = match signal(SignalKind::user_defined2()) {
+            Ok(sigusr2) => sigusr2,
+            Err(e) => {
+                error!(
+                    "Failed to install SIGUSR2 handler, restarts are disabled: {}",
+                    e
+                );
+                return;
+            }
+        };
+        while sigusr2.recv().await.is_some() {
+            info!("Received SIGUSR2, restarting");
+            match self.restart(handle.clone(), &lifecycle).await {
+                Ok(()) => return,
+                Err(e) => error!("Restart failed, still serving: {}", e),
+            }
+        }
+    }
+}
+
+/// Blocks until the new process writes to `ready`, exits or `timeout`
+/// passes.
+#[cfg(unix)]
+fn wait_ready(mut ready: UnixStream, timeout: Duration) -> Result<(), IoError> {
+    use std::io::Read;
+
+    ready.set_read_timeout(Some(timeout))?;
+    let mut byte = [0u8; 1];
+    match ready.read(&mut byte) {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(IoError::new(
+            ErrorKind::UnexpectedEof,
+            "the new process exited before serving",
+        )),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Err(IoError::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "the new process did not serve within {}s",
+                    timeout.as_secs()
+                ),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,158 @@
+//! Certificate revocation lists.
+//!
+//! CRL files are trusted as configured: their signatures are not checked,
+//! so they must come from a trusted location. A CRL past its `nextUpdate`
+//! no longer counts as covering its issuer.
+
+use actix_web::rt::task::JoinHandle;
+use actix_web::rt::time::interval;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use rustls::Certificate;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use x509_parser::prelude::{parse_x509_certificate, parse_x509_crl};
+
+use super::{RevocationChecker, RevocationStatus};
+
+/// Revoked serial numbers published by one issuer.
+struct Crl {
+    issuer: Vec<u8>,
+    /// `nextUpdate` as a Unix timestamp.
+    next_update: Option<i64>,
+    revoked: HashSet<Vec<u8>>,
+}
+
+impl Crl {
+    fn parse(der: &[u8]) -> Result<Crl, IoError> {
+        let (_, crl) = parse_x509_crl(der)
+            .map_err(|e| IoError::new(ErrorKind::InvalidData, format!("Invalid CRL: {}", e)))?;
+        Ok(Crl {
+            issuer: crl.issuer().as_raw().to_vec(),
+            next_update: crl.next_update().map(|t| t.timestamp()),
+            revoked: crl
+                .iter_revoked_certificates()
+                .map(|revoked| revoked.raw_serial().to_vec())
+                .collect(),
+        })
+    }
+
+    fn is_current(&self) -> bool {
+        self.next_update
+            .map(|next| next > chrono::Utc::now().timestamp())
+            .unwrap_or(true)
+    }
+}
+
+/// Checks certificates against CRLs loaded from files.
+pub struct CrlChecker {
+    paths: Vec<String>,
+    crls: RwLock<Vec<Crl>>,
+}
+
+impl CrlChecker {
+    /// Loads the DER-encoded CRLs at `paths`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file cannot be read or is not a valid CRL.
+    pub fn from_files(paths: Vec<String>) -> Result<CrlChecker, IoError> {
+        let crls = load(&paths)?;
+        Ok(CrlChecker {
+            paths,
+            crls: RwLock::new(crls),
+        })
+    }
+
+    /// Builds a checker from in-memory DER-encoded CRLs, without reloading.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any input is not a valid CRL.
+    pub fn from_der(crls: &[Vec<u8>]) -> Result<CrlChecker, IoError> {
+        Ok(CrlChecker {
+            paths: Vec::new(),
+            crls: RwLock::new(
+                crls.iter()
+                    .map(|der| Crl::parse(der))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+
+    /// Re-reads the CRL files. On failure the previous CRLs stay in use.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file cannot be read or is not a valid CRL.
+    pub fn reload(&self) -> Result<(), IoError> {
+        let crls = load(&self.paths)?;
+        *self.crls.write().unwrap() = crls;
+        Ok(())
+    }
+
+    /// Spawns a job reloading the CRL files every `every`.
+    pub fn spawn_refresh(checker: Arc<CrlChecker>, every: Duration) -> JoinHandle<()> {
+        actix_web::rt::spawn(async move {
+            let mut ticks = interval(every);
+            // The first tick completes immediately; the files were just loaded
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                match checker.reload() {
+                    Ok(()) => info!("Reloaded {} CRL file(s)", checker.paths.len()),
+                    Err(e) => warn!("Failed to reload CRLs, keeping previous ones: {}", e),
+                }
+            }
+        })
+    }
+}
+
+fn load(paths: &[String]) -> Result<Vec<Crl>, IoError> {
+    paths
+        .iter()
+        .map(|path| {
+            let der = fs::read(path).map_err(|e| {
+                error!("Failed to read CRL file '{}': {}", path, e);
+                e
+            })?;
+            Crl::parse(&der).map_err(|e| {
+                error!("Failed to parse CRL file '{}': {}", path, e);
+                e
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl RevocationChecker for CrlChecker {
+    async fn check(&self, chain: &[Certificate]) -> RevocationStatus {
+        let Some(leaf) = chain.first() else {
+            return RevocationStatus::Unknown;
+        };
+        let Ok((_, cert)) = parse_x509_certificate(&leaf.0) else {
+            return RevocationStatus::Unknown;
+        };
+
+        let crls = self.crls.read().unwrap();
+        let mut covered = false;
+        for crl in crls
+            .iter()
+            .filter(|crl| crl.issuer == cert.issuer().as_raw())
+        {
+            if crl.revoked.contains(cert.raw_serial()) {
+                return RevocationStatus::Revoked;
+            }
+            covered |= crl.is_current();
+        }
+
+        if covered {
+            RevocationStatus::Good
+        } else {
+            RevocationStatus::Unknown
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Revocation checking for mTLS client certificates.
+//!
+//! A [`RevocationChecker`] reports whether a presented certificate has been
+//! revoked. Two backends exist:
+//!
+//! * [`crl::CrlChecker`] looks serial numbers up in DER-encoded CRL files
+//!   (`CRL_FILES`), reloaded every `CRL_REFRESH_INTERVAL_SECS`.
+//! * [`ocsp::OcspChecker`] asks the issuer's OCSP responder.
+//!
+//! The check runs in the
+//! [`RevocationCheck`](crate::middleware::revocation::RevocationCheck)
+//! middleware right after the client certificate is verified during the
+//! handshake: a rustls verifier can neither await an OCSP response nor
+//! answer with a `401`, so revoked certificates are rejected there instead.
+
+pub mod crl;
+pub mod ocsp;
+
+use async_trait::async_trait;
+use log::{error, info};
+use ring::signature;
+use rustls::Certificate;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::outbound::UrlPolicy;
+
+/// Outcome of a revocation check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RevocationStatus {
+    Good,
+    Revoked,
+    /// No authoritative answer: no CRL covers the issuer, or the OCSP
+    /// responder was unreachable or gave an invalid response.
+    Unknown,
+}
+
+/// Decides whether a client certificate has been revoked.
+#[async_trait]
+pub trait RevocationChecker: Send + Sync {
+    /// Checks the leaf of `chain` (leaf first, as presented by the client).
+    async fn check(&self, chain: &[Certificate]) -> RevocationStatus;
+}
+
+/// Builds the checker configured by `CRL_FILES` or `OCSP_ENABLED`.
+///
+/// # Returns
+///
+/// * `Result<Option<Arc<dyn RevocationChecker>>, IoError>` - The checker, `None` if revocation checking is disabled, or an IoError if the configuration is invalid.
+pub fn checker_from_env() -> Result<Option<Arc<dyn RevocationChecker>>, IoError> {
+    let crl_files: Vec<String> = env::var("CRL_FILES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(str::to_string)
+        .collect();
+    let ocsp_enabled = env::var("OCSP_ENABLED")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+
+    match (crl_files.is_empty(), ocsp_enabled) {
+        (true, false) => Ok(None),
+        (false, true) => {
+            error!("CRL_FILES and OCSP_ENABLED are mutually exclusive");
+            Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "Configure either CRL_FILES or OCSP_ENABLED, not both",
+            ))
+        }
+        (false, false) => {
+            let refresh = env::var("CRL_REFRESH_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::from_secs(3600));
+            let checker = Arc::new(crl::CrlChecker::from_files(crl_files)?);
+            info!(
+                "Checking client certificates against CRLs, reloaded every {}s",
+                refresh.as_secs()
+            );
+            crl::CrlChecker::spawn_refresh(checker.clone(), refresh);
+            Ok(Some(checker))
+        }
+        (true, true) => {
+            let ca_path = env::var("CLIENT_CA_FILE").map_err(|_| {
+                error!("OCSP_ENABLED requires CLIENT_CA_FILE");
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    "OCSP_ENABLED requires CLIENT_CA_FILE",
+                )
+            })?;
+            let issuers = load_pem_certs(&ca_path)?;
+            info!("Checking client certificates with OCSP");
+            Ok(Some(Arc::new(ocsp::OcspChecker::new(
+                issuers,
+                env::var("OCSP_RESPONDER_URL").ok(),
+                UrlPolicy::from_env()?,
+            ))))
+        }
+    }
+}
+
+/// Reads every certificate from a PEM file.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or contains no certificates.
+pub fn load_pem_certs(path: &str) -> Result<Vec<Certificate>, IoError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("No certificates found in '{}'", path),
+        ));
+    }
+    Ok(certs)
+}
+
+/// DER contents of the signature algorithm OIDs accepted for OCSP responses.
+const SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// Verifies `signature` over `message` with a subject public key.
+///
+/// `algorithm` is the DER content of the signature algorithm OID and
+/// `public_key` the contents of the SubjectPublicKeyInfo bit string.
+pub(crate) fn verify_signature(
+    algorithm: &[u8],
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let algorithm: &dyn signature::VerificationAlgorithm = match algorithm {
+        SHA256_WITH_RSA => &signature::RSA_PKCS1_2048_8192_SHA256,
+        SHA384_WITH_RSA => &signature::RSA_PKCS1_2048_8192_SHA384,
+        SHA512_WITH_RSA => &signature::RSA_PKCS1_2048_8192_SHA512,
+        ECDSA_WITH_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        ECDSA_WITH_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        ED25519 => &signature::ED25519,
+        _ => return false,
+    };
+    signature::UnparsedPublicKey::new(algorithm, public_key)
+        .verify(message, signature)
+        .is_ok()
+}
@@ -0,0 +1,405 @@
+//! OCSP (RFC 6960) revocation checking.
+//!
+//! For each presented leaf the issuer's responder is asked for the
+//! certificate's status. The responder URL comes from the leaf's Authority
+//! Information Access extension unless `OCSP_RESPONDER_URL` overrides it.
+//! URLs from certificates are client-supplied, so requests go through the
+//! [`OutboundClient`] and its SSRF policy; the configured override is
+//! allowlisted.
+//! Responses must be signed by the issuer itself or by a delegated responder
+//! certificate the issuer signed for OCSP signing. Answers are cached per
+//! serial number until the response's `nextUpdate` (at most an hour).
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::warn;
+use reqwest::Url;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use rustls::Certificate;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP;
+use x509_parser::prelude::parse_x509_certificate;
+
+use crate::outbound::{OutboundClient, UrlPolicy};
+
+use super::{verify_signature, RevocationChecker, RevocationStatus};
+
+/// Upper bound on how long an answer is cached.
+const MAX_CACHE: Duration = Duration::from_secs(3600);
+/// Cache lifetime for responses without `nextUpdate`.
+const DEFAULT_CACHE: Duration = Duration::from_secs(300);
+/// Tolerated clock difference with the responder.
+const CLOCK_SKEW_SECS: i64 = 300;
+
+const SHA1_OID: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OCSP_BASIC_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+/// Checks certificates with live OCSP requests.
+pub struct OcspChecker {
+    issuers: Vec<Certificate>,
+    responder_override: Option<String>,
+    client: OutboundClient,
+    cache: Mutex<HashMap<Vec<u8>, (RevocationStatus, Instant)>>,
+}
+
+impl OcspChecker {
+    /// Creates a checker trusting `issuers` (typically `CLIENT_CA_FILE`) to
+    /// sign responses for the certificates they issued. Responders are
+    /// contacted subject to `policy`.
+    pub fn new(
+        issuers: Vec<Certificate>,
+        responder_override: Option<String>,
+        policy: UrlPolicy,
+    ) -> Self {
+        let override_url = responder_override
+            .as_deref()
+            .and_then(|url| Url::parse(url).ok());
+        let policy = match override_url {
+            Some(url) => policy.allow_url(&url),
+            None => policy,
+        };
+        OcspChecker {
+            issuers,
+            responder_override,
+            client: OutboundClient::new(policy).with_timeout(Duration::from_secs(5)),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn responder_url(&self, leaf: &X509Certificate) -> Option<String> {
+        if let Some(url) = &self.responder_override {
+            return Some(url.clone());
+        }
+        leaf.extensions()
+            .iter()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::AuthorityInfoAccess(aia) => {
+                    aia.accessdescs.iter().find_map(|desc| {
+                        match (&desc.access_method, &desc.access_location) {
+                            (method, GeneralName::URI(uri))
+                                if *method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP =>
+                            {
+                                Some(uri.to_string())
+                            }
+                            _ => None,
+                        }
+                    })
+                }
+                _ => None,
+            })
+    }
+
+    async fn query(&self, chain: &[Certificate]) -> Result<(RevocationStatus, Duration), String> {
+        let leaf_der = &chain.first().ok_or("empty chain")?.0;
+        let (_, leaf) = parse_x509_certificate(leaf_der).map_err(|e| e.to_string())?;
+
+        // The issuer is the next certificate in the chain or a configured CA
+        let issuer_der = chain[1..]
+            .iter()
+            .chain(self.issuers.iter())
+            .find(|candidate| {
+                parse_x509_certificate(&candidate.0)
+                    .map(|(_, c)| c.subject().as_raw() == leaf.issuer().as_raw())
+                    .unwrap_or(false)
+            })
+            .ok_or("issuer certificate not found")?;
+        let (_, issuer) = parse_x509_certificate(&issuer_der.0).map_err(|e| e.to_string())?;
+
+        let url = self
+            .responder_url(&leaf)
+            .ok_or("certificate has no OCSP responder")?;
+        let request = build_request(&leaf, &issuer);
+
+        let response = self
+            .client
+            .post(&url, "application/ocsp-request", request)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("responder returned {}", response.status()));
+        }
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+
+        parse_response(&body, leaf.raw_serial(), &issuer)
+    }
+}
+
+#[async_trait]
+impl RevocationChecker for OcspChecker {
+    async fn check(&self, chain: &[Certificate]) -> RevocationStatus {
+        let Some(serial) = chain
+            .first()
+            .and_then(|leaf| parse_x509_certificate(&leaf.0).ok())
+            .map(|(_, cert)| cert.raw_serial().to_vec())
+        else {
+            return RevocationStatus::Unknown;
+        };
+
+        if let Some((status, expires)) = self.cache.lock().unwrap().get(&serial) {
+            if *expires > Instant::now() {
+                return *status;
+            }
+        }
+
+        match self.query(chain).await {
+            Ok((status, ttl)) => {
+                let mut cache = self.cache.lock().unwrap();
+                let now = Instant::now();
+                cache.retain(|_, (_, expires)| *expires > now);
+                cache.insert(serial, (status, now + ttl));
+                status
+            }
+            Err(e) => {
+                warn!("OCSP check failed: {}", e);
+                RevocationStatus::Unknown
+            }
+        }
+    }
+}
+
+/// Encodes a DER TLV.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// One DER tag-length-value split off the front of a buffer.
+struct Tlv<'a> {
+    tag: u8,
+    /// The content octets.
+    value: &'a [u8],
+    /// The whole encoding, header included.
+    raw: &'a [u8],
+    /// What follows the TLV.
+    rest: &'a [u8],
+}
+
+/// Splits one DER TLV off `input`.
+fn read_tlv(input: &[u8]) -> Result<Tlv<'_>, String> {
+    let malformed = || "malformed OCSP response".to_string();
+    let tag = *input.first().ok_or_else(malformed)?;
+    let first = *input.get(1).ok_or_else(malformed)?;
+    let (len, header) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return Err(malformed());
+        }
+        let bytes = input.get(2..2 + n).ok_or_else(malformed)?;
+        (
+            bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize),
+            2 + n,
+        )
+    };
+    let end = header.checked_add(len).ok_or_else(malformed)?;
+    if input.len() < end {
+        return Err(malformed());
+    }
+    Ok(Tlv {
+        tag,
+        value: &input[header..end],
+        raw: &input[..end],
+        rest: &input[end..],
+    })
+}
+
+/// Reads a TLV and checks its tag.
+fn expect(input: &[u8], tag: u8) -> Result<Tlv<'_>, String> {
+    let tlv = read_tlv(input)?;
+    if tlv.tag != tag {
+        return Err(format!(
+            "unexpected tag {:#04x}, expected {:#04x}",
+            tlv.tag, tag
+        ));
+    }
+    Ok(tlv)
+}
+
+/// Builds a DER `OCSPRequest` for `leaf` using a SHA-1 `CertID`.
+fn build_request(leaf: &X509Certificate, issuer: &X509Certificate) -> Vec<u8> {
+    let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, leaf.issuer().as_raw());
+    let key_hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        &issuer.public_key().subject_public_key.data,
+    );
+
+    let mut algorithm = der(0x06, SHA1_OID);
+    algorithm.extend(der(0x05, &[]));
+    let mut cert_id = der(0x30, &algorithm);
+    cert_id.extend(der(0x04, name_hash.as_ref()));
+    cert_id.extend(der(0x04, key_hash.as_ref()));
+    cert_id.extend(der(0x02, leaf.raw_serial()));
+
+    let request = der(0x30, &der(0x30, &cert_id));
+    let tbs_request = der(0x30, &der(0x30, &request));
+    der(0x30, &tbs_request)
+}
+
+fn parse_time(content: &[u8]) -> Result<DateTime<Utc>, String> {
+    let text = std::str::from_utf8(content).map_err(|e| e.to_string())?;
+    NaiveDateTime::parse_from_str(text, "%Y%m%d%H%M%SZ")
+        .map(|t| t.and_utc())
+        .map_err(|e| format!("invalid GeneralizedTime '{}': {}", text, e))
+}
+
+/// Parses and verifies a DER `OCSPResponse`, returning the status of the
+/// certificate with `serial` and how long the answer may be cached.
+fn parse_response(
+    input: &[u8],
+    serial: &[u8],
+    issuer: &X509Certificate,
+) -> Result<(RevocationStatus, Duration), String> {
+    let response = expect(input, 0x30)?.value;
+    let status = expect(response, 0x0a)?;
+    if status.value != [0] {
+        return Err(format!("responder status {:?}", status.value));
+    }
+    let bytes = expect(status.rest, 0xa0)?.value;
+    let bytes = expect(bytes, 0x30)?.value;
+    let response_type = expect(bytes, 0x06)?;
+    if response_type.value != OCSP_BASIC_OID {
+        return Err("unsupported OCSP response type".to_string());
+    }
+    let basic = expect(response_type.rest, 0x04)?.value;
+
+    // BasicOCSPResponse: tbsResponseData, signatureAlgorithm, signature, certs
+    let basic = expect(basic, 0x30)?.value;
+    let tbs = expect(basic, 0x30)?;
+    let algorithm = expect(tbs.rest, 0x30)?;
+    let signature = expect(algorithm.rest, 0x03)?;
+    let certs = signature.rest;
+    let algorithm = expect(algorithm.value, 0x06)?.value;
+    let signature = signature.value.get(1..).ok_or("empty signature")?;
+
+    let signed_by_issuer = verify_signature(
+        algorithm,
+        &issuer.public_key().subject_public_key.data,
+        tbs.raw,
+        signature,
+    );
+    if !signed_by_issuer && !signed_by_delegate(certs, issuer, algorithm, tbs.raw, signature)? {
+        return Err("OCSP response signature is not valid".to_string());
+    }
+
+    // ResponseData: [0] version, responderID, producedAt, responses
+    let mut fields = tbs.value;
+    if fields.first() == Some(&0xa0) {
+        fields = read_tlv(fields)?.rest;
+    }
+    let fields = read_tlv(fields)?.rest;
+    let fields = expect(fields, 0x18)?.rest;
+    let mut responses = expect(fields, 0x30)?.value;
+
+    while !responses.is_empty() {
+        let single = expect(responses, 0x30)?;
+        responses = single.rest;
+
+        let cert_id = expect(single.value, 0x30)?;
+        let single = cert_id.rest;
+        let cert_id = expect(cert_id.value, 0x30)?.rest;
+        let cert_id = expect(cert_id, 0x04)?.rest;
+        let cert_id = expect(cert_id, 0x04)?.rest;
+        if expect(cert_id, 0x02)?.value != serial {
+            continue;
+        }
+
+        let cert_status = read_tlv(single)?;
+        let single = cert_status.rest;
+        let status = match cert_status.tag {
+            0x80 => RevocationStatus::Good,
+            0xa1 => RevocationStatus::Revoked,
+            _ => RevocationStatus::Unknown,
+        };
+
+        let now = Utc::now();
+        let this_update = expect(single, 0x18)?;
+        let single = this_update.rest;
+        if parse_time(this_update.value)?.timestamp() > now.timestamp() + CLOCK_SKEW_SECS {
+            return Err("OCSP response is not yet valid".to_string());
+        }
+        let ttl = match single.first() {
+            Some(0xa0) => {
+                let next_update = expect(single, 0xa0)?.value;
+                let next_update = expect(next_update, 0x18)?.value;
+                let remaining = parse_time(next_update)?.timestamp() - now.timestamp();
+                if remaining <= 0 {
+                    return Err("OCSP response has expired".to_string());
+                }
+                Duration::from_secs(remaining as u64).min(MAX_CACHE)
+            }
+            _ => DEFAULT_CACHE,
+        };
+        return Ok((status, ttl));
+    }
+
+    Err("OCSP response does not cover the certificate".to_string())
+}
+
+/// Checks whether the response was signed by a delegated responder: a
+/// certificate in the response's `certs`, signed by `issuer` and carrying
+/// the OCSP signing extended key usage.
+fn signed_by_delegate(
+    certs: &[u8],
+    issuer: &X509Certificate,
+    algorithm: &[u8],
+    tbs: &[u8],
+    signature: &[u8],
+) -> Result<bool, String> {
+    if certs.is_empty() {
+        return Ok(false);
+    }
+    let certs = expect(certs, 0xa0)?.value;
+    let mut certs = expect(certs, 0x30)?.value;
+
+    while !certs.is_empty() {
+        let cert = read_tlv(certs)?;
+        certs = cert.rest;
+        let Ok((_, responder)) = parse_x509_certificate(cert.raw) else {
+            continue;
+        };
+
+        let issued_by_issuer = responder.issuer().as_raw() == issuer.subject().as_raw()
+            && verify_signature(
+                responder.signature_algorithm.algorithm.as_bytes(),
+                &issuer.public_key().subject_public_key.data,
+                responder.tbs_certificate.as_ref(),
+                &responder.signature_value.data,
+            );
+        let ocsp_signing = matches!(
+            responder.extended_key_usage(),
+            Ok(Some(eku)) if eku.value.ocsp_signing
+        );
+
+        if issued_by_issuer
+            && ocsp_signing
+            && responder.validity().is_valid()
+            && verify_signature(
+                algorithm,
+                &responder.public_key().subject_public_key.data,
+                tbs,
+                signature,
+            )
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,467 @@
+//! Documentation and security requirements of every registered route.
+//!
+//! Routes are registered with [`DescribedRoute::described_route`], which
+//! adds the route to the `ServiceConfig` and records its [`RouteMetadata`]
+//! in the same call, so the policy of a route (whether it needs
+//! credentials, how it is rate limited, how long it may be cached) is
+//! written down where the route is added and cannot drift from it. Scopes
+//! wrap their configuration in [`ScopeMetadata::configure`] to prefix the
+//! paths recorded inside and add the scope's middleware and guards.
+//! Services the table cannot see through, such as resources with their
+//! own middleware, call [`record`] next to their registration.
+//!
+//! Recording only happens inside [`collect`], which
+//! [`ModuleRegistry::metadata`](crate::routes::ModuleRegistry::metadata)
+//! runs over the same registration code the workers use. `GET
+//! /admin/routes` lists the result as JSON, or as aligned text for
+//! `Accept: text/plain`, making the route table and the server's security
+//! policy auditable without reading the source. [`collect_mounts`] records
+//! the same registrations in order, for
+//! [`route_overlap`](crate::route_overlap) to check.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::http::Method;
+use actix_web::{web, FromRequest, Handler, HttpRequest, HttpResponse, Responder};
+use serde_json::{json, Value};
+
+use crate::discovery;
+use crate::error::ApiError;
+use crate::util::negotiate::{negotiate_request, vary_accept};
+
+/// A request rate limit applied by a route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+/// What a route does and what it requires of callers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteMetadata {
+    pub description: &'static str,
+    /// Whether the route rejects callers without credentials.
+    pub auth_required: bool,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Largest accepted request body, when the route sets its own.
+    pub max_body_size: Option<usize>,
+    /// How long clients may cache the response.
+    pub cache_ttl: Option<Duration>,
+    /// Middleware applied to the route by its scopes or resource, outermost
+    /// first.
+    pub middleware: Vec<&'static str>,
+    /// Guards other than the method, e.g. the feature of a dynamic scope.
+    pub guards: Vec<String>,
+}
+
+impl RouteMetadata {
+    /// Metadata of a public route without limits.
+    pub const fn new(description: &'static str) -> Self {
+        RouteMetadata {
+            description,
+            auth_required: false,
+            rate_limit: None,
+            max_body_size: None,
+            cache_ttl: None,
+            middleware: Vec::new(),
+            guards: Vec::new(),
+        }
+    }
+
+    /// Marks the route as requiring credentials.
+    pub fn auth_required(mut self) -> Self {
+        self.auth_required = true;
+        self
+    }
+
+    /// Limits the route to `requests` per `per`.
+    pub fn rate_limit(mut self, requests: u32, per: Duration) -> Self {
+        self.rate_limit = Some(RateLimitConfig { requests, per });
+        self
+    }
+
+    /// Sets the largest accepted request body.
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = Some(bytes);
+        self
+    }
+
+    /// Sets how long clients may cache the response.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Notes middleware attached to the route's own resource.
+    pub fn middleware(mut self, name: &'static str) -> Self {
+        self.middleware.push(name);
+        self
+    }
+
+    /// Notes a guard besides the method one.
+    pub fn guard(mut self, guard: impl Into<String>) -> Self {
+        self.guards.push(guard.into());
+        self
+    }
+
+    fn describe(&self, method: &str, path: &str) -> Value {
+        json!({
+            "method": method,
+            "path": path,
+            "description": self.description,
+            "auth_required": self.auth_required,
+            "rate_limit": self.rate_limit.map(|limit| json!({
+                "requests": limit.requests,
+                "per_secs": limit.per.as_secs(),
+            })),
+            "max_body_size": self.max_body_size,
+            "cache_ttl_secs": self.cache_ttl.map(|ttl| ttl.as_secs()),
+            "auth_exempt": discovery::is_public(path),
+            "middleware": self.middleware,
+            "guards": self.guards,
+        })
+    }
+
+    fn render_text(&self, method: &str, path: &str) -> String {
+        let auth = if discovery::is_public(path) {
+            "exempt"
+        } else if self.auth_required {
+            "auth"
+        } else {
+            "public"
+        };
+        let rate = self.rate_limit.map_or("-".to_string(), |limit| {
+            format!("{}/{}s", limit.requests, limit.per.as_secs())
+        });
+        let mut line = format!(
+            "{:<7} {:<40} {:<6} {:<8} {}",
+            method, path, auth, rate, self.description
+        );
+        if !self.middleware.is_empty() {
+            line.push_str(&format!(" [middleware: {}]", self.middleware.join(", ")));
+        }
+        if !self.guards.is_empty() {
+            line.push_str(&format!(" [guards: {}]", self.guards.join(", ")));
+        }
+        line
+    }
+}
+
+/// Metadata of every route, keyed by `METHOD /path`.
+#[derive(Clone, Debug, Default)]
+pub struct MetadataMap(pub HashMap<String, RouteMetadata>);
+
+impl MetadataMap {
+    pub fn new() -> Self {
+        MetadataMap::default()
+    }
+
+    /// Describes the `method` route at `path`, replacing any earlier entry.
+    pub fn insert(&mut self, method: &str, path: &str, metadata: RouteMetadata) -> &mut Self {
+        self.0.insert(format!("{} {}", method, path), metadata);
+        self
+    }
+
+    /// Returns the metadata of the `method` route at `path`.
+    pub fn get(&self, method: &str, path: &str) -> Option<&RouteMetadata> {
+        self.0.get(&format!("{} {}", method, path))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns every route as `(method, path, metadata)`, sorted by path
+    /// then method.
+    fn sorted(&self) -> Vec<(&str, &str, &RouteMetadata)> {
+        let mut routes: Vec<(&str, &str, &RouteMetadata)> = self
+            .0
+            .iter()
+            .filter_map(|(key, metadata)| {
+                key.split_once(' ')
+                    .map(|(method, path)| (method, path, metadata))
+            })
+            .collect();
+        routes.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+        routes
+    }
+
+    /// Describes every route, sorted by path then method.
+    pub fn describe(&self) -> Value {
+        Value::Array(
+            self.sorted()
+                .into_iter()
+                .map(|(method, path, metadata)| metadata.describe(method, path))
+                .collect(),
+        )
+    }
+
+    /// Renders every route as one aligned line, sorted by path then method.
+    pub fn render_text(&self) -> String {
+        self.sorted()
+            .into_iter()
+            .map(|(method, path, metadata)| metadata.render_text(method, path) + "\n")
+            .collect()
+    }
+}
+
+/// What a [`Mount`] registered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountKind {
+    /// A scope, which takes every path below its prefix.
+    Scope,
+    /// A route for the method.
+    Route(String),
+}
+
+/// A scope or route, in registration order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mount {
+    pub kind: MountKind,
+    /// Full path, or prefix of a scope.
+    pub path: String,
+    /// Index of the innermost scope it was registered in.
+    pub parent: Option<usize>,
+}
+
+impl fmt::Display for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            MountKind::Scope => write!(f, "scope {}", self.path),
+            MountKind::Route(method) => write!(f, "{} {}", method, self.path),
+        }
+    }
+}
+
+/// Routes recorded while [`collect`] runs, every scope and route in
+/// registration order, and the scopes being configured with the index of
+/// their mount.
+#[derive(Default)]
+struct Recording {
+    routes: MetadataMap,
+    mounts: Vec<Mount>,
+    scopes: Vec<(ScopeMetadata, usize)>,
+}
+
+impl Recording {
+    /// Full path of `path` below the scopes being configured.
+    fn full_path(&self, path: &str) -> String {
+        let mut full_path: String = self
+            .scopes
+            .iter()
+            .map(|(scope, _)| scope.prefix.trim_end_matches('/'))
+            .collect();
+        full_path.push_str(path);
+        full_path
+    }
+
+    fn mount(&mut self, kind: MountKind, path: String) -> usize {
+        self.mounts.push(Mount {
+            kind,
+            path,
+            parent: self.scopes.last().map(|&(_, index)| index),
+        });
+        self.mounts.len() - 1
+    }
+}
+
+thread_local! {
+    static RECORDING: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+fn run(register: impl FnOnce()) -> Recording {
+    RECORDING.with(|r| *r.borrow_mut() = Some(Recording::default()));
+    register();
+    RECORDING
+        .with(|r| r.borrow_mut().take())
+        .unwrap_or_default()
+}
+
+/// Runs `register` and returns the routes it recorded.
+pub fn collect(register: impl FnOnce()) -> MetadataMap {
+    run(register).routes
+}
+
+/// Runs `register` and returns the scopes and routes it recorded, in
+/// registration order.
+pub fn collect_mounts(register: impl FnOnce()) -> Vec<Mount> {
+    run(register).mounts
+}
+
+/// Records the `method` route at `path`, relative to the scopes being
+/// configured. Does nothing outside [`collect`].
+pub fn record(method: &str, path: &str, metadata: RouteMetadata) {
+    RECORDING.with(|r| {
+        let mut recording = r.borrow_mut();
+        let Some(recording) = recording.as_mut() else {
+            return;
+        };
+        let full_path = recording.full_path(path);
+        let mut middleware = Vec::new();
+        let mut guards = Vec::new();
+        let mut auth_required = metadata.auth_required;
+        for (scope, _) in &recording.scopes {
+            middleware.extend(scope.middleware.iter().copied());
+            guards.extend(scope.guards.iter().cloned());
+            auth_required |= scope.auth_required;
+        }
+        middleware.extend(metadata.middleware.iter().copied());
+        guards.extend(metadata.guards.iter().cloned());
+        let metadata = RouteMetadata {
+            auth_required,
+            middleware,
+            guards,
+            ..metadata
+        };
+        recording.routes.insert(method, &full_path, metadata);
+        recording.mount(MountKind::Route(method.to_string()), full_path);
+    });
+}
+
+/// Registration of a route together with its metadata.
+pub trait DescribedRoute {
+    /// Routes `method` requests for `path` to `handler` and records
+    /// `metadata` for the route.
+    fn described_route<F, Args>(
+        &mut self,
+        method: Method,
+        path: &str,
+        handler: F,
+        metadata: RouteMetadata,
+    ) -> &mut Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static;
+}
+
+impl DescribedRoute for web::ServiceConfig {
+    fn described_route<F, Args>(
+        &mut self,
+        method: Method,
+        path: &str,
+        handler: F,
+        metadata: RouteMetadata,
+    ) -> &mut Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        record(method.as_str(), path, metadata);
+        self.route(path, web::method(method).to(handler))
+    }
+}
+
+/// What a scope adds to the routes registered inside it.
+#[derive(Clone, Debug, Default)]
+pub struct ScopeMetadata {
+    prefix: String,
+    middleware: Vec<&'static str>,
+    guards: Vec<String>,
+    auth_required: bool,
+}
+
+impl ScopeMetadata {
+    /// Metadata of a scope mounted at `prefix`.
+    pub fn new(prefix: &str) -> Self {
+        ScopeMetadata {
+            prefix: prefix.to_string(),
+            ..ScopeMetadata::default()
+        }
+    }
+
+    /// Notes middleware wrapping the scope.
+    pub fn middleware(mut self, name: &'static str) -> Self {
+        self.middleware.push(name);
+        self
+    }
+
+    /// Notes a guard of the scope.
+    pub fn guard(mut self, guard: impl Into<String>) -> Self {
+        self.guards.push(guard.into());
+        self
+    }
+
+    /// Marks every route of the scope as requiring credentials.
+    pub fn auth_required(mut self) -> Self {
+        self.auth_required = true;
+        self
+    }
+
+    /// Wraps `configure` so the routes it records are placed below this
+    /// scope. Pass the result to `Scope::configure`.
+    pub fn configure<F>(self, configure: F) -> impl FnOnce(&mut web::ServiceConfig)
+    where
+        F: FnOnce(&mut web::ServiceConfig),
+    {
+        move |cfg| {
+            let recording = RECORDING.with(|r| match r.borrow_mut().as_mut() {
+                Some(recording) => {
+                    let mut prefix = recording.full_path(self.prefix.trim_end_matches('/'));
+                    if prefix.is_empty() {
+                        prefix.push('/');
+                    }
+                    let index = recording.mount(MountKind::Scope, prefix);
+                    recording.scopes.push((self, index));
+                    true
+                }
+                None => false,
+            });
+            configure(cfg);
+            if recording {
+                RECORDING.with(|r| {
+                    if let Some(recording) = r.borrow_mut().as_mut() {
+                        recording.scopes.pop();
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Registers `GET /routes` on the `/admin` scope.
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    cfg.described_route(
+        Method::GET,
+        "/routes",
+        list_routes,
+        RouteMetadata::new("Every route and its metadata"),
+    );
+}
+
+/// Media types `GET /admin/routes` can answer with.
+const ROUTES_TYPES: &[&str] = &["application/json", "text/plain"];
+
+/// Handler for `GET /admin/routes`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with every route and its metadata, as JSON or one line per route for `Accept: text/plain`, or a 406 ApiError.
+pub async fn list_routes(
+    req: HttpRequest,
+    routes: Option<web::Data<MetadataMap>>,
+) -> Result<HttpResponse, ApiError> {
+    let routes = routes.map(|routes| routes.into_inner());
+    let res = match negotiate_request(&req, ROUTES_TYPES)? {
+        "text/plain" => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(
+                routes
+                    .map(|routes| routes.render_text())
+                    .unwrap_or_default(),
+            ),
+        _ => {
+            let routes = routes.map_or(Value::Array(Vec::new()), |routes| routes.describe());
+            HttpResponse::Ok().json(json!({ "routes": routes }))
+        }
+    };
+    Ok(vary_accept(res))
+}
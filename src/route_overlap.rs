@@ -0,0 +1,166 @@
+//! Startup check for route registrations that shadow each other.
+//!
+//! actix-web hands a request to the first service whose path matches, in
+//! registration order. A scope takes every path below its prefix, even
+//! those it has no route for, and a tail pattern such as `/{path:.*}` takes
+//! every path below its own prefix. Anything registered later below the
+//! same prefix, outside that scope, is never reached: a static mount at `/`
+//! added before the API would swallow it. [`find`] reports such
+//! registrations, and routes registered twice, from the mounts recorded by
+//! [`ModuleRegistry::mounts`](crate::routes::ModuleRegistry::mounts).
+//!
+//! At startup every finding is logged as a warning; with
+//! `STRICT_ROUTES=true` startup fails instead.
+
+use std::env;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+
+use log::warn;
+
+use crate::route_meta::{Mount, MountKind};
+
+/// Why a registration is a problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverlapKind {
+    /// An earlier scope or tail pattern takes every path it would serve.
+    Shadowed,
+    /// The same method and path were registered before.
+    Duplicate,
+}
+
+/// A registration that cannot be served as written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Overlap {
+    pub kind: OverlapKind,
+    /// The registration that is not reached, e.g. `GET /api/items`.
+    pub mount: String,
+    /// The earlier registration taking its requests.
+    pub by: String,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            OverlapKind::Shadowed => write!(
+                f,
+                "{} is never reached: {} is registered first and takes every path below it",
+                self.mount, self.by
+            ),
+            OverlapKind::Duplicate => write!(
+                f,
+                "{} is registered twice; only the first registration is served",
+                self.mount
+            ),
+        }
+    }
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    match path.strip_prefix('/').unwrap_or(path) {
+        "" => Vec::new(),
+        path => path.split('/').collect(),
+    }
+}
+
+fn is_param(segment: &str) -> bool {
+    segment.starts_with('{') && segment.ends_with('}')
+}
+
+/// Whether `segment` is a pattern matching the rest of the path.
+fn is_tail(segment: &str) -> bool {
+    is_param(segment) && (segment.contains(":.*") || segment.contains(":.+"))
+}
+
+/// The prefix `mount` takes every path below, and whether that includes
+/// the prefix itself.
+fn captured(mount: &Mount) -> Option<(Vec<&str>, bool)> {
+    let segments = segments(&mount.path);
+    match mount.kind {
+        MountKind::Scope => Some((segments, true)),
+        MountKind::Route(_) => match segments.split_last() {
+            Some((last, prefix)) if is_tail(last) => Some((prefix.to_vec(), false)),
+            _ => None,
+        },
+    }
+}
+
+/// Whether a path matching `path` can fall below `prefix`.
+fn below(prefix: &[&str], inclusive: bool, path: &[&str]) -> bool {
+    let deeper = path.len() > prefix.len() || (inclusive && path.len() == prefix.len());
+    deeper && prefix.iter().zip(path).all(|(p, s)| p == s || is_param(p))
+}
+
+/// Whether `mounts[index]` was registered inside `mounts[scope]`.
+fn inside(mounts: &[Mount], index: usize, scope: usize) -> bool {
+    let mut parent = mounts[index].parent;
+    while let Some(current) = parent {
+        if current == scope {
+            return true;
+        }
+        parent = mounts[current].parent;
+    }
+    false
+}
+
+/// Lists the registrations in `mounts` that are shadowed by an earlier one
+/// or registered twice, in registration order.
+pub fn find(mounts: &[Mount]) -> Vec<Overlap> {
+    let mut overlaps = Vec::new();
+    for (index, mount) in mounts.iter().enumerate() {
+        let path = segments(&mount.path);
+        let shadowed_by = mounts[..index].iter().enumerate().find(|&(earlier, by)| {
+            !inside(mounts, index, earlier)
+                && captured(by).is_some_and(|(prefix, inclusive)| below(&prefix, inclusive, &path))
+        });
+        if let Some((_, by)) = shadowed_by {
+            overlaps.push(Overlap {
+                kind: OverlapKind::Shadowed,
+                mount: mount.to_string(),
+                by: by.to_string(),
+            });
+            continue;
+        }
+        if let MountKind::Route(_) = mount.kind {
+            let duplicate = mounts[..index]
+                .iter()
+                .find(|earlier| earlier.kind == mount.kind && earlier.path == mount.path);
+            if let Some(by) = duplicate {
+                overlaps.push(Overlap {
+                    kind: OverlapKind::Duplicate,
+                    mount: mount.to_string(),
+                    by: by.to_string(),
+                });
+            }
+        }
+    }
+    overlaps
+}
+
+/// Logs every overlap in `mounts` as a warning.
+///
+/// # Errors
+///
+/// Returns an IoError listing the overlaps if there are any and `strict`
+/// is set.
+pub fn check(mounts: &[Mount], strict: bool) -> Result<(), IoError> {
+    let overlaps = find(mounts);
+    for overlap in &overlaps {
+        warn!("Overlapping routes: {}", overlap);
+    }
+    if strict && !overlaps.is_empty() {
+        let list: Vec<String> = overlaps.iter().map(Overlap::to_string).collect();
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("STRICT_ROUTES: {}", list.join("; ")),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `STRICT_ROUTES` turns overlaps into startup errors.
+pub fn strict_from_env() -> bool {
+    env::var("STRICT_ROUTES")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
@@ -0,0 +1,409 @@
+//! Route registration split into modules.
+//!
+//! Each group of routes implements [`RouteModule`] and is added to a
+//! [`ModuleRegistry`], which the server applies to every worker's `App`.
+//! A module can be tested on its own by registering it in a test app.
+//! Modules register their routes with
+//! [`DescribedRoute`](crate::route_meta::DescribedRoute), so the
+//! [`MetadataMap`] listed by `GET /admin/routes` is recorded by the same
+//! registration calls the workers run.
+//!
+//! Builds with the default `full` feature register every module. Builds
+//! with only the `core` feature serve `/hello`, the health and metrics
+//! routes, the discovery files and the favicons.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::Method;
+use actix_web::{web, App};
+
+use crate::discovery::{self, Discovery};
+use crate::favicon::{self, Favicons};
+use crate::health;
+use crate::hello;
+use crate::metrics;
+use crate::route_meta::{self, DescribedRoute, MetadataMap, Mount, RouteMetadata};
+
+/// Cache lifetime of the static files served with `max-age=86400`.
+pub(crate) const ONE_DAY: Duration = Duration::from_secs(86_400);
+
+/// Cache lifetime of the favicons.
+pub(crate) const ONE_WEEK: Duration = Duration::from_secs(7 * 86_400);
+
+#[cfg(feature = "full")]
+pub use self::full::{AdminModule, ApiModule, StaticModule, TlsModule};
+
+/// A group of routes registered together.
+pub trait RouteModule: Send + Sync {
+    /// Registers the module's routes and app data on `cfg`, describing
+    /// each route as it is added.
+    fn register(&self, cfg: &mut web::ServiceConfig);
+}
+
+/// Modules applied to the app in the order they were added.
+#[derive(Clone, Default)]
+pub struct ModuleRegistry {
+    modules: Vec<Arc<dyn RouteModule>>,
+}
+
+impl ModuleRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        ModuleRegistry::default()
+    }
+
+    /// Adds `module` after the ones already added.
+    pub fn with_module<M: RouteModule + 'static>(mut self, module: M) -> Self {
+        self.modules.push(Arc::new(module));
+        self
+    }
+
+    /// Returns the number of modules.
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Returns whether no module was added.
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    /// Registers every module on `cfg`.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        for module in &self.modules {
+            module.register(cfg);
+        }
+    }
+
+    /// Collects the route metadata of every module by registering them on
+    /// a throwaway app.
+    pub fn metadata(&self) -> MetadataMap {
+        route_meta::collect(|| {
+            drop(App::new().configure(|cfg| self.configure(cfg)));
+        })
+    }
+
+    /// Collects every scope and route of every module in registration
+    /// order, for [`route_overlap`](crate::route_overlap).
+    pub fn mounts(&self) -> Vec<Mount> {
+        route_meta::collect_mounts(|| {
+            drop(App::new().configure(|cfg| self.configure(cfg)));
+        })
+    }
+}
+
+/// `/health`, `/ready`, `/health/dependency/{name}` and `/metrics`.
+#[derive(Clone, Copy, Default)]
+pub struct HealthModule;
+
+impl RouteModule for HealthModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        cfg.described_route(
+            Method::GET,
+            "/health",
+            health::health,
+            RouteMetadata::new("Liveness probe"),
+        )
+        .described_route(
+            Method::GET,
+            "/ready",
+            health::ready,
+            RouteMetadata::new(
+                "Readiness probe; 503 once shutdown has started or a critical dependency is down",
+            ),
+        )
+        .described_route(
+            Method::GET,
+            "/health/dependency/{name}",
+            health::dependency,
+            RouteMetadata::new("Result of one dependency check"),
+        )
+        .described_route(
+            Method::GET,
+            "/health/history",
+            health::history::history,
+            RouteMetadata::new("Recent readiness results, oldest first"),
+        )
+        .described_route(
+            Method::GET,
+            "/health/trend",
+            health::history::trend,
+            RouteMetadata::new("Error rate, latency and flaps of recent readiness results"),
+        )
+        .described_route(
+            Method::GET,
+            "/metrics",
+            metrics::metrics,
+            RouteMetadata::new("Metrics in Prometheus format"),
+        );
+    }
+}
+
+/// `/robots.txt` and `/.well-known/security.txt`.
+#[derive(Clone)]
+pub struct DiscoveryModule {
+    pub discovery: web::Data<Discovery>,
+}
+
+impl RouteModule for DiscoveryModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        discovery::configure(self.discovery.clone())(cfg);
+    }
+}
+
+/// `/favicon.ico` and `/apple-touch-icon.png`.
+#[derive(Clone)]
+pub struct FaviconModule {
+    pub favicons: web::Data<Favicons>,
+}
+
+impl RouteModule for FaviconModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        favicon::configure(self.favicons.clone())(cfg);
+    }
+}
+
+/// `/hello`, the only API route of `core` builds.
+#[derive(Clone, Copy, Default)]
+pub struct HelloModule;
+
+impl RouteModule for HelloModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        cfg.described_route(
+            Method::GET,
+            "/hello",
+            hello,
+            RouteMetadata::new("Localized greeting"),
+        );
+    }
+}
+
+#[cfg(feature = "full")]
+mod full {
+    use std::time::Duration;
+
+    use actix_web::http::Method;
+    use actix_web::web;
+
+    use super::{HelloModule, RouteModule};
+    use crate::audit::trail::{self, AuditLog};
+    use crate::auth::{self, AuthBackend};
+    use crate::cache::{self, MultiLayerCache};
+    use crate::dynamic_scope::{DynamicScope, Features};
+    use crate::memory::{self, MemoryPressureWatcher};
+    use crate::middleware::api_key::{AdminKey, ApiKeyAuth};
+    use crate::middleware::i18n::{self, Translations};
+    use crate::proxy::{self, Proxy};
+    use crate::route_meta::{DescribedRoute, RouteMetadata, ScopeMetadata};
+    use crate::static_files::{self, StaticFiles};
+    use crate::tls_chain::{self, TlsChain};
+    use crate::{admin, mail, middleware, pwa};
+
+    /// The `/admin` scope, behind the admin API key.
+    #[derive(Clone)]
+    pub struct AdminModule {
+        pub admin_key: web::Data<AdminKey>,
+        pub memory_watcher: web::Data<MemoryPressureWatcher>,
+        /// Recent administrative actions, for `/admin/audit` without a
+        /// database.
+        pub audit_log: web::Data<AuditLog>,
+        pub translations: Option<web::Data<Translations>>,
+        pub cache: Option<web::Data<MultiLayerCache>>,
+        /// Whether the reverse proxy admin routes are registered.
+        pub proxy: bool,
+        #[cfg(feature = "db")]
+        pub audit_store: Option<web::Data<crate::audit::store::AuditStore>>,
+    }
+
+    impl RouteModule for AdminModule {
+        fn register(&self, cfg: &mut web::ServiceConfig) {
+            let routes = ScopeMetadata::new("/admin")
+                .middleware("ApiKeyAuth")
+                .auth_required()
+                .configure(|cfg| {
+                    admin::configure(cfg);
+                    cfg.app_data(self.memory_watcher.clone())
+                        .described_route(
+                            Method::GET,
+                            "/memory",
+                            memory::memory_status,
+                            RouteMetadata::new("Memory usage and pressure"),
+                        )
+                        .described_route(
+                            Method::POST,
+                            "/test-email",
+                            mail::test_email,
+                            RouteMetadata::new("Send a test email through the mail queue"),
+                        );
+                    if let Some(translations) = &self.translations {
+                        cfg.app_data(translations.clone()).described_route(
+                            Method::GET,
+                            "/i18n/supported-languages",
+                            i18n::supported_languages,
+                            RouteMetadata::new("Languages of the response translations"),
+                        );
+                    }
+                    if let Some(cache) = &self.cache {
+                        cfg.app_data(cache.clone()).described_route(
+                            Method::GET,
+                            "/cache/stats",
+                            cache::stats,
+                            RouteMetadata::new("Response cache hits by tier, misses and evictions"),
+                        );
+                    }
+                    if self.proxy {
+                        proxy::configure_admin(cfg);
+                    }
+                    // The database holds the audit trail events too, and more
+                    // than the last thousand
+                    let persisted = false;
+                    #[cfg(feature = "db")]
+                    let persisted = if let Some(store) = &self.audit_store {
+                        cfg.app_data(store.clone()).described_route(
+                            Method::GET,
+                            "/audit",
+                            crate::audit::store::list_events,
+                            RouteMetadata::new("Persisted audit events"),
+                        );
+                        true
+                    } else {
+                        persisted
+                    };
+                    if !persisted {
+                        cfg.app_data(self.audit_log.clone()).described_route(
+                            Method::GET,
+                            "/audit",
+                            trail::list_events,
+                            RouteMetadata::new("Recent administrative actions"),
+                        );
+                    }
+                });
+            cfg.service(
+                web::scope("/admin")
+                    .wrap(ApiKeyAuth::shared(self.admin_key.get_ref().clone()))
+                    .app_data(self.admin_key.clone())
+                    .configure(routes),
+            );
+        }
+    }
+
+    /// `/tls`, the served certificate chain, behind the admin API key.
+    #[derive(Clone)]
+    pub struct TlsModule {
+        pub admin_key: AdminKey,
+        pub chain: web::Data<TlsChain>,
+    }
+
+    impl RouteModule for TlsModule {
+        fn register(&self, cfg: &mut web::ServiceConfig) {
+            tls_chain::configure(self.chain.clone(), self.admin_key.clone())(cfg);
+        }
+    }
+
+    /// `/hello`, the authentication routes and the reverse proxy. Add it
+    /// last: the proxy catches every path below its prefix.
+    #[derive(Clone, Default)]
+    pub struct ApiModule {
+        pub auth_backend: Option<web::Data<dyn AuthBackend>>,
+        pub password_reset: Option<web::Data<auth::reset::PasswordReset>>,
+        /// Features the route groups that can be switched off register in.
+        pub features: Features,
+        #[cfg(feature = "webauthn")]
+        pub passkeys: Option<web::Data<auth::webauthn::PasskeyAuth>>,
+        /// Macaroon authority, with the admin key allowed to mint.
+        #[cfg(feature = "macaroon")]
+        pub macaroons: Option<(web::Data<auth::macaroon::MacaroonAuthority>, AdminKey)>,
+        pub reverse_proxy: Option<web::Data<Proxy>>,
+    }
+
+    impl RouteModule for ApiModule {
+        fn register(&self, cfg: &mut web::ServiceConfig) {
+            HelloModule.register(cfg);
+            if let Some(reverse_proxy) = &self.reverse_proxy {
+                Proxy::configure(reverse_proxy.clone())(cfg);
+            }
+            if let Some(backend) = &self.auth_backend {
+                let protected = ScopeMetadata::new("/protected")
+                    .middleware("BasicAuth")
+                    .auth_required()
+                    .configure(|cfg| {
+                        cfg.described_route(
+                            Method::GET,
+                            "",
+                            auth::protected,
+                            RouteMetadata::new("Example route behind Basic authentication"),
+                        );
+                    });
+                cfg.app_data(backend.clone())
+                    .described_route(
+                        Method::POST,
+                        "/auth/login",
+                        auth::login,
+                        RouteMetadata::new("Check credentials against the credential backend"),
+                    )
+                    .service(
+                        web::scope("/protected")
+                            .wrap(middleware::basic_auth::BasicAuth::new(
+                                backend.clone().into_inner(),
+                            ))
+                            .configure(protected),
+                    );
+            }
+            #[cfg(feature = "webauthn")]
+            if let (Some(passkeys), Some(backend)) = (&self.passkeys, &self.auth_backend) {
+                auth::webauthn::PasskeyAuth::configure(
+                    passkeys.clone(),
+                    backend.clone().into_inner(),
+                )(cfg);
+            }
+            #[cfg(feature = "macaroon")]
+            if let Some((macaroons, admin_key)) = &self.macaroons {
+                auth::macaroon::configure(macaroons.clone(), admin_key.clone())(cfg);
+            }
+            if let Some(reset) = &self.password_reset {
+                let routes = ScopeMetadata::new("/auth/password-reset")
+                    .guard("feature password-reset")
+                    .configure(|cfg| {
+                        cfg.described_route(
+                            Method::POST,
+                            "/request",
+                            auth::reset::request_reset,
+                            RouteMetadata::new("Email a password reset link")
+                                .rate_limit(10, Duration::from_secs(60)),
+                        )
+                        .described_route(
+                            Method::POST,
+                            "/confirm",
+                            auth::reset::confirm_reset,
+                            RouteMetadata::new("Set a new password with a reset token"),
+                        );
+                    });
+                cfg.service(DynamicScope::new(
+                    &self.features,
+                    "password-reset",
+                    web::scope("/auth/password-reset")
+                        .app_data(reset.clone())
+                        .configure(routes),
+                ));
+            }
+        }
+    }
+
+    /// The web app manifest, service worker and `STATIC_DIR` files.
+    #[derive(Clone)]
+    pub struct StaticModule {
+        pub pwa: web::Data<pwa::PwaConfig>,
+        /// `None` when `STATIC_DIR` is unset.
+        pub files: Option<web::Data<StaticFiles>>,
+    }
+
+    impl RouteModule for StaticModule {
+        fn register(&self, cfg: &mut web::ServiceConfig) {
+            pwa::configure(self.pwa.clone())(cfg);
+            if let Some(files) = &self.files {
+                static_files::configure(files.clone())(cfg);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! ClamAV scanner speaking the clamd TCP protocol (`clamav` feature).
+//!
+//! Files are streamed with `zINSTREAM`: each chunk is sent prefixed with its
+//! length as a 4-byte big-endian number, and a zero length ends the stream.
+//! clamd answers `stream: OK`, `stream: <signature> FOUND`, or an error such
+//! as `INSTREAM size limit exceeded. ERROR`, terminated by a NUL byte. A
+//! connection, read or write that fails or takes longer than
+//! `CLAMD_TIMEOUT_SECS` makes the scanner unavailable.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use super::{ContentScanner, ScanVerdict};
+
+/// Default for `CLAMD_ADDRESS`.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:3310";
+
+/// Default for `CLAMD_TIMEOUT_SECS`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes sent per `INSTREAM` chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest reply read from clamd.
+const MAX_REPLY_BYTES: usize = 4096;
+
+/// Settings for [`ClamdScanner`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClamdConfig {
+    /// `host:port` of clamd's TCP socket.
+    pub address: String,
+    /// Limit on the whole scan of one file.
+    pub timeout: Duration,
+}
+
+impl Default for ClamdConfig {
+    fn default() -> Self {
+        ClamdConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl ClamdConfig {
+    /// Reads `CLAMD_ADDRESS` and `CLAMD_TIMEOUT_SECS`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the timeout is not a positive number.
+    pub fn from_env() -> Result<Self, IoError> {
+        let defaults = ClamdConfig::default();
+        let timeout = match env::var("CLAMD_TIMEOUT_SECS") {
+            Ok(value) => match value.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "CLAMD_TIMEOUT_SECS must be a positive number, got '{}'",
+                            value
+                        ),
+                    ))
+                }
+            },
+            Err(_) => defaults.timeout,
+        };
+        Ok(ClamdConfig {
+            address: env::var("CLAMD_ADDRESS")
+                .ok()
+                .filter(|a| !a.is_empty())
+                .unwrap_or(defaults.address),
+            timeout,
+        })
+    }
+}
+
+/// Scans files with clamd.
+#[derive(Clone, Debug)]
+pub struct ClamdScanner {
+    config: ClamdConfig,
+}
+
+impl ClamdScanner {
+    pub fn new(config: ClamdConfig) -> Self {
+        ClamdScanner { config }
+    }
+
+    /// Streams the file to clamd and returns its reply, without the NUL.
+    async fn instream(&self, path: &Path) -> Result<String, IoError> {
+        let mut file = File::open(path).await?;
+        let mut stream = TcpStream::connect(&self.config.address).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            stream.write_all(&chunk[..read]).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        stream.flush().await?;
+
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let read = stream.read(&mut buf).await?;
+            reply.extend_from_slice(&buf[..read]);
+            if read == 0 || reply.contains(&0) || reply.len() > MAX_REPLY_BYTES {
+                break;
+            }
+        }
+        let end = reply.iter().position(|&b| b == 0).unwrap_or(reply.len());
+        Ok(String::from_utf8_lossy(&reply[..end]).trim().to_string())
+    }
+}
+
+/// Interprets a clamd reply to `INSTREAM`.
+pub fn parse_reply(reply: &str) -> ScanVerdict {
+    let result = reply
+        .strip_prefix("stream:")
+        .map(str::trim)
+        .unwrap_or(reply);
+    if result == "OK" {
+        ScanVerdict::Clean
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        ScanVerdict::Infected(signature.trim().to_string())
+    } else if result.is_empty() {
+        ScanVerdict::Unavailable("clamd closed the connection without a verdict".to_string())
+    } else {
+        ScanVerdict::Unavailable(format!("clamd replied '{}'", result))
+    }
+}
+
+#[async_trait]
+impl ContentScanner for ClamdScanner {
+    async fn scan(&self, path: &Path) -> ScanVerdict {
+        match timeout(self.config.timeout, self.instream(path)).await {
+            Ok(Ok(reply)) => parse_reply(&reply),
+            Ok(Err(e)) => ScanVerdict::Unavailable(format!(
+                "cannot scan with clamd at {}: {}",
+                self.config.address, e
+            )),
+            Err(_) => ScanVerdict::Unavailable(format!(
+                "clamd at {} did not answer within {:?}",
+                self.config.address, self.config.timeout
+            )),
+        }
+    }
+}
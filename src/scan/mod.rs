@@ -0,0 +1,286 @@
+//! Content scanning of uploaded files.
+//!
+//! Compliance requires uploads to be scanned before they are served back.
+//! An upload handler writes the file, then calls
+//! [`UploadScanning::check`] before making it downloadable:
+//!
+//! * a clean file is kept;
+//! * an infected file is deleted and the upload rejected with 422
+//!   `upload_infected`;
+//! * when the scanner cannot be reached, `SCAN_UNAVAILABLE_POLICY` decides:
+//!   `quarantine` (the default) moves the file to `SCAN_QUARANTINE_DIR` and
+//!   rejects the upload with 503 `scanner_unavailable`, `allow` keeps it
+//!   and logs a warning.
+//!
+//! Every rejection, quarantine and unscanned file is recorded in the audit
+//! trail, and each verdict is counted in `upload_scans_total`.
+//!
+//! `CONTENT_SCANNER` selects the [`ContentScanner`]: `none` (the default)
+//! passes every file, `clamav` streams files to clamd (`clamav` feature).
+//! No route accepts uploads yet; handlers taking
+//! [`Multipart`](crate::util::multipart::Multipart) call the hook.
+
+#[cfg(feature = "clamav")]
+pub mod clamav;
+
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_web::http::StatusCode;
+use async_trait::async_trait;
+use log::warn;
+use serde_json::json;
+
+use crate::audit::{AuditTrail, Outcome};
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+
+/// Default for `SCAN_QUARANTINE_DIR`.
+pub const DEFAULT_QUARANTINE_DIR: &str = "quarantine";
+
+/// What a scanner found in a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Malware was found, by signature name.
+    Infected(String),
+    /// The file could not be scanned, and why.
+    Unavailable(String),
+}
+
+impl ScanVerdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanVerdict::Clean => "clean",
+            ScanVerdict::Infected(_) => "infected",
+            ScanVerdict::Unavailable(_) => "unavailable",
+        }
+    }
+}
+
+/// Scans files for malware.
+#[async_trait]
+pub trait ContentScanner: Send + Sync {
+    /// Scans the file at `path`.
+    async fn scan(&self, path: &Path) -> ScanVerdict;
+}
+
+/// Passes every file, for deployments without a scanner.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopScanner;
+
+#[async_trait]
+impl ContentScanner for NoopScanner {
+    async fn scan(&self, _path: &Path) -> ScanVerdict {
+        ScanVerdict::Clean
+    }
+}
+
+/// What to do with a file the scanner could not scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnavailablePolicy {
+    /// Move it to the quarantine directory and reject the upload.
+    Quarantine,
+    /// Keep it and log a warning.
+    Allow,
+}
+
+impl FromStr for UnavailablePolicy {
+    type Err = IoError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "quarantine" => Ok(UnavailablePolicy::Quarantine),
+            "allow" => Ok(UnavailablePolicy::Allow),
+            _ => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "SCAN_UNAVAILABLE_POLICY must be quarantine or allow, got '{}'",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+/// The scanner and what to do with its verdicts.
+#[derive(Clone)]
+pub struct UploadScanning {
+    scanner: Arc<dyn ContentScanner>,
+    on_unavailable: UnavailablePolicy,
+    quarantine_dir: PathBuf,
+}
+
+impl Default for UploadScanning {
+    fn default() -> Self {
+        UploadScanning::new(Arc::new(NoopScanner))
+    }
+}
+
+impl UploadScanning {
+    /// Scans with `scanner`, quarantining files it cannot scan in
+    /// [`DEFAULT_QUARANTINE_DIR`].
+    pub fn new(scanner: Arc<dyn ContentScanner>) -> Self {
+        UploadScanning {
+            scanner,
+            on_unavailable: UnavailablePolicy::Quarantine,
+            quarantine_dir: PathBuf::from(DEFAULT_QUARANTINE_DIR),
+        }
+    }
+
+    /// Applies `policy` to files that cannot be scanned.
+    pub fn on_unavailable(mut self, policy: UnavailablePolicy) -> Self {
+        self.on_unavailable = policy;
+        self
+    }
+
+    /// Moves files that cannot be scanned to `dir`.
+    pub fn quarantine_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.quarantine_dir = dir.into();
+        self
+    }
+
+    /// Reads `CONTENT_SCANNER`, `SCAN_UNAVAILABLE_POLICY` and
+    /// `SCAN_QUARANTINE_DIR`, and with the `clamav` feature the clamd
+    /// settings.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<UploadScanning, IoError>` - The configuration, or an IoError if a setting is invalid or the scanner needs a feature this build lacks.
+    pub fn from_env() -> Result<Self, IoError> {
+        let scanner: Arc<dyn ContentScanner> = match env::var("CONTENT_SCANNER").as_deref() {
+            Ok("none") | Ok("") | Err(_) => Arc::new(NoopScanner),
+            Ok("clamav") => {
+                #[cfg(feature = "clamav")]
+                {
+                    let config = clamav::ClamdConfig::from_env()?;
+                    log::info!("Scanning uploads with clamd at {}", config.address);
+                    Arc::new(clamav::ClamdScanner::new(config))
+                }
+                #[cfg(not(feature = "clamav"))]
+                {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidInput,
+                        "CONTENT_SCANNER=clamav requires the `clamav` feature",
+                    ));
+                }
+            }
+            Ok(other) => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown CONTENT_SCANNER '{}'", other),
+                ))
+            }
+        };
+        let policy = match env::var("SCAN_UNAVAILABLE_POLICY") {
+            Ok(value) => value.parse()?,
+            Err(_) => UnavailablePolicy::Quarantine,
+        };
+        let dir = env::var("SCAN_QUARANTINE_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| DEFAULT_QUARANTINE_DIR.to_string());
+        Ok(UploadScanning::new(scanner)
+            .on_unavailable(policy)
+            .quarantine_dir(dir))
+    }
+
+    /// Scans the uploaded file at `path` before it becomes downloadable,
+    /// recording rejections in `audit`.
+    ///
+    /// # Errors
+    ///
+    /// Returns 422 `upload_infected` after deleting an infected file, or
+    /// 503 `scanner_unavailable` after quarantining a file that could not
+    /// be scanned.
+    pub async fn check(&self, path: &Path, audit: &AuditTrail) -> Result<(), ApiError> {
+        let verdict = self.scanner.scan(path).await;
+        Metrics::global().inc("upload_scans_total", &[("verdict", verdict.as_str())]);
+        let file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        match verdict {
+            ScanVerdict::Clean => Ok(()),
+            ScanVerdict::Infected(signature) => {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to delete infected upload {}: {}", path.display(), e);
+                }
+                warn!(
+                    "Rejected upload {}: infected with {}",
+                    path.display(),
+                    signature
+                );
+                audit.record_with(
+                    "upload_rejected",
+                    Outcome::Failure,
+                    json!({ "file": file, "signature": signature }),
+                );
+                Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "upload_infected",
+                    "The uploaded file was rejected by the content scanner",
+                ))
+            }
+            ScanVerdict::Unavailable(reason) => match self.on_unavailable {
+                UnavailablePolicy::Allow => {
+                    warn!(
+                        "Accepted upload {} without scanning it: {}",
+                        path.display(),
+                        reason
+                    );
+                    audit.record_with(
+                        "upload_unscanned",
+                        Outcome::Success,
+                        json!({ "file": file, "reason": reason }),
+                    );
+                    Ok(())
+                }
+                UnavailablePolicy::Quarantine => {
+                    let quarantined = self.quarantine(path, &file);
+                    if let Err(e) = &quarantined {
+                        warn!("Failed to quarantine upload {}: {}", path.display(), e);
+                        let _ = fs::remove_file(path);
+                    }
+                    warn!(
+                        "Quarantined upload {}, the scanner is unavailable: {}",
+                        path.display(),
+                        reason
+                    );
+                    audit.record_with(
+                        "upload_quarantined",
+                        Outcome::Failure,
+                        json!({
+                            "file": file,
+                            "reason": reason,
+                            "quarantined_as": quarantined.ok().map(|p| p.display().to_string()),
+                        }),
+                    );
+                    Err(ApiError::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "scanner_unavailable",
+                        "The uploaded file could not be scanned and was quarantined",
+                    ))
+                }
+            },
+        }
+    }
+
+    /// Moves `path` into the quarantine directory under a unique name.
+    fn quarantine(&self, path: &Path, file: &str) -> Result<PathBuf, IoError> {
+        fs::create_dir_all(&self.quarantine_dir)?;
+        let target = self
+            .quarantine_dir
+            .join(format!("{}-{}", uuid::Uuid::new_v4(), file));
+        // A rename fails across file systems
+        if fs::rename(path, &target).is_err() {
+            fs::copy(path, &target)?;
+            fs::remove_file(path)?;
+        }
+        Ok(target)
+    }
+}
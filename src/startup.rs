@@ -0,0 +1,224 @@
+//! Configuration validated before the server starts.
+//!
+//! `main` loads and checks every piece of configuration in one pass, each
+//! as a named step of [`StartupChecks`], before spawning background tasks,
+//! binding sockets or building the `HttpServer`. The first failing step
+//! stops startup with a [`ConfigError`] naming it, so a misconfiguration
+//! never leaves a partially started server behind. Once every step has
+//! passed, [`StartupChecks::summary`] lists them in the order they ran.
+//!
+//! Secrets may be written by a sidecar shortly after the container starts.
+//! With `STARTUP_WAIT_FOR_FILES_SECS` set, the first step,
+//! [`wait_for_files_from_env`], waits for them instead of failing right
+//! away. It only runs at startup; reloads read files as they are.
+
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+/// First pause between two looks for missing files; doubled after each.
+const FIRST_FILE_POLL: Duration = Duration::from_millis(100);
+
+/// Longest pause between two looks for missing files.
+const MAX_FILE_POLL: Duration = Duration::from_secs(2);
+
+/// A configuration step that failed, and why.
+#[derive(Debug)]
+pub struct ConfigError {
+    /// Name of the failed step, as passed to [`StartupChecks::check`].
+    pub step: &'static str,
+    /// Position of the step in the validation order, starting at 1.
+    pub position: usize,
+    pub source: IoError,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid configuration at step {} ({}): {}",
+            self.position, self.step, self.source
+        )
+    }
+}
+
+impl StdError for ConfigError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<ConfigError> for IoError {
+    fn from(e: ConfigError) -> Self {
+        IoError::new(e.source.kind(), e)
+    }
+}
+
+/// Runs the configuration steps in order and records those that passed.
+#[derive(Debug, Default)]
+pub struct StartupChecks {
+    passed: Vec<&'static str>,
+}
+
+impl StartupChecks {
+    pub fn new() -> Self {
+        StartupChecks::default()
+    }
+
+    /// Records the outcome of step `step`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ConfigError`] naming the step if `result` is an error;
+    /// the error is also logged.
+    pub fn check<T, E: Into<IoError>>(
+        &mut self,
+        step: &'static str,
+        result: Result<T, E>,
+    ) -> Result<T, ConfigError> {
+        match result {
+            Ok(value) => {
+                self.passed.push(step);
+                Ok(value)
+            }
+            Err(e) => {
+                let e = ConfigError {
+                    step,
+                    position: self.passed.len() + 1,
+                    source: e.into(),
+                };
+                error!("{}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the steps that passed, in the order they ran.
+    pub fn passed(&self) -> &[&'static str] {
+        &self.passed
+    }
+
+    /// Describes the steps that passed, numbered in the order they ran.
+    pub fn summary(&self) -> String {
+        self.passed
+            .iter()
+            .enumerate()
+            .map(|(i, step)| format!("{}. {}", i + 1, step))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Files read at startup, as `(variable, path)`: the certificate, the key
+/// and every other `*_FILE` variable that is set, sorted by variable.
+pub fn startup_files() -> Vec<(String, String)> {
+    let mut files = vec![
+        (
+            "CERT_FILE".to_string(),
+            env::var("CERT_FILE").unwrap_or_else(|_| "cert.pem".to_string()),
+        ),
+        (
+            "KEY_FILE".to_string(),
+            env::var("KEY_FILE").unwrap_or_else(|_| "key.pem".to_string()),
+        ),
+    ];
+    let mut secrets: Vec<(String, String)> = env::vars()
+        .filter(|(var, path)| {
+            // The access log is written by the server, not read
+            var.ends_with("_FILE")
+                && !matches!(var.as_str(), "CERT_FILE" | "KEY_FILE" | "ACCESS_LOG_FILE")
+                && !path.is_empty()
+        })
+        .collect();
+    secrets.sort();
+    files.extend(secrets);
+    files
+}
+
+/// Checks that `path` can be opened and is not empty, as a file still
+/// being written may be.
+fn readable(path: &str) -> Result<(), IoError> {
+    if File::open(path)?.metadata()?.len() == 0 {
+        return Err(IoError::new(ErrorKind::UnexpectedEof, "file is empty"));
+    }
+    Ok(())
+}
+
+/// Waits until every file of `files` can be read, looking again with
+/// backoff for up to `timeout`.
+///
+/// # Errors
+///
+/// Returns an IoError naming the first file still missing once `timeout`
+/// has passed.
+pub fn wait_for_files(files: &[(String, String)], timeout: Duration) -> Result<(), IoError> {
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let mut pause = FIRST_FILE_POLL;
+    loop {
+        let missing: Vec<(&str, &str, IoError)> = files
+            .iter()
+            .filter_map(|(var, path)| {
+                readable(path)
+                    .err()
+                    .map(|e| (var.as_str(), path.as_str(), e))
+            })
+            .collect();
+        let Some((var, path, e)) = missing.first() else {
+            if started.elapsed() >= FIRST_FILE_POLL {
+                info!("Startup files readable after {:?}", started.elapsed());
+            }
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(IoError::new(
+                e.kind(),
+                format!(
+                    "{} '{}' cannot be read after waiting {:?}: {}",
+                    var, path, timeout, e
+                ),
+            ));
+        }
+        let wait = pause.min(deadline - now);
+        let names: Vec<String> = missing
+            .iter()
+            .map(|(var, path, e)| format!("{} '{}' ({})", var, path, e))
+            .collect();
+        info!("Waiting {}ms for {}", wait.as_millis(), names.join(", "));
+        thread::sleep(wait);
+        pause = (pause * 2).min(MAX_FILE_POLL);
+    }
+}
+
+/// Waits `STARTUP_WAIT_FOR_FILES_SECS` at most for the [`startup_files`];
+/// returns right away when unset or 0.
+///
+/// # Errors
+///
+/// Returns an IoError if the variable is not a number of seconds or a file
+/// is still missing once it has passed.
+pub fn wait_for_files_from_env() -> Result<(), IoError> {
+    let secs = match env::var("STARTUP_WAIT_FOR_FILES_SECS") {
+        Ok(value) if !value.is_empty() => value.parse::<u64>().map_err(|_| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "STARTUP_WAIT_FOR_FILES_SECS must be a number of seconds, got '{}'",
+                    value
+                ),
+            )
+        })?,
+        _ => 0,
+    };
+    if secs == 0 {
+        return Ok(());
+    }
+    wait_for_files(&startup_files(), Duration::from_secs(secs))
+}
@@ -0,0 +1,236 @@
+//! Static files served from `STATIC_DIR` under `/static/`.
+//!
+//! Build tools can emit compressed copies of each asset next to it
+//! (`app.js.br`, `app.js.gz`). When the client's `Accept-Encoding` allows
+//! it and such a sibling exists, it is served as is with the matching
+//! `Content-Encoding`, so static content costs no CPU to compress. Brotli
+//! is preferred over gzip at equal quality. Files without a usable
+//! precompressed variant are compressed on the fly by the scope's
+//! [`Compress`] middleware.
+//!
+//! Paths with a segment starting with `.` (including `..`) are refused, so
+//! neither hidden files nor anything outside the directory is reachable.
+//! Files are cached for a day and carry `Vary: Accept-Encoding`.
+
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Compress;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::info;
+
+use crate::error::ApiError;
+use crate::route_meta::{DescribedRoute, RouteMetadata, ScopeMetadata};
+use crate::routes::ONE_DAY;
+
+/// Cache lifetime of the static files.
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// Precompressed variants as `(coding, file suffix)`, most preferred first.
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+/// The directory static files are served from.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    /// Serves the files under `root`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `root` is not a directory.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, IoError> {
+        let root = root.into();
+        if !root.is_dir() {
+            return Err(IoError::new(
+                ErrorKind::NotFound,
+                format!("STATIC_DIR '{}' is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles { root })
+    }
+
+    /// Reads `STATIC_DIR`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<StaticFiles>, IoError>` - The directory, `None` if unset, or an IoError if it is not a directory.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        match env::var("STATIC_DIR").ok().filter(|d| !d.is_empty()) {
+            Some(dir) => {
+                let files = StaticFiles::new(&dir)?;
+                info!("Serving /static/ from {}", dir);
+                Ok(Some(files))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Maps the request path below `/static/` to a file under the root, or
+    /// `None` if a segment is hidden or a parent reference.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            if segment.starts_with('.') || segment.contains('\\') {
+                return None;
+            }
+            file.push(segment);
+        }
+        Some(file)
+    }
+}
+
+/// Returns the precompressed variants `accept_encoding` allows, as
+/// `(coding, file suffix)`, best first.
+pub fn accepted_variants(accept_encoding: Option<&str>) -> Vec<(&'static str, &'static str)> {
+    let mut qualities: Vec<(&str, f32)> = Vec::new();
+    for entry in accept_encoding.unwrap_or_default().split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = parts
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, value)| value.trim().parse::<f32>().ok());
+        if let Some(q) = q {
+            qualities.push((coding, q));
+        }
+    }
+    let quality = |coding: &str| {
+        qualities
+            .iter()
+            .find(|(c, _)| c.eq_ignore_ascii_case(coding))
+            .or_else(|| qualities.iter().find(|(c, _)| *c == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+    let mut variants: Vec<(&'static str, &'static str, f32)> = PRECOMPRESSED
+        .iter()
+        .map(|&(coding, suffix)| (coding, suffix, quality(coding)))
+        .filter(|(_, _, q)| *q > 0.0)
+        .collect();
+    // Stable, so equal qualities keep the PRECOMPRESSED order
+    variants.sort_by(|a, b| b.2.total_cmp(&a.2));
+    variants
+        .into_iter()
+        .map(|(coding, suffix, _)| (coding, suffix))
+        .collect()
+}
+
+/// Returns the content type of `path` from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "application/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Reads `file`, or its first precompressed sibling among `variants`.
+fn read(
+    file: &Path,
+    variants: &[(&'static str, &'static str)],
+) -> Option<(Vec<u8>, Option<&'static str>)> {
+    if !file.is_file() {
+        return None;
+    }
+    for &(coding, suffix) in variants {
+        let mut sibling = file.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(suffix);
+        let sibling = PathBuf::from(sibling);
+        if sibling.is_file() {
+            if let Ok(bytes) = fs::read(&sibling) {
+                return Some((bytes, Some(coding)));
+            }
+        }
+    }
+    fs::read(file).ok().map(|bytes| (bytes, None))
+}
+
+/// Handler for `GET /static/{path}`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, ApiError>` - 200 OK with the file or its precompressed variant, or a 404 ApiError.
+pub async fn serve(
+    req: HttpRequest,
+    files: web::Data<StaticFiles>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let not_found = || {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("No static file at /static/{}", path.as_str()),
+        )
+    };
+    let file = files.resolve(&path).ok_or_else(not_found)?;
+    let variants = accepted_variants(
+        req.headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok()),
+    );
+    let lookup = file.clone();
+    let (bytes, coding) = web::block(move || read(&lookup, &variants))
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(not_found)?;
+
+    let mut res = HttpResponse::Ok();
+    res.insert_header((header::CACHE_CONTROL, CACHE_CONTROL))
+        .insert_header((header::VARY, HeaderValue::from_static("Accept-Encoding")))
+        .content_type(content_type(&file));
+    if let Some(coding) = coding {
+        // Compress leaves responses that already have an encoding alone
+        res.insert_header((header::CONTENT_ENCODING, coding));
+    }
+    Ok(res.body(bytes))
+}
+
+/// Registers `GET /static/{path}`, compressing files on the fly when no
+/// precompressed variant applies.
+pub fn configure(files: web::Data<StaticFiles>) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg.service(
+            web::scope("/static")
+                .app_data(files)
+                .wrap(Compress::default())
+                .configure(
+                    ScopeMetadata::new("/static")
+                        .middleware("Compress")
+                        .configure(|cfg| {
+                            cfg.described_route(
+                                Method::GET,
+                                "/{path:.*}",
+                                serve,
+                                RouteMetadata::new("Static file, precompressed when available")
+                                    .cache_ttl(ONE_DAY),
+                            );
+                        }),
+                ),
+        );
+    }
+}
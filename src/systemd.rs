@@ -0,0 +1,81 @@
+//! systemd socket activation support.
+//!
+//! When the server is started by a systemd `.socket` unit, the listening
+//! sockets are passed as already-open file descriptors starting at fd 3, and
+//! the `LISTEN_PID` / `LISTEN_FDS` environment variables describe them (see
+//! `sd_listen_fds(3)`). This module detects that situation so the server can
+//! serve on the inherited sockets instead of binding new ones.
+
+use log::{info, warn};
+use std::env;
+use std::net::TcpListener;
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+pub const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Computes how many file descriptors systemd passed to this process.
+///
+/// The descriptors only belong to us if `LISTEN_PID` matches our own PID;
+/// otherwise they were meant for a parent process and must be ignored.
+///
+/// # Returns
+///
+/// * `usize` - The number of inherited descriptors, or 0 if not socket-activated.
+pub fn listen_fds_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    let listen_pid = match listen_pid.and_then(|v| v.trim().parse::<u32>().ok()) {
+        Some(listen_pid) => listen_pid,
+        None => return 0,
+    };
+    if listen_pid != pid {
+        return 0;
+    }
+
+    listen_fds
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0)
+}
+
+/// Takes ownership of the listening sockets passed by systemd, if any.
+///
+/// The `LISTEN_*` variables are removed from the environment afterwards so
+/// that child processes do not try to reuse the same descriptors.
+///
+/// # Returns
+///
+/// * `Vec<TcpListener>` - The inherited listeners; empty when not socket-activated.
+#[cfg(unix)]
+pub fn take_listeners() -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let count = listen_fds_count(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let mut listeners = Vec::with_capacity(count);
+    for offset in 0..count {
+        let fd = SD_LISTEN_FDS_START + offset as i32;
+        // SAFETY: systemd guarantees these descriptors are open and owned by
+        // this process once LISTEN_PID matches our PID.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.set_nonblocking(true) {
+            Ok(()) => {
+                info!("Using systemd socket-activated listener on fd {}", fd);
+                listeners.push(listener);
+            }
+            Err(e) => warn!("Ignoring systemd file descriptor {}: {}", fd, e),
+        }
+    }
+    listeners
+}
+
+/// Socket activation is only supported on Unix platforms.
+#[cfg(not(unix))]
+pub fn take_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
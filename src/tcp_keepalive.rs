@@ -0,0 +1,194 @@
+//! TCP keepalive probes on accepted connections.
+//!
+//! A client that vanishes without FIN or RST (a laptop closing its lid, a
+//! NAT or stateful firewall dropping its mapping) leaves an idle connection
+//! the server would otherwise hold forever. With `TCP_KEEPALIVE_SECS` set,
+//! the kernel sends a probe after that many idle seconds, repeats it every
+//! `TCP_KEEPALIVE_INTERVAL_SECS`, and resets the connection after
+//! `TCP_KEEPALIVE_PROBES` unanswered probes.
+//!
+//! The options are set on the listening sockets, which the server binds
+//! itself for this, and are inherited by every accepted connection. How
+//! much of them applies depends on the platform:
+//!
+//! * Linux, Android, FreeBSD and NetBSD: idle time, interval and probe count
+//!   (`TCP_KEEPIDLE`, `TCP_KEEPINTVL`, `TCP_KEEPCNT`).
+//! * macOS and iOS: the same, the idle time being `TCP_KEEPALIVE`.
+//! * Windows: idle time and interval (`SIO_KEEPALIVE_VALS`); the probe
+//!   count is fixed by the system (10).
+//! * Other platforms: keepalive is only switched on; timings follow the
+//!   system-wide settings.
+
+use std::env;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+/// Default for `TCP_KEEPALIVE_INTERVAL_SECS`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+/// Default for `TCP_KEEPALIVE_PROBES`.
+pub const DEFAULT_PROBES: u32 = 4;
+/// Pending connection queue of the listeners, as used by Actix.
+const BACKLOG: i32 = 2048;
+
+/// Keepalive timings applied to accepted connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpKeepaliveSettings {
+    /// Idle time before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes before the connection is reset.
+    pub probes: u32,
+}
+
+impl TcpKeepaliveSettings {
+    /// Parses the settings; `None` when `idle_secs` is unset or zero.
+    /// Invalid or zero interval and probe values fall back to the defaults.
+    pub fn parse(
+        idle_secs: Option<&str>,
+        interval_secs: Option<&str>,
+        probes: Option<&str>,
+    ) -> Option<Self> {
+        let idle = idle_secs
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&secs| secs > 0)?;
+        Some(TcpKeepaliveSettings {
+            idle: Duration::from_secs(idle),
+            interval: interval_secs
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|&secs| secs > 0)
+                .map_or(DEFAULT_INTERVAL, Duration::from_secs),
+            probes: probes
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(DEFAULT_PROBES),
+        })
+    }
+
+    /// Reads `TCP_KEEPALIVE_SECS`, `TCP_KEEPALIVE_INTERVAL_SECS` and
+    /// `TCP_KEEPALIVE_PROBES`.
+    pub fn from_env() -> Option<Self> {
+        Self::parse(
+            env::var("TCP_KEEPALIVE_SECS").ok().as_deref(),
+            env::var("TCP_KEEPALIVE_INTERVAL_SECS").ok().as_deref(),
+            env::var("TCP_KEEPALIVE_PROBES").ok().as_deref(),
+        )
+    }
+
+    /// Describes the settings as applied on this platform.
+    pub fn describe(&self) -> String {
+        if cfg!(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "macos",
+            target_os = "ios"
+        )) {
+            format!(
+                "first probe after {}s idle, then every {}s, reset after {} unanswered probes",
+                self.idle.as_secs(),
+                self.interval.as_secs(),
+                self.probes
+            )
+        } else if cfg!(windows) {
+            format!(
+                "first probe after {}s idle, then every {}s; the probe count is fixed by Windows",
+                self.idle.as_secs(),
+                self.interval.as_secs()
+            )
+        } else {
+            "enabled with the system-wide timings; this platform ignores the configured values"
+                .to_string()
+        }
+    }
+
+    fn keepalive(&self) -> TcpKeepalive {
+        let keepalive = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "macos",
+            target_os = "ios",
+            windows
+        ))]
+        let keepalive = keepalive.with_interval(self.interval);
+        with_retries(keepalive, self.probes)
+    }
+
+    /// Enables keepalive on `listener`, for the connections it accepts from
+    /// now on. Used for listeners the server did not bind itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the options cannot be set.
+    pub fn apply(&self, listener: &TcpListener) -> Result<(), IoError> {
+        SockRef::from(listener).set_tcp_keepalive(&self.keepalive())
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn with_retries(keepalive: TcpKeepalive, probes: u32) -> TcpKeepalive {
+    keepalive.with_retries(probes)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn with_retries(keepalive: TcpKeepalive, _probes: u32) -> TcpKeepalive {
+    keepalive
+}
+
+/// Binds a listener, with `keepalive` enabled, on every address `address`
+/// resolves to, the way Actix's own `bind` does.
+///
+/// # Errors
+///
+/// Returns the last IoError if no address could be bound.
+pub fn bind(address: &str, keepalive: &TcpKeepaliveSettings) -> Result<Vec<TcpListener>, IoError> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for addr in address.to_socket_addrs()? {
+        match bind_one(addr, keepalive) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(last_error.unwrap_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                format!("{} does not resolve to an address", address),
+            )
+        }));
+    }
+    Ok(listeners)
+}
+
+fn bind_one(addr: SocketAddr, keepalive: &TcpKeepaliveSettings) -> Result<TcpListener, IoError> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_tcp_keepalive(&keepalive.keepalive())?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
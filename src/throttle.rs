@@ -0,0 +1,381 @@
+//! Responses to rate-limited and load-shed requests.
+//!
+//! Requests over a rate limit get 429 and requests shed under load get 503.
+//! By default both carry the usual JSON error body. Operators can replace
+//! the body of either with a file, e.g. a branded HTML page or a JSON error
+//! naming a support contact, and add headers to both:
+//!
+//! * `RATE_LIMITED_RESPONSE_FILE`: body of 429 responses
+//! * `LOAD_SHED_RESPONSE_FILE`: body of 503 load-shedding responses
+//! * `THROTTLE_RESPONSE_HEADERS`: comma-separated `Name:Value` pairs
+//!
+//! The content type follows the file extension (`.html`, `.json`, else
+//! plain text). `{retry_after}` and `{request_id}` in a file are replaced
+//! with the seconds to wait and the request's ID, so a page can quote a
+//! support reference. `Retry-After` is always sent, and overrides a
+//! configured header of the same name.
+//!
+//! The `Retry-After` of load-shed responses is estimated by a
+//! [`RetryAfterEstimator`] from the shedding so far, rather than fixed: once
+//! the shed rate falls it is extrapolated to when it reaches zero, otherwise
+//! the typical length of past shedding episodes, less the time this one has
+//! lasted, is used; an episode longer than usual is expected to last about as
+//! long again. Until there is an estimate, `RETRY_AFTER_DEFAULT_SECS` is sent,
+//! or the shedding middleware's own default. Estimates are capped at
+//! `RETRY_AFTER_MAX_SECS`.
+//!
+//! Register [`ThrottleResponses`] with `App::app_data`; without it the JSON
+//! defaults are used.
+
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use log::info;
+
+use crate::error::ApiError;
+use crate::middleware::extra_headers::parse_extra_headers;
+use crate::middleware::request_id::RequestId;
+
+/// Why a request was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// Over a rate limit: 429.
+    RateLimited,
+    /// Shed to protect the server: 503.
+    LoadShed,
+}
+
+impl Throttle {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Throttle::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Throttle::LoadShed => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Syntax of a body template, from its file extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// Escapes a substituted value; request IDs may hold any visible ASCII.
+    fn escape(self, value: &str) -> String {
+        match self {
+            Format::Html => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&#39;"),
+            // Placeholders sit inside JSON strings
+            Format::Json => value.replace('\\', "\\\\").replace('"', "\\\""),
+            Format::Text => value.to_string(),
+        }
+    }
+}
+
+/// A body template read from a file.
+#[derive(Clone, Debug)]
+struct CustomBody {
+    format: Format,
+    template: String,
+}
+
+impl CustomBody {
+    fn load(var: &str, path: &str) -> Result<Self, IoError> {
+        let template = fs::read_to_string(path).map_err(|e| {
+            IoError::new(
+                e.kind(),
+                format!("{} '{}' cannot be read: {}", var, path, e),
+            )
+        })?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let format = match extension.as_deref() {
+            Some("html") | Some("htm") => Format::Html,
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        };
+        Ok(CustomBody { format, template })
+    }
+
+    fn render(&self, retry_after: u64, request_id: &str) -> String {
+        self.template
+            .replace("{retry_after}", &retry_after.to_string())
+            .replace("{request_id}", &self.format.escape(request_id))
+    }
+}
+
+/// Default for `RETRY_AFTER_MAX_SECS`.
+pub const DEFAULT_RETRY_AFTER_MAX: Duration = Duration::from_secs(60);
+
+/// Length of the windows sheds are counted over to tell whether the shed
+/// rate is falling.
+pub const SHED_WINDOW: Duration = Duration::from_secs(5);
+
+/// Time without a shed request that ends a shedding episode.
+pub const EPISODE_GAP: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Shedding {
+    /// Start of the current episode.
+    started: Option<Instant>,
+    last_shed: Option<Instant>,
+    /// Moving average of the length of past episodes.
+    typical: Option<Duration>,
+    window_start: Option<Instant>,
+    /// Sheds in the current window, the previous one and the one before.
+    current: u64,
+    previous: Option<u64>,
+    older: Option<u64>,
+}
+
+/// Estimates when load shedding will stop, for the `Retry-After` of
+/// load-shed responses.
+#[derive(Debug)]
+pub struct RetryAfterEstimator {
+    max: Duration,
+    shedding: Mutex<Shedding>,
+}
+
+impl Default for RetryAfterEstimator {
+    fn default() -> Self {
+        RetryAfterEstimator::new(DEFAULT_RETRY_AFTER_MAX)
+    }
+}
+
+impl RetryAfterEstimator {
+    /// Creates an estimator whose estimates are at most `max`.
+    pub fn new(max: Duration) -> Self {
+        RetryAfterEstimator {
+            max: max.max(Duration::from_secs(1)),
+            shedding: Mutex::new(Shedding::default()),
+        }
+    }
+
+    /// Records a shed request.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Duration>` - How long until capacity is expected back, or `None` while there is no basis for an estimate.
+    pub fn record_shed(&self) -> Option<Duration> {
+        self.record_shed_at(Instant::now())
+    }
+
+    /// Like [`record_shed`](Self::record_shed), at the given time.
+    pub fn record_shed_at(&self, now: Instant) -> Option<Duration> {
+        let mut shedding = self.shedding.lock().unwrap();
+        let started = match (shedding.started, shedding.last_shed) {
+            (Some(started), Some(last)) if now.saturating_duration_since(last) < EPISODE_GAP => {
+                started
+            }
+            (started, last) => {
+                // A new episode; the last one ended with its last shed
+                if let (Some(started), Some(last)) = (started, last) {
+                    let length = last.saturating_duration_since(started);
+                    shedding.typical = Some(match shedding.typical {
+                        Some(typical) => (typical + length) / 2,
+                        None => length,
+                    });
+                }
+                shedding.started = Some(now);
+                shedding.window_start = Some(now);
+                shedding.current = 0;
+                shedding.previous = None;
+                shedding.older = None;
+                now
+            }
+        };
+        let mut window_start = shedding.window_start.unwrap_or(now);
+        while now.saturating_duration_since(window_start) >= SHED_WINDOW {
+            shedding.older = shedding.previous;
+            shedding.previous = Some(shedding.current);
+            shedding.current = 0;
+            window_start += SHED_WINDOW;
+        }
+        shedding.window_start = Some(window_start);
+        shedding.current += 1;
+        shedding.last_shed = Some(now);
+
+        let elapsed = now.saturating_duration_since(started);
+        let estimate = match (shedding.older, shedding.previous, shedding.typical) {
+            // Extrapolate a falling shed rate to zero
+            (Some(older), Some(previous), _) if previous < older => {
+                SHED_WINDOW.mul_f64(previous as f64 / (older - previous) as f64)
+            }
+            (_, _, Some(typical)) if typical > elapsed => typical - elapsed,
+            _ if elapsed >= SHED_WINDOW => elapsed,
+            _ => return None,
+        };
+        Some(estimate.clamp(Duration::from_secs(1), self.max))
+    }
+}
+
+/// Configured bodies and headers of throttling responses.
+#[derive(Clone, Debug, Default)]
+pub struct ThrottleResponses {
+    rate_limited: Option<CustomBody>,
+    load_shed: Option<CustomBody>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    estimator: Arc<RetryAfterEstimator>,
+    default_retry_after: Option<Duration>,
+}
+
+impl ThrottleResponses {
+    /// Serves the file at `path` as the body of `kind` responses.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be read.
+    pub fn with_body_file(mut self, kind: Throttle, path: &str) -> Result<Self, IoError> {
+        let var = match kind {
+            Throttle::RateLimited => "RATE_LIMITED_RESPONSE_FILE",
+            Throttle::LoadShed => "LOAD_SHED_RESPONSE_FILE",
+        };
+        let body = Some(CustomBody::load(var, path)?);
+        match kind {
+            Throttle::RateLimited => self.rate_limited = body,
+            Throttle::LoadShed => self.load_shed = body,
+        }
+        Ok(self)
+    }
+
+    /// Adds `headers` to every throttling response.
+    pub fn with_headers(mut self, headers: Vec<(HeaderName, HeaderValue)>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sends `default` as the `Retry-After` of load-shed responses until
+    /// there is an estimate, instead of the shedding middleware's own, and
+    /// caps estimates at `max`.
+    pub fn with_retry_after(mut self, default: Option<Duration>, max: Duration) -> Self {
+        self.default_retry_after = default;
+        self.estimator = Arc::new(RetryAfterEstimator::new(max));
+        self
+    }
+
+    /// Reads `RATE_LIMITED_RESPONSE_FILE`, `LOAD_SHED_RESPONSE_FILE`,
+    /// `THROTTLE_RESPONSE_HEADERS`, `RETRY_AFTER_DEFAULT_SECS` and
+    /// `RETRY_AFTER_MAX_SECS`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ThrottleResponses, IoError>` - The configuration, or an IoError if a file cannot be read or a header or number is invalid.
+    pub fn from_env() -> Result<Self, IoError> {
+        let default_retry_after = secs_from_env("RETRY_AFTER_DEFAULT_SECS")?;
+        let max_retry_after =
+            secs_from_env("RETRY_AFTER_MAX_SECS")?.unwrap_or(DEFAULT_RETRY_AFTER_MAX);
+        let mut responses = ThrottleResponses::default()
+            .with_headers(parse_extra_headers(
+                &env::var("THROTTLE_RESPONSE_HEADERS").unwrap_or_default(),
+            )?)
+            .with_retry_after(default_retry_after, max_retry_after);
+        for (kind, var) in [
+            (Throttle::RateLimited, "RATE_LIMITED_RESPONSE_FILE"),
+            (Throttle::LoadShed, "LOAD_SHED_RESPONSE_FILE"),
+        ] {
+            if let Some(path) = env::var(var).ok().filter(|p| !p.is_empty()) {
+                responses = responses.with_body_file(kind, &path)?;
+                info!(
+                    "Serving {} as the body of {} responses",
+                    path,
+                    kind.status()
+                );
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Builds the response turning a request away, waiting `retry_after`
+    /// before retrying. `error` is the default body. Load-shed responses
+    /// are recorded, and `retry_after` is only their fallback.
+    pub fn respond(
+        &self,
+        kind: Throttle,
+        error: ApiError,
+        retry_after: Duration,
+        request_id: Option<&str>,
+    ) -> HttpResponse {
+        let retry_after = match kind {
+            Throttle::RateLimited => retry_after,
+            Throttle::LoadShed => self
+                .estimator
+                .record_shed()
+                .or(self.default_retry_after)
+                .unwrap_or(retry_after),
+        };
+        // Round up so clients never retry early
+        let secs = (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1);
+        let custom = match kind {
+            Throttle::RateLimited => self.rate_limited.as_ref(),
+            Throttle::LoadShed => self.load_shed.as_ref(),
+        };
+        let mut res = match custom {
+            Some(body) => HttpResponse::build(kind.status())
+                .content_type(body.format.content_type())
+                .body(body.render(secs, request_id.unwrap_or_default())),
+            None => error.error_response(),
+        };
+        let headers = res.headers_mut();
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), value.clone());
+        }
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        res
+    }
+}
+
+fn secs_from_env(name: &str) -> Result<Option<Duration>, IoError> {
+    match env::var(name) {
+        Ok(value) => match value.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Some(Duration::from_secs(secs))),
+            _ => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} must be a positive number of seconds, got '{}'",
+                    name, value
+                ),
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Builds the response turning `req` away with the app's
+/// [`ThrottleResponses`], or the JSON defaults without one.
+pub fn respond(
+    req: &HttpRequest,
+    kind: Throttle,
+    error: ApiError,
+    retry_after: Duration,
+) -> HttpResponse {
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    let request_id = request_id.as_ref().map(RequestId::as_str);
+    match req.app_data::<web::Data<ThrottleResponses>>() {
+        Some(responses) => responses.respond(kind, error, retry_after, request_id),
+        None => ThrottleResponses::default().respond(kind, error, retry_after, request_id),
+    }
+}
@@ -0,0 +1,184 @@
+//! The served certificate chain, for inspection without `openssl s_client`.
+//!
+//! Behind the admin API key:
+//!
+//! * `GET /tls/chain.pem` returns every certificate of the chain, leaf
+//!   first, as concatenated PEM.
+//! * `GET /tls/cert.der` returns the leaf certificate in DER.
+//! * `GET /tls/fingerprint` returns the SHA-256 fingerprint of the leaf, in
+//!   the colon-separated form printed by `openssl x509 -fingerprint`.
+//!
+//! Both files are sent as attachments so browsers download them. The chain
+//! is the one read from `CERT_FILE` at startup; the server does not reload
+//! its certificate, so it is the chain every connection is served.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, Error as IoError, ErrorKind};
+
+use actix_web::http::header::{self, ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::Method;
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+use rustls::Certificate;
+
+use crate::middleware::api_key::{AdminKey, ApiKeyAuth};
+use crate::route_meta::{DescribedRoute, RouteMetadata, ScopeMetadata};
+
+/// Content type of the PEM chain.
+pub const PEM_CONTENT_TYPE: &str = "application/x-pem-file";
+
+/// Content type of the DER leaf certificate.
+pub const DER_CONTENT_TYPE: &str = "application/pkix-cert";
+
+/// Characters of base64 per PEM line, as RFC 7468 requires.
+const PEM_LINE_LENGTH: usize = 64;
+
+/// The certificate chain the server presents, leaf first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsChain {
+    certificates: Vec<Certificate>,
+}
+
+impl TlsChain {
+    /// Creates a chain from certificates, leaf first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if `certificates` is empty.
+    pub fn new(certificates: Vec<Certificate>) -> Result<Self, IoError> {
+        if certificates.is_empty() {
+            return Err(IoError::new(ErrorKind::InvalidData, "no certificate found"));
+        }
+        Ok(TlsChain { certificates })
+    }
+
+    /// Reads the chain from the PEM file named by `CERT_FILE` (default
+    /// `cert.pem`), the file the TLS configuration was loaded from.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be read or holds no certificate.
+    pub fn from_env() -> Result<Self, IoError> {
+        let cert_path = env::var("CERT_FILE").unwrap_or_else(|_| "cert.pem".to_string());
+        let mut reader = BufReader::new(File::open(&cert_path)?);
+        let certs = rustls_pemfile::certs(&mut reader)?;
+        TlsChain::new(certs.into_iter().map(Certificate).collect())
+    }
+
+    /// The certificates, leaf first.
+    pub fn certificates(&self) -> &[Certificate] {
+        &self.certificates
+    }
+
+    /// The leaf certificate.
+    pub fn leaf(&self) -> &Certificate {
+        &self.certificates[0]
+    }
+}
+
+/// Encodes DER certificates as concatenated PEM `CERTIFICATE` blocks.
+pub fn pem_encode(certificates: &[Certificate]) -> String {
+    let mut pem = String::new();
+    for certificate in certificates {
+        pem.push_str("-----BEGIN CERTIFICATE-----\n");
+        let encoded = STANDARD.encode(&certificate.0);
+        let mut rest = encoded.as_str();
+        while !rest.is_empty() {
+            let (line, tail) = rest.split_at(rest.len().min(PEM_LINE_LENGTH));
+            pem.push_str(line);
+            pem.push('\n');
+            rest = tail;
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+    }
+    pem
+}
+
+/// The SHA-256 fingerprint of a DER certificate as colon-separated
+/// uppercase hex.
+pub fn fingerprint_sha256(certificate: &Certificate) -> String {
+    digest::digest(&digest::SHA256, &certificate.0)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn attachment(filename: &str) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename.to_string())],
+    }
+}
+
+/// Handler for `GET /tls/chain.pem`.
+pub async fn chain_pem(chain: web::Data<TlsChain>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(PEM_CONTENT_TYPE)
+        .insert_header(attachment("chain.pem"))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(pem_encode(chain.certificates()))
+}
+
+/// Handler for `GET /tls/cert.der`.
+pub async fn leaf_der(chain: web::Data<TlsChain>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(DER_CONTENT_TYPE)
+        .insert_header(attachment("cert.der"))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(chain.leaf().0.clone())
+}
+
+/// Handler for `GET /tls/fingerprint`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - `{"algorithm": "sha256", "fingerprint": "AB:CD:..."}` for the leaf certificate.
+pub async fn fingerprint(chain: web::Data<TlsChain>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "algorithm": "sha256",
+        "fingerprint": fingerprint_sha256(chain.leaf()),
+    }))
+}
+
+/// Registers the `/tls` scope, behind `admin_key`.
+pub fn configure(
+    chain: web::Data<TlsChain>,
+    admin_key: AdminKey,
+) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        let routes = ScopeMetadata::new("/tls")
+            .middleware("ApiKeyAuth")
+            .auth_required()
+            .configure(|cfg| {
+                cfg.app_data(chain)
+                    .described_route(
+                        Method::GET,
+                        "/chain.pem",
+                        chain_pem,
+                        RouteMetadata::new("Served certificate chain as PEM"),
+                    )
+                    .described_route(
+                        Method::GET,
+                        "/cert.der",
+                        leaf_der,
+                        RouteMetadata::new("Served leaf certificate as DER"),
+                    )
+                    .described_route(
+                        Method::GET,
+                        "/fingerprint",
+                        fingerprint,
+                        RouteMetadata::new("SHA-256 fingerprint of the served leaf certificate"),
+                    );
+            });
+        cfg.service(
+            web::scope("/tls")
+                .wrap(ApiKeyAuth::shared(admin_key))
+                .configure(routes),
+        );
+    }
+}
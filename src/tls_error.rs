@@ -0,0 +1,88 @@
+//! Why the server's TLS configuration could not be loaded.
+//!
+//! [`load_tls_config`](crate::load_tls_config) reports a
+//! [`TlsConfigError`], so callers can tell a missing file from a bad key
+//! without matching on messages. It converts into an `IoError` carrying
+//! the same message, so `?` keeps working in functions returning
+//! `IoError`, and the original error can be recovered with
+//! `IoError::get_ref` and `downcast_ref`.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+
+/// A failure to load the certificate, the private key or the client CAs.
+#[derive(Debug)]
+pub enum TlsConfigError {
+    /// The certificate or key file cannot be opened.
+    FileNotFound { path: String, source: IoError },
+    /// The certificate file is not valid PEM or holds no certificate.
+    CertParse { path: String, reason: String },
+    /// The key file is not valid PEM.
+    KeyParse { path: String, reason: String },
+    /// The key file holds no PKCS#8 private key.
+    NoKeys { path: String },
+    /// The private key is not the one of the leaf certificate, or the leaf
+    /// is missing or out of place in the chain.
+    KeyCertMismatch { reason: String },
+    /// The client CA file (`CLIENT_CA_FILE`) cannot be loaded, or mTLS is
+    /// misconfigured.
+    ClientAuth(IoError),
+    /// rustls rejected the certificate and key.
+    BuildFailed { reason: String },
+    /// `TLS_FIPS_MODE` is set in a build without the `fips` feature.
+    FipsNotSupported,
+}
+
+impl TlsConfigError {
+    /// Returns the `ErrorKind` of the `IoError` this converts into.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            TlsConfigError::FileNotFound { source, .. } => source.kind(),
+            TlsConfigError::ClientAuth(source) => source.kind(),
+            TlsConfigError::FipsNotSupported => ErrorKind::Unsupported,
+            _ => ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for TlsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsConfigError::FileNotFound { path, source } => {
+                write!(f, "cannot open '{}': {}", path, source)
+            }
+            TlsConfigError::CertParse { path, reason } => {
+                write!(f, "invalid certificate in '{}': {}", path, reason)
+            }
+            TlsConfigError::KeyParse { path, reason } => {
+                write!(f, "invalid private key in '{}': {}", path, reason)
+            }
+            TlsConfigError::NoKeys { path } => write!(f, "no private keys found in '{}'", path),
+            TlsConfigError::KeyCertMismatch { reason } => f.write_str(reason),
+            TlsConfigError::ClientAuth(source) => fmt::Display::fmt(source, f),
+            TlsConfigError::BuildFailed { reason } => {
+                write!(f, "cannot build the TLS configuration: {}", reason)
+            }
+            TlsConfigError::FipsNotSupported => {
+                f.write_str("TLS_FIPS_MODE requires a build with the `fips` feature")
+            }
+        }
+    }
+}
+
+impl StdError for TlsConfigError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            TlsConfigError::FileNotFound { source, .. } => Some(source),
+            TlsConfigError::ClientAuth(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<TlsConfigError> for IoError {
+    fn from(e: TlsConfigError) -> Self {
+        IoError::new(e.kind(), e)
+    }
+}
@@ -0,0 +1,173 @@
+//! Per-connection TLS details made available to request handlers.
+//!
+//! Actix-web hands the raw connection to [`on_connect`] once the TLS
+//! handshake has completed. The details captured there are stored as
+//! connection data and can be read by middleware through
+//! `HttpRequest::conn_data::<TlsInfo>()`.
+//!
+//! With `TLS_DEBUG=true`, a [`HandshakeLogger`] also logs the SNI name and
+//! served certificate of every connection (not every request) to the
+//! `tls_debug` log target.
+
+use std::any::Any;
+use std::env;
+use std::fs::File;
+use std::future::{ready, Ready};
+use std::io::{BufReader, Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+
+use actix_tls::accept::rustls_0_20::TlsStream;
+use actix_web::dev::{Extensions, Payload};
+use actix_web::rt::net::TcpStream;
+use actix_web::{error, Error, FromRequest, HttpMessage, HttpRequest};
+use log::info;
+use rustls::Certificate;
+
+use crate::proxy_protocol::ProxyInfo;
+
+/// TLS details captured once per connection.
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+    /// Certificate chain presented by the client, leaf first.
+    pub peer_certificates: Option<Vec<Certificate>>,
+    /// Server name requested through SNI, if any.
+    pub sni_hostname: Option<String>,
+}
+
+/// Connection callback for `HttpServer::on_connect`.
+///
+/// Stores a [`TlsInfo`] in the connection data for TLS connections; plain
+/// TCP connections are left untouched.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = tls.get_ref();
+        data.insert(TlsInfo {
+            peer_certificates: session
+                .peer_certificates()
+                .filter(|certs| !certs.is_empty())
+                .map(|certs| certs.to_vec()),
+            sni_hostname: session.sni_hostname().map(str::to_string),
+        });
+    }
+}
+
+/// Logs the TLS handshake of each connection, for checking which server
+/// names clients ask for and which certificate answers them.
+#[derive(Clone, Debug)]
+pub struct HandshakeLogger {
+    certificate: String,
+}
+
+impl HandshakeLogger {
+    /// Creates a logger reporting `certificate` as the served certificate.
+    pub fn new(certificate: impl Into<String>) -> Self {
+        HandshakeLogger {
+            certificate: certificate.into(),
+        }
+    }
+
+    /// Creates a logger when `TLS_DEBUG` is `true`, describing the leaf
+    /// certificate in `CERT_FILE`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<HandshakeLogger>, IoError>` - The logger, `None` if TLS_DEBUG is off, or an IoError if the certificate cannot be read.
+    pub fn from_env() -> Result<Option<Self>, IoError> {
+        if !env::var("TLS_DEBUG").map(|v| v == "true").unwrap_or(false) {
+            return Ok(None);
+        }
+        let cert_path = env::var("CERT_FILE").unwrap_or_else(|_| "cert.pem".to_string());
+        let mut reader = BufReader::new(File::open(&cert_path)?);
+        let certs = rustls_pemfile::certs(&mut reader)?;
+        let leaf = certs
+            .first()
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidData, "no certificate found"))?;
+        Ok(Some(HandshakeLogger::new(describe_certificate(leaf)?)))
+    }
+
+    /// Formats the log line for one handshake. Clients sending no SNI
+    /// extension are reported as `no-sni`.
+    pub fn describe(
+        &self,
+        peer: Option<SocketAddr>,
+        sni: Option<&str>,
+        protocol: Option<&str>,
+    ) -> String {
+        format!(
+            "TLS handshake from {}: sni={} certificate=\"{}\" protocol={}",
+            peer.map_or_else(|| "unknown".to_string(), |p| p.to_string()),
+            sni.unwrap_or("no-sni"),
+            self.certificate,
+            protocol.unwrap_or("unknown"),
+        )
+    }
+
+    /// Connection callback logging the handshake. Runs after
+    /// [`ProxyProtocolAcceptor::on_connect`](crate::proxy_protocol::ProxyProtocolAcceptor::on_connect)
+    /// so the original client address is reported behind a load balancer.
+    pub fn on_connect(&self, connection: &dyn Any, data: &Extensions) {
+        let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+            return;
+        };
+        let (tcp, session) = tls.get_ref();
+        let peer = data
+            .get::<ProxyInfo>()
+            .map(|info| info.src_addr)
+            .or_else(|| tcp.peer_addr().ok());
+        let protocol = session.protocol_version().map(|v| format!("{:?}", v));
+        info!(
+            target: "tls_debug",
+            "{}",
+            self.describe(peer, session.sni_hostname(), protocol.as_deref())
+        );
+    }
+}
+
+/// Describes a DER certificate by subject and serial number.
+///
+/// # Errors
+///
+/// Returns an IoError if the certificate cannot be parsed.
+pub fn describe_certificate(der: &[u8]) -> Result<String, IoError> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| IoError::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(format!(
+        "{} (serial {})",
+        cert.subject(),
+        cert.raw_serial_as_string()
+    ))
+}
+
+/// Extractor for the certificate chain presented by an mTLS client.
+///
+/// The chain is placed in the request extensions by the
+/// [`ClientCertificates`](crate::middleware::mtls::ClientCertificates)
+/// middleware. Extraction fails with `400 Bad Request` when no certificate
+/// was presented.
+#[derive(Clone, Debug)]
+pub struct PeerCertificate(pub Vec<Certificate>);
+
+impl PeerCertificate {
+    /// Returns the client's own (end-entity) certificate.
+    pub fn leaf(&self) -> &Certificate {
+        &self.0[0]
+    }
+
+    /// Returns the full chain presented by the client, leaf first.
+    pub fn chain(&self) -> &[Certificate] {
+        &self.0
+    }
+}
+
+impl FromRequest for PeerCertificate {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let result = match req.extensions().get::<PeerCertificate>() {
+            Some(cert) if !cert.0.is_empty() => Ok(cert.clone()),
+            _ => Err(error::ErrorBadRequest("Client certificate required")),
+        };
+        ready(result)
+    }
+}
@@ -0,0 +1,136 @@
+//! TLS key logging for debugging with Wireshark.
+//!
+//! With `DANGEROUS_TLS_KEYLOG=1`, the secrets of every TLS session are
+//! appended to the file named by `SSLKEYLOGFILE`, in the NSS key log format
+//! Wireshark and other tools read to decrypt captured traffic. Anyone who
+//! can read that file can decrypt every request and response of the logged
+//! sessions, credentials and cookies included, long after the fact. It is
+//! meant for diagnosing handshake and cipher issues on a development or
+//! staging instance only.
+//!
+//! Key logging cannot be enabled under `APP_ENV=prod` (or `production`):
+//! startup fails instead. `SSLKEYLOGFILE` alone does nothing, unlike in
+//! browsers and curl, so an environment variable leaking into a production
+//! container cannot turn it on.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+
+use log::{error, warn};
+use rustls::KeyLog;
+
+/// Environment variable that must be `1` to log keys.
+pub const KEYLOG_FLAG: &str = "DANGEROUS_TLS_KEYLOG";
+
+/// Environment variable naming the key log file.
+pub const KEYLOG_FILE: &str = "SSLKEYLOGFILE";
+
+/// Returns whether `APP_ENV` names a production environment.
+pub fn is_production() -> bool {
+    env::var("APP_ENV").is_ok_and(|app_env| {
+        let app_env = app_env.trim();
+        app_env.eq_ignore_ascii_case("prod") || app_env.eq_ignore_ascii_case("production")
+    })
+}
+
+/// Appends TLS session secrets to a file in the NSS key log format.
+pub struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// Opens `path` for appending, creating it readable by the owner only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an IoError if the file cannot be opened.
+    pub fn open(path: &str) -> Result<Self, IoError> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        Ok(KeyLogFile {
+            file: Mutex::new(options.open(path)?),
+        })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line =
+            String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+        line.push_str(label);
+        line.push(' ');
+        for byte in client_random {
+            let _ = write!(line, "{:02x}", byte);
+        }
+        line.push(' ');
+        for byte in secret {
+            let _ = write!(line, "{:02x}", byte);
+        }
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write the TLS key log: {}", e);
+        }
+    }
+}
+
+/// Opens the key log named by `SSLKEYLOGFILE` when `DANGEROUS_TLS_KEYLOG=1`,
+/// for `ServerConfig::key_log`; `None` otherwise.
+///
+/// # Errors
+///
+/// Returns an IoError under `APP_ENV=prod`, without `SSLKEYLOGFILE` or if
+/// the file cannot be opened.
+pub fn from_env() -> Result<Option<Arc<dyn KeyLog>>, IoError> {
+    let path = env::var(KEYLOG_FILE).ok().filter(|p| !p.is_empty());
+    if env::var(KEYLOG_FLAG).as_deref() != Ok("1") {
+        if path.is_some() {
+            warn!(
+                "{} is set but ignored; TLS keys are only logged with {}=1",
+                KEYLOG_FILE, KEYLOG_FLAG
+            );
+        }
+        return Ok(None);
+    }
+    if is_production() {
+        error!(
+            "{}=1 is refused under APP_ENV=prod: it would let anyone with the key log decrypt all traffic",
+            KEYLOG_FLAG
+        );
+        return Err(IoError::new(
+            ErrorKind::PermissionDenied,
+            format!("{} cannot be enabled under APP_ENV=prod", KEYLOG_FLAG),
+        ));
+    }
+    let Some(path) = path else {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("{}=1 requires {}", KEYLOG_FLAG, KEYLOG_FILE),
+        ));
+    };
+    let key_log = KeyLogFile::open(&path).map_err(|e| {
+        IoError::new(
+            e.kind(),
+            format!("cannot open {} '{}': {}", KEYLOG_FILE, path, e),
+        )
+    })?;
+    for line in [
+        "************************************************************",
+        "DANGER: TLS session keys are being written to a key log file.",
+        "Anyone who can read it can decrypt ALL traffic of this server,",
+        "including credentials. Never enable this in production.",
+        "************************************************************",
+    ] {
+        warn!("{}", line);
+    }
+    warn!("TLS key log: {}", path);
+    Ok(Some(Arc::new(key_log)))
+}
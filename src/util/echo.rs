@@ -0,0 +1,36 @@
+//! Responses that reflect client data.
+//!
+//! A body containing client input must never be content-sniffed: a browser
+//! guessing `text/html` from an echoed `<script>` turns reflection into
+//! cross-site scripting. These helpers always set an explicit
+//! `Content-Type` and `X-Content-Type-Options: nosniff`, whatever the body
+//! looks like.
+
+use actix_web::http::header::{CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use serde::Serialize;
+
+/// `Content-Type` of plain text echoes.
+pub const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+/// Value of `X-Content-Type-Options`.
+pub const NOSNIFF: &str = "nosniff";
+
+fn builder(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header((X_CONTENT_TYPE_OPTIONS, NOSNIFF));
+    builder
+}
+
+/// Returns `body` as plain text that browsers will not sniff.
+pub fn safe_text(status: StatusCode, body: impl Into<String>) -> HttpResponse {
+    builder(status)
+        .insert_header((CONTENT_TYPE, TEXT_PLAIN))
+        .body(body.into())
+}
+
+/// Returns `value` as JSON that browsers will not sniff.
+pub fn safe_json<T: Serialize>(status: StatusCode, value: &T) -> HttpResponse {
+    builder(status).json(value)
+}
@@ -0,0 +1,494 @@
+//! CSV and NDJSON exports of list endpoints.
+//!
+//! A list endpoint asks [`Export::from_request`] whether the client wants
+//! an export: `?format=csv` or `?format=ndjson`, or else an `Accept` header
+//! preferring `text/csv` or `application/x-ndjson` over
+//! `application/json`. If so, [`Export::respond`] streams the rows through
+//! [`StreamBody`], encoding them a batch at a time, so an export of
+//! millions of rows never sits in memory, and names the download with
+//! `Content-Disposition`.
+//!
+//! Rows are any `Serialize` struct or map. CSV columns follow the fields of
+//! the first row; nested values are written as JSON. Fields holding the
+//! delimiter, quotes or line breaks are quoted. `?delimiter=` picks another
+//! single-character delimiter (e.g. `;` or `%09` for tabs) and `?bom=true`
+//! starts the file with a UTF-8 byte order mark, so Excel detects the
+//! encoding.
+
+use std::fmt::{self, Display};
+
+use actix_web::http::header::{
+    self, ContentDisposition, DispositionParam, DispositionType, HeaderValue,
+};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::Utc;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::ser::{self, Impossible, Serialize, SerializeMap, SerializeStruct, Serializer};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::ApiError;
+use crate::util::negotiate::negotiate;
+use crate::util::stream::StreamBody;
+
+/// Rows encoded per chunk of the response.
+const ROWS_PER_CHUNK: usize = 256;
+
+/// Media types an endpoint offers, the regular JSON response first.
+const OFFERED: &[&str] = &["application/json", "text/csv", "application/x-ndjson"];
+
+/// Export formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Export parameters of the query string; endpoints ignore them otherwise.
+#[derive(Deserialize)]
+struct ExportParams {
+    format: Option<String>,
+    delimiter: Option<String>,
+    bom: Option<bool>,
+}
+
+/// Options of a requested export.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Export {
+    pub format: ExportFormat,
+    /// CSV field delimiter.
+    pub delimiter: char,
+    /// Start CSV files with a UTF-8 byte order mark.
+    pub bom: bool,
+}
+
+impl Export {
+    /// A CSV export with the default options.
+    pub fn csv() -> Self {
+        Export {
+            format: ExportFormat::Csv,
+            delimiter: ',',
+            bom: false,
+        }
+    }
+
+    /// An NDJSON export.
+    pub fn ndjson() -> Self {
+        Export {
+            format: ExportFormat::Ndjson,
+            ..Export::csv()
+        }
+    }
+
+    /// Returns the export `req` asks for, or `None` for the regular JSON
+    /// response.
+    ///
+    /// # Errors
+    ///
+    /// Returns a 400 ApiError for an unknown `format`, a `delimiter` that
+    /// is not a single character other than a quote or line break, or a
+    /// malformed `bom`.
+    pub fn from_request(req: &HttpRequest) -> Result<Option<Self>, ApiError> {
+        let invalid = |field: &str, message: &str| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                "Invalid export parameters",
+            )
+            .with_field(field, message)
+        };
+        let params = web::Query::<ExportParams>::from_query(req.query_string())
+            .map_err(|_| invalid("bom", "must be true or false"))?
+            .into_inner();
+        let format = match params.format.as_deref() {
+            Some("json") => return Ok(None),
+            Some("csv") => ExportFormat::Csv,
+            Some("ndjson") => ExportFormat::Ndjson,
+            Some(_) => return Err(invalid("format", "must be json, csv or ndjson")),
+            None => {
+                let accept = req
+                    .headers()
+                    .get(header::ACCEPT)
+                    .and_then(|v| v.to_str().ok());
+                match negotiate(accept, OFFERED) {
+                    Some("text/csv") => ExportFormat::Csv,
+                    Some("application/x-ndjson") => ExportFormat::Ndjson,
+                    _ => return Ok(None),
+                }
+            }
+        };
+        let delimiter = match params.delimiter.as_deref() {
+            None => ',',
+            Some(delimiter) => {
+                let mut chars = delimiter.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if !matches!(c, '"' | '\r' | '\n') => c,
+                    _ => return Err(invalid("delimiter", "must be a single character")),
+                }
+            }
+        };
+        Ok(Some(Export {
+            format,
+            delimiter,
+            bom: params.bom.unwrap_or(false),
+        }))
+    }
+
+    /// Streams `rows` as an attachment named `{name}-{timestamp}.{ext}`.
+    ///
+    /// An error from `rows`, or a row that cannot be encoded, truncates the
+    /// download; see [`StreamBody`].
+    pub fn respond<S, T, E>(&self, name: &str, rows: S) -> HttpResponse
+    where
+        S: Stream<Item = Result<T, E>> + 'static,
+        T: Serialize + 'static,
+        E: Display + 'static,
+    {
+        let filename = format!(
+            "{}-{}.{}",
+            name,
+            Utc::now().format("%Y%m%dT%H%M%SZ"),
+            self.format.extension()
+        );
+        let mut encoder = Encoder::new(self.clone());
+        let body = rows
+            .ready_chunks(ROWS_PER_CHUNK)
+            .flat_map(move |batch| stream::iter(encoder.encode(batch)));
+        let mut res = HttpResponse::Ok();
+        res.content_type(self.format.content_type())
+            .insert_header(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename(filename)],
+            })
+            .insert_header((header::VARY, HeaderValue::from_static("Accept")));
+        StreamBody::new(body).into_response(res)
+    }
+
+    /// Like [`respond`](Self::respond), for rows already at hand.
+    pub fn respond_iter<I>(&self, name: &str, rows: I) -> HttpResponse
+    where
+        I: IntoIterator,
+        I::IntoIter: 'static,
+        I::Item: Serialize + 'static,
+    {
+        self.respond(
+            name,
+            stream::iter(rows.into_iter().map(Ok::<_, ExportError>)),
+        )
+    }
+}
+
+/// Encodes batches of rows, remembering the CSV columns.
+struct Encoder {
+    export: Export,
+    columns: Option<Vec<String>>,
+}
+
+impl Encoder {
+    fn new(export: Export) -> Self {
+        Encoder {
+            export,
+            columns: None,
+        }
+    }
+
+    /// Encodes `batch` into one chunk, followed by the first error, if any.
+    fn encode<T: Serialize, E: Display>(
+        &mut self,
+        batch: Vec<Result<T, E>>,
+    ) -> Vec<Result<Bytes, String>> {
+        let mut chunk = Vec::new();
+        let mut error = None;
+        for row in batch {
+            let encoded = match row {
+                Ok(row) => self.encode_row(&row, &mut chunk),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = encoded {
+                error = Some(e);
+                break;
+            }
+        }
+        let mut items = Vec::with_capacity(2);
+        if !chunk.is_empty() {
+            items.push(Ok(Bytes::from(chunk)));
+        }
+        if let Some(e) = error {
+            items.push(Err(e));
+        }
+        items
+    }
+
+    fn encode_row<T: Serialize>(&mut self, row: &T, out: &mut Vec<u8>) -> Result<(), String> {
+        if self.export.format == ExportFormat::Ndjson {
+            serde_json::to_writer(&mut *out, row).map_err(|e| e.to_string())?;
+            out.push(b'\n');
+            return Ok(());
+        }
+
+        let fields = row.serialize(RowSerializer).map_err(|e| e.to_string())?;
+        let delimiter = self.export.delimiter;
+        let columns = match self.columns.take() {
+            Some(columns) => columns,
+            None => {
+                if self.export.bom {
+                    out.extend_from_slice("\u{feff}".as_bytes());
+                }
+                let columns: Vec<String> = fields.iter().map(|(name, _)| name.clone()).collect();
+                let header: Vec<String> = columns.iter().map(|c| csv_field(c, delimiter)).collect();
+                write_record(out, &header, delimiter);
+                columns
+            }
+        };
+        let record: Vec<String> = columns
+            .iter()
+            .map(|column| {
+                let value = fields.iter().find(|(name, _)| name == column);
+                csv_field(&cell(value.map(|(_, v)| v)), delimiter)
+            })
+            .collect();
+        write_record(out, &record, delimiter);
+        self.columns = Some(columns);
+        Ok(())
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, fields: &[String], delimiter: char) {
+    let mut separator = [0; 4];
+    let separator = delimiter.encode_utf8(&mut separator).as_bytes();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.extend_from_slice(separator);
+        }
+        out.extend_from_slice(field.as_bytes());
+    }
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Text of a CSV cell: empty for missing and null values, nested values as
+/// JSON.
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Quotes `field` if it holds the delimiter, a quote or a line break,
+/// doubling its quotes (RFC 4180).
+pub fn csv_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Failure to encode a row, or a row source that cannot fail.
+#[derive(Debug)]
+pub struct ExportError(String);
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl ser::Error for ExportError {
+    fn custom<M: Display>(msg: M) -> Self {
+        ExportError(msg.to_string())
+    }
+}
+
+/// Splits a struct or map into its fields, in order; serde_json would sort
+/// them.
+struct RowSerializer;
+
+type Fields = Vec<(String, Value)>;
+
+fn not_a_row<T>() -> Result<T, ExportError> {
+    Err(ExportError("CSV rows must be structs or maps".to_string()))
+}
+
+macro_rules! reject {
+    ($($method:ident($($arg:ty),*)),* $(,)?) => {
+        $(fn $method(self, $(_: $arg),*) -> Result<Fields, ExportError> {
+            not_a_row()
+        })*
+    };
+}
+
+impl Serializer for RowSerializer {
+    type Ok = Fields;
+    type Error = ExportError;
+    type SerializeSeq = Impossible<Fields, ExportError>;
+    type SerializeTuple = Impossible<Fields, ExportError>;
+    type SerializeTupleStruct = Impossible<Fields, ExportError>;
+    type SerializeTupleVariant = Impossible<Fields, ExportError>;
+    type SerializeMap = MapRow;
+    type SerializeStruct = StructRow;
+    type SerializeStructVariant = Impossible<Fields, ExportError>;
+
+    reject!(
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+        serialize_none(),
+        serialize_unit(),
+        serialize_unit_struct(&'static str),
+        serialize_unit_variant(&'static str, u32, &'static str),
+    );
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Fields, ExportError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Fields, ExportError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Fields, ExportError> {
+        not_a_row()
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, ExportError> {
+        not_a_row()
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, ExportError> {
+        not_a_row()
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, ExportError> {
+        not_a_row()
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, ExportError> {
+        not_a_row()
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapRow, ExportError> {
+        Ok(MapRow {
+            fields: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<StructRow, ExportError> {
+        Ok(StructRow(Vec::with_capacity(len)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, ExportError> {
+        not_a_row()
+    }
+}
+
+fn to_value<T: ?Sized + Serialize>(value: &T) -> Result<Value, ExportError> {
+    serde_json::to_value(value).map_err(|e| ExportError(e.to_string()))
+}
+
+struct StructRow(Fields);
+
+impl SerializeStruct for StructRow {
+    type Ok = Fields;
+    type Error = ExportError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ExportError> {
+        self.0.push((key.to_string(), to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Fields, ExportError> {
+        Ok(self.0)
+    }
+}
+
+struct MapRow {
+    fields: Fields,
+    key: Option<String>,
+}
+
+impl SerializeMap for MapRow {
+    type Ok = Fields;
+    type Error = ExportError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), ExportError> {
+        match to_value(key)? {
+            Value::String(key) => self.key = Some(key),
+            other => self.key = Some(other.to_string()),
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), ExportError> {
+        let key = self.key.take().unwrap_or_default();
+        self.fields.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Fields, ExportError> {
+        Ok(self.fields)
+    }
+}
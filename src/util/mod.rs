@@ -0,0 +1,11 @@
+//! Reusable helpers for handlers.
+
+pub mod echo;
+pub mod export;
+pub mod multipart;
+pub mod negotiate;
+pub mod query;
+pub mod query_builder;
+pub mod rate_limit;
+pub mod real_ip;
+pub mod stream;
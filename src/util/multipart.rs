@@ -0,0 +1,504 @@
+//! `multipart/form-data` bodies with per-part limits.
+//!
+//! A total size limit alone does not protect the parser: a body of a few
+//! megabytes can hold 100k tiny parts, or headers and field values far
+//! larger than any form needs. The [`Multipart`] extractor parses the body
+//! as it streams in and checks every [`MultipartLimits`] limit as soon as
+//! it can be exceeded: the part count when a part starts, the size of a
+//! part's headers while they are read, the field name once they are parsed,
+//! and the size of non-file field values and of the whole body as data
+//! arrives. A violation stops reading at once: the request is answered
+//! with 413 or 400 naming the limit, the rest of the body is never read,
+//! and the connection is closed rather than drained. Rejections are counted
+//! in `multipart_rejections_total{limit}`.
+//!
+//! Handlers take [`Multipart`] as an argument; the limits come from a
+//! `MultipartLimits` registered with `App::app_data`, or the defaults.
+
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::http::{ConnectionType, StatusCode};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures_util::StreamExt;
+use log::warn;
+
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+
+/// Default for `MULTIPART_MAX_PARTS`.
+pub const DEFAULT_MAX_PARTS: usize = 100;
+
+/// Default for `MULTIPART_MAX_FIELD_NAME_LEN`.
+pub const DEFAULT_MAX_FIELD_NAME_LEN: usize = 100;
+
+/// Default for `MULTIPART_MAX_FIELD_BYTES`.
+pub const DEFAULT_MAX_FIELD_BYTES: usize = 64 * 1024;
+
+/// Default for `MULTIPART_MAX_PART_HEADER_BYTES`.
+pub const DEFAULT_MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+/// Default for `MULTIPART_MAX_BYTES`.
+pub const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+/// Limits on the parts of a `multipart/form-data` body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultipartLimits {
+    /// Most parts in one body.
+    pub max_parts: usize,
+    /// Longest field name, in bytes.
+    pub max_field_name_len: usize,
+    /// Largest value of a field that is not a file.
+    pub max_field_bytes: usize,
+    /// Largest header block of one part.
+    pub max_part_header_bytes: usize,
+    /// Largest body, files included.
+    pub max_bytes: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        MultipartLimits {
+            max_parts: DEFAULT_MAX_PARTS,
+            max_field_name_len: DEFAULT_MAX_FIELD_NAME_LEN,
+            max_field_bytes: DEFAULT_MAX_FIELD_BYTES,
+            max_part_header_bytes: DEFAULT_MAX_PART_HEADER_BYTES,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl MultipartLimits {
+    /// Reads `MULTIPART_MAX_PARTS`, `MULTIPART_MAX_FIELD_NAME_LEN`,
+    /// `MULTIPART_MAX_FIELD_BYTES`, `MULTIPART_MAX_PART_HEADER_BYTES` and
+    /// `MULTIPART_MAX_BYTES`; invalid values keep the default.
+    pub fn from_env() -> Self {
+        let defaults = MultipartLimits::default();
+        let read = |name: &str, default: usize| match env::var(name) {
+            Ok(value) => value.parse().unwrap_or_else(|_| {
+                warn!("Ignoring invalid {}: '{}'", name, value);
+                default
+            }),
+            Err(_) => default,
+        };
+        MultipartLimits {
+            max_parts: read("MULTIPART_MAX_PARTS", defaults.max_parts),
+            max_field_name_len: read("MULTIPART_MAX_FIELD_NAME_LEN", defaults.max_field_name_len),
+            max_field_bytes: read("MULTIPART_MAX_FIELD_BYTES", defaults.max_field_bytes),
+            max_part_header_bytes: read(
+                "MULTIPART_MAX_PART_HEADER_BYTES",
+                defaults.max_part_header_bytes,
+            ),
+            max_bytes: read("MULTIPART_MAX_BYTES", defaults.max_bytes),
+        }
+    }
+}
+
+/// Why a `multipart/form-data` body was rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MultipartViolation {
+    /// The request is not `multipart/form-data` with a boundary.
+    NotMultipart,
+    TooManyParts(usize),
+    FieldNameTooLong(usize),
+    /// A non-file field, by name, was larger than the limit.
+    FieldTooLarge(String, usize),
+    PartHeadersTooLarge(usize),
+    BodyTooLarge(usize),
+    /// The body does not follow the multipart syntax.
+    Malformed(&'static str),
+}
+
+impl MultipartViolation {
+    /// The violated limit, as reported to clients and in metrics.
+    pub fn limit(&self) -> &'static str {
+        match self {
+            MultipartViolation::NotMultipart => "content_type",
+            MultipartViolation::TooManyParts(_) => "max_parts",
+            MultipartViolation::FieldNameTooLong(_) => "max_field_name_len",
+            MultipartViolation::FieldTooLarge(..) => "max_field_bytes",
+            MultipartViolation::PartHeadersTooLarge(_) => "max_part_header_bytes",
+            MultipartViolation::BodyTooLarge(_) => "max_bytes",
+            MultipartViolation::Malformed(_) => "syntax",
+        }
+    }
+
+    /// The structured error sent to the client.
+    pub fn error(&self) -> ApiError {
+        let (status, code, message) = match self {
+            MultipartViolation::NotMultipart => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type",
+                "Expected multipart/form-data with a boundary".to_string(),
+            ),
+            MultipartViolation::TooManyParts(max) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "too_many_parts",
+                format!("A form may have at most {} parts", max),
+            ),
+            MultipartViolation::FieldNameTooLong(max) => (
+                StatusCode::BAD_REQUEST,
+                "field_name_too_long",
+                format!("Field names may be at most {} bytes", max),
+            ),
+            MultipartViolation::FieldTooLarge(name, max) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "field_too_large",
+                format!("Field '{}' is larger than {} bytes", name, max),
+            ),
+            MultipartViolation::PartHeadersTooLarge(max) => (
+                StatusCode::BAD_REQUEST,
+                "part_headers_too_large",
+                format!("Part headers may be at most {} bytes", max),
+            ),
+            MultipartViolation::BodyTooLarge(max) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                format!("The body may be at most {} bytes", max),
+            ),
+            MultipartViolation::Malformed(reason) => (
+                StatusCode::BAD_REQUEST,
+                "malformed_multipart",
+                format!("The multipart body is malformed: {}", reason),
+            ),
+        };
+        let error = ApiError::new(status, code, message.clone());
+        match self {
+            MultipartViolation::NotMultipart | MultipartViolation::Malformed(_) => error,
+            _ => error.with_field(self.limit(), message),
+        }
+    }
+}
+
+impl fmt::Display for MultipartViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error())
+    }
+}
+
+impl ResponseError for MultipartViolation {
+    fn status_code(&self) -> StatusCode {
+        self.error().status_code()
+    }
+
+    /// The structured error, closing the connection so the unread rest of
+    /// the body is not drained.
+    fn error_response(&self) -> HttpResponse {
+        let mut res = self.error().error_response();
+        res.head_mut().set_connection_type(ConnectionType::Close);
+        res
+    }
+}
+
+/// One part of a form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultipartField {
+    pub name: String,
+    /// The file name, for file parts.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Bytes,
+}
+
+enum State {
+    /// Before the first delimiter.
+    Preamble,
+    /// After a delimiter, before the line break or `--` ending the body.
+    Delimiter,
+    Headers,
+    Body(MultipartField, BytesMut),
+    End,
+}
+
+/// Incremental `multipart/form-data` parser, fed the body chunk by chunk.
+pub struct MultipartParser {
+    /// `--boundary`.
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    state: State,
+    buf: BytesMut,
+    received: usize,
+    fields: Vec<MultipartField>,
+}
+
+impl MultipartParser {
+    /// Creates a parser for parts separated by `boundary`.
+    pub fn new(boundary: &str, limits: MultipartLimits) -> Self {
+        MultipartParser {
+            delimiter: format!("--{}", boundary).into_bytes(),
+            limits,
+            state: State::Preamble,
+            buf: BytesMut::new(),
+            received: 0,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Parses `chunk`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first limit the body exceeds, or why it is malformed; the
+    /// parser must not be fed again afterwards.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), MultipartViolation> {
+        self.received += chunk.len();
+        if self.received > self.limits.max_bytes {
+            return Err(MultipartViolation::BodyTooLarge(self.limits.max_bytes));
+        }
+        if matches!(self.state, State::End) {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(chunk);
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Returns the parsed fields once the whole body was fed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MultipartViolation::Malformed`] if the body ended before its
+    /// closing delimiter.
+    pub fn finish(self) -> Result<Vec<MultipartField>, MultipartViolation> {
+        match self.state {
+            State::End => Ok(self.fields),
+            _ => Err(MultipartViolation::Malformed("the body ended early")),
+        }
+    }
+
+    /// Advances through the buffer; `false` once more data is needed.
+    fn step(&mut self) -> Result<bool, MultipartViolation> {
+        match std::mem::replace(&mut self.state, State::End) {
+            State::Preamble => match find(&self.buf, &self.delimiter) {
+                Some(at) => {
+                    let _ = self.buf.split_to(at + self.delimiter.len());
+                    self.state = State::Delimiter;
+                    Ok(true)
+                }
+                None => {
+                    // Keep what could be the start of the delimiter
+                    let keep = self.delimiter.len().min(self.buf.len());
+                    let _ = self.buf.split_to(self.buf.len() - keep);
+                    self.state = State::Preamble;
+                    Ok(false)
+                }
+            },
+            State::Delimiter => {
+                if self.buf.len() < 2 {
+                    self.state = State::Delimiter;
+                    return Ok(false);
+                }
+                match &self.buf[..2] {
+                    b"--" => {
+                        self.buf.clear();
+                        Ok(false)
+                    }
+                    b"\r\n" => {
+                        let _ = self.buf.split_to(2);
+                        if self.fields.len() >= self.limits.max_parts {
+                            return Err(MultipartViolation::TooManyParts(self.limits.max_parts));
+                        }
+                        self.state = State::Headers;
+                        Ok(true)
+                    }
+                    _ => Err(MultipartViolation::Malformed(
+                        "a delimiter is not followed by a line break",
+                    )),
+                }
+            }
+            State::Headers => match find(&self.buf, b"\r\n\r\n") {
+                Some(at) if at + 4 <= self.limits.max_part_header_bytes => {
+                    let block = self.buf.split_to(at + 4);
+                    let field = self.parse_headers(&block[..at])?;
+                    self.state = State::Body(field, BytesMut::new());
+                    Ok(true)
+                }
+                None if self.buf.len() < self.limits.max_part_header_bytes => {
+                    self.state = State::Headers;
+                    Ok(false)
+                }
+                _ => Err(MultipartViolation::PartHeadersTooLarge(
+                    self.limits.max_part_header_bytes,
+                )),
+            },
+            State::Body(mut field, mut data) => {
+                let mut closing = b"\r\n".to_vec();
+                closing.extend_from_slice(&self.delimiter);
+                let (end, complete) = match find(&self.buf, &closing) {
+                    Some(at) => (at, true),
+                    None => (self.buf.len().saturating_sub(closing.len() - 1), false),
+                };
+                data.extend_from_slice(&self.buf.split_to(end));
+                if field.filename.is_none() && data.len() > self.limits.max_field_bytes {
+                    return Err(MultipartViolation::FieldTooLarge(
+                        field.name,
+                        self.limits.max_field_bytes,
+                    ));
+                }
+                if !complete {
+                    self.state = State::Body(field, data);
+                    return Ok(false);
+                }
+                let _ = self.buf.split_to(closing.len());
+                field.data = data.freeze();
+                self.fields.push(field);
+                self.state = State::Delimiter;
+                Ok(true)
+            }
+            State::End => {
+                self.buf.clear();
+                Ok(false)
+            }
+        }
+    }
+
+    fn parse_headers(&self, block: &[u8]) -> Result<MultipartField, MultipartViolation> {
+        let block = std::str::from_utf8(block)
+            .map_err(|_| MultipartViolation::Malformed("part headers are not UTF-8"))?;
+        let mut disposition = None;
+        let mut content_type = None;
+        for line in block.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(MultipartViolation::Malformed("a part header has no ':'"))?;
+            if name.trim().eq_ignore_ascii_case("content-disposition") {
+                disposition = Some(value.trim());
+            } else if name.trim().eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+        let disposition = disposition.ok_or(MultipartViolation::Malformed(
+            "a part has no Content-Disposition",
+        ))?;
+        let mut params = disposition.split(';').map(str::trim);
+        if !params
+            .next()
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("form-data"))
+        {
+            return Err(MultipartViolation::Malformed("a part is not form-data"));
+        }
+        let (mut name, mut filename) = (None, None);
+        for param in params {
+            let Some((key, value)) = param.split_once('=') else {
+                continue;
+            };
+            let value = unquote(value.trim());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "name" => name = Some(value),
+                "filename" => filename = Some(value),
+                _ => {}
+            }
+        }
+        let name = name.ok_or(MultipartViolation::Malformed("a part has no name"))?;
+        if name.len() > self.limits.max_field_name_len {
+            return Err(MultipartViolation::FieldNameTooLong(
+                self.limits.max_field_name_len,
+            ));
+        }
+        Ok(MultipartField {
+            name,
+            filename,
+            content_type,
+            data: Bytes::new(),
+        })
+    }
+}
+
+/// Position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Removes the quotes and backslash escapes of a quoted string.
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_string(),
+    }
+}
+
+/// The boundary of a `multipart/form-data` request.
+fn boundary(req: &HttpRequest) -> Option<String> {
+    let content_type = req.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| unquote(value.trim()))
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// Extractor for a `multipart/form-data` body, parsed within
+/// [`MultipartLimits`].
+///
+/// # Errors
+///
+/// Extraction fails with a [`MultipartViolation`] response: 415 without a
+/// multipart content type, 413 or 400 naming the exceeded limit, or 400
+/// for a malformed body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Multipart {
+    pub fields: Vec<MultipartField>,
+}
+
+impl Multipart {
+    /// The first part named `name`.
+    pub fn get(&self, name: &str) -> Option<&MultipartField> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
+impl FromRequest for Multipart {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let limits = req
+            .app_data::<MultipartLimits>()
+            .cloned()
+            .unwrap_or_default();
+        let boundary = boundary(req);
+        let declared = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        let mut payload = payload.take();
+
+        Box::pin(async move {
+            let Some(boundary) = boundary else {
+                return Err(reject(MultipartViolation::NotMultipart));
+            };
+            if declared.is_some_and(|length| length > limits.max_bytes) {
+                return Err(reject(MultipartViolation::BodyTooLarge(limits.max_bytes)));
+            }
+            let mut parser = MultipartParser::new(&boundary, limits);
+            while let Some(chunk) = payload.next().await {
+                parser.feed(&chunk?).map_err(reject)?;
+            }
+            let fields = parser.finish().map_err(reject)?;
+            Ok(Multipart { fields })
+        })
+    }
+}
+
+fn reject(violation: MultipartViolation) -> Error {
+    Metrics::global().inc(
+        "multipart_rejections_total",
+        &[("limit", violation.limit())],
+    );
+    violation.into()
+}
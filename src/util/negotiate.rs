@@ -0,0 +1,131 @@
+//! Content negotiation on the `Accept` header.
+//!
+//! A handler lists the media types it can produce, in order of preference,
+//! and [`negotiate`] picks the one the client rates highest. Each offer
+//! takes the quality of the most specific matching range (`text/html`
+//! over `text/*` over `*/*`); ties go to the handler's order. Entries
+//! without a `type/subtype` or with an invalid `q` are ignored, and a
+//! missing, empty or entirely malformed header accepts anything, so the
+//! first offer is served.
+//!
+//! Responses that depend on the negotiation must carry `Vary: Accept`;
+//! [`vary_accept`] adds it.
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::error::ApiError;
+
+/// One media range of an `Accept` header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaRange {
+    /// The type, or `*`.
+    pub main: String,
+    /// The subtype, or `*`.
+    pub sub: String,
+    /// The quality, between 0 and 1.
+    pub q: f32,
+}
+
+impl MediaRange {
+    /// Returns how specifically this range matches `main/sub`: 3 for an
+    /// exact match, 2 for `main/*`, 1 for `*/*`, or `None`.
+    fn specificity(&self, main: &str, sub: &str) -> Option<u8> {
+        match (self.main.as_str(), self.sub.as_str()) {
+            ("*", "*") => Some(1),
+            (m, "*") if m.eq_ignore_ascii_case(main) => Some(2),
+            (m, s) if m.eq_ignore_ascii_case(main) && s.eq_ignore_ascii_case(sub) => Some(3),
+            _ => None,
+        }
+    }
+}
+
+/// Splits `type/subtype; params` into its lowercase type and subtype.
+fn essence(media_type: &str) -> Option<(String, String)> {
+    let essence = media_type.split(';').next()?.trim();
+    let (main, sub) = essence.split_once('/')?;
+    let (main, sub) = (main.trim(), sub.trim());
+    let token = |s: &str| !s.is_empty() && !s.contains(char::is_whitespace);
+    if !token(main) || !token(sub) || (main == "*" && sub != "*") {
+        return None;
+    }
+    Some((main.to_ascii_lowercase(), sub.to_ascii_lowercase()))
+}
+
+/// Parses an `Accept` header, skipping malformed entries.
+pub fn parse_accept(header: &str) -> Vec<MediaRange> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let (main, sub) = essence(entry)?;
+            let mut q = 1.0;
+            for param in entry.split(';').skip(1) {
+                let Some((name, value)) = param.split_once('=') else {
+                    continue;
+                };
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+            Some(MediaRange { main, sub, q })
+        })
+        .collect()
+}
+
+/// Picks the offer `accept` rates highest, or `None` if it accepts none.
+pub fn negotiate<'a>(accept: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    let ranges = accept.map(parse_accept).unwrap_or_default();
+    if ranges.is_empty() {
+        return offered.first().copied();
+    }
+    let mut best: Option<(&'a str, f32)> = None;
+    for &offer in offered {
+        let Some((main, sub)) = essence(offer) else {
+            continue;
+        };
+        let q = ranges
+            .iter()
+            .filter_map(|range| range.specificity(&main, &sub).map(|s| (s, range.q)))
+            .max_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+            .map_or(0.0, |(_, q)| q);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((offer, q));
+        }
+    }
+    best.map(|(offer, _)| offer)
+}
+
+/// Negotiates against the `Accept` header of `req`.
+///
+/// # Errors
+///
+/// Returns a `406 Not Acceptable` [`ApiError`] `not_acceptable` listing the
+/// offered types when none is acceptable.
+pub fn negotiate_request<'a>(req: &HttpRequest, offered: &[&'a str]) -> Result<&'a str, ApiError> {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    negotiate(accept, offered).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "not_acceptable",
+            format!(
+                "None of the available media types is acceptable: {}",
+                offered.join(", ")
+            ),
+        )
+    })
+}
+
+/// Adds `Accept` to the `Vary` header of `res`.
+pub fn vary_accept(mut res: HttpResponse) -> HttpResponse {
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("Accept"));
+    res
+}
@@ -0,0 +1,190 @@
+//! Query string extraction with support for nested structures.
+//!
+//! `web::Query<T>` only handles flat query strings. [`NestedQuery<T>`]
+//! deserializes nested objects and arrays using `serde_qs`, e.g.
+//! `?filter[name]=alice&filter[age]=30&sort[]=name&sort[]=age`, or the
+//! equivalent dot notation `?filter.name=alice&filter.age=30` when
+//! configured with [`QueryNotation::Dots`].
+
+use std::borrow::Cow;
+use std::future::{ready, Ready};
+use std::ops::Deref;
+
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{Error, FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+
+/// Syntax used for nested keys in the query string.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryNotation {
+    /// `filter[name]=alice`
+    #[default]
+    Brackets,
+    /// `filter.name=alice`; array suffixes such as `tags[]` are still allowed.
+    Dots,
+}
+
+/// Configuration for the [`NestedQuery`] extractor.
+///
+/// Register it with `App::app_data`; requests without a registered config use
+/// the defaults (bracket notation, maximum depth 5).
+#[derive(Clone, Debug)]
+pub struct NestedQueryConfig {
+    max_depth: usize,
+    notation: QueryNotation,
+}
+
+impl NestedQueryConfig {
+    /// Sets the maximum nesting depth; deeper keys are rejected with `400`.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets the notation used for nested keys.
+    pub fn notation(mut self, notation: QueryNotation) -> Self {
+        self.notation = notation;
+        self
+    }
+
+    /// Parses a raw query string into `T`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `400 Bad Request` [`ApiError`] if a key is nested deeper than
+    /// the configured maximum or the query does not match `T`. Deserialization
+    /// errors name the offending field.
+    pub fn parse<T: DeserializeOwned>(&self, query: &str) -> Result<T, ApiError> {
+        let query = match self.notation {
+            QueryNotation::Brackets => Cow::Borrowed(query),
+            QueryNotation::Dots => Cow::Owned(dots_to_brackets(query)),
+        };
+
+        for key in keys(&query) {
+            let depth = key_depth(key);
+            if depth > self.max_depth {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "query_too_deep",
+                    format!(
+                        "Query parameters may be nested at most {} levels deep",
+                        self.max_depth
+                    ),
+                )
+                .with_field(key, format!("nesting depth {} is too deep", depth)));
+            }
+        }
+
+        let config = serde_qs::Config::new(self.max_depth, false);
+        let deserializer = serde_qs::Deserializer::with_config(&config, query.as_bytes())
+            .map_err(|e| invalid_query().with_field(".", e.to_string()))?;
+
+        serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let field = e.path().to_string();
+            invalid_query().with_field(field, e.into_inner().to_string())
+        })
+    }
+}
+
+impl Default for NestedQueryConfig {
+    fn default() -> Self {
+        NestedQueryConfig {
+            max_depth: 5,
+            notation: QueryNotation::Brackets,
+        }
+    }
+}
+
+/// Extractor for query strings containing nested objects and arrays.
+///
+/// # Errors
+///
+/// Extraction fails with `400 Bad Request` and a structured JSON body when
+/// the query string cannot be deserialized into `T`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NestedQuery<T>(pub T);
+
+impl<T> NestedQuery<T> {
+    /// Unwraps the deserialized value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for NestedQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for NestedQuery<T> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let result = match req.app_data::<NestedQueryConfig>() {
+            Some(config) => config.parse(req.query_string()),
+            None => NestedQueryConfig::default().parse(req.query_string()),
+        };
+        ready(result.map(NestedQuery).map_err(Error::from))
+    }
+}
+
+fn invalid_query() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_query",
+        "Query string is invalid",
+    )
+}
+
+/// Returns the keys of a raw query string.
+fn keys(query: &str) -> impl Iterator<Item = &str> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').map(|(key, _)| key).unwrap_or(pair))
+}
+
+/// Counts the nesting levels of a key, including percent-encoded brackets.
+fn key_depth(key: &str) -> usize {
+    key.matches('[').count() + key.matches("%5B").count() + key.matches("%5b").count()
+}
+
+/// Rewrites dot-notation keys (`a.b.c`) into bracket notation (`a[b][c]`).
+///
+/// Only the part of a key before its first bracket is rewritten, so array
+/// suffixes like `a.tags[]` become `a[tags][]`.
+fn dots_to_brackets(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (pair, None),
+            };
+            let split = key.find('[').unwrap_or(key.len());
+            let (path, suffix) = key.split_at(split);
+
+            let mut segments = path.split('.');
+            let mut rewritten = segments.next().unwrap_or_default().to_string();
+            for segment in segments {
+                rewritten.push('[');
+                rewritten.push_str(segment);
+                rewritten.push(']');
+            }
+            rewritten.push_str(suffix);
+
+            match value {
+                Some(value) => format!("{}={}", rewritten, value),
+                None => rewritten,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
@@ -0,0 +1,148 @@
+//! Building query strings without string concatenation.
+//!
+//! [`QueryStringBuilder`] holds decoded `key=value` pairs in order and
+//! percent-encodes them in [`build`](QueryStringBuilder::build), so values
+//! containing `&`, `=` or `#` cannot spill into other parameters. Parsing
+//! follows `application/x-www-form-urlencoded`: `+` decodes to a space.
+//! Every byte except ASCII letters, digits and `-._~` is encoded on output.
+
+use std::collections::HashMap;
+
+use actix_web::http::Uri;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Bytes encoded in keys and values: everything but RFC 3986 unreserved.
+const QUERY_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Ordered query parameters; a parameter without a value (`?debug`) keeps
+/// its bare form.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryStringBuilder {
+    pairs: Vec<(String, Option<String>)>,
+}
+
+impl QueryStringBuilder {
+    pub fn new() -> Self {
+        QueryStringBuilder::default()
+    }
+
+    /// Parses the query string of `uri`.
+    pub fn from_uri(uri: &Uri) -> Self {
+        QueryStringBuilder::parse(uri.query().unwrap_or_default())
+    }
+
+    /// Parses a query string, without the leading `?`. Empty segments are
+    /// skipped.
+    pub fn parse(query: &str) -> Self {
+        let pairs = query
+            .split('&')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment.split_once('=') {
+                Some((key, value)) => (decode(key), Some(decode(value))),
+                None => (decode(segment), None),
+            })
+            .collect();
+        QueryStringBuilder { pairs }
+    }
+
+    /// Appends `key=value`, unless exactly that pair is already present.
+    /// Other values of `key` are kept, as in `tag=a&tag=b`.
+    pub fn add(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let pair = (key.into(), Some(value.into()));
+        if !self.pairs.contains(&pair) {
+            self.pairs.push(pair);
+        }
+        self
+    }
+
+    /// Like [`add`](Self::add) when `value` is `Some`; does nothing otherwise.
+    pub fn add_optional(
+        &mut self,
+        key: impl Into<String>,
+        value: Option<impl Into<String>>,
+    ) -> &mut Self {
+        if let Some(value) = value {
+            self.add(key, value);
+        }
+        self
+    }
+
+    /// Sets each key of `map` to its value, replacing any values it had.
+    /// New keys are appended in sorted order, so the result is stable.
+    pub fn set_from_map(&mut self, map: HashMap<String, String>) -> &mut Self {
+        let mut entries: Vec<(String, String)> = map.into_iter().collect();
+        entries.sort();
+        for (key, value) in entries {
+            match self.pairs.iter().position(|(k, _)| *k == key) {
+                Some(first) => {
+                    self.pairs[first].1 = Some(value);
+                    // Keep the first occurrence, which now holds the value
+                    let mut seen = false;
+                    self.pairs.retain(|(k, _)| {
+                        if *k != key {
+                            return true;
+                        }
+                        !std::mem::replace(&mut seen, true)
+                    });
+                }
+                None => self.pairs.push((key, Some(value))),
+            }
+        }
+        self
+    }
+
+    /// Removes every value of `key`.
+    pub fn remove(&mut self, key: &str) -> &mut Self {
+        self.pairs.retain(|(k, _)| k != key);
+        self
+    }
+
+    /// Returns the first value of `key`; `Some("")` for a bare key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_deref().unwrap_or_default())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Returns the encoded query string, without the leading `?`; empty
+    /// without parameters.
+    pub fn build(&self) -> String {
+        self.pairs
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => format!("{}={}", encode(key), encode(value)),
+                None => encode(key),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Appends the query string to `path`, adding `?` only when there are
+    /// parameters.
+    pub fn append_to(&self, path: &str) -> String {
+        if self.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, self.build())
+        }
+    }
+}
+
+fn encode(component: &str) -> String {
+    utf8_percent_encode(component, QUERY_COMPONENT).to_string()
+}
+
+fn decode(component: &str) -> String {
+    percent_decode_str(&component.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
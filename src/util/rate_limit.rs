@@ -0,0 +1,83 @@
+//! Keyed token-bucket rate limiting.
+//!
+//! Each key has its own bucket holding up to `burst` tokens, refilled at
+//! `per_second` tokens per second; every request takes one token. Keys are
+//! free-form, so callers isolate limits by choosing the key, e.g.
+//! [`Tenant::scoped_key`](crate::middleware::tenant::Tenant::scoped_key).
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets by key.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allows `per_second` requests per second per key, with bursts of up to
+    /// `burst` requests.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        RateLimiter {
+            per_second: f64::from(per_second.max(1)),
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allows bursts of up to `burst` requests per key, refilling one token
+    /// every `interval`; for limits slower than one request per second.
+    pub fn per_interval(burst: u32, interval: Duration) -> Self {
+        RateLimiter {
+            per_second: 1.0 / interval.as_secs_f64().max(0.001),
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`.
+    ///
+    /// # Errors
+    ///
+    /// Returns how long to wait until a token is available when the bucket
+    /// is empty.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Like [`check`](Self::check), at the given time.
+    pub fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        // Full buckets carry no state, so forget them to bound memory
+        if buckets.len() > 10_000 {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * per_second
+                    < burst
+            });
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
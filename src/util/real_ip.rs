@@ -0,0 +1,389 @@
+//! Client address resolution behind reverse proxies.
+//!
+//! Forwarding headers are only believed when the connection comes from a
+//! trusted proxy (`TRUSTED_PROXIES`). The standard `Forwarded` header
+//! (RFC 7239) is preferred; `X-Forwarded-For`, `X-Forwarded-Proto` and
+//! `X-Forwarded-Host` are used when it is absent. Hops are walked from the
+//! nearest proxy outwards, skipping trusted proxies; the first untrusted hop
+//! is the client. Obfuscated (`_hidden`) and `unknown` nodes cannot be
+//! checked against the trusted list, so the walk stops there.
+//!
+//! With the PROXY protocol enabled, the client address from the PROXY
+//! header takes the place of the connection's peer address.
+
+use std::fmt;
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::HeaderMap;
+use actix_web::{Error, FromRequest, HttpRequest};
+use log::debug;
+
+use crate::proxy_protocol::ProxyInfo;
+
+/// A network range in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns whether `ip` lies in this range.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    if net[..full] != ip[..full] {
+        return false;
+    }
+    let rest = prefix % 8;
+    rest == 0 || (net[full] ^ ip[full]) & (0xffu8 << (8 - rest)) == 0
+}
+
+impl FromStr for Cidr {
+    type Err = IoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || IoError::new(ErrorKind::InvalidInput, format!("Invalid CIDR '{}'", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Proxies whose forwarding headers are believed.
+///
+/// Register it with `App::app_data`; without it no forwarding header is
+/// trusted and the peer address is always the client.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    /// Parses a comma-separated list of addresses and CIDR ranges.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an entry is not a valid address or range.
+    pub fn parse(list: &str) -> Result<Self, IoError> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Cidr::from_str)
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+
+    /// Returns whether `ip` belongs to a trusted proxy.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// A node identifier from a forwarding header (RFC 7239 section 6).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ForwardedNode {
+    Ip(IpAddr),
+    /// An obfuscated identifier such as `_hidden`.
+    Obfuscated(String),
+    /// The `unknown` identifier.
+    Unknown,
+}
+
+impl ForwardedNode {
+    /// Parses a node, with or without a port, e.g. `"[2001:db8::1]:4711"`.
+    pub fn parse(value: &str) -> Option<ForwardedNode> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("unknown") {
+            return Some(ForwardedNode::Unknown);
+        }
+        if let Some(rest) = value.strip_prefix('[') {
+            let (ip, port) = rest.split_once(']')?;
+            if !(port.is_empty() || port.strip_prefix(':').is_some_and(valid_port)) {
+                return None;
+            }
+            return ip.parse().ok().map(ForwardedNode::Ip);
+        }
+        let node = match value.split_once(':') {
+            // A bare IPv6 address must be bracketed
+            Some((_, port)) if port.contains(':') => return None,
+            Some((node, port)) if valid_port(port) => node,
+            Some(_) => return None,
+            None => value,
+        };
+        if let Some(name) = node.strip_prefix('_') {
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+            return valid.then(|| ForwardedNode::Obfuscated(node.to_string()));
+        }
+        node.parse::<std::net::Ipv4Addr>()
+            .ok()
+            .map(|ip| ForwardedNode::Ip(IpAddr::V4(ip)))
+    }
+
+    /// Returns the address, unless the node is obfuscated or unknown.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ForwardedNode::Ip(ip) => Some(*ip),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ForwardedNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardedNode::Ip(ip) => write!(f, "{}", ip),
+            ForwardedNode::Obfuscated(name) => write!(f, "{}", name),
+            ForwardedNode::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A port, or an obfuscated port such as `_abc`.
+fn valid_port(port: &str) -> bool {
+    port.parse::<u16>().is_ok()
+        || port.strip_prefix('_').is_some_and(|p| {
+            !p.is_empty()
+                && p.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+        })
+}
+
+/// One element of a `Forwarded` header: the parameters added by one proxy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    pub for_node: Option<ForwardedNode>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+/// Parses `Forwarded` header values into elements, nearest client first.
+///
+/// Values may be quoted strings with backslash escapes; separators inside
+/// quotes are literal. Returns `None` if any value is malformed.
+pub fn parse_forwarded<'a>(
+    values: impl IntoIterator<Item = &'a str>,
+) -> Option<Vec<ForwardedElement>> {
+    let mut elements = Vec::new();
+
+    for value in values {
+        let mut chars = value.chars().peekable();
+        let mut element = ForwardedElement::default();
+        let mut empty = true;
+
+        loop {
+            while chars.peek().is_some_and(|c| *c == ' ' || *c == '\t') {
+                chars.next();
+            }
+            let name: String =
+                std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',' && *c != ';'))
+                    .collect();
+            let name = name.trim().to_ascii_lowercase();
+
+            if chars.next_if_eq(&'=').is_some() {
+                let mut param = String::new();
+                if chars.next_if_eq(&'"').is_some() {
+                    loop {
+                        match chars.next()? {
+                            '"' => break,
+                            '\\' => param.push(chars.next()?),
+                            c => param.push(c),
+                        }
+                    }
+                } else {
+                    param.extend(std::iter::from_fn(|| {
+                        chars.next_if(|c| *c != ',' && *c != ';')
+                    }));
+                    param = param.trim().to_string();
+                    if param.is_empty() || param.contains(['"', ' ', '[']) {
+                        return None;
+                    }
+                }
+                while chars.peek().is_some_and(|c| *c == ' ' || *c == '\t') {
+                    chars.next();
+                }
+
+                match name.as_str() {
+                    "" => return None,
+                    "for" => element.for_node = Some(ForwardedNode::parse(&param)?),
+                    "proto" => element.proto = Some(param.to_ascii_lowercase()),
+                    "host" => element.host = Some(param),
+                    // `by` and extensions are not needed
+                    _ => {}
+                }
+                empty = false;
+            } else if !name.is_empty() {
+                return None;
+            }
+
+            match chars.next() {
+                Some(';') => continue,
+                Some(',') | None => {
+                    if !empty {
+                        elements.push(std::mem::take(&mut element));
+                    }
+                    empty = true;
+                    if chars.peek().is_none() {
+                        break;
+                    }
+                }
+                Some(_) => return None,
+            }
+        }
+    }
+
+    Some(elements)
+}
+
+/// The resolved client of a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RealIp {
+    /// The client node; an address unless a proxy hid it.
+    pub node: ForwardedNode,
+    /// Scheme the client used, if reported by a trusted proxy.
+    pub proto: Option<String>,
+    /// Host the client requested, if reported by a trusted proxy.
+    pub host: Option<String>,
+}
+
+impl RealIp {
+    /// Returns the client address, if known.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.node.ip()
+    }
+}
+
+/// Resolves the client of a request.
+///
+/// `peer` is the address of the directly connected host. Forwarding headers
+/// are only consulted when it is a trusted proxy.
+pub fn resolve(peer: Option<SocketAddr>, headers: &HeaderMap, trusted: &TrustedProxies) -> RealIp {
+    let direct = RealIp {
+        node: peer
+            .map(|p| ForwardedNode::Ip(p.ip().to_canonical()))
+            .unwrap_or(ForwardedNode::Unknown),
+        proto: None,
+        host: None,
+    };
+    match peer {
+        Some(peer) if trusted.contains(&peer.ip()) => {}
+        _ => return direct,
+    }
+
+    let hops: Vec<ForwardedElement> = if headers.contains_key("forwarded") {
+        let values: Option<Vec<&str>> = headers
+            .get_all("forwarded")
+            .map(|v| v.to_str().ok())
+            .collect();
+        match values.and_then(parse_forwarded) {
+            Some(elements) => elements,
+            None => {
+                debug!("Ignoring malformed Forwarded header");
+                return direct;
+            }
+        }
+    } else {
+        let list = |name: &str| -> Vec<String> {
+            headers
+                .get_all(name)
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect()
+        };
+        let proto = list("x-forwarded-proto").pop();
+        let host = list("x-forwarded-host").pop();
+        let mut hops = Vec::new();
+        for entry in list("x-forwarded-for") {
+            match ForwardedNode::parse(&entry).or_else(|| entry.parse().ok().map(ForwardedNode::Ip))
+            {
+                Some(node) => hops.push(ForwardedElement {
+                    for_node: Some(node),
+                    proto: proto.clone(),
+                    host: host.clone(),
+                }),
+                None => {
+                    debug!("Ignoring malformed X-Forwarded-For header");
+                    return direct;
+                }
+            }
+        }
+        hops
+    };
+
+    // Walk from the nearest hop; the first one not known to be a trusted
+    // proxy is the client
+    let mut client = direct;
+    for hop in hops.into_iter().rev() {
+        let Some(node) = hop.for_node else {
+            break;
+        };
+        let trusted_hop = node.ip().is_some_and(|ip| trusted.contains(&ip));
+        client = RealIp {
+            node: match node {
+                ForwardedNode::Ip(ip) => ForwardedNode::Ip(ip.to_canonical()),
+                node => node,
+            },
+            proto: hop.proto,
+            host: hop.host,
+        };
+        if !trusted_hop {
+            break;
+        }
+    }
+    client
+}
+
+/// Returns the address of the host `req` came from: the peer of the
+/// connection, or behind a PROXY protocol load balancer the client its
+/// header names.
+pub fn peer(req: &HttpRequest) -> Option<SocketAddr> {
+    req.conn_data::<ProxyInfo>()
+        .map(|info| info.src_addr)
+        .or_else(|| req.peer_addr())
+}
+
+/// Resolves the client of `req` using the registered [`TrustedProxies`].
+pub fn real_ip(req: &HttpRequest) -> RealIp {
+    let default = TrustedProxies::default();
+    let trusted = req.app_data::<TrustedProxies>().unwrap_or(&default);
+    resolve(peer(req), req.headers(), trusted)
+}
+
+impl FromRequest for RealIp {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(real_ip(req)))
+    }
+}
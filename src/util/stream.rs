@@ -0,0 +1,144 @@
+//! Streaming responses with backpressure.
+//!
+//! [`StreamBody`] turns a producer, any `Stream<Item = Result<Bytes, E>>`,
+//! into a chunked response. The producer runs as its own task and hands
+//! chunks to the response through a bounded channel, so a slow client
+//! pauses the producer once `buffer` chunks are waiting instead of letting
+//! them pile up in memory. When the client disconnects the producer is
+//! dropped right away, even while it is waiting for data, which releases
+//! whatever it holds.
+//!
+//! A producer error after the response has started can no longer change
+//! its status. It is logged, the optional error trailer is sent as a last
+//! chunk, and the connection is aborted so the client sees a truncated body
+//! rather than a complete one.
+
+use std::fmt::Display;
+use std::io::Error as IoError;
+use std::time::Duration;
+
+use actix_web::rt::time::{sleep_until, Instant};
+use actix_web::web::Bytes;
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{debug, warn};
+use tokio::sync::mpsc;
+
+/// Default number of chunks buffered between producer and client.
+pub const DEFAULT_BUFFER: usize = 16;
+
+type Trailer<E> = Box<dyn Fn(&E) -> Bytes>;
+
+/// Adapts a stream of chunks into a backpressured streaming response.
+pub struct StreamBody<S, E> {
+    source: S,
+    buffer: usize,
+    bytes_per_second: Option<u64>,
+    trailer: Option<Trailer<E>>,
+}
+
+impl<S, E> StreamBody<S, E>
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: Display + 'static,
+{
+    /// Streams the chunks of `source`.
+    pub fn new(source: S) -> Self {
+        StreamBody {
+            source,
+            buffer: DEFAULT_BUFFER,
+            bytes_per_second: None,
+            trailer: None,
+        }
+    }
+
+    /// Sets how many chunks may wait for the client before the producer is
+    /// paused (at least one).
+    pub fn buffer(mut self, chunks: usize) -> Self {
+        self.buffer = chunks.max(1);
+        self
+    }
+
+    /// Paces the stream to at most `bytes_per_second`.
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.bytes_per_second = Some(bytes_per_second.max(1));
+        self
+    }
+
+    /// Sends `trailer(error)` as the last chunk when the producer fails, e.g.
+    /// a comment line telling the client the body is incomplete.
+    pub fn error_trailer(mut self, trailer: impl Fn(&E) -> Bytes + 'static) -> Self {
+        self.trailer = Some(Box::new(trailer));
+        self
+    }
+
+    /// Starts the producer and returns `res` with the streamed body.
+    ///
+    /// Must be called on the actix runtime, e.g. from a handler.
+    pub fn into_response(self, mut res: HttpResponseBuilder) -> HttpResponse {
+        let (tx, rx) = mpsc::channel(self.buffer);
+        actix_web::rt::spawn(produce(
+            self.source,
+            tx,
+            self.bytes_per_second,
+            self.trailer,
+        ));
+        res.streaming(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        }))
+    }
+}
+
+/// Forwards the chunks of `source` to `tx` until either side is done.
+async fn produce<S, E>(
+    source: S,
+    tx: mpsc::Sender<Result<Bytes, IoError>>,
+    bytes_per_second: Option<u64>,
+    trailer: Option<Trailer<E>>,
+) where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Display,
+{
+    let mut source = Box::pin(source);
+    let started = Instant::now();
+    let mut sent: u64 = 0;
+    loop {
+        let item = tokio::select! {
+            item = source.next() => item,
+            _ = tx.closed() => {
+                debug!("Client disconnected after {} bytes; stopping the stream", sent);
+                return;
+            }
+        };
+        match item {
+            None => return,
+            Some(Ok(chunk)) => {
+                sent += chunk.len() as u64;
+                if tx.send(Ok(chunk)).await.is_err() {
+                    debug!(
+                        "Client disconnected after {} bytes; stopping the stream",
+                        sent
+                    );
+                    return;
+                }
+                if let Some(rate) = bytes_per_second {
+                    let due = started + Duration::from_secs_f64(sent as f64 / rate as f64);
+                    tokio::select! {
+                        _ = sleep_until(due) => {}
+                        _ = tx.closed() => return,
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                warn!("Streamed response failed after {} bytes: {}", sent, e);
+                if let Some(trailer) = &trailer {
+                    let _ = tx.send(Ok(trailer(&e))).await;
+                }
+                let _ = tx
+                    .send(Err(IoError::other(format!("stream truncated: {}", e))))
+                    .await;
+                return;
+            }
+        }
+    }
+}
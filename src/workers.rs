@@ -0,0 +1,149 @@
+//! Number of worker threads.
+//!
+//! Without `NUM_WORKERS` the server starts one worker per CPU it may use.
+//! In a container that is not the number of CPUs on the host: the CPU
+//! quota of the cgroup (`cpu.max` on cgroup v2, `cpu.cfs_quota_us` and
+//! `cpu.cfs_period_us` on v1) caps how much CPU time the process gets, and
+//! workers beyond it only add contention. [`WorkerCount::from_env`] reads
+//! the quota from `/sys/fs/cgroup`, which in a container is its own cgroup,
+//! rounds it up to whole CPUs and uses the smaller of that and the host
+//! count, never less than one worker.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use log::info;
+
+/// Where the cgroup filesystem is mounted.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// How many worker threads the server runs; always at least one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkerCount(NonZeroUsize);
+
+impl WorkerCount {
+    /// `count` workers, or one if `count` is zero.
+    pub fn new(count: usize) -> Self {
+        WorkerCount(NonZeroUsize::new(count).unwrap_or(NonZeroUsize::MIN))
+    }
+
+    /// One worker per CPU on the host, limited to the cgroup CPU quota.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - CPUs on the host.
+    /// * `quota` - CPUs the cgroup may use, possibly fractional, or `None` if unlimited.
+    pub fn for_cpus(host: usize, quota: Option<f64>) -> Self {
+        let count = match quota {
+            Some(quota) if quota > 0.0 => host.min(quota.ceil() as usize),
+            _ => host,
+        };
+        WorkerCount::new(count)
+    }
+
+    /// The number of workers.
+    pub fn get(self) -> usize {
+        self.0.get()
+    }
+
+    /// Reads `NUM_WORKERS`, falling back to [`WorkerCount::for_cpus`] with the
+    /// host CPUs and the quota under [`CGROUP_ROOT`].
+    ///
+    /// # Returns
+    ///
+    /// * `WorkerCount` - The configured count, or the default for the CPUs available.
+    pub fn from_env() -> Self {
+        if let Some(count) = env::var("NUM_WORKERS")
+            .ok()
+            .and_then(|s| s.parse::<NonZeroUsize>().ok())
+        {
+            return WorkerCount(count);
+        }
+        let host = host_cpus();
+        let quota = cgroup_cpu_quota(Path::new(CGROUP_ROOT));
+        let workers = WorkerCount::for_cpus(host, quota);
+        match quota {
+            Some(quota) => info!(
+                "{} CPUs on the host, {:.2} allowed by the cgroup CPU quota; defaulting to {} workers",
+                host, quota, workers
+            ),
+            None => info!(
+                "{} CPUs on the host, no cgroup CPU quota; defaulting to {} workers",
+                host, workers
+            ),
+        }
+        workers
+    }
+}
+
+impl fmt::Display for WorkerCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Parses a cgroup v2 `cpu.max`, e.g. `200000 100000` (two CPUs) or
+/// `max 100000` (unlimited), into CPUs.
+pub fn parse_cpu_max(contents: &str) -> Option<f64> {
+    let mut fields = contents.split_whitespace();
+    let quota = fields.next()?;
+    let period: u64 = fields.next().map_or(Some(100_000), |p| p.parse().ok())?;
+    if quota == "max" || period == 0 {
+        return None;
+    }
+    Some(quota.parse::<u64>().ok()? as f64 / period as f64)
+}
+
+/// Parses cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us` into CPUs; a
+/// quota of `-1` is unlimited.
+pub fn parse_cfs_quota(quota: &str, period: &str) -> Option<f64> {
+    let quota: i64 = quota.trim().parse().ok()?;
+    let period: u64 = period.trim().parse().ok()?;
+    if quota <= 0 || period == 0 {
+        return None;
+    }
+    Some(quota as f64 / period as f64)
+}
+
+/// Reads the CPU quota of the cgroup mounted at `root`, v2 first, then the
+/// v1 `cpu` controller; `None` if there is no quota or it cannot be read.
+pub fn cgroup_cpu_quota(root: &Path) -> Option<f64> {
+    if let Ok(contents) = fs::read_to_string(root.join("cpu.max")) {
+        return parse_cpu_max(&contents);
+    }
+    ["cpu", "cpu,cpuacct"].iter().find_map(|controller| {
+        let dir = root.join(controller);
+        let quota = fs::read_to_string(dir.join("cpu.cfs_quota_us")).ok()?;
+        let period = fs::read_to_string(dir.join("cpu.cfs_period_us")).ok()?;
+        parse_cfs_quota(&quota, &period)
+    })
+}
+
+/// Counts the CPUs in a kernel CPU list such as `0-3,6`.
+pub fn parse_cpu_list(list: &str) -> Option<usize> {
+    let mut count = 0;
+    for range in list.trim().split(',') {
+        count += match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                last.checked_sub(first)? + 1
+            }
+            None => range.parse::<usize>().map(|_| 1).ok()?,
+        };
+    }
+    Some(count)
+}
+
+/// CPUs online on the host, from `/sys/devices/system/cpu/online` on Linux.
+///
+/// `num_cpus` is the fallback elsewhere; it may already apply the quota.
+pub fn host_cpus() -> usize {
+    fs::read_to_string("/sys/devices/system/cpu/online")
+        .ok()
+        .and_then(|list| parse_cpu_list(&list))
+        .filter(|&count| count > 0)
+        .unwrap_or_else(num_cpus::get)
+}
@@ -0,0 +1,260 @@
+//! WebSocket connections.
+//!
+//! [`serve`] upgrades a request and runs the connection, handing every text
+//! and binary message to a handler and sending back what it returns. Pings
+//! are answered and a close from the client is echoed.
+//!
+//! A client can send messages faster than they can be processed, so every
+//! connection has its own token bucket: [`MessageRateLimiter`] allows
+//! `max_messages_per_second` with bursts of up to `burst` messages. A client
+//! exceeding it is sent a close frame with code 1008 (policy violation) and
+//! disconnected. Register the limits with `App::app_data`; without them
+//! [`MessageRateLimiter::default`] applies.
+
+use std::env;
+use std::time::Instant;
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::stream::{self, StreamExt};
+use log::{debug, warn};
+
+use crate::connections::OpenConnection;
+use crate::error::ApiError;
+use crate::util::rate_limit::RateLimiter;
+use crate::util::real_ip::real_ip;
+
+/// Default sustained message rate per connection.
+pub const DEFAULT_MAX_MESSAGES_PER_SECOND: u32 = 20;
+
+/// Message rate limits of WebSocket connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageRateLimiter {
+    /// Messages per second a connection may send on average.
+    pub max_messages_per_second: u32,
+    /// Messages a connection may send at once.
+    pub burst: u32,
+}
+
+impl MessageRateLimiter {
+    pub fn new(max_messages_per_second: u32, burst: u32) -> Self {
+        MessageRateLimiter {
+            max_messages_per_second: max_messages_per_second.max(1),
+            burst: burst.max(1),
+        }
+    }
+
+    /// Reads `WS_MAX_MSG_PER_SEC` and `WS_MSG_BURST`; the burst defaults to
+    /// twice the rate.
+    pub fn from_env() -> Self {
+        let read = |var: &str| {
+            env::var(var)
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .filter(|&v| v > 0)
+        };
+        let rate = read("WS_MAX_MSG_PER_SEC").unwrap_or(DEFAULT_MAX_MESSAGES_PER_SECOND);
+        MessageRateLimiter::new(rate, read("WS_MSG_BURST").unwrap_or(rate.saturating_mul(2)))
+    }
+
+    /// Starts the bucket of a new connection from `peer`.
+    pub fn connection(&self, peer: impl Into<String>) -> ConnectionLimit {
+        ConnectionLimit {
+            peer: peer.into(),
+            bucket: RateLimiter::new(self.max_messages_per_second, self.burst),
+            received: 0,
+        }
+    }
+}
+
+impl Default for MessageRateLimiter {
+    fn default() -> Self {
+        MessageRateLimiter::new(
+            DEFAULT_MAX_MESSAGES_PER_SECOND,
+            DEFAULT_MAX_MESSAGES_PER_SECOND * 2,
+        )
+    }
+}
+
+/// The token bucket of one connection.
+pub struct ConnectionLimit {
+    peer: String,
+    bucket: RateLimiter,
+    received: u64,
+}
+
+impl ConnectionLimit {
+    /// Counts a message arriving now; false once the client is over the
+    /// rate.
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    /// Like [`allow`](Self::allow), for a message arriving at `now`.
+    pub fn allow_at(&mut self, now: Instant) -> bool {
+        self.received += 1;
+        self.bucket.check_at(&self.peer, now).is_ok()
+    }
+
+    /// Messages received so far, including a rejected one.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+}
+
+/// Upgrades `req` to a WebSocket and runs the connection, sending back the
+/// message `handler` returns for each text or binary message.
+///
+/// # Errors
+///
+/// Returns a 400 ApiError if `req` is not a valid WebSocket handshake.
+pub fn serve<F>(
+    req: &HttpRequest,
+    payload: web::Payload,
+    handler: F,
+) -> Result<HttpResponse, ApiError>
+where
+    F: FnMut(Message) -> Option<Message> + 'static,
+{
+    ws::verify_handshake(req.head()).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_websocket_handshake",
+            "Invalid WebSocket handshake",
+        )
+        .with_field("upgrade", e.to_string())
+    })?;
+    // verify_handshake checked the key is present
+    let key = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .map(|key| ws::hash_key(key.as_bytes()))
+        .unwrap_or_default();
+
+    let limits = req
+        .app_data::<web::Data<MessageRateLimiter>>()
+        .map(|limits| *limits.get_ref())
+        .unwrap_or_default();
+    let peer = real_ip(req).node.to_string();
+    let session = Session {
+        payload,
+        codec: Codec::new(),
+        buf: BytesMut::new(),
+        limit: limits.connection(peer),
+        handler,
+        closed: false,
+        _open: OpenConnection::open("websocket"),
+    };
+
+    Ok(HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS)
+        .upgrade("websocket")
+        .insert_header((
+            header::SEC_WEBSOCKET_ACCEPT,
+            HeaderValue::from_bytes(&key).expect("accept key is base64"),
+        ))
+        .streaming(stream::unfold(session, |mut session| async move {
+            session.next_chunk().await.map(|chunk| (chunk, session))
+        })))
+}
+
+/// State of a connection; reads frames and produces what to send back.
+struct Session<F> {
+    payload: web::Payload,
+    codec: Codec,
+    buf: BytesMut,
+    limit: ConnectionLimit,
+    handler: F,
+    closed: bool,
+    /// Counts the WebSocket in `open_connections` while the session lives.
+    _open: OpenConnection,
+}
+
+impl<F> Session<F>
+where
+    F: FnMut(Message) -> Option<Message>,
+{
+    /// Returns the next frames to send, or `None` once the connection is
+    /// done.
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, ws::ProtocolError>> {
+        while !self.closed {
+            let frame = match self.codec.decode(&mut self.buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => match self.payload.next().await {
+                    Some(Ok(bytes)) => {
+                        self.buf.extend_from_slice(&bytes);
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        debug!("WebSocket from {} failed: {}", self.limit.peer(), e);
+                        return None;
+                    }
+                    None => return None,
+                },
+                Err(e) => {
+                    debug!("Invalid WebSocket frame from {}: {}", self.limit.peer(), e);
+                    return Some(self.close(CloseCode::Protocol.into()));
+                }
+            };
+            if let Some(reply) = self.handle(frame) {
+                return Some(reply);
+            }
+        }
+        None
+    }
+
+    fn handle(&mut self, frame: Frame) -> Option<Result<Bytes, ws::ProtocolError>> {
+        if let Frame::Close(reason) = frame {
+            return Some(self.close(reason.unwrap_or_else(|| CloseCode::Normal.into())));
+        }
+        if !self.limit.allow() {
+            warn!(
+                "Closing WebSocket from {} after {} messages: over the message rate limit",
+                self.limit.peer(),
+                self.limit.received()
+            );
+            return Some(self.close((CloseCode::Policy, "message rate exceeded").into()));
+        }
+        let reply = match frame {
+            Frame::Text(text) => match std::str::from_utf8(&text) {
+                Ok(text) => (self.handler)(Message::Text(text.into())),
+                Err(_) => return Some(self.close(CloseCode::Invalid.into())),
+            },
+            Frame::Binary(data) => (self.handler)(Message::Binary(data)),
+            Frame::Ping(data) => Some(Message::Pong(data)),
+            Frame::Pong(_) => None,
+            Frame::Continuation(_) => {
+                return Some(
+                    self.close(
+                        (
+                            CloseCode::Unsupported,
+                            "fragmented messages are not supported",
+                        )
+                            .into(),
+                    ),
+                )
+            }
+            Frame::Close(_) => unreachable!("handled above"),
+        };
+        reply.map(|message| self.encode(message))
+    }
+
+    /// Sends a close frame and ends the connection.
+    fn close(&mut self, reason: CloseReason) -> Result<Bytes, ws::ProtocolError> {
+        self.closed = true;
+        self.encode(Message::Close(Some(reason)))
+    }
+
+    fn encode(&mut self, message: Message) -> Result<Bytes, ws::ProtocolError> {
+        let mut out = BytesMut::new();
+        self.codec.encode(message, &mut out)?;
+        Ok(out.freeze())
+    }
+}
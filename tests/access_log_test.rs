@@ -0,0 +1,110 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use std::time::Duration;
+
+use main::metrics::Metrics;
+use main::middleware::access_log::{sample_bucket, AccessLog, SamplingConfig, SAMPLED_METRIC};
+use main::middleware::request_id::AssignRequestId;
+
+fn config(sample_rate: f64) -> SamplingConfig {
+    SamplingConfig {
+        sample_rate,
+        slow_threshold: Duration::from_millis(500),
+    }
+}
+
+#[test]
+fn test_errors_and_slow_requests_are_always_logged() {
+    let config = config(0.0);
+    let fast = Duration::from_millis(5);
+
+    assert!(!config.should_log("id", StatusCode::OK, fast));
+    assert!(config.should_log("id", StatusCode::NOT_FOUND, fast));
+    assert!(config.should_log("id", StatusCode::INTERNAL_SERVER_ERROR, fast));
+    assert!(config.should_log("id", StatusCode::OK, Duration::from_millis(500)));
+}
+
+#[test]
+fn test_sampling_is_deterministic_and_proportional() {
+    assert_eq!(sample_bucket("request-1"), sample_bucket("request-1"));
+    assert!(config(1.0).should_log("anything", StatusCode::OK, Duration::ZERO));
+
+    let config = config(0.25);
+    let sampled = (0..10_000)
+        .filter(|i| config.should_log(&format!("req-{}", i), StatusCode::OK, Duration::ZERO))
+        .count();
+    assert!((2_200..=2_800).contains(&sampled), "sampled {}", sampled);
+}
+
+#[actix_rt::test]
+async fn test_sampling_counters() {
+    let app = init_service(
+        App::new()
+            .wrap(AccessLog::new(config(0.0)))
+            .route(
+                "/ok",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            )
+            .route(
+                "/fail",
+                web::get().to(|| async { HttpResponse::InternalServerError().finish() }),
+            ),
+    )
+    .await;
+
+    let metrics = Metrics::global();
+    let sampled_in = metrics.counter_value(SAMPLED_METRIC, &[("decision", "in")]);
+    let sampled_out = metrics.counter_value(SAMPLED_METRIC, &[("decision", "out")]);
+
+    call_service(&app, TestRequest::get().uri("/ok").to_request()).await;
+    call_service(&app, TestRequest::get().uri("/fail").to_request()).await;
+
+    assert_eq!(
+        metrics.counter_value(SAMPLED_METRIC, &[("decision", "in")]),
+        sampled_in + 1
+    );
+    assert_eq!(
+        metrics.counter_value(SAMPLED_METRIC, &[("decision", "out")]),
+        sampled_out + 1
+    );
+    assert!(metrics
+        .render()
+        .contains("access_log_sampled_total{decision=\"out\"}"));
+}
+
+#[actix_rt::test]
+async fn test_request_id_is_chained_or_generated() {
+    let app = init_service(App::new().wrap(AssignRequestId::default()).route(
+        "/ok",
+        web::get().to(|| async { HttpResponse::Ok().finish() }),
+    ))
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/ok")
+        .insert_header(("X-Request-Id", "abc-123"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    let child = resp
+        .headers()
+        .get("x-request-id")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(
+        child.starts_with("abc-123:"),
+        "unexpected child ID {}",
+        child
+    );
+
+    let req = TestRequest::get().uri("/ok").to_request();
+    let resp = call_service(&app, req).await;
+    let generated = resp
+        .headers()
+        .get("x-request-id")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert_eq!(generated.len(), 36);
+}
@@ -0,0 +1,141 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App};
+use serde_json::Value;
+use std::fs;
+use tempfile::NamedTempFile;
+
+use main::admin;
+use main::middleware::api_key::{validate_key, AdminKey, ApiKeyAuth};
+
+mod common;
+
+use common::logs;
+
+const OLD_KEY: &str = "old-admin-key-0123456789";
+const NEW_KEY: &str = "new-admin-key-0123456789";
+
+fn key_file(contents: &str) -> NamedTempFile {
+    let file = NamedTempFile::new().unwrap();
+    fs::write(file.path(), contents).unwrap();
+    file
+}
+
+#[test]
+fn validates_rotated_keys() {
+    assert!(validate_key(NEW_KEY).is_ok());
+    assert!(validate_key("").is_err());
+    assert!(validate_key("short").is_err());
+    assert!(validate_key("contains a space in the key").is_err());
+    assert!(validate_key("non-ascii-key-é-0123456789").is_err());
+}
+
+#[test]
+fn reload_rotates_to_the_file_contents() {
+    let file = key_file(&format!("{}\n", OLD_KEY));
+    let key = AdminKey::from_file(file.path().to_str().unwrap()).unwrap();
+    assert!(key.matches(OLD_KEY.as_bytes()));
+
+    fs::write(file.path(), NEW_KEY).unwrap();
+    assert!(key.reload().unwrap());
+    assert!(key.matches(NEW_KEY.as_bytes()));
+    assert!(!key.matches(OLD_KEY.as_bytes()));
+
+    // An unchanged file is not a rotation
+    assert!(!key.reload().unwrap());
+}
+
+#[test]
+fn invalid_key_keeps_the_current_one() {
+    let file = key_file(OLD_KEY);
+    let key = AdminKey::from_file(file.path().to_str().unwrap()).unwrap();
+
+    for bad in ["", "short", "has whitespace inside the key"] {
+        fs::write(file.path(), bad).unwrap();
+        assert!(key.reload().is_err());
+        assert!(key.matches(OLD_KEY.as_bytes()));
+    }
+}
+
+#[test]
+fn fixed_keys_are_not_reloadable() {
+    let key = AdminKey::new(Some(OLD_KEY.to_string()));
+    assert!(!key.is_reloadable());
+    assert!(key.reload().is_err());
+    assert!(key.matches(OLD_KEY.as_bytes()));
+
+    assert!(!AdminKey::new(None).is_set());
+    assert!(!AdminKey::new(None).matches(b""));
+}
+
+#[actix_web::test]
+async fn reload_endpoint_rotates_and_audits() {
+    logs::capture();
+    let file = key_file(OLD_KEY);
+    let key = AdminKey::from_file(file.path().to_str().unwrap()).unwrap();
+    let app = init_service(
+        App::new().service(
+            web::scope("/admin")
+                .wrap(ApiKeyAuth::shared(key.clone()))
+                .app_data(web::Data::new(key.clone()))
+                .configure(admin::configure),
+        ),
+    )
+    .await;
+
+    fs::write(file.path(), "short").unwrap();
+    let req = TestRequest::post()
+        .uri("/admin/config/reload")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(logs::contains("admin_key_rotation_failed"));
+
+    fs::write(file.path(), NEW_KEY).unwrap();
+    let req = TestRequest::post()
+        .uri("/admin/config/reload")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["rotated"], true);
+    assert!(logs::contains("admin_key_rotated"));
+    assert!(!logs::contains(NEW_KEY));
+
+    // The old key stops working on every request at once
+    let req = TestRequest::post()
+        .uri("/admin/config/reload")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let req = TestRequest::post()
+        .uri("/admin/config/reload")
+        .insert_header(("X-Api-Key", NEW_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["rotated"], false);
+}
+
+#[actix_web::test]
+async fn reload_endpoint_needs_a_key_file() {
+    let key = AdminKey::new(Some(OLD_KEY.to_string()));
+    let app = init_service(
+        App::new().service(
+            web::scope("/admin")
+                .wrap(ApiKeyAuth::shared(key.clone()))
+                .app_data(web::Data::new(key))
+                .configure(admin::configure),
+        ),
+    )
+    .await;
+
+    let req = TestRequest::post()
+        .uri("/admin/config/reload")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+}
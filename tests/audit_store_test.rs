@@ -0,0 +1,259 @@
+#![cfg(feature = "db")]
+
+use actix_web::{test, web, App};
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+
+use main::audit;
+use main::audit::store::{
+    list_events, AuditQuery, AuditStore, AuditWriter, NewAuditEvent, DROPPED_METRIC,
+};
+use main::db::Database;
+use main::metrics::Metrics;
+
+fn event(action: &str, principal: &str, days_ago: i64) -> NewAuditEvent {
+    NewAuditEvent {
+        timestamp: Utc::now() - Duration::days(days_ago),
+        action: action.to_string(),
+        principal: Some(principal.to_string()),
+        request_id: None,
+        details: json!({ "event": action, "username": principal }),
+    }
+}
+
+async fn seeded_store() -> AuditStore {
+    let store = AuditStore::new(Database::open_in_memory().unwrap());
+    store
+        .insert_batch(vec![
+            event("login_failed", "alice", 40),
+            event("login_succeeded", "alice", 20),
+            event("login_failed", "bob", 10),
+            event("login_succeeded", "bob", 5),
+            event("login_succeeded", "alice", 1),
+        ])
+        .await
+        .unwrap();
+    store
+}
+
+fn actions(events: &[main::audit::store::AuditEvent]) -> Vec<(&str, Option<&str>)> {
+    events
+        .iter()
+        .map(|e| (e.action.as_str(), e.principal.as_deref()))
+        .collect()
+}
+
+#[actix_rt::test]
+async fn test_query_filters() {
+    let store = seeded_store().await;
+
+    let all = store.query(AuditQuery::default()).await.unwrap();
+    assert_eq!(all.events.len(), 5);
+    assert!(all.events[0].id > all.events[4].id, "newest first");
+
+    let bob = store
+        .query(AuditQuery {
+            principal: Some("bob".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        actions(&bob.events),
+        [
+            ("login_succeeded", Some("bob")),
+            ("login_failed", Some("bob"))
+        ]
+    );
+
+    let failed = store
+        .query(AuditQuery {
+            action: Some("login_failed".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(failed.events.len(), 2);
+
+    let window = store
+        .query(AuditQuery {
+            from: Some(Utc::now() - Duration::days(25)),
+            to: Some(Utc::now() - Duration::days(7)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        actions(&window.events),
+        [
+            ("login_failed", Some("bob")),
+            ("login_succeeded", Some("alice"))
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn test_keyset_pagination() {
+    let store = seeded_store().await;
+    let page = |page: Option<i64>| AuditQuery {
+        page,
+        limit: Some(2),
+        ..Default::default()
+    };
+
+    let first = store.query(page(None)).await.unwrap();
+    assert_eq!(first.events.len(), 2);
+    let second = store.query(page(first.next_page)).await.unwrap();
+    assert_eq!(second.events.len(), 2);
+    let third = store.query(page(second.next_page)).await.unwrap();
+    assert_eq!(third.events.len(), 1);
+    assert_eq!(third.next_page, None);
+
+    let mut ids: Vec<i64> = [first.events, second.events, third.events]
+        .concat()
+        .iter()
+        .map(|e| e.id)
+        .collect();
+    let len = ids.len();
+    ids.dedup();
+    assert_eq!(ids.len(), len, "pages overlap");
+    assert!(ids.windows(2).all(|w| w[0] > w[1]));
+
+    // Events added meanwhile do not shift later pages
+    store
+        .insert_batch(vec![event("login_failed", "carol", 0)])
+        .await
+        .unwrap();
+    let again = store.query(page(first.next_page)).await.unwrap();
+    assert_eq!(again.events[0].id, ids[2]);
+}
+
+#[actix_rt::test]
+async fn test_purge_removes_only_expired_events() {
+    let store = seeded_store().await;
+    let purged = store
+        .purge_before(Utc::now() - Duration::days(15))
+        .await
+        .unwrap();
+    assert_eq!(purged, 2);
+
+    let remaining = store.query(AuditQuery::default()).await.unwrap();
+    assert_eq!(
+        actions(&remaining.events),
+        [
+            ("login_succeeded", Some("alice")),
+            ("login_succeeded", Some("bob")),
+            ("login_failed", Some("bob"))
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn test_recorded_events_are_written_in_background() {
+    let store = AuditStore::new(Database::open_in_memory().unwrap());
+    audit::store::install(AuditWriter::spawn(store.clone(), 100));
+
+    audit::record("password_changed", json!({ "principal": "dave" }));
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let events = loop {
+        let page = store.query(AuditQuery::default()).await.unwrap();
+        if !page.events.is_empty() || std::time::Instant::now() > deadline {
+            break page.events;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(20)).await;
+    };
+    assert_eq!(actions(&events), [("password_changed", Some("dave"))]);
+    assert_eq!(events[0].details["principal"], "dave");
+}
+
+#[actix_rt::test]
+async fn test_full_queue_drops_and_counts() {
+    let store = AuditStore::new(Database::open_in_memory().unwrap());
+    let writer = AuditWriter::spawn(store, 1);
+    let dropped = || Metrics::global().counter_value(DROPPED_METRIC, &[("reason", "queue_full")]);
+    let before = dropped();
+
+    // Nothing runs the writer task between these synchronous calls
+    for _ in 0..5 {
+        writer.enqueue(event("login_failed", "eve", 0));
+    }
+    assert_eq!(dropped(), before + 4);
+}
+
+#[actix_rt::test]
+async fn test_admin_endpoint() {
+    let store = seeded_store().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(store))
+            .route("/admin/audit", web::get().to(list_events)),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/audit?principal=alice&action=login_succeeded&limit=1")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["events"].as_array().unwrap().len(), 1);
+    assert_eq!(body["events"][0]["principal"], "alice");
+    let next = body["next_page"].as_i64().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/admin/audit?principal=alice&action=login_succeeded&limit=1&page={}",
+            next
+        ))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["events"].as_array().unwrap().len(), 1);
+    assert_eq!(body["next_page"], Value::Null);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/audit?from=yesterday")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_rt::test]
+async fn test_admin_endpoint_exports() {
+    let store = seeded_store().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(store))
+            .route("/admin/audit", web::get().to(list_events)),
+    )
+    .await;
+
+    // The page size does not limit an export
+    let req = test::TestRequest::get()
+        .uri("/admin/audit?principal=alice&limit=1&format=csv")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+    let lines: Vec<&str> = body.split_terminator("\r\n").collect();
+    assert_eq!(lines[0], "id,timestamp,action,principal,request_id,details");
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].contains(",login_succeeded,alice,,"));
+    // Details are nested JSON, quoted
+    assert!(lines[1].ends_with(r#","{""event"":""login_succeeded"",""username"":""alice""}""#));
+
+    let req = test::TestRequest::get()
+        .uri("/admin/audit?action=login_failed")
+        .insert_header(("Accept", "application/x-ndjson"))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let events: Vec<Value> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["principal"], "bob");
+}
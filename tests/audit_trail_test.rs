@@ -0,0 +1,218 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body_json, TestRequest,
+};
+use actix_web::{web, App};
+use chrono::{Duration as ChronoDuration, TimeZone, Utc};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use tempfile::TempDir;
+
+use main::admin;
+use main::audit::trail::{self, AuditEvent, TRAIL_CAPACITY};
+use main::audit::{AuditLog, Outcome};
+use main::dynamic_scope::Features;
+use main::flags::{FlagDefinition, Flags};
+
+mod common;
+
+use common::logs;
+
+fn flags() -> Flags {
+    Flags::new(vec![FlagDefinition {
+        name: "dark-launch".to_string(),
+        description: "The dark-launch flag".to_string(),
+        default: false,
+        rollout_percent: None,
+    }])
+    .unwrap()
+}
+
+fn event(action: &str, minute: u32) -> AuditEvent {
+    AuditEvent {
+        timestamp: Utc.with_ymd_and_hms(2024, 6, 1, 12, minute, 0).unwrap(),
+        admin_ip: None,
+        request_id: format!("req-{}", minute),
+        action: action.to_string(),
+        outcome: Outcome::Success,
+        detail: Value::Null,
+    }
+}
+
+macro_rules! admin_app {
+    ($log:expr) => {
+        init_service(
+            App::new()
+                .app_data($log.clone())
+                .app_data(web::Data::new(flags()))
+                .app_data(web::Data::new(Features::new(None)))
+                .service(
+                    web::scope("/admin")
+                        .configure(admin::configure)
+                        .route("/audit", web::get().to(trail::list_events)),
+                ),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn admin_actions_are_appended_to_the_file() {
+    logs::capture();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    fs::write(&path, "{\"action\":\"earlier\"}\n").unwrap();
+    let log = web::Data::new(AuditLog::open(path.to_str().unwrap()).unwrap());
+    let app = admin_app!(log);
+
+    let req = TestRequest::put()
+        .uri("/admin/flags/dark-launch")
+        .peer_addr("192.0.2.7:4321".parse().unwrap())
+        .set_json(json!({ "enabled": true, "ttl_secs": 300 }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    let req = TestRequest::post()
+        .uri("/admin/features/missing/enable")
+        .peer_addr("192.0.2.7:4321".parse().unwrap())
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["action"], "earlier");
+
+    let overridden = &lines[1];
+    assert_eq!(overridden["action"], "feature_flag_overridden");
+    assert_eq!(overridden["outcome"], "success");
+    assert_eq!(overridden["admin_ip"], "192.0.2.7");
+    assert_eq!(overridden["detail"]["flag"], "dark-launch");
+    assert!(!overridden["request_id"].as_str().unwrap().is_empty());
+    assert!(overridden["timestamp"].as_str().unwrap().contains('T'));
+
+    let toggled = &lines[2];
+    assert_eq!(toggled["action"], "feature_toggled");
+    assert_eq!(toggled["outcome"], "failure");
+    assert_eq!(toggled["detail"]["error"], "unknown_feature");
+    assert_ne!(toggled["request_id"], overridden["request_id"]);
+
+    // Still in the audit log target
+    assert!(logs::contains("feature_flag_overridden"));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        AuditLog::open(path.to_str().unwrap()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[actix_web::test]
+async fn recent_events_are_filtered_by_time_and_action() {
+    let log = web::Data::new(AuditLog::default());
+    log.append(event("feature_flag_overridden", 0));
+    log.append(event("config_applied", 10));
+    log.append(event("feature_flag_override_cleared", 20));
+    log.append(event("feature_toggled", 30));
+    let app = admin_app!(log);
+
+    let actions = |body: &Value| -> Vec<String> {
+        body["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["action"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    // Newest first
+    let req = TestRequest::get().uri("/admin/audit").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        actions(&body),
+        [
+            "feature_toggled",
+            "feature_flag_override_cleared",
+            "config_applied",
+            "feature_flag_overridden"
+        ]
+    );
+
+    let req = TestRequest::get()
+        .uri("/admin/audit?action=feature_flag")
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        actions(&body),
+        ["feature_flag_override_cleared", "feature_flag_overridden"]
+    );
+
+    let req = TestRequest::get()
+        .uri("/admin/audit?since=2024-06-01T12:10:00Z&action=feature_flag")
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(actions(&body), ["feature_flag_override_cleared"]);
+    assert_eq!(body["events"][0]["request_id"], "req-20");
+
+    // Offsets other than Z
+    let req = TestRequest::get()
+        .uri("/admin/audit?since=2024-06-01T14:25:00%2B02:00")
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(actions(&body), ["feature_toggled"]);
+
+    let req = TestRequest::get()
+        .uri("/admin/audit?since=yesterday")
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_query");
+}
+
+#[test]
+fn only_the_last_events_are_kept_in_memory() {
+    let log = AuditLog::default();
+    let start = Utc::now();
+    for i in 0..TRAIL_CAPACITY + 5 {
+        let mut event = event("config_applied", 0);
+        event.timestamp = start + ChronoDuration::seconds(i as i64);
+        event.request_id = format!("req-{}", i);
+        log.append(event);
+    }
+    let events = log.query(None, None);
+    assert_eq!(events.len(), TRAIL_CAPACITY);
+    assert_eq!(events[0].request_id, format!("req-{}", TRAIL_CAPACITY + 4));
+    assert_eq!(events[TRAIL_CAPACITY - 1].request_id, "req-5");
+}
+
+#[test]
+fn the_file_is_read_from_the_environment() {
+    let _env = common::env_lock();
+    env::remove_var("AUDIT_LOG_FILE");
+    let log = AuditLog::from_env().unwrap();
+    log.append(event("config_applied", 0));
+    assert_eq!(log.query(None, Some("config")).len(), 1);
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    env::set_var("AUDIT_LOG_FILE", &path);
+    AuditLog::from_env()
+        .unwrap()
+        .append(event("config_applied", 0));
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+    env::set_var("AUDIT_LOG_FILE", dir.path().join("missing/audit.jsonl"));
+    assert!(AuditLog::from_env().is_err());
+    env::remove_var("AUDIT_LOG_FILE");
+}
@@ -0,0 +1,273 @@
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::web::Bytes;
+use actix_web::{web, App, HttpResponse};
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use std::time::Duration;
+
+use main::clock::Clock;
+use main::middleware::aws_sigv4::{
+    canonical_request, parse_authorization, sha256_hex, sign, signing_key, string_to_sign,
+    AwsSigV4, AwsSigV4Verifier, SigV4Error, SignedRequest,
+};
+
+// Credentials and time of the examples in the AWS SigV4 documentation and
+// test suite
+const ACCESS_KEY: &str = "AKIDEXAMPLE";
+const SECRET: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+const AMZ_DATE: &str = "20150830T123600Z";
+const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn signed_at() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap()
+}
+
+fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect()
+}
+
+/// The `get-vanilla` case of the AWS SigV4 test suite.
+fn get_vanilla() -> SignedRequest {
+    SignedRequest {
+        method: "GET".to_string(),
+        path: "/".to_string(),
+        query: String::new(),
+        headers: headers(&[
+            ("host", "example.amazonaws.com"),
+            ("x-amz-date", AMZ_DATE),
+            (
+                "authorization",
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                 SignedHeaders=host;x-amz-date, \
+                 Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+            ),
+        ]),
+        body: Bytes::new(),
+    }
+}
+
+fn verifier() -> AwsSigV4Verifier {
+    AwsSigV4Verifier::new(ACCESS_KEY, SECRET, Duration::from_secs(300))
+}
+
+#[test]
+fn test_iam_documentation_example() {
+    // GET https://iam.amazonaws.com/?Action=ListUsers&Version=2010-05-08
+    let headers = headers(&[
+        (
+            "content-type",
+            "application/x-www-form-urlencoded; charset=utf-8",
+        ),
+        ("host", "iam.amazonaws.com"),
+        ("x-amz-date", AMZ_DATE),
+    ]);
+    let signed = ["content-type", "host", "x-amz-date"].map(str::to_string);
+    let canonical = canonical_request(
+        "GET",
+        "/",
+        "Version=2010-05-08&Action=ListUsers",
+        &headers,
+        &signed,
+        EMPTY_HASH,
+    );
+    assert_eq!(
+        canonical,
+        "GET\n/\nAction=ListUsers&Version=2010-05-08\n\
+         content-type:application/x-www-form-urlencoded; charset=utf-8\n\
+         host:iam.amazonaws.com\nx-amz-date:20150830T123600Z\n\n\
+         content-type;host;x-amz-date\n\
+         e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        sha256_hex(canonical.as_bytes()),
+        "f536975d06c0309214f805bb90ccff089219ecd68b2577efef23edd43b7e1a59"
+    );
+
+    let to_sign = string_to_sign(AMZ_DATE, "20150830/us-east-1/iam/aws4_request", &canonical);
+    let key = signing_key(SECRET, "20150830", "us-east-1", "iam");
+    let key_hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(
+        key_hex,
+        "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9"
+    );
+    assert_eq!(
+        sign(&key, &to_sign),
+        "5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+    );
+}
+
+#[test]
+fn test_get_vanilla_verifies() {
+    assert_eq!(
+        verifier().verify(&get_vanilla(), &Clock::fixed(signed_at())),
+        Ok(())
+    );
+    let later = signed_at() + ChronoDuration::seconds(299);
+    assert_eq!(
+        verifier().verify(&get_vanilla(), &Clock::fixed(later)),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_tampered_requests_are_rejected() {
+    let mut request = get_vanilla();
+    request.path = "/other".to_string();
+    assert_eq!(
+        verifier().verify(&request, &Clock::fixed(signed_at())),
+        Err(SigV4Error::SignatureDoesNotMatch)
+    );
+
+    let mut request = get_vanilla();
+    request.body = Bytes::from_static(b"extra");
+    assert_eq!(
+        verifier().verify(&request, &Clock::fixed(signed_at())),
+        Err(SigV4Error::SignatureDoesNotMatch)
+    );
+
+    let wrong_secret =
+        AwsSigV4Verifier::new(ACCESS_KEY, "not-the-secret", Duration::from_secs(300));
+    assert_eq!(
+        wrong_secret.verify(&get_vanilla(), &Clock::fixed(signed_at())),
+        Err(SigV4Error::SignatureDoesNotMatch)
+    );
+
+    let other_key = AwsSigV4Verifier::new("AKIDOTHER", SECRET, Duration::from_secs(300));
+    assert_eq!(
+        other_key.verify(&get_vanilla(), &Clock::fixed(signed_at())),
+        Err(SigV4Error::UnknownAccessKey)
+    );
+}
+
+#[test]
+fn test_clock_skew_is_enforced() {
+    // The window is AWS_CLOCK_SKEW_SECS plus the global CLOCK_SKEW_SECS
+    for offset in [-329, 329] {
+        let now = signed_at() + ChronoDuration::seconds(offset);
+        assert_eq!(
+            verifier().verify(&get_vanilla(), &Clock::fixed(now)),
+            Ok(())
+        );
+    }
+    for offset in [-331, 331] {
+        let now = signed_at() + ChronoDuration::seconds(offset);
+        assert_eq!(
+            verifier().verify(&get_vanilla(), &Clock::fixed(now)),
+            Err(SigV4Error::RequestExpired)
+        );
+        let strict = Clock::fixed(now - ChronoDuration::seconds(offset.signum() * 30))
+            .with_skew(Duration::ZERO);
+        assert_eq!(
+            verifier().verify(&get_vanilla(), &strict),
+            Err(SigV4Error::RequestExpired)
+        );
+    }
+}
+
+#[test]
+fn test_malformed_authorization() {
+    assert!(parse_authorization("Basic YWxpY2U6c2VjcmV0").is_err());
+    assert!(parse_authorization(
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+         SignedHeaders=x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+    )
+    .is_err());
+    assert!(parse_authorization(
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service, \
+         SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+    )
+    .is_err());
+
+    let auth = parse_authorization(
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+         SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+    )
+    .unwrap();
+    assert_eq!(auth.access_key_id, "AKIDEXAMPLE");
+    assert_eq!(auth.scope(), "20150830/us-east-1/service/aws4_request");
+    assert_eq!(auth.signed_headers, vec!["host", "x-amz-date"]);
+}
+
+/// Signs a request the way SigV4 clients do, at the current time.
+fn authorization(method: &str, path: &str, host: &str, body: &[u8]) -> (String, String) {
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let date = &amz_date[..8];
+    let headers = headers(&[("host", host), ("x-amz-date", &amz_date)]);
+    let signed = ["host", "x-amz-date"].map(str::to_string);
+    let canonical = canonical_request(method, path, "", &headers, &signed, &sha256_hex(body));
+    let scope = format!("{}/us-east-1/execute-api/aws4_request", date);
+    let signature = sign(
+        &signing_key(SECRET, date, "us-east-1", "execute-api"),
+        &string_to_sign(&amz_date, &scope, &canonical),
+    );
+    (
+        amz_date,
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-date, Signature={}",
+            ACCESS_KEY, scope, signature
+        ),
+    )
+}
+
+#[actix_rt::test]
+async fn test_middleware_verifies_signed_requests() {
+    let verifier = verifier().with_paths(vec!["/api".to_string()]);
+    let app = init_service(
+        App::new()
+            .wrap(AwsSigV4::new(verifier))
+            .route(
+                "/api/echo",
+                web::post().to(|body: Bytes| async move { HttpResponse::Ok().body(body) }),
+            )
+            .route(
+                "/public",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            ),
+    )
+    .await;
+
+    let (amz_date, auth) = authorization("POST", "/api/echo", "localhost:3000", b"payload");
+    let req = TestRequest::post()
+        .uri("/api/echo")
+        .insert_header(("Host", "localhost:3000"))
+        .insert_header(("X-Amz-Date", amz_date.as_str()))
+        .insert_header(("Authorization", auth.as_str()))
+        .set_payload("payload")
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    // The handler still receives the body that was hashed
+    assert_eq!(read_body(resp).await, "payload");
+
+    let req = TestRequest::post()
+        .uri("/api/echo")
+        .insert_header(("Host", "localhost:3000"))
+        .insert_header(("X-Amz-Date", amz_date.as_str()))
+        .insert_header(("Authorization", auth.as_str()))
+        .set_payload("tampered")
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/xml"
+    );
+    let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    assert!(
+        body.contains("<Code>SignatureDoesNotMatch</Code>"),
+        "{}",
+        body
+    );
+
+    let req = TestRequest::post().uri("/api/echo").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    assert!(body.contains("<Code>MissingAuthenticationToken</Code>"));
+
+    // Paths outside AWS_SIGV4_PATHS are not checked
+    let req = TestRequest::get().uri("/public").to_request();
+    assert_eq!(call_service(&app, req).await.status(), 200);
+}
@@ -0,0 +1,187 @@
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body, TestRequest,
+};
+use actix_web::{web, App};
+use reqwest::Url;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use main::proxy::{
+    configure_admin, BackendPool, CircuitBreaker, CircuitState, Mirror, Proxy, Strategy,
+};
+
+fn urls(n: usize) -> Vec<Url> {
+    (0..n)
+        .map(|i| Url::parse(&format!("http://backend-{}.internal/", i)).unwrap())
+        .collect()
+}
+
+fn host(guard: &main::proxy::pool::BackendGuard) -> String {
+    guard.url().host_str().unwrap().to_string()
+}
+
+/// Upstream answering every request with `status` and its own name.
+fn upstream(name: &'static str, status: u16) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(
+                format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    name.len(),
+                    name
+                )
+                .as_bytes(),
+            );
+        }
+    });
+    url
+}
+
+#[test]
+fn test_round_robin_distributes_evenly() {
+    let pool = BackendPool::new(urls(3), Strategy::RoundRobin);
+    let picked: Vec<String> = (0..6)
+        .map(|_| {
+            let guard = pool.select().unwrap();
+            let name = host(&guard);
+            guard.finish(true);
+            name
+        })
+        .collect();
+    assert_eq!(
+        picked,
+        [
+            "backend-0.internal",
+            "backend-1.internal",
+            "backend-2.internal",
+            "backend-0.internal",
+            "backend-1.internal",
+            "backend-2.internal"
+        ]
+    );
+    assert!(pool.stats().iter().all(|s| s.requests == 2));
+}
+
+#[test]
+fn test_least_connections_prefers_idle_backend() {
+    let pool = BackendPool::new(urls(3), Strategy::LeastConnections);
+    let first = pool.select().unwrap();
+    let second = pool.select().unwrap();
+    assert_ne!(host(&first), host(&second));
+    let third = pool.select().unwrap();
+    assert_eq!(host(&third), "backend-2.internal");
+    assert_eq!(pool.backends()[2].in_flight(), 1);
+
+    drop(first);
+    assert_eq!(pool.backends()[0].in_flight(), 0);
+    assert_eq!(host(&pool.select().unwrap()), "backend-0.internal");
+}
+
+#[test]
+fn test_random_selects_every_backend() {
+    let pool = BackendPool::new(urls(3), Strategy::Random);
+    for _ in 0..300 {
+        pool.select().unwrap().finish(true);
+    }
+    assert!(pool.stats().iter().all(|s| s.requests > 0));
+}
+
+#[test]
+fn test_open_circuit_is_skipped() {
+    let pool = BackendPool::with_circuit_breaker(
+        urls(2),
+        Strategy::RoundRobin,
+        1,
+        Duration::from_secs(60),
+    );
+    let guard = pool.select().unwrap();
+    assert_eq!(host(&guard), "backend-0.internal");
+    guard.finish(false);
+
+    for _ in 0..4 {
+        assert_eq!(host(&pool.select().unwrap()), "backend-1.internal");
+    }
+    let stats = pool.stats();
+    assert_eq!(stats[0].circuit, "open");
+    assert_eq!(stats[0].error_rate, 1.0);
+
+    pool.select().unwrap().finish(false);
+    assert!(pool.select().is_none());
+}
+
+#[test]
+fn test_circuit_breaker_half_open_trial() {
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+    let start = Instant::now();
+    breaker.record_failure_at(start);
+    assert_eq!(breaker.state_at(start), CircuitState::Closed);
+    breaker.record_failure_at(start);
+    assert_eq!(breaker.state_at(start), CircuitState::Open);
+    assert!(!breaker.allow_at(start));
+
+    // One trial request after the open period
+    let later = start + Duration::from_secs(10);
+    assert_eq!(breaker.state_at(later), CircuitState::HalfOpen);
+    assert!(breaker.allow_at(later));
+    assert!(!breaker.allow_at(later));
+
+    // A failed trial reopens the circuit, a successful one closes it
+    breaker.record_failure_at(later);
+    assert_eq!(breaker.state_at(later), CircuitState::Open);
+    let much_later = later + Duration::from_secs(10);
+    assert!(breaker.allow_at(much_later));
+    breaker.record_success();
+    assert_eq!(breaker.state_at(much_later), CircuitState::Closed);
+}
+
+#[actix_rt::test]
+async fn test_proxy_balances_and_reports_stats() {
+    let pool = BackendPool::new(
+        vec![
+            upstream("one", 200),
+            upstream("two", 200),
+            upstream("three", 500),
+        ],
+        Strategy::RoundRobin,
+    );
+    let proxy = web::Data::new(Proxy::with_pool(
+        "/proxy",
+        pool,
+        Mirror::new(None, Duration::from_secs(5)),
+    ));
+    let app = init_service(
+        App::new()
+            .configure(Proxy::configure(proxy.clone()))
+            .service(web::scope("/admin").configure(configure_admin)),
+    )
+    .await;
+
+    let mut bodies = Vec::new();
+    for _ in 0..6 {
+        let req = TestRequest::get().uri("/proxy/item").to_request();
+        let resp = call_service(&app, req).await;
+        bodies.push(String::from_utf8(read_body(resp).await.to_vec()).unwrap());
+    }
+    assert_eq!(bodies, ["one", "two", "three", "one", "two", "three"]);
+
+    let req = TestRequest::get().uri("/admin/backends/stats").to_request();
+    let stats: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(stats["strategy"], "round_robin");
+    let backends = stats["backends"].as_array().unwrap();
+    assert_eq!(backends.len(), 3);
+    for backend in backends {
+        assert_eq!(backend["requests"], 2);
+        assert_eq!(backend["in_flight"], 0);
+    }
+    assert_eq!(backends[0]["errors"], 0);
+    assert_eq!(backends[2]["errors"], 2);
+    assert_eq!(backends[2]["error_rate"], 1.0);
+    assert_eq!(backends[2]["circuit"], "closed");
+}
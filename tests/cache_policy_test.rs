@@ -0,0 +1,174 @@
+use actix_web::cookie::Cookie;
+use actix_web::http::header;
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use std::env;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use main::middleware::cache_policy::{parse_rules, CacheDirective, CachePolicy, CacheRule};
+use main::route_meta::{MetadataMap, RouteMetadata};
+
+mod common;
+
+const RULES: &str =
+    "/admin=no-store,/static=public:3600,/api/profile=private,/api=no-store,/api/catalog=public:60";
+
+macro_rules! policy_app {
+    () => {{
+        let mut routes = MetadataMap::new();
+        routes.insert(
+            "GET",
+            "/account/{id}",
+            RouteMetadata::new("Account").auth_required(),
+        );
+        init_service(
+            App::new()
+                .app_data(web::Data::new(routes))
+                .wrap(CachePolicy::new(parse_rules(RULES).unwrap()))
+                .route(
+                    "/static/own",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((header::CACHE_CONTROL, "public, max-age=5"))
+                            .finish()
+                    }),
+                )
+                .route(
+                    "/static/login",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .cookie(Cookie::new("session", "abc"))
+                            .insert_header((header::CACHE_CONTROL, "public, max-age=5"))
+                            .finish()
+                    }),
+                )
+                .route(
+                    "/account/{id}",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .route(
+                    "/static/secret",
+                    web::get().to(|| async { HttpResponse::Unauthorized().finish() }),
+                )
+                .default_service(web::to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await
+    }};
+}
+
+/// Sends `req` and returns its `Cache-Control`, if any.
+macro_rules! cache_control {
+    ($app:expr, $req:expr) => {{
+        let resp = call_service(&$app, $req.to_request()).await;
+        resp.headers()
+            .get(header::CACHE_CONTROL)
+            .map(|v| v.to_str().unwrap().to_string())
+    }};
+}
+
+#[actix_rt::test]
+async fn each_rule_class_is_applied() {
+    let app = policy_app!();
+    let get = |uri: &str| TestRequest::get().uri(uri);
+
+    assert_eq!(
+        cache_control!(app, get("/admin/flags")).unwrap(),
+        "no-store"
+    );
+    assert_eq!(
+        cache_control!(app, get("/static/app.css")).unwrap(),
+        "public, max-age=3600"
+    );
+    assert_eq!(cache_control!(app, get("/api/profile")).unwrap(), "private");
+    // The longest matching prefix wins
+    assert_eq!(
+        cache_control!(app, get("/api/catalog/7")).unwrap(),
+        "public, max-age=60"
+    );
+    assert_eq!(cache_control!(app, get("/api/orders")).unwrap(), "no-store");
+    // Prefixes match whole segments, and unmatched paths are left alone
+    assert_eq!(cache_control!(app, get("/administrator")), None);
+    assert_eq!(cache_control!(app, get("/hello")), None);
+    // Public rules only apply to reads
+    assert_eq!(
+        cache_control!(app, TestRequest::post().uri("/api/catalog/7")),
+        None
+    );
+}
+
+#[actix_rt::test]
+async fn handler_headers_take_precedence() {
+    let app = policy_app!();
+    let req = TestRequest::get().uri("/static/own");
+    assert_eq!(cache_control!(app, req).unwrap(), "public, max-age=5");
+}
+
+#[actix_rt::test]
+async fn cookies_and_credentials_force_no_store() {
+    let app = policy_app!();
+
+    // Even over the handler's own header
+    let req = TestRequest::get().uri("/static/login");
+    assert_eq!(cache_control!(app, req).unwrap(), "no-store");
+    // A route documented as requiring credentials
+    let req = TestRequest::get().uri("/account/7");
+    assert_eq!(cache_control!(app, req).unwrap(), "no-store");
+    // A request carrying credentials under a public rule
+    let req = TestRequest::get()
+        .uri("/static/app.css")
+        .insert_header((header::AUTHORIZATION, "Bearer token"));
+    assert_eq!(cache_control!(app, req).unwrap(), "no-store");
+    let req = TestRequest::get()
+        .uri("/static/app.css")
+        .insert_header(("X-API-Key", "key"));
+    assert_eq!(cache_control!(app, req).unwrap(), "no-store");
+    // An authentication challenge
+    let req = TestRequest::get().uri("/static/secret");
+    assert_eq!(cache_control!(app, req).unwrap(), "no-store");
+}
+
+#[test]
+fn rules_are_parsed_and_validated() {
+    assert_eq!(
+        parse_rules(" /static = public:86400 , /me=PRIVATE").unwrap(),
+        vec![
+            CacheRule {
+                prefix: "/static".to_string(),
+                directive: CacheDirective::Public(Duration::from_secs(86400)),
+            },
+            CacheRule {
+                prefix: "/me".to_string(),
+                directive: CacheDirective::Private,
+            },
+        ]
+    );
+    for invalid in [
+        "/static",
+        "static=no-store",
+        "/x=public",
+        "/x=public:soon",
+        "/x=max-age",
+    ] {
+        let err = parse_rules(invalid).expect_err(invalid);
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn admin_and_auth_are_no_store_by_default() {
+    let _env = common::env_lock();
+    env::remove_var("CACHE_POLICY_RULES");
+    let policy = CachePolicy::from_env().unwrap();
+    for path in ["/admin/routes", "/auth/login"] {
+        assert_eq!(
+            policy.rule_for(path).unwrap().directive,
+            CacheDirective::NoStore
+        );
+    }
+    assert!(policy.rule_for("/static/app.css").is_none());
+
+    env::set_var("CACHE_POLICY_RULES", "/admin=sometimes");
+    assert!(CachePolicy::from_env().is_err());
+    env::remove_var("CACHE_POLICY_RULES");
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body, TestRequest,
+};
+use actix_web::web::Bytes;
+use actix_web::{web, App, HttpMessage, HttpResponse};
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use main::cache::{self, CacheStore, CachedResponse, MultiLayerCache, RedisStore, CACHE_HEADER};
+use main::middleware::tenant::Tenant;
+
+/// An in-memory L2 that can be made to fail like an unreachable Redis.
+#[derive(Default)]
+struct MockRedis {
+    entries: Mutex<HashMap<String, Vec<u8>>>,
+    down: AtomicBool,
+}
+
+#[async_trait]
+impl CacheStore for MockRedis {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, IoError> {
+        if self.down.load(Ordering::SeqCst) {
+            return Err(IoError::new(ErrorKind::ConnectionRefused, "redis is down"));
+        }
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    async fn set(&self, key: &str, value: &[u8], _: Duration) -> Result<(), IoError> {
+        if self.down.load(Ordering::SeqCst) {
+            return Err(IoError::new(ErrorKind::ConnectionRefused, "redis is down"));
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+}
+
+fn cache_with(l1_size: usize, l2: Option<Arc<MockRedis>>) -> MultiLayerCache {
+    MultiLayerCache::new(
+        vec!["/catalog".to_string()],
+        NonZeroUsize::new(l1_size).unwrap(),
+        Duration::from_secs(60),
+        l2.map(|l2| l2 as Arc<dyn CacheStore>),
+    )
+}
+
+macro_rules! counting_app {
+    ($cache:expr, $calls:expr) => {{
+        let calls = $calls.clone();
+        init_service(
+            App::new()
+                .wrap($cache.clone())
+                .default_service(web::to(move || {
+                    let calls = calls.clone();
+                    async move {
+                        let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                        HttpResponse::Ok()
+                            .content_type("application/json")
+                            .body(format!("{{\"call\":{}}}", n))
+                    }
+                })),
+        )
+        .await
+    }};
+}
+
+/// Fetches `uri`, returning the `X-Cache` header and the body.
+macro_rules! get {
+    ($app:expr, $uri:expr) => {{
+        let req = TestRequest::get().uri($uri).to_request();
+        let resp = call_service(&$app, req).await;
+        let tier = resp
+            .headers()
+            .get(CACHE_HEADER)
+            .map(|v| v.to_str().unwrap().to_string());
+        (tier.unwrap_or_default(), read_body(resp).await)
+    }};
+}
+
+#[actix_rt::test]
+async fn l1_answers_repeated_requests() {
+    let cache = cache_with(10, None);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = counting_app!(cache, calls);
+
+    assert_eq!(get!(app, "/catalog/1").0, "MISS");
+    let (tier, body) = get!(app, "/catalog/1");
+    assert_eq!(tier, "L1");
+    assert_eq!(body, "{\"call\":1}");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Another query string is another entry
+    assert_eq!(get!(app, "/catalog/1?page=2").0, "MISS");
+    assert_eq!(cache.stats().l1_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.stats().misses.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn l2_is_shared_between_instances() {
+    let redis = Arc::new(MockRedis::default());
+    let calls = Arc::new(AtomicUsize::new(0));
+    let first = cache_with(10, Some(redis.clone()));
+    let second = cache_with(10, Some(redis.clone()));
+    let first_app = counting_app!(first, calls);
+    let second_app = counting_app!(second, calls);
+
+    assert_eq!(get!(first_app, "/catalog/1").0, "MISS");
+    assert_eq!(redis.entries.lock().unwrap().len(), 1);
+
+    // The second instance's L1 is empty; L2 answers, then L1 holds a copy
+    let (tier, body) = get!(second_app, "/catalog/1");
+    assert_eq!(tier, "L2");
+    assert_eq!(body, "{\"call\":1}");
+    assert_eq!(get!(second_app, "/catalog/1").0, "L1");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(second.stats().l2_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(second.stats().l1_hits.load(Ordering::SeqCst), 1);
+    assert_eq!(second.stats().misses.load(Ordering::SeqCst), 0);
+}
+
+#[actix_rt::test]
+async fn l1_evicts_the_least_recently_used_entry() {
+    let cache = cache_with(2, None);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = counting_app!(cache, calls);
+
+    get!(app, "/catalog/a");
+    get!(app, "/catalog/b");
+    assert_eq!(get!(app, "/catalog/a").0, "L1");
+    get!(app, "/catalog/c");
+    assert_eq!(cache.stats().l1_evictions.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.l1_len(), 2);
+
+    // b was the least recently used
+    assert_eq!(get!(app, "/catalog/a").0, "L1");
+    assert_eq!(get!(app, "/catalog/b").0, "MISS");
+}
+
+#[actix_rt::test]
+async fn redis_failures_fall_back_to_l1() {
+    let redis = Arc::new(MockRedis::default());
+    redis.down.store(true, Ordering::SeqCst);
+    let cache = cache_with(10, Some(redis.clone()));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = counting_app!(cache, calls);
+
+    assert_eq!(get!(app, "/catalog/1").0, "MISS");
+    assert_eq!(get!(app, "/catalog/1").0, "L1");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.stats().l2_errors.load(Ordering::SeqCst), 2);
+    assert!(redis.entries.lock().unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn private_requests_and_responses_are_not_cached() {
+    let cache = cache_with(10, None);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = counting_app!(cache, calls);
+
+    for _ in 0..2 {
+        let req = TestRequest::get()
+            .uri("/catalog/1")
+            .insert_header((header::AUTHORIZATION, "Bearer token"))
+            .to_request();
+        call_service(&app, req).await;
+        let req = TestRequest::post().uri("/catalog/1").to_request();
+        call_service(&app, req).await;
+        get!(app, "/elsewhere");
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 6);
+    assert_eq!(cache.l1_len(), 0);
+
+    let app = init_service(App::new().wrap(cache.clone()).route(
+        "/catalog/session",
+        web::get().to(|| async {
+            HttpResponse::Ok()
+                .insert_header((header::SET_COOKIE, "session=1"))
+                .body("private")
+        }),
+    ))
+    .await;
+    get!(app, "/catalog/session");
+    assert_eq!(get!(app, "/catalog/session").0, "MISS");
+}
+
+#[actix_rt::test]
+async fn hits_replay_the_response_headers() {
+    let cache = cache_with(10, Some(Arc::new(MockRedis::default())));
+    let app = init_service(App::new().wrap(cache.clone()).route(
+        "/catalog/doc",
+        web::get().to(|| async {
+            HttpResponse::Ok()
+                .content_type("text/plain")
+                .insert_header((header::CONTENT_ENCODING, "identity"))
+                .insert_header((header::CONTENT_LANGUAGE, "de"))
+                .insert_header((header::ETAG, "\"v1\""))
+                .insert_header((header::VARY, "Accept-Language"))
+                .insert_header((header::LOCATION, "/catalog/doc/de"))
+                .body("hallo")
+        }),
+    ))
+    .await;
+
+    get!(app, "/catalog/doc");
+    let req = TestRequest::get().uri("/catalog/doc").to_request();
+    let resp = call_service(&app, req).await;
+    let headers = resp.headers();
+    assert_eq!(headers.get(CACHE_HEADER).unwrap(), "L1");
+    assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "text/plain");
+    assert_eq!(headers.get(header::CONTENT_ENCODING).unwrap(), "identity");
+    assert_eq!(headers.get(header::CONTENT_LANGUAGE).unwrap(), "de");
+    assert_eq!(headers.get(header::ETAG).unwrap(), "\"v1\"");
+    assert_eq!(headers.get(header::VARY).unwrap(), "Accept-Language");
+    assert_eq!(headers.get(header::LOCATION).unwrap(), "/catalog/doc/de");
+    assert_eq!(headers.get_all(CACHE_HEADER).count(), 1);
+}
+
+#[actix_rt::test]
+async fn responses_varying_on_other_headers_are_not_cached() {
+    let cache = cache_with(10, None);
+    let app = init_service(
+        App::new()
+            .wrap(cache.clone())
+            .route(
+                "/catalog/encoded",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header((header::VARY, "Accept-Language, Accept-Encoding"))
+                        .body("varies")
+                }),
+            )
+            .route(
+                "/catalog/any",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header((header::VARY, "*"))
+                        .body("varies")
+                }),
+            ),
+    )
+    .await;
+
+    for uri in ["/catalog/encoded", "/catalog/any"] {
+        get!(app, uri);
+        assert_eq!(get!(app, uri).0, "MISS", "{}", uri);
+    }
+    assert_eq!(cache.l1_len(), 0);
+}
+
+#[actix_rt::test]
+async fn keys_are_scoped_by_tenant() {
+    let cache = cache_with(10, None);
+    let req = TestRequest::get().uri("/catalog/1?page=2").to_srv_request();
+    let plain = cache.key(&req).unwrap();
+    req.extensions_mut().insert(Tenant {
+        id: "acme".to_string(),
+    });
+    let scoped = cache.key(&req).unwrap();
+    assert_ne!(plain, scoped);
+    assert!(scoped.contains("acme"));
+    assert!(scoped.ends_with(&plain));
+}
+
+#[actix_rt::test]
+async fn stats_are_broken_down_by_tier() {
+    let redis = Arc::new(MockRedis::default());
+    let cache = cache_with(10, Some(redis));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = counting_app!(cache, calls);
+    get!(app, "/catalog/1");
+    get!(app, "/catalog/1");
+
+    let admin = init_service(
+        App::new()
+            .app_data(web::Data::new(cache.clone()))
+            .route("/admin/cache/stats", web::get().to(cache::stats)),
+    )
+    .await;
+    let req = TestRequest::get().uri("/admin/cache/stats").to_request();
+    let body: Value = call_and_read_body_json(&admin, req).await;
+    assert_eq!(body["l1"]["hits"], 1);
+    assert_eq!(body["l1"]["evictions"], 0);
+    assert_eq!(body["l1"]["entries"], 1);
+    assert_eq!(body["l2"]["enabled"], true);
+    assert_eq!(body["l2"]["hits"], 0);
+    assert_eq!(body["misses"], 1);
+}
+
+#[test]
+fn entries_round_trip_through_l2_encoding() {
+    let entry = CachedResponse {
+        headers: vec![
+            (
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            ),
+            (header::ETAG, header::HeaderValue::from_static("\"v1\"")),
+            (
+                header::VARY,
+                header::HeaderValue::from_static("Accept-Language"),
+            ),
+        ],
+        body: Bytes::from_static(b"{\"a\":1}"),
+        expires_at_ms: 1_700_000_000_000,
+    };
+    assert_eq!(CachedResponse::decode(&entry.encode()), Some(entry.clone()));
+    assert_eq!(CachedResponse::decode(b"short"), None);
+    // Truncated inside the headers
+    assert_eq!(CachedResponse::decode(&entry.encode()[..20]), None);
+}
+
+/// Serves `GET` and `SET` over RESP from a map, like a tiny Redis.
+async fn fake_redis() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let entries = Arc::new(Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
+    actix_rt::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let entries = entries.clone();
+            actix_rt::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let count: usize = line[1..].trim().parse().unwrap();
+                    let mut args = Vec::new();
+                    for _ in 0..count {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        let len: usize = line[1..].trim().parse().unwrap();
+                        let mut arg = vec![0; len + 2];
+                        stream.read_exact(&mut arg).await.unwrap();
+                        arg.truncate(len);
+                        args.push(arg);
+                    }
+                    let reply = match args[0].as_slice() {
+                        b"SET" => {
+                            entries
+                                .lock()
+                                .unwrap()
+                                .insert(args[1].clone(), args[2].clone());
+                            b"+OK\r\n".to_vec()
+                        }
+                        b"GET" => match entries.lock().unwrap().get(&args[1]) {
+                            Some(value) => {
+                                let mut reply = format!("${}\r\n", value.len()).into_bytes();
+                                reply.extend_from_slice(value);
+                                reply.extend_from_slice(b"\r\n");
+                                reply
+                            }
+                            None => b"$-1\r\n".to_vec(),
+                        },
+                        _ => b"-ERR unknown command\r\n".to_vec(),
+                    };
+                    stream.get_mut().write_all(&reply).await.unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+#[actix_rt::test]
+async fn redis_store_speaks_resp() {
+    let store = RedisStore::new(&fake_redis().await, Duration::from_secs(1));
+    assert_eq!(store.get("missing").await.unwrap(), None);
+    store
+        .set("key", b"binary\r\nvalue", Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.get("key").await.unwrap().as_deref(),
+        Some(&b"binary\r\nvalue"[..])
+    );
+    assert!(store.command(&[b"FLUSHALL"]).await.is_err());
+    // The error reply left the connection usable
+    assert!(store.get("key").await.unwrap().is_some());
+}
+
+#[actix_rt::test]
+async fn oversized_redis_replies_are_malformed() {
+    let reply = format!("${}\r\n", cache::MAX_ENTRY_BYTES + 1);
+    let err = cache::redis::read_reply(&mut reply.as_bytes())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let reply = format!("${}\r\n", i64::MAX);
+    assert!(cache::redis::read_reply(&mut reply.as_bytes())
+        .await
+        .is_err());
+}
+
+#[actix_rt::test]
+async fn unreachable_redis_fails_fast() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let store = RedisStore::new(&addr, Duration::from_millis(200));
+    assert!(store.get("key").await.is_err());
+}
+
+#[actix_rt::test]
+async fn hits_carry_the_age_of_the_entry() {
+    let redis = Arc::new(MockRedis::default());
+    let calls = Arc::new(AtomicUsize::new(0));
+    let cache = cache_with(10, Some(redis.clone()));
+    let app = counting_app!(cache, calls);
+
+    let req = TestRequest::get().uri("/catalog/1").to_request();
+    let resp = call_service(&app, req).await;
+    assert!(!resp.headers().contains_key(header::AGE));
+    let req = TestRequest::get().uri("/catalog/1").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.headers().get(header::AGE).unwrap(), "0");
+
+    // An entry another instance stored 20 seconds into its 60
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let entry = CachedResponse {
+        headers: Vec::new(),
+        body: Bytes::from_static(b"older"),
+        expires_at_ms: now_ms + 40_000,
+    };
+    redis
+        .entries
+        .lock()
+        .unwrap()
+        .insert("cache:GET /catalog/2 ".to_string(), entry.encode());
+    let req = TestRequest::get().uri("/catalog/2").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.headers().get(CACHE_HEADER).unwrap(), "L2");
+    let age: u64 = resp
+        .headers()
+        .get(header::AGE)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((19..=20).contains(&age), "age {}", age);
+}
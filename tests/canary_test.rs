@@ -0,0 +1,233 @@
+use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App};
+use chrono::Utc;
+use reqwest::Url;
+use ring::hmac;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use main::flags::{FlagDefinition, Flags};
+use main::proxy::canary::{sign, DEFAULT_STICKY_COOKIE};
+use main::proxy::{
+    BackendPool, Canary, CanaryConfig, CanaryMatch, Mirror, Proxy, Strategy, Upstream,
+};
+
+/// Upstream answering every request with 200 and its own name.
+fn upstream(name: &'static str) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    name.len(),
+                    name
+                )
+                .as_bytes(),
+            );
+        }
+    });
+    url
+}
+
+fn proxy(config: CanaryConfig) -> web::Data<Proxy> {
+    let proxy = Proxy::new(
+        "/proxy",
+        upstream("primary"),
+        Mirror::new(None, Duration::from_secs(5)),
+    );
+    web::Data::new(proxy.with_canary(Canary::new(config)))
+}
+
+fn kill_switch() -> web::Data<Flags> {
+    web::Data::new(
+        Flags::new(vec![FlagDefinition {
+            name: "canary".to_string(),
+            description: "Send traffic to the canary".to_string(),
+            default: true,
+            rollout_percent: None,
+        }])
+        .unwrap(),
+    )
+}
+
+macro_rules! app {
+    ($proxy:expr, $flags:expr) => {
+        init_service(
+            App::new()
+                .app_data($flags.clone())
+                .configure(Proxy::configure($proxy.clone())),
+        )
+        .await
+    };
+}
+
+#[test]
+fn test_signed_values_need_the_key() {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"canary signing key");
+    let matcher = CanaryMatch::Signed(key.clone());
+
+    let value = sign(&key, "tester-1");
+    assert!(value.starts_with("tester-1."));
+    assert!(matcher.matches(&value));
+    assert!(!matcher.matches("tester-1"));
+    assert!(!matcher.matches(&value.replace("tester-1", "tester-2")));
+
+    let other = hmac::Key::new(hmac::HMAC_SHA256, b"another key");
+    assert!(!matcher.matches(&sign(&other, "tester-1")));
+}
+
+#[actix_rt::test]
+async fn test_header_selects_canary() {
+    let mut config = CanaryConfig::new(upstream("canary"));
+    config.header = Some("X-Canary".to_string());
+    config.matcher = Some(CanaryMatch::Exact("always".to_string()));
+    config.routes = vec!["/api".to_string()];
+    let app = app!(proxy(config), kill_switch());
+
+    let req = TestRequest::get()
+        .uri("/proxy/api/items")
+        .insert_header(("X-Canary", "always"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "canary");
+
+    let req = TestRequest::get()
+        .uri("/proxy/api/items")
+        .insert_header(("X-Canary", "never"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "primary");
+
+    let req = TestRequest::get()
+        .uri("/proxy/other")
+        .insert_header(("X-Canary", "always"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "primary");
+}
+
+#[actix_rt::test]
+async fn test_percentage_assignment_is_sticky() {
+    let mut config = CanaryConfig::new(upstream("canary"));
+    config.percent = 50;
+    let app = app!(proxy(config), kill_switch());
+
+    let mut seen = HashSet::new();
+    for _ in 0..30 {
+        let req = TestRequest::get().uri("/proxy/page").to_request();
+        let resp = call_service(&app, req).await;
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == DEFAULT_STICKY_COOKIE)
+            .expect("sticky cookie is set")
+            .into_owned();
+        assert_eq!(cookie.path(), Some("/proxy"));
+        let first = read_body(resp).await;
+
+        for _ in 0..3 {
+            let req = TestRequest::get()
+                .uri("/proxy/page")
+                .cookie(cookie.clone())
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.response().cookies().count(), 0);
+            assert_eq!(read_body(resp).await, first);
+        }
+        seen.insert(first);
+    }
+    assert_eq!(seen.len(), 2, "both upstreams serve some callers");
+}
+
+#[actix_rt::test]
+async fn test_kill_switch_sends_everything_to_primary() {
+    let mut config = CanaryConfig::new(upstream("canary"));
+    config.header = Some("X-Canary".to_string());
+    config.matcher = Some(CanaryMatch::Exact("always".to_string()));
+    config.percent = 100;
+    config.flag = Some("canary".to_string());
+    let flags = kill_switch();
+    let app = app!(proxy(config), flags);
+
+    let req = TestRequest::get()
+        .uri("/proxy/x")
+        .insert_header(("X-Canary", "always"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "canary");
+
+    flags
+        .set_override("canary", false, Duration::from_secs(60), Utc::now())
+        .unwrap();
+    let req = TestRequest::get()
+        .uri("/proxy/x")
+        .insert_header(("X-Canary", "always"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "primary");
+    let req = TestRequest::get().uri("/proxy/x").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.response().cookies().count(), 0);
+    assert_eq!(read_body(resp).await, "primary");
+
+    flags.clear_override("canary").unwrap();
+    let req = TestRequest::get()
+        .uri("/proxy/x")
+        .insert_header(("X-Canary", "always"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "canary");
+}
+
+#[actix_rt::test]
+async fn test_unhealthy_canary_falls_back_to_primary() {
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", closed.local_addr().unwrap())).unwrap();
+    drop(closed);
+    let mut config = CanaryConfig::new(url.clone());
+    config.header = Some("X-Canary".to_string());
+    config.matcher = Some(CanaryMatch::Exact("always".to_string()));
+    let canary = Canary::with_pool(
+        config,
+        BackendPool::with_circuit_breaker(
+            vec![url],
+            Strategy::RoundRobin,
+            1,
+            Duration::from_secs(60),
+        ),
+    );
+    let proxy = web::Data::new(
+        Proxy::new(
+            "/proxy",
+            upstream("primary"),
+            Mirror::new(None, Duration::from_secs(5)),
+        )
+        .with_canary(canary),
+    );
+    let app = app!(proxy, kill_switch());
+
+    let request = || {
+        TestRequest::get()
+            .uri("/proxy/x")
+            .insert_header(("X-Canary", "always"))
+            .to_request()
+    };
+    let resp = call_service(&app, request()).await;
+    assert_eq!(resp.status(), 502);
+
+    assert_eq!(call_and_read_body(&app, request()).await, "primary");
+    assert_eq!(
+        proxy
+            .canary()
+            .unwrap()
+            .select(
+                &TestRequest::get()
+                    .insert_header(("X-Canary", "always"))
+                    .to_http_request(),
+                "/x"
+            )
+            .upstream,
+        Upstream::Canary
+    );
+}
@@ -0,0 +1,124 @@
+#![cfg(feature = "clamav")]
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use main::scan::clamav::{parse_reply, ClamdConfig, ClamdScanner};
+use main::scan::{ContentScanner, ScanVerdict};
+
+const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+/// A clamd answering one `INSTREAM` per connection: infected if the stream
+/// contains the EICAR test string, clean otherwise.
+async fn stub_clamd() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut command = [0u8; 10];
+                socket.read_exact(&mut command).await.unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = Vec::new();
+                loop {
+                    let length = socket.read_u32().await.unwrap() as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let start = data.len();
+                    data.resize(start + length, 0);
+                    socket.read_exact(&mut data[start..]).await.unwrap();
+                }
+                let reply: &[u8] = if data.windows(EICAR.len()).any(|w| w == EICAR) {
+                    b"stream: Eicar-Test-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                socket.write_all(reply).await.unwrap();
+            });
+        }
+    });
+    address
+}
+
+fn file_with(contents: &[u8]) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(contents).unwrap();
+    file
+}
+
+fn scanner(address: SocketAddr, timeout: Duration) -> ClamdScanner {
+    ClamdScanner::new(ClamdConfig {
+        address: address.to_string(),
+        timeout,
+    })
+}
+
+#[actix_rt::test]
+async fn instream_verdicts_are_reported() {
+    let scanner = scanner(stub_clamd().await, Duration::from_secs(5));
+
+    // Larger than one chunk, so the stream spans several
+    let mut clean = vec![b'a'; 200 * 1024];
+    let file = file_with(&clean);
+    assert_eq!(scanner.scan(file.path()).await, ScanVerdict::Clean);
+
+    clean.extend_from_slice(EICAR);
+    let file = file_with(&clean);
+    assert_eq!(
+        scanner.scan(file.path()).await,
+        ScanVerdict::Infected("Eicar-Test-Signature".to_string())
+    );
+
+    let file = file_with(b"");
+    assert_eq!(scanner.scan(file.path()).await, ScanVerdict::Clean);
+}
+
+#[actix_rt::test]
+async fn unreachable_or_silent_clamd_is_unavailable() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+    let file = file_with(b"data");
+    let verdict = scanner(address, Duration::from_secs(5))
+        .scan(file.path())
+        .await;
+    assert!(
+        matches!(verdict, ScanVerdict::Unavailable(_)),
+        "{:?}",
+        verdict
+    );
+
+    // Accepts, but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let _held = tokio::spawn(async move {
+        let (_socket, _) = listener.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+    let verdict = scanner(address, Duration::from_millis(300))
+        .scan(file.path())
+        .await;
+    match verdict {
+        ScanVerdict::Unavailable(reason) => assert!(reason.contains("did not answer")),
+        other => panic!("unexpected verdict {:?}", other),
+    }
+}
+
+#[test]
+fn replies_are_parsed() {
+    assert_eq!(parse_reply("stream: OK"), ScanVerdict::Clean);
+    assert_eq!(
+        parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND"),
+        ScanVerdict::Infected("Win.Test.EICAR_HDB-1".to_string())
+    );
+    assert_eq!(
+        parse_reply("INSTREAM size limit exceeded. ERROR"),
+        ScanVerdict::Unavailable("clamd replied 'INSTREAM size limit exceeded. ERROR'".to_string())
+    );
+    assert!(matches!(parse_reply(""), ScanVerdict::Unavailable(_)));
+}
@@ -0,0 +1,95 @@
+use chrono::{TimeZone, Utc};
+use std::env;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+
+use main::auth::file::{hash_password, FileBackend};
+use main::auth::reset::PasswordReset;
+use main::auth::AuthBackend;
+use main::clock::{Clock, MockClock, DEFAULT_CLOCK_SKEW};
+use main::master_key::MasterKey;
+
+mod common;
+
+fn start() -> chrono::DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap()
+}
+
+#[test]
+fn expiry_allows_the_skew() {
+    let clock = MockClock::new(start());
+    let expires_at = start();
+
+    // Valid until the expiry plus 30 seconds
+    clock.advance(Duration::from_secs(29));
+    assert!(!clock.clock().has_expired(expires_at));
+    assert!(!clock.clock().has_expired_at(expires_at.timestamp()));
+    clock.advance(Duration::from_secs(1));
+    assert!(clock.clock().has_expired(expires_at));
+    assert!(clock.clock().has_expired_at(expires_at.timestamp()));
+
+    // Without tolerance, expired at the instant itself
+    let strict = Clock::fixed(expires_at).with_skew(Duration::ZERO);
+    assert!(strict.has_expired(expires_at));
+}
+
+#[test]
+fn not_before_allows_the_skew() {
+    let not_before = start() + chrono::Duration::seconds(30);
+    let clock = MockClock::new(start());
+    assert!(!clock.clock().is_before(not_before));
+    clock.set(start() - chrono::Duration::seconds(1));
+    assert!(clock.clock().is_before(not_before));
+}
+
+#[test]
+fn freshness_window_allows_the_skew_both_ways() {
+    let window = Duration::from_secs(60);
+    for offset in [-90, 90] {
+        let at = start() + chrono::Duration::seconds(offset);
+        assert!(Clock::fixed(start()).is_within(at, window));
+    }
+    for offset in [-91, 91] {
+        let at = start() + chrono::Duration::seconds(offset);
+        assert!(!Clock::fixed(start()).is_within(at, window));
+    }
+}
+
+#[actix_rt::test]
+async fn reset_tokens_are_accepted_just_inside_the_skew() {
+    let users = format!("alice:{}:admin\n", hash_password("old password 123"));
+    let backend: Arc<dyn AuthBackend> = Arc::new(FileBackend::parse(&users).unwrap());
+    let key = MasterKey::new(&[7u8; 32]).unwrap();
+    let clock = MockClock::new(start());
+    let reset = PasswordReset::new(backend, &key, Duration::from_secs(60))
+        .with_clock(clock.clock().with_skew(Duration::from_secs(10)));
+
+    // Expires 60 seconds after issue, accepted for 10 more
+    let late = reset.issue("alice").await.unwrap();
+    let too_late = reset.issue("alice").await.unwrap();
+    clock.advance(Duration::from_secs(69));
+    assert!(reset.confirm(&late, "new password 456").await.is_ok());
+    clock.advance(Duration::from_secs(1));
+    let err = reset
+        .confirm(&too_late, "new password 789")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), "invalid_token");
+    assert!(err.message().contains("expired"));
+}
+
+#[test]
+fn skew_is_read_from_the_environment() {
+    let _env = common::env_lock();
+    env::remove_var("CLOCK_SKEW_SECS");
+    assert_eq!(Clock::from_env().unwrap().skew(), DEFAULT_CLOCK_SKEW);
+
+    env::set_var("CLOCK_SKEW_SECS", "5");
+    assert_eq!(Clock::from_env().unwrap().skew(), Duration::from_secs(5));
+
+    env::set_var("CLOCK_SKEW_SECS", "soon");
+    let err = Clock::from_env().err().expect("invalid skew");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    env::remove_var("CLOCK_SKEW_SECS");
+}
@@ -0,0 +1,51 @@
+//! In-memory logger for asserting on log output.
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::{Mutex, Once};
+
+static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        RECORDS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the capturing logger for this test binary (idempotent).
+pub fn capture() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CaptureLogger).expect("Logger already installed");
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+/// Returns `true` if any captured record contains `needle`.
+pub fn contains(needle: &str) -> bool {
+    RECORDS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|record| record.contains(needle))
+}
+
+/// Returns how many captured records contain `needle`.
+pub fn count(needle: &str) -> usize {
+    RECORDS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|record| record.contains(needle))
+        .count()
+}
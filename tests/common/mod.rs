@@ -0,0 +1,137 @@
+//! Shared helpers for integration tests.
+#![allow(dead_code)]
+
+pub mod logs;
+pub mod test_utils;
+
+use rcgen::{
+    BasicConstraints, Certificate as RcgenCertificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa,
+};
+use reqwest::{Client, ClientBuilder};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Serializes tests that read or write process environment variables.
+pub fn env_lock() -> MutexGuard<'static, ()> {
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+    ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns a local port that was free a moment ago, for servers started as
+/// separate processes.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A throwaway certificate authority with a server certificate for `localhost`.
+pub struct TestPki {
+    ca: RcgenCertificate,
+    pub ca_pem: String,
+    pub server_cert_pem: String,
+    pub server_key_pem: String,
+}
+
+impl TestPki {
+    /// Generates a fresh CA and a `localhost` server certificate signed by it.
+    pub fn generate() -> Self {
+        Self::generate_named("Test CA")
+    }
+
+    /// Like [`generate`](Self::generate), with the CA's common name set to
+    /// `ca_name`, for tests needing CAs with distinct issuer names.
+    pub fn generate_named(ca_name: &str) -> Self {
+        let mut params = CertificateParams::new(Vec::<String>::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, ca_name);
+        let ca = RcgenCertificate::from_params(params).expect("Failed to generate CA");
+        let ca_pem = ca.serialize_pem().expect("Failed to encode CA");
+
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "localhost");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let server = RcgenCertificate::from_params(params).expect("Failed to generate cert");
+        let server_cert_pem = server
+            .serialize_pem_with_signer(&ca)
+            .expect("Failed to sign server cert");
+
+        TestPki {
+            ca,
+            ca_pem,
+            server_cert_pem,
+            server_key_pem: server.serialize_private_key_pem(),
+        }
+    }
+
+    /// Writes the server certificate and key as PEM files into `dir`.
+    ///
+    /// Returns the `(cert, key)` paths, suitable for `CERT_FILE` and `KEY_FILE`.
+    pub fn write_server_files(&self, dir: &Path) -> (PathBuf, PathBuf) {
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, &self.server_cert_pem).expect("Failed to write cert");
+        fs::write(&key_path, &self.server_key_pem).expect("Failed to write key");
+        (cert_path, key_path)
+    }
+
+    /// Builds a server config from the generated certificate, without client auth.
+    pub fn server_config(&self) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(self.server_chain(), self.server_key())
+            .expect("Failed to build server config")
+    }
+
+    /// Returns the server certificate chain as DER.
+    pub fn server_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut self.server_cert_pem.as_bytes())
+            .expect("Failed to parse server cert")
+            .into_iter()
+            .map(Certificate)
+            .collect()
+    }
+
+    /// Returns the server private key as DER.
+    pub fn server_key(&self) -> PrivateKey {
+        let mut keys = rustls_pemfile::pkcs8_private_keys(&mut self.server_key_pem.as_bytes())
+            .expect("Failed to parse server key");
+        PrivateKey(keys.remove(0))
+    }
+
+    /// Returns the CA used to sign certificates, e.g. to issue client certs.
+    pub fn ca(&self) -> &RcgenCertificate {
+        &self.ca
+    }
+
+    /// Returns a client builder that trusts exactly this CA.
+    ///
+    /// Built-in roots are disabled and certificate verification stays on, so
+    /// requests only succeed if the server presents a certificate signed by
+    /// the CA that is valid for the requested hostname.
+    pub fn client_builder(&self) -> ClientBuilder {
+        let ca = reqwest::Certificate::from_pem(self.ca_pem.as_bytes()).expect("Invalid CA");
+        Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(ca)
+    }
+
+    /// Returns a client that trusts exactly this CA.
+    pub fn client(&self) -> Client {
+        self.client_builder()
+            .build()
+            .expect("Failed to create HTTPS client")
+    }
+}
@@ -0,0 +1,61 @@
+//! In-memory TLS configuration for tests.
+//!
+//! [`MockTlsConfig`] lives with the other integration test helpers, so it
+//! is only compiled into test targets, the way `#[cfg(test)]` code is: it
+//! needs `rcgen`, a dev-dependency, and is not part of the server.
+
+use rcgen::generate_simple_self_signed;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+
+/// A self-signed `localhost` certificate with matching server and client
+/// configurations.
+pub struct MockTlsConfig {
+    pub certificate: Certificate,
+    pub key: PrivateKey,
+}
+
+impl MockTlsConfig {
+    /// Generates a fresh self-signed certificate and key for `localhost`,
+    /// kept in memory and never written to disk.
+    pub fn self_signed() -> Self {
+        let generated = generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("Failed to generate self-signed cert");
+        MockTlsConfig {
+            certificate: Certificate(
+                generated
+                    .serialize_der()
+                    .expect("Failed to encode self-signed cert"),
+            ),
+            key: PrivateKey(generated.serialize_private_key_der()),
+        }
+    }
+
+    /// Returns a server config presenting a fresh certificate, and a client
+    /// config trusting only that certificate.
+    pub fn generate() -> (ServerConfig, ClientConfig) {
+        let mock = MockTlsConfig::self_signed();
+        (mock.server_config(), mock.client_config())
+    }
+
+    /// A server config presenting the certificate, without client auth.
+    pub fn server_config(&self) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![self.certificate.clone()], self.key.clone())
+            .expect("Failed to build server config")
+    }
+
+    /// A client config whose only trusted root is the certificate, with
+    /// verification left on.
+    pub fn client_config(&self) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&self.certificate)
+            .expect("Failed to trust self-signed cert");
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+}
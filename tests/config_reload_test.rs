@@ -0,0 +1,270 @@
+// The env lock only serializes tests that each run their own runtime, so
+// holding it across awaits cannot deadlock
+#![allow(clippy::await_holding_lock)]
+
+use actix_web::http::StatusCode;
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body_json, TestRequest,
+};
+use actix_web::{web, App, HttpResponse};
+use serde_json::Value;
+use std::env;
+use std::fs;
+
+use main::admin;
+use main::config_reload::{schema, ReloadableConfig, FIELDS, REDACTED};
+use main::flags::{gated, Flags};
+use main::middleware::api_key::{AdminKey, ApiKeyAuth};
+
+mod common;
+
+use common::logs;
+
+const OLD_KEY: &str = "old-admin-key-0123456789";
+const NEW_KEY: &str = "new-admin-key-0123456789";
+
+macro_rules! admin_app {
+    ($key:expr) => {{
+        let key: AdminKey = $key;
+        init_service(
+            App::new()
+                .app_data(web::Data::new(ReloadableConfig::from_env(key.clone())))
+                .service(
+                    web::scope("/admin")
+                        .wrap(ApiKeyAuth::shared(key.clone()))
+                        .app_data(web::Data::new(key))
+                        .configure(admin::configure),
+                ),
+        )
+        .await
+    }};
+}
+
+#[actix_web::test]
+async fn preview_shows_the_changed_field_and_apply_swaps_it() {
+    let _env = common::env_lock();
+    logs::capture();
+    env::remove_var("ADMIN_API_KEY_FILE");
+    env::set_var("ADMIN_API_KEY", OLD_KEY);
+    let app = admin_app!(AdminKey::new(Some(OLD_KEY.to_string())));
+
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["changes"].as_array().unwrap().len(), 0);
+
+    env::set_var("ADMIN_API_KEY", NEW_KEY);
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let changes = body["changes"].as_array().unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0]["field"], "ADMIN_API_KEY");
+    assert_eq!(changes[0]["new"], REDACTED);
+    assert_eq!(changes[0]["restart_required"], false);
+    assert!(!body.to_string().contains(NEW_KEY));
+
+    // Previewing changed nothing: the old key still works
+    let req = TestRequest::post()
+        .uri("/admin/config/apply")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["applied"][0]["field"], "ADMIN_API_KEY");
+    assert_eq!(body["pending_restart"].as_array().unwrap().len(), 0);
+    assert!(logs::contains("config_applied"));
+    assert!(!logs::contains(NEW_KEY));
+
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", NEW_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["changes"].as_array().unwrap().len(), 0);
+    env::remove_var("ADMIN_API_KEY");
+}
+
+#[actix_web::test]
+async fn restart_required_fields_stay_pending() {
+    let _env = common::env_lock();
+    env::remove_var("ADMIN_API_KEY_FILE");
+    env::set_var("ADMIN_API_KEY", OLD_KEY);
+    env::remove_var("HSTS_MAX_AGE");
+    let app = admin_app!(AdminKey::new(Some(OLD_KEY.to_string())));
+
+    env::set_var("HSTS_MAX_AGE", "600");
+    for _ in 0..2 {
+        let req = TestRequest::post()
+            .uri("/admin/config/apply")
+            .insert_header(("X-Api-Key", OLD_KEY))
+            .to_request();
+        let body: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["applied"].as_array().unwrap().len(), 0);
+        assert_eq!(body["pending_restart"][0]["field"], "HSTS_MAX_AGE");
+        assert_eq!(body["pending_restart"][0]["new"], "600");
+    }
+    env::remove_var("HSTS_MAX_AGE");
+    env::remove_var("ADMIN_API_KEY");
+}
+
+#[actix_web::test]
+async fn preview_returns_every_validation_failure() {
+    let _env = common::env_lock();
+    env::remove_var("ADMIN_API_KEY_FILE");
+    env::set_var("ADMIN_API_KEY", OLD_KEY);
+    let app = admin_app!(AdminKey::new(Some(OLD_KEY.to_string())));
+
+    env::set_var("ADMIN_API_KEY", "short");
+    env::set_var("TRUSTED_PROXIES", "not-a-network");
+    env::set_var("NUM_WORKERS", "0");
+    for (method, uri) in [
+        (TestRequest::get(), "/admin/config/preview"),
+        (TestRequest::post(), "/admin/config/apply"),
+    ] {
+        let req = method
+            .uri(uri)
+            .insert_header(("X-Api-Key", OLD_KEY))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_config");
+        let mut fields: Vec<_> = body["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["field"].as_str().unwrap().to_string())
+            .collect();
+        fields.sort();
+        assert_eq!(fields, ["ADMIN_API_KEY", "NUM_WORKERS", "TRUSTED_PROXIES"]);
+    }
+    env::remove_var("TRUSTED_PROXIES");
+    env::remove_var("NUM_WORKERS");
+    env::set_var("ADMIN_API_KEY", OLD_KEY);
+
+    // The failed apply kept the old key
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["changes"].as_array().unwrap().len(), 0);
+    env::remove_var("ADMIN_API_KEY");
+}
+
+#[actix_web::test]
+async fn applying_reloads_feature_flags() {
+    let _env = common::env_lock();
+    env::remove_var("ADMIN_API_KEY_FILE");
+    env::remove_var("FEATURE_FLAGS");
+    env::set_var("ADMIN_API_KEY", OLD_KEY);
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("flags.json");
+    fs::write(&path, r#"[{"name": "new-route"}]"#).unwrap();
+    env::set_var("FEATURE_FLAGS_FILE", &path);
+    let flags = Flags::from_env().unwrap();
+    let key = AdminKey::new(Some(OLD_KEY.to_string()));
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(
+                ReloadableConfig::from_env(key.clone()).with_flags(flags.clone()),
+            ))
+            .app_data(web::Data::new(flags.clone()))
+            .service(gated(
+                "new-route",
+                web::resource("/new")
+                    .route(web::get().to(|| async { HttpResponse::Ok().finish() })),
+            ))
+            .service(
+                web::scope("/admin")
+                    .wrap(ApiKeyAuth::shared(key.clone()))
+                    .app_data(web::Data::new(key))
+                    .configure(admin::configure),
+            ),
+    )
+    .await;
+    let get_new = || TestRequest::get().uri("/new").to_request();
+    assert_eq!(
+        call_service(&app, get_new()).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    // The file is edited in place: same path, new contents
+    fs::write(&path, r#"[{"name": "new-route", "default": true}]"#).unwrap();
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["changes"][0]["field"], "FEATURE_FLAGS_FILE");
+    assert_eq!(body["changes"][0]["restart_required"], false);
+    // Previewing changed nothing
+    assert_eq!(
+        call_service(&app, get_new()).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = TestRequest::post()
+        .uri("/admin/config/apply")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["applied"][0]["field"], "FEATURE_FLAGS_FILE");
+    assert_eq!(call_service(&app, get_new()).await.status(), StatusCode::OK);
+
+    // FEATURE_FLAGS switches it off again
+    env::set_var("FEATURE_FLAGS", "new-route=off");
+    let req = TestRequest::post()
+        .uri("/admin/config/apply")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["applied"][0]["field"], "FEATURE_FLAGS");
+    assert_eq!(
+        call_service(&app, get_new()).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let req = TestRequest::get()
+        .uri("/admin/config/preview")
+        .insert_header(("X-Api-Key", OLD_KEY))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["changes"].as_array().unwrap().len(), 0);
+
+    env::remove_var("FEATURE_FLAGS");
+    env::remove_var("FEATURE_FLAGS_FILE");
+    env::remove_var("ADMIN_API_KEY");
+}
+
+#[test]
+fn schema_describes_every_field() {
+    let schema = schema();
+    let properties = schema["properties"].as_object().unwrap();
+    assert_eq!(properties.len(), FIELDS.len());
+    for field in FIELDS {
+        let property = &properties[field.name];
+        assert_eq!(property["description"], field.description, "{}", field.name);
+        assert_eq!(property["x-restart-required"], field.restart_required);
+    }
+
+    assert_eq!(properties["SERVER_ADDRESS"]["type"], "string");
+    assert_eq!(properties["SERVER_ADDRESS"]["default"], "127.0.0.1:3000");
+    assert_eq!(properties["HSTS_MAX_AGE"]["type"], "integer");
+    assert_eq!(properties["HSTS_MAX_AGE"]["default"], 31_536_000);
+    assert_eq!(properties["NUM_WORKERS"]["minimum"], 1);
+    assert!(properties["NUM_WORKERS"].get("default").is_none());
+    assert_eq!(properties["ADMIN_API_KEY"]["writeOnly"], true);
+    assert_eq!(properties["ADMIN_API_KEY"]["x-restart-required"], false);
+}
@@ -0,0 +1,78 @@
+use actix_web::dev::Extensions;
+use actix_web::http::{header, StatusCode};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpRequest, HttpResponse};
+
+use main::connections::{self, OpenConnection};
+use main::error::ApiError;
+use main::metrics::Metrics;
+use main::ws;
+
+#[test]
+fn protocol_follows_alpn() {
+    assert_eq!(connections::protocol(Some(b"h2")), "h2");
+    assert_eq!(connections::protocol(Some(b"http/1.1")), "http/1.1");
+    assert_eq!(connections::protocol(None), "http/1.1");
+}
+
+#[test]
+fn guard_counts_until_dropped() {
+    let first = OpenConnection::open("test-guard");
+    let second = OpenConnection::open("test-guard");
+    assert_eq!(connections::open("test-guard"), 2);
+    drop(first);
+    assert_eq!(connections::open("test-guard"), 1);
+    drop(second);
+    assert_eq!(connections::open("test-guard"), 0);
+    assert!(Metrics::global()
+        .render()
+        .contains("open_connections{protocol=\"test-guard\"} 0"));
+}
+
+#[actix_rt::test]
+async fn connection_data_is_counted_until_the_connection_ends() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+
+    let before = connections::open("http/1.1");
+    let mut data = Extensions::new();
+    connections::on_connect(&server, &mut data);
+    assert_eq!(connections::open("http/1.1"), before + 1);
+
+    // The client vanishing without a request; the server drops the data
+    drop(client);
+    drop(data);
+    assert_eq!(connections::open("http/1.1"), before);
+
+    // Unknown connection types are not counted
+    let mut data = Extensions::new();
+    connections::on_connect(&"not a connection", &mut data);
+    assert!(data.get::<OpenConnection>().is_none());
+}
+
+async fn echo(req: HttpRequest, payload: web::Payload) -> Result<HttpResponse, ApiError> {
+    ws::serve(&req, payload, Some)
+}
+
+#[actix_rt::test]
+async fn websockets_are_counted_while_open() {
+    let app = init_service(App::new().route("/ws", web::get().to(echo))).await;
+    let req = TestRequest::get()
+        .uri("/ws")
+        .insert_header((header::UPGRADE, "websocket"))
+        .insert_header((header::CONNECTION, "Upgrade"))
+        .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+        .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(connections::open("websocket"), 1);
+
+    // Dropping the body is what an abrupt disconnect does
+    drop(resp);
+    assert_eq!(connections::open("websocket"), 0);
+}
@@ -0,0 +1,160 @@
+#![cfg(feature = "consul")]
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use serde_json::Value;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use main::consul::{ConsulConfig, ConsulRegistration};
+
+mod common;
+
+/// Requests received by the mock agent: (method, path, body).
+type Recorded = Arc<Mutex<Vec<(String, String, String)>>>;
+
+/// Starts a mock Consul agent. The first `fail_heartbeats` heartbeats return
+/// 404, as an agent that lost the registration after a restart would.
+fn start_mock_agent(fail_heartbeats: usize) -> (String, Recorded) {
+    let recorded: Recorded = Arc::new(Mutex::new(Vec::new()));
+    let failures = Arc::new(AtomicUsize::new(fail_heartbeats));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let server_recorded = recorded.clone();
+    let server = HttpServer::new(move || {
+        let recorded = server_recorded.clone();
+        let failures = failures.clone();
+        App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+            let recorded = recorded.clone();
+            let failures = failures.clone();
+            async move {
+                let path = req.path().to_string();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((req.method().to_string(), path.clone(), body));
+                let failing = path.starts_with("/v1/agent/check/pass/")
+                    && failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                if failing {
+                    HttpResponse::NotFound().finish()
+                } else {
+                    HttpResponse::Ok().finish()
+                }
+            }
+        }))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_rt::spawn(server);
+    (url, recorded)
+}
+
+fn config(agent_url: String) -> ConsulConfig {
+    ConsulConfig {
+        agent_url,
+        service_id: "web-1".to_string(),
+        service_name: "web".to_string(),
+        address: "10.0.0.5".to_string(),
+        port: 3000,
+        tags: vec!["blue".to_string(), "https".to_string()],
+        check_interval: Duration::from_secs(15),
+        ttl: Duration::from_millis(300),
+        tls_skip_verify: false,
+    }
+}
+
+fn paths(recorded: &Recorded) -> Vec<String> {
+    recorded
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, path, _)| path.clone())
+        .collect()
+}
+
+#[actix_rt::test]
+async fn test_registration_payload() {
+    let (url, recorded) = start_mock_agent(0);
+    let registration = ConsulRegistration::new(config(url));
+    registration.register().await.expect("Registration failed");
+
+    let recorded = recorded.lock().unwrap();
+    let (method, path, body) = &recorded[0];
+    assert_eq!(method, "PUT");
+    assert_eq!(path, "/v1/agent/service/register");
+
+    let body: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(body["ID"], "web-1");
+    assert_eq!(body["Name"], "web");
+    assert_eq!(body["Address"], "10.0.0.5");
+    assert_eq!(body["Port"], 3000);
+    assert_eq!(body["Tags"], serde_json::json!(["blue", "https"]));
+    assert_eq!(body["Checks"][0]["HTTP"], "https://10.0.0.5:3000/health");
+    assert_eq!(body["Checks"][0]["Interval"], "15s");
+    assert_eq!(body["Checks"][1]["CheckID"], "web-1:ttl");
+    assert_eq!(body["Checks"][1]["TTL"], "300ms");
+}
+
+#[actix_rt::test]
+async fn test_heartbeats_and_reregistration() {
+    let (url, recorded) = start_mock_agent(1);
+    let registration = Arc::new(ConsulRegistration::new(config(url)));
+    let job = registration.clone().spawn();
+
+    actix_rt::time::sleep(Duration::from_millis(800)).await;
+    job.abort();
+
+    let paths = paths(&recorded);
+    let registrations = paths
+        .iter()
+        .filter(|p| *p == "/v1/agent/service/register")
+        .count();
+    let heartbeats = paths
+        .iter()
+        .filter(|p| *p == "/v1/agent/check/pass/web-1:ttl")
+        .count();
+
+    // Initial registration, plus one after the failed heartbeat
+    assert_eq!(registrations, 2, "{:?}", paths);
+    assert!(heartbeats >= 2, "{:?}", paths);
+}
+
+#[actix_rt::test]
+async fn test_deregistration() {
+    let (url, recorded) = start_mock_agent(0);
+    let registration = ConsulRegistration::new(config(url));
+    registration
+        .deregister()
+        .await
+        .expect("Deregistration failed");
+
+    let recorded = recorded.lock().unwrap();
+    assert_eq!(recorded[0].0, "PUT");
+    assert_eq!(recorded[0].1, "/v1/agent/service/deregister/web-1");
+}
+
+#[actix_rt::test]
+async fn test_unreachable_agent_does_not_panic() {
+    let registration = ConsulRegistration::new(config("http://127.0.0.1:9".to_string()));
+    assert!(registration.register().await.is_err());
+    assert!(registration.heartbeat().await.is_err());
+}
+
+#[test]
+fn test_ttl_below_three_seconds_is_rejected() {
+    let _env = common::env_lock();
+    std::env::set_var("CONSUL_TTL_SECS", "2");
+    let err = ConsulConfig::from_env("127.0.0.1:3000").unwrap_err();
+    assert!(err.to_string().contains("CONSUL_TTL_SECS"), "{}", err);
+
+    std::env::set_var("CONSUL_TTL_SECS", "3");
+    let config = ConsulConfig::from_env("127.0.0.1:3000").unwrap().unwrap();
+    assert_eq!(config.ttl, Duration::from_secs(3));
+    std::env::remove_var("CONSUL_TTL_SECS");
+}
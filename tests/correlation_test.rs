@@ -0,0 +1,328 @@
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body_json, TestRequest,
+};
+use actix_web::{web, App, HttpResponse};
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use main::middleware::request_id::{AssignRequestId, CorrelationChain, TraceContext};
+use main::outbound::{OutboundClient, UrlPolicy};
+use main::proxy::{Mirror, Proxy};
+
+mod common;
+
+async fn whoami(chain: CorrelationChain) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "own": chain.own(),
+        "parent": chain.parent(),
+        "depth": chain.depth(),
+    }))
+}
+
+#[test]
+fn test_chain_accessors() {
+    let root = CorrelationChain::root();
+    assert_eq!(root.parent(), None);
+    assert_eq!(root.depth(), 1);
+
+    let child = CorrelationChain::child_of(root.own());
+    assert_eq!(child.parent(), Some(root.own()));
+    assert!(child.own().starts_with(&format!("{}:", root.own())));
+    assert_eq!(child.depth(), 2);
+
+    let grandchild = CorrelationChain::child_of(child.own());
+    assert_eq!(grandchild.parent(), Some(child.own()));
+    assert_eq!(grandchild.depth(), 3);
+}
+
+#[actix_rt::test]
+async fn test_nested_calls_form_chain_up_to_max_depth() {
+    common::logs::capture();
+    let app = init_service(
+        App::new()
+            .wrap(AssignRequestId::new(3))
+            .route("/whoami", web::get().to(whoami)),
+    )
+    .await;
+
+    // Each hop passes the ID it was given to the next service
+    let mut previous: Option<String> = None;
+    for depth in 1..=3 {
+        let mut req = TestRequest::get().uri("/whoami");
+        if let Some(id) = &previous {
+            req = req.insert_header(("X-Request-Id", id.as_str()));
+        }
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let header = resp
+            .headers()
+            .get("x-request-id")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body: Value = read_body_json(resp).await;
+
+        assert_eq!(body["depth"], depth);
+        assert_eq!(body["own"], header);
+        assert_eq!(body["parent"].as_str(), previous.as_deref());
+        previous = Some(header);
+    }
+
+    let req = TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("X-Request-Id", previous.unwrap().as_str()))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "correlation_chain_too_deep");
+    assert!(common::logs::contains("correlation_chain_too_deep"));
+}
+
+#[actix_rt::test]
+async fn test_invalid_incoming_id_starts_new_chain() {
+    let app = init_service(
+        App::new()
+            .wrap(AssignRequestId::default())
+            .route("/whoami", web::get().to(whoami)),
+    )
+    .await;
+    let req = TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("X-Request-Id", "has spaces"))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["depth"], 1);
+    assert!(body["parent"].is_null());
+}
+
+#[actix_rt::test]
+async fn test_outbound_requests_propagate_child_id() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).unwrap();
+        tx.send(String::from_utf8_lossy(&buf[..n]).to_string())
+            .unwrap();
+        let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+    });
+
+    let chain = CorrelationChain::child_of("upstream-id");
+    let client = OutboundClient::new(
+        UrlPolicy {
+            allowed_ports: Vec::new(),
+            ..UrlPolicy::default()
+        }
+        .allow_host("127.0.0.1"),
+    )
+    .with_correlation(&chain);
+    client
+        .get(&format!("http://127.0.0.1:{}/", port))
+        .await
+        .unwrap();
+
+    let request = rx.recv().unwrap().to_ascii_lowercase();
+    assert!(
+        request.contains(&format!(
+            "x-request-id: {}",
+            chain.own().to_ascii_lowercase()
+        )),
+        "request did not carry the chain: {}",
+        request
+    );
+}
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+/// Captures the head of the first request it receives and answers 204.
+fn capturing_server() -> (u16, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).unwrap();
+        tx.send(String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase())
+            .unwrap();
+        let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+    });
+    (port, rx)
+}
+
+/// Returns the value of `name` in a captured, lowercased request head.
+fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+        .map(str::trim)
+}
+
+fn assert_valid_traceparent(value: &str) {
+    let fields: Vec<&str> = value.split('-').collect();
+    assert_eq!(fields.len(), 4, "{}", value);
+    assert_eq!(fields[0], "00");
+    assert_eq!(fields[1].len(), 32);
+    assert_eq!(fields[2].len(), 16);
+    assert_eq!(fields[3].len(), 2);
+    assert!(fields[1..].iter().all(|f| f
+        .bytes()
+        .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())));
+    assert_ne!(fields[1], "0".repeat(32));
+    assert_ne!(fields[2], "0".repeat(16));
+}
+
+#[test]
+fn test_traceparent_is_continued_or_replaced() {
+    let trace = TraceContext::child_of(TRACEPARENT, Some("vendor=value")).unwrap();
+    assert_eq!(trace.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(trace.parent_span_id().as_deref(), Some("00f067aa0ba902b7"));
+    assert_ne!(trace.span_id(), "00f067aa0ba902b7");
+    assert_eq!(trace.tracestate(), Some("vendor=value"));
+    assert_valid_traceparent(&trace.traceparent());
+    assert!(trace.traceparent().ends_with("-01"));
+
+    // Later versions may append fields
+    let future = format!("cc{}-extra", &TRACEPARENT[2..]);
+    assert!(TraceContext::child_of(&future, None).is_some());
+
+    for invalid in [
+        "",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+    ] {
+        assert!(
+            TraceContext::child_of(invalid, None).is_none(),
+            "{} should be rejected",
+            invalid
+        );
+    }
+
+    let root = TraceContext::root();
+    assert_eq!(root.parent_span_id(), None);
+    assert_valid_traceparent(&root.traceparent());
+}
+
+async fn trace(trace: TraceContext, chain: CorrelationChain) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "traceparent": trace.traceparent(),
+        "parent_span_id": trace.parent_span_id(),
+        "tracestate": trace.tracestate(),
+        "chain_traceparent": chain.trace().map(|t| t.traceparent()),
+    }))
+}
+
+#[actix_rt::test]
+async fn test_middleware_assigns_trace_context() {
+    let app = init_service(
+        App::new()
+            .wrap(AssignRequestId::default())
+            .route("/trace", web::get().to(trace)),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/trace").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_valid_traceparent(body["traceparent"].as_str().unwrap());
+    assert!(body["parent_span_id"].is_null());
+    assert_eq!(body["chain_traceparent"], body["traceparent"]);
+
+    let req = TestRequest::get()
+        .uri("/trace")
+        .insert_header(("traceparent", TRACEPARENT))
+        .insert_header(("tracestate", "vendor=value"))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let traceparent = body["traceparent"].as_str().unwrap();
+    assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert_ne!(traceparent, TRACEPARENT);
+    assert_eq!(body["parent_span_id"], "00f067aa0ba902b7");
+    assert_eq!(body["tracestate"], "vendor=value");
+
+    let req = TestRequest::get()
+        .uri("/trace")
+        .insert_header(("traceparent", "garbage"))
+        .insert_header(("tracestate", "vendor=value"))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert!(!body["traceparent"]
+        .as_str()
+        .unwrap()
+        .contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+    assert!(body["tracestate"].is_null());
+}
+
+#[actix_rt::test]
+async fn test_outbound_requests_propagate_trace_context() {
+    let (port, rx) = capturing_server();
+    let trace = TraceContext::child_of(TRACEPARENT, Some("vendor=value")).unwrap();
+    let chain = CorrelationChain::child_of("upstream-id").with_trace(trace.clone());
+    OutboundClient::new(
+        UrlPolicy {
+            allowed_ports: Vec::new(),
+            ..UrlPolicy::default()
+        }
+        .allow_host("127.0.0.1"),
+    )
+    .with_correlation(&chain)
+    .get(&format!("http://127.0.0.1:{}/", port))
+    .await
+    .unwrap();
+
+    let request = rx.recv().unwrap();
+    assert_eq!(
+        header(&request, "traceparent"),
+        Some(trace.traceparent().as_str())
+    );
+    assert_eq!(header(&request, "tracestate"), Some("vendor=value"));
+    assert_eq!(
+        header(&request, "x-request-id"),
+        Some(chain.own().to_ascii_lowercase().as_str())
+    );
+}
+
+#[actix_rt::test]
+async fn test_proxied_requests_carry_trace_headers() {
+    let (port, rx) = capturing_server();
+    let upstream = reqwest::Url::parse(&format!("http://127.0.0.1:{}/", port)).unwrap();
+    let proxy = web::Data::new(Proxy::new(
+        "/proxy",
+        upstream,
+        Mirror::new(None, Duration::from_secs(5)),
+    ));
+    let app = init_service(
+        App::new()
+            .wrap(AssignRequestId::default())
+            .configure(Proxy::configure(proxy)),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/proxy/x")
+        .insert_header(("traceparent", TRACEPARENT))
+        .insert_header(("tracestate", "vendor=value"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 204);
+
+    let request = rx.recv().unwrap();
+    let traceparent = header(&request, "traceparent").expect("traceparent is sent");
+    assert_valid_traceparent(traceparent);
+    assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert_ne!(traceparent, TRACEPARENT);
+    assert_eq!(request.matches("traceparent:").count(), 1);
+    assert_eq!(header(&request, "tracestate"), Some("vendor=value"));
+    assert!(header(&request, "x-request-id").is_some());
+}
@@ -0,0 +1,192 @@
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App};
+use chrono::{Duration, TimeZone, Utc};
+use std::env;
+use std::io::ErrorKind;
+
+use main::discovery::{self, Discovery, RobotsPolicy, SecurityTxt};
+use main::middleware::aws_sigv4::{AwsSigV4Verifier, DEFAULT_CLOCK_SKEW};
+use main::middleware::mtls::RequireClientCertificate;
+
+mod common;
+
+fn clear_env() {
+    for var in [
+        "ROBOTS_POLICY",
+        "ROBOTS_FILE",
+        "SECURITY_TXT_CONTACT",
+        "SECURITY_TXT_EXPIRES",
+        "SECURITY_TXT_POLICY",
+        "SECURITY_TXT_PREFERRED_LANGUAGES",
+    ] {
+        env::remove_var(var);
+    }
+}
+
+#[test]
+fn robots_modes() {
+    let _env = common::env_lock();
+    clear_env();
+
+    let policy = RobotsPolicy::from_env().unwrap();
+    assert_eq!(policy, RobotsPolicy::DisallowAll);
+    assert_eq!(policy.render(), "User-agent: *\nDisallow: /\n");
+
+    env::set_var("ROBOTS_POLICY", "allow");
+    assert_eq!(
+        RobotsPolicy::from_env().unwrap().render(),
+        "User-agent: *\nDisallow:\n"
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("robots.txt");
+    std::fs::write(&path, "User-agent: *\nDisallow: /admin\n").unwrap();
+    env::remove_var("ROBOTS_POLICY");
+    env::set_var("ROBOTS_FILE", &path);
+    assert_eq!(
+        RobotsPolicy::from_env().unwrap().render(),
+        "User-agent: *\nDisallow: /admin\n"
+    );
+
+    env::set_var("ROBOTS_FILE", dir.path().join("missing.txt"));
+    assert!(RobotsPolicy::from_env().is_err());
+    env::remove_var("ROBOTS_FILE");
+    env::set_var("ROBOTS_POLICY", "file");
+    assert!(RobotsPolicy::from_env().is_err());
+    env::set_var("ROBOTS_POLICY", "sometimes");
+    assert!(RobotsPolicy::from_env().is_err());
+    clear_env();
+}
+
+#[test]
+fn security_txt_fields_are_formatted() {
+    let now = Utc.with_ymd_and_hms(2026, 6, 1, 0, 0, 0).unwrap();
+    let txt = SecurityTxt::new(
+        vec![
+            "mailto:security@example.com".to_string(),
+            "https://example.com/report".to_string(),
+        ],
+        Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap(),
+        Some("https://example.com/disclosure".to_string()),
+        Some("en, fr".to_string()),
+        now,
+    )
+    .unwrap();
+    assert_eq!(
+        txt.render(),
+        "Contact: mailto:security@example.com\n\
+         Contact: https://example.com/report\n\
+         Expires: 2027-01-01T00:00:00Z\n\
+         Policy: https://example.com/disclosure\n\
+         Preferred-Languages: en, fr\n"
+    );
+
+    let expires = now + Duration::days(30);
+    let contact = || vec!["mailto:security@example.com".to_string()];
+    assert!(SecurityTxt::new(Vec::new(), expires, None, None, now).is_err());
+    assert!(SecurityTxt::new(
+        vec!["security@example.com".into()],
+        expires,
+        None,
+        None,
+        now
+    )
+    .is_err());
+    assert!(SecurityTxt::new(contact(), expires, Some("http://x".into()), None, now).is_err());
+    assert_eq!(
+        SecurityTxt::new(contact(), expires, None, None, now)
+            .unwrap()
+            .render(),
+        "Contact: mailto:security@example.com\nExpires: 2026-07-01T00:00:00Z\n"
+    );
+}
+
+#[test]
+fn expired_security_txt_fails_startup() {
+    let _env = common::env_lock();
+    clear_env();
+
+    // Without a contact, security.txt is simply not served
+    assert!(Discovery::from_env().unwrap().security_txt.is_none());
+
+    env::set_var("SECURITY_TXT_CONTACT", "mailto:security@example.com");
+    let e = Discovery::from_env().unwrap_err();
+    assert!(e.to_string().contains("SECURITY_TXT_EXPIRES"));
+
+    env::set_var("SECURITY_TXT_EXPIRES", "2020-01-01T00:00:00Z");
+    let e = Discovery::from_env().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert!(e.to_string().contains("expired on 2020-01-01T00:00:00Z"));
+
+    env::set_var("SECURITY_TXT_EXPIRES", "next year");
+    assert!(Discovery::from_env().is_err());
+
+    let expires = (Utc::now() + Duration::days(90)).to_rfc3339();
+    env::set_var("SECURITY_TXT_EXPIRES", &expires);
+    let discovery = Discovery::from_env().unwrap();
+    assert!(discovery
+        .security_txt
+        .unwrap()
+        .starts_with("Contact: mailto:security@example.com\nExpires: "));
+    clear_env();
+}
+
+#[actix_rt::test]
+async fn files_are_served_as_cacheable_text() {
+    let now = Utc::now();
+    let txt = SecurityTxt::new(
+        vec!["mailto:security@example.com".to_string()],
+        now + Duration::days(90),
+        None,
+        None,
+        now,
+    )
+    .unwrap();
+    let discovery = Discovery::new(&RobotsPolicy::DisallowAll, Some(&txt));
+    let app =
+        init_service(App::new().configure(discovery::configure(web::Data::new(discovery)))).await;
+
+    for (uri, expected) in [
+        ("/robots.txt", RobotsPolicy::DisallowAll.render()),
+        ("/.well-known/security.txt", txt.render()),
+    ] {
+        let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=86400"
+        );
+        assert_eq!(read_body(resp).await, expected);
+    }
+
+    // No contact: no security.txt
+    let app = init_service(App::new().configure(discovery::configure(web::Data::new(
+        Discovery::new(&RobotsPolicy::AllowAll, None),
+    ))))
+    .await;
+    let req = TestRequest::get()
+        .uri("/.well-known/security.txt")
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[test]
+fn discovery_paths_are_exempt_from_path_based_auth() {
+    let sigv4 = AwsSigV4Verifier::new("AKID", "secret", DEFAULT_CLOCK_SKEW);
+    let mtls = RequireClientCertificate::new(vec!["/".to_string()]);
+    for path in discovery::PUBLIC_PATHS {
+        assert!(!sigv4.applies_to(path));
+        assert!(!mtls.applies_to(path));
+    }
+    assert!(sigv4.applies_to("/hello"));
+    assert!(mtls.applies_to("/.well-known/other"));
+}
@@ -0,0 +1,222 @@
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::{A, AAAA};
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use main::metrics::Metrics;
+use main::outbound::happy_eyeballs::{self, interleave};
+use main::outbound::resolver::{self, DnsResolver};
+use main::proxy::{Mirror, Proxy};
+
+/// Answers for one name: addresses per family, `None` for SERVFAIL.
+struct Zone {
+    v4: Option<Vec<&'static str>>,
+    v6: Option<Vec<&'static str>>,
+    ttl: u32,
+}
+
+/// Stub nameserver on UDP, returning its address and a query counter.
+fn stub_dns(zones: HashMap<&'static str, Zone>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counter = queries.clone();
+    thread::spawn(move || {
+        let no_records = Vec::new();
+        let mut buf = [0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+            let Ok(request) = Message::from_vec(&buf[..len]) else {
+                continue;
+            };
+            let Some(query) = request.queries().first().cloned() else {
+                continue;
+            };
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(request.op_code())
+                .set_recursion_desired(true)
+                .set_recursion_available(true)
+                .add_query(query.clone());
+            let name = query.name().to_ascii();
+            match zones.get(name.trim_end_matches('.')) {
+                None => {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+                Some(zone) => {
+                    let answers = match query.query_type() {
+                        RecordType::A => zone.v4.as_ref(),
+                        RecordType::AAAA => zone.v6.as_ref(),
+                        _ => Some(&no_records),
+                    };
+                    match answers {
+                        None => {
+                            response.set_response_code(ResponseCode::ServFail);
+                        }
+                        Some(ips) => {
+                            for ip in ips {
+                                let rdata = match ip.parse::<IpAddr>().unwrap() {
+                                    IpAddr::V4(ip) => RData::A(A(ip)),
+                                    IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+                                };
+                                response.add_answer(Record::from_rdata(
+                                    query.name().clone(),
+                                    zone.ttl,
+                                    rdata,
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+            let _ = socket.send_to(&response.to_vec().unwrap(), peer);
+        }
+    });
+    (addr, queries)
+}
+
+fn zones() -> HashMap<&'static str, Zone> {
+    HashMap::from([
+        (
+            "dual.example",
+            Zone {
+                v4: Some(vec!["192.0.2.1", "192.0.2.2"]),
+                v6: Some(vec!["2001:db8::1", "2001:db8::2"]),
+                ttl: 60,
+            },
+        ),
+        (
+            "short.example",
+            Zone {
+                v4: Some(vec!["192.0.2.3"]),
+                v6: Some(vec![]),
+                ttl: 1,
+            },
+        ),
+        (
+            "broken-v6.example",
+            Zone {
+                v4: Some(vec!["192.0.2.4"]),
+                v6: None,
+                ttl: 60,
+            },
+        ),
+    ])
+}
+
+fn ips(list: &[&str]) -> Vec<IpAddr> {
+    list.iter().map(|ip| ip.parse().unwrap()).collect()
+}
+
+#[test]
+fn test_interleave_alternates_families() {
+    assert_eq!(
+        interleave(&ips(&[
+            "2001:db8::1",
+            "2001:db8::2",
+            "192.0.2.1",
+            "192.0.2.2"
+        ])),
+        ips(&["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"])
+    );
+    assert_eq!(
+        interleave(&ips(&["192.0.2.1", "192.0.2.2", "2001:db8::1"])),
+        ips(&["192.0.2.1", "2001:db8::1", "192.0.2.2"])
+    );
+    assert!(interleave::<IpAddr>(&[]).is_empty());
+}
+
+#[actix_rt::test]
+async fn test_answers_are_cached_until_ttl() {
+    let (dns, queries) = stub_dns(zones());
+    let resolver = DnsResolver::new(&[dns], Duration::from_secs(300));
+    let hits = || Metrics::global().counter_value("outbound_dns_cache_total", &[("result", "hit")]);
+
+    let first = resolver.lookup("dual.example").await.unwrap();
+    assert_eq!(first.len(), 4);
+    assert_eq!(first[0].is_ipv6(), !first[1].is_ipv6());
+    let sent = queries.load(Ordering::SeqCst);
+
+    let before = hits();
+    assert_eq!(resolver.lookup("dual.example").await.unwrap(), first);
+    assert_eq!(queries.load(Ordering::SeqCst), sent);
+    assert!(hits() > before);
+
+    // A 1 second TTL expires
+    resolver.lookup("short.example").await.unwrap();
+    let sent = queries.load(Ordering::SeqCst);
+    actix_rt::time::sleep(Duration::from_millis(1_100)).await;
+    assert_eq!(
+        resolver.lookup("short.example").await.unwrap(),
+        ips(&["192.0.2.3"])
+    );
+    assert!(queries.load(Ordering::SeqCst) > sent);
+}
+
+#[actix_rt::test]
+async fn test_broken_family_falls_back() {
+    let (dns, _) = stub_dns(zones());
+    let resolver = DnsResolver::new(&[dns], Duration::from_secs(300));
+
+    assert_eq!(
+        resolver.lookup("broken-v6.example").await.unwrap(),
+        ips(&["192.0.2.4"])
+    );
+    assert!(resolver.lookup("missing.example").await.is_err());
+}
+
+#[actix_rt::test]
+async fn test_connection_races_past_stalled_address() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // 100::/64 is discard-only, so this attempt stalls or fails
+    let addrs: Vec<SocketAddr> = vec![
+        format!("[100::1]:{}", port).parse().unwrap(),
+        format!("127.0.0.1:{}", port).parse().unwrap(),
+    ];
+
+    let start = Instant::now();
+    let stream = happy_eyeballs::connect(&addrs, Duration::from_millis(50))
+        .await
+        .expect("IPv4 attempt should win");
+    assert_eq!(stream.peer_addr().unwrap(), addrs[1]);
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // Every address failing reports the last error
+    drop(listener);
+    assert!(
+        happy_eyeballs::connect(&addrs[1..], Duration::from_millis(50))
+            .await
+            .is_err()
+    );
+}
+
+#[actix_rt::test]
+async fn test_proxy_reports_resolution_failures() {
+    let (dns, _) = stub_dns(zones());
+    resolver::install(DnsResolver::new(&[dns], Duration::from_secs(300)));
+
+    let proxy = web::Data::new(Proxy::new(
+        "/proxy",
+        Url::parse("http://missing.example:8080/").unwrap(),
+        Mirror::new(None, Duration::from_secs(5)),
+    ));
+    let app = init_service(App::new().configure(Proxy::configure(proxy))).await;
+    let req = TestRequest::get().uri("/proxy/orders").to_request();
+    let resp = call_service(&app, req).await;
+
+    assert_eq!(resp.status(), 502);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "upstream_dns_failure");
+}
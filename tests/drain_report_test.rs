@@ -0,0 +1,219 @@
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde_json::Value;
+use std::env;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use main::lifecycle::{self, DrainReport, Lifecycle, DEFAULT_SHUTDOWN_TIMEOUT};
+use main::metrics::Metrics;
+use main::middleware::in_flight::{
+    list_in_flight, InFlightRegistry, InFlightRequest, TrackInFlight, UNTRACKED_METRIC,
+};
+
+mod common;
+
+use common::logs;
+
+fn request(route: &str) -> InFlightRequest {
+    InFlightRequest {
+        method: "GET".to_string(),
+        route: route.to_string(),
+        client_ip: Some("192.0.2.7".to_string()),
+        request_id: format!("req{}", route.replace('/', "-")),
+        started: Instant::now(),
+    }
+}
+
+async fn slow() -> HttpResponse {
+    actix_web::rt::time::sleep(Duration::from_secs(30)).await;
+    HttpResponse::Ok().finish()
+}
+
+/// Starts a server with a slow route and a drain timeout of three seconds.
+fn start(registry: Arc<InFlightRegistry>) -> (actix_web::dev::ServerHandle, u16) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(TrackInFlight::new(registry.clone()))
+            .route("/slow/{id}", web::get().to(slow))
+    })
+    .workers(1)
+    .shutdown_timeout(3)
+    .disable_signals()
+    .listen(listener)
+    .unwrap()
+    .run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    (handle, port)
+}
+
+#[actix_rt::test]
+async fn drain_reports_slow_requests_and_the_ones_aborted() {
+    logs::capture();
+    let registry = Arc::new(InFlightRegistry::new(1, 100));
+    let (handle, port) = start(registry.clone());
+
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    client
+        .write_all(b"GET /slow/42 HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: held-open\r\n\r\n")
+        .await
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while registry.is_empty() {
+        assert!(Instant::now() < deadline, "request was not tracked");
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    let tracked = registry.snapshot();
+    assert_eq!(tracked[0].route, "/slow/{id}");
+    assert_eq!(tracked[0].client_ip.as_deref(), Some("127.0.0.1"));
+
+    // Leaves many report intervals before the timeout, even under load
+    let report = DrainReport::new(registry.clone(), Duration::from_secs(3))
+        .with_interval(Duration::from_millis(100));
+    let started = Instant::now();
+    lifecycle::shutdown_with_report(handle, &Lifecycle::new(), Duration::ZERO, &report).await;
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    assert!(logs::contains("Draining: 1 requests in flight"));
+    assert!(logs::contains("In flight: method=GET route=\"/slow/{id}\""));
+    assert!(logs::contains("client_ip=127.0.0.1 request_id=held-open"));
+    assert!(logs::contains(
+        "Shutdown timeout of 3s reached: aborting 1 requests"
+    ));
+    assert!(logs::contains(
+        "Aborted at the shutdown timeout: method=GET route=\"/slow/{id}\""
+    ));
+    // The aborted request is dropped with its worker, and so leaves the
+    // registry
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !registry.is_empty() {
+        assert!(Instant::now() < deadline, "aborted request still tracked");
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    drop(client);
+}
+
+#[actix_rt::test]
+async fn drain_waits_for_open_connections_and_closes_idle_ones() {
+    logs::capture();
+    let registry = Arc::new(InFlightRegistry::new(1, 100));
+
+    // Waits out the settle time, then for the last connection to close
+    let (handle, _) = start(registry.clone());
+    let open = Arc::new(AtomicUsize::new(1));
+    let counted = open.clone();
+    let report = DrainReport::new(registry.clone(), Duration::from_secs(5))
+        .with_connections(move || counted.load(Ordering::SeqCst))
+        .with_settle_time(Duration::from_millis(200));
+    let closing = open.clone();
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(Duration::from_millis(400)).await;
+        closing.store(0, Ordering::SeqCst);
+    });
+    let started = Instant::now();
+    assert!(report.drain(&handle).await);
+    assert!(started.elapsed() >= Duration::from_millis(400));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // Connections accepted just before the pause are given the settle time
+    let (handle, _) = start(registry.clone());
+    let report = DrainReport::new(registry.clone(), Duration::from_secs(5))
+        .with_settle_time(Duration::from_millis(200));
+    let started = Instant::now();
+    assert!(report.drain(&handle).await);
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // Idle connections left at the timeout do not fail the drain
+    let (handle, _) = start(registry.clone());
+    let report = DrainReport::new(registry.clone(), Duration::from_millis(200))
+        .with_connections(|| 2);
+    assert!(report.drain(&handle).await);
+    assert!(logs::contains(
+        "Drain timeout reached: closing 2 idle connections"
+    ));
+}
+
+#[actix_rt::test]
+async fn finished_requests_leave_the_registry() {
+    let registry = Arc::new(InFlightRegistry::new(2, 100));
+    let app = init_service(App::new().wrap(TrackInFlight::new(registry.clone())).route(
+        "/fast",
+        web::get().to(|| async { HttpResponse::Ok().finish() }),
+    ))
+    .await;
+    let req = TestRequest::get().uri("/fast").to_request();
+    assert!(call_service(&app, req).await.status().is_success());
+    assert!(registry.is_empty());
+
+    // Untracked requests are still served
+    let full = Arc::new(InFlightRegistry::new(1, 1));
+    let _held = full.track(request("/held")).unwrap();
+    let before = Metrics::global().counter_value(UNTRACKED_METRIC, &[]);
+    assert!(full.track(request("/over")).is_none());
+    assert_eq!(
+        Metrics::global().counter_value(UNTRACKED_METRIC, &[]),
+        before + 1
+    );
+    assert_eq!(full.len(), 1);
+}
+
+#[actix_rt::test]
+async fn admin_endpoint_lists_the_longest_running_first() {
+    let registry = Arc::new(InFlightRegistry::new(4, 100));
+    let _first = registry.track(request("/first")).unwrap();
+    actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+    let second = registry.track(request("/second")).unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::from(registry.clone()))
+            .route("/admin/in-flight", web::get().to(list_in_flight)),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/admin/in-flight").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let requests = body["requests"].as_array().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["route"], "/first");
+    assert_eq!(requests[0]["request_id"], "req-first");
+    assert_eq!(requests[0]["client_ip"], "192.0.2.7");
+    assert!(requests[0]["elapsed_ms"].as_u64().unwrap() >= 10);
+    assert_eq!(requests[1]["route"], "/second");
+
+    drop(second);
+    assert_eq!(registry.len(), 1);
+}
+
+#[test]
+fn timeouts_are_read_from_the_environment() {
+    let _env = common::env_lock();
+    let registry = Arc::new(InFlightRegistry::new(1, 10));
+    env::remove_var("SHUTDOWN_TIMEOUT_SECS");
+    env::remove_var("DRAIN_REPORT_INTERVAL");
+    let report = DrainReport::from_env(registry.clone()).unwrap();
+    assert_eq!(report.timeout(), DEFAULT_SHUTDOWN_TIMEOUT);
+
+    env::set_var("SHUTDOWN_TIMEOUT_SECS", "5");
+    env::set_var("DRAIN_REPORT_INTERVAL", "500ms");
+    let report = DrainReport::from_env(registry.clone()).unwrap();
+    assert_eq!(report.timeout(), Duration::from_secs(5));
+
+    env::set_var("DRAIN_REPORT_INTERVAL", "0s");
+    let err = DrainReport::from_env(registry.clone())
+        .err()
+        .expect("invalid");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    env::set_var("DRAIN_REPORT_INTERVAL", "5s");
+    env::set_var("SHUTDOWN_TIMEOUT_SECS", "forever");
+    assert!(DrainReport::from_env(registry).is_err());
+    env::remove_var("SHUTDOWN_TIMEOUT_SECS");
+    env::remove_var("DRAIN_REPORT_INTERVAL");
+}
@@ -0,0 +1,219 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{test, web, App, HttpResponse};
+use serde_json::{json, Value};
+
+use main::middleware::dry_run::{DryRun, MAX_DRY_RUN_BODY_BYTES};
+use main::route_meta::{MetadataMap, RouteMetadata};
+
+/// An app whose item handlers count how often they run.
+macro_rules! app {
+    ($dry_run:expr, $calls:expr) => {{
+        let calls = $calls.clone();
+        test::init_service(App::new().wrap($dry_run).route(
+            "/items/{id}",
+            web::route().to(move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { HttpResponse::Ok().body("handled") }
+            }),
+        ))
+        .await
+    }};
+}
+
+fn dry_run(method: Method) -> test::TestRequest {
+    test::TestRequest::default()
+        .method(method)
+        .uri("/items/42")
+        .peer_addr("192.0.2.7:4000".parse().unwrap())
+        .insert_header(("X-Dry-Run", "true"))
+}
+
+async fn assert_intercepted(method: Method, body: Value) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(100), calls);
+    let req = dry_run(method.clone()).set_json(&body).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let got: Value = test::read_body_json(resp).await;
+    assert_eq!(
+        got,
+        json!({
+            "dry_run": true,
+            "would_have": {
+                "method": method.as_str(),
+                "path": "/items/42",
+                "body": body,
+                "handler": "/items/{id}",
+            },
+        })
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn post_is_intercepted() {
+    assert_intercepted(Method::POST, json!({"name": "widget"})).await;
+}
+
+#[actix_web::test]
+async fn put_is_intercepted() {
+    assert_intercepted(Method::PUT, json!({"name": "gadget", "count": 3})).await;
+}
+
+#[actix_web::test]
+async fn patch_is_intercepted() {
+    assert_intercepted(Method::PATCH, json!({"count": 4})).await;
+}
+
+#[actix_web::test]
+async fn delete_is_intercepted() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(100), calls);
+    let resp = test::call_service(&app, dry_run(Method::DELETE).to_request()).await;
+    let got: Value = test::read_body_json(resp).await;
+    assert_eq!(got["would_have"]["method"], "DELETE");
+    assert_eq!(got["would_have"]["body"], Value::Null);
+    assert_eq!(got["would_have"]["handler"], "/items/{id}");
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn text_bodies_and_unknown_routes_are_reported() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(100), calls);
+    let req = dry_run(Method::POST)
+        .uri("/missing")
+        .insert_header((header::CONTENT_TYPE, "text/plain"))
+        .set_payload("hello")
+        .to_request();
+    let got: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(got["would_have"]["body"], "hello");
+    assert_eq!(got["would_have"]["handler"], Value::Null);
+}
+
+#[actix_web::test]
+async fn other_requests_reach_the_handler() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(100), calls);
+
+    let get = dry_run(Method::GET).to_request();
+    assert_eq!(test::call_and_read_body(&app, get).await, "handled");
+
+    let real = test::TestRequest::post().uri("/items/42").to_request();
+    assert_eq!(test::call_and_read_body(&app, real).await, "handled");
+
+    let off = dry_run(Method::POST)
+        .insert_header(("X-Dry-Run", "false"))
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, off).await, "handled");
+
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[actix_web::test]
+async fn dry_runs_have_their_own_rate_limit() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(2), calls);
+    for _ in 0..2 {
+        let resp = test::call_service(&app, dry_run(Method::POST).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let resp = test::call_service(&app, dry_run(Method::POST).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+    // Another client still has its allowance, and real requests are not limited
+    let other = dry_run(Method::POST)
+        .peer_addr("192.0.2.8:4000".parse().unwrap())
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, other).await.status(),
+        StatusCode::OK
+    );
+    let real = test::TestRequest::post()
+        .uri("/items/42")
+        .peer_addr("192.0.2.7:4000".parse().unwrap())
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, real).await, "handled");
+}
+
+#[actix_web::test]
+async fn oversized_bodies_are_rejected() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(100), calls);
+    let req = dry_run(Method::POST)
+        .set_payload(vec![b'x'; MAX_DRY_RUN_BODY_BYTES + 1])
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn secret_fields_are_redacted() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = app!(DryRun::new(100), calls);
+    let req = dry_run(Method::POST)
+        .set_json(json!({
+            "username": "alice",
+            "password": "hunter2",
+            "profile": {"new_password": "hunter3", "tokens": [{"refresh_token": "abc"}]},
+        }))
+        .to_request();
+    let got: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        got["would_have"]["body"],
+        json!({
+            "username": "alice",
+            "password": "[redacted]",
+            "profile": {"new_password": "[redacted]", "tokens": "[redacted]"},
+        })
+    );
+
+    let form = dry_run(Method::POST)
+        .insert_header((header::CONTENT_TYPE, "application/x-www-form-urlencoded"))
+        .set_payload("username=alice&password=hunter2")
+        .to_request();
+    let got: Value = test::call_and_read_body_json(&app, form).await;
+    assert_eq!(
+        got["would_have"]["body"],
+        "username=alice&password=[redacted]"
+    );
+}
+
+#[actix_web::test]
+async fn routes_requiring_credentials_cannot_be_dry_run() {
+    let mut routes = MetadataMap::new();
+    routes.insert(
+        "POST",
+        "/admin/items/{id}",
+        RouteMetadata::new("Admin item").auth_required(),
+    );
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(routes))
+            .wrap(DryRun::new(100))
+            .route(
+                "/admin/items/{id}",
+                web::post().to(move || {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    async { HttpResponse::Ok().body("handled") }
+                }),
+            ),
+    )
+    .await;
+    let req = dry_run(Method::POST)
+        .uri("/admin/items/42")
+        .set_json(json!({"name": "widget"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let got: Value = test::read_body_json(resp).await;
+    assert!(got.to_string().contains("dry_run_not_allowed"));
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+}
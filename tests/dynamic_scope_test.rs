@@ -0,0 +1,137 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use serde_json::Value;
+use std::sync::atomic::Ordering;
+
+use main::admin;
+use main::dynamic_scope::{DynamicScope, Features};
+use main::middleware::api_key::{AdminKey, ApiKeyAuth};
+
+mod common;
+
+use common::logs;
+
+const KEY: &str = "admin-secret";
+
+macro_rules! app {
+    ($features:expr) => {
+        init_service(
+            App::new()
+                .app_data(web::Data::new($features.clone()))
+                .service(
+                    web::scope("/admin")
+                        .wrap(ApiKeyAuth::shared(AdminKey::new(Some(KEY.to_string()))))
+                        .configure(admin::configure),
+                )
+                .service(DynamicScope::new(
+                    &$features,
+                    "beta",
+                    web::scope("/beta").route(
+                        "/items",
+                        web::get().to(|| async { HttpResponse::Ok().body("items") }),
+                    ),
+                ))
+                .route(
+                    "/stable",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await
+    };
+}
+
+macro_rules! admin {
+    ($app:expr, $method:ident, $uri:expr) => {{
+        let req = TestRequest::$method()
+            .uri($uri)
+            .insert_header(("X-Api-Key", KEY))
+            .to_request();
+        let resp = call_service(&$app, req).await;
+        let status = resp.status();
+        let body: Value = read_body_json(resp).await;
+        (status, body)
+    }};
+}
+
+macro_rules! status {
+    ($app:expr, $uri:expr) => {
+        call_service(&$app, TestRequest::get().uri($uri).to_request())
+            .await
+            .status()
+    };
+}
+
+#[test]
+fn initial_states_follow_the_enabled_list() {
+    let all = Features::new(None);
+    assert!(all.register("beta").load(Ordering::Relaxed));
+
+    let listed = Features::new(Some(vec!["beta".to_string()]));
+    listed.register("beta");
+    listed.register("legacy");
+    assert_eq!(listed.is_enabled("beta"), Some(true));
+    assert_eq!(listed.is_enabled("legacy"), Some(false));
+    assert_eq!(listed.is_enabled("missing"), None);
+
+    // Registering again shares the state
+    assert_eq!(listed.set("legacy", true), Some(false));
+    assert!(listed.register("legacy").load(Ordering::Relaxed));
+}
+
+#[actix_web::test]
+async fn disabled_scopes_answer_404_until_enabled() {
+    logs::capture();
+    let features = Features::new(None);
+    let app = app!(features);
+
+    assert_eq!(status!(app, "/beta/items"), StatusCode::OK);
+
+    let (status, body) = admin!(app, post, "/admin/features/beta/disable");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["enabled"], false);
+    assert!(logs::contains("feature_toggled"));
+
+    let resp = call_service(&app, TestRequest::get().uri("/beta/items").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "not_found");
+    assert_eq!(status!(app, "/stable"), StatusCode::OK);
+
+    let (_, body) = admin!(app, get, "/admin/features");
+    assert_eq!(body["features"][0]["name"], "beta");
+    assert_eq!(body["features"][0]["enabled"], false);
+
+    let (status, _) = admin!(app, post, "/admin/features/beta/enable");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(status!(app, "/beta/items"), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn features_start_disabled_unless_listed() {
+    let features = Features::new(Some(vec!["other".to_string()]));
+    let app = app!(features);
+
+    assert_eq!(status!(app, "/beta/items"), StatusCode::NOT_FOUND);
+    admin!(app, post, "/admin/features/beta/enable");
+    assert_eq!(status!(app, "/beta/items"), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn toggling_needs_the_api_key_and_a_known_feature() {
+    let features = Features::new(None);
+    let app = app!(features);
+
+    let req = TestRequest::post()
+        .uri("/admin/features/beta/disable")
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(status!(app, "/beta/items"), StatusCode::OK);
+
+    let (status, body) = admin!(app, post, "/admin/features/missing/disable");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "unknown_feature");
+}
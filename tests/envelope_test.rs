@@ -0,0 +1,113 @@
+use actix_web::{test, web, App, HttpResponse};
+use serde_json::{json, Value};
+
+use main::middleware::envelope::ResponseEnvelope;
+
+async fn object() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "message": "hi" }))
+}
+
+async fn array() -> HttpResponse {
+    HttpResponse::Ok().json(json!([1, 2, 3]))
+}
+
+async fn scalar() -> HttpResponse {
+    HttpResponse::Ok().json(42)
+}
+
+async fn error() -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({ "error": "bad_request" }))
+}
+
+async fn text() -> HttpResponse {
+    HttpResponse::Ok().body("plain text")
+}
+
+macro_rules! app {
+    () => {
+        test::init_service(
+            App::new()
+                .wrap(ResponseEnvelope::with_version("1.2.3"))
+                .route("/object", web::get().to(object))
+                .route("/array", web::get().to(array))
+                .route("/scalar", web::get().to(scalar))
+                .route("/error", web::get().to(error))
+                .route("/text", web::get().to(text)),
+        )
+        .await
+    };
+}
+
+fn assert_standard_fields(body: &Value, request_id: &str) {
+    assert_eq!(body["request_id"], request_id);
+    assert_eq!(body["version"], "1.2.3");
+    assert!(body["timestamp"].is_string(), "timestamp missing: {}", body);
+}
+
+#[actix_rt::test]
+async fn test_object_body_is_merged() {
+    let app = app!();
+    let req = test::TestRequest::get()
+        .uri("/object")
+        .insert_header(("X-Request-Id", "req-1"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["message"], "hi");
+    assert!(body.get("data").is_none());
+    assert_standard_fields(&body, "req-1");
+}
+
+#[actix_rt::test]
+async fn test_array_body_is_wrapped() {
+    let app = app!();
+    let req = test::TestRequest::get()
+        .uri("/array")
+        .insert_header(("X-Request-Id", "req-2"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["data"], json!([1, 2, 3]));
+    assert_standard_fields(&body, "req-2");
+}
+
+#[actix_rt::test]
+async fn test_scalar_body_is_wrapped() {
+    let app = app!();
+    let req = test::TestRequest::get()
+        .uri("/scalar")
+        .insert_header(("X-Request-Id", "req-3"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["data"], 42);
+    assert_standard_fields(&body, "req-3");
+}
+
+#[actix_rt::test]
+async fn test_request_id_is_generated_when_absent() {
+    let app = app!();
+    let req = test::TestRequest::get().uri("/object").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    let request_id = body["request_id"].as_str().expect("request_id missing");
+    assert!(!request_id.is_empty());
+}
+
+#[actix_rt::test]
+async fn test_error_response_is_not_wrapped() {
+    let app = app!();
+    let req = test::TestRequest::get().uri("/error").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body, json!({ "error": "bad_request" }));
+}
+
+#[actix_rt::test]
+async fn test_non_json_response_is_not_wrapped() {
+    let app = app!();
+    let req = test::TestRequest::get().uri("/text").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+
+    assert_eq!(body, "plain text");
+}
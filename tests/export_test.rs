@@ -0,0 +1,194 @@
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use main::error::ApiError;
+use main::util::export::{csv_field, Export, ExportFormat};
+
+#[derive(Serialize)]
+struct Note {
+    id: u32,
+    title: String,
+    tags: Vec<&'static str>,
+    author: Option<&'static str>,
+}
+
+fn notes() -> Vec<Note> {
+    vec![
+        Note {
+            id: 1,
+            title: "plain".into(),
+            tags: vec![],
+            author: Some("alice"),
+        },
+        Note {
+            id: 2,
+            title: "comma, \"quoted\"\nand a line break".into(),
+            tags: vec!["a", "b"],
+            author: None,
+        },
+    ]
+}
+
+async fn list_notes(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    match Export::from_request(&req)? {
+        Some(export) => Ok(export.respond_iter("notes", notes())),
+        None => Ok(HttpResponse::Ok().json(notes())),
+    }
+}
+
+async fn read_all(res: HttpResponse) -> String {
+    let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[test]
+fn csv_fields_are_quoted_when_needed() {
+    assert_eq!(csv_field("plain", ','), "plain");
+    assert_eq!(csv_field("a,b", ','), "\"a,b\"");
+    assert_eq!(csv_field("a,b", ';'), "a,b");
+    assert_eq!(csv_field("say \"hi\"", ','), "\"say \"\"hi\"\"\"");
+    assert_eq!(csv_field("two\nlines", ','), "\"two\nlines\"");
+    assert_eq!(csv_field("cr\r", ','), "\"cr\r\"");
+}
+
+#[actix_rt::test]
+async fn csv_keeps_field_order_and_quotes_values() {
+    let csv = read_all(Export::csv().respond_iter("notes", notes())).await;
+    assert_eq!(
+        csv,
+        "id,title,tags,author\r\n\
+         1,plain,[],alice\r\n\
+         2,\"comma, \"\"quoted\"\"\nand a line break\",\"[\"\"a\"\",\"\"b\"\"]\",\r\n"
+    );
+}
+
+#[actix_rt::test]
+async fn csv_delimiter_and_bom_are_configurable() {
+    let export = Export {
+        delimiter: ';',
+        bom: true,
+        ..Export::csv()
+    };
+    let csv = read_all(export.respond_iter("notes", notes())).await;
+    assert!(csv.starts_with("\u{feff}id;title;tags;author\r\n1;plain;[];alice\r\n"));
+}
+
+#[actix_rt::test]
+async fn ndjson_lines_are_valid_json() {
+    let ndjson = read_all(Export::ndjson().respond_iter("notes", notes())).await;
+    let lines: Vec<Value> = ndjson
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["title"], "comma, \"quoted\"\nand a line break");
+    assert_eq!(lines[1]["tags"], json!(["a", "b"]));
+    assert!(ndjson.ends_with('\n'));
+}
+
+#[actix_rt::test]
+async fn format_is_negotiated() {
+    let app = init_service(App::new().route("/notes", web::get().to(list_notes))).await;
+
+    let req = TestRequest::get().uri("/notes").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+
+    let req = TestRequest::get()
+        .uri("/notes")
+        .insert_header((header::ACCEPT, "text/csv"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/csv; charset=utf-8"
+    );
+    let disposition = resp.headers().get(header::CONTENT_DISPOSITION).unwrap();
+    let disposition = disposition.to_str().unwrap();
+    assert!(disposition.starts_with("attachment; filename=\"notes-"));
+    assert!(disposition.ends_with(".csv\""));
+
+    // The parameter wins over Accept
+    let req = TestRequest::get()
+        .uri("/notes?format=ndjson")
+        .insert_header((header::ACCEPT, "text/csv"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        ExportFormat::Ndjson.content_type()
+    );
+
+    for uri in ["/notes?format=xml", "/notes?format=csv&delimiter=ab"] {
+        let req = TestRequest::get().uri(uri).to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            uri
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct Row {
+    id: usize,
+    name: &'static str,
+}
+
+async fn next_chunk(body: &mut Pin<Box<BoxBody>>) -> Option<usize> {
+    poll_fn(|cx| body.as_mut().poll_next(cx))
+        .await
+        .map(|chunk| chunk.unwrap().len())
+}
+
+#[actix_rt::test]
+async fn large_exports_are_produced_as_they_are_read() {
+    const TOTAL: usize = 1_000_000;
+    let produced = Arc::new(AtomicUsize::new(0));
+    let counter = produced.clone();
+    let rows = (0..TOTAL).map(move |id| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Row { id, name: "row" }
+    });
+    let res = Export::csv().respond_iter("rows", rows);
+    let mut body = Box::pin(res.into_body());
+
+    assert!(next_chunk(&mut body).await.unwrap() > 0);
+    // Give the producer time to run ahead as far as it can
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    let ahead = produced.load(Ordering::SeqCst);
+    assert!(
+        ahead < 10_000,
+        "{} rows produced ahead of the client",
+        ahead
+    );
+
+    // Reading on keeps the producer going
+    for _ in 0..20 {
+        next_chunk(&mut body).await.unwrap();
+    }
+    actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    assert!(produced.load(Ordering::SeqCst) > ahead);
+
+    // A client going away stops it
+    drop(body);
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    let stopped = produced.load(Ordering::SeqCst);
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(produced.load(Ordering::SeqCst), stopped);
+    assert!(stopped < TOTAL);
+}
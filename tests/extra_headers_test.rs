@@ -0,0 +1,38 @@
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+
+use main::middleware::extra_headers::{extra_headers, parse_extra_headers};
+
+#[test]
+fn test_parse_extra_headers() {
+    let headers = parse_extra_headers("X-Region:eu-west-1, X-Cache-Marker: edge").unwrap();
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers[0].0, "x-region");
+    assert_eq!(headers[0].1, "eu-west-1");
+    assert_eq!(headers[1].0, "x-cache-marker");
+    assert_eq!(headers[1].1, "edge");
+
+    assert!(parse_extra_headers("").unwrap().is_empty());
+}
+
+#[test]
+fn test_invalid_extra_headers_are_rejected() {
+    assert!(parse_extra_headers("X-Region").is_err());
+    assert!(parse_extra_headers("Bad Name:value").is_err());
+    assert!(parse_extra_headers("X-Region:line\u{7f}break").is_err());
+}
+
+#[actix_rt::test]
+async fn test_extra_headers_are_injected() {
+    let headers = parse_extra_headers("X-Region:eu-west-1").unwrap();
+    let app = init_service(
+        App::new()
+            .wrap(extra_headers(&headers))
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.headers().get("x-region").unwrap(), "eu-west-1");
+}
@@ -0,0 +1,174 @@
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use std::env;
+
+use main::favicon::{self, Favicons, FAVICON_PATHS};
+use main::metrics::Metrics;
+use main::middleware::access_log::{
+    excluded_paths_from_env, AccessLog, SamplingConfig, SAMPLED_METRIC,
+};
+
+mod common;
+
+macro_rules! app {
+    ($favicons:expr) => {
+        test::init_service(App::new().configure(favicon::configure(web::Data::new($favicons))))
+            .await
+    };
+}
+
+#[actix_rt::test]
+async fn embedded_icons_are_served_with_long_cache_headers() {
+    let app = app!(Favicons::embedded());
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/favicon.ico").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/x-icon"
+    );
+    assert_eq!(
+        resp.headers().get(header::CACHE_CONTROL).unwrap(),
+        "public, max-age=604800"
+    );
+    let body = test::read_body(resp).await;
+    // ICO header: reserved 0, type 1 (icon)
+    assert_eq!(&body[..4], &[0, 0, 1, 0]);
+
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/apple-touch-icon.png")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/png"
+    );
+    assert!(test::read_body(resp).await.starts_with(b"\x89PNG"));
+}
+
+#[actix_rt::test]
+async fn override_files_replace_the_embedded_icons() {
+    let dir = tempfile::tempdir().unwrap();
+    let icon = dir.path().join("icon.svg");
+    std::fs::write(&icon, "<svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap();
+
+    let favicons = {
+        let _env = common::env_lock();
+        env::remove_var("FAVICON_DISABLED");
+        env::remove_var("APPLE_TOUCH_ICON_FILE");
+        env::set_var("FAVICON_FILE", &icon);
+        let favicons = Favicons::from_env().unwrap();
+
+        env::set_var("FAVICON_FILE", dir.path().join("icon.gif"));
+        assert!(Favicons::from_env().is_err());
+        env::set_var("FAVICON_FILE", dir.path().join("missing.png"));
+        assert!(Favicons::from_env().is_err());
+        env::remove_var("FAVICON_FILE");
+        favicons
+    };
+    assert_eq!(
+        favicons.apple_touch_icon,
+        Favicons::embedded().apple_touch_icon
+    );
+
+    let app = app!(favicons);
+    let resp = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/favicon.ico").to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "image/svg+xml"
+    );
+    assert_eq!(
+        test::read_body(resp).await,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\"/>"
+    );
+}
+
+#[actix_rt::test]
+async fn disabled_mode_answers_204_with_cache_headers() {
+    let favicons = {
+        let _env = common::env_lock();
+        env::set_var("FAVICON_DISABLED", "true");
+        env::set_var("FAVICON_FILE", "/does/not/matter.png");
+        let favicons = Favicons::from_env().unwrap();
+        env::remove_var("FAVICON_DISABLED");
+        env::remove_var("FAVICON_FILE");
+        favicons
+    };
+    let app = app!(favicons);
+
+    for path in FAVICON_PATHS {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT, "{}", path);
+        assert_eq!(
+            resp.headers().get(header::CACHE_CONTROL).unwrap(),
+            "public, max-age=604800"
+        );
+        assert!(test::read_body(resp).await.is_empty());
+    }
+}
+
+#[actix_rt::test]
+async fn favicons_are_left_out_of_the_access_log() {
+    let excluded = {
+        let _env = common::env_lock();
+        env::remove_var("ACCESS_LOG_EXCLUDED_PATHS");
+        let excluded = excluded_paths_from_env();
+        env::set_var("ACCESS_LOG_EXCLUDED_PATHS", "");
+        assert!(excluded_paths_from_env().is_empty());
+        env::remove_var("ACCESS_LOG_EXCLUDED_PATHS");
+        excluded
+    };
+    assert_eq!(excluded, FAVICON_PATHS);
+
+    let app = test::init_service(
+        App::new()
+            .wrap(AccessLog::new(SamplingConfig::default()).excluding(excluded))
+            .configure(favicon::configure(web::Data::new(Favicons::embedded())))
+            .route(
+                "/favicon-broken",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            ),
+    )
+    .await;
+
+    let metrics = Metrics::global();
+    let logged = || {
+        metrics.counter_value(SAMPLED_METRIC, &[("decision", "in")])
+            + metrics.counter_value(SAMPLED_METRIC, &[("decision", "out")])
+    };
+    let before = logged();
+    test::call_service(
+        &app,
+        test::TestRequest::get().uri("/favicon.ico").to_request(),
+    )
+    .await;
+    test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/apple-touch-icon.png")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(logged(), before);
+    // Only exact paths are excluded
+    test::call_service(
+        &app,
+        test::TestRequest::get().uri("/favicon-broken").to_request(),
+    )
+    .await;
+    assert_eq!(logged(), before + 1);
+}
@@ -0,0 +1,175 @@
+use rustls::cipher_suite::{
+    TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256,
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256, TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+};
+use rustls::kx_group::{SECP256R1, SECP384R1, X25519};
+use rustls::version::{TLS12, TLS13};
+use rustls::{
+    Certificate, CipherSuite, ClientConfig, ClientConnection, RootCertStore, ServerConfig,
+    ServerConnection, SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion,
+};
+use std::env;
+use std::sync::Arc;
+
+use main::fips::{self, RustlsFipsMode};
+use main::load_tls_config;
+use main::tls_error::TlsConfigError;
+
+mod common;
+
+use common::TestPki;
+
+fn fips_server(pki: &TestPki) -> ServerConfig {
+    RustlsFipsMode { enabled: true }
+        .server_builder()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(pki.server_chain(), pki.server_key())
+        .unwrap()
+}
+
+fn client(
+    pki: &TestPki,
+    suites: &[SupportedCipherSuite],
+    kx_groups: &[&'static SupportedKxGroup],
+    versions: &[&'static SupportedProtocolVersion],
+) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    for der in rustls_pemfile::certs(&mut pki.ca_pem.as_bytes()).unwrap() {
+        roots.add(&Certificate(der)).unwrap();
+    }
+    ClientConfig::builder()
+        .with_cipher_suites(suites)
+        .with_kx_groups(kx_groups)
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// Runs a handshake in memory and returns the negotiated cipher suite.
+fn handshake(server: ServerConfig, client: ClientConfig) -> Result<CipherSuite, rustls::Error> {
+    let mut server = ServerConnection::new(Arc::new(server)).unwrap();
+    let mut client =
+        ClientConnection::new(Arc::new(client), "localhost".try_into().unwrap()).unwrap();
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut to_server = Vec::new();
+        client.write_tls(&mut to_server).unwrap();
+        if !to_server.is_empty() {
+            server.read_tls(&mut to_server.as_slice()).unwrap();
+            server.process_new_packets()?;
+        }
+
+        let mut to_client = Vec::new();
+        server.write_tls(&mut to_client).unwrap();
+        if !to_client.is_empty() {
+            client.read_tls(&mut to_client.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+
+        assert!(
+            !to_server.is_empty() || !to_client.is_empty(),
+            "handshake stalled"
+        );
+    }
+    Ok(client.negotiated_cipher_suite().unwrap().suite())
+}
+
+#[test]
+fn only_aes_gcm_suites_and_nist_curves_are_selected() {
+    let names: Vec<String> = fips::cipher_suites()
+        .iter()
+        .map(|s| format!("{:?}", s.suite()))
+        .collect();
+    assert_eq!(names.len(), 6);
+    assert!(names
+        .iter()
+        .all(|n| n.contains("_AES_") && n.contains("_GCM_")));
+    assert!(!names.iter().any(|n| n.contains("CHACHA20")));
+
+    let groups: Vec<String> = fips::kx_groups()
+        .iter()
+        .map(|g| format!("{:?}", g.name))
+        .collect();
+    assert_eq!(groups, ["secp256r1", "secp384r1"]);
+}
+
+#[test]
+fn fips_server_negotiates_aes_gcm() {
+    let pki = TestPki::generate();
+
+    let offered = client(
+        &pki,
+        &[TLS13_CHACHA20_POLY1305_SHA256, TLS13_AES_128_GCM_SHA256],
+        &[&X25519, &SECP256R1],
+        &[&TLS13],
+    );
+    assert_eq!(
+        handshake(fips_server(&pki), offered).unwrap(),
+        CipherSuite::TLS13_AES_128_GCM_SHA256
+    );
+
+    let tls12 = client(
+        &pki,
+        &[
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        ],
+        &[&SECP384R1],
+        &[&TLS12],
+    );
+    assert_eq!(
+        handshake(fips_server(&pki), tls12).unwrap(),
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+    );
+}
+
+#[test]
+fn fips_server_rejects_chacha20_and_x25519() {
+    let pki = TestPki::generate();
+
+    let chacha = client(
+        &pki,
+        &[TLS13_CHACHA20_POLY1305_SHA256],
+        &[&SECP256R1],
+        &[&TLS13],
+    );
+    assert!(handshake(fips_server(&pki), chacha).is_err());
+
+    let x25519 = client(&pki, &[TLS13_AES_128_GCM_SHA256], &[&X25519], &[&TLS13]);
+    assert!(handshake(fips_server(&pki), x25519).is_err());
+
+    // The default configuration accepts both
+    let chacha = client(
+        &pki,
+        &[TLS13_CHACHA20_POLY1305_SHA256],
+        &[&X25519],
+        &[&TLS13],
+    );
+    assert_eq!(
+        handshake(pki.server_config(), chacha).unwrap(),
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+    );
+}
+
+#[test]
+fn fips_mode_follows_the_build() {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
+    let _env = common::env_lock();
+    env::remove_var("CLIENT_CA_FILE");
+    env::remove_var("MTLS_REQUIRED_PATHS");
+    env::set_var("CERT_FILE", &cert_path);
+    env::set_var("KEY_FILE", &key_path);
+    env::set_var("TLS_FIPS_MODE", "true");
+
+    let result = load_tls_config();
+    env::remove_var("TLS_FIPS_MODE");
+    if cfg!(feature = "fips") {
+        assert!(result.is_ok());
+    } else {
+        assert!(matches!(result, Err(TlsConfigError::FipsNotSupported)));
+    }
+    assert!(load_tls_config().is_ok());
+}
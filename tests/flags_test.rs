@@ -0,0 +1,332 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body_json, TestRequest,
+};
+use actix_web::{web, App, HttpResponse};
+use chrono::{Duration as ChronoDuration, Utc};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use main::admin;
+use main::flags::{
+    bucket, gated, parse_env_flags, FlagDefinition, FlagError, Flags, MAX_OVERRIDE_TTL,
+};
+use main::middleware::feature_flags::FeatureFlagsHeader;
+
+mod common;
+
+use common::logs;
+
+fn flag(name: &str, default: bool, rollout_percent: Option<u8>) -> FlagDefinition {
+    FlagDefinition {
+        name: name.to_string(),
+        description: format!("The {} flag", name),
+        default,
+        rollout_percent,
+    }
+}
+
+fn flags() -> Flags {
+    Flags::new(vec![
+        flag("dark-launch", false, None),
+        flag("on-by-default", true, None),
+        flag("half", false, Some(50)),
+    ])
+    .unwrap()
+}
+
+#[test]
+fn evaluates_defaults() {
+    let flags = flags();
+    let now = Utc::now();
+
+    assert_eq!(
+        flags.evaluate_at("dark-launch", Some("user:alice"), now),
+        Ok(false)
+    );
+    assert_eq!(flags.evaluate_at("on-by-default", None, now), Ok(true));
+    // Callers that cannot be identified get the default of a rollout
+    assert_eq!(flags.evaluate_at("half", None, now), Ok(false));
+}
+
+#[test]
+fn percentage_buckets_are_deterministic() {
+    for subject in ["user:alice", "user:bob", "ip:192.0.2.7"] {
+        assert_eq!(bucket("half", subject), bucket("half", subject));
+        assert!(bucket("half", subject) < 100);
+    }
+
+    let flags = flags();
+    let now = Utc::now();
+    let enabled = (0..1000)
+        .filter(|i| {
+            let subject = format!("user:{}", i);
+            let on = flags.evaluate_at("half", Some(&subject), now).unwrap();
+            assert_eq!(on, bucket("half", &subject) < 50);
+            on
+        })
+        .count();
+    assert!((400..600).contains(&enabled), "{} of 1000 enabled", enabled);
+
+    let none = Flags::new(vec![flag("none", true, Some(0))]).unwrap();
+    let all = Flags::new(vec![flag("all", false, Some(100))]).unwrap();
+    assert_eq!(none.evaluate_at("none", Some("user:alice"), now), Ok(false));
+    assert_eq!(all.evaluate_at("all", Some("user:alice"), now), Ok(true));
+}
+
+#[test]
+fn overrides_win_until_they_expire() {
+    let flags = flags();
+    let now = Utc::now();
+
+    let o = flags
+        .set_override("dark-launch", true, Duration::from_secs(60), now)
+        .unwrap();
+    assert_eq!(o.expires_at, now + ChronoDuration::seconds(60));
+    assert_eq!(flags.evaluate_at("dark-launch", None, now), Ok(true));
+    assert_eq!(
+        flags.evaluate_at("dark-launch", None, now + ChronoDuration::seconds(59)),
+        Ok(true)
+    );
+    assert_eq!(
+        flags.evaluate_at("dark-launch", None, now + ChronoDuration::seconds(60)),
+        Ok(false)
+    );
+
+    flags
+        .set_override("on-by-default", false, Duration::from_secs(60), now)
+        .unwrap();
+    assert_eq!(flags.evaluate_at("on-by-default", None, now), Ok(false));
+    assert_eq!(flags.clear_override("on-by-default"), Ok(true));
+    assert_eq!(flags.evaluate_at("on-by-default", None, now), Ok(true));
+    assert_eq!(flags.clear_override("on-by-default"), Ok(false));
+}
+
+#[test]
+fn override_ttl_is_limited() {
+    let flags = flags();
+    let now = Utc::now();
+
+    assert_eq!(
+        flags.set_override("dark-launch", true, Duration::ZERO, now),
+        Err(FlagError::InvalidTtl)
+    );
+    assert_eq!(
+        flags.set_override(
+            "dark-launch",
+            true,
+            MAX_OVERRIDE_TTL + Duration::from_secs(1),
+            now
+        ),
+        Err(FlagError::InvalidTtl)
+    );
+    assert!(flags
+        .set_override("dark-launch", true, MAX_OVERRIDE_TTL, now)
+        .is_ok());
+}
+
+#[test]
+fn unknown_flags_are_errors() {
+    let flags = flags();
+    let now = Utc::now();
+    let unknown = Err(FlagError::Unknown("missing".to_string()));
+
+    assert_eq!(flags.evaluate_at("missing", None, now), unknown);
+    assert_eq!(
+        flags
+            .set_override("missing", true, Duration::from_secs(60), now)
+            .map(|_| true),
+        unknown
+    );
+    assert_eq!(flags.clear_override("missing"), unknown);
+}
+
+#[test]
+fn rejects_invalid_definitions() {
+    assert!(Flags::new(vec![flag("", false, None)]).is_err());
+    assert!(Flags::new(vec![flag("a", false, Some(101))]).is_err());
+    assert!(Flags::new(vec![flag("a", false, None), flag("a", true, None)]).is_err());
+}
+
+macro_rules! admin_app {
+    ($flags:expr) => {
+        init_service(
+            App::new()
+                .app_data(web::Data::new($flags.clone()))
+                .service(web::scope("/admin").configure(admin::configure)),
+        )
+        .await
+    };
+}
+
+#[actix_web::test]
+async fn admin_overrides_are_audited_and_listed() {
+    logs::capture();
+    let flags = flags();
+    let app = admin_app!(flags);
+
+    let req = TestRequest::put()
+        .uri("/admin/flags/dark-launch")
+        .set_json(json!({ "enabled": true, "ttl_secs": 300 }))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["enabled"], true);
+    assert!(logs::contains("feature_flag_overridden"));
+    assert_eq!(flags.evaluate_at("dark-launch", None, Utc::now()), Ok(true));
+
+    let req = TestRequest::get().uri("/admin/status").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let listed = body["flags"].as_array().unwrap();
+    assert_eq!(listed.len(), 3);
+    let dark = listed.iter().find(|f| f["name"] == "dark-launch").unwrap();
+    assert_eq!(dark["override"]["enabled"], true);
+
+    let req = TestRequest::delete()
+        .uri("/admin/flags/dark-launch")
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(logs::contains("feature_flag_override_cleared"));
+    assert_eq!(
+        flags.evaluate_at("dark-launch", None, Utc::now()),
+        Ok(false)
+    );
+}
+
+#[actix_web::test]
+async fn admin_rejects_unknown_flags_and_bad_ttls() {
+    let app = admin_app!(flags());
+
+    let req = TestRequest::put()
+        .uri("/admin/flags/missing")
+        .set_json(json!({ "enabled": true, "ttl_secs": 300 }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "unknown_flag");
+
+    let req = TestRequest::put()
+        .uri("/admin/flags/dark-launch")
+        .set_json(json!({ "enabled": true, "ttl_secs": 0 }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn debug_header_lists_evaluated_flags() {
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(flags()))
+            .wrap(FeatureFlagsHeader)
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/")
+        .peer_addr("192.0.2.7:4000".parse().unwrap())
+        .to_request();
+    let resp = call_service(&app, req).await;
+    let header = resp
+        .headers()
+        .get("X-Feature-Flags")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let half = if bucket("half", "ip:192.0.2.7") < 50 {
+        "on"
+    } else {
+        "off"
+    };
+    assert_eq!(
+        header,
+        format!("dark-launch=off, half={}, on-by-default=on", half)
+    );
+}
+
+#[actix_web::test]
+async fn gated_routes_answer_404_while_their_flag_is_off() {
+    let flags = flags();
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(flags.clone()))
+            .service(gated(
+                "dark-launch",
+                web::resource("/new")
+                    .route(web::get().to(|| async { HttpResponse::Ok().finish() })),
+            ))
+            .service(gated(
+                "undeclared",
+                web::resource("/later")
+                    .route(web::get().to(|| async { HttpResponse::Ok().finish() })),
+            )),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/new").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "not_found");
+
+    flags
+        .set_override("dark-launch", true, Duration::from_secs(60), Utc::now())
+        .unwrap();
+    let req = TestRequest::get().uri("/new").to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+    // Declaring the flag later turns the route on without a restart
+    let req = TestRequest::get().uri("/later").to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    flags.reload(vec![flag("undeclared", true, None)]).unwrap();
+    let req = TestRequest::get().uri("/later").to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    // The override of the removed flag went with it
+    let req = TestRequest::get().uri("/new").to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[test]
+fn environment_flags_declare_and_force_flags() {
+    assert_eq!(
+        parse_env_flags("a, b=off,c=on ,d=TRUE").unwrap(),
+        [
+            ("a".to_string(), true),
+            ("b".to_string(), false),
+            ("c".to_string(), true),
+            ("d".to_string(), true),
+        ]
+    );
+    assert!(parse_env_flags("a=maybe").is_err());
+    assert!(parse_env_flags("=on").is_err());
+
+    let _env = common::env_lock();
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("flags.json");
+    std::fs::write(
+        &path,
+        r#"[{"name": "half", "rollout_percent": 50}, {"name": "kept", "default": true}]"#,
+    )
+    .unwrap();
+    std::env::set_var("FEATURE_FLAGS_FILE", &path);
+    std::env::set_var("FEATURE_FLAGS", "half=on,extra");
+    let flags = Flags::from_env().unwrap();
+    std::env::remove_var("FEATURE_FLAGS_FILE");
+    std::env::remove_var("FEATURE_FLAGS");
+
+    assert_eq!(flags.names(), ["extra", "half", "kept"]);
+    let now = Utc::now();
+    for subject in ["user:alice", "user:bob", "user:carol"] {
+        assert_eq!(flags.evaluate_at("half", Some(subject), now), Ok(true));
+    }
+    assert_eq!(flags.evaluate_at("extra", None, now), Ok(true));
+    assert_eq!(flags.evaluate_at("kept", None, now), Ok(true));
+}
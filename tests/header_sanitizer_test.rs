@@ -0,0 +1,196 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use chrono::DateTime;
+use serde_json::{Map, Value};
+use std::env;
+use std::io::ErrorKind;
+
+use main::middleware::header_sanitizer::{
+    sanitize_response_headers, sanitize_value, HeaderSanitizer, SanitizeMode,
+};
+use main::util::real_ip::{real_ip, TrustedProxies};
+
+mod common;
+
+/// Echoes the headers the handler sees, and the client address.
+async fn headers(req: HttpRequest) -> HttpResponse {
+    let mut seen = Map::new();
+    for (name, value) in req.headers() {
+        seen.insert(
+            name.to_string(),
+            Value::String(value.to_str().unwrap_or("").to_string()),
+        );
+    }
+    seen.insert(
+        "client".to_string(),
+        Value::String(real_ip(&req).node.to_string()),
+    );
+    HttpResponse::Ok().json(seen)
+}
+
+fn spoofed(peer: &str) -> TestRequest {
+    TestRequest::get()
+        .uri("/")
+        .peer_addr(peer.parse().unwrap())
+        .insert_header(("X-Internal-Auth", "admin"))
+        .insert_header(("X-Real-IP", "10.0.0.1"))
+        .insert_header(("X-Forwarded-For", "198.51.100.9"))
+        .insert_header(("Forwarded", "for=198.51.100.9"))
+        .insert_header(("X-Internal-Received-At", "1970-01-01T00:00:00.000Z"))
+        .insert_header(("X-Custom-Trust", "yes"))
+}
+
+async fn seen(sanitizer: HeaderSanitizer, req: TestRequest) -> Map<String, Value> {
+    let app = init_service(
+        App::new()
+            .app_data(TrustedProxies::parse("10.1.0.0/16").unwrap())
+            .wrap(sanitizer)
+            .route("/", web::get().to(headers)),
+    )
+    .await;
+    call_and_read_body_json(&app, req.to_request()).await
+}
+
+#[actix_web::test]
+async fn untrusted_peers_cannot_spoof_internal_headers() {
+    let sanitizer = HeaderSanitizer::default()
+        .extended("X-Custom-Trust")
+        .unwrap();
+    let seen = seen(sanitizer, spoofed("203.0.113.5:4000")).await;
+    for name in [
+        "x-internal-auth",
+        "x-real-ip",
+        "x-forwarded-for",
+        "forwarded",
+        "x-custom-trust",
+    ] {
+        assert!(!seen.contains_key(name), "{} reached the handler", name);
+    }
+    assert_eq!(seen["client"], "203.0.113.5");
+
+    let received_at = seen["x-internal-received-at"].as_str().unwrap();
+    assert_ne!(received_at, "1970-01-01T00:00:00.000Z");
+    assert!(DateTime::parse_from_rfc3339(received_at).is_ok());
+}
+
+#[actix_web::test]
+async fn trusted_proxy_headers_survive() {
+    let seen = seen(HeaderSanitizer::default(), spoofed("10.1.2.3:4000")).await;
+    assert_eq!(seen["x-internal-auth"], "admin");
+    assert_eq!(seen["x-real-ip"], "10.0.0.1");
+    assert_eq!(seen["forwarded"], "for=198.51.100.9");
+    assert_eq!(seen["client"], "198.51.100.9");
+    // Server-owned headers are replaced even behind a trusted proxy
+    assert_ne!(seen["x-internal-received-at"], "1970-01-01T00:00:00.000Z");
+}
+
+#[actix_web::test]
+async fn duplicate_accept_headers_are_joined() {
+    let req = TestRequest::get()
+        .uri("/")
+        .append_header(("Accept", "text/html"))
+        .append_header(("Accept", "application/json;q=0.9"));
+    let seen = seen(HeaderSanitizer::default(), req).await;
+    assert_eq!(seen["accept"], "text/html, application/json;q=0.9");
+}
+
+#[actix_web::test]
+async fn duplicate_host_headers_are_rejected() {
+    let app = init_service(
+        App::new()
+            .wrap(HeaderSanitizer::default())
+            .route("/", web::get().to(headers)),
+    )
+    .await;
+    let req = TestRequest::get()
+        .uri("/")
+        .append_header(("Host", "example.com"))
+        .append_header(("Host", "evil.example"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn strip_list_is_extendable_and_validated() {
+    let sanitizer = HeaderSanitizer::default()
+        .extended(" X-Tenant-Override , x-real-ip")
+        .unwrap();
+    let names: Vec<&str> = sanitizer.stripped().iter().map(|n| n.as_str()).collect();
+    assert!(names.contains(&"x-tenant-override"));
+    assert_eq!(names.iter().filter(|&&n| n == "x-real-ip").count(), 1);
+
+    assert!(HeaderSanitizer::default().extended("bad header").is_err());
+}
+
+#[test]
+fn line_breaks_in_header_values_are_removed_or_encoded() {
+    for value in [
+        &b"/next\r\nSet-Cookie: session=stolen"[..],
+        b"/next\nSet-Cookie: session=stolen",
+        b"/next\rSet-Cookie: session=stolen",
+        b"\r\n\r\n<script>alert(1)</script>",
+    ] {
+        assert_eq!(sanitize_value(value, SanitizeMode::Remove), None);
+        let encoded = sanitize_value(value, SanitizeMode::Encode).unwrap();
+        assert!(!encoded.as_bytes().contains(&b'\r'));
+        assert!(!encoded.as_bytes().contains(&b'\n'));
+    }
+    assert_eq!(
+        sanitize_value(b"/next\r\nSet-Cookie: a=b", SanitizeMode::Encode).unwrap(),
+        "/next%0D%0ASet-Cookie: a=b"
+    );
+
+    // Clean values are kept as they are, in either mode
+    for mode in [SanitizeMode::Remove, SanitizeMode::Encode] {
+        assert_eq!(sanitize_value(b"/next?a=%0D", mode).unwrap(), "/next?a=%0D");
+    }
+    // Other control characters are still refused
+    assert_eq!(sanitize_value(b"a\0b", SanitizeMode::Encode), None);
+}
+
+#[actix_web::test]
+async fn clean_response_headers_pass_through() {
+    let app = init_service(
+        App::new()
+            .wrap(HeaderSanitizer::default().with_mode(SanitizeMode::Encode))
+            .route(
+                "/",
+                web::get().to(|| async {
+                    HttpResponse::Found()
+                        .append_header(("Location", "/next"))
+                        .append_header(("Set-Cookie", "a=1"))
+                        .append_header(("Set-Cookie", "b=2"))
+                        .finish()
+                }),
+            ),
+    )
+    .await;
+    let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(resp.headers().get("location").unwrap(), "/next");
+    assert_eq!(resp.headers().get_all("set-cookie").count(), 2);
+
+    let mut headers = resp.headers().clone();
+    assert!(sanitize_response_headers(&mut headers, SanitizeMode::Remove).is_empty());
+    assert_eq!(headers.len(), resp.headers().len());
+}
+
+#[test]
+fn sanitize_mode_is_read_from_the_environment() {
+    let _env = common::env_lock();
+    env::remove_var("HEADER_SANITIZE_MODE");
+    assert_eq!(
+        HeaderSanitizer::from_env().unwrap().mode(),
+        SanitizeMode::Remove
+    );
+    env::set_var("HEADER_SANITIZE_MODE", "Encode");
+    assert_eq!(
+        HeaderSanitizer::from_env().unwrap().mode(),
+        SanitizeMode::Encode
+    );
+    env::set_var("HEADER_SANITIZE_MODE", "strip");
+    let err = HeaderSanitizer::from_env().err().expect("invalid mode");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    env::remove_var("HEADER_SANITIZE_MODE");
+}
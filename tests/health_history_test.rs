@@ -0,0 +1,200 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use main::health::{
+    self, DependencyHealthCheck, HealthHistory, HealthRegistry, HealthSample, HealthStatus,
+    HealthTrend,
+};
+use main::lifecycle::Lifecycle;
+
+struct Mock(HealthStatus);
+
+#[async_trait]
+impl DependencyHealthCheck for Mock {
+    async fn check(&self) -> HealthStatus {
+        self.0.clone()
+    }
+}
+
+fn now() -> DateTime<Utc> {
+    "2026-01-01T12:00:00Z".parse().unwrap()
+}
+
+/// A history with one sample per status, `seconds_apart` apart, the last
+/// one at `now()`.
+fn history_of(statuses: &[&'static str], seconds_apart: i64, latency_ms: u64) -> HealthHistory {
+    let history = HealthHistory::new(statuses.len());
+    for (i, status) in statuses.iter().enumerate() {
+        let age = (statuses.len() - 1 - i) as i64 * seconds_apart;
+        history.push(HealthSample {
+            timestamp: now() - chrono::Duration::seconds(age),
+            status,
+            latency_ms,
+        });
+    }
+    history
+}
+
+#[test]
+fn trend_of_empty_history_is_zero() {
+    let trend = HealthTrend::compute_at(&HealthHistory::new(10), now());
+    assert_eq!(trend.error_rate_last_5min, 0.0);
+    assert_eq!(trend.avg_latency_ms, 0.0);
+    assert_eq!(trend.flap_count, 0);
+}
+
+#[test]
+fn trend_of_steady_health() {
+    let history = history_of(&["ready", "ready", "degraded", "ready"], 10, 20);
+    let trend = HealthTrend::compute_at(&history, now());
+    assert_eq!(trend.error_rate_last_5min, 0.0);
+    assert_eq!(trend.avg_latency_ms, 20.0);
+    // Degraded still serves traffic
+    assert_eq!(trend.flap_count, 0);
+}
+
+#[test]
+fn trend_of_steady_outage_does_not_flap() {
+    let history = history_of(&["unavailable"; 5], 10, 5);
+    let trend = HealthTrend::compute_at(&history, now());
+    assert_eq!(trend.error_rate_last_5min, 1.0);
+    assert_eq!(trend.flap_count, 0);
+}
+
+#[test]
+fn trend_counts_flaps() {
+    let history = history_of(
+        &[
+            "ready",
+            "unavailable",
+            "ready",
+            "unavailable",
+            "ready",
+            "ready",
+        ],
+        10,
+        10,
+    );
+    let trend = HealthTrend::compute_at(&history, now());
+    assert_eq!(trend.flap_count, 4);
+    assert!((trend.error_rate_last_5min - 2.0 / 6.0).abs() < 1e-9);
+}
+
+#[test]
+fn trend_error_rate_ignores_old_failures() {
+    // One sample a minute: the three failures are 7-9 minutes old
+    let history = history_of(
+        &[
+            "unavailable",
+            "unavailable",
+            "unavailable",
+            "ready",
+            "ready",
+            "ready",
+            "ready",
+            "unavailable",
+            "ready",
+            "ready",
+        ],
+        60,
+        10,
+    );
+    let trend = HealthTrend::compute_at(&history, now());
+    // Samples 0-5 minutes old: ready, ready, ready, unavailable, ready, ready
+    assert!((trend.error_rate_last_5min - 1.0 / 6.0).abs() < 1e-9);
+    assert_eq!(trend.flap_count, 3);
+}
+
+#[test]
+fn trend_averages_latency_over_whole_history() {
+    let history = HealthHistory::new(3);
+    for (age, latency_ms) in [(600, 100), (30, 20), (0, 30)] {
+        history.push(HealthSample {
+            timestamp: now() - chrono::Duration::seconds(age),
+            status: "ready",
+            latency_ms,
+        });
+    }
+    assert_eq!(
+        HealthTrend::compute_at(&history, now()).avg_latency_ms,
+        50.0
+    );
+}
+
+#[test]
+fn history_drops_oldest_when_full() {
+    let history = HealthHistory::new(2);
+    history.record("ready", Duration::from_millis(1));
+    history.record("unavailable", Duration::from_millis(2));
+    history.record("degraded", Duration::from_millis(3));
+    let statuses: Vec<_> = history.samples().iter().map(|s| s.status).collect();
+    assert_eq!(statuses, ["unavailable", "degraded"]);
+}
+
+#[actix_rt::test]
+async fn ready_results_are_listed_and_summarized() {
+    let mut registry = HealthRegistry::new(Duration::from_secs(1));
+    registry.register(
+        "db",
+        Arc::new(Mock(HealthStatus::Unhealthy("refused".into()))),
+        true,
+    );
+    let lifecycle = web::Data::new(Lifecycle::new());
+    let app = init_service(
+        App::new()
+            .app_data(lifecycle.clone())
+            .app_data(web::Data::new(registry))
+            .app_data(web::Data::new(HealthHistory::new(10)))
+            .route("/ready", web::get().to(health::ready))
+            .route("/health/history", web::get().to(health::history::history))
+            .route("/health/trend", web::get().to(health::history::trend)),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let req = TestRequest::get().uri("/ready").to_request();
+        call_service(&app, req).await;
+    }
+    lifecycle.mark_not_ready();
+    let req = TestRequest::get().uri("/ready").to_request();
+    call_service(&app, req).await;
+
+    let req = TestRequest::get().uri("/health/history").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let entries = body.as_array().unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0]["status"], "unavailable");
+    assert_eq!(entries[2]["status"], "shutting_down");
+    assert!(entries[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+    assert!(entries[0]["latency_ms"].is_u64());
+
+    let req = TestRequest::get().uri("/health/trend").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["error_rate_last_5min"], 1.0);
+    assert_eq!(body["flap_count"], 0);
+}
+
+#[actix_rt::test]
+async fn endpoints_without_history_are_empty() {
+    let app = init_service(
+        App::new()
+            .route("/health/history", web::get().to(health::history::history))
+            .route("/health/trend", web::get().to(health::history::trend)),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/health/history").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body, serde_json::json!([]));
+
+    let req = TestRequest::get().uri("/health/trend").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["flap_count"], 0);
+    assert_eq!(body["avg_latency_ms"], 0.0);
+}
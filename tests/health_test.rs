@@ -0,0 +1,363 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use async_trait::async_trait;
+use serde_json::Value;
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use main::health::checks::{PostgresDependency, RedisDependency, TcpDependency};
+use main::health::{self, DependencyHealthCheck, HealthRegistry, HealthStatus};
+use main::lifecycle::Lifecycle;
+
+struct Mock(HealthStatus);
+
+#[async_trait]
+impl DependencyHealthCheck for Mock {
+    async fn check(&self) -> HealthStatus {
+        self.0.clone()
+    }
+}
+
+struct Hanging;
+
+#[async_trait]
+impl DependencyHealthCheck for Hanging {
+    async fn check(&self) -> HealthStatus {
+        actix_web::rt::time::sleep(Duration::from_secs(60)).await;
+        HealthStatus::Healthy
+    }
+}
+
+/// Slow check counting how often it actually runs.
+struct Counting(Arc<AtomicUsize>);
+
+#[async_trait]
+impl DependencyHealthCheck for Counting {
+    async fn check(&self) -> HealthStatus {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+        HealthStatus::Healthy
+    }
+}
+
+fn up() -> Arc<dyn DependencyHealthCheck> {
+    Arc::new(Mock(HealthStatus::Healthy))
+}
+
+fn down() -> Arc<dyn DependencyHealthCheck> {
+    Arc::new(Mock(HealthStatus::Unhealthy("connection refused".into())))
+}
+
+macro_rules! app {
+    ($registry:expr) => {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(Lifecycle::new()))
+                .app_data(web::Data::new($registry))
+                .route("/ready", web::get().to(health::ready))
+                .route(
+                    "/health/dependency/{name}",
+                    web::get().to(health::dependency),
+                ),
+        )
+        .await
+    };
+}
+
+macro_rules! get {
+    ($app:expr, $uri:expr) => {{
+        let req = test::TestRequest::get().uri($uri).to_request();
+        let resp = test::call_service(&$app, req).await;
+        let status = resp.status();
+        let body: Value = test::read_body_json(resp).await;
+        (status, body)
+    }};
+}
+
+#[actix_rt::test]
+async fn test_ready_when_all_dependencies_are_up() {
+    let mut registry = HealthRegistry::default();
+    registry
+        .register("db", up(), true)
+        .register("cache", up(), false);
+    let app = app!(registry);
+
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["dependencies"].as_array().unwrap().len(), 2);
+}
+
+#[actix_rt::test]
+async fn test_noncritical_failure_degrades() {
+    let mut registry = HealthRegistry::default();
+    registry
+        .register("db", up(), true)
+        .register("cache", down(), false);
+    let app = app!(registry);
+
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "degraded");
+    let cache = &body["dependencies"][1];
+    assert_eq!(cache["status"], "down");
+    assert_eq!(cache["error"], "connection refused");
+}
+
+#[actix_rt::test]
+async fn test_critical_failure_is_unavailable() {
+    let mut registry = HealthRegistry::default();
+    registry
+        .register("db", down(), true)
+        .register("cache", up(), false);
+    let app = app!(registry);
+
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "unavailable");
+}
+
+#[actix_rt::test]
+async fn test_slow_check_times_out() {
+    let mut registry = HealthRegistry::new(Duration::from_millis(50));
+    registry.register("slow", Arc::new(Hanging), true);
+    let app = app!(registry);
+
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(body["dependencies"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("timed out"));
+}
+
+#[actix_rt::test]
+async fn test_single_dependency_endpoint() {
+    let mut registry = HealthRegistry::default();
+    registry
+        .register("db", up(), true)
+        .register("cache", down(), false);
+    let app = app!(registry);
+
+    let (status, body) = get!(app, "/health/dependency/db");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["name"], "db");
+    assert_eq!(body["status"], "up");
+
+    let (status, _) = get!(app, "/health/dependency/cache");
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    let (status, body) = get!(app, "/health/dependency/missing");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "unknown_dependency");
+}
+
+/// Serves one connection, answering whatever it reads with `reply`.
+fn stub_server(reply: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    actix_web::rt::spawn(async move {
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 64];
+        let _ = stream.read(&mut buf).await;
+        let _ = stream.write_all(reply).await;
+    });
+    addr
+}
+#[actix_rt::test]
+async fn test_concurrent_probes_share_one_check() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut registry = HealthRegistry::default();
+    registry.register("db", Arc::new(Counting(calls.clone())), true);
+
+    let results = futures_util::future::join_all((0..10).map(|_| registry.check_all())).await;
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(results.iter().all(|r| r.len() == 1 && r[0].is_up()));
+
+    // A probe after the round finished runs the check again
+    registry.check_all().await;
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[actix_rt::test]
+async fn test_reports_are_cached() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut registry = HealthRegistry::default().with_cache_ttl(Duration::from_secs(60));
+    registry.register("db", Arc::new(Counting(calls.clone())), true);
+    let app = app!(registry);
+
+    for _ in 0..3 {
+        let (status, body) = get!(app, "/ready");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+fn closed_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[actix_rt::test]
+async fn test_postgres_check() {
+    let addr = stub_server(b"N");
+    assert_eq!(
+        PostgresDependency::new(&addr).check().await,
+        HealthStatus::Healthy
+    );
+
+    let addr = stub_server(b"E");
+    assert!(!PostgresDependency::new(&addr).check().await.is_healthy());
+
+    let addr = closed_port();
+    assert!(!PostgresDependency::new(&addr).check().await.is_healthy());
+}
+
+#[actix_rt::test]
+async fn test_redis_check() {
+    let addr = stub_server(b"+PONG\r\n");
+    assert_eq!(
+        RedisDependency::new(&addr).check().await,
+        HealthStatus::Healthy
+    );
+
+    let addr = stub_server(b"-NOAUTH Authentication required.\r\n");
+    assert_eq!(
+        RedisDependency::new(&addr).check().await,
+        HealthStatus::Healthy
+    );
+
+    let addr = stub_server(b"-ERR unknown command\r\n");
+    assert!(!RedisDependency::new(&addr).check().await.is_healthy());
+}
+
+/// Returns a listener whose accept queue is full, so new connections are
+/// never answered, with the connections filling it. Both must be kept alive.
+fn saturated_listener() -> (Socket, Vec<TcpStream>, SocketAddr) {
+    let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    listener
+        .bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())
+        .unwrap();
+    listener.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap().as_socket().unwrap();
+    let mut held = Vec::new();
+    // Once the queue is full the SYNs are dropped and connecting times out
+    while let Ok(stream) = TcpStream::connect_timeout(&addr, Duration::from_millis(200)) {
+        held.push(stream);
+        assert!(held.len() < 64, "accept queue never filled");
+    }
+    (listener, held, addr)
+}
+
+/// Counts the connections waiting in `listener`'s accept queue.
+fn pending_connections(listener: &TcpListener) -> usize {
+    listener.set_nonblocking(true).unwrap();
+    std::iter::from_fn(|| listener.accept().ok()).count()
+}
+
+#[actix_rt::test]
+async fn test_tcp_targets_decide_readiness() {
+    let accepting = TcpListener::bind("127.0.0.1:0").unwrap();
+    let accepting_addr = accepting.local_addr().unwrap().to_string();
+    let refusing = closed_port();
+
+    let mut registry = HealthRegistry::new(Duration::from_millis(300));
+    registry
+        .register("api", Arc::new(TcpDependency::new(&accepting_addr)), true)
+        .register("search", Arc::new(TcpDependency::new(&refusing)), false);
+    let app = app!(registry);
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "degraded");
+    assert_eq!(body["dependencies"][0]["status"], "up");
+    assert!(body["dependencies"][0]["latency_ms"].is_number());
+    assert_eq!(body["dependencies"][1]["status"], "down");
+    assert!(body["dependencies"][1]["error"].is_string());
+
+    // A listener with a full accept queue never answers the SYN
+    let (_silent, _held, silent_addr) = saturated_listener();
+    let mut registry = HealthRegistry::new(Duration::from_millis(300));
+    registry
+        .register("api", Arc::new(TcpDependency::new(&accepting_addr)), true)
+        .register(
+            "db",
+            Arc::new(TcpDependency::new(&silent_addr.to_string())),
+            true,
+        );
+    let app = app!(registry);
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "unavailable");
+    assert_eq!(body["dependencies"][1]["status"], "down");
+}
+
+#[actix_rt::test]
+async fn test_tls_handshake_is_required() {
+    // Accepts but never speaks TLS
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = silent.local_addr().unwrap().to_string();
+    let check = TcpDependency::new(&addr)
+        .with_tls("localhost", &[])
+        .unwrap();
+
+    let mut registry = HealthRegistry::new(Duration::from_millis(200));
+    registry.register("upstream", Arc::new(check), true);
+    let app = app!(registry);
+    let (status, body) = get!(app, "/ready");
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(body["dependencies"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("timed out"));
+
+    // A plain server closing the connection fails the handshake
+    let addr = stub_server(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+    let check = TcpDependency::new(&addr)
+        .with_tls("localhost", &[])
+        .unwrap();
+    assert!(!check.check().await.is_healthy());
+
+    assert!(TcpDependency::new(&addr)
+        .with_tls("not a host", &[])
+        .is_err());
+}
+
+#[actix_rt::test]
+async fn test_tcp_targets_are_dialed_once_per_interval() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut registry = HealthRegistry::default();
+    registry.register_with_interval(
+        "api",
+        Arc::new(TcpDependency::new(&addr)),
+        true,
+        Duration::from_secs(60),
+    );
+    let app = app!(registry);
+
+    for _ in 0..3 {
+        let (status, body) = get!(app, "/ready");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["dependencies"][0]["status"], "up");
+    }
+    let (status, _) = get!(app, "/health/dependency/api");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(pending_connections(&listener), 1);
+
+    // Without an interval every probe dials
+    let mut registry = HealthRegistry::default();
+    registry.register("api", Arc::new(TcpDependency::new(&addr)), true);
+    let app = app!(registry);
+    for _ in 0..3 {
+        get!(app, "/ready");
+    }
+    assert_eq!(pending_connections(&listener), 3);
+}
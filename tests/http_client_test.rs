@@ -0,0 +1,100 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use std::env;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use main::health::checks::ExternalHttpDependency;
+use main::health::{DependencyHealthCheck, HealthStatus};
+use main::outbound::client::{DEFAULT_POOL_MAX_IDLE_PER_HOST, DEFAULT_TIMEOUT};
+use main::outbound::{HttpClient, HttpClientSettings};
+
+mod common;
+
+/// Starts a server answering `/health`, counting the connections it accepts.
+fn start() -> (u16, Arc<AtomicUsize>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/health",
+            web::get().to(|| async { HttpResponse::Ok().finish() }),
+        )
+    })
+    .on_connect(move |_, _| {
+        accepted.fetch_add(1, Ordering::SeqCst);
+    })
+    .workers(1)
+    .disable_signals()
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_web::rt::spawn(server);
+    (port, connections)
+}
+
+#[actix_rt::test]
+async fn dependency_checks_reuse_pooled_connections() {
+    let (port, connections) = start();
+    let client = HttpClient::new(HttpClientSettings::default()).unwrap();
+    let url = format!("http://127.0.0.1:{}/health", port);
+    let first = ExternalHttpDependency::new(&url, &client);
+    let second = ExternalHttpDependency::new(&url, &client);
+
+    for _ in 0..3 {
+        assert_eq!(first.check().await, HealthStatus::Healthy);
+        assert_eq!(second.check().await, HealthStatus::Healthy);
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn settings_are_read_from_the_environment() {
+    let _env = common::env_lock();
+    let names = [
+        "HTTP_CLIENT_CONNECT_TIMEOUT_MS",
+        "HTTP_CLIENT_TIMEOUT_MS",
+        "HTTP_CLIENT_POOL_IDLE_TIMEOUT_SECS",
+        "HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST",
+    ];
+    for name in names {
+        env::remove_var(name);
+    }
+    let settings = HttpClientSettings::from_env().unwrap();
+    assert_eq!(settings, HttpClientSettings::default());
+    assert_eq!(settings.timeout, DEFAULT_TIMEOUT);
+    assert_eq!(
+        settings.pool_max_idle_per_host,
+        DEFAULT_POOL_MAX_IDLE_PER_HOST
+    );
+
+    env::set_var("HTTP_CLIENT_CONNECT_TIMEOUT_MS", "250");
+    env::set_var("HTTP_CLIENT_TIMEOUT_MS", "2000");
+    env::set_var("HTTP_CLIENT_POOL_IDLE_TIMEOUT_SECS", "0");
+    env::set_var("HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST", "2");
+    let client = HttpClient::from_env().unwrap();
+    assert_eq!(
+        client.settings(),
+        &HttpClientSettings {
+            connect_timeout: Duration::from_millis(250),
+            timeout: Duration::from_secs(2),
+            pool_idle_timeout: Duration::ZERO,
+            pool_max_idle_per_host: 2,
+        }
+    );
+
+    env::set_var("HTTP_CLIENT_TIMEOUT_MS", "0");
+    let err = HttpClient::from_env().err().expect("zero timeout");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    env::set_var("HTTP_CLIENT_TIMEOUT_MS", "2000");
+    env::set_var("HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST", "many");
+    let err = HttpClient::from_env().err().expect("not a number");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    for name in names {
+        env::remove_var(name);
+    }
+}
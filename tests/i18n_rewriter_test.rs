@@ -0,0 +1,158 @@
+use actix_web::http::header::{ACCEPT_LANGUAGE, CONTENT_TYPE};
+use actix_web::test::{call_and_read_body, call_and_read_body_json, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use main::middleware::i18n::{supported_languages, I18nRewriter, Translations};
+
+fn strings(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn translations() -> Translations {
+    Translations::new(vec![
+        (
+            "en".to_string(),
+            strings(&[
+                ("greeting", "Hello"),
+                ("farewell", "Goodbye"),
+                ("quoted", "Say \"hi\""),
+            ]),
+        ),
+        ("fr".to_string(), strings(&[("greeting", "Bonjour")])),
+        ("pt-BR".to_string(), strings(&[("greeting", "Olá")])),
+    ])
+}
+
+#[test]
+fn test_language_negotiation() {
+    let t = translations();
+
+    assert_eq!(t.negotiate(Some("fr, en-US;q=0.9")), "fr");
+    assert_eq!(t.negotiate(Some("de, fr;q=0.5")), "fr");
+    // A regional tag matches its primary language
+    assert_eq!(t.negotiate(Some("fr-CA")), "fr");
+    assert_eq!(t.negotiate(Some("pt-br")), "pt-br");
+    // Nothing acceptable: English
+    assert_eq!(t.negotiate(Some("de")), "en");
+    assert_eq!(t.negotiate(None), "en");
+}
+
+#[test]
+fn test_placeholders_are_replaced() {
+    let t = translations();
+
+    assert_eq!(
+        t.rewrite("fr", "{greeting}, Alice! {farewell}.", false),
+        "Bonjour, Alice! Goodbye."
+    );
+    // Unknown keys and non-key braces are left untouched
+    assert_eq!(
+        t.rewrite("fr", "{unknown} {} { x }", false),
+        "{unknown} {} { x }"
+    );
+    assert_eq!(t.rewrite("en", "{{greeting}}", false), "{Hello}");
+    // Values are escaped inside JSON
+    assert_eq!(
+        t.rewrite("en", r#"{"message":"{quoted}"}"#, true),
+        r#"{"message":"Say \"hi\""}"#
+    );
+}
+
+#[test]
+fn test_missing_keys_fall_back_to_english() {
+    let t = translations();
+
+    assert_eq!(t.lookup("fr", "greeting"), Some("Bonjour"));
+    assert_eq!(t.lookup("fr", "farewell"), Some("Goodbye"));
+    assert_eq!(t.lookup("de", "greeting"), Some("Hello"));
+    assert_eq!(t.lookup("fr", "unknown"), None);
+}
+
+#[test]
+fn test_translations_load_from_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("en.json"), r#"{"greeting": "Hello"}"#).unwrap();
+    fs::write(dir.path().join("fr.json"), r#"{"greeting": "Bonjour"}"#).unwrap();
+    fs::write(dir.path().join("README.txt"), "not a translation").unwrap();
+
+    let t = Translations::load(dir.path()).expect("Failed to load translations");
+    assert_eq!(t.languages(), vec!["en", "fr"]);
+    assert_eq!(t.lookup("fr", "greeting"), Some("Bonjour"));
+
+    fs::write(dir.path().join("de.json"), r#"{"greeting": 1}"#).unwrap();
+    assert!(Translations::load(dir.path()).is_err());
+}
+
+#[actix_rt::test]
+async fn test_text_and_json_responses_are_translated() {
+    let app = init_service(
+        App::new()
+            .wrap(I18nRewriter::new(Arc::new(translations())))
+            .route(
+                "/text",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .content_type("text/plain; charset=utf-8")
+                        .body("{greeting}!")
+                }),
+            )
+            .route(
+                "/json",
+                web::get()
+                    .to(|| async { HttpResponse::Ok().json(json!({"message": "{greeting}"})) }),
+            )
+            .route(
+                "/html",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header((CONTENT_TYPE, "text/html"))
+                        .body("{greeting}")
+                }),
+            ),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/text")
+        .insert_header((ACCEPT_LANGUAGE, "fr, en-US;q=0.9"))
+        .to_request();
+    let body = call_and_read_body(&app, req).await;
+    assert_eq!(body, "Bonjour!");
+
+    let req = TestRequest::get()
+        .uri("/json")
+        .insert_header((ACCEPT_LANGUAGE, "de"))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["message"], "Hello");
+
+    let req = TestRequest::get()
+        .uri("/html")
+        .insert_header((ACCEPT_LANGUAGE, "fr"))
+        .to_request();
+    let body = call_and_read_body(&app, req).await;
+    assert_eq!(body, "{greeting}");
+}
+
+#[actix_rt::test]
+async fn test_supported_languages_are_listed() {
+    let app = init_service(App::new().app_data(web::Data::new(translations())).route(
+        "/admin/i18n/supported-languages",
+        web::get().to(supported_languages),
+    ))
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/admin/i18n/supported-languages")
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["languages"], json!(["en", "fr", "pt-br"]));
+    assert_eq!(body["fallback"], "en");
+}
@@ -0,0 +1,102 @@
+use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App};
+use fluent_bundle::FluentArgs;
+
+use main::hello;
+use main::i18n::{parse_accept_language, I18n};
+use main::middleware::locale::LocaleNegotiation;
+
+fn load() -> I18n {
+    I18n::load("locales", "en-US").expect("Failed to load translations")
+}
+
+#[test]
+fn test_accept_language_is_ordered_by_quality() {
+    assert_eq!(
+        parse_accept_language("en-US;q=0.5, fr, de;q=0.8, *;q=0.1, it;q=0"),
+        vec!["fr", "de", "en-US"]
+    );
+}
+
+#[test]
+fn test_negotiation_order() {
+    let i18n = load();
+
+    // The ?lang= override wins over Accept-Language
+    assert_eq!(i18n.negotiate(Some("fr"), Some("en-US")).to_string(), "fr");
+    // Accept-Language is used by quality, skipping unavailable locales
+    assert_eq!(
+        i18n.negotiate(None, Some("de, fr;q=0.9, en-US;q=0.8"))
+            .to_string(),
+        "fr"
+    );
+    // A regional variant matches the language-only bundle
+    assert_eq!(i18n.negotiate(None, Some("fr-CA")).to_string(), "fr");
+    // Unknown override falls through to Accept-Language
+    assert_eq!(i18n.negotiate(Some("xx"), Some("fr")).to_string(), "fr");
+    // Nothing matches: default locale
+    assert_eq!(i18n.negotiate(None, Some("de")).to_string(), "en-US");
+    assert_eq!(i18n.negotiate(None, None).to_string(), "en-US");
+}
+
+#[test]
+fn test_missing_key_falls_back_to_english() {
+    let i18n = load();
+    let fr = i18n.negotiate(Some("fr"), None);
+
+    let mut args = FluentArgs::new();
+    args.set("name", "Alice");
+    // `greeting` is only defined in English
+    assert_eq!(i18n.format(&fr, "greeting", Some(&args)), "Hello, Alice!");
+    // Unknown keys are returned as-is
+    assert_eq!(i18n.format(&fr, "does-not-exist", None), "does-not-exist");
+}
+
+#[test]
+fn test_placeables_and_plurals() {
+    let i18n = load();
+    let en = i18n.negotiate(Some("en-US"), None);
+    let fr = i18n.negotiate(Some("fr"), None);
+
+    let format = |locale, count: i64| {
+        let mut args = FluentArgs::new();
+        args.set("count", count);
+        i18n.format(locale, "items-count", Some(&args))
+    };
+
+    assert_eq!(format(&en, 1), "You have one item.");
+    assert_eq!(format(&en, 5), "You have 5 items.");
+    assert_eq!(format(&fr, 1), "Vous avez 1 élément.");
+    assert_eq!(format(&fr, 5), "Vous avez 5 éléments.");
+}
+
+#[test]
+fn test_missing_default_locale_fails() {
+    assert!(I18n::load("locales", "de").is_err());
+    assert!(I18n::load("non_existent_locales", "en-US").is_err());
+}
+
+#[actix_rt::test]
+async fn test_hello_is_localized() {
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(load()))
+            .wrap(LocaleNegotiation)
+            .route("/hello", web::get().to(hello)),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/hello")
+        .insert_header(("Accept-Language", "fr-FR, en;q=0.5"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.headers().get("content-language").unwrap(), "fr");
+    assert_eq!(read_body(resp).await, "Bonjour le monde !");
+
+    let req = TestRequest::get()
+        .uri("/hello?lang=en-US")
+        .insert_header(("Accept-Language", "fr"))
+        .to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "Hello world!");
+}
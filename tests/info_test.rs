@@ -0,0 +1,60 @@
+use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+use actix_web::{web, App};
+use serde_json::Value;
+
+use main::admin;
+use main::info::{ServerInfo, ENABLED_FEATURES};
+
+#[test]
+fn every_field_is_filled_in() {
+    let info = ServerInfo::current();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    for (name, value) in [
+        ("git_commit", &info.git_commit),
+        ("build_date", &info.build_date),
+        ("rust_version", &info.rust_version),
+        ("os", &info.os),
+        ("arch", &info.arch),
+        ("tls_provider", &info.tls_provider),
+    ] {
+        assert!(!value.is_empty(), "{} is empty", name);
+    }
+    assert_eq!(info.build_date.len(), "YYYY-MM-DD".len());
+    assert!(info.rust_version.starts_with("rustc "));
+    assert_eq!(info.pid, std::process::id());
+    assert_eq!(info.features_enabled, ENABLED_FEATURES);
+    #[cfg(feature = "core")]
+    assert!(info.features_enabled.iter().any(|f| f == "core"));
+}
+
+#[actix_rt::test]
+async fn endpoints_return_the_info() {
+    let app =
+        init_service(App::new().service(web::scope("/admin").configure(admin::configure))).await;
+
+    let req = TestRequest::get().uri("/admin/info").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    for field in [
+        "version",
+        "git_commit",
+        "build_date",
+        "rust_version",
+        "os",
+        "arch",
+        "tls_provider",
+    ] {
+        assert!(
+            body[field].as_str().is_some_and(|v| !v.is_empty()),
+            "{} missing from {}",
+            field,
+            body
+        );
+    }
+    assert!(body["features_enabled"].is_array());
+    assert!(body["uptime_secs"].is_u64());
+    assert_eq!(body["pid"], std::process::id());
+
+    let req = TestRequest::get().uri("/admin/info/features").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["features"], serde_json::json!(ENABLED_FEATURES));
+}
@@ -1,31 +1,51 @@
-use actix_web::{test, web, App};
-use reqwest::Client;
+use actix_web::{test, web, App, HttpServer};
 use std::env;
+use std::net::TcpListener;
 use std::process::Command;
-use std::time::Duration;
 
 // Import the necessary modules from your main application
-use main::{hello, load_tls_config, not_found};
+use main::tls_error::TlsConfigError;
+use main::{hello, is_ca_certificate, key_matches_certificate, load_tls_config, not_found};
+
+mod common;
+
+use common::TestPki;
+
+/// Starts the application routes on an ephemeral port and returns the port.
+fn start_server(tls_config: rustls::ServerConfig) -> (u16, actix_web::dev::ServerHandle) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+
+    let server = HttpServer::new(|| {
+        App::new()
+            .route("/hello", web::get().to(hello))
+            .default_service(web::route().to(not_found))
+    })
+    .workers(2)
+    .listen_rustls(listener, tls_config)
+    .expect("Failed to listen")
+    .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+    (port, handle)
+}
 
 #[actix_rt::test]
 async fn test_server_integration() {
-    // Set up environment variables for testing
-    env::set_var("CERT_FILE", "path/to/test/cert.pem");
-    env::set_var("KEY_FILE", "path/to/test/key.pem");
-    env::set_var("SERVER_ADDRESS", "127.0.0.1:3001");
-    env::set_var("NUM_WORKERS", "2");
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
 
-    // Start the server in a separate process
-    let mut server = Command::new("cargo")
-        .args(&["run", "--bin", "your_binary_name"])
-        .spawn()
-        .expect("Failed to start server");
-
-    // Give the server some time to start up
-    std::thread::sleep(Duration::from_secs(2));
+    // Load the TLS config through the real loading path
+    let tls_config = {
+        let _env = common::env_lock();
+        env::set_var("CERT_FILE", &cert_path);
+        env::set_var("KEY_FILE", &key_path);
+        env::remove_var("CLIENT_CA_FILE");
+        load_tls_config().expect("Failed to load TLS config")
+    };
 
     // Create a test app
-    let _ = load_tls_config().expect("Failed to load TLS config");
     let app = test::init_service(
         App::new()
             .route("/hello", web::get().to(hello))
@@ -38,15 +58,13 @@ async fn test_server_integration() {
     let resp = test::call_service(&app, req).await;
     assert!(resp.status().is_success());
 
-    // Create an HTTPS client
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true) // For testing purposes only
-        .build()
-        .expect("Failed to create HTTPS client");
+    // Start the HTTPS server and a client that trusts only the test CA
+    let (port, handle) = start_server(tls_config);
+    let client = pki.client();
 
     // Test the /hello route
     let resp = client
-        .get("https://127.0.0.1:3001/hello")
+        .get(format!("https://localhost:{}/hello", port))
         .send()
         .await
         .expect("Failed to execute request");
@@ -56,7 +74,7 @@ async fn test_server_integration() {
 
     // Test a non-existent route (should return 404)
     let resp = client
-        .get("https://127.0.0.1:3001/non_existent")
+        .get(format!("https://localhost:{}/non_existent", port))
         .send()
         .await
         .expect("Failed to execute request");
@@ -64,22 +82,50 @@ async fn test_server_integration() {
     assert_eq!(resp.status(), 404);
     assert_eq!(resp.text().await.unwrap(), "Not Found");
 
-    // Test server configuration
-    let server_addr = env::var("SERVER_ADDRESS").unwrap();
-    assert_eq!(server_addr, "127.0.0.1:3001");
+    // Clean up: stop the server
+    handle.stop(true).await;
+}
 
-    let num_workers = env::var("NUM_WORKERS").unwrap().parse::<usize>().unwrap();
-    assert_eq!(num_workers, 2);
+#[actix_rt::test]
+async fn test_untrusted_certificate_is_rejected() {
+    let served = TestPki::generate();
+    let trusted = TestPki::generate();
+    let (port, handle) = start_server(served.server_config());
 
-    // Clean up: stop the server
-    server.kill().expect("Failed to stop server");
+    // The client trusts a different CA, so the handshake must fail
+    let result = trusted
+        .client()
+        .get(format!("https://localhost:{}/hello", port))
+        .send()
+        .await;
+    assert!(
+        result.is_err(),
+        "Certificate from an unknown CA was accepted"
+    );
+
+    // The right CA is accepted
+    let resp = served
+        .client()
+        .get(format!("https://localhost:{}/hello", port))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert!(resp.status().is_success());
+
+    handle.stop(true).await;
 }
 
 #[actix_rt::test]
 async fn test_tls_config() {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
+    let _env = common::env_lock();
+    env::remove_var("CLIENT_CA_FILE");
+
     // Test TLS configuration loading
-    env::set_var("CERT_FILE", "path/to/test/cert.pem");
-    env::set_var("KEY_FILE", "path/to/test/key.pem");
+    env::set_var("CERT_FILE", &cert_path);
+    env::set_var("KEY_FILE", &key_path);
 
     let tls_config = load_tls_config();
     assert!(tls_config.is_ok(), "Failed to load TLS configuration");
@@ -95,17 +141,126 @@ async fn test_tls_config() {
     );
 }
 
+#[actix_rt::test]
+async fn test_mismatched_private_key_is_reported() {
+    let pki = TestPki::generate();
+    let other = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, _) = pki.write_server_files(dir.path());
+    let other_dir = tempfile::tempdir().unwrap();
+    let (_, other_key_path) = other.write_server_files(other_dir.path());
+    let _env = common::env_lock();
+    env::remove_var("CLIENT_CA_FILE");
+
+    env::set_var("CERT_FILE", &cert_path);
+    env::set_var("KEY_FILE", &other_key_path);
+
+    let err = load_tls_config().expect_err("Mismatched key should be rejected");
+    assert!(matches!(err, TlsConfigError::KeyCertMismatch { .. }));
+    assert_eq!(err.to_string(), "private key does not match certificate");
+
+    assert_eq!(
+        key_matches_certificate(&pki.server_chain()[0], &pki.server_key()),
+        Some(true)
+    );
+    assert_eq!(
+        key_matches_certificate(&pki.server_chain()[0], &other.server_key()),
+        Some(false)
+    );
+}
+
+#[actix_rt::test]
+async fn test_tls_errors_are_distinguishable() {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
+    let _env = common::env_lock();
+    env::remove_var("CLIENT_CA_FILE");
+    env::remove_var("MTLS_REQUIRED_PATHS");
+
+    env::set_var("CERT_FILE", dir.path().join("missing.pem"));
+    env::set_var("KEY_FILE", &key_path);
+    let err = load_tls_config().expect_err("Missing certificate should be rejected");
+    assert!(
+        matches!(err, TlsConfigError::FileNotFound { .. }),
+        "{}",
+        err
+    );
+    let io: std::io::Error = err.into();
+    assert_eq!(io.kind(), std::io::ErrorKind::NotFound);
+    assert!(matches!(
+        io.get_ref()
+            .and_then(|e| e.downcast_ref::<TlsConfigError>()),
+        Some(TlsConfigError::FileNotFound { .. })
+    ));
+
+    let empty = dir.path().join("empty.pem");
+    std::fs::write(&empty, "").unwrap();
+    env::set_var("CERT_FILE", &cert_path);
+    env::set_var("KEY_FILE", &empty);
+    let err = load_tls_config().expect_err("Empty key file should be rejected");
+    assert!(matches!(err, TlsConfigError::NoKeys { .. }), "{}", err);
+
+    env::set_var("CERT_FILE", &empty);
+    env::set_var("KEY_FILE", &key_path);
+    let err = load_tls_config().expect_err("Empty certificate file should be rejected");
+    assert!(matches!(err, TlsConfigError::CertParse { .. }), "{}", err);
+    let io: std::io::Error = err.into();
+    assert_eq!(io.kind(), std::io::ErrorKind::InvalidData);
+
+    env::set_var("CERT_FILE", &cert_path);
+    env::set_var("MTLS_REQUIRED_PATHS", "/admin");
+    let err = load_tls_config().expect_err("MTLS_REQUIRED_PATHS needs CLIENT_CA_FILE");
+    assert!(matches!(err, TlsConfigError::ClientAuth(_)), "{}", err);
+    env::remove_var("MTLS_REQUIRED_PATHS");
+}
+
+#[actix_rt::test]
+async fn test_chain_without_leaf_is_reported() {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (_, key_path) = pki.write_server_files(dir.path());
+    let _env = common::env_lock();
+    env::remove_var("CLIENT_CA_FILE");
+    env::set_var("KEY_FILE", &key_path);
+
+    // Only the CA half of the bundle
+    let ca_only = dir.path().join("ca-only.pem");
+    std::fs::write(&ca_only, &pki.ca_pem).unwrap();
+    env::set_var("CERT_FILE", &ca_only);
+    let err = load_tls_config().expect_err("Leaf-less chain should be rejected");
+    assert!(
+        err.to_string().contains("certificate chain has no leaf"),
+        "{}",
+        err
+    );
+
+    // Both halves, concatenated in the wrong order
+    let reversed = dir.path().join("reversed.pem");
+    std::fs::write(
+        &reversed,
+        format!("{}\n{}", pki.ca_pem, pki.server_cert_pem),
+    )
+    .unwrap();
+    env::set_var("CERT_FILE", &reversed);
+    let err = load_tls_config().expect_err("Misordered chain should be rejected");
+    assert!(err.to_string().contains("position 2"), "{}", err);
+
+    assert_eq!(is_ca_certificate(&pki.server_chain()[0]), Some(false));
+}
+
 #[actix_rt::test]
 async fn test_server_error_handling() {
-    // Test server startup with invalid address
-    env::set_var("SERVER_ADDRESS", "invalid_address");
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
 
-    let result = Command::new("cargo")
-        .args(&["run", "--bin", "your_binary_name"])
+    // Test server startup with invalid address
+    let result = Command::new(env!("CARGO_BIN_EXE_secure-actix-web-server"))
+        .env("CERT_FILE", &cert_path)
+        .env("KEY_FILE", &key_path)
+        .env("SERVER_ADDRESS", "invalid_address")
         .output();
 
     assert!(result.is_err() || !result.unwrap().status.success());
-
-    // Reset SERVER_ADDRESS for other tests
-    env::set_var("SERVER_ADDRESS", "127.0.0.1:3001");
 }
@@ -0,0 +1,101 @@
+use actix_web::http::{ConnectionType, KeepAlive, Version};
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use std::time::Duration;
+
+use main::middleware::keep_alive::{H1KeepAlive, KeepAliveSettings};
+
+fn settings(h1: Option<u64>, h2: Option<u64>) -> KeepAliveSettings {
+    KeepAliveSettings {
+        h1: h1.map(Duration::from_secs),
+        h2: h2.map(Duration::from_secs),
+    }
+}
+
+#[test]
+fn test_server_timer_follows_h1_then_h2() {
+    assert_eq!(
+        settings(Some(2), Some(75)).server_keep_alive(),
+        KeepAlive::Timeout(Duration::from_secs(2))
+    );
+    assert_eq!(
+        settings(None, Some(75)).server_keep_alive(),
+        KeepAlive::Timeout(Duration::from_secs(75))
+    );
+    assert_eq!(
+        settings(None, None).server_keep_alive(),
+        KeepAlive::Disabled
+    );
+}
+
+#[test]
+fn test_effective_behaviour_is_described() {
+    let effective = settings(Some(5), Some(5)).effective();
+    assert_eq!(effective.h1, "idle timeout 5s");
+    assert_eq!(effective.h2, "PING every 5s, closed if unanswered");
+    assert!(effective.warning.is_none());
+
+    let effective = settings(None, Some(60)).effective();
+    assert_eq!(effective.h1, "disabled (Connection: close)");
+    assert_eq!(effective.h2, "PING every 60s, closed if unanswered");
+    assert!(effective.warning.is_none());
+
+    let effective = settings(Some(2), Some(60)).effective();
+    assert!(effective.warning.unwrap().contains("H2_KEEP_ALIVE_SECS=60"));
+
+    let effective = settings(Some(2), None).effective();
+    assert!(effective.warning.is_some());
+
+    let effective = settings(None, None).effective();
+    assert_eq!(effective.h2, "no PINGs, open until the client closes");
+}
+
+#[actix_rt::test]
+async fn test_h1_connections_close_when_h1_keep_alive_is_disabled() {
+    let app = init_service(
+        App::new()
+            .wrap(H1KeepAlive::new(&settings(None, Some(60))))
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/")
+        .version(Version::HTTP_11)
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(
+        resp.response().head().connection_type(),
+        ConnectionType::Close
+    );
+
+    let req = TestRequest::get()
+        .uri("/")
+        .version(Version::HTTP_2)
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_ne!(
+        resp.response().head().connection_type(),
+        ConnectionType::Close
+    );
+}
+
+#[actix_rt::test]
+async fn test_h1_connections_are_kept_when_enabled() {
+    let app = init_service(
+        App::new()
+            .wrap(H1KeepAlive::new(&settings(Some(5), Some(60))))
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .uri("/")
+        .version(Version::HTTP_11)
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_ne!(
+        resp.response().head().connection_type(),
+        ConnectionType::Close
+    );
+}
@@ -0,0 +1,299 @@
+#![cfg(feature = "macaroon")]
+
+use actix_web::http::StatusCode;
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body, read_body_json, TestRequest,
+};
+use actix_web::{web, App, HttpResponse};
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::{json, Value};
+
+use main::auth::macaroon::{self, Caveat, MacaroonAuthority, MacaroonError};
+use main::auth::Principal;
+use main::clock::{Clock, MockClock};
+use main::middleware::api_key::AdminKey;
+use main::middleware::macaroon_auth::{parse_macaroon, MacaroonAuth};
+
+const ROOT_KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+const ADMIN_KEY: &str = "admin-secret";
+
+fn authority() -> MacaroonAuthority {
+    MacaroonAuthority::new(ROOT_KEY).unwrap()
+}
+
+async fn whoami(principal: Principal) -> HttpResponse {
+    HttpResponse::Ok().body(principal.username)
+}
+
+macro_rules! app {
+    ($paths:expr) => {
+        init_service(
+            App::new()
+                .wrap(MacaroonAuth::new($paths))
+                .configure(macaroon::configure(
+                    web::Data::new(authority()),
+                    AdminKey::new(Some(ADMIN_KEY.to_string())),
+                ))
+                .route(
+                    "/hello",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .route("/whoami", web::get().to(whoami))
+                .route("/whoami", web::post().to(whoami)),
+        )
+        .await
+    };
+}
+
+#[test]
+fn parses_each_caveat_type() {
+    assert_eq!(
+        Caveat::parse("time < 2025-01-01").unwrap(),
+        Caveat::Before(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap())
+    );
+    assert_eq!(
+        Caveat::parse("time > 2024-06-01T12:00:00+02:00").unwrap(),
+        Caveat::After(Utc.with_ymd_and_hms(2024, 6, 1, 10, 0, 0).unwrap())
+    );
+    assert_eq!(
+        Caveat::parse("route = /hello").unwrap(),
+        Caveat::Route("/hello".to_string())
+    );
+    assert_eq!(
+        Caveat::parse("method = get").unwrap(),
+        Caveat::Method("GET".to_string())
+    );
+
+    for bad in [
+        "time < tomorrow",
+        "route = hello",
+        "role = admin",
+        "route=/hello",
+        "time < 2025-01-01 extra",
+    ] {
+        assert!(Caveat::parse(bad).is_err(), "{} should be rejected", bad);
+    }
+}
+
+#[test]
+fn caveats_round_trip_through_display() {
+    for text in ["route = /hello", "method = POST"] {
+        assert_eq!(Caveat::parse(text).unwrap().to_string(), text);
+    }
+    let before = Caveat::parse("time < 2025-01-01").unwrap();
+    assert_eq!(Caveat::parse(&before.to_string()).unwrap(), before);
+}
+
+#[test]
+fn time_caveats_bound_validity() {
+    let now = Utc::now();
+    let before = Caveat::Before(now + Duration::minutes(5));
+    let after = Caveat::After(now - Duration::minutes(5));
+
+    let mock = MockClock::new(now);
+    let clock = mock.clock();
+    assert!(before.is_satisfied("GET", "/", &clock));
+    assert!(after.is_satisfied("GET", "/", &clock));
+    mock.set(now + Duration::minutes(10));
+    assert!(!before.is_satisfied("GET", "/", &clock));
+    mock.set(now - Duration::minutes(10));
+    assert!(!after.is_satisfied("GET", "/", &clock));
+}
+
+#[test]
+fn route_caveat_matches_path_segments() {
+    let route = Caveat::Route("/api/".to_string());
+    let clock = Clock::default();
+
+    assert!(route.is_satisfied("GET", "/api", &clock));
+    assert!(route.is_satisfied("GET", "/api/users", &clock));
+    assert!(!route.is_satisfied("GET", "/apiary", &clock));
+    assert!(!route.is_satisfied("GET", "/admin", &clock));
+}
+
+#[test]
+fn method_caveat_matches_method() {
+    let method = Caveat::Method("GET".to_string());
+    let clock = Clock::default();
+
+    assert!(method.is_satisfied("GET", "/", &clock));
+    assert!(method.is_satisfied("get", "/", &clock));
+    assert!(!method.is_satisfied("POST", "/", &clock));
+}
+
+#[test]
+fn verifies_signature_and_caveats() {
+    let authority = authority();
+    let now = Utc::now();
+    let clock = Clock::fixed(now);
+    let token = authority
+        .mint(
+            "alice",
+            &["route = /hello".to_string(), "method = GET".to_string()],
+        )
+        .unwrap();
+
+    assert_eq!(
+        authority.verify(&token, "GET", "/hello", &clock),
+        Ok("alice".to_string())
+    );
+    assert_eq!(
+        authority.verify(&token, "GET", "/admin", &clock),
+        Err(MacaroonError::NotPermitted("route = /hello".to_string()))
+    );
+    assert_eq!(
+        authority.verify(&token, "POST", "/hello", &clock),
+        Err(MacaroonError::NotPermitted("method = GET".to_string()))
+    );
+
+    let expired = MacaroonAuthority::attenuate(
+        &token,
+        &format!("time < {}", (now - Duration::minutes(1)).to_rfc3339()),
+    )
+    .unwrap();
+    assert_eq!(
+        authority.verify(&expired, "GET", "/hello", &clock),
+        Err(MacaroonError::Expired)
+    );
+    let not_yet = MacaroonAuthority::attenuate(
+        &token,
+        &format!("time > {}", (now + Duration::minutes(1)).to_rfc3339()),
+    )
+    .unwrap();
+    assert_eq!(
+        authority.verify(&not_yet, "GET", "/hello", &clock),
+        Err(MacaroonError::Expired)
+    );
+}
+
+#[test]
+fn rejects_tokens_from_another_key() {
+    let other = MacaroonAuthority::new(b"another root key of thirty-two b").unwrap();
+    let token = other.mint("mallory", &[]).unwrap();
+
+    assert_eq!(
+        authority().verify(&token, "GET", "/hello", &Clock::default()),
+        Err(MacaroonError::Invalid)
+    );
+    assert_eq!(
+        authority().verify("not a macaroon", "GET", "/hello", &Clock::default()),
+        Err(MacaroonError::Invalid)
+    );
+}
+
+#[test]
+fn rejects_short_root_keys() {
+    assert!(MacaroonAuthority::new(b"too short").is_err());
+}
+
+#[test]
+fn parses_authorization_header() {
+    assert_eq!(parse_macaroon("Macaroon abc"), Some("abc"));
+    assert_eq!(parse_macaroon("macaroon  abc "), Some("abc"));
+    assert_eq!(parse_macaroon("Bearer abc"), None);
+    assert_eq!(parse_macaroon("Macaroon "), None);
+}
+
+#[actix_web::test]
+async fn create_requires_admin_key() {
+    let app = app!(Vec::new());
+
+    let req = TestRequest::post()
+        .uri("/auth/macaroon/create")
+        .set_json(json!({ "identifier": "alice" }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let req = TestRequest::post()
+        .uri("/auth/macaroon/create")
+        .insert_header(("X-API-Key", ADMIN_KEY))
+        .set_json(json!({ "identifier": "alice", "caveats": ["role = admin"] }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn created_and_attenuated_macaroons_authenticate() {
+    let app = app!(Vec::new());
+
+    let req = TestRequest::post()
+        .uri("/auth/macaroon/create")
+        .insert_header(("X-API-Key", ADMIN_KEY))
+        .set_json(json!({ "identifier": "alice" }))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let root = body["macaroon"].as_str().unwrap().to_string();
+
+    let req = TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("Authorization", format!("Macaroon {}", root)))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(read_body(resp).await, "alice");
+
+    let req = TestRequest::post()
+        .uri("/auth/macaroon/attenuate")
+        .set_json(json!({ "macaroon": root, "caveat": "method = GET" }))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    let attenuated = body["macaroon"].as_str().unwrap().to_string();
+
+    let req = TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("Authorization", format!("Macaroon {}", attenuated)))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = TestRequest::post()
+        .uri("/whoami")
+        .insert_header(("Authorization", format!("Macaroon {}", attenuated)))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers().contains_key("WWW-Authenticate"));
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "macaroon_not_permitted");
+}
+
+#[actix_web::test]
+async fn expired_and_invalid_macaroons_get_401() {
+    let app = app!(Vec::new());
+    let expired = authority()
+        .mint("alice", &["time < 2020-01-01".to_string()])
+        .unwrap();
+
+    let req = TestRequest::get()
+        .uri("/hello")
+        .insert_header(("Authorization", format!("Macaroon {}", expired)))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "macaroon_expired");
+
+    let req = TestRequest::get()
+        .uri("/hello")
+        .insert_header(("Authorization", "Macaroon garbage"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_macaroon");
+}
+
+#[actix_web::test]
+async fn configured_paths_require_a_macaroon() {
+    let app = app!(vec!["/whoami".to_string()]);
+
+    let req = TestRequest::get().uri("/hello").to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = TestRequest::get().uri("/whoami").to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+}
@@ -0,0 +1,349 @@
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use main::auth::reset::{EmailNotifier, ResetNotifier};
+use main::mail::alerts::{AlertDigest, ServerError, MAX_LISTED};
+use main::mail::{self, deliver, templates, Email, MailError, MailQueue, Mailer, RetryPolicy};
+
+/// Records sent messages, failing the first `failures` attempts with
+/// `error`.
+#[derive(Default)]
+struct RecordingMailer {
+    sent: Mutex<Vec<Email>>,
+    attempts: AtomicUsize,
+    failures: usize,
+    error: Option<MailError>,
+}
+
+#[async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+            return Err(self.error.clone().unwrap());
+        }
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+fn fast_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(20),
+    }
+}
+
+async fn wait_for<F: Fn() -> bool>(condition: F) {
+    for _ in 0..200 {
+        if condition() {
+            return;
+        }
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met in time");
+}
+
+#[test]
+fn test_templates_escape_html_only() {
+    let email = templates::password_reset("alice@example.com", "<alice>", "tok&en", 0);
+    assert_eq!(email.to, "alice@example.com");
+    assert_eq!(email.subject, "Reset your password");
+    assert!(email.text.contains("Hello <alice>,"));
+    assert!(email.text.contains("tok&en"));
+    assert!(email.text.contains("1970-01-01 00:00 UTC"));
+    assert!(email.html.contains("Hello &lt;alice&gt;,"));
+    assert!(email.html.contains("<code>tok&amp;en</code>"));
+
+    assert_eq!(
+        templates::render("{{a}} {{b}} {{c}}", &[("a", "1"), ("b", "<2>")], true),
+        "1 &lt;2&gt; {{c}}"
+    );
+}
+
+#[test]
+fn test_error_digest_lists_errors() {
+    let error = ServerError {
+        at: chrono::Utc::now(),
+        status: 502,
+        method: "GET".to_string(),
+        path: "/proxy/<x>".to_string(),
+        request_id: "req-1".to_string(),
+    };
+    let email = templates::error_digest("ops@example.com", &[error], 3);
+    assert_eq!(email.subject, "4 server errors");
+    assert!(email.text.contains("(3 not listed)"));
+    assert!(email.text.contains("502 GET /proxy/<x> request_id=req-1"));
+    assert!(email.html.contains("<td>GET /proxy/&lt;x&gt;</td>"));
+}
+
+#[test]
+fn test_address_validation() {
+    assert!(templates::is_address("alice@example.com"));
+    assert!(!templates::is_address("alice"));
+    assert!(!templates::is_address("@example.com"));
+    assert!(!templates::is_address("alice@localhost"));
+    assert!(!templates::is_address("alice@example.com\r\nBcc: x@y.z"));
+    assert!(!templates::is_address("Alice <alice@example.com>"));
+}
+
+#[test]
+fn test_backoff_doubles_up_to_the_maximum() {
+    let retry = RetryPolicy {
+        max_attempts: 10,
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(5),
+    };
+    assert_eq!(retry.backoff(1), Duration::from_secs(1));
+    assert_eq!(retry.backoff(2), Duration::from_secs(2));
+    assert_eq!(retry.backoff(3), Duration::from_secs(4));
+    assert_eq!(retry.backoff(4), Duration::from_secs(5));
+    assert_eq!(retry.backoff(40), Duration::from_secs(5));
+}
+
+#[test]
+fn test_digest_caps_listed_errors() {
+    let digest = AlertDigest::default();
+    for i in 0..MAX_LISTED + 2 {
+        digest.push(ServerError {
+            at: chrono::Utc::now(),
+            status: 500,
+            method: "GET".to_string(),
+            path: format!("/{}", i),
+            request_id: i.to_string(),
+        });
+    }
+    let (errors, dropped) = digest.take();
+    assert_eq!(errors.len(), MAX_LISTED);
+    assert_eq!(dropped, 2);
+    assert_eq!(digest.take(), (Vec::new(), 0));
+}
+
+#[actix_rt::test]
+async fn test_transient_failures_are_retried() {
+    let mailer = RecordingMailer {
+        failures: 2,
+        error: Some(MailError::Transient("451 try later".to_string())),
+        ..Default::default()
+    };
+    let email = templates::test_message("ops@example.com");
+
+    assert_eq!(deliver(&mailer, &fast_retry(), &email).await, Ok(()));
+    assert_eq!(mailer.attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(mailer.sent.lock().unwrap().as_slice(), &[email]);
+}
+
+#[actix_rt::test]
+async fn test_permanent_failures_and_exhausted_retries_give_up() {
+    let email = templates::test_message("ops@example.com");
+
+    let mailer = RecordingMailer {
+        failures: 5,
+        error: Some(MailError::Permanent("550 no such user".to_string())),
+        ..Default::default()
+    };
+    assert!(matches!(
+        deliver(&mailer, &fast_retry(), &email).await,
+        Err(MailError::Permanent(_))
+    ));
+    assert_eq!(mailer.attempts.load(Ordering::SeqCst), 1);
+
+    let mailer = RecordingMailer {
+        failures: 5,
+        error: Some(MailError::Transient("421 busy".to_string())),
+        ..Default::default()
+    };
+    assert!(matches!(
+        deliver(&mailer, &fast_retry(), &email).await,
+        Err(MailError::Transient(_))
+    ));
+    assert_eq!(mailer.attempts.load(Ordering::SeqCst), 3);
+}
+
+#[actix_rt::test]
+async fn test_reset_tokens_are_emailed() {
+    let mailer = Arc::new(RecordingMailer::default());
+    let queue = MailQueue::spawn(mailer.clone(), fast_retry(), 10);
+    let notifier = EmailNotifier::new(queue, Some("example.com".to_string()));
+
+    assert_eq!(notifier.address("bob").as_deref(), Some("bob@example.com"));
+    assert_eq!(
+        notifier.address("carol@example.org").as_deref(),
+        Some("carol@example.org")
+    );
+    assert!(
+        EmailNotifier::new(MailQueue::spawn(mailer.clone(), fast_retry(), 1), None)
+            .address("bob")
+            .is_none()
+    );
+
+    notifier.send("bob", "secret-token", 0).await.unwrap();
+    wait_for(|| !mailer.sent.lock().unwrap().is_empty()).await;
+    let sent = mailer.sent.lock().unwrap()[0].clone();
+    assert_eq!(sent.to, "bob@example.com");
+    assert!(sent.text.contains("secret-token"));
+}
+
+#[actix_rt::test]
+async fn test_admin_test_email_is_queued() {
+    let mailer = Arc::new(RecordingMailer::default());
+    mail::install(MailQueue::spawn(mailer.clone(), fast_retry(), 10));
+    let app =
+        init_service(App::new().route("/admin/test-email", web::post().to(mail::test_email))).await;
+
+    let req = TestRequest::post()
+        .uri("/admin/test-email")
+        .set_json(json!({ "to": "not-an-address" }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_address");
+
+    let req = TestRequest::post()
+        .uri("/admin/test-email")
+        .set_json(json!({ "to": "ops@example.com" }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+
+    wait_for(|| !mailer.sent.lock().unwrap().is_empty()).await;
+    assert_eq!(mailer.sent.lock().unwrap()[0].subject, "Test email");
+}
+
+#[cfg(feature = "email")]
+mod smtp {
+    use super::*;
+    use main::mail::smtp::{SmtpConfig, SmtpMailer, SmtpTls};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A message accepted by the stub.
+    #[derive(Clone, Debug, Default)]
+    struct Received {
+        from: String,
+        to: Vec<String>,
+        data: String,
+    }
+
+    #[derive(Default)]
+    struct StubState {
+        received: Vec<Received>,
+        data_attempts: usize,
+    }
+
+    /// Starts an SMTP server that answers the first `reject` messages with
+    /// a transient 451 and accepts the rest. Returns its port and state.
+    async fn start_stub(reject: usize) -> (u16, Arc<Mutex<StubState>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(StubState::default()));
+        let shared = state.clone();
+
+        actix_rt::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let state = shared.clone();
+                actix_rt::spawn(async move {
+                    let (read, mut write) = socket.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    let mut current = Received::default();
+                    write.write_all(b"220 stub ESMTP\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let upper = line.to_ascii_uppercase();
+                        let reply: &[u8] = if upper.starts_with("EHLO") {
+                            b"250 stub\r\n"
+                        } else if upper.starts_with("MAIL FROM:") {
+                            current = Received {
+                                from: line[10..].trim().to_string(),
+                                ..Default::default()
+                            };
+                            b"250 OK\r\n"
+                        } else if upper.starts_with("RCPT TO:") {
+                            current.to.push(line[8..].trim().to_string());
+                            b"250 OK\r\n"
+                        } else if upper == "DATA" {
+                            write.write_all(b"354 go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                data.push_str(&line);
+                                data.push('\n');
+                            }
+                            current.data = data;
+                            let mut state = state.lock().unwrap();
+                            state.data_attempts += 1;
+                            if state.data_attempts <= reject {
+                                b"451 4.3.0 try again later\r\n"
+                            } else {
+                                state.received.push(current.clone());
+                                b"250 OK queued\r\n"
+                            }
+                        } else if upper == "QUIT" {
+                            let _ = write.write_all(b"221 bye\r\n").await;
+                            break;
+                        } else {
+                            b"250 OK\r\n"
+                        };
+                        if write.write_all(reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (port, state)
+    }
+
+    fn mailer(port: u16) -> SmtpMailer {
+        let mut config = SmtpConfig::new("127.0.0.1", "Server <server@example.com>");
+        config.tls = SmtpTls::None;
+        config.port = port;
+        config.timeout = Duration::from_secs(5);
+        SmtpMailer::new(&config).expect("Failed to create SMTP mailer")
+    }
+
+    #[actix_rt::test]
+    async fn test_message_reaches_smtp_server() {
+        let (port, state) = start_stub(0).await;
+        let email = templates::test_message("ops@example.com");
+
+        assert_eq!(deliver(&mailer(port), &fast_retry(), &email).await, Ok(()));
+
+        let state = state.lock().unwrap();
+        let received = &state.received[0];
+        assert_eq!(received.from, "<server@example.com>");
+        assert_eq!(received.to, vec!["<ops@example.com>"]);
+        assert!(received.data.contains("From: Server <server@example.com>"));
+        assert!(received.data.contains("To: ops@example.com"));
+        assert!(received.data.contains("Subject: Test email"));
+        assert!(received.data.contains("multipart/alternative"));
+        assert!(received.data.contains("Content-Type: text/plain"));
+        assert!(received.data.contains("Content-Type: text/html"));
+        assert!(received
+            .data
+            .contains("This is a test email sent from the admin API."));
+        assert!(received
+            .data
+            .contains("<p>This is a test email sent from the admin API.</p>"));
+    }
+
+    #[actix_rt::test]
+    async fn test_transient_smtp_reply_is_retried() {
+        let (port, state) = start_stub(1).await;
+        let email = templates::test_message("ops@example.com");
+
+        assert_eq!(deliver(&mailer(port), &fast_retry(), &email).await, Ok(()));
+
+        let state = state.lock().unwrap();
+        assert_eq!(state.data_attempts, 2);
+        assert_eq!(state.received.len(), 1);
+    }
+}
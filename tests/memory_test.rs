@@ -0,0 +1,128 @@
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use serde_json::Value;
+use std::time::Duration;
+
+use main::memory::{
+    memory_status, parse_mach_task_basic_info, parse_proc_status, MemoryPressureWatcher,
+};
+use main::middleware::memory_pressure::MemoryPressure;
+
+const PROC_STATUS: &str = "Name:\tsecure-actix-we\n\
+    State:\tS (sleeping)\n\
+    VmPeak:\t  912340 kB\n\
+    VmSize:\t  912340 kB\n\
+    VmHWM:\t   48120 kB\n\
+    VmRSS:\t   45012 kB\n\
+    Threads:\t9\n";
+
+#[test]
+fn test_parse_proc_status() {
+    assert_eq!(parse_proc_status(PROC_STATUS), Some(45012 * 1024));
+    assert_eq!(parse_proc_status("VmRSS: 12 mB\n"), Some(12 * 1024 * 1024));
+    assert_eq!(parse_proc_status("Name:\tx\nThreads:\t1\n"), None);
+    assert_eq!(parse_proc_status("VmRSS:\tlots kB\n"), None);
+    assert_eq!(parse_proc_status("VmRSS:\t12 pages\n"), None);
+}
+
+#[test]
+fn test_parse_mach_task_basic_info() {
+    // virtual_size, resident_size, resident_size_max, user_time,
+    // system_time, policy, suspend_count
+    let resident: u64 = 5 * 1024 * 1024 * 1024 + 4096;
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(64u64 << 30).to_ne_bytes());
+    bytes.extend_from_slice(&resident.to_ne_bytes());
+    bytes.extend_from_slice(&(6u64 << 30).to_ne_bytes());
+    bytes.extend_from_slice(&[0u8; 24]);
+    let words: Vec<i32> = bytes
+        .chunks(4)
+        .map(|c| i32::from_ne_bytes(c.try_into().unwrap()))
+        .collect();
+
+    assert_eq!(words.len(), 12);
+    assert_eq!(parse_mach_task_basic_info(&words), Some(resident));
+    assert_eq!(parse_mach_task_basic_info(&words[..4]), None);
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn test_read_rss_of_this_process() {
+    let rss = main::memory::read_rss().unwrap();
+    assert!(rss > 1024 * 1024);
+}
+
+#[test]
+fn test_watcher_flags_pressure_above_limit() {
+    let watcher = MemoryPressureWatcher::new(Some(1000), Duration::from_secs(1));
+    let flag = watcher.flag();
+
+    watcher.update(500);
+    assert!(!watcher.is_under_pressure());
+    watcher.update(1001);
+    assert!(watcher.is_under_pressure());
+    assert!(flag.load(std::sync::atomic::Ordering::Relaxed));
+    watcher.update(1000);
+    assert!(!watcher.is_under_pressure());
+
+    let unlimited = MemoryPressureWatcher::new(None, Duration::from_secs(1));
+    unlimited.update(u64::MAX);
+    assert!(!unlimited.is_under_pressure());
+}
+
+#[actix_rt::test]
+async fn test_middleware_sheds_load_under_pressure() {
+    let watcher = web::Data::new(MemoryPressureWatcher::new(
+        Some(1000),
+        Duration::from_secs(1),
+    ));
+    let app = init_service(
+        App::new()
+            .wrap(MemoryPressure::new(watcher.flag()))
+            .route(
+                "/hello",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            )
+            .route(
+                "/health",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            )
+            .route(
+                "/healthz",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            )
+            .service(
+                web::scope("/admin")
+                    .app_data(watcher.clone())
+                    .route("/memory", web::get().to(memory_status)),
+            ),
+    )
+    .await;
+
+    let req = TestRequest::get().uri("/hello").to_request();
+    assert_eq!(call_service(&app, req).await.status(), 200);
+
+    watcher.update(2000);
+    let req = TestRequest::get().uri("/hello").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "5");
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "memory_pressure");
+
+    let req = TestRequest::get().uri("/health").to_request();
+    assert_eq!(call_service(&app, req).await.status(), 200);
+    let req = TestRequest::get().uri("/healthz").to_request();
+    assert_eq!(call_service(&app, req).await.status(), 503);
+
+    let req = TestRequest::get().uri("/admin/memory").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["limit_bytes"], 1000);
+    assert!(body["under_pressure"].is_boolean());
+
+    watcher.update(10);
+    let req = TestRequest::get().uri("/hello").to_request();
+    assert_eq!(call_service(&app, req).await.status(), 200);
+}
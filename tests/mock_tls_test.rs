@@ -0,0 +1,110 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use x509_parser::prelude::{parse_x509_certificate, GeneralName};
+
+mod common;
+
+use common::test_utils::MockTlsConfig;
+
+/// Runs a handshake in memory for `server_name`.
+fn handshake(
+    server: ServerConfig,
+    client: ClientConfig,
+    server_name: &str,
+) -> Result<(), rustls::Error> {
+    let mut server = ServerConnection::new(Arc::new(server)).unwrap();
+    let mut client =
+        ClientConnection::new(Arc::new(client), server_name.try_into().unwrap()).unwrap();
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut to_server = Vec::new();
+        client.write_tls(&mut to_server).unwrap();
+        if !to_server.is_empty() {
+            server.read_tls(&mut to_server.as_slice()).unwrap();
+            server.process_new_packets()?;
+        }
+
+        let mut to_client = Vec::new();
+        server.write_tls(&mut to_client).unwrap();
+        if !to_client.is_empty() {
+            client.read_tls(&mut to_client.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn generated_certificate_is_valid_for_localhost() {
+    let mock = MockTlsConfig::self_signed();
+    let (_, cert) = parse_x509_certificate(&mock.certificate.0).unwrap();
+    let names: Vec<String> = cert
+        .subject_alternative_name()
+        .unwrap()
+        .expect("no subject alternative names")
+        .value
+        .general_names
+        .iter()
+        .map(|name| match name {
+            GeneralName::DNSName(dns) => dns.to_string(),
+            other => format!("{:?}", other),
+        })
+        .collect();
+    assert_eq!(names, ["localhost"]);
+    assert!(cert.validity().is_valid());
+    assert_eq!(cert.subject(), cert.issuer());
+
+    handshake(mock.server_config(), mock.client_config(), "localhost").unwrap();
+    // Verification stays on: other names and other certificates fail
+    let (server, client) = MockTlsConfig::generate();
+    assert!(handshake(server, client, "example.com").is_err());
+    let (server, _) = MockTlsConfig::generate();
+    let (_, client) = MockTlsConfig::generate();
+    assert!(handshake(server, client, "localhost").is_err());
+}
+
+#[actix_rt::test]
+async fn server_and_client_configs_talk_https() {
+    let (server_config, client_config) = MockTlsConfig::generate();
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/hello",
+            web::get().to(|| async { HttpResponse::Ok().body("hello") }),
+        )
+    })
+    .workers(1)
+    .disable_signals()
+    .listen_rustls(listener, server_config)
+    .expect("Failed to listen")
+    .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    let response = actix_web::rt::task::spawn_blocking(move || {
+        let conn = ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+            .expect("Failed to create TLS connection");
+        let sock = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect");
+        let mut tls = StreamOwned::new(conn, sock);
+        tls.write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .expect("Failed to send request");
+        let mut response = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            match tls.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+            }
+        }
+        String::from_utf8_lossy(&response).into_owned()
+    })
+    .await
+    .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("hello"), "{}", response);
+
+    handle.stop(true).await;
+}
@@ -0,0 +1,220 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use rcgen::{Certificate as RcgenCertificate, CertificateParams, DnType, ExtendedKeyUsagePurpose};
+use reqwest::Identity;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+};
+use rustls::{Certificate, RootCertStore, ServerConfig};
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use main::middleware::mtls::{ClientCertificates, RequireClientCertificate};
+use main::tls_info::{self, PeerCertificate};
+
+mod common;
+
+use common::TestPki;
+
+/// Returns the DER of the leaf and the chain length seen by the handler.
+async fn whoami(cert: PeerCertificate) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("X-Chain-Length", cert.chain().len().to_string()))
+        .body(cert.leaf().0.clone())
+}
+
+/// Generates a client certificate signed by the test CA, returning the PEM
+/// identity (key followed by certificate) and the certificate DER.
+fn generate_client_cert(pki: &TestPki) -> (Vec<u8>, Vec<u8>) {
+    let mut params = CertificateParams::new(vec!["client.test".to_string()]);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "test-client");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let cert = RcgenCertificate::from_params(params).expect("Failed to generate client cert");
+
+    let cert_pem = cert
+        .serialize_pem_with_signer(pki.ca())
+        .expect("Failed to sign client cert");
+    let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+        .expect("Failed to parse client cert")
+        .remove(0);
+
+    let mut identity = cert.serialize_private_key_pem().into_bytes();
+    identity.extend_from_slice(cert_pem.as_bytes());
+    (identity, cert_der)
+}
+
+fn ca_roots(pki: &TestPki) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(pki.ca().serialize_der().unwrap()))
+        .expect("Failed to add CA");
+    roots
+}
+
+/// Builds a server config for `localhost` with the given client verifier.
+fn server_config(pki: &TestPki, verifier: Arc<dyn ClientCertVerifier>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(pki.server_chain(), pki.server_key())
+        .expect("Failed to build server config")
+}
+
+/// Starts an HTTPS server exposing `/whoami` and returns its port.
+fn start_server(config: ServerConfig) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+
+    let server = HttpServer::new(|| {
+        App::new()
+            .wrap(ClientCertificates)
+            .route("/whoami", web::get().to(whoami))
+    })
+    .on_connect(tls_info::on_connect)
+    .workers(1)
+    .listen_rustls(listener, config)
+    .expect("Failed to listen")
+    .run();
+    actix_rt::spawn(server);
+    port
+}
+
+#[actix_rt::test]
+async fn test_handler_receives_client_certificate() {
+    let pki = TestPki::generate();
+    let (identity, client_der) = generate_client_cert(&pki);
+    let port = start_server(server_config(
+        &pki,
+        AllowAnyAuthenticatedClient::new(ca_roots(&pki)),
+    ));
+
+    let client = pki
+        .client_builder()
+        .identity(Identity::from_pem(&identity).expect("Invalid identity"))
+        .build()
+        .expect("Failed to create HTTPS client");
+
+    let resp = client
+        .get(format!("https://localhost:{}/whoami", port))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers()["x-chain-length"], "1");
+    assert_eq!(resp.bytes().await.unwrap().to_vec(), client_der);
+}
+
+#[actix_rt::test]
+async fn test_missing_client_certificate_is_rejected() {
+    let pki = TestPki::generate();
+    let port = start_server(server_config(
+        &pki,
+        AllowAnyAnonymousOrAuthenticatedClient::new(ca_roots(&pki)),
+    ));
+
+    let resp = pki
+        .client()
+        .get(format!("https://localhost:{}/whoami", port))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(resp.status(), 400);
+}
+
+/// Starts an HTTPS server accepting anonymous clients that requires a client
+/// certificate under `/admin`, and returns its port.
+fn start_scoped_server(pki: &TestPki) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+    let config = server_config(
+        pki,
+        AllowAnyAnonymousOrAuthenticatedClient::new(ca_roots(pki)),
+    );
+
+    let server = HttpServer::new(|| {
+        App::new()
+            .wrap(RequireClientCertificate::new(vec!["/admin".to_string()]))
+            .wrap(ClientCertificates)
+            .route("/admin/whoami", web::get().to(whoami))
+            .route(
+                "/administrator",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            )
+            .route(
+                "/hello",
+                web::get().to(|| async { HttpResponse::Ok().finish() }),
+            )
+    })
+    .on_connect(tls_info::on_connect)
+    .workers(1)
+    .listen_rustls(listener, config)
+    .expect("Failed to listen")
+    .run();
+    actix_rt::spawn(server);
+    port
+}
+
+#[actix_rt::test]
+async fn test_scoped_paths_require_client_certificate() {
+    let pki = TestPki::generate();
+    let port = start_scoped_server(&pki);
+    let client = pki.client();
+
+    let resp = client
+        .get(format!("https://localhost:{}/admin/whoami", port))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "client_certificate_required");
+
+    for path in ["/hello", "/administrator"] {
+        let resp = client
+            .get(format!("https://localhost:{}{}", port, path))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(resp.status(), 200, "{} should not need a certificate", path);
+    }
+}
+
+#[actix_rt::test]
+async fn test_scoped_paths_accept_client_certificate() {
+    let pki = TestPki::generate();
+    let (identity, client_der) = generate_client_cert(&pki);
+    let port = start_scoped_server(&pki);
+
+    let client = pki
+        .client_builder()
+        .identity(Identity::from_pem(&identity).expect("Invalid identity"))
+        .build()
+        .expect("Failed to create HTTPS client");
+
+    let resp = client
+        .get(format!("https://localhost:{}/admin/whoami", port))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert!(resp.status().is_success());
+    assert_eq!(resp.bytes().await.unwrap().to_vec(), client_der);
+
+    let resp = client
+        .get(format!("https://localhost:{}/hello", port))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(resp.status(), 200);
+}
+
+#[test]
+fn test_required_paths_match_whole_segments() {
+    let scope = RequireClientCertificate::new(vec!["/admin/".to_string()]);
+    assert!(scope.applies_to("/admin"));
+    assert!(scope.applies_to("/admin/memory"));
+    assert!(!scope.applies_to("/administrator"));
+    assert!(!scope.applies_to("/hello"));
+}
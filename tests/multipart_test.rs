@@ -0,0 +1,244 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use main::metrics::Metrics;
+use main::util::multipart::{Multipart, MultipartLimits, MultipartParser, MultipartViolation};
+
+const BOUNDARY: &str = "XyZzY";
+
+fn limits() -> MultipartLimits {
+    MultipartLimits {
+        max_parts: 5,
+        max_field_name_len: 16,
+        max_field_bytes: 64,
+        max_part_header_bytes: 256,
+        max_bytes: 4096,
+    }
+}
+
+/// Encodes `(name, filename, value)` parts as a form.
+fn form(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, filename, value) in parts {
+        body.extend_from_slice(format!("--{}\r\n", BOUNDARY).as_bytes());
+        let disposition = match filename {
+            Some(filename) => format!(
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n",
+                name, filename
+            ),
+            None => format!("Content-Disposition: form-data; name=\"{}\"\r\n", name),
+        };
+        body.extend_from_slice(disposition.as_bytes());
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(value);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+async fn upload(form: Multipart) -> HttpResponse {
+    let fields: Vec<Value> = form
+        .fields
+        .iter()
+        .map(|field| {
+            json!({
+                "name": field.name,
+                "filename": field.filename,
+                "bytes": field.data.len(),
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "fields": fields }))
+}
+
+macro_rules! upload_app {
+    () => {
+        init_service(
+            App::new()
+                .app_data(limits())
+                .route("/upload", web::post().to(upload)),
+        )
+        .await
+    };
+}
+
+/// Posts `body` and returns the status and JSON body.
+macro_rules! post {
+    ($app:expr, $body:expr) => {{
+        let req = TestRequest::post()
+            .uri("/upload")
+            .insert_header((
+                "content-type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            ))
+            .set_payload($body)
+            .to_request();
+        let resp = call_service(&$app, req).await;
+        let status = resp.status();
+        let body: Value = read_body_json(resp).await;
+        (status, body)
+    }};
+}
+
+#[actix_rt::test]
+async fn compliant_forms_are_parsed() {
+    let app = upload_app!();
+    let file = vec![7u8; 1024];
+    let (status, body) = post!(
+        app,
+        form(&[
+            ("title", None, b"Quarterly report"),
+            ("tags", None, b"a,b\r\n--not-a-boundary"),
+            ("report", Some("report.bin"), &file),
+        ])
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body["fields"],
+        json!([
+            {"name": "title", "filename": null, "bytes": 16},
+            {"name": "tags", "filename": null, "bytes": 21},
+            {"name": "report", "filename": "report.bin", "bytes": 1024},
+        ])
+    );
+}
+
+#[test]
+fn boundaries_split_across_chunks_are_found() {
+    let body = form(&[("a", None, b"first"), ("b", Some("b.txt"), b"second")]);
+    let mut parser = MultipartParser::new(BOUNDARY, limits());
+    for byte in &body {
+        parser.feed(&[*byte]).unwrap();
+    }
+    let fields = parser.finish().unwrap();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(&fields[0].data[..], b"first");
+    assert_eq!(fields[1].filename.as_deref(), Some("b.txt"));
+    assert_eq!(&fields[1].data[..], b"second");
+
+    let mut parser = MultipartParser::new(BOUNDARY, limits());
+    parser.feed(&body[..body.len() / 2]).unwrap();
+    assert!(matches!(
+        parser.finish(),
+        Err(MultipartViolation::Malformed(_))
+    ));
+}
+
+#[actix_rt::test]
+async fn each_limit_is_enforced_and_named() {
+    let app = upload_app!();
+    let rejections = |limit: &str| {
+        Metrics::global().counter_value("multipart_rejections_total", &[("limit", limit)])
+    };
+    let before = rejections("max_parts");
+
+    let parts: Vec<(&str, Option<&str>, &[u8])> = vec![("f", None, &b"x"[..]); 6];
+    let (status, body) = post!(app, form(&parts));
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error"], "too_many_parts");
+    assert_eq!(body["fields"][0]["field"], "max_parts");
+    assert_eq!(rejections("max_parts"), before + 1);
+
+    let (status, body) = post!(app, form(&[(&"n".repeat(17), None, b"x")]));
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "field_name_too_long");
+    assert_eq!(body["fields"][0]["field"], "max_field_name_len");
+
+    let (status, body) = post!(app, form(&[("comment", None, &[b'c'; 65])]));
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error"], "field_too_large");
+    assert_eq!(body["fields"][0]["field"], "max_field_bytes");
+    assert!(body["message"].as_str().unwrap().contains("'comment'"));
+    // Files are only bound by the body limit
+    let (status, _) = post!(app, form(&[("upload", Some("a.bin"), &[b'c'; 65])]));
+    assert_eq!(status, StatusCode::OK);
+
+    let mut headers = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"a\"\r\nX-Padding: {}\r\n\r\nx\r\n--{}--\r\n",
+        BOUNDARY,
+        "p".repeat(300),
+        BOUNDARY
+    )
+    .into_bytes();
+    let (status, body) = post!(app, headers.clone());
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "part_headers_too_large");
+    assert_eq!(body["fields"][0]["field"], "max_part_header_bytes");
+
+    let (status, body) = post!(app, form(&[("upload", Some("a.bin"), &[0; 5000])]));
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error"], "payload_too_large");
+    assert_eq!(body["fields"][0]["field"], "max_bytes");
+
+    headers.truncate(20);
+    let (status, body) = post!(app, headers);
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "malformed_multipart");
+
+    let req = TestRequest::post()
+        .uri("/upload")
+        .insert_header(("content-type", "application/json"))
+        .set_payload("{}")
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[actix_rt::test]
+async fn rejection_does_not_wait_for_the_rest_of_the_body() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| {
+        App::new()
+            .app_data(MultipartLimits {
+                max_bytes: 10 * 1024 * 1024,
+                ..limits()
+            })
+            .route("/upload", web::post().to(upload))
+    })
+    .workers(1)
+    .disable_signals()
+    .listen(listener)
+    .unwrap()
+    .run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    // Announce a large body but only send the parts over the limit
+    let parts: Vec<(&str, Option<&str>, &[u8])> = vec![("f", None, &b"x"[..]); 6];
+    let mut start = form(&parts);
+    start.truncate(start.len() - format!("--{}--\r\n", BOUNDARY).len());
+    let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    client
+        .write_all(
+            format!(
+                "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary={}\r\nContent-Length: 5000000\r\n\r\n",
+                BOUNDARY
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    client.write_all(&start).await.unwrap();
+
+    // The answer arrives, and the connection is closed, while the client
+    // still owes almost all of the body
+    let mut response = Vec::new();
+    actix_web::rt::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("connection left open waiting for the body")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert!(response.to_ascii_lowercase().contains("connection: close"));
+    assert!(response.contains("\"too_many_parts\""));
+
+    handle.stop(true).await;
+}
@@ -0,0 +1,127 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+use actix_web::{web, App};
+use serde_json::Value;
+
+use main::hello;
+use main::util::negotiate::{negotiate, parse_accept, MediaRange};
+
+const OFFERED: &[&str] = &["text/plain", "application/json", "text/html"];
+
+#[test]
+fn quality_values_order_the_offers() {
+    assert_eq!(
+        negotiate(Some("text/plain;q=0.5, application/json"), OFFERED),
+        Some("application/json")
+    );
+    assert_eq!(
+        negotiate(
+            Some("text/html;q=0.9, application/json;q=0.8, text/plain;q=0.1"),
+            OFFERED
+        ),
+        Some("text/html")
+    );
+    // Ties go to the handler's preference
+    assert_eq!(
+        negotiate(Some("text/html, application/json"), OFFERED),
+        Some("application/json")
+    );
+    // q=0 rules an offer out
+    assert_eq!(
+        negotiate(Some("text/plain;q=0, */*;q=0.1"), OFFERED),
+        Some("application/json")
+    );
+}
+
+#[test]
+fn wildcards_match_by_specificity() {
+    assert_eq!(negotiate(Some("*/*"), OFFERED), Some("text/plain"));
+    assert_eq!(
+        negotiate(Some("application/*"), OFFERED),
+        Some("application/json")
+    );
+    // The most specific range decides, whatever its quality
+    assert_eq!(
+        negotiate(Some("text/*;q=0.9, text/plain;q=0.2"), OFFERED),
+        Some("text/html")
+    );
+    assert_eq!(
+        negotiate(Some("*/*;q=0.1, text/html"), OFFERED),
+        Some("text/html")
+    );
+    assert_eq!(
+        negotiate(
+            Some("TEXT/HTML"),
+            &["text/plain; charset=utf-8", "text/html"]
+        ),
+        Some("text/html")
+    );
+}
+
+#[test]
+fn malformed_entries_are_ignored() {
+    assert_eq!(
+        parse_accept("text/html;q=2, garbage, */plain, application/json;q=abc, text/plain;q=0.3"),
+        vec![MediaRange {
+            main: "text".to_string(),
+            sub: "plain".to_string(),
+            q: 0.3,
+        }]
+    );
+    assert_eq!(
+        negotiate(Some("garbage, also garbage"), OFFERED),
+        Some("text/plain")
+    );
+}
+
+#[test]
+fn missing_accept_picks_the_first_offer() {
+    assert_eq!(negotiate(None, OFFERED), Some("text/plain"));
+    assert_eq!(negotiate(Some(""), OFFERED), Some("text/plain"));
+    assert_eq!(negotiate(Some("image/png"), OFFERED), None);
+    assert_eq!(negotiate(None, &[]), None);
+}
+
+#[actix_rt::test]
+async fn hello_offers_text_json_and_html() {
+    let app = init_service(App::new().route("/hello", web::get().to(hello))).await;
+    let get = |accept: Option<&'static str>| {
+        let mut req = TestRequest::get().uri("/hello");
+        if let Some(accept) = accept {
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        req.to_request()
+    };
+
+    let resp = call_service(&app, get(None)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/plain; charset=utf-8"
+    );
+    assert_eq!(read_body(resp).await, "Hello world!");
+
+    let resp = call_service(&app, get(Some("application/json"))).await;
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body, serde_json::json!({ "message": "Hello world!" }));
+
+    let resp = call_service(&app, get(Some("text/html,*/*;q=0.8"))).await;
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    assert!(body.contains("<p>Hello world!</p>"), "{}", body);
+
+    let resp = call_service(&app, get(Some("image/png, application/xml"))).await;
+    assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    assert_eq!(resp.headers().get(header::VARY).unwrap(), "Accept");
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "not_acceptable");
+    let message = body["message"].as_str().unwrap();
+    for offered in OFFERED {
+        assert!(message.contains(offered), "{}", message);
+    }
+}
@@ -0,0 +1,64 @@
+//! Kept in its own binary: reqwest reads the proxy variables once per
+//! process, when the first client is built.
+
+use async_trait::async_trait;
+use std::env;
+use std::io::{Error as IoError, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread;
+
+use main::outbound::{OutboundClient, Resolve, UrlPolicy};
+
+/// Resolves every host to the loopback address.
+struct LoopbackResolver;
+
+#[async_trait]
+impl Resolve for LoopbackResolver {
+    async fn resolve(&self, _host: &str, port: u16) -> Result<Vec<SocketAddr>, IoError> {
+        Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+    }
+}
+
+/// Answers one request with `body`, returning the port.
+fn mock_server(body: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        if let Some(mut stream) = listener.incoming().flatten().next() {
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    port
+}
+
+#[actix_rt::test]
+async fn test_proxy_environment_does_not_bypass_the_checked_address() {
+    // A proxy would resolve the host again, past the policy checks
+    let proxy = mock_server("via proxy");
+    let port = mock_server("pinned");
+    for var in ["HTTP_PROXY", "http_proxy", "ALL_PROXY"] {
+        env::set_var(var, format!("http://127.0.0.1:{}", proxy));
+    }
+
+    let client = OutboundClient::new(
+        UrlPolicy {
+            allowed_ports: Vec::new(),
+            ..UrlPolicy::default()
+        }
+        .allow_host("internal.svc"),
+    )
+    .with_resolver(Arc::new(LoopbackResolver));
+    let response = client
+        .get(&format!("http://internal.svc:{}/", port))
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "pinned");
+}
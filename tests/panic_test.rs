@@ -0,0 +1,97 @@
+use actix_web::{test, web, App, HttpResponse};
+use serde_json::Value;
+
+use main::hello;
+use main::middleware::panic::PanicHandler;
+
+mod common;
+
+async fn explode() -> HttpResponse {
+    panic!("handler exploded");
+}
+
+#[actix_rt::test]
+async fn test_panic_returns_500_and_worker_survives() {
+    common::logs::capture();
+    let app = test::init_service(
+        App::new()
+            .wrap(PanicHandler)
+            .route("/explode", web::get().to(explode))
+            .route("/hello", web::get().to(hello)),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/explode").to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    let resp = err.error_response();
+    assert_eq!(resp.status(), 500);
+    let body: Value =
+        serde_json::from_slice(&actix_web::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"], "internal_error");
+
+    // The same service keeps handling requests
+    let req = test::TestRequest::get().uri("/hello").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    assert!(common::logs::contains("handler exploded"));
+}
+
+#[cfg(feature = "debug_endpoints")]
+mod debug_endpoints {
+    use super::*;
+    use main::admin;
+    use main::middleware::api_key::ApiKeyAuth;
+
+    macro_rules! app {
+        () => {
+            test::init_service(
+                App::new()
+                    .wrap(PanicHandler)
+                    .route("/hello", web::get().to(hello))
+                    .service(
+                        web::scope("/admin")
+                            .wrap(ApiKeyAuth::new(Some("secret".to_string())))
+                            .configure(admin::configure),
+                    ),
+            )
+            .await
+        };
+    }
+
+    #[actix_rt::test]
+    async fn test_debug_panic_endpoint() {
+        common::logs::capture();
+        let app = app!();
+
+        let req = test::TestRequest::post()
+            .uri("/admin/debug/panic")
+            .insert_header(("X-Api-Key", "secret"))
+            .to_request();
+        let err = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(err.error_response().status(), 500);
+
+        // The worker is still alive
+        let req = test::TestRequest::get().uri("/hello").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "Hello world!");
+
+        // The panic was logged
+        assert!(common::logs::contains("test panic"));
+    }
+
+    #[actix_rt::test]
+    async fn test_debug_panic_requires_api_key() {
+        let app = app!();
+
+        let req = test::TestRequest::post()
+            .uri("/admin/debug/panic")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+        let req = test::TestRequest::post()
+            .uri("/admin/debug/panic")
+            .insert_header(("X-Api-Key", "wrong"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+}
@@ -0,0 +1,217 @@
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use main::auth::file::{hash_password, FileBackend};
+use main::auth::reset::{confirm_reset, request_reset, PasswordReset, ResetNotifier};
+use main::auth::AuthBackend;
+use main::clock::MockClock;
+use main::master_key::MasterKey;
+
+#[derive(Default)]
+struct Capture(Mutex<Vec<(String, String)>>);
+
+#[async_trait]
+impl ResetNotifier for Capture {
+    async fn send(&self, username: &str, token: &str, _expires_at: i64) -> Result<(), String> {
+        self.0
+            .lock()
+            .unwrap()
+            .push((username.to_string(), token.to_string()));
+        Ok(())
+    }
+}
+
+fn backend() -> Arc<dyn AuthBackend> {
+    let users = format!("alice:{}:admin\n", hash_password("old password 123"));
+    Arc::new(FileBackend::parse(&users).unwrap())
+}
+
+fn key() -> MasterKey {
+    MasterKey::new(&[7u8; 32]).unwrap()
+}
+
+fn reset(backend: Arc<dyn AuthBackend>) -> PasswordReset {
+    PasswordReset::new(backend, &key(), Duration::from_secs(1800))
+}
+
+async fn wait_for(capture: &Capture, count: usize) {
+    for _ in 0..100 {
+        if capture.0.lock().unwrap().len() >= count {
+            return;
+        }
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[test]
+fn test_master_key_rejects_short_secrets() {
+    assert!(MasterKey::new(&[1u8; 16]).is_err());
+    assert!(MasterKey::new(&[1u8; 32]).is_ok());
+}
+
+#[actix_rt::test]
+async fn test_reset_flow_changes_password() {
+    let backend = backend();
+    let capture = Arc::new(Capture::default());
+    let reset = web::Data::new(reset(backend.clone()).with_notifier(capture.clone()));
+    let app = init_service(
+        App::new()
+            .app_data(reset)
+            .route("/request", web::post().to(request_reset))
+            .route("/confirm", web::post().to(confirm_reset)),
+    )
+    .await;
+
+    let req = TestRequest::post()
+        .uri("/request")
+        .set_json(json!({ "username": "alice" }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 202);
+    wait_for(&capture, 1).await;
+    let (username, token) = capture.0.lock().unwrap()[0].clone();
+    assert_eq!(username, "alice");
+
+    let req = TestRequest::post()
+        .uri("/confirm")
+        .set_json(json!({ "token": token, "new_password": "new password 456" }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    assert!(backend
+        .authenticate("alice", "old password 123")
+        .await
+        .is_err());
+    assert!(backend
+        .authenticate("alice", "new password 456")
+        .await
+        .is_ok());
+
+    // The token is spent
+    let req = TestRequest::post()
+        .uri("/confirm")
+        .set_json(json!({ "token": token, "new_password": "another password 789" }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_token");
+}
+
+#[actix_rt::test]
+async fn test_unknown_user_is_accepted_without_notification() {
+    let capture = Arc::new(Capture::default());
+    let reset = web::Data::new(reset(backend()).with_notifier(capture.clone()));
+    let app = init_service(
+        App::new()
+            .app_data(reset)
+            .route("/request", web::post().to(request_reset)),
+    )
+    .await;
+
+    let req = TestRequest::post()
+        .uri("/request")
+        .set_json(json!({ "username": "mallory" }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 202);
+    actix_rt::time::sleep(Duration::from_millis(100)).await;
+    assert!(capture.0.lock().unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn test_requests_are_rate_limited_per_client() {
+    let reset = web::Data::new(reset(backend()));
+    let app = init_service(
+        App::new()
+            .app_data(reset)
+            .route("/request", web::post().to(request_reset)),
+    )
+    .await;
+
+    for _ in 0..10 {
+        let req = TestRequest::post()
+            .uri("/request")
+            .set_json(json!({ "username": "alice" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 202);
+    }
+    let req = TestRequest::post()
+        .uri("/request")
+        .set_json(json!({ "username": "alice" }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().contains_key("retry-after"));
+}
+
+#[actix_rt::test]
+async fn test_expired_token_is_rejected() {
+    let clock = MockClock::new(chrono::Utc::now());
+    let reset = reset(backend()).with_clock(clock.clock());
+    let token = reset.issue("alice").await.unwrap();
+    clock.advance(Duration::from_secs(3600));
+
+    let err = reset.confirm(&token, "new password 456").await.unwrap_err();
+    assert_eq!(err.code(), "invalid_token");
+    assert!(err.message().contains("expired"));
+}
+
+#[actix_rt::test]
+async fn test_tampered_token_is_rejected() {
+    let reset = reset(backend());
+    let token = reset.issue("alice").await.unwrap();
+    let (payload, _) = token.split_once('.').unwrap();
+
+    let err = reset
+        .confirm(&format!("{}.AAAA", payload), "new password 456")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), "invalid_token");
+
+    // Signed with a different master key
+    let other = PasswordReset::new(
+        backend(),
+        &MasterKey::new(&[8u8; 32]).unwrap(),
+        Duration::from_secs(1800),
+    );
+    let forged = other.issue("alice").await.unwrap();
+    assert!(reset.confirm(&forged, "new password 456").await.is_err());
+}
+
+#[actix_rt::test]
+async fn test_weak_password_keeps_token_usable() {
+    let reset = reset(backend());
+    let token = reset.issue("alice").await.unwrap();
+
+    let err = reset.confirm(&token, "short").await.unwrap_err();
+    assert_eq!(err.code(), "weak_password");
+    assert_eq!(err.fields()[0].field, "new_password");
+    let err = reset
+        .confirm(&token, "alice's new password")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), "weak_password");
+
+    assert_eq!(
+        reset.confirm(&token, "new password 456").await.unwrap(),
+        "alice"
+    );
+}
+
+#[actix_rt::test]
+async fn test_password_change_invalidates_outstanding_tokens() {
+    let reset = reset(backend());
+    let first = reset.issue("alice").await.unwrap();
+    let second = reset.issue("alice").await.unwrap();
+
+    reset.confirm(&first, "new password 456").await.unwrap();
+    let err = reset
+        .confirm(&second, "another password 789")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), "invalid_token");
+}
@@ -0,0 +1,96 @@
+use actix_web::{test, web, App};
+
+use main::admin;
+use main::middleware::api_key::ApiKeyAuth;
+
+macro_rules! app {
+    () => {
+        test::init_service(
+            App::new().service(
+                web::scope("/admin")
+                    .wrap(ApiKeyAuth::new(Some("secret".to_string())))
+                    .configure(admin::configure),
+            ),
+        )
+        .await
+    };
+}
+
+#[cfg(feature = "profiling")]
+mod profiling {
+    use super::*;
+    use pprof::protos::{Message, Profile};
+
+    #[actix_rt::test]
+    async fn test_cpu_profile_and_single_session() {
+        let app = app!();
+
+        let profile = test::TestRequest::get()
+            .uri("/admin/debug/pprof/profile?seconds=1")
+            .insert_header(("X-Api-Key", "secret"))
+            .to_request();
+        let concurrent = test::TestRequest::get()
+            .uri("/admin/debug/pprof/profile?seconds=1")
+            .insert_header(("X-Api-Key", "secret"))
+            .to_request();
+
+        // The second request starts while the first is still sampling
+        let (first, second) = futures_util::join!(
+            test::call_service(&app, profile),
+            test::call_service(&app, concurrent)
+        );
+        assert_eq!(first.status(), 200);
+        assert_eq!(second.status(), 409);
+
+        let body = test::read_body(first).await;
+        assert!(!body.is_empty());
+        let profile = Profile::decode(body).expect("Response is not a pprof protobuf");
+        assert!(!profile.string_table.is_empty());
+        assert!(!profile.sample_type.is_empty());
+
+        // The session is released afterwards
+        let req = test::TestRequest::get()
+            .uri("/admin/debug/pprof/profile?seconds=1&format=flamegraph")
+            .insert_header(("X-Api-Key", "secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "image/svg+xml");
+    }
+
+    #[actix_rt::test]
+    async fn test_cpu_profile_validates_parameters() {
+        let app = app!();
+        for uri in [
+            "/admin/debug/pprof/profile?seconds=0",
+            "/admin/debug/pprof/profile?seconds=100000",
+            "/admin/debug/pprof/profile?seconds=1&format=svg",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("X-Api-Key", "secret"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), 400, "{}", uri);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_cpu_profile_requires_api_key() {
+        let app = app!();
+        let req = test::TestRequest::get()
+            .uri("/admin/debug/pprof/profile?seconds=1")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 401);
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+#[actix_rt::test]
+async fn test_profiling_endpoints_absent_without_feature() {
+    let app = app!();
+    let req = test::TestRequest::get()
+        .uri("/admin/debug/pprof/profile?seconds=1")
+        .insert_header(("X-Api-Key", "secret"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+}
@@ -0,0 +1,74 @@
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App, HttpResponse};
+
+use main::middleware::proto_enforcement::ProtoEnforcer;
+
+mod common;
+
+macro_rules! app {
+    ($enforcer:expr) => {
+        init_service(App::new().wrap($enforcer).route(
+            "/orders",
+            web::get().to(|| async { HttpResponse::Ok().body("orders") }),
+        ))
+        .await
+    };
+}
+
+#[actix_rt::test]
+async fn test_http_is_redirected_to_https() {
+    let app = app!(ProtoEnforcer::new(true));
+    let req = TestRequest::get()
+        .uri("/orders?page=2")
+        .insert_header(("Host", "shop.example.com:80"))
+        .insert_header(("X-Forwarded-Proto", "http"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+
+    assert_eq!(resp.status(), 301);
+    assert_eq!(
+        resp.headers().get("location").unwrap(),
+        "https://shop.example.com/orders?page=2"
+    );
+}
+
+#[actix_rt::test]
+async fn test_https_passes_through() {
+    let app = app!(ProtoEnforcer::new(true));
+    for proto in [Some("https"), Some("http, https"), None] {
+        let mut req = TestRequest::get()
+            .uri("/orders")
+            .insert_header(("Host", "shop.example.com"));
+        if let Some(proto) = proto {
+            req = req.insert_header(("X-Forwarded-Proto", proto));
+        }
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), 200, "{:?}", proto);
+        assert_eq!(read_body(resp).await, "orders");
+    }
+}
+
+#[actix_rt::test]
+async fn test_disabled_ignores_forwarded_proto() {
+    let app = app!(ProtoEnforcer::new(false));
+    let req = TestRequest::get()
+        .uri("/orders")
+        .insert_header(("Host", "shop.example.com"))
+        .insert_header(("X-Forwarded-Proto", "http"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[test]
+fn test_from_env_needs_trust_proxy() {
+    let _env = common::env_lock();
+    std::env::set_var("FORCE_HTTPS", "true");
+    std::env::set_var("TRUST_PROXY", "false");
+    assert!(!ProtoEnforcer::from_env().is_enabled());
+    std::env::set_var("TRUST_PROXY", "true");
+    assert!(ProtoEnforcer::from_env().is_enabled());
+    std::env::remove_var("FORCE_HTTPS");
+    assert!(!ProtoEnforcer::from_env().is_enabled());
+    std::env::remove_var("TRUST_PROXY");
+}
@@ -0,0 +1,270 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use main::lifecycle::Lifecycle;
+use main::proxy_protocol::{
+    parse_proxy_protocol_v1, parse_proxy_protocol_v2, read_header, ParseError, ProxyInfo,
+    ProxyProtocolAcceptor, V2_SIGNATURE,
+};
+
+fn info(src: &str, dst: &str) -> ProxyInfo {
+    ProxyInfo {
+        src_addr: src.parse().unwrap(),
+        dst_addr: dst.parse().unwrap(),
+    }
+}
+
+fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+}
+
+#[test]
+fn test_v1_tcp4_and_tcp6() {
+    assert_eq!(
+        parse_proxy_protocol_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"),
+        Ok(info("192.0.2.1:56324", "198.51.100.1:443"))
+    );
+    assert_eq!(
+        parse_proxy_protocol_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n"),
+        Ok(info("[2001:db8::1]:4000", "[2001:db8::2]:443"))
+    );
+    assert_eq!(
+        parse_proxy_protocol_v1(b"PROXY UNKNOWN\r\n"),
+        Err(ParseError::NoAddress)
+    );
+}
+
+#[test]
+fn test_v1_rejects_malformed_headers() {
+    assert_eq!(
+        parse_proxy_protocol_v1(b"PROXY TCP4 192.0.2.1"),
+        Err(ParseError::Incomplete)
+    );
+    for header in [
+        &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n"[..],
+        b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n",
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 080 443\r\n",
+        b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n",
+        b"GET / HTTP/1.1\r\n",
+    ] {
+        assert!(
+            matches!(parse_proxy_protocol_v1(header), Err(ParseError::Invalid(_))),
+            "{:?}",
+            String::from_utf8_lossy(header)
+        );
+    }
+    let long = [b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat();
+    assert!(matches!(
+        parse_proxy_protocol_v1(&long),
+        Err(ParseError::Invalid(_))
+    ));
+}
+
+#[test]
+fn test_v2_ipv4_and_ipv6() {
+    let ipv4 = v2(
+        0x1,
+        0x11,
+        &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+    );
+    assert_eq!(
+        parse_proxy_protocol_v2(&ipv4),
+        Ok(info("192.0.2.1:56324", "198.51.100.1:443"))
+    );
+
+    let mut addresses = Vec::new();
+    addresses.extend_from_slice(
+        &"2001:db8::1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    addresses.extend_from_slice(
+        &"2001:db8::2"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    addresses.extend_from_slice(&[0x0f, 0xa0, 0x01, 0xbb]);
+    // TLVs after the addresses are skipped
+    addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+    assert_eq!(
+        parse_proxy_protocol_v2(&v2(0x1, 0x21, &addresses)),
+        Ok(info("[2001:db8::1]:4000", "[2001:db8::2]:443"))
+    );
+}
+
+#[test]
+fn test_v2_local_incomplete_and_invalid() {
+    assert_eq!(
+        parse_proxy_protocol_v2(&v2(0x0, 0x00, &[])),
+        Err(ParseError::NoAddress)
+    );
+    let ipv4 = v2(0x1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+    assert_eq!(
+        parse_proxy_protocol_v2(&ipv4[..20]),
+        Err(ParseError::Incomplete)
+    );
+    assert_eq!(
+        parse_proxy_protocol_v2(&ipv4[..8]),
+        Err(ParseError::Incomplete)
+    );
+    assert!(matches!(
+        parse_proxy_protocol_v2(&v2(0x1, 0x11, &[1, 2, 3])),
+        Err(ParseError::Invalid(_))
+    ));
+    let mut bad_version = ipv4.clone();
+    bad_version[12] = 0x11;
+    assert!(matches!(
+        parse_proxy_protocol_v2(&bad_version),
+        Err(ParseError::Invalid(_))
+    ));
+    assert!(matches!(
+        parse_proxy_protocol_v2(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"),
+        Err(ParseError::Invalid(_))
+    ));
+}
+
+async fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+#[actix_rt::test]
+async fn test_read_header_leaves_payload_unread() {
+    for header in [
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".to_vec(),
+        v2(
+            0x1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        ),
+    ] {
+        let (mut client, mut server) = pair().await;
+        client
+            .write_all(&[header, b"payload".to_vec()].concat())
+            .await
+            .unwrap();
+        let info = read_header(&mut server).await.unwrap();
+        assert_eq!(
+            info,
+            Some(self::info("192.0.2.1:56324", "198.51.100.1:443"))
+        );
+        let mut rest = [0u8; 7];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"payload");
+    }
+
+    let (mut client, mut server) = pair().await;
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    assert!(read_header(&mut server).await.is_err());
+}
+
+#[actix_rt::test]
+async fn test_acceptor_relays_and_records_client_address() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let public = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let public_addr = public.local_addr().unwrap();
+    let acceptor = ProxyProtocolAcceptor::new();
+    acceptor
+        .spawn(public, backend.local_addr().unwrap())
+        .unwrap();
+
+    let mut client = TcpStream::connect(public_addr).await.unwrap();
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 198.51.100.1 40000 443\r\nhello")
+        .await
+        .unwrap();
+
+    let (mut relayed, peer): (TcpStream, SocketAddr) = backend.accept().await.unwrap();
+    let mut received = [0u8; 5];
+    relayed.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"hello");
+    assert_eq!(
+        acceptor.lookup(peer).map(|i| i.src_addr),
+        Some("203.0.113.7:40000".parse().unwrap())
+    );
+
+    relayed.write_all(b"world").await.unwrap();
+    client.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"world");
+
+    // The mapping is dropped with the connection
+    drop(client);
+    drop(relayed);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while acceptor.lookup(peer).is_some() && std::time::Instant::now() < deadline {
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(acceptor.lookup(peer), None);
+}
+
+#[actix_rt::test]
+async fn test_acceptor_closes_connections_without_header() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let public = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let public_addr = public.local_addr().unwrap();
+    ProxyProtocolAcceptor::new()
+        .spawn(public, backend.local_addr().unwrap())
+        .unwrap();
+
+    let mut client = TcpStream::connect(public_addr).await.unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut buf = [0u8; 1];
+    let read = actix_web::rt::time::timeout(Duration::from_secs(5), client.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
+
+#[actix_rt::test]
+async fn test_acceptor_stops_when_the_drain_starts() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let public = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let public_addr = public.local_addr().unwrap();
+    let acceptor = ProxyProtocolAcceptor::new();
+    acceptor
+        .spawn(public, backend.local_addr().unwrap())
+        .unwrap();
+    let lifecycle = Lifecycle::new();
+    let stop = acceptor.clone();
+    lifecycle.on_drain(move || stop.stop());
+
+    let mut client = TcpStream::connect(public_addr).await.unwrap();
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 198.51.100.1 40000 443\r\nhello")
+        .await
+        .unwrap();
+    let (mut relayed, _) = backend.accept().await.unwrap();
+    let mut received = [0u8; 5];
+    relayed.read_exact(&mut received).await.unwrap();
+
+    lifecycle.begin_drain();
+
+    // New connections are refused once the listener is closed
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(public_addr).await.is_ok() {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "Still accepting after the drain started"
+        );
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    // The relayed connection carries on
+    relayed.write_all(b"world").await.unwrap();
+    client.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"world");
+}
@@ -0,0 +1,322 @@
+use actix_web::{test, web, App};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use main::metrics::Metrics;
+use main::middleware::access_log::sample_bucket;
+use main::proxy::mirror::{
+    configure_admin, MIRROR_COMPARISON_METRIC, MIRROR_REQUESTS_METRIC, MIRROR_SKIPPED_METRIC,
+};
+use main::proxy::{Mirror, MirrorConfig, Proxy};
+
+/// A request received by a stub upstream.
+#[derive(Clone, Debug)]
+struct Recorded {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Recorded {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Upstream {
+    url: String,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl Upstream {
+    fn received(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Waits until at least `n` requests arrived, returning them.
+    async fn wait_for(&self, n: usize) -> Vec<Recorded> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while self.received().len() < n && Instant::now() < deadline {
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        self.received()
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Option<Recorded> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let n = stream.read(&mut buf).ok().filter(|&n| n > 0)?;
+        data.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(n, v)| (n.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+    let length = headers
+        .iter()
+        .find(|(n, _)| n == "content-length")
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+    while data.len() < header_end + length {
+        let n = stream.read(&mut buf).ok().filter(|&n| n > 0)?;
+        data.extend_from_slice(&buf[..n]);
+    }
+    Some(Recorded {
+        method,
+        path,
+        headers,
+        body: data[header_end..header_end + length].to_vec(),
+    })
+}
+
+/// Starts an upstream answering every request with `status` and `body`
+/// after `delay`, recording what it receives.
+fn upstream(status: u16, body: &'static str, delay: Duration) -> Upstream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/base", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorder = requests.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let recorder = recorder.clone();
+            thread::spawn(move || {
+                let Some(request) = read_request(&mut stream) else {
+                    return;
+                };
+                recorder.lock().unwrap().push(request);
+                thread::sleep(delay);
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nX-Upstream: stub\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                );
+            });
+        }
+    });
+    Upstream { url, requests }
+}
+
+fn proxy(primary: &Upstream, mirror: Option<MirrorConfig>) -> web::Data<Proxy> {
+    web::Data::new(Proxy::new(
+        "/proxy",
+        Url::parse(&primary.url).unwrap(),
+        Mirror::new(mirror, Duration::from_secs(5)),
+    ))
+}
+
+fn mirror_config(mirror: &Upstream) -> MirrorConfig {
+    MirrorConfig::new(Url::parse(&mirror.url).unwrap())
+}
+
+macro_rules! app {
+    ($proxy:expr) => {
+        test::init_service(
+            App::new()
+                .configure(Proxy::configure($proxy.clone()))
+                .service(web::scope("/admin").configure(configure_admin)),
+        )
+        .await
+    };
+}
+
+#[actix_rt::test]
+async fn test_response_comes_from_primary_while_mirror_is_slow() {
+    let primary = upstream(200, "primary", Duration::ZERO);
+    let mirror = upstream(200, "mirror", Duration::from_secs(3));
+    let app = app!(proxy(&primary, Some(mirror_config(&mirror))));
+
+    let started = Instant::now();
+    let req = test::TestRequest::post()
+        .uri("/proxy/items?page=2")
+        .insert_header(("X-Custom", "1"))
+        .insert_header(("Proxy-Authorization", "Basic c2VjcmV0"))
+        .set_payload("payload")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-upstream").unwrap(), "stub");
+    assert_eq!(test::read_body(resp).await, "primary");
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let received = primary.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].path, "/base/items?page=2");
+
+    let mirrored = mirror.wait_for(1).await;
+    assert_eq!(mirrored.len(), 1);
+    let mirrored = &mirrored[0];
+    assert_eq!(mirrored.method, "POST");
+    assert_eq!(mirrored.path, "/base/items?page=2");
+    assert_eq!(mirrored.body, b"payload");
+    assert_eq!(mirrored.header("x-custom"), Some("1"));
+    assert_eq!(mirrored.header("proxy-authorization"), None);
+}
+
+#[actix_rt::test]
+async fn test_unreachable_mirror_does_not_affect_primary() {
+    let primary = upstream(200, "primary", Duration::ZERO);
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", closed.local_addr().unwrap());
+    drop(closed);
+    let app = app!(proxy(
+        &primary,
+        Some(MirrorConfig::new(Url::parse(&url).unwrap()))
+    ));
+
+    let errors = || Metrics::global().counter_value(MIRROR_REQUESTS_METRIC, &[("status", "error")]);
+    let before = errors();
+    let req = test::TestRequest::get().uri("/proxy/x").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(test::read_body(resp).await, "primary");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while errors() == before && Instant::now() < deadline {
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(errors() > before);
+}
+
+#[actix_rt::test]
+async fn test_sampling_follows_request_ids() {
+    let primary = upstream(200, "primary", Duration::ZERO);
+    let mirror = upstream(200, "mirror", Duration::ZERO);
+    let mut config = mirror_config(&mirror);
+    config.sample_rate = 0.25;
+    let app = app!(proxy(&primary, Some(config)));
+
+    let ids: Vec<String> = (0..400).map(|i| format!("sampling-{}", i)).collect();
+    let expected = ids.iter().filter(|id| sample_bucket(id) < 0.25).count();
+    assert!((60..=140).contains(&expected), "expected {}", expected);
+
+    for id in &ids {
+        let req = test::TestRequest::get()
+            .uri("/proxy/sampled")
+            .insert_header(("X-Request-Id", id.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    mirror.wait_for(expected).await;
+    actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mirror.received().len(), expected);
+    assert_eq!(primary.received().len(), ids.len());
+}
+
+#[actix_rt::test]
+async fn test_routes_and_body_cap_limit_mirroring() {
+    let primary = upstream(200, "primary", Duration::ZERO);
+    let mirror = upstream(200, "mirror", Duration::ZERO);
+    let mut config = mirror_config(&mirror);
+    config.routes = vec!["/orders".to_string()];
+    config.max_body_bytes = 8;
+    let app = app!(proxy(&primary, Some(config)));
+
+    let too_large =
+        || Metrics::global().counter_value(MIRROR_SKIPPED_METRIC, &[("reason", "body_too_large")]);
+    let before = too_large();
+    for (uri, body) in [
+        ("/proxy/users", "small"),
+        ("/proxy/orders", "far too large"),
+        ("/proxy/orders/1", "small"),
+    ] {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .set_payload(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+    }
+
+    let mirrored = mirror.wait_for(1).await;
+    actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mirror.received().len(), 1);
+    assert_eq!(mirrored[0].path, "/base/orders/1");
+    assert_eq!(too_large(), before + 1);
+}
+
+#[actix_rt::test]
+async fn test_status_and_body_comparison_is_recorded() {
+    let primary = upstream(200, "same", Duration::ZERO);
+    let mirror = upstream(203, "same", Duration::ZERO);
+    let app = app!(proxy(&primary, Some(mirror_config(&mirror))));
+
+    let metrics = Metrics::global();
+    let status_203 = || metrics.counter_value(MIRROR_REQUESTS_METRIC, &[("status", "203")]);
+    let before = status_203();
+    let req = test::TestRequest::get().uri("/proxy/compare").to_request();
+    test::call_service(&app, req).await;
+
+    mirror.wait_for(1).await;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while status_203() == before && Instant::now() < deadline {
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(status_203(), before + 1);
+    assert!(metrics.counter_value(MIRROR_COMPARISON_METRIC, &[("result", "mismatch")]) > 0);
+    assert!(metrics.render().contains("proxy_mirror_latency_ms_total"));
+}
+
+#[actix_rt::test]
+async fn test_mirror_is_reconfigured_at_runtime() {
+    let primary = upstream(200, "primary", Duration::ZERO);
+    let mirror = upstream(200, "mirror", Duration::ZERO);
+    let app = app!(proxy(&primary, None));
+
+    let req = test::TestRequest::get()
+        .uri("/admin/proxy/mirror")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({ "enabled": false }));
+
+    let req = test::TestRequest::put()
+        .uri("/admin/proxy/mirror")
+        .set_json(json!({ "url": mirror.url, "sample_rate": 2.0 }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let req = test::TestRequest::put()
+        .uri("/admin/proxy/mirror")
+        .set_json(json!({ "url": mirror.url, "routes": ["/live"] }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["enabled"], true);
+    assert_eq!(body["sample_rate"], 1.0);
+
+    let req = test::TestRequest::get().uri("/proxy/live").to_request();
+    test::call_service(&app, req).await;
+    assert_eq!(mirror.wait_for(1).await.len(), 1);
+
+    let req = test::TestRequest::delete()
+        .uri("/admin/proxy/mirror")
+        .to_request();
+    test::call_service(&app, req).await;
+    let req = test::TestRequest::get().uri("/proxy/live").to_request();
+    test::call_service(&app, req).await;
+    actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mirror.received().len(), 1);
+    assert_eq!(primary.received().len(), 2);
+}
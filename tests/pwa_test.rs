@@ -0,0 +1,97 @@
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+use actix_web::{web, App};
+
+use main::pwa::{self, Icon, Manifest, PwaConfig};
+
+fn config() -> PwaConfig {
+    PwaConfig {
+        manifest: Manifest {
+            name: "Secure Server".to_string(),
+            short_name: "Secure".to_string(),
+            start_url: "/".to_string(),
+            display: "standalone".to_string(),
+            background_color: "#fff".to_string(),
+            theme_color: "#000".to_string(),
+            icons: vec![Icon::parse("/icons/192.png:192x192").unwrap()],
+        },
+        precache_assets: vec!["/".to_string(), "/static/app.js".to_string()],
+    }
+}
+
+#[test]
+fn test_icon_parsing() {
+    assert_eq!(
+        Icon::parse("/icons/logo.svg:any"),
+        Some(Icon {
+            src: "/icons/logo.svg".to_string(),
+            sizes: "any".to_string(),
+            mime_type: "image/svg+xml".to_string(),
+        })
+    );
+    assert_eq!(Icon::parse("icons/192.png:192x192"), None);
+    assert_eq!(Icon::parse("/icons/192.png"), None);
+    assert_eq!(Icon::parse("/icons/192.bmp:192x192"), None);
+}
+
+#[actix_rt::test]
+async fn test_manifest() {
+    let app = init_service(App::new().configure(pwa::configure(web::Data::new(config())))).await;
+    let req = TestRequest::get().uri("/manifest.json").to_request();
+    let resp = call_service(&app, req).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("cache-control").unwrap(),
+        "public, max-age=86400"
+    );
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/manifest+json"
+    );
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["name"], "Secure Server");
+    assert_eq!(body["short_name"], "Secure");
+    assert_eq!(body["start_url"], "/");
+    assert_eq!(body["display"], "standalone");
+    assert_eq!(body["background_color"], "#fff");
+    assert_eq!(body["theme_color"], "#000");
+    assert_eq!(body["icons"][0]["src"], "/icons/192.png");
+    assert_eq!(body["icons"][0]["sizes"], "192x192");
+    assert_eq!(body["icons"][0]["type"], "image/png");
+}
+
+#[actix_rt::test]
+async fn test_service_worker() {
+    let app = init_service(App::new().configure(pwa::configure(web::Data::new(config())))).await;
+    let req = TestRequest::get().uri("/service-worker.js").to_request();
+    let resp = call_service(&app, req).await;
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("cache-control").unwrap(), "no-cache");
+    assert!(resp
+        .headers()
+        .get("content-type")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .starts_with("application/javascript"));
+    let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    assert!(body.contains(r#"const PRECACHE_ASSETS = ["/","/static/app.js"];"#));
+    assert!(body.contains("caches.match(event.request)"));
+    assert!(!body.contains("__CACHE_NAME__"));
+}
+
+#[test]
+fn test_cache_name_changes_with_assets() {
+    let mut other = config();
+    other.precache_assets.push("/static/app.css".to_string());
+    let cache_name = |script: String| script.lines().next().unwrap().to_string();
+    assert_eq!(
+        cache_name(config().service_worker()),
+        cache_name(config().service_worker())
+    );
+    assert_ne!(
+        cache_name(config().service_worker()),
+        cache_name(other.service_worker())
+    );
+}
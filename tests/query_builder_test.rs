@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use actix_web::http::Uri;
+
+use main::util::query_builder::QueryStringBuilder;
+
+#[test]
+fn values_are_percent_encoded() {
+    let mut query = QueryStringBuilder::new();
+    query
+        .add("q", "a&b=c #1")
+        .add("path", "/x/y")
+        .add("name", "zoë")
+        .add("safe", "A-z_0.9~");
+    assert_eq!(
+        query.build(),
+        "q=a%26b%3Dc%20%231&path=%2Fx%2Fy&name=zo%C3%AB&safe=A-z_0.9~"
+    );
+}
+
+#[test]
+fn empty_builder_builds_nothing() {
+    let query = QueryStringBuilder::new();
+    assert_eq!(query.build(), "");
+    assert_eq!(query.append_to("/items"), "/items");
+}
+
+#[test]
+fn optional_values_are_added_when_present() {
+    let mut query = QueryStringBuilder::new();
+    query
+        .add_optional("page", Some("2"))
+        .add_optional("sort", None::<String>);
+    assert_eq!(query.append_to("/items"), "/items?page=2");
+}
+
+#[test]
+fn identical_pairs_are_added_once() {
+    let mut query = QueryStringBuilder::new();
+    query.add("tag", "a").add("tag", "b").add("tag", "a");
+    assert_eq!(query.build(), "tag=a&tag=b");
+}
+
+#[test]
+fn map_values_replace_existing_ones() {
+    let mut query = QueryStringBuilder::parse("tag=a&page=1&tag=b&keep=1");
+    query.set_from_map(HashMap::from([
+        ("tag".to_string(), "c".to_string()),
+        ("limit".to_string(), "10".to_string()),
+        ("b".to_string(), "2".to_string()),
+    ]));
+    // Replaced in place; new keys are appended sorted
+    assert_eq!(query.build(), "tag=c&page=1&keep=1&b=2&limit=10");
+}
+
+#[test]
+fn remove_drops_every_value() {
+    let mut query = QueryStringBuilder::parse("tag=a&page=1&tag=b");
+    query.remove("tag").remove("missing");
+    assert_eq!(query.build(), "page=1");
+}
+
+#[test]
+fn parsing_decodes_and_building_round_trips() {
+    let uri: Uri = "/search?q=a%26b+c&flag&empty=&x=%2Fy".parse().unwrap();
+    let query = QueryStringBuilder::from_uri(&uri);
+    assert_eq!(query.get("q"), Some("a&b c"));
+    assert_eq!(query.get("flag"), Some(""));
+    assert_eq!(query.get("empty"), Some(""));
+    assert_eq!(query.get("x"), Some("/y"));
+
+    let built = query.build();
+    assert_eq!(built, "q=a%26b%20c&flag&empty=&x=%2Fy");
+    assert_eq!(QueryStringBuilder::parse(&built), query);
+}
+
+#[test]
+fn uri_without_query_is_empty() {
+    let uri: Uri = "/items".parse().unwrap();
+    assert!(QueryStringBuilder::from_uri(&uri).is_empty());
+    let uri: Uri = "/items?&&".parse().unwrap();
+    assert!(QueryStringBuilder::from_uri(&uri).is_empty());
+}
@@ -0,0 +1,103 @@
+use actix_web::{test, web, App, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use main::util::query::{NestedQuery, NestedQueryConfig, QueryNotation};
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Filter {
+    name: Option<String>,
+    age: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Search {
+    q: Option<String>,
+    filter: Option<Filter>,
+    #[serde(default)]
+    sort: Vec<String>,
+}
+
+async fn search(query: NestedQuery<Search>) -> HttpResponse {
+    HttpResponse::Ok().json(query.into_inner())
+}
+
+macro_rules! app {
+    ($config:expr) => {
+        test::init_service(
+            App::new()
+                .app_data($config)
+                .route("/search", web::get().to(search)),
+        )
+        .await
+    };
+}
+
+#[actix_rt::test]
+async fn test_flat_query() {
+    let app = app!(NestedQueryConfig::default());
+    let req = test::TestRequest::get().uri("/search?q=rust").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["q"], "rust");
+    assert_eq!(body["filter"], Value::Null);
+}
+
+#[actix_rt::test]
+async fn test_nested_and_array_query() {
+    let app = app!(NestedQueryConfig::default());
+    let req = test::TestRequest::get()
+        .uri("/search?filter[name]=alice&filter[age]=30&sort[]=name&sort[]=age")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["filter"], json!({ "name": "alice", "age": 30 }));
+    assert_eq!(body["sort"], json!(["name", "age"]));
+}
+
+#[actix_rt::test]
+async fn test_dot_notation_query() {
+    let app = app!(NestedQueryConfig::default().notation(QueryNotation::Dots));
+    let req = test::TestRequest::get()
+        .uri("/search?filter.name=alice&filter.age=30&sort[]=name")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(body["filter"], json!({ "name": "alice", "age": 30 }));
+    assert_eq!(body["sort"], json!(["name"]));
+}
+
+#[actix_rt::test]
+async fn test_invalid_field_reports_path() {
+    let app = app!(NestedQueryConfig::default());
+    let req = test::TestRequest::get()
+        .uri("/search?filter[age]=old")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_query");
+    assert_eq!(body["fields"][0]["field"], "filter.age");
+}
+
+#[actix_rt::test]
+async fn test_excessively_deep_query_is_rejected() {
+    let app = app!(NestedQueryConfig::default().max_depth(2));
+    let req = test::TestRequest::get()
+        .uri("/search?filter[a][b][c][d]=1")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"], "query_too_deep");
+    assert_eq!(body["fields"][0]["field"], "filter[a][b][c][d]");
+
+    // Encoded brackets count towards the depth as well
+    let req = test::TestRequest::get()
+        .uri("/search?filter%5Ba%5D%5Bb%5D%5Bc%5D=1")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 400);
+}
@@ -0,0 +1,197 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::test::{call_and_read_body, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use std::net::{IpAddr, SocketAddr};
+
+use main::util::real_ip::{
+    parse_forwarded, resolve, ForwardedElement, ForwardedNode, RealIp, TrustedProxies,
+};
+
+fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in pairs {
+        map.append(
+            HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            HeaderValue::from_str(value).unwrap(),
+        );
+    }
+    map
+}
+
+fn peer(addr: &str) -> Option<SocketAddr> {
+    Some(addr.parse().unwrap())
+}
+
+fn ip(addr: &str) -> ForwardedNode {
+    ForwardedNode::Ip(addr.parse::<IpAddr>().unwrap())
+}
+
+fn trusted() -> TrustedProxies {
+    TrustedProxies::parse("10.0.0.0/8, fd00::/8").unwrap()
+}
+
+#[test]
+fn test_parse_forwarded_quoting_and_case() {
+    let elements = parse_forwarded([
+        r#"For="[2001:db8:cafe::17]:4711";Proto=HTTPS;host="example.com", for=192.0.2.60;by=203.0.113.43"#,
+    ])
+    .unwrap();
+    assert_eq!(
+        elements,
+        vec![
+            ForwardedElement {
+                for_node: Some(ip("2001:db8:cafe::17")),
+                proto: Some("https".to_string()),
+                host: Some("example.com".to_string()),
+            },
+            ForwardedElement {
+                for_node: Some(ip("192.0.2.60")),
+                proto: None,
+                host: None,
+            },
+        ]
+    );
+
+    // Separators and escapes inside quotes are literal
+    let elements = parse_forwarded([r#"for=_gazonk;host="a\"b,c;d""#]).unwrap();
+    assert_eq!(elements[0].host.as_deref(), Some(r#"a"b,c;d"#));
+
+    // Multiple header lines are concatenated in order
+    let elements = parse_forwarded(["for=192.0.2.1", "for=10.0.0.1"]).unwrap();
+    assert_eq!(elements.len(), 2);
+}
+
+#[test]
+fn test_parse_forwarded_obfuscated_and_unknown() {
+    let elements = parse_forwarded(["for=_hidden, for=unknown, for=\"_SEVKISEK:_port\""]).unwrap();
+    assert_eq!(
+        elements[0].for_node,
+        Some(ForwardedNode::Obfuscated("_hidden".to_string()))
+    );
+    assert_eq!(elements[1].for_node, Some(ForwardedNode::Unknown));
+    assert_eq!(
+        elements[2].for_node,
+        Some(ForwardedNode::Obfuscated("_SEVKISEK".to_string()))
+    );
+}
+
+#[test]
+fn test_parse_forwarded_rejects_malformed() {
+    // IPv6 must be quoted and bracketed
+    assert!(parse_forwarded(["for=2001:db8::1"]).is_none());
+    assert!(parse_forwarded(["for=\"2001:db8::1\""]).is_none());
+    assert!(parse_forwarded(["for=\"[2001:db8::1]:99999\""]).is_none());
+    assert!(parse_forwarded(["for=\"unterminated"]).is_none());
+    assert!(parse_forwarded(["for"]).is_none());
+    assert!(parse_forwarded(["for=_"]).is_none());
+}
+
+#[test]
+fn test_forwarded_ignored_from_untrusted_peer() {
+    let h = headers(&[("forwarded", "for=198.51.100.7")]);
+    let client = resolve(peer("203.0.113.9:5000"), &h, &trusted());
+    assert_eq!(client.node, ip("203.0.113.9"));
+    assert_eq!(client.proto, None);
+}
+
+#[test]
+fn test_forwarded_preferred_over_x_forwarded_for() {
+    let h = headers(&[
+        ("forwarded", "for=198.51.100.7;proto=https;host=example.com"),
+        ("x-forwarded-for", "192.0.2.99"),
+    ]);
+    let client = resolve(peer("10.1.2.3:5000"), &h, &trusted());
+    assert_eq!(
+        client,
+        RealIp {
+            node: ip("198.51.100.7"),
+            proto: Some("https".to_string()),
+            host: Some("example.com".to_string()),
+        }
+    );
+}
+
+#[test]
+fn test_forwarded_skips_trusted_hops() {
+    // The client forged the first element; only hops after the last
+    // untrusted one are believed
+    let h = headers(&[(
+        "forwarded",
+        "for=192.0.2.1, for=\"[2001:db8::5]:1234\", for=\"[fd00::2]\"",
+    )]);
+    let client = resolve(peer("[fd00::1]:443"), &h, &trusted());
+    assert_eq!(client.node, ip("2001:db8::5"));
+}
+
+#[test]
+fn test_forwarded_obfuscated_client() {
+    let h = headers(&[("forwarded", "for=_abc123, for=10.0.0.2")]);
+    let client = resolve(peer("10.0.0.1:443"), &h, &trusted());
+    assert_eq!(
+        client.node,
+        ForwardedNode::Obfuscated("_abc123".to_string())
+    );
+    assert_eq!(client.ip(), None);
+}
+
+#[test]
+fn test_malformed_forwarded_falls_back_to_peer() {
+    let h = headers(&[
+        ("forwarded", "for=\"broken"),
+        ("x-forwarded-for", "198.51.100.7"),
+    ]);
+    let client = resolve(peer("10.0.0.1:443"), &h, &trusted());
+    assert_eq!(client.node, ip("10.0.0.1"));
+}
+
+#[test]
+fn test_x_forwarded_for_fallback() {
+    let h = headers(&[
+        ("x-forwarded-for", "203.0.113.5, 2001:db8::7, 10.0.0.3"),
+        ("x-forwarded-proto", "https"),
+        ("x-forwarded-host", "example.org"),
+    ]);
+    let client = resolve(peer("10.0.0.1:443"), &h, &trusted());
+    assert_eq!(client.node, ip("2001:db8::7"));
+    assert_eq!(client.proto.as_deref(), Some("https"));
+    assert_eq!(client.host.as_deref(), Some("example.org"));
+}
+
+#[test]
+fn test_ipv4_mapped_peer_is_trusted() {
+    let h = headers(&[("x-forwarded-for", "198.51.100.7")]);
+    let client = resolve(peer("[::ffff:10.0.0.1]:443"), &h, &trusted());
+    assert_eq!(client.node, ip("198.51.100.7"));
+}
+
+#[test]
+fn test_trusted_proxies_parse() {
+    assert!(!TrustedProxies::parse("")
+        .unwrap()
+        .contains(&"10.0.0.1".parse().unwrap()));
+    assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+    assert!(TrustedProxies::parse("not-an-ip").is_err());
+    let single = TrustedProxies::parse("192.0.2.1").unwrap();
+    assert!(single.contains(&"192.0.2.1".parse().unwrap()));
+    assert!(!single.contains(&"192.0.2.2".parse().unwrap()));
+}
+
+#[actix_rt::test]
+async fn test_extractor_uses_registered_trusted_proxies() {
+    let app =
+        init_service(App::new().app_data(trusted()).route(
+            "/",
+            web::get().to(|client: RealIp| async move {
+                HttpResponse::Ok().body(client.node.to_string())
+            }),
+        ))
+        .await;
+
+    let req = TestRequest::get()
+        .uri("/")
+        .peer_addr("10.9.9.9:1234".parse().unwrap())
+        .insert_header(("Forwarded", "for=\"[2001:db8::1]\""))
+        .to_request();
+    let body = call_and_read_body(&app, req).await;
+    assert_eq!(body, "2001:db8::1");
+}
@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::test::{call_and_read_body, init_service, try_call_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use serde_json::Value;
+
+use main::resource_budget::{
+    enable_counting, thread_allocated, thread_cpu_time, ResourceBudget, ResourceBudgetOverride,
+    Usage,
+};
+
+mod common;
+
+/// Burns `total` of CPU time, yielding every couple of milliseconds.
+async fn spin(total: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < total {
+        let slice = Instant::now();
+        while slice.elapsed() < Duration::from_millis(2) {
+            std::hint::spin_loop();
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Counts handlers dropped before finishing.
+struct Cancelled(Arc<AtomicUsize>, bool);
+
+impl Cancelled {
+    fn finish(mut self) {
+        self.1 = true;
+    }
+}
+
+impl Drop for Cancelled {
+    fn drop(&mut self) {
+        if !self.1 {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+fn cpu(ms: u64) -> ResourceBudget {
+    ResourceBudget {
+        cpu_budget_ms: ms,
+        memory_budget_bytes: 0,
+    }
+}
+
+macro_rules! spinning_app {
+    ($budget:expr, $spin:expr, $cancelled:expr) => {{
+        let cancelled = $cancelled.clone();
+        init_service(App::new().wrap($budget).route(
+            "/work",
+            web::get().to(move || {
+                let guard = Cancelled(cancelled.clone(), false);
+                async move {
+                    spin($spin).await;
+                    guard.finish();
+                    HttpResponse::Ok().body("done")
+                }
+            }),
+        ))
+        .await
+    }};
+}
+
+#[actix_rt::test]
+async fn cpu_hog_is_cancelled_with_503() {
+    let cancelled = Arc::new(AtomicUsize::new(0));
+    let app = spinning_app!(cpu(20), Duration::from_secs(2), cancelled);
+    let started = Instant::now();
+    let req = TestRequest::get().uri("/work").to_request();
+    let err = try_call_service(&app, req).await.unwrap_err();
+
+    let res = err.error_response();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value =
+        serde_json::from_slice(&actix_web::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"], "resource_budget_exceeded");
+    assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[actix_rt::test]
+async fn requests_within_budget_complete() {
+    let cancelled = Arc::new(AtomicUsize::new(0));
+    let app = spinning_app!(cpu(500), Duration::from_millis(10), cancelled);
+    let req = TestRequest::get().uri("/work").to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "done");
+    assert_eq!(cancelled.load(Ordering::SeqCst), 0);
+}
+
+#[actix_rt::test]
+async fn memory_hog_is_cancelled() {
+    let budget = ResourceBudget {
+        cpu_budget_ms: 0,
+        memory_budget_bytes: 4 * 1024 * 1024,
+    };
+    let app = init_service(App::new().wrap(budget).route(
+        "/hoard",
+        web::get().to(|| async {
+            let mut hoard: Vec<Vec<u8>> = Vec::new();
+            for _ in 0..64 {
+                hoard.push(vec![1; 1024 * 1024]);
+                tokio::task::yield_now().await;
+            }
+            HttpResponse::Ok().body(hoard.len().to_string())
+        }),
+    ))
+    .await;
+    let req = TestRequest::get().uri("/hoard").to_request();
+    let err = try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(
+        err.as_response_error().status_code(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[actix_rt::test]
+async fn routes_can_override_the_budget() {
+    let cancelled = Arc::new(AtomicUsize::new(0));
+    let handler_cancelled = cancelled.clone();
+    let app = init_service(
+        App::new().wrap(cpu(20)).service(
+            web::resource("/report")
+                .wrap(ResourceBudgetOverride::new(cpu(2000)))
+                .route(web::get().to(move || {
+                    let guard = Cancelled(handler_cancelled.clone(), false);
+                    async move {
+                        spin(Duration::from_millis(60)).await;
+                        guard.finish();
+                        HttpResponse::Ok().body("report")
+                    }
+                })),
+        ),
+    )
+    .await;
+    let req = TestRequest::get().uri("/report").to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "report");
+    assert_eq!(cancelled.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn usage_is_checked_against_each_budget() {
+    let budget = ResourceBudget {
+        cpu_budget_ms: 10,
+        memory_budget_bytes: 1000,
+    };
+    let usage = |cpu_ms, memory| Usage {
+        cpu: Duration::from_millis(cpu_ms),
+        memory,
+    };
+    assert_eq!(budget.exceeded_by(&usage(5, 500)), None);
+    assert_eq!(budget.exceeded_by(&usage(11, 500)), Some("cpu"));
+    assert_eq!(budget.exceeded_by(&usage(5, 1001)), Some("memory"));
+    assert_eq!(
+        ResourceBudget::default().exceeded_by(&usage(1000, 1 << 30)),
+        None
+    );
+    assert!(!ResourceBudget::default().is_limited());
+}
+
+#[test]
+fn allocations_and_cpu_time_are_measured_per_thread() {
+    enable_counting();
+    let before = thread_allocated();
+    let block = vec![0u8; 1 << 20];
+    assert!(thread_allocated() - before >= 1 << 20);
+    drop(block);
+    assert!(thread_allocated() - before < 1 << 20);
+
+    if let Some(start) = thread_cpu_time() {
+        let wall = Instant::now();
+        while wall.elapsed() < Duration::from_millis(20) {
+            std::hint::spin_loop();
+        }
+        assert!(thread_cpu_time().unwrap() - start >= Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn invalid_budgets_are_rejected() {
+    let _env = common::env_lock();
+    std::env::set_var("RESOURCE_CPU_BUDGET_MS", "250");
+    std::env::set_var("RESOURCE_MEMORY_BUDGET_BYTES", "1048576");
+    assert_eq!(
+        ResourceBudget::from_env().unwrap(),
+        ResourceBudget {
+            cpu_budget_ms: 250,
+            memory_budget_bytes: 1 << 20,
+        }
+    );
+
+    for (name, value) in [
+        ("RESOURCE_CPU_BUDGET_MS", "-1"),
+        ("RESOURCE_CPU_BUDGET_MS", "1s"),
+        ("RESOURCE_MEMORY_BUDGET_BYTES", "1GB"),
+    ] {
+        std::env::set_var(name, value);
+        let err = ResourceBudget::from_env().unwrap_err();
+        assert!(err.to_string().contains(name), "{}", err);
+        std::env::set_var(name, "0");
+    }
+    std::env::remove_var("RESOURCE_CPU_BUDGET_MS");
+    std::env::remove_var("RESOURCE_MEMORY_BUDGET_BYTES");
+    assert!(!ResourceBudget::from_env().unwrap().is_limited());
+}
@@ -0,0 +1,265 @@
+#![cfg(target_os = "linux")]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::{Certificate, ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+
+use main::restart::{listener_from_inherited_fd, parse_fds};
+
+mod common;
+
+use common::{free_port, TestPki};
+
+const ADMIN_KEY: &str = "restart-test-admin-key-0123";
+
+fn wait_for_exit(child: &mut Child, timeout: Duration) -> std::process::ExitStatus {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().expect("Failed to poll server") {
+            return status;
+        }
+        if Instant::now() > deadline {
+            child.kill().ok();
+            panic!("Server did not exit within {:?}", timeout);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn signal(pid: u32, signal: &str) -> bool {
+    Command::new("kill")
+        .args([signal, &pid.to_string()])
+        .status()
+        .expect("Failed to run kill")
+        .success()
+}
+
+/// Whether `pid` is running; the new process is not our child, and nobody
+/// may reap it once it exits.
+fn running(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit(") ")
+            .next()
+            .is_some_and(|rest| !rest.starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn listener_fds_are_parsed() {
+    assert_eq!(parse_fds("3"), Some(vec![3]));
+    assert_eq!(parse_fds("7, 8,"), Some(vec![7, 8]));
+    assert_eq!(parse_fds(""), Some(vec![]));
+    assert_eq!(parse_fds("3,x"), None);
+    assert_eq!(parse_fds("-1"), None);
+}
+
+#[test]
+fn inherited_fd_becomes_the_same_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fd = listener.into_raw_fd();
+
+    // SAFETY: the descriptor was just released by its only owner
+    let listener = unsafe { listener_from_inherited_fd(fd) };
+    assert_eq!(listener.local_addr().unwrap(), addr);
+    let _client = TcpStream::connect(addr).unwrap();
+    let (_accepted, peer) = listener.accept().unwrap();
+    assert!(peer.ip().is_loopback());
+}
+
+/// Sends requests over TLS, directly or preceded by a PROXY header as a
+/// load balancer would.
+#[derive(Clone)]
+enum Client {
+    Direct(reqwest::Client),
+    Proxied(Arc<ClientConfig>),
+}
+
+impl Client {
+    fn new(pki: &TestPki, proxy_protocol: bool) -> Self {
+        if !proxy_protocol {
+            // A new connection per request, so every request goes through accept
+            return Client::Direct(
+                pki.client_builder()
+                    .pool_max_idle_per_host(0)
+                    .build()
+                    .unwrap(),
+            );
+        }
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(pki.ca().serialize_der().unwrap()))
+            .unwrap();
+        Client::Proxied(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ))
+    }
+
+    /// Returns the status and body of `GET path`.
+    async fn get(&self, port: u16, path: &str) -> Result<(u16, String), String> {
+        match self {
+            Client::Direct(client) => {
+                let resp = client
+                    .get(format!("https://localhost:{}{}", port, path))
+                    .header("X-Api-Key", ADMIN_KEY)
+                    .send()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let status = resp.status().as_u16();
+                Ok((status, resp.text().await.map_err(|e| e.to_string())?))
+            }
+            Client::Proxied(config) => {
+                let (config, path) = (config.clone(), path.to_string());
+                actix_rt::task::spawn_blocking(move || proxied_get(config, port, &path))
+                    .await
+                    .unwrap()
+            }
+        }
+    }
+}
+
+fn proxied_get(config: Arc<ClientConfig>, port: u16, path: &str) -> Result<(u16, String), String> {
+    let mut sock = TcpStream::connect(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    write!(sock, "PROXY TCP4 203.0.113.7 127.0.0.1 40000 {}\r\n", port)
+        .map_err(|e| e.to_string())?;
+    let conn = ClientConnection::new(config, ServerName::try_from("localhost").unwrap())
+        .map_err(|e| e.to_string())?;
+    let mut tls = StreamOwned::new(conn, sock);
+    write!(
+        tls,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nX-Api-Key: {}\r\nConnection: close\r\n\r\n",
+        path, ADMIN_KEY
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match tls.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            // Servers may close without close_notify once the body is sent
+            Err(_) if !response.is_empty() => break,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| format!("incomplete response {:?}", response))?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("no status line in {:?}", head))?;
+    Ok((status, body.to_string()))
+}
+
+#[actix_rt::test]
+async fn sigusr2_restarts_without_dropping_connections() {
+    restart_under_load(false).await;
+}
+
+#[actix_rt::test]
+async fn sigusr2_restarts_behind_proxy_protocol_without_dropping_connections() {
+    restart_under_load(true).await;
+}
+
+async fn restart_under_load(proxy_protocol: bool) {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
+    let port = free_port();
+
+    let mut old = Command::new(env!("CARGO_BIN_EXE_secure-actix-web-server"))
+        .env("CERT_FILE", &cert_path)
+        .env("KEY_FILE", &key_path)
+        .env("SERVER_ADDRESS", format!("127.0.0.1:{}", port))
+        .env("NUM_WORKERS", "2")
+        .env("ADMIN_API_KEY", ADMIN_KEY)
+        .env("DRAIN_TIMEOUT_SECS", "10")
+        .env("PROXY_PROTOCOL", proxy_protocol.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start server");
+
+    let client = Client::new(&pki, proxy_protocol);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.get(port, "/ready").await.is_err() {
+        assert!(Instant::now() < deadline, "Server did not start");
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+    let serving_pid = |client: Client| async move {
+        let (_, body) = client.get(port, "/admin/info").await.unwrap();
+        let info: serde_json::Value = serde_json::from_str(&body).unwrap();
+        info["pid"].as_u64().unwrap() as u32
+    };
+    assert_eq!(serving_pid(client.clone()).await, old.id());
+
+    // Keep requests coming throughout the restart
+    let stop = Arc::new(AtomicBool::new(false));
+    let served = Arc::new(AtomicUsize::new(0));
+    let failures = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut load = Vec::new();
+    for _ in 0..4 {
+        let (client, stop, served, failures) = (
+            client.clone(),
+            stop.clone(),
+            served.clone(),
+            failures.clone(),
+        );
+        load.push(actix_rt::spawn(async move {
+            while !stop.load(Ordering::SeqCst) {
+                match client.get(port, "/hello").await {
+                    Ok((200, _)) => {
+                        served.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Ok((status, _)) => failures.lock().unwrap().push(status.to_string()),
+                    Err(e) => failures.lock().unwrap().push(e),
+                }
+            }
+        }));
+    }
+    actix_rt::time::sleep(Duration::from_millis(300)).await;
+
+    assert!(signal(old.id(), "-USR2"));
+    let old_pid = old.id();
+    let status =
+        actix_rt::task::spawn_blocking(move || wait_for_exit(&mut old, Duration::from_secs(30)))
+            .await
+            .unwrap();
+    assert!(status.success(), "Old process exited with {:?}", status);
+    let served_before = served.load(Ordering::SeqCst);
+
+    // The new process goes on serving on the same socket
+    actix_rt::time::sleep(Duration::from_millis(500)).await;
+    stop.store(true, Ordering::SeqCst);
+    for task in load {
+        task.await.unwrap();
+    }
+    let new_pid = serving_pid(client.clone()).await;
+    assert!(signal(new_pid, "-TERM"));
+
+    assert_ne!(new_pid, old_pid);
+    assert!(served_before > 0);
+    assert!(served.load(Ordering::SeqCst) > served_before);
+    assert_eq!(*failures.lock().unwrap(), Vec::<String>::new());
+
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while running(new_pid) {
+        assert!(Instant::now() < deadline, "New process did not exit");
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+}
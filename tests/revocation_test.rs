@@ -0,0 +1,337 @@
+use actix_web::dev::Service;
+use actix_web::{test, web, App, HttpMessage, HttpResponse};
+use async_trait::async_trait;
+use chrono::{Duration as ChronoDuration, Utc};
+use rcgen::{Certificate as RcgenCertificate, CertificateParams, DnType};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use rustls::Certificate;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use x509_parser::prelude::parse_x509_certificate;
+
+use main::middleware::revocation::RevocationCheck;
+use main::outbound::UrlPolicy;
+use main::revocation::crl::CrlChecker;
+use main::revocation::ocsp::OcspChecker;
+use main::revocation::{RevocationChecker, RevocationStatus};
+use main::tls_info::PeerCertificate;
+
+mod common;
+
+use common::TestPki;
+
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        out.push(0x82);
+        out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn seq(parts: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &parts.concat())
+}
+
+/// Signs `message` with the test CA key, returning the signature bit string.
+fn sign(pki: &TestPki, message: &[u8]) -> Vec<u8> {
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_ASN1_SIGNING,
+        &pki.ca().serialize_private_key_der(),
+        &rng,
+    )
+    .unwrap();
+    let signature = key.sign(&rng, message).unwrap();
+    let mut bits = vec![0];
+    bits.extend_from_slice(signature.as_ref());
+    der(0x03, &bits)
+}
+
+fn ca_der(pki: &TestPki) -> Vec<u8> {
+    pki.ca().serialize_der().unwrap()
+}
+
+fn ca_subject(pki: &TestPki) -> Vec<u8> {
+    let der = ca_der(pki);
+    let (_, ca) = parse_x509_certificate(&der).unwrap();
+    ca.subject().as_raw().to_vec()
+}
+
+/// Issues a client certificate, returning its DER and raw serial number.
+fn client_cert(pki: &TestPki, name: &str) -> (Certificate, Vec<u8>) {
+    let mut params = CertificateParams::new(vec![format!("{}.test", name)]);
+    params.distinguished_name.push(DnType::CommonName, name);
+    let cert = RcgenCertificate::from_params(params).unwrap();
+    let der = cert.serialize_der_with_signer(pki.ca()).unwrap();
+    let serial = parse_x509_certificate(&der)
+        .unwrap()
+        .1
+        .raw_serial()
+        .to_vec();
+    (Certificate(der), serial)
+}
+
+fn utc_time(offset: ChronoDuration) -> Vec<u8> {
+    der(
+        0x17,
+        (Utc::now() + offset)
+            .format("%y%m%d%H%M%SZ")
+            .to_string()
+            .as_bytes(),
+    )
+}
+
+fn generalized_time(offset: ChronoDuration) -> Vec<u8> {
+    der(
+        0x18,
+        (Utc::now() + offset)
+            .format("%Y%m%d%H%M%SZ")
+            .to_string()
+            .as_bytes(),
+    )
+}
+
+/// Builds a CRL from the test CA revoking `serials`.
+fn build_crl(pki: &TestPki, serials: &[&[u8]]) -> Vec<u8> {
+    let algorithm = seq(&[der(0x06, ECDSA_WITH_SHA256)]);
+    let revoked: Vec<Vec<u8>> = serials
+        .iter()
+        .map(|serial| seq(&[der(0x02, serial), utc_time(ChronoDuration::hours(-1))]))
+        .collect();
+    let tbs = seq(&[
+        der(0x02, &[1]),
+        algorithm.clone(),
+        ca_subject(pki),
+        utc_time(ChronoDuration::hours(-1)),
+        utc_time(ChronoDuration::days(1)),
+        seq(&revoked),
+    ]);
+    let signature = sign(pki, &tbs);
+    seq(&[tbs, algorithm, signature])
+}
+
+/// Builds an OCSP response from the test CA for `serial`.
+fn build_ocsp_response(pki: &TestPki, serial: &[u8], revoked: bool) -> Vec<u8> {
+    let cert_id = seq(&[
+        seq(&[der(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a]), der(0x05, &[])]),
+        der(0x04, &[0; 20]),
+        der(0x04, &[0; 20]),
+        der(0x02, serial),
+    ]);
+    let status = if revoked {
+        der(0xa1, &generalized_time(ChronoDuration::hours(-1)))
+    } else {
+        der(0x80, &[])
+    };
+    let single = seq(&[
+        cert_id,
+        status,
+        generalized_time(ChronoDuration::minutes(-5)),
+        der(0xa0, &generalized_time(ChronoDuration::hours(1))),
+    ]);
+    let tbs = seq(&[
+        der(0xa1, &ca_subject(pki)),
+        generalized_time(ChronoDuration::zero()),
+        seq(&[single]),
+    ]);
+    let signature = sign(pki, &tbs);
+    let basic = seq(&[tbs, seq(&[der(0x06, ECDSA_WITH_SHA256)]), signature]);
+    seq(&[
+        der(0x0a, &[0]),
+        der(0xa0, &seq(&[der(0x06, OCSP_BASIC), der(0x04, &basic)])),
+    ])
+}
+
+/// Serves `body` as the response to every HTTP request, returning the URL.
+fn mock_responder(body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/ocsp-response\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(header.as_bytes());
+            let _ = stream.write_all(&body);
+        }
+    });
+    url
+}
+
+#[actix_rt::test]
+async fn test_crl_checker_detects_revoked_serial() {
+    let pki = TestPki::generate();
+    let (revoked, revoked_serial) = client_cert(&pki, "revoked");
+    let (good, _) = client_cert(&pki, "good");
+
+    let checker = CrlChecker::from_der(&[build_crl(&pki, &[&revoked_serial])]).unwrap();
+    assert_eq!(checker.check(&[revoked]).await, RevocationStatus::Revoked);
+    assert_eq!(checker.check(&[good]).await, RevocationStatus::Good);
+}
+
+#[actix_rt::test]
+async fn test_crl_checker_unknown_issuer() {
+    let pki = TestPki::generate();
+    // CRLs are matched to certificates by issuer name
+    let other = TestPki::generate_named("Other CA");
+    let (cert, _) = client_cert(&other, "stranger");
+
+    let checker = CrlChecker::from_der(&[build_crl(&pki, &[])]).unwrap();
+    assert_eq!(checker.check(&[cert]).await, RevocationStatus::Unknown);
+}
+
+#[actix_rt::test]
+async fn test_crl_checker_reloads_files() {
+    let pki = TestPki::generate();
+    let (cert, serial) = client_cert(&pki, "later-revoked");
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ca.crl");
+
+    std::fs::write(&path, build_crl(&pki, &[])).unwrap();
+    let checker = CrlChecker::from_files(vec![path.to_str().unwrap().to_string()]).unwrap();
+    assert_eq!(
+        checker.check(std::slice::from_ref(&cert)).await,
+        RevocationStatus::Good
+    );
+
+    std::fs::write(&path, build_crl(&pki, &[&serial])).unwrap();
+    checker.reload().unwrap();
+    assert_eq!(checker.check(&[cert]).await, RevocationStatus::Revoked);
+
+    // A broken file keeps the previous CRLs
+    std::fs::write(&path, b"not a crl").unwrap();
+    assert!(checker.reload().is_err());
+}
+
+#[actix_rt::test]
+async fn test_ocsp_checker_good_and_revoked() {
+    let pki = TestPki::generate();
+    let issuers = vec![Certificate(ca_der(&pki))];
+
+    let (good, good_serial) = client_cert(&pki, "good");
+    let url = mock_responder(build_ocsp_response(&pki, &good_serial, false));
+    let checker = OcspChecker::new(issuers.clone(), Some(url), UrlPolicy::default());
+    assert_eq!(checker.check(&[good]).await, RevocationStatus::Good);
+
+    let (revoked, revoked_serial) = client_cert(&pki, "revoked");
+    let url = mock_responder(build_ocsp_response(&pki, &revoked_serial, true));
+    let checker = OcspChecker::new(issuers, Some(url), UrlPolicy::default());
+    assert_eq!(checker.check(&[revoked]).await, RevocationStatus::Revoked);
+}
+
+#[actix_rt::test]
+async fn test_ocsp_checker_rejects_forged_response() {
+    let pki = TestPki::generate();
+    let forger = TestPki::generate();
+    let (cert, serial) = client_cert(&pki, "victim");
+
+    // Signed by an unrelated CA, so the response must not be trusted
+    let url = mock_responder(build_ocsp_response(&forger, &serial, false));
+    let checker = OcspChecker::new(
+        vec![Certificate(ca_der(&pki))],
+        Some(url),
+        UrlPolicy::default(),
+    );
+    assert_eq!(checker.check(&[cert]).await, RevocationStatus::Unknown);
+}
+
+#[actix_rt::test]
+async fn test_ocsp_checker_unreachable_responder() {
+    let pki = TestPki::generate();
+    let (cert, _) = client_cert(&pki, "client");
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let checker = OcspChecker::new(
+        vec![Certificate(ca_der(&pki))],
+        Some(format!("http://127.0.0.1:{}/", port)),
+        UrlPolicy::default(),
+    );
+    assert_eq!(checker.check(&[cert]).await, RevocationStatus::Unknown);
+}
+
+/// Checker returning a fixed status.
+struct MockChecker(RevocationStatus);
+
+#[async_trait]
+impl RevocationChecker for MockChecker {
+    async fn check(&self, _: &[Certificate]) -> RevocationStatus {
+        self.0
+    }
+}
+
+async fn call_with_status(status: RevocationStatus, fail_open: bool) -> (u16, Option<Value>) {
+    let app = test::init_service(
+        App::new()
+            .wrap(RevocationCheck::new(
+                Some(Arc::new(MockChecker(status))),
+                fail_open,
+            ))
+            .wrap_fn(|req, srv| {
+                req.extensions_mut()
+                    .insert(PeerCertificate(vec![Certificate(vec![0x30, 0x00])]));
+                srv.call(req)
+            })
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    let status = resp.status().as_u16();
+    if status == 200 {
+        (status, None)
+    } else {
+        (status, Some(test::read_body_json(resp).await))
+    }
+}
+
+#[actix_rt::test]
+async fn test_middleware_rejects_revoked_certificate() {
+    let (status, body) = call_with_status(RevocationStatus::Revoked, true).await;
+    assert_eq!(status, 401);
+    assert_eq!(body.unwrap()["error"], "certificate_revoked");
+
+    let (status, _) = call_with_status(RevocationStatus::Good, false).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_rt::test]
+async fn test_middleware_unknown_status_policy() {
+    let (status, body) = call_with_status(RevocationStatus::Unknown, false).await;
+    assert_eq!(status, 401);
+    assert_eq!(body.unwrap()["error"], "certificate_status_unknown");
+
+    let (status, _) = call_with_status(RevocationStatus::Unknown, true).await;
+    assert_eq!(status, 200);
+}
+
+#[actix_rt::test]
+async fn test_middleware_ignores_requests_without_certificate() {
+    let app = test::init_service(
+        App::new()
+            .wrap(RevocationCheck::new(
+                Some(Arc::new(MockChecker(RevocationStatus::Revoked))),
+                false,
+            ))
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(resp.status(), 200);
+}
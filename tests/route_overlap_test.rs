@@ -0,0 +1,152 @@
+use actix_web::http::Method;
+use actix_web::test::{call_and_read_body, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+
+use main::route_meta::{DescribedRoute, RouteMetadata, ScopeMetadata};
+use main::route_overlap::{self, OverlapKind};
+use main::routes::{HealthModule, HelloModule, ModuleRegistry, RouteModule};
+
+mod common;
+
+use common::logs;
+
+/// Static files mounted at the root.
+struct RootFilesModule;
+
+impl RouteModule for RootFilesModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        let routes = ScopeMetadata::new("").configure(|cfg| {
+            cfg.described_route(
+                Method::GET,
+                "/{path:.*}",
+                || async { HttpResponse::Ok().body("file") },
+                RouteMetadata::new("Static file"),
+            );
+        });
+        cfg.service(web::scope("").configure(routes));
+    }
+}
+
+/// Routes registered on their own, in order.
+struct RoutesModule(Vec<&'static str>);
+
+impl RouteModule for RoutesModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        for path in &self.0 {
+            cfg.described_route(
+                Method::GET,
+                path,
+                HttpResponse::Ok,
+                RouteMetadata::new("Test route"),
+            );
+        }
+    }
+}
+
+/// `/admin` with a route inside.
+struct AdminScopeModule;
+
+impl RouteModule for AdminScopeModule {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        let routes = ScopeMetadata::new("/admin").configure(|cfg| {
+            cfg.described_route(
+                Method::GET,
+                "/status",
+                HttpResponse::Ok,
+                RouteMetadata::new("Status"),
+            );
+        });
+        cfg.service(web::scope("/admin").configure(routes));
+    }
+}
+
+#[test]
+fn registered_modules_do_not_overlap() {
+    let registry = ModuleRegistry::new()
+        .with_module(HealthModule)
+        .with_module(AdminScopeModule)
+        .with_module(HelloModule)
+        .with_module(RootFilesModule);
+    assert!(route_overlap::find(&registry.mounts()).is_empty());
+}
+
+#[actix_web::test]
+async fn root_mount_shadows_later_routes() {
+    let registry = ModuleRegistry::new()
+        .with_module(RootFilesModule)
+        .with_module(HelloModule);
+    let overlaps = route_overlap::find(&registry.mounts());
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].kind, OverlapKind::Shadowed);
+    assert_eq!(overlaps[0].mount, "GET /hello");
+    assert_eq!(overlaps[0].by, "scope /");
+
+    // The finding is real: the static mount answers /hello
+    let app = init_service(App::new().configure(|cfg| registry.configure(cfg))).await;
+    let req = TestRequest::get().uri("/hello").to_request();
+    assert_eq!(call_and_read_body(&app, req).await, "file");
+}
+
+#[test]
+fn scopes_shadow_later_routes_below_their_prefix() {
+    let registry = ModuleRegistry::new()
+        .with_module(AdminScopeModule)
+        .with_module(RoutesModule(vec![
+            "/admin/extra",
+            "/admin",
+            "/administrator",
+            "/{page}",
+        ]));
+    let overlaps: Vec<String> = route_overlap::find(&registry.mounts())
+        .iter()
+        .map(|o| o.mount.clone())
+        .collect();
+    // A parameter only partly overlaps the scope, so it is still reached
+    assert_eq!(overlaps, ["GET /admin/extra", "GET /admin"]);
+}
+
+#[test]
+fn tail_patterns_shadow_paths_below_them() {
+    let registry = ModuleRegistry::new().with_module(RoutesModule(vec![
+        "/files/{path:.*}",
+        "/files",
+        "/files/readme",
+        "/files/{id}/raw",
+    ]));
+    let overlaps = route_overlap::find(&registry.mounts());
+    let shadowed: Vec<&str> = overlaps.iter().map(|o| o.mount.as_str()).collect();
+    assert_eq!(shadowed, ["GET /files/readme", "GET /files/{id}/raw"]);
+    assert!(overlaps.iter().all(|o| o.by == "GET /files/{path:.*}"));
+}
+
+#[test]
+fn duplicate_routes_are_reported() {
+    let registry = ModuleRegistry::new()
+        .with_module(HelloModule)
+        .with_module(RoutesModule(vec!["/hello"]));
+    let overlaps = route_overlap::find(&registry.mounts());
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].kind, OverlapKind::Duplicate);
+    assert!(overlaps[0]
+        .to_string()
+        .contains("GET /hello is registered twice"));
+}
+
+#[test]
+fn strict_mode_fails_instead_of_warning() {
+    logs::capture();
+    let mounts = ModuleRegistry::new()
+        .with_module(RootFilesModule)
+        .with_module(RoutesModule(vec!["/shadowed-by-root"]))
+        .mounts();
+
+    assert!(route_overlap::check(&mounts, false).is_ok());
+    assert!(logs::contains(
+        "GET /shadowed-by-root is never reached: scope / is registered first"
+    ));
+
+    let err = route_overlap::check(&mounts, true).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("STRICT_ROUTES: GET /shadowed-by-root"));
+}
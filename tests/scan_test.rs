@@ -0,0 +1,180 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::web::Bytes;
+use actix_web::{web, App, HttpResponse};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use main::audit::{AuditLog, AuditTrail, Outcome};
+use main::error::ApiError;
+use main::scan::{ContentScanner, NoopScanner, ScanVerdict, UnavailablePolicy, UploadScanning};
+
+mod common;
+
+/// Finds `EICAR` in files, and fails on files named `unscannable`.
+struct StubScanner;
+
+#[async_trait]
+impl ContentScanner for StubScanner {
+    async fn scan(&self, path: &Path) -> ScanVerdict {
+        if path.ends_with("unscannable") {
+            return ScanVerdict::Unavailable("scanner down".to_string());
+        }
+        match fs::read(path) {
+            Ok(data) if data.windows(5).any(|w| w == b"EICAR") => {
+                ScanVerdict::Infected("Eicar-Test-Signature".to_string())
+            }
+            Ok(_) => ScanVerdict::Clean,
+            Err(e) => ScanVerdict::Unavailable(e.to_string()),
+        }
+    }
+}
+
+/// Stores the body as `{dir}/{name}` and scans it, as an upload handler
+/// would.
+async fn upload(
+    name: web::Path<String>,
+    body: Bytes,
+    dir: web::Data<PathBuf>,
+    scanning: web::Data<UploadScanning>,
+    audit: AuditTrail,
+) -> Result<HttpResponse, ApiError> {
+    let path = dir.join(name.into_inner());
+    fs::write(&path, &body).unwrap();
+    scanning.check(&path, &audit).await?;
+    Ok(HttpResponse::Created().finish())
+}
+
+macro_rules! scan_app {
+    ($dir:expr, $scanning:expr, $log:expr) => {
+        init_service(
+            App::new()
+                .app_data(web::Data::new($dir.to_path_buf()))
+                .app_data(web::Data::new($scanning))
+                .app_data($log.clone())
+                .route("/upload/{name}", web::put().to(upload)),
+        )
+        .await
+    };
+}
+
+fn put(name: &str, body: &'static str) -> actix_http::Request {
+    TestRequest::put()
+        .uri(&format!("/upload/{}", name))
+        .set_payload(body)
+        .to_request()
+}
+
+#[actix_rt::test]
+async fn infected_uploads_are_deleted_and_audited() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = web::Data::new(AuditLog::default());
+    let app = scan_app!(dir.path(), UploadScanning::new(Arc::new(StubScanner)), log);
+
+    let resp = call_service(&app, put("report.txt", "quarterly numbers")).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.path().join("report.txt").exists());
+
+    let resp = call_service(&app, put("payload.com", "X5O!P%@AP EICAR-STANDARD")).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "upload_infected");
+    assert!(!dir.path().join("payload.com").exists());
+
+    let events = log.query(None, Some("upload"));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].action, "upload_rejected");
+    assert_eq!(events[0].outcome, Outcome::Failure);
+    assert_eq!(events[0].detail["file"], "payload.com");
+    assert_eq!(events[0].detail["signature"], "Eicar-Test-Signature");
+}
+
+#[actix_rt::test]
+async fn unscannable_uploads_are_quarantined_by_default() {
+    let dir = tempfile::tempdir().unwrap();
+    let quarantine = dir.path().join("quarantine");
+    let log = web::Data::new(AuditLog::default());
+    let app = scan_app!(
+        dir.path(),
+        UploadScanning::new(Arc::new(StubScanner)).quarantine_dir(&quarantine),
+        log
+    );
+
+    let resp = call_service(&app, put("unscannable", "data")).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "scanner_unavailable");
+    assert!(!dir.path().join("unscannable").exists());
+    let quarantined: Vec<_> = fs::read_dir(&quarantine).unwrap().collect();
+    assert_eq!(quarantined.len(), 1);
+
+    let events = log.query(None, Some("upload_quarantined"));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].detail["reason"], "scanner down");
+    assert_eq!(
+        events[0].detail["quarantined_as"],
+        quarantined[0]
+            .as_ref()
+            .unwrap()
+            .path()
+            .display()
+            .to_string()
+    );
+}
+
+#[actix_rt::test]
+async fn unscannable_uploads_can_be_allowed_with_a_warning() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = web::Data::new(AuditLog::default());
+    let app = scan_app!(
+        dir.path(),
+        UploadScanning::new(Arc::new(StubScanner)).on_unavailable(UnavailablePolicy::Allow),
+        log
+    );
+
+    let resp = call_service(&app, put("unscannable", "data")).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert!(dir.path().join("unscannable").exists());
+    let events = log.query(None, Some("upload_unscanned"));
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].outcome, Outcome::Success);
+}
+
+#[actix_rt::test]
+async fn noop_scanner_passes_everything() {
+    assert_eq!(
+        NoopScanner.scan(Path::new("/nonexistent")).await,
+        ScanVerdict::Clean
+    );
+}
+
+#[test]
+fn scanner_settings_are_validated() {
+    let _env = common::env_lock();
+    for name in [
+        "CONTENT_SCANNER",
+        "SCAN_UNAVAILABLE_POLICY",
+        "SCAN_QUARANTINE_DIR",
+    ] {
+        env::remove_var(name);
+    }
+    assert!(UploadScanning::from_env().is_ok());
+    env::set_var("SCAN_UNAVAILABLE_POLICY", "ignore");
+    assert!(UploadScanning::from_env().is_err());
+    env::set_var("SCAN_UNAVAILABLE_POLICY", "Allow");
+    assert!(UploadScanning::from_env().is_ok());
+    env::set_var("CONTENT_SCANNER", "sophos");
+    assert!(UploadScanning::from_env().is_err());
+    #[cfg(not(feature = "clamav"))]
+    {
+        env::set_var("CONTENT_SCANNER", "clamav");
+        let err = UploadScanning::from_env().err().unwrap();
+        assert!(err.to_string().contains("`clamav` feature"));
+    }
+    env::remove_var("CONTENT_SCANNER");
+    env::remove_var("SCAN_UNAVAILABLE_POLICY");
+}
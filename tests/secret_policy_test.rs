@@ -0,0 +1,169 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::env;
+use std::io::ErrorKind;
+
+use main::auth::file::hash_password;
+use main::auth::secret_policy::{
+    self, estimate_entropy_bits, SecretPolicy, Strictness, DEFAULT_MIN_KEY_ENTROPY_BITS,
+};
+use main::middleware::api_key::AdminKey;
+
+mod common;
+
+use common::logs;
+
+const STRONG_KEY: &str = "k3Jv9QzX2mWp7LtR8nYc";
+
+fn hash_with(algorithm: Algorithm, memory_kib: u32, iterations: u32) -> String {
+    let params = Params::new(memory_kib, iterations, 1, None).unwrap();
+    Argon2::new(algorithm, Version::V0x13, params)
+        .hash_password(b"correct horse", &SaltString::generate(&mut OsRng))
+        .unwrap()
+        .to_string()
+}
+
+fn clear_env() {
+    for name in [
+        "ADMIN_SECRET_POLICY",
+        "ADMIN_KEY_MIN_ENTROPY_BITS",
+        "ADMIN_HASH_MIN_MEMORY_KIB",
+        "ADMIN_HASH_MIN_ITERATIONS",
+        "ADMIN_PASSWORD",
+        "AUTH_BACKEND",
+        "AUTH_USERS_FILE",
+        "APP_ENV",
+    ] {
+        env::remove_var(name);
+    }
+}
+
+#[test]
+fn weak_keys_are_detected() {
+    let policy = SecretPolicy::default();
+    assert_eq!(policy.check_key(STRONG_KEY), None);
+    assert_eq!(policy.check_key("3f9a0c7e1b5d8264"), None);
+
+    let weakness = policy.check_key("3f9a0c7e").unwrap();
+    assert!(weakness.contains("below the minimum of 64"), "{}", weakness);
+    // Repeated and sequential characters add almost nothing
+    assert!(estimate_entropy_bits(&"a".repeat(40)) < 5.0);
+    assert!(policy.check_key("abcdefghijklmnopqrstuvwxyz").is_some());
+    assert!(policy
+        .check_key("ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789")
+        .is_some());
+    // Common words make any key weak
+    assert_eq!(
+        policy.check_key("Xy7#kP2qL9vR-ChangeMe").unwrap(),
+        "contains the common word 'changeme'"
+    );
+    assert_eq!(estimate_entropy_bits(""), 0.0);
+}
+
+#[test]
+fn hashes_below_the_minimum_cost_are_detected() {
+    let policy = SecretPolicy::default();
+    assert_eq!(policy.check_hash(&hash_password("correct horse")), None);
+
+    let weakness = policy
+        .check_hash(&hash_with(Algorithm::Argon2id, 1024, 2))
+        .unwrap();
+    assert_eq!(
+        weakness,
+        "uses 1024 KiB of memory, below the minimum of 19456"
+    );
+    let weakness = policy
+        .check_hash(&hash_with(Algorithm::Argon2id, 19456, 1))
+        .unwrap();
+    assert_eq!(weakness, "uses 1 iterations, below the minimum of 2");
+    let weakness = policy
+        .check_hash(&hash_with(Algorithm::Argon2i, 19456, 2))
+        .unwrap();
+    assert_eq!(weakness, "uses argon2i rather than argon2id");
+
+    let users = format!(
+        "# comment\nalice:{}:admin\nbob:{}:\n",
+        hash_password("correct horse"),
+        hash_with(Algorithm::Argon2id, 1024, 2)
+    );
+    assert_eq!(
+        policy.check_users(&users),
+        ["the password hash of user 'bob' uses 1024 KiB of memory, below the minimum of 19456"]
+    );
+}
+
+#[test]
+fn warn_logs_weak_secrets_without_revealing_them() {
+    let _env = common::env_lock();
+    clear_env();
+    logs::capture();
+    let dir = tempfile::tempdir().unwrap();
+    let users = dir.path().join("users");
+    std::fs::write(
+        &users,
+        format!("bob:{}:\n", hash_with(Algorithm::Argon2id, 1024, 2)),
+    )
+    .unwrap();
+    env::set_var("AUTH_USERS_FILE", &users);
+    env::set_var("ADMIN_PASSWORD", "hunter2hunter2");
+
+    let policy = SecretPolicy::from_env().unwrap();
+    assert_eq!(policy.strictness, Strictness::Warn);
+    let weaknesses = policy.check_env(&AdminKey::new(Some("shortkey".to_string())));
+    assert_eq!(weaknesses.len(), 3, "{:?}", weaknesses);
+    assert!(weaknesses[0].starts_with("the admin API key has an estimated"));
+    assert!(weaknesses[1].starts_with("ADMIN_PASSWORD holds a plaintext password"));
+    assert!(weaknesses[2].starts_with("the password hash of user 'bob'"));
+
+    secret_policy::check_from_env(&AdminKey::new(Some("shortkey".to_string()))).unwrap();
+    assert!(logs::contains("Weak admin secret: the admin API key"));
+    assert!(logs::contains("Weak admin secret: ADMIN_PASSWORD holds"));
+    assert!(!logs::contains("shortkey"));
+    assert!(!logs::contains("hunter2"));
+
+    // A hash in ADMIN_PASSWORD is checked instead
+    env::set_var("ADMIN_PASSWORD", hash_password("correct horse"));
+    env::remove_var("AUTH_USERS_FILE");
+    let weaknesses = policy.check_env(&AdminKey::new(Some(STRONG_KEY.to_string())));
+    assert!(weaknesses.is_empty(), "{:?}", weaknesses);
+    clear_env();
+}
+
+#[test]
+fn strict_refuses_weak_secrets() {
+    let _env = common::env_lock();
+    clear_env();
+    env::set_var("ADMIN_SECRET_POLICY", "strict");
+    let err = secret_policy::check_from_env(&AdminKey::new(Some("password1234".to_string())))
+        .expect_err("weak key accepted");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(err
+        .to_string()
+        .contains("the admin API key contains the common word 'password'"));
+    secret_policy::check_from_env(&AdminKey::new(Some(STRONG_KEY.to_string()))).unwrap();
+    // No key at all is left to the API key check
+    secret_policy::check_from_env(&AdminKey::new(None)).unwrap();
+
+    // Production is strict unless told otherwise
+    env::remove_var("ADMIN_SECRET_POLICY");
+    env::set_var("APP_ENV", "production");
+    assert_eq!(
+        SecretPolicy::from_env().unwrap().strictness,
+        Strictness::Strict
+    );
+    env::set_var("ADMIN_SECRET_POLICY", "off");
+    secret_policy::check_from_env(&AdminKey::new(Some("password".to_string()))).unwrap();
+
+    env::set_var("ADMIN_SECRET_POLICY", "lenient");
+    assert!(SecretPolicy::from_env().is_err());
+    env::set_var("ADMIN_SECRET_POLICY", "warn");
+    env::set_var("ADMIN_KEY_MIN_ENTROPY_BITS", "128");
+    let policy = SecretPolicy::from_env().unwrap();
+    assert_ne!(policy.min_key_entropy_bits, DEFAULT_MIN_KEY_ENTROPY_BITS);
+    assert!(policy.check_key(STRONG_KEY).is_some());
+    assert!(policy.check_key(&STRONG_KEY.repeat(2)).is_none());
+    env::set_var("ADMIN_HASH_MIN_ITERATIONS", "many");
+    assert!(SecretPolicy::from_env().is_err());
+    clear_env();
+}
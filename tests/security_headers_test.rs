@@ -0,0 +1,167 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use std::collections::HashMap;
+
+use main::error::ApiError;
+use main::middleware::security_headers::{
+    HstsMaxAgeStrategy, SecurityHeadersBuilder, DEFAULT_HSTS_MAX_AGE_CAP,
+};
+use main::util::echo::{safe_json, safe_text, TEXT_PLAIN};
+
+const NOW: i64 = 1_700_000_000;
+const DAY: i64 = 86_400;
+
+#[test]
+fn test_fixed_max_age() {
+    let strategy = HstsMaxAgeStrategy::Fixed(300);
+    assert_eq!(strategy.max_age(None, NOW, DEFAULT_HSTS_MAX_AGE_CAP), 300);
+    assert_eq!(
+        strategy.max_age(Some(NOW + DAY), NOW, DEFAULT_HSTS_MAX_AGE_CAP),
+        300
+    );
+}
+
+#[test]
+fn test_cert_expiry_max_age() {
+    let strategy = HstsMaxAgeStrategy::CertExpiry {
+        margin_secs: DAY as u32,
+    };
+    let cap = DEFAULT_HSTS_MAX_AGE_CAP;
+
+    // 30 days left: 29 days after the margin
+    assert_eq!(
+        strategy.max_age(Some(NOW + 30 * DAY), NOW, cap),
+        (29 * DAY) as u32
+    );
+    // Inside the margin, or already expired
+    assert_eq!(strategy.max_age(Some(NOW + DAY / 2), NOW, cap), 0);
+    assert_eq!(strategy.max_age(Some(NOW - DAY), NOW, cap), 0);
+    // Long-lived certificates are capped
+    assert_eq!(strategy.max_age(Some(NOW + 5 * 365 * DAY), NOW, cap), cap);
+    assert_eq!(strategy.max_age(Some(NOW + 30 * DAY), NOW, 3600), 3600);
+    // Unknown expiry pins nothing
+    assert_eq!(strategy.max_age(None, NOW, cap), 0);
+}
+
+#[actix_rt::test]
+async fn test_header_follows_reloaded_certificate() {
+    let now = chrono::Utc::now().timestamp();
+    let headers = SecurityHeadersBuilder::new()
+        .hsts_strategy(HstsMaxAgeStrategy::CertExpiry { margin_secs: 0 })
+        .cert_not_after(now + 10 * DAY)
+        .include_subdomains(true)
+        .build();
+    let app = init_service(
+        App::new()
+            .wrap(headers.clone())
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() }))
+            .route(
+                "/own",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header(("Strict-Transport-Security", "max-age=0"))
+                        .finish()
+                }),
+            ),
+    )
+    .await;
+
+    let max_age = |value: &str| -> i64 {
+        value
+            .strip_prefix("max-age=")
+            .and_then(|v| v.split(';').next())
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+
+    let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    let value = resp
+        .headers()
+        .get("strict-transport-security")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(value.ends_with("; includeSubDomains"), "{}", value);
+    assert!((10 * DAY - max_age(&value)).abs() <= 5, "{}", value);
+
+    // A renewed certificate takes effect without rebuilding the middleware
+    headers.set_cert_not_after(now + 90 * DAY);
+    let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    let value = resp.headers().get("strict-transport-security").unwrap();
+    assert!((90 * DAY - max_age(value.to_str().unwrap())).abs() <= 5);
+
+    // Handlers may set their own policy
+    let resp = call_service(&app, TestRequest::get().uri("/own").to_request()).await;
+    assert_eq!(
+        resp.headers().get("strict-transport-security").unwrap(),
+        "max-age=0"
+    );
+}
+
+#[actix_rt::test]
+async fn test_nosniff_on_every_response() {
+    let app = init_service(
+        App::new()
+            .wrap(SecurityHeadersBuilder::new().build())
+            .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+    )
+    .await;
+
+    let resp = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+    assert_eq!(
+        resp.headers().get("x-content-type-options").unwrap(),
+        "nosniff"
+    );
+}
+
+#[actix_rt::test]
+async fn test_safe_echo_is_never_sniffed() {
+    async fn echo(query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        safe_text(StatusCode::OK, query.get("q").cloned().unwrap_or_default())
+    }
+    async fn echo_json(query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        safe_json(StatusCode::OK, &*query)
+    }
+    let app = init_service(
+        App::new()
+            .route("/echo", web::get().to(echo))
+            .route("/echo.json", web::get().to(echo_json))
+            .route(
+                "/fail",
+                web::get().to(|| async {
+                    Err::<HttpResponse, _>(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid",
+                        "<script>alert(1)</script> is not allowed",
+                    ))
+                }),
+            ),
+    )
+    .await;
+
+    for (uri, content_type) in [
+        (
+            "/echo?q=%3Chtml%3E%3Cscript%3Ealert(1)%3C/script%3E",
+            TEXT_PLAIN,
+        ),
+        ("/echo.json?q=%3Chtml%3E", "application/json"),
+        ("/fail", "application/json"),
+    ] {
+        let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            content_type,
+            "{}",
+            uri
+        );
+        assert_eq!(
+            resp.headers().get("x-content-type-options").unwrap(),
+            "nosniff",
+            "{}",
+            uri
+        );
+    }
+}
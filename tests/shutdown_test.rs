@@ -0,0 +1,179 @@
+#![cfg(unix)]
+
+use std::env;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use main::lifecycle::{grace_delay_from_env, parse_delay};
+
+mod common;
+
+use common::{free_port, TestPki};
+
+/// Waits for the child to exit, killing it if it takes longer than `timeout`.
+fn wait_for_exit(child: &mut Child, timeout: Duration) -> std::process::ExitStatus {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().expect("Failed to poll server") {
+            return status;
+        }
+        if Instant::now() > deadline {
+            child.kill().ok();
+            panic!("Server did not exit within {:?}", timeout);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[actix_rt::test]
+async fn test_sigterm_flips_readiness_then_drains() {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
+    let port = free_port();
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_secure-actix-web-server"))
+        .env("CERT_FILE", &cert_path)
+        .env("KEY_FILE", &key_path)
+        .env("SERVER_ADDRESS", format!("127.0.0.1:{}", port))
+        .env("NUM_WORKERS", "1")
+        .env("PRE_SHUTDOWN_DELAY", "3s")
+        .env("RUST_LOG", "info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start server");
+
+    let client = pki.client();
+    let url = |path: &str| format!("https://localhost:{}{}", port, path);
+
+    // Wait for the server to become ready
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Ok(resp) = client.get(url("/ready")).send().await {
+            assert_eq!(resp.status(), 200);
+            break;
+        }
+        assert!(Instant::now() < deadline, "Server did not start");
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let status = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .expect("Failed to send SIGTERM");
+    assert!(status.success());
+    actix_rt::time::sleep(Duration::from_millis(500)).await;
+
+    // During the grace delay readiness fails but other routes still work
+    let resp = client.get(url("/ready")).send().await.unwrap();
+    assert_eq!(resp.status(), 503);
+    let resp = client.get(url("/hello")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "Hello world!");
+
+    // After the delay the server drains and exits cleanly
+    let status = wait_for_exit(&mut server, Duration::from_secs(15));
+    assert!(status.success(), "Server exited with {:?}", status);
+
+    let output = server.wait_with_output().unwrap();
+    let logs = String::from_utf8_lossy(&output.stderr);
+    let not_ready = logs
+        .find("Shutdown phase 1")
+        .expect("Missing readiness log");
+    let waiting = logs
+        .find("Shutdown phase 2: serving normally for 3s")
+        .expect("Missing grace delay log");
+    let waited = logs
+        .find("Shutdown phase 2 complete")
+        .expect("Missing grace delay completion log");
+    let draining = logs.find("Shutdown phase 3").expect("Missing drain log");
+    let complete = logs
+        .find("Shutdown complete")
+        .expect("Missing completion log");
+    assert!(
+        not_ready < waiting && waiting < waited && waited < draining && draining < complete,
+        "{}",
+        logs
+    );
+}
+
+#[actix_rt::test]
+async fn test_access_log_is_flushed_on_shutdown() {
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = pki.write_server_files(dir.path());
+    let log_path = dir.path().join("access.log");
+    let port = free_port();
+
+    // The periodic flush never runs, so only the shutdown flush can write
+    let mut server = Command::new(env!("CARGO_BIN_EXE_secure-actix-web-server"))
+        .env("CERT_FILE", &cert_path)
+        .env("KEY_FILE", &key_path)
+        .env("SERVER_ADDRESS", format!("127.0.0.1:{}", port))
+        .env("NUM_WORKERS", "1")
+        .env("ACCESS_LOG_FILE", &log_path)
+        .env("ACCESS_LOG_FLUSH_INTERVAL_MS", "3600000")
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start server");
+
+    let client = pki.client();
+    let url = |path: &str| format!("https://localhost:{}{}", port, path);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.get(url("/ready")).send().await.is_err() {
+        assert!(Instant::now() < deadline, "Server did not start");
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let resp = client
+        .get(url("/hello"))
+        .header("X-Request-Id", "last-before-shutdown")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let buffered = std::fs::read_to_string(&log_path).unwrap();
+    assert!(!buffered.contains("last-before-shutdown"));
+
+    let status = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .expect("Failed to send SIGTERM");
+    assert!(status.success());
+    let status = wait_for_exit(&mut server, Duration::from_secs(15));
+    assert!(status.success(), "Server exited with {:?}", status);
+
+    let logs = std::fs::read_to_string(&log_path).unwrap();
+    assert!(
+        logs.lines()
+            .any(|l| l.contains("\"GET /hello\" 200") && l.contains("last-before-shutdown")),
+        "{}",
+        logs
+    );
+}
+
+#[test]
+fn test_pre_shutdown_delay_parsing() {
+    assert_eq!(parse_delay("15"), Some(Duration::from_secs(15)));
+    assert_eq!(parse_delay("15s"), Some(Duration::from_secs(15)));
+    assert_eq!(parse_delay("500ms"), Some(Duration::from_millis(500)));
+    assert_eq!(parse_delay("2m"), Some(Duration::from_secs(120)));
+    assert_eq!(parse_delay("1.5s"), None);
+    assert_eq!(parse_delay("soon"), None);
+    assert_eq!(parse_delay(""), None);
+
+    let _env = common::env_lock();
+    env::remove_var("PRE_SHUTDOWN_DELAY");
+    env::set_var("SHUTDOWN_GRACE_DELAY_SECS", "7");
+    assert_eq!(grace_delay_from_env().unwrap(), Duration::from_secs(7));
+
+    // The new name wins, and must be valid
+    env::set_var("PRE_SHUTDOWN_DELAY", "250ms");
+    assert_eq!(grace_delay_from_env().unwrap(), Duration::from_millis(250));
+    env::set_var("PRE_SHUTDOWN_DELAY", "a while");
+    assert!(grace_delay_from_env().is_err());
+
+    env::remove_var("PRE_SHUTDOWN_DELAY");
+    env::remove_var("SHUTDOWN_GRACE_DELAY_SECS");
+    assert_eq!(grace_delay_from_env().unwrap(), Duration::ZERO);
+}
@@ -0,0 +1,78 @@
+use actix_web::{test, web, App, HttpResponse};
+use std::time::Duration;
+
+use main::metrics::Metrics;
+use main::middleware::request_id::AssignRequestId;
+use main::middleware::slow_request::{SlowRequestDetector, SLOW_REQUESTS_METRIC};
+
+mod common;
+
+use common::logs;
+
+async fn delayed(body: web::Bytes) -> HttpResponse {
+    actix_rt::time::sleep(Duration::from_millis(60)).await;
+    HttpResponse::Accepted().body(body)
+}
+
+macro_rules! app {
+    ($detector:expr) => {
+        test::init_service(
+            App::new()
+                .wrap($detector)
+                .wrap(AssignRequestId::default())
+                .route("/slow", web::post().to(delayed))
+                .route(
+                    "/fast",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await
+    };
+}
+
+#[actix_rt::test]
+async fn slow_requests_are_logged_with_context() {
+    logs::capture();
+    let app = app!(SlowRequestDetector::new(Duration::from_millis(50)));
+    let slow = || Metrics::global().counter_value(SLOW_REQUESTS_METRIC, &[]);
+    let before = slow();
+
+    test::call_service(&app, test::TestRequest::get().uri("/fast").to_request()).await;
+    assert_eq!(slow(), before);
+
+    let req = test::TestRequest::post()
+        .uri("/slow?page=2")
+        .insert_header(("X-Request-Id", "slow-context-1"))
+        .insert_header(("User-Agent", "probe/1.0"))
+        .set_payload("secret-body")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+    assert!(slow() > before);
+
+    // The incoming ID is the parent of this server's ID
+    assert!(logs::contains(
+        "method=POST path=\"/slow\" query=\"page=2\" status=202 request_id=slow-context-1:"
+    ));
+    assert!(logs::contains("user_agent=\"probe/1.0\""));
+    assert!(logs::contains("Slow request: duration_ms="));
+    // Bodies are only logged when enabled
+    assert!(!logs::contains("secret-body"));
+}
+
+#[actix_rt::test]
+async fn request_bodies_are_logged_when_enabled() {
+    logs::capture();
+    let app = app!(SlowRequestDetector::new(Duration::from_millis(50)).with_body_logging(8));
+
+    let req = test::TestRequest::post()
+        .uri("/slow")
+        .insert_header(("X-Request-Id", "slow-body-1"))
+        .set_payload("0123456789abcdef")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    // The handler still gets the whole body
+    assert_eq!(test::read_body(resp).await, "0123456789abcdef");
+    assert!(logs::contains("request_id=slow-body-1:"));
+    assert!(logs::contains("user_agent=\"-\" body=\"01234567\""));
+}
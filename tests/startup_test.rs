@@ -0,0 +1,174 @@
+use std::env;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use main::load_tls_config;
+use main::startup::{
+    startup_files, wait_for_files, wait_for_files_from_env, ConfigError, StartupChecks,
+};
+
+mod common;
+
+use common::{logs, TestPki};
+
+#[test]
+fn passed_steps_are_summarized_in_order() {
+    let mut checks = StartupChecks::new();
+    assert_eq!(checks.check("tls", Ok::<_, IoError>(1)).unwrap(), 1);
+    checks.check("locales", Ok::<_, IoError>(())).unwrap();
+    checks.check("feature flags", Ok::<_, IoError>(())).unwrap();
+
+    assert_eq!(checks.passed(), ["tls", "locales", "feature flags"]);
+    assert_eq!(checks.summary(), "1. tls, 2. locales, 3. feature flags");
+}
+
+#[test]
+fn failing_step_is_named_in_the_error() {
+    logs::capture();
+    let mut checks = StartupChecks::new();
+    checks.check("tls", Ok::<_, IoError>(())).unwrap();
+    let e = checks
+        .check::<(), _>(
+            "trusted proxies",
+            Err(IoError::new(ErrorKind::InvalidInput, "bad CIDR 'x'")),
+        )
+        .unwrap_err();
+
+    assert_eq!(e.step, "trusted proxies");
+    assert_eq!(e.position, 2);
+    assert_eq!(
+        e.to_string(),
+        "invalid configuration at step 2 (trusted proxies): bad CIDR 'x'"
+    );
+    assert!(logs::contains("step 2 (trusted proxies)"));
+    assert_eq!(checks.passed(), ["tls"]);
+
+    // Converts into an IoError keeping the kind and the original error
+    let io: IoError = e.into();
+    assert_eq!(io.kind(), ErrorKind::InvalidInput);
+    let inner = io.get_ref().unwrap().downcast_ref::<ConfigError>().unwrap();
+    assert_eq!(inner.step, "trusted proxies");
+}
+
+fn files(cert: &Path, key: &Path) -> Vec<(String, String)> {
+    vec![
+        ("CERT_FILE".to_string(), cert.display().to_string()),
+        ("KEY_FILE".to_string(), key.display().to_string()),
+    ]
+}
+
+/// Writes `contents` to `path` after `delay`, on another thread.
+fn write_later(path: PathBuf, contents: String, delay: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        thread::sleep(delay);
+        fs::write(path, contents).unwrap();
+    })
+}
+
+#[test]
+fn late_key_file_is_waited_for() {
+    logs::capture();
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = pki.write_server_files(dir.path());
+    fs::remove_file(&key).unwrap();
+    let writer = write_later(
+        key.clone(),
+        pki.server_key_pem.clone(),
+        Duration::from_millis(300),
+    );
+
+    let started = Instant::now();
+    wait_for_files(&files(&cert, &key), Duration::from_secs(10)).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(logs::contains(&format!("KEY_FILE '{}'", key.display())));
+    writer.join().unwrap();
+
+    // The server starts with the files once they are there
+    let _env = common::env_lock();
+    env::remove_var("CLIENT_CA_FILE");
+    env::remove_var("MTLS_REQUIRED_PATHS");
+    env::set_var("CERT_FILE", &cert);
+    env::set_var("KEY_FILE", &key);
+    assert!(load_tls_config().is_ok());
+}
+
+#[test]
+fn missing_file_fails_once_the_deadline_passes() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = dir.path().join("cert.pem");
+    fs::write(&cert, "certificate").unwrap();
+    let key = dir.path().join("key.pem");
+
+    let started = Instant::now();
+    let e = wait_for_files(&files(&cert, &key), Duration::from_millis(500)).unwrap_err();
+    assert!(started.elapsed() >= Duration::from_millis(500));
+    assert!(started.elapsed() < Duration::from_secs(3));
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert!(e.to_string().starts_with("KEY_FILE '"));
+}
+
+#[test]
+fn empty_files_are_not_ready() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    fs::write(&cert, "certificate").unwrap();
+    fs::write(&key, "").unwrap();
+    let writer = write_later(key.clone(), "key".to_string(), Duration::from_millis(200));
+    wait_for_files(&files(&cert, &key), Duration::from_secs(10)).unwrap();
+    writer.join().unwrap();
+
+    fs::write(&key, "").unwrap();
+    let e = wait_for_files(&files(&cert, &key), Duration::from_millis(200)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn waiting_is_opt_in_and_covers_file_secrets() {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    fs::write(&cert, "certificate").unwrap();
+    fs::write(&key, "key").unwrap();
+    let users = dir.path().join("users");
+
+    let _env = common::env_lock();
+    env::set_var("CERT_FILE", &cert);
+    env::set_var("KEY_FILE", &key);
+    env::set_var("AUTH_USERS_FILE", &users);
+    env::remove_var("STARTUP_WAIT_FOR_FILES_SECS");
+    // Off by default: missing files are left to the steps reading them
+    assert!(wait_for_files_from_env().is_ok());
+
+    assert!(startup_files().contains(&("AUTH_USERS_FILE".to_string(), users.display().to_string())));
+    env::set_var("STARTUP_WAIT_FOR_FILES_SECS", "1");
+    let e = wait_for_files_from_env().unwrap_err();
+    assert!(e.to_string().starts_with("AUTH_USERS_FILE"));
+
+    let writer = write_later(
+        users.clone(),
+        "alice:hash".to_string(),
+        Duration::from_millis(200),
+    );
+    env::set_var("STARTUP_WAIT_FOR_FILES_SECS", "10");
+    assert!(wait_for_files_from_env().is_ok());
+    writer.join().unwrap();
+
+    env::set_var("STARTUP_WAIT_FOR_FILES_SECS", "soon");
+    assert_eq!(
+        wait_for_files_from_env().unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    for var in [
+        "CERT_FILE",
+        "KEY_FILE",
+        "AUTH_USERS_FILE",
+        "STARTUP_WAIT_FOR_FILES_SECS",
+    ] {
+        env::remove_var(var);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{web, App};
+
+use main::static_files::{self, accepted_variants, StaticFiles};
+
+fn static_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("app.js"), "console.log('raw');").unwrap();
+    fs::write(dir.path().join("app.js.br"), b"brotli-bytes").unwrap();
+    fs::write(dir.path().join("app.js.gz"), b"gzip-bytes").unwrap();
+    fs::write(dir.path().join("style.css"), "body { margin: 0 }").unwrap();
+    fs::write(dir.path().join(".env"), "SECRET=1").unwrap();
+    dir
+}
+
+macro_rules! app {
+    ($dir:expr) => {
+        init_service(App::new().configure(static_files::configure(web::Data::new(
+            StaticFiles::new($dir.path()).unwrap(),
+        ))))
+        .await
+    };
+}
+
+#[test]
+fn test_accepted_variants() {
+    assert_eq!(
+        accepted_variants(Some("gzip, deflate, br")),
+        vec![("br", "br"), ("gzip", "gz")]
+    );
+    assert_eq!(
+        accepted_variants(Some("br;q=0.5, gzip")),
+        vec![("gzip", "gz"), ("br", "br")]
+    );
+    assert_eq!(accepted_variants(Some("br;q=0, *")), vec![("gzip", "gz")]);
+    assert_eq!(accepted_variants(Some("identity")), vec![]);
+    assert_eq!(accepted_variants(None), vec![]);
+}
+
+#[actix_rt::test]
+async fn test_precompressed_variant_is_served() {
+    let dir = static_dir();
+    let app = app!(dir);
+
+    let req = TestRequest::get()
+        .uri("/static/app.js")
+        .insert_header(("Accept-Encoding", "gzip, br"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-encoding").unwrap(), "br");
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/javascript; charset=utf-8"
+    );
+    assert_eq!(
+        resp.headers().get("cache-control").unwrap(),
+        "public, max-age=86400"
+    );
+    assert!(resp
+        .headers()
+        .get_all("vary")
+        .any(|v| v.to_str().unwrap().eq_ignore_ascii_case("accept-encoding")));
+    assert_eq!(read_body(resp).await, "brotli-bytes");
+
+    let req = TestRequest::get()
+        .uri("/static/app.js")
+        .insert_header(("Accept-Encoding", "gzip"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+    assert_eq!(read_body(resp).await, "gzip-bytes");
+}
+
+#[actix_rt::test]
+async fn test_fallback_without_variant() {
+    let dir = static_dir();
+    let app = app!(dir);
+
+    // No precompressed file is accepted: the raw file
+    let req = TestRequest::get()
+        .uri("/static/app.js")
+        .insert_header(("Accept-Encoding", "identity"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(read_body(resp).await, "console.log('raw');");
+
+    // No precompressed file exists: compressed on the fly
+    let req = TestRequest::get()
+        .uri("/static/style.css")
+        .insert_header(("Accept-Encoding", "gzip"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+    assert_ne!(read_body(resp).await, "body { margin: 0 }");
+}
+
+#[actix_rt::test]
+async fn test_missing_and_hidden_files() {
+    let dir = static_dir();
+    let app = app!(dir);
+
+    for uri in [
+        "/static/missing.js",
+        "/static/.env",
+        "/static/%2E%2E/app.js",
+    ] {
+        let req = TestRequest::get().uri(uri).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 404, "{}", uri);
+    }
+}
+
+#[test]
+fn test_missing_directory_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(StaticFiles::new(dir.path().join("missing")).is_err());
+}
@@ -0,0 +1,167 @@
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{test, web, App, HttpResponse};
+use futures_util::stream::{self, StreamExt};
+
+use main::log_buffer::{self, LogBuffer};
+use main::util::stream::StreamBody;
+
+type Chunk = Option<Result<Bytes, Box<dyn std::error::Error>>>;
+
+async fn next_chunk(body: &mut Pin<Box<BoxBody>>) -> Chunk {
+    poll_fn(|cx| body.as_mut().poll_next(cx)).await
+}
+
+/// Sets its flag when dropped, i.e. when the producer is gone.
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[actix_rt::test]
+async fn streams_every_chunk_in_order() {
+    let chunks = (0..100).map(|i| Ok::<_, std::io::Error>(Bytes::from(format!("{},", i))));
+    let res = StreamBody::new(stream::iter(chunks))
+        .buffer(2)
+        .into_response(HttpResponse::Ok());
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+    let expected: String = (0..100).map(|i| format!("{},", i)).collect();
+    assert_eq!(body, expected.as_bytes());
+}
+
+#[actix_rt::test]
+async fn disconnect_cancels_the_producer() {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let flag = DropFlag(dropped.clone());
+    // One chunk, then waits forever, as a producer waiting for data would
+    let source = stream::once(async { Ok::<_, std::io::Error>(Bytes::from_static(b"first")) })
+        .chain(stream::pending())
+        .map(move |chunk| {
+            let _ = &flag;
+            chunk
+        });
+    let res = StreamBody::new(source).into_response(HttpResponse::Ok());
+
+    let mut body = Box::pin(res.into_body());
+    let first = next_chunk(&mut body).await.unwrap().unwrap();
+    assert_eq!(first, "first");
+    assert!(!dropped.load(Ordering::SeqCst));
+
+    drop(body);
+    for _ in 0..50 {
+        if dropped.load(Ordering::SeqCst) {
+            break;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(dropped.load(Ordering::SeqCst), "producer still running");
+}
+
+#[actix_rt::test]
+async fn error_mid_stream_sends_the_trailer_and_truncates() {
+    let source = stream::iter(vec![
+        Ok(Bytes::from_static(b"row 1\n")),
+        Err("database went away"),
+        Ok(Bytes::from_static(b"row 2\n")),
+    ]);
+    let res = StreamBody::new(source)
+        .error_trailer(|e| Bytes::from(format!("# truncated: {}\n", e)))
+        .into_response(HttpResponse::Ok());
+
+    let mut body = Box::pin(res.into_body());
+    assert_eq!(next_chunk(&mut body).await.unwrap().unwrap(), "row 1\n");
+    assert_eq!(
+        next_chunk(&mut body).await.unwrap().unwrap(),
+        "# truncated: database went away\n"
+    );
+    // The connection is aborted rather than the body completed
+    assert!(next_chunk(&mut body).await.unwrap().is_err());
+}
+
+#[actix_rt::test]
+async fn error_without_trailer_still_truncates() {
+    let source = stream::iter(vec![Ok(Bytes::from_static(b"partial")), Err("boom")]);
+    let res = StreamBody::new(source).into_response(HttpResponse::Ok());
+    assert!(actix_web::body::to_bytes(res.into_body()).await.is_err());
+}
+
+#[actix_rt::test]
+async fn rate_limit_paces_the_stream() {
+    let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from_static(&[0; 100])));
+    let started = std::time::Instant::now();
+    let res = StreamBody::new(stream::iter(chunks))
+        .rate_limit(1000)
+        .into_response(HttpResponse::Ok());
+    let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+    assert_eq!(body.len(), 400);
+    // Each 100-byte chunk uses 100ms of the budget
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[actix_rt::test]
+async fn log_tail_returns_the_last_lines() {
+    let buffer = web::Data::new(LogBuffer::new(3));
+    for i in 1..=5 {
+        buffer.push(format!("line {}", i));
+    }
+    let app = test::init_service(
+        App::new()
+            .app_data(buffer.clone())
+            .route("/admin/logs/tail", web::get().to(log_buffer::tail)),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/logs/tail")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(test::read_body(resp).await, "line 3\nline 4\nline 5\n");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/logs/tail?lines=1")
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, "line 5\n");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/logs/tail?lines=4")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_rt::test]
+async fn log_tail_follows_new_lines() {
+    let buffer = web::Data::new(LogBuffer::new(10));
+    buffer.push("before");
+    let app = test::init_service(
+        App::new()
+            .app_data(buffer.clone())
+            .route("/admin/logs/tail", web::get().to(log_buffer::tail)),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/logs/tail?follow=true")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    let mut body = Box::pin(resp.into_body());
+    assert_eq!(next_chunk(&mut body).await.unwrap().unwrap(), "before\n");
+
+    buffer.push("after");
+    assert_eq!(next_chunk(&mut body).await.unwrap().unwrap(), "after\n");
+}
@@ -0,0 +1,23 @@
+use main::systemd::listen_fds_count;
+
+#[test]
+fn test_listen_fds_for_this_process() {
+    assert_eq!(listen_fds_count(Some("1234"), Some("2"), 1234), 2);
+}
+
+#[test]
+fn test_listen_fds_for_another_process_are_ignored() {
+    assert_eq!(listen_fds_count(Some("4321"), Some("2"), 1234), 0);
+}
+
+#[test]
+fn test_not_socket_activated() {
+    assert_eq!(listen_fds_count(None, None, 1234), 0);
+    assert_eq!(listen_fds_count(Some("1234"), None, 1234), 0);
+}
+
+#[test]
+fn test_invalid_values_are_ignored() {
+    assert_eq!(listen_fds_count(Some("abc"), Some("2"), 1234), 0);
+    assert_eq!(listen_fds_count(Some("1234"), Some("two"), 1234), 0);
+}
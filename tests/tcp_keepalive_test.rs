@@ -0,0 +1,78 @@
+use socket2::SockRef;
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use main::tcp_keepalive::{self, TcpKeepaliveSettings, DEFAULT_INTERVAL, DEFAULT_PROBES};
+
+fn settings() -> TcpKeepaliveSettings {
+    TcpKeepaliveSettings {
+        idle: Duration::from_secs(42),
+        interval: Duration::from_secs(7),
+        probes: 3,
+    }
+}
+
+#[test]
+fn test_disabled_unless_idle_time_is_set() {
+    assert_eq!(
+        TcpKeepaliveSettings::parse(None, Some("5"), Some("2")),
+        None
+    );
+    assert_eq!(TcpKeepaliveSettings::parse(Some("0"), None, None), None);
+    assert_eq!(TcpKeepaliveSettings::parse(Some("soon"), None, None), None);
+}
+
+#[test]
+fn test_parses_timings_with_defaults() {
+    assert_eq!(
+        TcpKeepaliveSettings::parse(Some("42"), Some("7"), Some("3")),
+        Some(settings())
+    );
+    assert_eq!(
+        TcpKeepaliveSettings::parse(Some(" 60 "), Some("0"), Some("x")),
+        Some(TcpKeepaliveSettings {
+            idle: Duration::from_secs(60),
+            interval: DEFAULT_INTERVAL,
+            probes: DEFAULT_PROBES,
+        })
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_describes_linux_settings() {
+    assert_eq!(
+        settings().describe(),
+        "first probe after 42s idle, then every 7s, reset after 3 unanswered probes"
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_accepted_connections_inherit_keepalive() {
+    let listeners = tcp_keepalive::bind("127.0.0.1:0", &settings()).unwrap();
+    assert_eq!(listeners.len(), 1);
+    let listener = &listeners[0];
+    listener.set_nonblocking(false).unwrap();
+
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let socket = SockRef::from(&accepted);
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(42));
+    assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(7));
+    assert_eq!(socket.keepalive_retries().unwrap(), 3);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_applies_to_existing_listeners() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    settings().apply(&listener).unwrap();
+
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    let socket = SockRef::from(&accepted);
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(42));
+}
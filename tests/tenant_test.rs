@@ -0,0 +1,144 @@
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+use actix_web::{web, App, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use main::middleware::tenant::{HostMatch, Tenant, TenantRegistry, TenantResolution};
+use main::util::rate_limit::RateLimiter;
+
+fn registry() -> TenantRegistry {
+    TenantRegistry::new("example.com", ["tenant-a", "tenant-b"]).with_allowed_hosts(["localhost"])
+}
+
+fn tenant(id: &str) -> HostMatch {
+    HostMatch::Tenant(Tenant { id: id.to_string() })
+}
+
+/// A per-tenant counter cache, keyed the way tenant-scoped handlers must key
+/// shared state.
+type Cache = web::Data<Mutex<HashMap<String, u32>>>;
+
+async fn visits(tenant: Tenant, cache: Cache) -> HttpResponse {
+    let mut cache = cache.lock().unwrap();
+    let count = cache.entry(tenant.scoped_key("visits")).or_insert(0);
+    *count += 1;
+    HttpResponse::Ok().body(format!("{}:{}", tenant.id, count))
+}
+
+macro_rules! app {
+    ($resolution:expr) => {
+        init_service(
+            App::new()
+                .app_data(Cache::new(Mutex::new(HashMap::new())))
+                .wrap($resolution)
+                .route("/visits", web::get().to(visits))
+                .route(
+                    "/",
+                    web::get().to(|| async { HttpResponse::Ok().body("home") }),
+                ),
+        )
+        .await
+    };
+}
+
+macro_rules! get {
+    ($uri:expr, $host:expr) => {
+        TestRequest::get()
+            .uri($uri)
+            .insert_header(("Host", $host))
+            .to_request()
+    };
+}
+
+#[test]
+fn test_hosts_are_resolved() {
+    let registry = registry();
+    assert_eq!(registry.resolve("tenant-a.example.com"), tenant("tenant-a"));
+    assert_eq!(
+        registry.resolve("Tenant-B.Example.com:8443"),
+        tenant("tenant-b")
+    );
+    assert_eq!(
+        registry.resolve("tenant-a.example.com."),
+        tenant("tenant-a")
+    );
+    assert_eq!(registry.resolve("example.com"), HostMatch::NoTenant);
+    assert_eq!(registry.resolve("localhost:3000"), HostMatch::NoTenant);
+    assert_eq!(registry.resolve("tenant-c.example.com"), HostMatch::Unknown);
+    assert_eq!(
+        registry.resolve("x.tenant-a.example.com"),
+        HostMatch::Unknown
+    );
+    assert_eq!(registry.resolve("tenant-aexample.com"), HostMatch::Unknown);
+    assert_eq!(registry.resolve("evil.com"), HostMatch::Unknown);
+}
+
+#[actix_rt::test]
+async fn test_tenants_are_isolated() {
+    let app = app!(TenantResolution::new(registry()));
+
+    for expected in ["tenant-a:1", "tenant-a:2"] {
+        let resp = call_service(&app, get!("/visits", "tenant-a.example.com")).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(read_body(resp).await, expected);
+    }
+    // tenant-b does not see tenant-a's cache entry
+    let resp = call_service(&app, get!("/visits", "tenant-b.example.com")).await;
+    assert_eq!(read_body(resp).await, "tenant-b:1");
+}
+
+#[actix_rt::test]
+async fn test_unknown_hosts_and_tenantless_requests() {
+    let app = app!(TenantResolution::new(registry()));
+
+    let resp = call_service(&app, get!("/", "tenant-c.example.com")).await;
+    assert_eq!(resp.status(), 404);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "unknown_tenant");
+
+    let resp = call_service(&app, get!("/", "evil.com")).await;
+    assert_eq!(resp.status(), 404);
+
+    // The apex is served, but tenant-scoped routes reject it
+    let resp = call_service(&app, get!("/", "example.com")).await;
+    assert_eq!(resp.status(), 200);
+    let resp = call_service(&app, get!("/visits", "example.com")).await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_rt::test]
+async fn test_rate_limit_is_per_tenant() {
+    let app = app!(TenantResolution::new(registry()).with_rate_limit(1, 2));
+
+    for _ in 0..2 {
+        let resp = call_service(&app, get!("/visits", "tenant-a.example.com")).await;
+        assert_eq!(resp.status(), 200);
+    }
+    let resp = call_service(&app, get!("/visits", "tenant-a.example.com")).await;
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+
+    // tenant-b has its own bucket
+    let resp = call_service(&app, get!("/visits", "tenant-b.example.com")).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[test]
+fn test_rate_limiter_refills() {
+    let limiter = RateLimiter::new(2, 2);
+    let start = Instant::now();
+
+    assert!(limiter.check_at("key", start).is_ok());
+    assert!(limiter.check_at("key", start).is_ok());
+    let wait = limiter.check_at("key", start).unwrap_err();
+    assert_eq!(wait, Duration::from_millis(500));
+    assert!(limiter.check_at("other", start).is_ok());
+
+    assert!(limiter
+        .check_at("key", start + Duration::from_millis(500))
+        .is_ok());
+    assert!(limiter
+        .check_at("key", start + Duration::from_millis(500))
+        .is_err());
+}
@@ -0,0 +1,196 @@
+use std::env;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use serde_json::Value;
+use tempfile::NamedTempFile;
+
+use main::error::ApiError;
+use main::middleware::extra_headers::parse_extra_headers;
+use main::middleware::memory_pressure::MemoryPressure;
+use main::middleware::request_id::AssignRequestId;
+use main::throttle::{self, RetryAfterEstimator, Throttle, ThrottleResponses};
+
+mod common;
+
+fn body_file(suffix: &str, contents: &str) -> NamedTempFile {
+    let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
+    file
+}
+
+async fn limited(req: HttpRequest) -> HttpResponse {
+    throttle::respond(
+        &req,
+        Throttle::RateLimited,
+        ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "Slow down"),
+        Duration::from_millis(2500),
+    )
+}
+
+#[actix_rt::test]
+async fn default_is_json_with_retry_after() {
+    let app = init_service(App::new().route("/", web::get().to(limited))).await;
+    let resp = call_service(&app, TestRequest::get().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "3");
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "rate_limited");
+}
+
+#[actix_rt::test]
+async fn rate_limited_body_and_headers_are_customized() {
+    let file = body_file(
+        ".json",
+        r#"{"error":"slow_down","support":"ref {request_id}","retry_in":{retry_after}}"#,
+    );
+    let responses = ThrottleResponses::default()
+        .with_body_file(Throttle::RateLimited, file.path().to_str().unwrap())
+        .unwrap()
+        .with_headers(parse_extra_headers("X-Support:help@example.com,Retry-After:999").unwrap());
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(responses))
+            .wrap(AssignRequestId::new(8))
+            .route("/", web::get().to(limited)),
+    )
+    .await;
+
+    let req = TestRequest::get()
+        .insert_header(("X-Request-Id", "abc\"123"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+    assert_eq!(resp.headers().get("x-support").unwrap(), "help@example.com");
+    // The computed value wins over a configured one
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "3");
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "slow_down");
+    // The request ID is a child of the incoming one, escaped for JSON
+    assert!(body["support"]
+        .as_str()
+        .unwrap()
+        .starts_with("ref abc\"123:"));
+    assert_eq!(body["retry_in"], 3);
+}
+
+#[actix_rt::test]
+async fn load_shed_body_is_customized() {
+    let file = body_file(".html", "<h1>Busy</h1><p>Retry in {retry_after}s</p>");
+    let responses = ThrottleResponses::default()
+        .with_body_file(Throttle::LoadShed, file.path().to_str().unwrap())
+        .unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(responses))
+            .wrap(MemoryPressure::new(Arc::new(AtomicBool::new(true))))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let resp = call_service(&app, TestRequest::get().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "5");
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(read_body(resp).await, "<h1>Busy</h1><p>Retry in 5s</p>");
+}
+
+#[test]
+fn missing_body_file_is_an_error() {
+    let err = ThrottleResponses::default()
+        .with_body_file(Throttle::LoadShed, "/nonexistent/busy.html")
+        .unwrap_err();
+    assert!(err.to_string().contains("LOAD_SHED_RESPONSE_FILE"));
+}
+
+#[test]
+fn retry_after_is_estimated_from_the_shedding() {
+    let estimator = RetryAfterEstimator::new(Duration::from_secs(60));
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+
+    // No basis for an estimate at first
+    assert_eq!(estimator.record_shed_at(start), None);
+    // A steady episode is expected to last about as long again
+    for secs in 1..=7 {
+        estimator.record_shed_at(at(secs));
+    }
+    assert_eq!(
+        estimator.record_shed_at(at(7)),
+        Some(Duration::from_secs(7))
+    );
+
+    // After a gap a new episode starts, expected to last like the last one
+    assert_eq!(
+        estimator.record_shed_at(at(30)),
+        Some(Duration::from_secs(7))
+    );
+    assert_eq!(
+        estimator.record_shed_at(at(33)),
+        Some(Duration::from_secs(4))
+    );
+
+    // A falling shed rate is extrapolated to zero: 40 sheds, then 10
+    let estimator = RetryAfterEstimator::new(Duration::from_secs(60));
+    for _ in 0..40 {
+        estimator.record_shed_at(start);
+    }
+    for _ in 0..10 {
+        estimator.record_shed_at(at(5));
+    }
+    let estimate = estimator.record_shed_at(at(10)).unwrap();
+    assert_eq!(estimate.as_millis(), 1666);
+
+    // Estimates are capped
+    let estimator = RetryAfterEstimator::new(Duration::from_secs(10));
+    for secs in 0..=9 {
+        estimator.record_shed_at(at(secs * 5));
+    }
+    assert_eq!(
+        estimator.record_shed_at(at(50)),
+        Some(Duration::from_secs(10))
+    );
+}
+
+#[actix_rt::test]
+async fn load_shed_falls_back_to_the_configured_default() {
+    let responses = ThrottleResponses::default()
+        .with_retry_after(Some(Duration::from_secs(12)), Duration::from_secs(60));
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(responses))
+            .wrap(MemoryPressure::new(Arc::new(AtomicBool::new(true))))
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    let resp = call_service(&app, TestRequest::get().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "12");
+}
+
+#[test]
+fn retry_after_settings_are_validated() {
+    let _env = common::env_lock();
+    env::set_var("RETRY_AFTER_DEFAULT_SECS", "soon");
+    assert!(ThrottleResponses::from_env().is_err());
+    env::set_var("RETRY_AFTER_DEFAULT_SECS", "0");
+    assert!(ThrottleResponses::from_env().is_err());
+    env::set_var("RETRY_AFTER_DEFAULT_SECS", "10");
+    env::set_var("RETRY_AFTER_MAX_SECS", "120");
+    assert!(ThrottleResponses::from_env().is_ok());
+    env::remove_var("RETRY_AFTER_DEFAULT_SECS");
+    env::remove_var("RETRY_AFTER_MAX_SECS");
+}
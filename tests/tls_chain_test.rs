@@ -0,0 +1,138 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+use actix_web::{web, App};
+use rustls::Certificate;
+use serde_json::Value;
+use std::env;
+
+use main::middleware::api_key::AdminKey;
+use main::tls_chain::{self, fingerprint_sha256, pem_encode, TlsChain};
+
+mod common;
+
+use common::TestPki;
+
+const KEY: &str = "tls-admin-key-0123456789";
+
+fn chain(pki: &TestPki) -> Vec<Certificate> {
+    let mut chain = pki.server_chain();
+    chain.extend(
+        rustls_pemfile::certs(&mut pki.ca_pem.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(Certificate),
+    );
+    chain
+}
+
+#[test]
+fn pem_encoding_round_trips() {
+    let pki = TestPki::generate();
+    let chain = chain(&pki);
+    let pem = pem_encode(&chain);
+
+    assert!(pem.starts_with("-----BEGIN CERTIFICATE-----\n"));
+    assert!(pem.ends_with("-----END CERTIFICATE-----\n"));
+    assert_eq!(pem.matches("-----BEGIN CERTIFICATE-----").count(), 2);
+    assert!(pem.lines().all(|line| line.len() <= 64));
+    let parsed: Vec<Certificate> = rustls_pemfile::certs(&mut pem.as_bytes())
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    assert_eq!(parsed, chain);
+
+    // Whole lines hold exactly 64 characters, the last one the remainder
+    let der = Certificate(vec![0; 96]);
+    assert_eq!(
+        pem_encode(&[der]),
+        format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n{}\n-----END CERTIFICATE-----\n",
+            "A".repeat(64),
+            "A".repeat(64)
+        )
+    );
+    let der = Certificate(vec![0; 3]);
+    assert_eq!(
+        pem_encode(&[der]),
+        "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n"
+    );
+    assert_eq!(pem_encode(&[]), "");
+}
+
+#[test]
+fn fingerprints_are_colon_separated_sha256() {
+    assert_eq!(
+        fingerprint_sha256(&Certificate(b"abc".to_vec())),
+        "BA:78:16:BF:8F:01:CF:EA:41:41:40:DE:5D:AE:22:23:B0:03:61:A3:96:17:7A:9C:B4:10:FF:61:F2:00:15:AD"
+    );
+}
+
+#[test]
+fn chain_is_read_from_cert_file() {
+    let _env = common::env_lock();
+    let pki = TestPki::generate();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chain.pem");
+    std::fs::write(&path, format!("{}{}", pki.server_cert_pem, pki.ca_pem)).unwrap();
+    env::set_var("CERT_FILE", &path);
+    let loaded = TlsChain::from_env().unwrap();
+    assert_eq!(loaded.certificates(), chain(&pki).as_slice());
+    assert_eq!(loaded.leaf(), &pki.server_chain()[0]);
+
+    std::fs::write(&path, "no certificates here").unwrap();
+    assert!(TlsChain::from_env().is_err());
+    env::remove_var("CERT_FILE");
+    assert!(TlsChain::new(Vec::new()).is_err());
+}
+
+#[actix_rt::test]
+async fn endpoints_serve_the_chain_behind_the_admin_key() {
+    let pki = TestPki::generate();
+    let chain = chain(&pki);
+    let app = init_service(App::new().configure(tls_chain::configure(
+        web::Data::new(TlsChain::new(chain.clone()).unwrap()),
+        AdminKey::new(Some(KEY.to_string())),
+    )))
+    .await;
+
+    for path in ["/tls/chain.pem", "/tls/cert.der", "/tls/fingerprint"] {
+        let req = TestRequest::get().uri(path).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{}", path);
+    }
+
+    let get = |path: &str| {
+        TestRequest::get()
+            .uri(path)
+            .insert_header(("x-api-key", KEY))
+            .to_request()
+    };
+    let resp = call_service(&app, get("/tls/chain.pem")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/x-pem-file"
+    );
+    assert_eq!(
+        resp.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"chain.pem\""
+    );
+    let body = read_body(resp).await;
+    assert_eq!(body, pem_encode(&chain).as_bytes());
+
+    let resp = call_service(&app, get("/tls/cert.der")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"cert.der\""
+    );
+    let body = read_body(resp).await;
+    assert_eq!(body, chain[0].0);
+
+    let resp = call_service(&app, get("/tls/fingerprint")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["algorithm"], "sha256");
+    assert_eq!(body["fingerprint"], fingerprint_sha256(&chain[0]));
+}
@@ -0,0 +1,79 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use std::env;
+use std::net::TcpListener;
+
+use main::tls_info::{describe_certificate, HandshakeLogger};
+
+mod common;
+use common::{logs, TestPki};
+
+#[test]
+fn test_handshake_description() {
+    let logger = HandshakeLogger::new("CN=localhost (serial 01)");
+    assert_eq!(
+        logger.describe(
+            Some("192.0.2.1:4000".parse().unwrap()),
+            Some("api.example.com"),
+            Some("TLSv1_3")
+        ),
+        "TLS handshake from 192.0.2.1:4000: sni=api.example.com certificate=\"CN=localhost (serial 01)\" protocol=TLSv1_3"
+    );
+    assert!(logger.describe(None, None, None).contains("sni=no-sni"));
+}
+
+#[test]
+fn test_logger_is_enabled_by_tls_debug() {
+    let _guard = common::env_lock();
+    let pki = TestPki::generate();
+    let dir = env::temp_dir().join(format!("tls-debug-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, _) = pki.write_server_files(&dir);
+    env::set_var("CERT_FILE", &cert_path);
+
+    env::remove_var("TLS_DEBUG");
+    assert!(HandshakeLogger::from_env().unwrap().is_none());
+
+    env::set_var("TLS_DEBUG", "true");
+    let logger = HandshakeLogger::from_env()
+        .unwrap()
+        .expect("TLS_DEBUG is set");
+    assert!(logger.describe(None, None, None).contains("CN=localhost"));
+
+    env::remove_var("TLS_DEBUG");
+    env::remove_var("CERT_FILE");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[actix_rt::test]
+async fn test_handshake_is_logged_once_per_connection() {
+    logs::capture();
+    let pki = TestPki::generate();
+    let certificate = describe_certificate(&pki.server_chain()[0].0).unwrap();
+    let logger = HandshakeLogger::new(certificate.clone());
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+    let port = listener.local_addr().unwrap().port();
+    let server = HttpServer::new(|| {
+        App::new().route("/", web::get().to(|| async { HttpResponse::Ok().finish() }))
+    })
+    .on_connect(move |connection, data| logger.on_connect(connection, data))
+    .workers(1)
+    .listen_rustls(listener, pki.server_config())
+    .expect("Failed to listen")
+    .run();
+    actix_rt::spawn(server);
+
+    // Three requests reuse one keep-alive connection
+    let client = pki.client();
+    for _ in 0..3 {
+        let resp = client
+            .get(format!("https://localhost:{}/", port))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert!(resp.status().is_success());
+    }
+
+    let line = format!("sni=localhost certificate=\"{}\"", certificate);
+    assert_eq!(logs::count(&line), 1);
+}
@@ -0,0 +1,134 @@
+use rustls::{ClientConfig, ClientConnection, ServerConnection};
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+use main::tls_keylog::{self, KeyLogFile};
+
+mod common;
+
+use common::test_utils::MockTlsConfig;
+
+/// Runs a handshake in memory.
+fn handshake(server: rustls::ServerConfig, client: ClientConfig) {
+    let mut server = ServerConnection::new(Arc::new(server)).unwrap();
+    let mut client =
+        ClientConnection::new(Arc::new(client), "localhost".try_into().unwrap()).unwrap();
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut to_server = Vec::new();
+        client.write_tls(&mut to_server).unwrap();
+        if !to_server.is_empty() {
+            server.read_tls(&mut to_server.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+        }
+
+        let mut to_client = Vec::new();
+        server.write_tls(&mut to_client).unwrap();
+        if !to_client.is_empty() {
+            client.read_tls(&mut to_client.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+    }
+}
+
+fn clear_env() {
+    env::remove_var("DANGEROUS_TLS_KEYLOG");
+    env::remove_var("SSLKEYLOGFILE");
+    env::remove_var("APP_ENV");
+}
+
+#[test]
+fn handshakes_are_logged_in_nss_format() {
+    let _env = common::env_lock();
+    clear_env();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("keys.log");
+    env::set_var("DANGEROUS_TLS_KEYLOG", "1");
+    env::set_var("SSLKEYLOGFILE", &path);
+    let key_log = tls_keylog::from_env().unwrap().expect("key log enabled");
+    clear_env();
+
+    let (mut server, client) = MockTlsConfig::generate();
+    server.key_log = key_log;
+    handshake(server, client);
+
+    let log = fs::read_to_string(&path).unwrap();
+    let labels: Vec<&str> = log
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert!(labels.contains(&"CLIENT_HANDSHAKE_TRAFFIC_SECRET"));
+    assert!(labels.contains(&"SERVER_TRAFFIC_SECRET_0"));
+    for line in log.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        assert_eq!(fields.len(), 3, "{}", line);
+        // 32 random bytes, then the secret, both as hex
+        assert_eq!(fields[1].len(), 64);
+        assert!(fields[2].bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn production_refuses_key_logging() {
+    let _env = common::env_lock();
+    clear_env();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("keys.log");
+    env::set_var("DANGEROUS_TLS_KEYLOG", "1");
+    env::set_var("SSLKEYLOGFILE", &path);
+    for app_env in ["prod", "PROD", "production"] {
+        env::set_var("APP_ENV", app_env);
+        let err = tls_keylog::from_env().err().expect("refused");
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+    assert!(!path.exists());
+
+    env::set_var("APP_ENV", "staging");
+    assert!(tls_keylog::from_env().unwrap().is_some());
+    clear_env();
+}
+
+#[test]
+fn key_logging_needs_the_flag_and_a_file() {
+    let _env = common::env_lock();
+    clear_env();
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("keys.log");
+
+    // SSLKEYLOGFILE alone is ignored
+    env::set_var("SSLKEYLOGFILE", &path);
+    assert!(tls_keylog::from_env().unwrap().is_none());
+    env::set_var("DANGEROUS_TLS_KEYLOG", "true");
+    assert!(tls_keylog::from_env().unwrap().is_none());
+    assert!(!path.exists());
+
+    env::set_var("DANGEROUS_TLS_KEYLOG", "1");
+    env::remove_var("SSLKEYLOGFILE");
+    let err = tls_keylog::from_env().err().expect("file required");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    clear_env();
+}
+
+#[test]
+fn entries_are_appended() {
+    use rustls::KeyLog;
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("keys.log");
+    fs::write(&path, "CLIENT_RANDOM 00 00\n").unwrap();
+    let key_log = KeyLogFile::open(path.to_str().unwrap()).unwrap();
+    key_log.log("CLIENT_RANDOM", &[0x01, 0xab], &[0xff]);
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "CLIENT_RANDOM 00 00\nCLIENT_RANDOM 01ab ff\n"
+    );
+}
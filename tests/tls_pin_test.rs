@@ -0,0 +1,233 @@
+use actix_web::test::{
+    call_and_read_body_json, call_service, init_service, read_body, read_body_json, TestRequest,
+};
+use actix_web::{web, App, HttpResponse, HttpServer};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rcgen::{
+    BasicConstraints, Certificate as RcgenCertificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa, SanType,
+};
+use reqwest::Url;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::time::Duration;
+
+use main::proxy::tls_pin::spki_sha256;
+use main::proxy::{configure_admin, Mirror, Proxy, TlsPinner};
+
+/// A CA and an upstream certificate for 127.0.0.1 signed by it.
+struct Upstream {
+    ca_der: Vec<u8>,
+    cert_der: Vec<u8>,
+    key_der: Vec<u8>,
+}
+
+impl Upstream {
+    fn generate() -> Self {
+        let mut params = CertificateParams::new(Vec::<String>::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Upstream CA");
+        let ca = RcgenCertificate::from_params(params).unwrap();
+
+        let mut params = CertificateParams::new(Vec::<String>::new());
+        params.subject_alt_names = vec![SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "upstream");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let cert = RcgenCertificate::from_params(params).unwrap();
+
+        Upstream {
+            ca_der: ca.serialize_der().unwrap(),
+            cert_der: cert.serialize_der_with_signer(&ca).unwrap(),
+            key_der: cert.serialize_private_key_der(),
+        }
+    }
+
+    /// Starts an HTTPS server answering `upstream` and returns its URL.
+    fn start(&self) -> Url {
+        self.start_with_extra_certificates(Vec::new())
+    }
+
+    /// Like [`start`](Self::start), sending `extra` after the leaf as if
+    /// they were intermediates.
+    fn start_with_extra_certificates(&self, extra: Vec<Vec<u8>>) -> Url {
+        let chain = std::iter::once(self.cert_der.clone())
+            .chain(extra)
+            .map(Certificate)
+            .collect();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(chain, PrivateKey(self.key_der.clone()))
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("https://{}/", listener.local_addr().unwrap())).unwrap();
+        let server = HttpServer::new(|| {
+            App::new().default_service(web::to(|| async { HttpResponse::Ok().body("upstream") }))
+        })
+        .workers(1)
+        .listen_rustls(listener, config)
+        .unwrap()
+        .run();
+        actix_rt::spawn(server);
+        url
+    }
+
+    fn pin(&self) -> String {
+        STANDARD.encode(spki_sha256(&self.cert_der).unwrap())
+    }
+}
+
+fn proxy(url: Url, pins: &str, ca_der: Vec<u8>) -> web::Data<Proxy> {
+    let pinner = TlsPinner::parse(pins)
+        .unwrap()
+        .with_root_certificate(ca_der);
+    web::Data::new(
+        Proxy::new("/proxy", url, Mirror::new(None, Duration::from_secs(5)))
+            .with_pinner(pinner)
+            .unwrap(),
+    )
+}
+
+macro_rules! app {
+    ($proxy:expr) => {
+        init_service(
+            App::new()
+                .configure(Proxy::configure($proxy.clone()))
+                .service(web::scope("/admin").configure(configure_admin)),
+        )
+        .await
+    };
+}
+
+#[test]
+fn test_parses_pin_file() {
+    let hex = "ab".repeat(32);
+    let base64 = STANDARD.encode([7u8; 32]);
+    let pinner = TlsPinner::parse(&format!(
+        "# upstream pins\n\napi.example.com:{}\nAPI.example.com:{}\n[::1]:{}\n",
+        hex, base64, base64
+    ))
+    .unwrap();
+    assert_eq!(
+        pinner.hosts().collect::<Vec<_>>(),
+        ["::1", "api.example.com"]
+    );
+
+    let described = pinner.describe();
+    assert_eq!(described[1]["pins"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        described[1]["pins"][0]["sha256"],
+        STANDARD.encode([0xab; 32])
+    );
+
+    for bad in [
+        "api.example.com",
+        ":abcd",
+        "api.example.com:not-a-hash",
+        "host:abab",
+    ] {
+        assert!(TlsPinner::parse(bad).is_err(), "{} should be rejected", bad);
+    }
+}
+
+#[actix_rt::test]
+async fn test_matching_pin_is_accepted_and_counted() {
+    let upstream = Upstream::generate();
+    let url = upstream.start();
+    let proxy = proxy(
+        url,
+        &format!("127.0.0.1:{}", upstream.pin()),
+        upstream.ca_der.clone(),
+    );
+    let app = app!(proxy);
+
+    let req = TestRequest::get().uri("/proxy/x").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(read_body(resp).await, "upstream");
+
+    let req = TestRequest::get().uri("/admin/tls/pins").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["pins"][0]["host"], "127.0.0.1");
+    assert_eq!(body["pins"][0]["pins"][0]["sha256"], upstream.pin());
+    assert_eq!(body["pins"][0]["pins"][0]["matches"], 1);
+    assert_eq!(body["pins"][0]["mismatches"], 0);
+}
+
+#[actix_rt::test]
+async fn test_mismatched_pin_gets_502() {
+    let upstream = Upstream::generate();
+    let url = upstream.start();
+    let other = Upstream::generate();
+    let proxy = proxy(
+        url,
+        &format!("127.0.0.1:{}", other.pin()),
+        upstream.ca_der.clone(),
+    );
+    let app = app!(proxy);
+
+    let req = TestRequest::get().uri("/proxy/x").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 502);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "upstream_pin_mismatch");
+
+    let req = TestRequest::get().uri("/admin/tls/pins").to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["pins"][0]["pins"][0]["matches"], 0);
+    assert_eq!(body["pins"][0]["mismatches"], 1);
+}
+
+#[actix_rt::test]
+async fn test_pinned_certificate_sent_as_intermediate_gets_502() {
+    let upstream = Upstream::generate();
+    let pinned = Upstream::generate();
+    // Any server can append a copy of the pinned certificate
+    let url = upstream.start_with_extra_certificates(vec![pinned.cert_der.clone()]);
+    let proxy = proxy(
+        url,
+        &format!("127.0.0.1:{}", pinned.pin()),
+        upstream.ca_der.clone(),
+    );
+    let app = app!(proxy);
+
+    let req = TestRequest::get().uri("/proxy/x").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 502);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "upstream_pin_mismatch");
+}
+
+#[actix_rt::test]
+async fn test_unpinned_hosts_only_need_a_valid_chain() {
+    let upstream = Upstream::generate();
+    let url = upstream.start();
+    let proxy = proxy(
+        url.clone(),
+        &format!("other.example.com:{}", upstream.pin()),
+        upstream.ca_der.clone(),
+    );
+    let app = app!(proxy);
+
+    let req = TestRequest::get().uri("/proxy/x").to_request();
+    assert_eq!(call_service(&app, req).await.status(), 200);
+
+    // Without the upstream's CA the chain itself is rejected
+    let untrusted = web::Data::new(
+        Proxy::new("/proxy", url, Mirror::new(None, Duration::from_secs(5)))
+            .with_pinner(TlsPinner::default())
+            .unwrap(),
+    );
+    let app = app!(untrusted);
+    let req = TestRequest::get().uri("/proxy/x").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), 502);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "bad_gateway");
+}
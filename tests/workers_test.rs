@@ -0,0 +1,58 @@
+use std::fs;
+
+use main::workers::{
+    cgroup_cpu_quota, parse_cfs_quota, parse_cpu_list, parse_cpu_max, WorkerCount,
+};
+
+#[test]
+fn parses_cgroup_v2_cpu_max() {
+    assert_eq!(parse_cpu_max("200000 100000\n"), Some(2.0));
+    assert_eq!(parse_cpu_max("50000 100000"), Some(0.5));
+    assert_eq!(parse_cpu_max("max 100000\n"), None);
+    assert_eq!(parse_cpu_max("150000"), Some(1.5));
+    assert_eq!(parse_cpu_max(""), None);
+}
+
+#[test]
+fn parses_cgroup_v1_cfs_quota() {
+    assert_eq!(parse_cfs_quota("300000\n", "100000\n"), Some(3.0));
+    assert_eq!(parse_cfs_quota("-1\n", "100000\n"), None);
+    assert_eq!(parse_cfs_quota("100000", "0"), None);
+}
+
+#[test]
+fn counts_cpu_lists() {
+    assert_eq!(parse_cpu_list("0-7\n"), Some(8));
+    assert_eq!(parse_cpu_list("0-3,6,8-9"), Some(7));
+    assert_eq!(parse_cpu_list("0"), Some(1));
+    assert_eq!(parse_cpu_list("3-1"), None);
+}
+
+#[test]
+fn quota_limits_the_default() {
+    assert_eq!(WorkerCount::for_cpus(64, Some(2.0)).get(), 2);
+    assert_eq!(WorkerCount::for_cpus(64, Some(1.5)).get(), 2);
+    assert_eq!(WorkerCount::for_cpus(4, Some(16.0)).get(), 4);
+    assert_eq!(WorkerCount::for_cpus(8, None).get(), 8);
+    // Never fewer than one worker
+    assert_eq!(WorkerCount::for_cpus(8, Some(0.1)).get(), 1);
+    assert_eq!(WorkerCount::for_cpus(0, None).get(), 1);
+    assert_eq!(WorkerCount::new(0).to_string(), "1");
+}
+
+#[test]
+fn reads_the_quota_of_either_cgroup_version() {
+    let v2 = tempfile::tempdir().unwrap();
+    fs::write(v2.path().join("cpu.max"), "250000 100000\n").unwrap();
+    assert_eq!(cgroup_cpu_quota(v2.path()), Some(2.5));
+
+    let v1 = tempfile::tempdir().unwrap();
+    let cpu = v1.path().join("cpu,cpuacct");
+    fs::create_dir(&cpu).unwrap();
+    fs::write(cpu.join("cpu.cfs_quota_us"), "200000\n").unwrap();
+    fs::write(cpu.join("cpu.cfs_period_us"), "100000\n").unwrap();
+    assert_eq!(cgroup_cpu_quota(v1.path()), Some(2.0));
+
+    let none = tempfile::tempdir().unwrap();
+    assert_eq!(cgroup_cpu_quota(none.path()), None);
+}
@@ -0,0 +1,149 @@
+use std::time::{Duration, Instant};
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{CloseCode, Codec, Frame, Message};
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{App, HttpRequest};
+
+use main::error::ApiError;
+use main::ws::{self, MessageRateLimiter};
+
+mod common;
+
+use common::logs;
+
+async fn echo(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<actix_web::HttpResponse, ApiError> {
+    ws::serve(&req, payload, Some)
+}
+
+/// Encodes `messages` as a client would, masked.
+fn client_frames(messages: impl IntoIterator<Item = Message>) -> Bytes {
+    let mut codec = Codec::new().client_mode();
+    let mut out = BytesMut::new();
+    for message in messages {
+        codec.encode(message, &mut out).unwrap();
+    }
+    out.freeze()
+}
+
+fn server_frames(body: &[u8]) -> Vec<Frame> {
+    let mut codec = Codec::new().client_mode();
+    let mut buf = BytesMut::from(body);
+    let mut frames = Vec::new();
+    while let Some(frame) = codec.decode(&mut buf).unwrap() {
+        frames.push(frame);
+    }
+    assert!(buf.is_empty());
+    frames
+}
+
+fn texts(count: usize) -> Vec<Message> {
+    (0..count)
+        .map(|i| Message::Text(format!("message {}", i).into()))
+        .collect()
+}
+
+fn upgrade(payload: Bytes) -> TestRequest {
+    TestRequest::get()
+        .uri("/ws")
+        .peer_addr("192.0.2.7:50000".parse().unwrap())
+        .insert_header((header::UPGRADE, "websocket"))
+        .insert_header((header::CONNECTION, "Upgrade"))
+        .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+        .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+        .set_payload(payload)
+}
+
+async fn exchange(limits: MessageRateLimiter, messages: Vec<Message>) -> Vec<Frame> {
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(limits))
+            .route("/ws", web::get().to(echo)),
+    )
+    .await;
+    let resp = call_service(&app, upgrade(client_frames(messages)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(
+        resp.headers().get(header::SEC_WEBSOCKET_ACCEPT).unwrap(),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+    server_frames(&read_body(resp).await)
+}
+
+#[test]
+fn bucket_allows_bursts_and_refills() {
+    let mut limit = MessageRateLimiter::new(2, 3).connection("192.0.2.1");
+    let start = Instant::now();
+    assert!((0..3).all(|_| limit.allow_at(start)));
+    assert!(!limit.allow_at(start));
+    assert!(limit.allow_at(start + Duration::from_millis(500)));
+    assert!(!limit.allow_at(start + Duration::from_millis(500)));
+    assert_eq!(limit.received(), 6);
+}
+
+#[test]
+fn connections_have_their_own_bucket() {
+    let limits = MessageRateLimiter::new(1, 1);
+    let now = Instant::now();
+    let mut first = limits.connection("192.0.2.1");
+    let mut second = limits.connection("192.0.2.1");
+    assert!(first.allow_at(now));
+    assert!(!first.allow_at(now));
+    assert!(second.allow_at(now));
+}
+
+#[actix_rt::test]
+async fn messages_within_the_limit_are_echoed() {
+    let frames = exchange(MessageRateLimiter::new(5, 10), texts(10)).await;
+    assert_eq!(frames.len(), 10);
+    assert_eq!(frames[9], Frame::Text(Bytes::from("message 9")));
+}
+
+#[actix_rt::test]
+async fn burst_over_the_limit_closes_with_policy_violation() {
+    logs::capture();
+    let frames = exchange(MessageRateLimiter::new(5, 5), texts(50)).await;
+    assert_eq!(frames.len(), 6);
+    assert_eq!(frames[4], Frame::Text(Bytes::from("message 4")));
+    match &frames[5] {
+        Frame::Close(Some(reason)) => assert_eq!(reason.code, CloseCode::Policy),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+    assert!(logs::contains(
+        "Closing WebSocket from 192.0.2.7 after 6 messages"
+    ));
+}
+
+#[actix_rt::test]
+async fn pings_count_towards_the_limit() {
+    let mut messages: Vec<Message> = (0..3)
+        .map(|_| Message::Ping(Bytes::from_static(b"hi")))
+        .collect();
+    messages.extend(texts(1));
+    let frames = exchange(MessageRateLimiter::new(3, 3), messages).await;
+    assert_eq!(frames[0], Frame::Pong(Bytes::from_static(b"hi")));
+    assert!(matches!(&frames[3], Frame::Close(Some(reason)) if reason.code == CloseCode::Policy));
+}
+
+#[actix_rt::test]
+async fn client_close_is_echoed() {
+    let mut messages = texts(1);
+    messages.push(Message::Close(Some(CloseCode::Normal.into())));
+    messages.extend(texts(1));
+    let frames = exchange(MessageRateLimiter::default(), messages).await;
+    assert_eq!(frames.len(), 2);
+    assert!(matches!(&frames[1], Frame::Close(Some(reason)) if reason.code == CloseCode::Normal));
+}
+
+#[actix_rt::test]
+async fn plain_requests_are_rejected() {
+    let app = init_service(App::new().route("/ws", web::get().to(echo))).await;
+    let req = TestRequest::get().uri("/ws").to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
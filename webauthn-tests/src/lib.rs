@@ -0,0 +1,3 @@
+//! Passkey tests for the server, run against a software authenticator.
+//!
+//! The tests are in `tests/`; this crate has no code of its own.
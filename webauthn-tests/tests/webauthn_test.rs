@@ -0,0 +1,247 @@
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+use webauthn_authenticator_rs::WebauthnAuthenticator;
+use webauthn_rs::prelude::{CreationChallengeResponse, RequestChallengeResponse, Url};
+
+use main::auth::file::{hash_password, FileBackend};
+use main::auth::webauthn::{
+    is_counter_regression, parse_attestation_object, PasskeyAuth, WebauthnConfig, SESSION_COOKIE,
+};
+use main::auth::AuthBackend;
+use main::clock::MockClock;
+
+const ORIGIN: &str = "https://localhost:8443";
+
+fn setup() -> (web::Data<PasskeyAuth>, Arc<dyn AuthBackend>) {
+    let users = format!("alice:{}:admin\n", hash_password("correct horse"));
+    let backend: Arc<dyn AuthBackend> = Arc::new(FileBackend::parse(&users).unwrap());
+    let config = WebauthnConfig::new("localhost", Url::parse(ORIGIN).unwrap()).unwrap();
+    (web::Data::new(PasskeyAuth::new(config).unwrap()), backend)
+}
+
+macro_rules! app {
+    ($passkeys:expr, $backend:expr) => {
+        init_service(
+            App::new().configure(PasskeyAuth::configure($passkeys.clone(), $backend.clone())),
+        )
+        .await
+    };
+}
+
+macro_rules! post {
+    ($app:expr, $uri:expr, $origin:expr, $body:expr) => {{
+        let req = TestRequest::post()
+            .uri($uri)
+            .insert_header(("Origin", $origin))
+            .insert_header((
+                "Authorization",
+                format!("Basic {}", STANDARD.encode("alice:correct horse")),
+            ))
+            .set_json($body)
+            .to_request();
+        call_service(&$app, req).await
+    }};
+}
+
+#[actix_rt::test]
+async fn test_register_and_login_ceremonies() {
+    let (passkeys, backend) = setup();
+    let app = app!(passkeys, backend);
+    let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+    // Enrollment
+    let resp = post!(app, "/auth/webauthn/register/start", ORIGIN, json!({}));
+    assert_eq!(resp.status(), 200);
+    let body: Value = read_body_json(resp).await;
+    let options: CreationChallengeResponse =
+        serde_json::from_value(body["options"].clone()).unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), options)
+        .unwrap();
+    let finish = json!({ "challenge_id": body["challenge_id"], "credential": credential });
+    let resp = post!(
+        app,
+        "/auth/webauthn/register/finish",
+        ORIGIN,
+        finish.clone()
+    );
+    assert_eq!(resp.status(), 201);
+
+    let stored = passkeys.credentials("alice");
+    assert_eq!(stored.len(), 1);
+    assert!(!stored[0].flagged);
+
+    // A challenge is answered once
+    let resp = post!(app, "/auth/webauthn/register/finish", ORIGIN, finish);
+    assert_eq!(resp.status(), 400);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_challenge");
+
+    // Login
+    let resp = post!(
+        app,
+        "/auth/webauthn/login/start",
+        ORIGIN,
+        json!({ "username": "alice" })
+    );
+    assert_eq!(resp.status(), 200);
+    let body: Value = read_body_json(resp).await;
+    let options: RequestChallengeResponse =
+        serde_json::from_value(body["options"].clone()).unwrap();
+    let assertion = authenticator
+        .do_authentication(Url::parse(ORIGIN).unwrap(), options)
+        .unwrap();
+    let resp = post!(
+        app,
+        "/auth/webauthn/login/finish",
+        ORIGIN,
+        json!({ "challenge_id": body["challenge_id"], "credential": assertion })
+    );
+    assert_eq!(resp.status(), 200);
+    let cookie = resp
+        .response()
+        .cookies()
+        .find(|c| c.name() == SESSION_COOKIE)
+        .expect("missing session cookie");
+    assert!(cookie.http_only().unwrap_or(false));
+    assert!(cookie.secure().unwrap_or(false));
+    let principal = passkeys.session(cookie.value()).unwrap();
+    assert_eq!(principal.username, "alice");
+    assert_eq!(principal.roles, vec!["admin"]);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["username"], "alice");
+}
+
+#[actix_rt::test]
+async fn test_registration_requires_login() {
+    let (passkeys, backend) = setup();
+    let app = app!(passkeys, backend);
+
+    let req = TestRequest::post()
+        .uri("/auth/webauthn/register/start")
+        .insert_header(("Origin", ORIGIN))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), 401);
+}
+
+#[actix_rt::test]
+async fn test_origin_mismatch_is_rejected() {
+    let (passkeys, backend) = setup();
+    let app = app!(passkeys, backend);
+
+    // A foreign Origin header never reaches the ceremony
+    let resp = post!(
+        app,
+        "/auth/webauthn/register/start",
+        "https://evil.example",
+        json!({})
+    );
+    assert_eq!(resp.status(), 403);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "origin_mismatch");
+
+    // A response signed for another origin fails verification
+    let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+    let resp = post!(app, "/auth/webauthn/register/start", ORIGIN, json!({}));
+    let body: Value = read_body_json(resp).await;
+    let options: CreationChallengeResponse =
+        serde_json::from_value(body["options"].clone()).unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse("https://localhost:9999").unwrap(), options)
+        .unwrap();
+    let resp = post!(
+        app,
+        "/auth/webauthn/register/finish",
+        ORIGIN,
+        json!({ "challenge_id": body["challenge_id"], "credential": credential })
+    );
+    assert_eq!(resp.status(), 400);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "webauthn_failed");
+    assert!(passkeys.credentials("alice").is_empty());
+}
+
+#[actix_rt::test]
+async fn test_challenges_expire() {
+    let (_, backend) = setup();
+    let config = WebauthnConfig::new("localhost", Url::parse(ORIGIN).unwrap()).unwrap();
+    let ttl = config.challenge_ttl;
+    let clock = MockClock::new(Utc::now());
+    let passkeys = web::Data::new(PasskeyAuth::new(config).unwrap().with_clock(clock.clock()));
+    let app = app!(passkeys, backend);
+    let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+    let resp = post!(app, "/auth/webauthn/register/start", ORIGIN, json!({}));
+    let body: Value = read_body_json(resp).await;
+    let options: CreationChallengeResponse =
+        serde_json::from_value(body["options"].clone()).unwrap();
+    let credential = authenticator
+        .do_registration(Url::parse(ORIGIN).unwrap(), options)
+        .unwrap();
+    clock.advance(ttl);
+    let resp = post!(
+        app,
+        "/auth/webauthn/register/finish",
+        ORIGIN,
+        json!({ "challenge_id": body["challenge_id"], "credential": credential })
+    );
+    assert_eq!(resp.status(), 400);
+    let body: Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "invalid_challenge");
+    assert!(passkeys.credentials("alice").is_empty());
+}
+
+#[test]
+fn test_config_requires_origin_within_rp_id() {
+    let config =
+        |rp_id: &str, origin: &str| WebauthnConfig::new(rp_id, Url::parse(origin).unwrap());
+    assert!(config("example.com", "https://login.example.com").is_ok());
+    assert!(config("example.com", "https://example.com").is_ok());
+    assert!(config("example.com", "https://example.com.evil.net").is_err());
+    assert!(config("example.com", "http://login.example.com").is_err());
+}
+
+#[test]
+fn test_counter_regression() {
+    assert!(!is_counter_regression(0, 0));
+    assert!(!is_counter_regression(5, 6));
+    assert!(is_counter_regression(5, 5));
+    assert!(is_counter_regression(5, 2));
+    assert!(is_counter_regression(5, 0));
+}
+
+#[test]
+fn test_parse_attestation_object() {
+    let aaguid = [0xabu8; 16];
+    let mut auth_data = vec![0u8; 32];
+    auth_data.push(0x45); // UP, UV, AT
+    auth_data.extend_from_slice(&7u32.to_be_bytes());
+    auth_data.extend_from_slice(&aaguid);
+    auth_data.extend_from_slice(&[0, 0]); // credential ID length
+
+    // {"fmt": "none", "attStmt": {}, "authData": h'...'}
+    let mut object = vec![0xa3, 0x63];
+    object.extend_from_slice(b"fmt");
+    object.push(0x64);
+    object.extend_from_slice(b"none");
+    object.push(0x67);
+    object.extend_from_slice(b"attStmt");
+    object.push(0xa0);
+    object.push(0x68);
+    object.extend_from_slice(b"authData");
+    object.extend_from_slice(&[0x58, auth_data.len() as u8]);
+    object.extend_from_slice(&auth_data);
+
+    let info = parse_attestation_object(&object).unwrap();
+    assert_eq!(info.sign_count, 7);
+    assert_eq!(info.aaguid.unwrap().as_bytes(), &aaguid);
+
+    assert!(parse_attestation_object(&object[..object.len() - 10]).is_none());
+    assert!(parse_attestation_object(&[0x80]).is_none());
+}